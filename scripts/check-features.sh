@@ -0,0 +1,34 @@
+#!/usr/bin/env bash
+# 逐一編譯並測試對外宣告的 feature 組合，確認 cfg 分支都還能編譯
+set -euo pipefail
+
+SCRIPT_DIR="$(cd "$(dirname "${BASH_SOURCE[0]}")" && pwd)"
+PROJECT_DIR="$(cd "$SCRIPT_DIR/.." && pwd)"
+
+COMBINATIONS=(
+  ""
+  "--no-default-features --features minimal"
+  "--no-default-features --features minimal,shell-restart"
+  "--no-default-features --features backend-diag"
+  "--no-default-features --features backend-ss"
+  "--no-default-features --features backend-lsof"
+  "--no-default-features --features backend-ss,backend-lsof,shell-restart"
+)
+
+cd "$PROJECT_DIR"
+for flags in "${COMBINATIONS[@]}"; do
+  echo "== cargo ${flags:-（預設 feature）}"
+  # shellcheck disable=SC2086
+  cargo clippy --all-targets $flags -- -D warnings
+  # shellcheck disable=SC2086
+  cargo test --quiet $flags
+done
+
+# 沒有任何 peer 後端時只能出現 compile_error! 這一個診斷，不夾雜 match 不完整之類的錯誤
+echo "== cargo --no-default-features（預期只有 compile_error!）"
+diagnostics="$(cargo build --no-default-features --message-format short 2>&1 | grep -E '^src/.*: (error|warning)' || true)"
+if [[ "$(wc -l <<<"$diagnostics")" -ne 1 || "$diagnostics" != *"至少需要啟用"* ]]; then
+  echo "未編入後端時的診斷不符預期：" >&2
+  echo "$diagnostics" >&2
+  exit 1
+fi
@@ -0,0 +1,151 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+
+use crate::ProcRoot;
+
+// 重啟前保存舊程序的狀態：fd 清單、status、cmdline 與當下的連線歸屬。
+// 全部盡力而為，個別檔案失敗只記下來，不影響重啟
+pub struct CaptureReport {
+    pub dir: PathBuf,
+    pub failures: Vec<String>,
+}
+
+pub fn capture_processes(
+    base: &Path,
+    label: &str,
+    proc_root: &ProcRoot,
+    pids: &[i32],
+    connections: &[(String, i32)],
+    summary: &str,
+) -> io::Result<CaptureReport> {
+    let dir = create_capture_dir(base, label)?;
+    let mut failures = Vec::new();
+    let mut record = |name: &str, result: io::Result<()>| {
+        if let Err(err) = result {
+            failures.push(format!("{name}: {err}"));
+        }
+    };
+
+    let mut lines: Vec<String> = connections
+        .iter()
+        .map(|(inode, pid)| format!("{inode} {pid}"))
+        .collect();
+    lines.insert(0, "# 應用程式端 inode 持有的 PID".to_string());
+    record("summary", fs::write(dir.join("summary.txt"), summary));
+    record(
+        "connections",
+        fs::write(dir.join("connections.txt"), lines.join("\n") + "\n"),
+    );
+
+    for pid in pids {
+        let pid_dir = dir.join(pid.to_string());
+        if let Err(err) = fs::create_dir(&pid_dir) {
+            record(&pid.to_string(), Err(err));
+            continue;
+        }
+        record(
+            &format!("{pid}/status"),
+            // 不用 fs::copy：它會用到 seccomp 白名單外的 copy_file_range 與 fchmod
+            fs::read(proc_root.pid_entry(*pid, "status"))
+                .and_then(|status| fs::write(pid_dir.join("status"), status)),
+        );
+        record(
+            &format!("{pid}/cmdline"),
+            fs::read(proc_root.pid_entry(*pid, "cmdline")).and_then(|raw| {
+                let text: Vec<String> = raw
+                    .split(|byte| *byte == 0)
+                    .filter(|arg| !arg.is_empty())
+                    .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                    .collect();
+                fs::write(pid_dir.join("cmdline"), text.join(" ") + "\n")
+            }),
+        );
+        record(
+            &format!("{pid}/fd"),
+            fd_listing(proc_root, *pid)
+                .and_then(|listing| fs::write(pid_dir.join("fd.txt"), listing)),
+        );
+    }
+    Ok(CaptureReport { dir, failures })
+}
+
+// <base>/<時間戳>-<label>，同一秒內重複時加上序號；內容含路徑與命令列，只給擁有者讀
+fn create_capture_dir(base: &Path, label: &str) -> io::Result<PathBuf> {
+    fs::create_dir_all(base)?;
+    let stem = format!("{}-{label}", crate::timestamp());
+    let mut builder = fs::DirBuilder::new();
+    builder.mode(0o700);
+    let mut dir = base.join(&stem);
+    for attempt in 2..=100 {
+        match builder.create(&dir) {
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                dir = base.join(format!("{stem}-{attempt}"));
+            }
+            result => return result.map(|()| dir),
+        }
+    }
+    builder.create(&dir).map(|()| dir)
+}
+
+fn fd_listing(proc_root: &ProcRoot, pid: i32) -> io::Result<String> {
+    let mut entries: Vec<(u32, String)> = fs::read_dir(proc_root.pid_entry(pid, "fd"))?
+        .flatten()
+        .filter_map(|entry| {
+            let fd = entry.file_name().to_str()?.parse().ok()?;
+            let target = fs::read_link(entry.path())
+                .map(|link| link.to_string_lossy().into_owned())
+                .unwrap_or_else(|err| format!("（{err}）"));
+            Some((fd, target))
+        })
+        .collect();
+    entries.sort_unstable();
+    Ok(entries
+        .iter()
+        .map(|(fd, target)| format!("{fd} -> {target}\n"))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture_writes_per_pid_files_and_survives_missing_pids() {
+        let base =
+            std::env::temp_dir().join(format!("qq-x11-guard-capture-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let own = std::process::id() as i32;
+        let proc_root = ProcRoot::default();
+        let connections = vec![("5001".to_string(), own)];
+
+        let report = capture_processes(
+            &base,
+            "inc-1",
+            &proc_root,
+            &[own, i32::MAX],
+            &connections,
+            "摘要\n",
+        )
+        .expect("建立擷取目錄");
+        assert!(report.dir.join(format!("{own}/fd.txt")).exists());
+        assert!(fs::read_to_string(report.dir.join(format!("{own}/status")))
+            .unwrap()
+            .contains("Pid:"));
+        assert!(fs::read_to_string(report.dir.join("connections.txt"))
+            .unwrap()
+            .contains(&format!("5001 {own}")));
+        // 不存在的 PID 只記為失敗
+        assert!(!report.failures.is_empty());
+        assert!(report
+            .failures
+            .iter()
+            .all(|item| item.starts_with(&i32::MAX.to_string())));
+
+        // 同一秒再擷取一次不會覆蓋
+        let second = capture_processes(&base, "inc-1", &proc_root, &[], &[], "").unwrap();
+        assert_ne!(second.dir, report.dir);
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,809 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// --config：TOML 的一個子集（最上層的 key = value，值為字串、整數、浮點數、布林或它們的陣列，不支援表格與日期）；
+// 副檔名為 .json 時改讀一個只有一層的 JSON 物件，值的種類相同，null 視為未設定。
+// 每個 key 對應一個命令列參數：app_name → --app-name，帶 _seconds 後綴而參數沒有的去掉後綴（cooldown_seconds → --cooldown）。
+// 轉成的參數插在命令列之前交給 parse_args，命令列上的同一個參數在後面處理，自然覆蓋檔案中的值。
+// 環境變數 QQ_GUARD_<名稱> 以同樣的方式對應（QQ_GUARD_RESTART_CMD → --restart-cmd），優先於設定檔。
+// --config-dir 與設定檔旁的 <設定檔>.d/ 中的 *.toml 依檔名順序合併在設定檔之後，見 merge
+
+pub const ENV_PREFIX: &str = "QQ_GUARD_";
+// 指定設定檔而不是某個參數
+pub const ENV_CONFIG: &str = "QQ_GUARD_CONFIG";
+
+// 不能寫在設定檔的參數：巢狀設定檔、只在命令列有意義的，以及 self-upgrade 內部使用的
+const COMMAND_LINE_ONLY: [&str; 6] = [
+    "--config",
+    "--config-dir",
+    "--print-config",
+    "--help",
+    "--version",
+    "--resume-state",
+];
+// 設定目錄中逐值附加而不是整組取代的參數
+const APPENDED: &str = "--app-name";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    // 整數與浮點數保留原本的寫法（去掉底線），交給各參數自己解析
+    Number(String),
+    Bool(bool),
+    Array(Vec<Value>),
+    Null,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    // 錯誤訊息中的來源，例如「設定檔 /etc/qq-guard.toml 第 3 行的 threshold」
+    pub origin: String,
+    pub flag: String,
+    pub args: Vec<String>,
+}
+
+// flags 為所有長參數與是否需要值
+pub fn load(path: &Path, flags: &[(&str, bool)]) -> Result<Vec<Entry>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|error| format!("無法讀取設定檔 {}: {error}", path.display()))?;
+    let json = path
+        .extension()
+        .is_some_and(|extension| extension == "json");
+    let mut entries =
+        parse(&text, json, flags).map_err(|error| format!("設定檔 {} {error}", path.display()))?;
+    for entry in &mut entries {
+        entry.origin = format!("設定檔 {} {}", path.display(), entry.origin);
+    }
+    Ok(entries)
+}
+
+// 設定目錄中的 *.toml（不含 . 開頭的隱藏檔），依檔名的字典順序
+pub fn drop_in_files(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = fs::read_dir(dir)
+        .map_err(|error| format!("無法讀取設定目錄 {}: {error}", dir.display()))?;
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|error| format!("無法讀取設定目錄 {}: {error}", dir.display()))?
+            .path();
+        let hidden = path
+            .file_name()
+            .is_some_and(|name| name.as_encoded_bytes().starts_with(b"."));
+        if !hidden
+            && path
+                .extension()
+                .is_some_and(|extension| extension == "toml")
+            && path.is_file()
+        {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// 把較後讀取的一個檔案合併進 merged：一般參數整組取代先前的值；--app-name 逐值附加，
+// 與先前某個值有相同的程式名稱（app_names 取出）時視為重新定義，以後者取代並回傳指出兩處來源的警告。
+// 同一個檔案內的重複留給參數解析回報
+pub fn merge(
+    merged: &mut Vec<Entry>,
+    layer: Vec<Entry>,
+    app_names: impl Fn(&str) -> Vec<String>,
+) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let mut appended = Vec::new();
+    for entry in layer {
+        if entry.flag != APPENDED {
+            merged.retain(|earlier| earlier.flag != entry.flag);
+            merged.push(entry);
+            continue;
+        }
+        // 拆成每個值一筆，各自保留來源
+        for value in entry.args.iter().skip(1).step_by(2) {
+            let item = Entry {
+                origin: entry.origin.clone(),
+                flag: entry.flag.clone(),
+                args: vec![entry.flag.clone(), value.clone()],
+            };
+            let names = app_names(value);
+            let redefined = merged.iter().position(|earlier| {
+                earlier.flag == APPENDED
+                    && app_names(&earlier.args[1])
+                        .iter()
+                        .any(|name| names.contains(name))
+            });
+            match redefined {
+                Some(at) => {
+                    let earlier = &merged[at];
+                    if earlier.args[1] != *value {
+                        warnings.push(format!(
+                            "{}（{}）與{}（{}）定義同一個程式，採用後者",
+                            earlier.origin, earlier.args[1], item.origin, value
+                        ));
+                    }
+                    merged[at] = item;
+                }
+                None => appended.push(item),
+            }
+        }
+    }
+    merged.append(&mut appended);
+    warnings
+}
+
+// 空值視為未設定（systemd 的 Environment=NAME= 常用來清除）。可重複的參數只能給一個值
+pub fn from_env(
+    vars: impl IntoIterator<Item = (OsString, OsString)>,
+    flags: &[(&str, bool)],
+) -> Result<Vec<Entry>, String> {
+    let mut vars: Vec<(String, OsString)> = vars
+        .into_iter()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value)))
+        .filter(|(name, value)| {
+            name.starts_with(ENV_PREFIX) && name != ENV_CONFIG && !value.is_empty()
+        })
+        .collect();
+    vars.sort();
+    let mut entries: Vec<Entry> = Vec::new();
+    for (name, value) in vars {
+        let at = |message: String| format!("環境變數 {name}: {message}");
+        let value = value
+            .into_string()
+            .map_err(|_| at("不是有效的 UTF-8".to_string()))?;
+        let key = name[ENV_PREFIX.len()..].to_ascii_lowercase();
+        let (flag, takes_value) = resolve(&key, flags).map_err(at)?;
+        if let Some(first) = entries.iter().find(|entry| entry.flag == flag) {
+            return Err(at(format!("與{} 都設定 {flag}", first.origin)));
+        }
+        let value = if takes_value {
+            Value::String(value)
+        } else {
+            match value.to_ascii_lowercase().as_str() {
+                "1" | "true" | "yes" | "on" => Value::Bool(true),
+                "0" | "false" | "no" | "off" => Value::Bool(false),
+                count if flag == "--verbose" && count.parse::<u8>().is_ok() => {
+                    Value::Number(count.to_string())
+                }
+                _ => {
+                    return Err(at(format!(
+                        "{flag} 的值須為 1/0、true/false、yes/no 或 on/off，不是 {value:?}"
+                    )))
+                }
+            }
+        };
+        let args = to_args(&key, flag, takes_value, &value, flags).map_err(at)?;
+        entries.push(Entry {
+            origin: format!("環境變數 {name}"),
+            flag: flag.to_string(),
+            args,
+        });
+    }
+    Ok(entries)
+}
+
+fn parse(text: &str, json: bool, flags: &[(&str, bool)]) -> Result<Vec<Entry>, String> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        position: 0,
+        line: 1,
+        json,
+    };
+    let pairs = if json {
+        parser.json_pairs()?
+    } else {
+        parser.toml_pairs()?
+    };
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut seen: HashMap<String, (usize, String)> = HashMap::new();
+    for (line, key, value) in pairs {
+        let at = |message: String| format!("第 {line} 行: {message}");
+        let (flag, takes_value) = resolve(&key, flags).map_err(at)?;
+        if let Some((first, first_key)) = seen.get(flag) {
+            return Err(at(format!(
+                "{key} 與第 {first} 行的 {first_key} 都設定 {flag}"
+            )));
+        }
+        seen.insert(flag.to_string(), (line, key.clone()));
+        if value == Value::Null {
+            continue;
+        }
+        let args = to_args(&key, flag, takes_value, &value, flags).map_err(at)?;
+        entries.push(Entry {
+            origin: format!("第 {line} 行的 {key}"),
+            flag: flag.to_string(),
+            args,
+        });
+    }
+    Ok(entries)
+}
+
+fn resolve<'a>(key: &str, flags: &[(&'a str, bool)]) -> Result<(&'a str, bool), String> {
+    let flag = format!("--{}", key.replace('_', "-"));
+    let candidates = [Some(flag.as_str()), flag.strip_suffix("-seconds")];
+    let found = candidates
+        .into_iter()
+        .flatten()
+        .find_map(|candidate| flags.iter().find(|(name, _)| *name == candidate));
+    match found {
+        Some((name, _)) if COMMAND_LINE_ONLY.contains(name) => {
+            Err(format!("{key} 只能寫在命令列（{name}）"))
+        }
+        Some((name, takes_value)) => Ok((name, *takes_value)),
+        None => Err(format!("不認得的設定 {key}")),
+    }
+}
+
+fn to_args(
+    key: &str,
+    flag: &str,
+    takes_value: bool,
+    value: &Value,
+    flags: &[(&str, bool)],
+) -> Result<Vec<String>, String> {
+    if !takes_value {
+        return match value {
+            Value::Bool(true) => Ok(vec![flag.to_string()]),
+            // 有對應的 --no-… 時寫成它，才能蓋掉預設開啟的功能
+            Value::Bool(false) => {
+                let negated = format!("--no-{}", &flag[2..]);
+                Ok(flags
+                    .iter()
+                    .any(|(name, _)| *name == negated)
+                    .then_some(negated)
+                    .into_iter()
+                    .collect())
+            }
+            Value::Number(count) if flag == "--verbose" => {
+                let count: usize = count
+                    .parse()
+                    .map_err(|_| format!("{key} 必須是 0-3 的整數或布林"))?;
+                Ok(vec![flag.to_string(); count.min(3)])
+            }
+            _ => Err(format!("{key} 必須是 true 或 false")),
+        };
+    }
+    let scalar = |value: &Value| match value {
+        Value::String(text) | Value::Number(text) => Ok(vec![flag.to_string(), text.clone()]),
+        Value::Bool(_) => Err(format!("{key} 需要字串或數字，不是 true/false")),
+        Value::Array(_) => Err(format!("{key} 的陣列不能巢狀")),
+        Value::Null => Err(format!("{key} 的陣列不能有 null")),
+    };
+    match value {
+        // 可重複的參數以陣列寫出多個值
+        Value::Array(items) => items.iter().try_fold(Vec::new(), |mut args, item| {
+            args.extend(scalar(item)?);
+            Ok(args)
+        }),
+        _ => scalar(value),
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+    line: usize,
+    json: bool,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let char = self.peek()?;
+        self.position += 1;
+        if char == '\n' {
+            self.line += 1;
+        }
+        Some(char)
+    }
+
+    fn error(&self, message: impl Into<String>) -> String {
+        format!("第 {} 行: {}", self.line, message.into())
+    }
+
+    // 略過同一行的空白與註解
+    fn skip_inline(&mut self) {
+        while let Some(char) = self.peek() {
+            match char {
+                ' ' | '\t' | '\r' => {
+                    self.bump();
+                }
+                '#' => {
+                    while self.peek().is_some_and(|char| char != '\n') {
+                        self.bump();
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn skip_blank_lines(&mut self) {
+        loop {
+            self.skip_inline();
+            if self.peek() != Some('\n') {
+                break;
+            }
+            self.bump();
+        }
+    }
+
+    // JSON 沒有註解，換行只是空白
+    fn skip_space(&mut self) {
+        if !self.json {
+            return self.skip_blank_lines();
+        }
+        while self
+            .peek()
+            .is_some_and(|char| matches!(char, ' ' | '\t' | '\r' | '\n'))
+        {
+            self.bump();
+        }
+    }
+
+    fn toml_pairs(&mut self) -> Result<Vec<(usize, String, Value)>, String> {
+        let mut pairs = Vec::new();
+        while let Some(pair) = self.next_pair()? {
+            pairs.push(pair);
+        }
+        Ok(pairs)
+    }
+
+    fn json_pairs(&mut self) -> Result<Vec<(usize, String, Value)>, String> {
+        let mut pairs = Vec::new();
+        self.skip_space();
+        if self.bump() != Some('{') {
+            return Err(self.error("JSON 設定檔須為一個物件 {…}"));
+        }
+        self.skip_space();
+        if self.peek() == Some('}') {
+            self.bump();
+        } else {
+            loop {
+                self.skip_space();
+                if self.peek() != Some('"') {
+                    return Err(self.error("預期以雙引號包住的設定名稱"));
+                }
+                let line = self.line;
+                let key = self.basic_string()?;
+                self.skip_space();
+                if self.bump() != Some(':') {
+                    return Err(self.error(format!("{key} 之後須為 :")));
+                }
+                self.skip_space();
+                if self.peek() == Some('{') {
+                    return Err(
+                        self.error(format!("不支援巢狀物件（{key}），所有設定都寫在最上層"))
+                    );
+                }
+                pairs.push((line, key, self.value()?));
+                self.skip_space();
+                match self.bump() {
+                    Some(',') => {}
+                    Some('}') => break,
+                    _ => return Err(self.error("設定之間須以逗號分隔，並以 } 結束")),
+                }
+            }
+        }
+        self.skip_space();
+        match self.peek() {
+            Some(char) => Err(self.error(format!("物件結束後多了 {char:?}"))),
+            None => Ok(pairs),
+        }
+    }
+
+    fn next_pair(&mut self) -> Result<Option<(usize, String, Value)>, String> {
+        self.skip_blank_lines();
+        let Some(first) = self.peek() else {
+            return Ok(None);
+        };
+        let line = self.line;
+        if first == '[' {
+            return Err(self.error("不支援表格（[…]），所有設定都寫在最上層"));
+        }
+        let key = match first {
+            '"' => self.basic_string()?,
+            '\'' => self.literal_string()?,
+            _ => {
+                let mut key = String::new();
+                while let Some(char) = self
+                    .peek()
+                    .filter(|char| char.is_ascii_alphanumeric() || *char == '_' || *char == '-')
+                {
+                    key.push(char);
+                    self.bump();
+                }
+                if key.is_empty() {
+                    return Err(self.error(format!("預期設定名稱，卻是 {first:?}")));
+                }
+                key
+            }
+        };
+        self.skip_inline();
+        if self.peek() == Some('.') {
+            return Err(self.error(format!("不支援帶點的名稱（{key}.…）")));
+        }
+        if self.bump() != Some('=') {
+            return Err(self.error(format!("{key} 之後須為 =")));
+        }
+        self.skip_inline();
+        let value = self.value()?;
+        self.skip_inline();
+        match self.bump() {
+            None | Some('\n') => Ok(Some((line, key, value))),
+            Some(char) => Err(self.error(format!("{key} 的值之後多了 {char:?}"))),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            Some('"') => self.basic_string().map(Value::String),
+            Some('\'') if self.json => Err(self.error("JSON 的字串須以雙引號包住")),
+            Some('\'') => self.literal_string().map(Value::String),
+            Some('[') => self.array(),
+            Some(_) => {
+                let mut word = String::new();
+                while let Some(char) = self.peek().filter(|char| {
+                    char.is_ascii_alphanumeric() || matches!(char, '_' | '-' | '+' | '.' | ':')
+                }) {
+                    word.push(char);
+                    self.bump();
+                }
+                match word.as_str() {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    "null" if self.json => Ok(Value::Null),
+                    _ => {
+                        let number = word.replace('_', "");
+                        if number.parse::<f64>().is_ok() && !number.contains(':') {
+                            Ok(Value::Number(number))
+                        } else if word.is_empty() {
+                            Err(self.error("缺少值"))
+                        } else {
+                            // 例如 2m 或 :0：字串須加引號
+                            Err(self.error(format!("無法解析的值 {word}（字串須加引號）")))
+                        }
+                    }
+                }
+            }
+            None => Err(self.error("缺少值")),
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.bump();
+        let mut items = Vec::new();
+        loop {
+            self.skip_space();
+            if self.peek() == Some(']') {
+                self.bump();
+                return Ok(Value::Array(items));
+            }
+            items.push(self.value()?);
+            self.skip_space();
+            match self.bump() {
+                Some(',') => {}
+                Some(']') => return Ok(Value::Array(items)),
+                _ => return Err(self.error("陣列的元素之間須以逗號分隔，並以 ] 結束")),
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String, String> {
+        self.bump();
+        let mut text = String::new();
+        loop {
+            match self.bump() {
+                Some('\'') => return Ok(text),
+                Some('\n') | None => return Err(self.error("字串缺少結尾的 '")),
+                Some(char) => text.push(char),
+            }
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<String, String> {
+        self.bump();
+        if self.peek() == Some('"') && self.chars.get(self.position + 1) == Some(&'"') {
+            return Err(self.error("不支援多行字串（\"\"\"）"));
+        }
+        let mut text = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(text),
+                Some('\n') | None => return Err(self.error("字串缺少結尾的 \"")),
+                Some('\\') => {
+                    let escaped = match self.bump() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('/') if self.json => '/',
+                        Some(kind @ ('u' | 'U')) => {
+                            let digits = if kind == 'u' { 4 } else { 8 };
+                            let hex: String = (0..digits).filter_map(|_| self.bump()).collect();
+                            u32::from_str_radix(&hex, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| self.error(format!("無效的 \\{kind}{hex}")))?
+                        }
+                        other => {
+                            return Err(self.error(format!(
+                                "不支援的跳脫字元 \\{}",
+                                other.map(String::from).unwrap_or_default()
+                            )))
+                        }
+                    };
+                    text.push(escaped);
+                }
+                Some(char) => text.push(char),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLAGS: [(&str, bool); 10] = [
+        ("--app-name", true),
+        ("--threshold", true),
+        ("--cooldown", true),
+        ("--restart-cmd", true),
+        ("--rule", true),
+        ("--dry-run", false),
+        ("--strict-attribution", false),
+        ("--no-strict-attribution", false),
+        ("--verbose", false),
+        ("--resume-state", true),
+    ];
+
+    fn args(text: &str) -> Result<Vec<String>, String> {
+        Ok(parse(text, false, &FLAGS)?
+            .into_iter()
+            .flat_map(|entry| entry.args)
+            .collect())
+    }
+
+    #[test]
+    fn keys_become_flags_and_errors_name_the_line() {
+        let text = r#"
+# 第二台機器
+app_name = "qq"   # 行尾註解
+threshold = 2_0
+cooldown_seconds = '2m'
+restart_cmd = "qq --no-sandbox \"$X\" é"
+rule = [
+  "bloat:rss > 2G:restart",  # 可重複的參數
+  'fds:fd > 900:alert',
+]
+dry_run = true
+strict_attribution = false
+verbose = 2
+"#;
+        assert_eq!(
+            args(text).unwrap(),
+            [
+                "--app-name",
+                "qq",
+                "--threshold",
+                "20",
+                "--cooldown",
+                "2m",
+                "--restart-cmd",
+                "qq --no-sandbox \"$X\" é",
+                "--rule",
+                "bloat:rss > 2G:restart",
+                "--rule",
+                "fds:fd > 900:alert",
+                "--dry-run",
+                "--no-strict-attribution",
+                "--verbose",
+                "--verbose",
+            ]
+        );
+
+        assert_eq!(
+            args("threshold = 10\nthreshhold = 20\n").unwrap_err(),
+            "第 2 行: 不認得的設定 threshhold"
+        );
+        assert_eq!(
+            args("cooldown = 60\n\ncooldown_seconds = 90\n").unwrap_err(),
+            "第 3 行: cooldown_seconds 與第 1 行的 cooldown 都設定 --cooldown"
+        );
+        assert_eq!(
+            args("[guard]\nthreshold = 10\n").unwrap_err(),
+            "第 1 行: 不支援表格（[…]），所有設定都寫在最上層"
+        );
+        assert_eq!(
+            args("cooldown = 2m\n").unwrap_err(),
+            "第 1 行: 無法解析的值 2m（字串須加引號）"
+        );
+        assert_eq!(
+            args("dry_run = \"yes\"\n").unwrap_err(),
+            "第 1 行: dry_run 必須是 true 或 false"
+        );
+        assert_eq!(
+            args("app_name = \"qq\" threshold = 3\n").unwrap_err(),
+            "第 1 行: app_name 的值之後多了 't'"
+        );
+        assert!(args("resume_state = \"/tmp/x\"\n")
+            .unwrap_err()
+            .contains("只能寫在命令列"));
+    }
+
+    #[test]
+    fn json_objects_map_the_same_way() {
+        let json = |text: &str| -> Result<Vec<String>, String> {
+            Ok(parse(text, true, &FLAGS)?
+                .into_iter()
+                .flat_map(|entry| entry.args)
+                .collect())
+        };
+        let text = r#"{
+  "app_name": "qq\u00e9",
+  "threshold": 20,
+  "restart_cmd": "\/opt\/QQ\/qq",
+  "rule": ["bloat:rss > 2G:restart"],
+  "cooldown_seconds": null,
+  "dry_run": false
+}
+"#;
+        // false 沒有對應的 --no-dry-run：不產生參數，命令列上的 --dry-run 照樣生效
+        assert_eq!(
+            json(text).unwrap(),
+            [
+                "--app-name",
+                "qqé",
+                "--threshold",
+                "20",
+                "--restart-cmd",
+                "/opt/QQ/qq",
+                "--rule",
+                "bloat:rss > 2G:restart",
+            ]
+        );
+        assert_eq!(json("{}").unwrap(), Vec::<String>::new());
+        assert_eq!(
+            json("{\n  \"threshold\": 20,\n  \"guard\": {}\n}").unwrap_err(),
+            "第 3 行: 不支援巢狀物件（guard），所有設定都寫在最上層"
+        );
+        assert_eq!(
+            json("{\"threshold\": 20\n\"cooldown\": 5}").unwrap_err(),
+            "第 2 行: 設定之間須以逗號分隔，並以 } 結束"
+        );
+        assert_eq!(
+            json("{\"threshhold\": 20}").unwrap_err(),
+            "第 1 行: 不認得的設定 threshhold"
+        );
+        assert!(json("threshold = 20").is_err());
+    }
+
+    #[test]
+    fn drop_ins_override_scalars_and_append_apps() {
+        let entries = |file: &str, text: &str| -> Vec<Entry> {
+            let mut entries = parse(text, false, &FLAGS).unwrap();
+            for entry in &mut entries {
+                entry.origin = format!("設定檔 {file} {}", entry.origin);
+            }
+            entries
+        };
+        let names = |value: &str| -> Vec<String> {
+            let head = value.split(['=', ':']).next().unwrap_or_default();
+            head.split(',').map(str::to_string).collect()
+        };
+        let mut merged = Vec::new();
+        let layers = [
+            (
+                "base.toml",
+                "app_name = \"qq\"\nthreshold = 10\nrule = [\"a:x11 > 1:alert\"]\n",
+            ),
+            (
+                "10-host.toml",
+                "threshold = 20\napp_name = [\"wechat:5\", \"qq\"]\n",
+            ),
+            ("20-qq.toml", "app_name = \"qq:30=qq --no-sandbox\"\n"),
+        ];
+        let mut warnings = Vec::new();
+        for (file, text) in layers {
+            warnings.extend(merge(&mut merged, entries(file, text), names));
+        }
+        assert_eq!(
+            merged
+                .iter()
+                .flat_map(|entry| entry.args.clone())
+                .collect::<Vec<_>>(),
+            [
+                "--rule",
+                "a:x11 > 1:alert",
+                "--app-name",
+                "qq:30=qq --no-sandbox",
+                "--threshold",
+                "20",
+                "--app-name",
+                "wechat:5",
+            ]
+        );
+        // 10-host.toml 原樣重複 qq 不算衝突；20-qq.toml 換了門檻與命令才警告
+        assert_eq!(
+            warnings,
+            ["設定檔 10-host.toml 第 2 行的 app_name（qq）與設定檔 20-qq.toml 第 1 行的 app_name（qq:30=qq --no-sandbox）定義同一個程式，採用後者"]
+        );
+        assert_eq!(merged[1].origin, "設定檔 20-qq.toml 第 1 行的 app_name");
+
+        let dir = std::env::temp_dir().join(format!("qq-guard-dropins-{}", std::process::id()));
+        fs::create_dir_all(dir.join("00-dir.toml")).unwrap();
+        for name in [
+            "20-b.toml",
+            "10-a.toml",
+            ".hidden.toml",
+            "30-c.json",
+            "README",
+        ] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let files = drop_in_files(&dir).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(files, [dir.join("10-a.toml"), dir.join("20-b.toml")]);
+        assert!(drop_in_files(&dir)
+            .unwrap_err()
+            .starts_with("無法讀取設定目錄"));
+    }
+
+    #[test]
+    fn environment_variables_map_to_flags() {
+        let env = |vars: &[(&str, &str)]| {
+            from_env(
+                vars.iter()
+                    .map(|(name, value)| (OsString::from(name), OsString::from(value))),
+                &FLAGS,
+            )
+        };
+        let entries = env(&[
+            ("QQ_GUARD_THRESHOLD", "20"),
+            ("QQ_GUARD_DRY_RUN", "yes"),
+            ("QQ_GUARD_STRICT_ATTRIBUTION", "off"),
+            ("QQ_GUARD_VERBOSE", "2"),
+            // 空值與設定檔路徑不是參數
+            ("QQ_GUARD_APP_NAME", ""),
+            ("QQ_GUARD_CONFIG", "/etc/qq-guard.toml"),
+            ("PATH", "/usr/bin"),
+        ])
+        .unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .flat_map(|entry| entry.args.clone())
+                .collect::<Vec<_>>(),
+            [
+                "--dry-run",
+                "--no-strict-attribution",
+                "--threshold",
+                "20",
+                "--verbose",
+                "--verbose",
+            ]
+        );
+        assert_eq!(entries[2].origin, "環境變數 QQ_GUARD_THRESHOLD");
+
+        assert_eq!(
+            env(&[("QQ_GUARD_COOLDOWN_SECONDS", "60")]).unwrap()[0].args,
+            ["--cooldown", "60"]
+        );
+        assert_eq!(
+            env(&[("QQ_GUARD_THRESHHOLD", "20")]).unwrap_err(),
+            "環境變數 QQ_GUARD_THRESHHOLD: 不認得的設定 threshhold"
+        );
+        assert!(env(&[("QQ_GUARD_DRY_RUN", "maybe")])
+            .unwrap_err()
+            .starts_with("環境變數 QQ_GUARD_DRY_RUN: "));
+        assert!(env(&[("QQ_GUARD_RESUME_STATE", "/tmp/x")]).is_err());
+    }
+}
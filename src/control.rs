@@ -0,0 +1,333 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem;
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::parse_duration_secs;
+
+// 控制 socket：每個連線送一行命令、收一段回覆後關閉。
+// 在降權後、seccomp 前建立，之後只需要 accept4/read/write。
+const MAX_REQUEST_LEN: u64 = 512;
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlCommand {
+    Status,
+    // Prometheus 文字格式的量測值
+    Metrics,
+    // 暫停的秒數；None 表示直到 resume
+    Pause(Option<u64>),
+    Resume,
+    // 立即重啟，附帶的原因會寫入日誌與通知
+    Restart(Option<String>),
+    // 立即重新量測；true 時以 JSON 回覆
+    Measure(bool),
+    // 清除重啟失敗的狀態，下一次失敗會再執行失敗處理
+    Clear,
+    // --confirm-restart：核准或拒絕等待中的動作
+    Approve,
+    Deny,
+    // 保存狀態後 exec 磁碟上的執行檔，套件更新後不必重新啟動服務
+    SelfUpgrade,
+}
+
+// 送出命令的用戶端（SO_PEERCRED），寫進日誌說明是誰下的決定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Peer {
+    pub pid: i32,
+    pub uid: u32,
+}
+
+pub fn parse_command(line: &str) -> Result<ControlCommand, String> {
+    let mut words = line.split_whitespace();
+    let command = words.next().ok_or("需要命令")?;
+    // restart 之後的整段文字都是原因，可以含空白
+    if command == "restart" {
+        let reason = words.collect::<Vec<_>>().join(" ");
+        return Ok(ControlCommand::Restart(
+            (!reason.is_empty()).then_some(reason),
+        ));
+    }
+    let argument = words.next();
+    if words.next().is_some() {
+        return Err(format!("參數過多: {}", line.trim()));
+    }
+    match (command, argument) {
+        ("status", None) => Ok(ControlCommand::Status),
+        ("metrics", None) => Ok(ControlCommand::Metrics),
+        ("pause", None) => Ok(ControlCommand::Pause(None)),
+        ("pause", Some(value)) => {
+            let seconds = parse_duration_secs(value)?;
+            if seconds == 0 {
+                return Err("暫停時間必須 >= 1 秒".to_string());
+            }
+            Ok(ControlCommand::Pause(Some(seconds)))
+        }
+        ("resume", None) => Ok(ControlCommand::Resume),
+        ("clear", None) => Ok(ControlCommand::Clear),
+        ("approve", None) => Ok(ControlCommand::Approve),
+        ("deny", None) => Ok(ControlCommand::Deny),
+        ("self-upgrade", None) => Ok(ControlCommand::SelfUpgrade),
+        ("measure", None) => Ok(ControlCommand::Measure(false)),
+        ("measure", Some("json")) => Ok(ControlCommand::Measure(true)),
+        ("measure", Some(value)) => Err(format!("measure 只接受 json: {value}")),
+        ("status" | "metrics" | "resume" | "clear" | "approve" | "deny" | "self-upgrade", Some(_)) => Err(format!("{command} 不接受參數")),
+        _ => Err(format!(
+            "不支援的命令: {command}（可用：status、metrics、measure [json]、pause [時間]、resume、clear、restart [原因]、approve、deny、self-upgrade）"
+        )),
+    }
+}
+
+pub struct ControlServer {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ControlServer {
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        // 上次異常結束留下的 socket 檔：確認沒有程序在聽才移除，避免搶走另一個實例
+        if path.exists() {
+            match UnixStream::connect(path) {
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        "已有其他實例在使用此控制 socket",
+                    ))
+                }
+                Err(_) => fs::remove_file(path)?,
+            }
+        }
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+
+    // self-upgrade 後由前一個執行檔交接的 socket：確認 fd 確實是綁在同一個路徑上的 unix socket 才接手，
+    // 不符時回傳錯誤、fd 保持原狀，由呼叫端改為重新建立
+    pub fn adopt(fd: RawFd, path: &Path) -> io::Result<Self> {
+        let mut address: libc::sockaddr_un = unsafe { mem::zeroed() };
+        let mut length = mem::size_of::<libc::sockaddr_un>() as libc::socklen_t;
+        if fd <= 2
+            || unsafe {
+                libc::getsockname(
+                    fd,
+                    (&mut address as *mut libc::sockaddr_un).cast(),
+                    &mut length,
+                )
+            } != 0
+            || address.sun_family != libc::AF_UNIX as libc::sa_family_t
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("fd {fd} 不是 unix socket"),
+            ));
+        }
+        let bound: Vec<u8> = address
+            .sun_path
+            .iter()
+            .take_while(|byte| **byte != 0)
+            .map(|byte| *byte as u8)
+            .collect();
+        if bound != path.as_os_str().as_encoded_bytes() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("fd {fd} 綁定的是 {}", String::from_utf8_lossy(&bound)),
+            ));
+        }
+        let listener = unsafe { UnixListener::from_raw_fd(fd) };
+        set_cloexec(fd, true)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+
+    // exec 前讓新的執行檔繼承 socket；exec 失敗時再設回
+    pub fn set_inherited(&self, inherited: bool) -> io::Result<()> {
+        set_cloexec(self.fd(), !inherited)
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // 逐一處理所有等待中的連線；讀寫皆有時限，慢的用戶端不會卡住監控迴圈
+    pub fn serve(&self, mut handle: impl FnMut(&str, Option<Peer>) -> String) {
+        while let Ok((stream, _)) = self.listener.accept() {
+            let _ = serve_client(stream, &mut handle);
+        }
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn set_cloexec(fd: RawFd, cloexec: bool) -> io::Result<()> {
+    let flags = if cloexec { libc::FD_CLOEXEC } else { 0 };
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn serve_client(
+    stream: UnixStream,
+    handle: &mut impl FnMut(&str, Option<Peer>) -> String,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new((&stream).take(MAX_REQUEST_LEN)).read_line(&mut line)?;
+    // 沒送任何內容就關閉的連線（例如另一個實例啟動時的探測）不必回覆
+    if line.trim().is_empty() {
+        return Ok(());
+    }
+    let mut reply = handle(line.trim(), peer_credentials(&stream));
+    if !reply.ends_with('\n') {
+        reply.push('\n');
+    }
+    (&stream).write_all(reply.as_bytes())
+}
+
+fn peer_credentials(stream: &UnixStream) -> Option<Peer> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    (result == 0).then_some(Peer {
+        pid: cred.pid,
+        uid: cred.uid,
+    })
+}
+
+// timeout 是等待回覆的上限；restart 要等整個重啟流程跑完才會回覆
+pub fn request(path: &Path, command: &str, timeout: Duration) -> io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.write_all(format!("{command}\n").as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn parse_command_accepts_known_commands() {
+        assert_eq!(parse_command("status"), Ok(ControlCommand::Status));
+        assert_eq!(parse_command(" pause "), Ok(ControlCommand::Pause(None)));
+        assert_eq!(
+            parse_command("pause 1h30m"),
+            Ok(ControlCommand::Pause(Some(5400)))
+        );
+        assert_eq!(parse_command("resume"), Ok(ControlCommand::Resume));
+        assert_eq!(parse_command("metrics"), Ok(ControlCommand::Metrics));
+        assert_eq!(parse_command("restart"), Ok(ControlCommand::Restart(None)));
+        assert_eq!(parse_command("measure"), Ok(ControlCommand::Measure(false)));
+        assert_eq!(
+            parse_command("measure json"),
+            Ok(ControlCommand::Measure(true))
+        );
+        assert_eq!(parse_command("clear"), Ok(ControlCommand::Clear));
+        assert_eq!(parse_command("approve"), Ok(ControlCommand::Approve));
+        assert_eq!(parse_command("deny"), Ok(ControlCommand::Deny));
+        assert_eq!(
+            parse_command("self-upgrade"),
+            Ok(ControlCommand::SelfUpgrade)
+        );
+        assert_eq!(
+            parse_command("restart  視窗卡住 無回應"),
+            Ok(ControlCommand::Restart(Some("視窗卡住 無回應".to_string())))
+        );
+        for line in [
+            "",
+            "pause 0",
+            "pause 5 6",
+            "resume now",
+            "metrics all",
+            "measure yaml",
+            "clear all",
+            "approve now",
+            "reboot",
+        ] {
+            assert!(parse_command(line).is_err(), "{line:?} 應該被拒絕");
+        }
+    }
+
+    #[test]
+    fn server_answers_one_request_per_connection() {
+        let path = std::env::temp_dir().join(format!("qq-x11-guard-ctl-{}", std::process::id()));
+        let server = ControlServer::bind(&path).expect("建立控制 socket");
+        assert!(ControlServer::bind(&path).is_err());
+
+        let client_path = path.clone();
+        let client =
+            thread::spawn(move || request(&client_path, "status", Duration::from_secs(5)).unwrap());
+        let mut seen = Vec::new();
+        while !client.is_finished() {
+            server.serve(|line, peer| {
+                assert_eq!(peer.map(|peer| peer.pid), Some(std::process::id() as i32));
+                seen.push(line.to_string());
+                format!("收到 {line}")
+            });
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(client.join().unwrap(), "收到 status\n");
+        assert_eq!(seen, vec!["status".to_string()]);
+        drop(server);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn adopt_takes_over_only_a_socket_bound_to_the_same_path() {
+        let path = std::env::temp_dir().join(format!("qq-x11-guard-adopt-{}", std::process::id()));
+        let server = ControlServer::bind(&path).expect("建立控制 socket");
+        assert!(ControlServer::adopt(server.fd(), Path::new("/tmp/other.sock")).is_err());
+        assert!(ControlServer::adopt(1, &path).is_err());
+        // 交接：原本的物件不能再關閉 fd，也不能刪除 socket 檔
+        let fd = server.fd();
+        mem::forget(server);
+        let adopted = ControlServer::adopt(fd, &path).expect("接手控制 socket");
+        let client = thread::spawn({
+            let path = path.clone();
+            move || request(&path, "status", Duration::from_secs(5)).unwrap()
+        });
+        while !client.is_finished() {
+            adopted.serve(|_, _| "ok".to_string());
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(client.join().unwrap(), "ok\n");
+    }
+}
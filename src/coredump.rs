@@ -0,0 +1,186 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::sandbox::{self, CommandSpec};
+use crate::ProcRoot;
+
+// --core-dump-dir：送出 SIGTERM 前替主程序留一份 core，漏連線的現場只有程序還活著時才看得到。
+// 由 gcore（沒有時改用 gdb --batch）寫出；gdb 附加期間目標程序是暫停的，所以有時限，
+// 逾時連同整個程序群組一起結束。大小先以 VmRSS 預估，寫完再以實際大小確認，超過就刪掉
+const FILE_PREFIX: &str = "core-";
+
+pub struct CoreLimits {
+    pub max_bytes: u64,
+    pub timeout: Duration,
+}
+
+pub struct CoreDump {
+    pub path: PathBuf,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+pub fn dump_core(
+    dir: &Path,
+    label: &str,
+    proc_root: &ProcRoot,
+    pid: i32,
+    limits: &CoreLimits,
+) -> Result<CoreDump, String> {
+    if let Some(resident) = resident_bytes(proc_root, pid) {
+        if resident > limits.max_bytes {
+            return Err(format!(
+                "常駐記憶體約 {} MiB，core 會超過上限 {} MiB",
+                resident >> 20,
+                limits.max_bytes >> 20
+            ));
+        }
+    }
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .map_err(|err| format!("無法建立 {}: {err}", dir.display()))?;
+    // gcore -o <prefix> 會寫成 <prefix>.<pid>，gdb 的 gcore 指令則直接指定完整路徑
+    let prefix = dir.join(format!("{FILE_PREFIX}{}-{label}", crate::timestamp()));
+    let path = PathBuf::from(format!("{}.{pid}", prefix.display()));
+    let program = ["gcore", "gdb"]
+        .into_iter()
+        .find(|program| crate::restart_command_resolvable(program))
+        .ok_or("找不到 gcore 或 gdb")?;
+    let spec = match program {
+        "gcore" => CommandSpec::new("gcore")
+            .arg("-o")
+            .arg(&prefix.to_string_lossy())
+            .arg(&pid.to_string()),
+        _ => CommandSpec::new("gdb")
+            .arg("--batch")
+            .arg("--nx")
+            .arg("-p")
+            .arg(&pid.to_string())
+            .arg("-ex")
+            .arg(&format!("gcore {}", path.display())),
+    };
+    let started = Instant::now();
+    let result = sandbox::run_output(&spec.timeout(limits.timeout));
+    let elapsed = started.elapsed();
+    let written = fs::metadata(&path).map(|metadata| metadata.len()).ok();
+    let problem = match (result, written) {
+        (Err(err), _) if err.kind() == io::ErrorKind::TimedOut => {
+            // 被強制結束的 gdb 可能讓程序停在 SIGSTOP，送 SIGCONT 讓它能正常收到之後的 SIGTERM
+            unsafe {
+                libc::kill(pid, libc::SIGCONT);
+            }
+            format!("{program} {err}")
+        }
+        (Err(err), _) => format!("無法執行 {program}（{err}）"),
+        (Ok(_), Some(bytes)) if bytes > limits.max_bytes => format!(
+            "寫出 {} MiB，超過上限 {} MiB",
+            bytes >> 20,
+            limits.max_bytes >> 20
+        ),
+        (Ok(_), Some(bytes)) => {
+            return Ok(CoreDump {
+                path,
+                bytes,
+                elapsed,
+            })
+        }
+        // 多半是 ptrace 被拒（Yama ptrace_scope 或權限不足）
+        (Ok(_), None) => "沒有產生 core 檔（可能無權 ptrace 目標程序）".to_string(),
+    };
+    let _ = fs::remove_file(&path);
+    Err(problem)
+}
+
+// /proc/<pid>/status 的 VmRSS（kB）；core 至少會有這麼大
+fn resident_bytes(proc_root: &ProcRoot, pid: i32) -> Option<u64> {
+    let status = fs::read_to_string(proc_root.pid_entry(pid, "status")).ok()?;
+    let kilobytes: u64 = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
+
+// 只保留最新的 keep 個 core-* 檔；檔名以時間戳開頭，字典序即時間順序（同位數的秒數）。
+// 回傳刪除的檔案，失敗的項目略過
+pub fn prune(dir: &Path, keep: usize) -> Vec<PathBuf> {
+    let mut dumps: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .flatten()
+            .filter(|entry| {
+                entry.file_name().to_string_lossy().starts_with(FILE_PREFIX)
+                    && entry.file_type().is_ok_and(|kind| kind.is_file())
+            })
+            .map(|entry| entry.path())
+            .collect(),
+        Err(_) => return Vec::new(),
+    };
+    dumps.sort_unstable();
+    let excess = dumps.len().saturating_sub(keep);
+    dumps
+        .into_iter()
+        .take(excess)
+        .filter(|path| fs::remove_file(path).is_ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prune_keeps_the_newest_dumps_and_ignores_other_files() {
+        let dir = std::env::temp_dir().join(format!("qq-x11-guard-core-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "core-1700000001-inc-1.42",
+            "core-1700000300-inc-2.42",
+            "core-1700000020-inc-3.42",
+            "notes.txt",
+        ] {
+            fs::write(dir.join(name), "x").unwrap();
+        }
+        let removed = prune(&dir, 2);
+        assert_eq!(removed, vec![dir.join("core-1700000001-inc-1.42")]);
+        let mut left: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .flatten()
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort_unstable();
+        assert_eq!(
+            left,
+            [
+                "core-1700000020-inc-3.42",
+                "core-1700000300-inc-2.42",
+                "notes.txt"
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn oversized_process_is_refused_before_attaching() {
+        let own = std::process::id() as i32;
+        let dir = std::env::temp_dir().join(format!("qq-x11-guard-core-big-{own}"));
+        let limits = CoreLimits {
+            max_bytes: 1,
+            timeout: Duration::from_secs(1),
+        };
+        let error = dump_core(&dir, "inc-1", &ProcRoot::default(), own, &limits)
+            .err()
+            .expect("超過上限應該拒絕");
+        assert!(error.contains("常駐記憶體"), "{error}");
+        // 在附加之前就拒絕，不會建立目錄
+        assert!(!dir.exists());
+    }
+}
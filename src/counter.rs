@@ -0,0 +1,413 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use crate::{
+    count_app_x11_connections, explain, is_x11_socket_name, parse_socket_inode, pidfd_open,
+    privileges, sorted_inodes, ConnectionCount, ConnectionSource, CountTrace, FdScanner,
+    PeerBackend, ProcRoot, X11Endpoint, SO_ACCEPTCON,
+};
+
+// --connections-from 的三種計數方式，各自決定「哪些 socket 算是應用程式連到 X server 的連線」：
+//   ss：X socket 上伺服器端 socket 的對端 inode（由 --backend 取得）與應用程式 fd 的交集，兩端都確認過
+//   net-unix：只讀 /proc/net/unix，計算 X socket 各名稱上已建立的連線；表中沒有對端欄位，
+//             無法得知另一端是哪個程式，算的是整個 DISPLAY 的連線，PID 記為 0
+//   fd：不需要 peer 集合，逐一複製應用程式的 socket fd，以 getpeername 確認對端是 X socket
+// 分別監控多個程式時 Guard 連同後端移到各自的執行緒，須為 Send
+pub trait ConnectionCounter: Send {
+    // 啟動訊息、explain 與量測過慢的提示中顯示的名稱
+    fn name(&self) -> &'static str;
+
+    fn count(
+        &mut self,
+        app_pids: &[i32],
+        endpoint: &X11Endpoint,
+        fd_scanner: &mut FdScanner,
+        strict: bool,
+        trace: Option<&mut CountTrace>,
+    ) -> ConnectionCount;
+}
+
+impl ConnectionCounter for PeerBackend {
+    fn name(&self) -> &'static str {
+        PeerBackend::name(self)
+    }
+
+    fn count(
+        &mut self,
+        app_pids: &[i32],
+        endpoint: &X11Endpoint,
+        fd_scanner: &mut FdScanner,
+        strict: bool,
+        trace: Option<&mut CountTrace>,
+    ) -> ConnectionCount {
+        count_app_x11_connections(app_pids, self, endpoint, fd_scanner, strict, trace)
+    }
+}
+
+// 回傳計數方式與 peer 後端退回時的說明。net-unix 與 fd 只認得 unix socket 路徑，
+// 經 TCP 轉送的 DISPLAY 只能用 ss（net/tcp 的歸屬本來就不經過 peer 後端）
+pub fn select(
+    source: ConnectionSource,
+    config: &crate::Config,
+    endpoint: &X11Endpoint,
+) -> Result<(Box<dyn ConnectionCounter>, Option<String>), String> {
+    if source != ConnectionSource::Ss && matches!(endpoint, X11Endpoint::Tcp(_)) {
+        return Err(format!(
+            "--connections-from {} 只支援 unix socket 的 DISPLAY，經 TCP 轉送的 {} 請用 ss",
+            source.name(),
+            config.display
+        ));
+    }
+    match source {
+        ConnectionSource::Ss => {
+            let (backend, note) = PeerBackend::select(config.backend)?;
+            Ok((Box::new(backend), note))
+        }
+        ConnectionSource::NetUnix => Ok((Box::new(NetUnixCounter), None)),
+        ConnectionSource::Fd => {
+            probe_pidfd_getfd()?;
+            Ok((Box::new(FdCounter), None))
+        }
+    }
+}
+
+pub struct NetUnixCounter;
+
+impl ConnectionCounter for NetUnixCounter {
+    fn name(&self) -> &'static str {
+        "net-unix"
+    }
+
+    // 沒有程序時不計數，與其他方式一致；嚴格歸屬檢查改為再讀一次表，兩次都在的連線才算數
+    fn count(
+        &mut self,
+        app_pids: &[i32],
+        endpoint: &X11Endpoint,
+        fd_scanner: &mut FdScanner,
+        strict: bool,
+        mut trace: Option<&mut CountTrace>,
+    ) -> ConnectionCount {
+        let mut result = ConnectionCount {
+            connections: Vec::new(),
+            rejected: 0,
+            unreadable: Vec::new(),
+        };
+        if !matches!(endpoint, X11Endpoint::Unix(_)) || app_pids.is_empty() {
+            return result;
+        }
+        let names = endpoint.socket_names();
+        let mut inodes = read_connected_inodes(&fd_scanner.proc_root, &names);
+        if let Some(trace) = trace.as_deref_mut() {
+            trace.peers = sorted_inodes(&inodes);
+            trace.candidates = trace.peers.iter().map(|inode| (inode.clone(), 0)).collect();
+        }
+        if strict && !inodes.is_empty() {
+            let again = read_connected_inodes(&fd_scanner.proc_root, &names);
+            let rejected: HashSet<String> = inodes.difference(&again).cloned().collect();
+            result.rejected = rejected.len();
+            if let Some(trace) = trace {
+                trace.rejected = sorted_inodes(&rejected);
+            }
+            inodes.retain(|inode| again.contains(inode));
+        }
+        result.connections = sorted_inodes(&inodes)
+            .into_iter()
+            .map(|inode| (inode, 0))
+            .collect();
+        result
+    }
+}
+
+fn read_connected_inodes(proc_root: &ProcRoot, names: &[String]) -> HashSet<String> {
+    let body = fs::read_to_string(proc_root.join("net/unix")).unwrap_or_default();
+    if explain::capturing() {
+        let related: String = body
+            .lines()
+            .filter(|line| names.iter().any(|name| line.ends_with(name.as_str())))
+            .map(|line| format!("{line}\n"))
+            .collect();
+        explain::record_raw("net/unix", &related);
+    }
+    parse_connected_inodes(&body, names)
+}
+
+// /proc/net/unix 每行：Num RefCount Protocol Flags Type St Inode Path。
+// X server accept 到的 socket 沿用監聽 socket 的路徑、狀態為 03（已連線），監聽 socket 本身帶 SO_ACCEPTCON。
+// 每條連線在表中只有伺服器端一行，連到不同名稱的連線各算一次
+fn parse_connected_inodes(body: &str, names: &[String]) -> HashSet<String> {
+    body.lines()
+        .skip(1)
+        .filter_map(|line| {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.len() < 8 || !is_x11_socket_name(names, tokens[7]) {
+                return None;
+            }
+            let flags = u32::from_str_radix(tokens[3], 16).unwrap_or(0);
+            (flags & SO_ACCEPTCON == 0 && tokens[5] == "03").then(|| tokens[6].to_string())
+        })
+        .collect()
+}
+
+pub struct FdCounter;
+
+impl ConnectionCounter for FdCounter {
+    fn name(&self) -> &'static str {
+        "fd"
+    }
+
+    // 對端在 getpeername 的當下確認過，嚴格歸屬檢查沒有需要再比對的東西
+    fn count(
+        &mut self,
+        app_pids: &[i32],
+        endpoint: &X11Endpoint,
+        fd_scanner: &mut FdScanner,
+        _strict: bool,
+        mut trace: Option<&mut CountTrace>,
+    ) -> ConnectionCount {
+        let mut result = ConnectionCount {
+            connections: Vec::new(),
+            rejected: 0,
+            unreadable: Vec::new(),
+        };
+        if !matches!(endpoint, X11Endpoint::Unix(_)) {
+            return result;
+        }
+        let names = endpoint.socket_names();
+        // 同一個 inode 出現在多個 PID（fork 後共用）時記在 PID 較小者
+        let mut pids = app_pids.to_vec();
+        pids.sort_unstable();
+        let mut connections: HashMap<String, i32> = HashMap::new();
+        for pid in pids {
+            match x11_socket_fds(&fd_scanner.proc_root, pid, &names) {
+                Ok((sockets, matched)) => {
+                    if let Some(trace) = trace.as_deref_mut() {
+                        trace.socket_inodes.push((pid, sorted_inodes(&sockets)));
+                    }
+                    for inode in matched {
+                        connections.entry(inode).or_insert(pid);
+                    }
+                }
+                Err(err) if privileges::is_permission_error(&err) => result.unreadable.push(pid),
+                // 程序剛結束
+                Err(_) => {}
+            }
+        }
+        result.connections = connections.into_iter().collect();
+        result
+            .connections
+            .sort_by_key(|(inode, _)| inode.parse::<u64>().unwrap_or(u64::MAX));
+        if let Some(trace) = trace {
+            trace.peers = result
+                .connections
+                .iter()
+                .map(|(inode, _)| inode.clone())
+                .collect();
+            trace.candidates = result.connections.clone();
+        }
+        result
+    }
+}
+
+// 回傳（程序的所有 socket inode, 對端是 X socket 的 inode）。
+// pidfd_getfd 需要對該程序的 ptrace 權限：同一使用者且 Yama ptrace_scope 為 0，或 root、CAP_SYS_PTRACE；
+// 權限不足時回傳 EPERM，由呼叫端記為無法讀取
+fn x11_socket_fds(
+    proc_root: &ProcRoot,
+    pid: i32,
+    names: &[String],
+) -> io::Result<(HashSet<String>, HashSet<String>)> {
+    let pidfd = pidfd_open(pid)?;
+    let mut sockets = HashSet::new();
+    let mut matched = HashSet::new();
+    for entry in fs::read_dir(proc_root.pid_entry(pid, "fd"))?.flatten() {
+        let Some(fd) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        else {
+            continue;
+        };
+        let Ok(link) = fs::read_link(entry.path()) else {
+            continue;
+        };
+        let Some(inode) = parse_socket_inode(&link.to_string_lossy()).map(str::to_string) else {
+            continue;
+        };
+        sockets.insert(inode.clone());
+        let copy = match pidfd_getfd(&pidfd, fd) {
+            Ok(value) => value,
+            Err(err) if err.raw_os_error() == Some(libc::EBADF) => continue,
+            Err(err) => return Err(err),
+        };
+        if peer_path(&copy).is_some_and(|peer| is_x11_socket_name(names, &peer)) {
+            matched.insert(inode);
+        }
+    }
+    Ok((sockets, matched))
+}
+
+// 複製其他程序的 fd 到本程序（Linux 5.6+）；只用來查詢後立即關閉，不影響原本的連線
+fn pidfd_getfd(pidfd: &OwnedFd, target: libc::c_int) -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_getfd, pidfd.as_raw_fd(), target, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) })
+}
+
+// 啟動時以本程序的 stdin 試一次：只有 ENOSYS 代表核心不支援，其他錯誤（stdin 已關閉）不影響
+fn probe_pidfd_getfd() -> Result<(), String> {
+    let own = pidfd_open(std::process::id() as i32)
+        .map_err(|err| format!("--connections-from fd 需要 pidfd（{err}）"))?;
+    match pidfd_getfd(&own, 0) {
+        Err(err) if err.raw_os_error() == Some(libc::ENOSYS) => {
+            Err("--connections-from fd 需要 pidfd_getfd，核心版本過舊（Linux 5.6+）".to_string())
+        }
+        _ => Ok(()),
+    }
+}
+
+// unix socket 對端綁定的路徑，抽象 socket 開頭的 NUL 換成 @，與 /proc/net/unix、ss 的寫法相同；
+// 不是 unix socket、未連線或對端沒有名稱時回傳 None
+fn peer_path(socket: &OwnedFd) -> Option<String> {
+    let mut address: libc::sockaddr_un = unsafe { mem::zeroed() };
+    let mut length = mem::size_of::<libc::sockaddr_un>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getpeername(
+            socket.as_raw_fd(),
+            &mut address as *mut _ as *mut libc::sockaddr,
+            &mut length,
+        )
+    };
+    if result != 0 || address.sun_family != libc::AF_UNIX as libc::sa_family_t {
+        return None;
+    }
+    let offset = mem::size_of::<libc::sa_family_t>();
+    let used = (length as usize)
+        .saturating_sub(offset)
+        .min(address.sun_path.len());
+    let bytes: Vec<u8> = address.sun_path[..used]
+        .iter()
+        .map(|byte| *byte as u8)
+        .collect();
+    let (prefix, name) = match bytes.split_first() {
+        Some((0, rest)) => ("@", rest),
+        Some(_) => ("", bytes.split(|byte| *byte == 0).next().unwrap_or(&[])),
+        None => return None,
+    };
+    (!name.is_empty()).then(|| format!("{prefix}{}", String::from_utf8_lossy(name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixListener, UnixStream};
+
+    #[test]
+    fn connected_inodes_skip_the_listener_and_unrelated_paths() {
+        let body = "Num       RefCount Protocol Flags    Type St Inode Path
+0000000000000000: 00000002 00000000 00010000 0001 01 20001 /tmp/.X11-unix/X0
+0000000000000000: 00000003 00000000 00000000 0001 03 20002 /tmp/.X11-unix/X0
+0000000000000000: 00000003 00000000 00000000 0001 03 20003 @/tmp/.X11-unix/X0
+0000000000000000: 00000003 00000000 00000000 0001 03 20004
+0000000000000000: 00000003 00000000 00000000 0001 03 20005 /tmp/.X11-unix/X01
+0000000000000000: 00000002 00000000 00000000 0001 02 20006 /tmp/.X11-unix/X0
+0000000000000000: 00000003 00000000 00000000 0001 03 20007 /run/user/1000/.X11-unix/X0
+0000000000000000: 00000003 00000000 00000000 0001 03 20008 /run/user/1000/.X11-unix/X1
+";
+        let names = crate::x11_socket_names("/tmp/.X11-unix/X0", Some("/run/user/1000".as_ref()));
+        let mut inodes: Vec<String> = parse_connected_inodes(body, &names).into_iter().collect();
+        inodes.sort();
+        assert_eq!(inodes, ["20002", "20003", "20007"]);
+    }
+
+    // 同一條連線：net-unix 看到的是伺服器端，fd 看到的是應用程式端，兩者 inode 不同但條數相同
+    #[test]
+    fn counters_agree_on_a_single_local_connection() {
+        let dir = std::env::temp_dir().join(format!("qq-x11-guard-counter-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("X0").to_string_lossy().into_owned();
+        let listener = UnixListener::bind(&path).unwrap();
+        let client = UnixStream::connect(&path).unwrap();
+        let (_server, _) = listener.accept().unwrap();
+        let endpoint = X11Endpoint::Unix(path.clone());
+        let own = std::process::id() as i32;
+        let mut fd_scanner = FdScanner::new(ProcRoot::new("/proc"));
+
+        let by_fd = FdCounter.count(&[own], &endpoint, &mut fd_scanner, false, None);
+        let link = fs::read_link(format!("/proc/self/fd/{}", client.as_raw_fd())).unwrap();
+        let client_inode = parse_socket_inode(&link.to_string_lossy())
+            .unwrap()
+            .to_string();
+        assert_eq!(by_fd.connections, [(client_inode.clone(), own)]);
+
+        let by_table = NetUnixCounter.count(&[own], &endpoint, &mut fd_scanner, true, None);
+        assert_eq!(by_table.connections.len(), 1);
+        assert_ne!(by_table.connections[0].0, client_inode);
+        assert_eq!(by_table.connections[0].1, 0);
+
+        // 沒有程序時兩者都不計數
+        assert!(FdCounter
+            .count(&[], &endpoint, &mut fd_scanner, false, None)
+            .connections
+            .is_empty());
+        assert!(NetUnixCounter
+            .count(&[], &endpoint, &mut fd_scanner, false, None)
+            .connections
+            .is_empty());
+        drop(client);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // X server 同時在 socket 檔與同名的抽象 socket 上監聽，客戶端各自選了其中一個：
+    // 兩種計數方式都要算到全部的連線，同一條連線也不因比對多個名稱而重複計算
+    #[test]
+    fn connections_split_across_filesystem_and_abstract_sockets_count_once() {
+        let dir =
+            std::env::temp_dir().join(format!("qq-x11-guard-abstract-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("X0").to_string_lossy().into_owned();
+        let listener = UnixListener::bind(&path).unwrap();
+        let abstract_name = SocketAddr::from_abstract_name(path.as_bytes()).unwrap();
+        let abstract_listener = UnixListener::bind_addr(&abstract_name).unwrap();
+        let clients = [
+            UnixStream::connect(&path).unwrap(),
+            UnixStream::connect_addr(&abstract_name).unwrap(),
+            UnixStream::connect_addr(&abstract_name).unwrap(),
+        ];
+        let _servers = [
+            listener.accept().unwrap(),
+            abstract_listener.accept().unwrap(),
+            abstract_listener.accept().unwrap(),
+        ];
+        let endpoint = X11Endpoint::Unix(path.clone());
+        let own = std::process::id() as i32;
+        let mut fd_scanner = FdScanner::new(ProcRoot::new("/proc"));
+
+        let mut client_inodes: Vec<(String, i32)> = clients
+            .iter()
+            .map(|client| {
+                let link = fs::read_link(format!("/proc/self/fd/{}", client.as_raw_fd())).unwrap();
+                let inode = parse_socket_inode(&link.to_string_lossy())
+                    .unwrap()
+                    .to_string();
+                (inode, own)
+            })
+            .collect();
+        client_inodes.sort_by_key(|(inode, _)| inode.parse::<u64>().unwrap());
+        // 同一個程序列兩次，如同 fork 後共用 fd 的兩個程序
+        let by_fd = FdCounter.count(&[own, own], &endpoint, &mut fd_scanner, false, None);
+        assert_eq!(by_fd.connections, client_inodes);
+
+        let by_table = NetUnixCounter.count(&[own], &endpoint, &mut fd_scanner, true, None);
+        assert_eq!(by_table.connections.len(), 3);
+        assert_eq!(by_table.rejected, 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+// --crash-loop：本程式重啟後不久程式就自己結束（例如 SIGKILL 弄壞了快取），再重啟只會重複同樣的事。
+// 記下每次重啟帶起的那一代程序（PID 與啟動時間），其中有程序在 uptime 內結束就算一次快速結束；
+// window 內達 exits 次時進入崩潰循環，不再自動重啟，直到控制 socket 的 clear 或 backoff 期滿
+pub struct Settings {
+    pub exits: usize,
+    pub window: Duration,
+    pub uptime: Duration,
+    pub backoff: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exit {
+    pub pid: i32,
+    // 從重啟驗證完成到發現結束的時間
+    pub uptime: Duration,
+    pub at: Instant,
+}
+
+impl Exit {
+    pub fn text(&self) -> String {
+        format!("PID {}（執行 {} 秒）", self.pid, self.uptime.as_secs())
+    }
+}
+
+#[derive(Default)]
+pub struct Detector {
+    // 本程式帶起、還沒結束的程序：(PID, 啟動時間)
+    launched: HashSet<(i32, u64)>,
+    launched_at: Option<Instant>,
+    exits: VecDeque<Exit>,
+    pub looping_since: Option<Instant>,
+}
+
+impl Detector {
+    // 重啟驗證完成時記下新的一代；再次重啟前由呼叫端 forget，本程式關閉的程序不算
+    pub fn launched(&mut self, identities: HashSet<(i32, u64)>, now: Instant) {
+        self.launched = identities;
+        self.launched_at = Some(now);
+    }
+
+    pub fn forget(&mut self) {
+        self.launched.clear();
+        self.launched_at = None;
+    }
+
+    // alive 為目前程序的 (PID, 啟動時間)。回傳進入崩潰循環時視窗內的快速結束記錄
+    pub fn observe(
+        &mut self,
+        settings: &Settings,
+        alive: &HashSet<(i32, u64)>,
+        now: Instant,
+    ) -> Option<Vec<Exit>> {
+        let launched_at = self.launched_at?;
+        let gone: Vec<(i32, u64)> = self.launched.difference(alive).copied().collect();
+        for identity in &gone {
+            self.launched.remove(identity);
+        }
+        let uptime = now.duration_since(launched_at);
+        if self.launched.is_empty() {
+            self.launched_at = None;
+        }
+        if gone.is_empty() || uptime > settings.uptime {
+            return None;
+        }
+        // 同一代的多個程序一起結束只算一次，以 PID 最小的為代表
+        let pid = gone.iter().map(|(pid, _)| *pid).min().unwrap_or_default();
+        self.exits.push_back(Exit {
+            pid,
+            uptime,
+            at: now,
+        });
+        self.prune(settings, now);
+        if self.looping_since.is_some() || self.exits.len() < settings.exits {
+            return None;
+        }
+        self.looping_since = Some(now);
+        Some(self.exits.iter().cloned().collect())
+    }
+
+    fn prune(&mut self, settings: &Settings, now: Instant) {
+        while self
+            .exits
+            .front()
+            .is_some_and(|exit| now.duration_since(exit.at) > settings.window)
+        {
+            self.exits.pop_front();
+        }
+    }
+
+    // backoff 期滿時解除，回傳崩潰循環持續的時間
+    pub fn expire(&mut self, settings: &Settings, now: Instant) -> Option<Duration> {
+        let since = self.looping_since?;
+        let lasted = now.duration_since(since);
+        if settings.backoff.is_none_or(|backoff| lasted < backoff) {
+            return None;
+        }
+        self.reset();
+        Some(lasted)
+    }
+
+    // 控制 socket 的 clear；快速結束的記錄一併清除，免得下一次結束又立刻進入
+    pub fn clear(&mut self) -> Option<Duration> {
+        let since = self.looping_since?;
+        self.reset();
+        Some(since.elapsed())
+    }
+
+    fn reset(&mut self) {
+        self.looping_since = None;
+        self.exits.clear();
+    }
+
+    // 視窗內的快速結束
+    pub fn recent(&self, settings: &Settings, now: Instant) -> Vec<&Exit> {
+        self.exits
+            .iter()
+            .filter(|exit| now.duration_since(exit.at) <= settings.window)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rapid_exits_after_launches_enter_a_loop_until_backoff() {
+        let settings = Settings {
+            exits: 3,
+            window: Duration::from_secs(600),
+            uptime: Duration::from_secs(60),
+            backoff: Some(Duration::from_secs(1800)),
+        };
+        let mut detector = Detector::default();
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+        let generation = |pid: i32| HashSet::from([(pid, 1000 + pid as u64), (pid + 1, 1000)]);
+        let none = HashSet::new();
+
+        // 沒有本程式帶起的程序時，程序消失不算
+        assert_eq!(detector.observe(&settings, &none, at(0)), None);
+
+        detector.launched(generation(100), at(0));
+        // 同一代仍在執行
+        assert_eq!(detector.observe(&settings, &generation(100), at(10)), None);
+        // 兩個程序一起結束只算一次
+        assert_eq!(detector.observe(&settings, &none, at(20)), None);
+        assert_eq!(detector.recent(&settings, at(20)).len(), 1);
+
+        // 執行超過 uptime 才結束的不算
+        detector.launched(generation(200), at(100));
+        assert_eq!(detector.observe(&settings, &none, at(200)), None);
+        assert_eq!(detector.recent(&settings, at(200)).len(), 1);
+
+        // 本程式再次重啟前 forget，關閉舊程序不算
+        detector.launched(generation(300), at(300));
+        detector.forget();
+        assert_eq!(detector.observe(&settings, &none, at(301)), None);
+
+        detector.launched(generation(400), at(400));
+        assert_eq!(detector.observe(&settings, &none, at(430)), None);
+        detector.launched(generation(500), at(500));
+        let evidence = detector.observe(&settings, &none, at(505)).unwrap();
+        assert_eq!(
+            evidence.iter().map(Exit::text).collect::<Vec<_>>(),
+            vec![
+                "PID 100（執行 20 秒）",
+                "PID 400（執行 30 秒）",
+                "PID 500（執行 5 秒）"
+            ]
+        );
+        assert_eq!(detector.looping_since, Some(at(505)));
+
+        assert_eq!(detector.expire(&settings, at(2000)), None);
+        assert_eq!(
+            detector.expire(&settings, at(2305)),
+            Some(Duration::from_secs(1800))
+        );
+        assert!(detector.recent(&settings, at(2305)).is_empty());
+    }
+}
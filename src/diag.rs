@@ -0,0 +1,345 @@
+use std::collections::HashSet;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+// 直接向核心的 sock_diag 查詢 unix socket，取代呼叫 ss；
+// socket 在初始化時就開好並常駐，seccomp 啟用後只需要 sendto/recvfrom。
+const NETLINK_SOCK_DIAG: i32 = 4;
+const SOCK_DIAG_BY_FAMILY: u16 = 20;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_DUMP: u16 = 0x300;
+
+const UDIAG_SHOW_NAME: u32 = 0x1;
+const UDIAG_SHOW_PEER: u32 = 0x4;
+const UNIX_DIAG_NAME: u16 = 0;
+const UNIX_DIAG_PEER: u16 = 2;
+
+const NLMSG_HEADER_LEN: usize = 16;
+const UNIX_DIAG_MSG_LEN: usize = 16;
+
+pub struct DiagSocket {
+    fd: OwnedFd,
+    seq: u32,
+}
+
+impl DiagSocket {
+    pub fn open() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                NETLINK_SOCK_DIAG,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            seq: 0,
+        })
+    }
+
+    // 與對每個名稱執行 ss -xH src <name> 相同：列出名稱為 X socket 之一且有 peer 的 socket，回傳對端 inode
+    pub fn peer_inodes(&mut self, names: &[String]) -> io::Result<HashSet<String>> {
+        self.seq = self.seq.wrapping_add(1);
+        let request = encode_dump_request(self.seq);
+        let sent = unsafe {
+            libc::send(
+                self.fd.as_raw_fd(),
+                request.as_ptr().cast(),
+                request.len(),
+                0,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        crate::explain::record_raw("sock_diag", "");
+
+        let mut inodes = HashSet::new();
+        let mut buffer = vec![0u8; 32768];
+        loop {
+            let received = unsafe {
+                libc::recv(
+                    self.fd.as_raw_fd(),
+                    buffer.as_mut_ptr().cast(),
+                    buffer.len(),
+                    0,
+                )
+            };
+            if received < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(error);
+            }
+            let done = parse_dump(&buffer[..received as usize], self.seq, names, &mut inodes)?;
+            if done {
+                return Ok(inodes);
+            }
+        }
+    }
+}
+
+fn encode_dump_request(seq: u32) -> Vec<u8> {
+    let total = NLMSG_HEADER_LEN + 24;
+    let mut request = Vec::with_capacity(total);
+    request.extend_from_slice(&(total as u32).to_ne_bytes());
+    request.extend_from_slice(&SOCK_DIAG_BY_FAMILY.to_ne_bytes());
+    request.extend_from_slice(&(NLM_F_REQUEST | NLM_F_DUMP).to_ne_bytes());
+    request.extend_from_slice(&seq.to_ne_bytes());
+    request.extend_from_slice(&0u32.to_ne_bytes());
+    // struct unix_diag_req
+    request.push(libc::AF_UNIX as u8);
+    request.push(0);
+    request.extend_from_slice(&0u16.to_ne_bytes());
+    request.extend_from_slice(&u32::MAX.to_ne_bytes());
+    request.extend_from_slice(&0u32.to_ne_bytes());
+    request.extend_from_slice(&(UDIAG_SHOW_NAME | UDIAG_SHOW_PEER).to_ne_bytes());
+    request.extend_from_slice(&[0u8; 8]);
+    request
+}
+
+fn align4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_ne_bytes(
+        bytes.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_ne_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "sock_diag 回應格式錯誤")
+}
+
+// 解析一次 recv 收到的所有 netlink 訊息；收到 NLMSG_DONE 時回傳 true
+fn parse_dump(
+    buffer: &[u8],
+    seq: u32,
+    names: &[String],
+    inodes: &mut HashSet<String>,
+) -> io::Result<bool> {
+    let mut offset = 0;
+    while offset + NLMSG_HEADER_LEN <= buffer.len() {
+        let length = read_u32(buffer, offset).ok_or_else(malformed)? as usize;
+        let kind = read_u16(buffer, offset + 4).ok_or_else(malformed)?;
+        let message_seq = read_u32(buffer, offset + 8).ok_or_else(malformed)?;
+        if length < NLMSG_HEADER_LEN || offset + length > buffer.len() {
+            return Err(malformed());
+        }
+        let payload = &buffer[offset + NLMSG_HEADER_LEN..offset + length];
+        offset += align4(length);
+        if message_seq != seq {
+            continue;
+        }
+        match kind {
+            NLMSG_DONE => return Ok(true),
+            NLMSG_ERROR => {
+                let code = read_u32(payload, 0).ok_or_else(malformed)? as i32;
+                return Err(io::Error::from_raw_os_error(-code));
+            }
+            SOCK_DIAG_BY_FAMILY => {
+                if let Some(peer) = parse_unix_diag_msg(payload, names) {
+                    // 完整的 dump 含所有 unix socket，explain 只記錄比對到的：X server 端的 inode 與對端
+                    if crate::explain::capturing() {
+                        let inode = read_u32(payload, 4).unwrap_or(0);
+                        crate::explain::append_raw(&format!("ino={inode} peer={peer}\n"));
+                    }
+                    inodes.insert(peer.to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(false)
+}
+
+fn parse_unix_diag_msg(payload: &[u8], names: &[String]) -> Option<u32> {
+    let mut name_matches = false;
+    let mut peer = None;
+    let mut offset = UNIX_DIAG_MSG_LEN;
+    while offset + 4 <= payload.len() {
+        let length = read_u16(payload, offset)? as usize;
+        let kind = read_u16(payload, offset + 2)?;
+        if length < 4 || offset + length > payload.len() {
+            return None;
+        }
+        let value = &payload[offset + 4..offset + length];
+        match kind {
+            UNIX_DIAG_NAME => name_matches = name_is_socket_name(value, names),
+            UNIX_DIAG_PEER => peer = read_u32(value, 0),
+            _ => {}
+        }
+        offset += align4(length);
+    }
+    if name_matches {
+        peer.filter(|inode| *inode != 0)
+    } else {
+        None
+    }
+}
+
+// 抽象名稱以 NUL 開頭，對應 ss 顯示的 @path；一般路徑可能帶結尾 NUL
+fn name_is_socket_name(name: &[u8], names: &[String]) -> bool {
+    let name = name.strip_suffix(&[0]).unwrap_or(name);
+    let name = match name.strip_prefix(&[0]) {
+        Some(rest) => [b"@", rest].concat(),
+        None => name.to_vec(),
+    };
+    names.iter().any(|candidate| candidate.as_bytes() == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(path: &str) -> Vec<String> {
+        crate::x11_socket_names(path, None)
+    }
+
+    // 以 x86_64 核心實際回應的排列方式建構：nlmsghdr + unix_diag_msg + rtattr
+    fn diag_message(seq: u32, inode: u32, name: &[u8], peer: Option<u32>) -> Vec<u8> {
+        let mut attrs = Vec::new();
+        attrs.extend_from_slice(&((4 + name.len()) as u16).to_ne_bytes());
+        attrs.extend_from_slice(&UNIX_DIAG_NAME.to_ne_bytes());
+        attrs.extend_from_slice(name);
+        attrs.resize(align4(attrs.len()), 0);
+        if let Some(peer) = peer {
+            attrs.extend_from_slice(&8u16.to_ne_bytes());
+            attrs.extend_from_slice(&UNIX_DIAG_PEER.to_ne_bytes());
+            attrs.extend_from_slice(&peer.to_ne_bytes());
+        }
+        let length = NLMSG_HEADER_LEN + UNIX_DIAG_MSG_LEN + attrs.len();
+        let mut message = Vec::new();
+        message.extend_from_slice(&(length as u32).to_ne_bytes());
+        message.extend_from_slice(&SOCK_DIAG_BY_FAMILY.to_ne_bytes());
+        message.extend_from_slice(&0x2u16.to_ne_bytes());
+        message.extend_from_slice(&seq.to_ne_bytes());
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        message.extend_from_slice(&[1, 1, 1, 0]);
+        message.extend_from_slice(&inode.to_ne_bytes());
+        message.extend_from_slice(&[0xff; 8]);
+        message.extend_from_slice(&attrs);
+        message
+    }
+
+    fn control_message(kind: u16, seq: u32, payload: &[u8]) -> Vec<u8> {
+        let mut message = Vec::new();
+        message.extend_from_slice(&((NLMSG_HEADER_LEN + payload.len()) as u32).to_ne_bytes());
+        message.extend_from_slice(&kind.to_ne_bytes());
+        message.extend_from_slice(&0x2u16.to_ne_bytes());
+        message.extend_from_slice(&seq.to_ne_bytes());
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        message.extend_from_slice(payload);
+        message
+    }
+
+    #[test]
+    fn parse_dump_keeps_peers_of_x11_socket_only() {
+        let path = &names("/tmp/.X11-unix/X0");
+        let mut buffer = Vec::new();
+        // 監聽 socket 本身沒有 peer
+        buffer.extend(diag_message(7, 100, b"/tmp/.X11-unix/X0", None));
+        buffer.extend(diag_message(7, 101, b"/tmp/.X11-unix/X0", Some(201)));
+        buffer.extend(diag_message(7, 102, b"\0/tmp/.X11-unix/X0", Some(202)));
+        buffer.extend(diag_message(7, 103, b"/tmp/.X11-unix/X1", Some(203)));
+        buffer.extend(diag_message(
+            7,
+            104,
+            b"/run/dbus/system_bus_socket",
+            Some(204),
+        ));
+        // 其他序號的殘留回應應被忽略
+        buffer.extend(diag_message(6, 105, b"/tmp/.X11-unix/X0", Some(205)));
+
+        let mut inodes = HashSet::new();
+        let done = parse_dump(&buffer, 7, path, &mut inodes).expect("可解析");
+        assert!(!done);
+        let expected: HashSet<String> = ["201", "202"].iter().map(|v| v.to_string()).collect();
+        assert_eq!(inodes, expected);
+
+        let done = parse_dump(
+            &control_message(NLMSG_DONE, 7, &[0; 4]),
+            7,
+            path,
+            &mut inodes,
+        )
+        .expect("可解析");
+        assert!(done);
+    }
+
+    fn from_hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&text[index..index + 2], 16).unwrap())
+            .collect()
+    }
+
+    // 在 x86_64 核心上對 /tmp/.X11-unix/X99 擷取的原始回應：監聽 socket（只有 NAME 與 SHUTDOWN）、
+    // 兩條已接受的連線（peer 7929、7918），最後是 NLMSG_DONE
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn parse_dump_reads_captured_kernel_response() {
+        let captured = [
+            "4000000014000200010000004027000001010a00af1e00004d00000000000000\
+             170000002f746d702f2e5831312d756e69782f58393900000500060000000000",
+            "4800000014000200010000004027000001010100f81e00001100000000000000\
+             170000002f746d702f2e5831312d756e69782f583939000008000200f91e0000\
+             0500060000000000",
+            "4800000014000200010000004027000001010100f21e00001200000000000000\
+             170000002f746d702f2e5831312d756e69782f583939000008000200ee1e0000\
+             0500060000000000",
+            "1400000003000200010000004027000000000000",
+        ];
+        let buffer: Vec<u8> = captured.iter().flat_map(|text| from_hex(text)).collect();
+        let mut inodes = HashSet::new();
+        let done =
+            parse_dump(&buffer, 1, &names("/tmp/.X11-unix/X99"), &mut inodes).expect("可解析");
+        assert!(done);
+        let expected: HashSet<String> = ["7929", "7918"].iter().map(|v| v.to_string()).collect();
+        assert_eq!(inodes, expected);
+    }
+
+    #[test]
+    fn parse_dump_reports_kernel_errors_and_truncation() {
+        let mut inodes = HashSet::new();
+        let error = control_message(NLMSG_ERROR, 3, &(-libc::EPERM).to_ne_bytes());
+        let result = parse_dump(&error, 3, &names("/tmp/.X11-unix/X0"), &mut inodes);
+        assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EPERM));
+
+        let message = diag_message(3, 101, b"/tmp/.X11-unix/X0", Some(201));
+        let result = parse_dump(
+            &message[..message.len() - 2],
+            3,
+            &names("/tmp/.X11-unix/X0"),
+            &mut inodes,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn dump_request_matches_kernel_layout() {
+        let request = encode_dump_request(9);
+        assert_eq!(request.len(), 40);
+        assert_eq!(read_u32(&request, 0), Some(40));
+        assert_eq!(read_u16(&request, 4), Some(SOCK_DIAG_BY_FAMILY));
+        assert_eq!(read_u32(&request, 8), Some(9));
+        assert_eq!(request[16], libc::AF_UNIX as u8);
+        assert_eq!(
+            read_u32(&request, 28),
+            Some(UDIAG_SHOW_NAME | UDIAG_SHOW_PEER)
+        );
+    }
+}
@@ -0,0 +1,505 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::{
+    active_reason, idle_text, json_object, json_string, matches_by_cmdline_only, measure_activity,
+    name_patterns, process_name, read_cmdline_args, session_idle, system_overload, xprobe,
+    ActionKind, CountTrace, EscalationCondition, Guard, DEFAULT_X_PROBE_FAILURES,
+};
+
+// explain：以守護程序本身的程式路徑量測一次，列出計數的每個階段，用來追查「數字對不上」：
+// 比對到哪些程序與依據、各程序的 socket inode、後端回報的 X socket 對端（附原始輸出）、
+// 兩者的交集與最後的計數，以及這個數字會不會觸發設定的各項門檻。
+// 後端在取得原始輸出時呼叫 record_raw / append_raw，只有 explain 執行期間會保留
+thread_local! {
+    static RAW_OUTPUT: RefCell<Option<Vec<(String, String)>>> = const { RefCell::new(None) };
+}
+
+pub fn capturing() -> bool {
+    RAW_OUTPUT.with(|raw| raw.borrow().is_some())
+}
+
+// 每次查詢各記一筆：嚴格歸屬檢查會再查一次，兩次的輸出分開列出
+pub fn record_raw(source: &str, body: &str) {
+    RAW_OUTPUT.with(|raw| {
+        if let Some(outputs) = raw.borrow_mut().as_mut() {
+            outputs.push((source.to_string(), body.to_string()));
+        }
+    });
+}
+
+// 接在最近一筆之後，給逐則訊息解析的 sock_diag 使用
+#[cfg(feature = "backend-diag")]
+pub fn append_raw(body: &str) {
+    RAW_OUTPUT.with(|raw| {
+        if let Some((_, text)) = raw
+            .borrow_mut()
+            .as_mut()
+            .and_then(|outputs| outputs.last_mut())
+        {
+            text.push_str(body);
+        }
+    });
+}
+
+struct Verdict {
+    name: String,
+    // None 表示單次量測無法判斷（需要歷史資料）
+    fired: Option<bool>,
+    detail: String,
+}
+
+struct MatchedProcess {
+    pid: i32,
+    name: String,
+    cmdline: Vec<String>,
+}
+
+pub fn run(guard: &mut Guard, json: bool) -> String {
+    let config = guard.config.clone();
+    let pids = guard.app_pids();
+    let mut excluded: Vec<i32> = guard.excluded_x_server.iter().copied().collect();
+    excluded.sort_unstable();
+    let matched: Vec<MatchedProcess> = pids
+        .iter()
+        .map(|pid| MatchedProcess {
+            pid: *pid,
+            name: process_name(&config.proc_root, *pid).unwrap_or_default(),
+            cmdline: read_cmdline_args(&config.proc_root, *pid).unwrap_or_default(),
+        })
+        .collect();
+
+    RAW_OUTPUT.with(|raw| *raw.borrow_mut() = Some(Vec::new()));
+    guard.count_trace = Some(CountTrace::default());
+    let counted = guard.count_connections(&pids);
+    let trace = guard.count_trace.take().unwrap_or_default();
+    let raw = RAW_OUTPUT.with(|raw| raw.borrow_mut().take().unwrap_or_default());
+    let counted_toward_threshold = guard.threshold_count(&counted.connections);
+    let mut per_pid: BTreeMap<i32, usize> = pids.iter().map(|pid| (*pid, 0)).collect();
+    for (_, pid) in &counted.connections {
+        *per_pid.entry(*pid).or_default() += 1;
+    }
+    let offender = guard.per_process_offender(&pids, &counted.connections);
+    let verdicts = verdicts(guard, &pids, counted_toward_threshold, offender);
+    let strict = guard.strict_attribution();
+    let elapsed_ms = guard.measure.last.unwrap_or_default().as_secs_f64() * 1000.0;
+
+    if json {
+        let list = |items: Vec<String>| format!("[{}]", items.join(","));
+        let strings = |items: &[String]| list(items.iter().map(|item| json_string(item)).collect());
+        return json_object(&[
+            ("app", json_string(&config.app_name)),
+            ("display", json_string(&config.display)),
+            ("endpoint", json_string(&guard.x11_endpoint.to_string())),
+            ("backend", json_string(guard.backend.name())),
+            ("strict_attribution", strict.to_string()),
+            (
+                "matched",
+                list(
+                    matched
+                        .iter()
+                        .map(|process| {
+                            json_object(&[
+                                ("pid", process.pid.to_string()),
+                                ("name", json_string(&process.name)),
+                                ("cmdline", strings(&process.cmdline)),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                "excluded_x_server",
+                list(excluded.iter().map(i32::to_string).collect()),
+            ),
+            ("peers", strings(&trace.peers)),
+            (
+                "raw",
+                list(
+                    raw.iter()
+                        .map(|(source, output)| {
+                            json_object(&[
+                                ("source", json_string(source)),
+                                ("output", json_string(output)),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                "socket_inodes",
+                list(
+                    trace
+                        .socket_inodes
+                        .iter()
+                        .map(|(pid, inodes)| {
+                            json_object(&[("pid", pid.to_string()), ("inodes", strings(inodes))])
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                "intersection",
+                list(
+                    trace
+                        .candidates
+                        .iter()
+                        .map(|(inode, pid)| {
+                            json_object(&[("inode", json_string(inode)), ("pid", pid.to_string())])
+                        })
+                        .collect(),
+                ),
+            ),
+            ("rejected", strings(&trace.rejected)),
+            ("intra_app", strings(&trace.intra_app)),
+            (
+                "unreadable",
+                list(counted.unreadable.iter().map(i32::to_string).collect()),
+            ),
+            (
+                "per_pid",
+                list(
+                    per_pid
+                        .iter()
+                        .map(|(pid, count)| {
+                            json_object(&[
+                                ("pid", pid.to_string()),
+                                ("connections", count.to_string()),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            ("connections", counted.connections.len().to_string()),
+            ("counted", counted_toward_threshold.to_string()),
+            (
+                "thresholds",
+                list(
+                    verdicts
+                        .iter()
+                        .map(|verdict| {
+                            json_object(&[
+                                ("name", json_string(&verdict.name)),
+                                (
+                                    "fired",
+                                    verdict
+                                        .fired
+                                        .map_or("null".to_string(), |fired| fired.to_string()),
+                                ),
+                                ("detail", json_string(&verdict.detail)),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            ("measure_ms", format!("{elapsed_ms:.1}")),
+        ]) + "\n";
+    }
+
+    let mut lines = Vec::new();
+    if matches_by_cmdline_only(&config.cmdline_match) {
+        lines.push(format!(
+            "[1] 比對程序：不看名稱，命令列符合 {} 項條件（含 --match-cmdline）",
+            config.cmdline_match.len()
+        ));
+    } else {
+        let patterns: Vec<String> = name_patterns(&config.cmdline_match)
+            .map(|pattern| pattern.source().to_string())
+            .collect();
+        let conditions = config.cmdline_match.len() - patterns.len();
+        let conditions = if conditions == 0 {
+            String::new()
+        } else {
+            format!("，且命令列符合 {conditions} 項條件")
+        };
+        let names = if patterns.is_empty() {
+            format!("名稱為 {}", guard.app_names.join("、"))
+        } else {
+            format!("名稱符合 --app-name-regex {}", patterns.join("、"))
+        };
+        lines.push(format!("[1] 比對程序：{names}{conditions}"));
+    }
+    for process in &matched {
+        lines.push(format!(
+            "  PID {}  名稱 {}  命令列: {}",
+            process.pid,
+            process.name,
+            if process.cmdline.is_empty() {
+                "（讀不到）".to_string()
+            } else {
+                process.cmdline.join(" ")
+            }
+        ));
+    }
+    for pid in &excluded {
+        lines.push(format!(
+            "  PID {pid}  名稱相符但可能是 X server（持有 X11 監聽 socket，或無法確認），排除"
+        ));
+    }
+    if matched.is_empty() {
+        lines.push(format!("  找不到 {} 的程序，不再往下量測", config.app_name));
+    } else {
+        lines.push(format!(
+            "[2] 後端 {} 回報連到 {} 的對端 inode（{} 個）",
+            guard.backend.name(),
+            guard.x11_endpoint,
+            trace.peers.len()
+        ));
+        if !trace.peers.is_empty() {
+            lines.push(format!("  {}", trace.peers.join(" ")));
+        }
+        for (index, (source, output)) in raw.iter().enumerate() {
+            // 嚴格歸屬檢查會以同樣的來源再查一次
+            let repeat = raw[..index]
+                .iter()
+                .filter(|(name, _)| name == source)
+                .count();
+            if repeat == 0 {
+                lines.push(format!("  原始輸出 {source}:"));
+            } else {
+                lines.push(format!("  原始輸出 {source}（第 {} 次查詢）:", repeat + 1));
+            }
+            if output.trim().is_empty() {
+                lines.push("    （空）".to_string());
+            }
+            lines.extend(output.lines().map(|line| format!("    {line}")));
+        }
+
+        lines.push("[3] 各程序持有的 socket inode".to_string());
+        if trace.socket_inodes.is_empty() {
+            lines.push(if guard.backend.name() == "net-unix" {
+                "  net-unix 只讀 /proc/net/unix，不掃描 fd，連線無法歸屬到程序（記為 PID 0）"
+                    .to_string()
+            } else {
+                "  後端沒有回報任何對端，不掃描 fd".to_string()
+            });
+        }
+        for (pid, inodes) in &trace.socket_inodes {
+            let note = if counted.unreadable.contains(pid) {
+                "（fd 無法讀取）"
+            } else {
+                ""
+            };
+            lines.push(format!(
+                "  PID {pid}（{} 個）{note}: {}",
+                inodes.len(),
+                inodes.join(" ")
+            ));
+        }
+
+        lines.push(format!(
+            "[4] 交集：應用程式端 inode → PID（{} 條）",
+            trace.candidates.len()
+        ));
+        for (inode, pid) in &trace.candidates {
+            lines.push(format!("  {inode} → PID {pid}"));
+        }
+        lines.push(format!(
+            "  嚴格歸屬檢查: {}",
+            match (strict, trace.rejected.is_empty()) {
+                (false, _) => "關閉".to_string(),
+                (true, true) => "開啟，沒有剔除".to_string(),
+                (true, false) => format!("開啟，剔除 {}", trace.rejected.join(" ")),
+            }
+        ));
+        lines.push(format!(
+            "  排除應用程式內部連線: {}",
+            match (config.exclude_intra_app, trace.intra_app.is_empty()) {
+                (false, _) => "關閉".to_string(),
+                (true, true) => "開啟，沒有排除".to_string(),
+                (true, false) => format!("開啟，排除 {}", trace.intra_app.join(" ")),
+            }
+        ));
+    }
+
+    lines.push("[5] 計數".to_string());
+    lines.extend(
+        per_pid
+            .iter()
+            .map(|(pid, count)| format!("  PID {pid}: {count} 條")),
+    );
+    lines.push(format!(
+        "  合計 {} 條，計入門檻 {} 條（量測耗時 {elapsed_ms:.1} ms）",
+        counted.connections.len(),
+        counted_toward_threshold
+    ));
+    if !counted.unreadable.is_empty() {
+        lines.push(format!(
+            "  無法讀取（未計入）: PID {:?}",
+            counted.unreadable
+        ));
+    }
+
+    lines.push("[6] 門檻".to_string());
+    for verdict in &verdicts {
+        let state = match verdict.fired {
+            Some(true) => "觸發",
+            Some(false) => "未觸發",
+            None => "無法判斷",
+        };
+        lines.push(format!("  [{state}] {}：{}", verdict.name, verdict.detail));
+    }
+    lines.join("\n") + "\n"
+}
+
+// offender 為 --threshold-scope per-process 時連線最多的程序，--threshold 與警示的百分比改以它對照
+fn verdicts(
+    guard: &mut Guard,
+    pids: &[i32],
+    count: usize,
+    offender: Option<(i32, usize)>,
+) -> Vec<Verdict> {
+    let config = guard.config.clone();
+    let threshold = config.threshold;
+    let gauge = offender.map_or(count, |(_, busiest)| busiest);
+    let over = gauge > threshold;
+    let action = match config.action {
+        ActionKind::Restart => "重啟",
+        ActionKind::Reload => "重新載入",
+    };
+    let counted = match offender {
+        Some((pid, busiest)) => {
+            format!("連線最多的程序 PID {pid} 計入門檻 {busiest} 條（合計 {count} 條）")
+        }
+        None => format!("計入門檻 {count} 條"),
+    };
+    let mut verdicts = vec![Verdict {
+        name: "--threshold".to_string(),
+        fired: Some(over),
+        detail: format!("{counted}，門檻 {threshold}，超過時{action}"),
+    }];
+    if let Some(min_age) = config.min_connection_age_seconds {
+        verdicts[0].detail +=
+            &format!("；--min-connection-age {min_age}：單次量測的連線都視為剛出現，不計入");
+    }
+    // --rule 以與守護程序相同的指標評估；churn 需要一分鐘的資料，單次量測一律未知
+    let metrics = guard.sample_metrics(pids, count);
+    for rule in &guard.rules[1..] {
+        verdicts.push(Verdict {
+            name: format!("規則 {}", rule.name),
+            fired: Some(rule.matches(&metrics)),
+            detail: format!(
+                "{}（{}），成立時 {}",
+                rule.expression,
+                rule.values_text(&metrics),
+                rule.action.name()
+            ),
+        });
+    }
+    for level in &config.escalation {
+        let (fired, detail) = match level.condition {
+            EscalationCondition::PercentOfThreshold(percent) => (
+                Some(gauge * 100 >= threshold * percent as usize),
+                format!("門檻的 {percent}%"),
+            ),
+            EscalationCondition::ConsecutiveBreaches(needed) => (
+                Some(over && needed <= 1),
+                format!("連續 {needed} 次超標，單次量測最多算 1 次"),
+            ),
+            EscalationCondition::BreakerOpen => {
+                (Some(guard.breaker_open()), "熔斷開啟".to_string())
+            }
+            EscalationCondition::Churn => (None, "連線變動率需要持續一分鐘的量測".to_string()),
+            EscalationCondition::Forecast => (None, "成長趨勢需要多次量測".to_string()),
+            EscalationCondition::Flapping => (None, "跨越門檻的次數需要多次量測".to_string()),
+        };
+        verdicts.push(Verdict {
+            name: format!("警示等級 {}", level.name),
+            fired,
+            detail,
+        });
+    }
+    if let Some(seconds) = config.deadline_restart_seconds {
+        verdicts.push(Verdict {
+            name: "--deadline-restart".to_string(),
+            fired: None,
+            detail: format!("需要連續超標 {seconds} 秒"),
+        });
+    }
+    if config.max_load.is_some() || config.max_cpu_pressure.is_some() {
+        let (fired, detail) = match system_overload(&config) {
+            Ok(Some(reason)) => (Some(true), format!("{reason}，超標時延後動作")),
+            Ok(None) => (Some(false), "系統負載在上限內".to_string()),
+            Err(error) => (None, error),
+        };
+        verdicts.push(Verdict {
+            name: "系統負載".to_string(),
+            fired,
+            detail,
+        });
+    }
+    if config.defer_while_active {
+        let activity = measure_activity(&config, &guard.x11_endpoint, pids);
+        let (fired, detail) = match active_reason(&config, &activity) {
+            Some(reason) => (Some(true), format!("{reason}，超標時延後動作")),
+            None => (Some(false), format!("{}，不在使用中", activity.text())),
+        };
+        verdicts.push(Verdict {
+            name: "使用中".to_string(),
+            fired,
+            detail,
+        });
+    }
+    if let Some(needed) = config.only_when_idle_seconds {
+        let reading = session_idle(&config, &guard.x11_endpoint, guard.app_session);
+        let fired = match &reading {
+            Ok((idle, _)) => Some(idle.as_secs() < needed),
+            Err(_) => None,
+        };
+        verdicts.push(Verdict {
+            name: "--only-when-idle".to_string(),
+            fired,
+            detail: format!("{}，未達 {needed} 秒時保留動作", idle_text(&reading)),
+        });
+    }
+    if config.x_probe_seconds.is_some() {
+        let failures = config.x_probe_failures.unwrap_or(DEFAULT_X_PROBE_FAILURES);
+        let (fired, detail) = match xprobe::probe(&guard.x11_endpoint, xprobe::TIMEOUT) {
+            Ok(elapsed) => (
+                Some(false),
+                format!("X server 在 {} ms 內回應", elapsed.as_millis()),
+            ),
+            Err(error) => (
+                Some(failures <= 1),
+                format!("探測失敗（{error}），連續 {failures} 次才暫緩動作"),
+            ),
+        };
+        verdicts.push(Verdict {
+            name: "--x-probe".to_string(),
+            fired,
+            detail,
+        });
+    }
+    verdicts
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "backend-diag")]
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "backend-diag")]
+    fn raw_output_is_kept_only_while_capturing() {
+        record_raw("ss", "ignored\n");
+        assert!(!capturing());
+        RAW_OUTPUT.with(|raw| *raw.borrow_mut() = Some(Vec::new()));
+        record_raw("sock_diag", "");
+        append_raw("ino=1 peer=2\n");
+        append_raw("ino=1 peer=3\n");
+        record_raw("sock_diag", "");
+        let raw = RAW_OUTPUT.with(|raw| raw.borrow_mut().take().unwrap());
+        assert_eq!(
+            raw,
+            vec![
+                (
+                    "sock_diag".to_string(),
+                    "ino=1 peer=2\nino=1 peer=3\n".to_string()
+                ),
+                ("sock_diag".to_string(), String::new()),
+            ]
+        );
+        append_raw("ignored\n");
+        assert!(!capturing());
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// --flap-crossings：連線數在門檻上下來回跳動時，每次重啟只會讓情況更糟。
+// 一段時間內跨越門檻（超標與回到門檻內各算一次）達上限時進入 flapping 狀態，暫停自動動作；
+// 之後連線數要連續 dwell 的時間不超過解除值，或經控制 socket clear，才恢復
+pub struct Settings {
+    pub crossings: usize,
+    pub window: Duration,
+    pub clear_at: usize,
+    pub dwell: Duration,
+}
+
+#[derive(Default)]
+pub struct Detector {
+    last_over: Option<bool>,
+    crossings: VecDeque<Instant>,
+    pub flapping_since: Option<Instant>,
+    // flapping 期間連線數不超過解除值的起點；超過時重新計時
+    pub calm_since: Option<Instant>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Change {
+    // 進入時視窗內的跨越次數
+    Flapping(usize),
+    Settled(Duration),
+}
+
+impl Detector {
+    pub fn record(
+        &mut self,
+        settings: &Settings,
+        over: bool,
+        count: usize,
+        now: Instant,
+    ) -> Option<Change> {
+        if self
+            .last_over
+            .replace(over)
+            .is_some_and(|last| last != over)
+        {
+            self.crossings.push_back(now);
+        }
+        while self
+            .crossings
+            .front()
+            .is_some_and(|at| now.duration_since(*at) > settings.window)
+        {
+            self.crossings.pop_front();
+        }
+        let Some(since) = self.flapping_since else {
+            if self.crossings.len() < settings.crossings {
+                return None;
+            }
+            self.flapping_since = Some(now);
+            self.calm_since = None;
+            return Some(Change::Flapping(self.crossings.len()));
+        };
+        if count > settings.clear_at {
+            self.calm_since = None;
+            return None;
+        }
+        let calm = *self.calm_since.get_or_insert(now);
+        if now.duration_since(calm) < settings.dwell {
+            return None;
+        }
+        self.reset();
+        Some(Change::Settled(now.duration_since(since)))
+    }
+
+    // 控制 socket 的 clear；跨越記錄一併清除，免得下一次量測又立刻進入
+    pub fn clear(&mut self) -> Option<Duration> {
+        let since = self.flapping_since?;
+        self.reset();
+        Some(since.elapsed())
+    }
+
+    fn reset(&mut self) {
+        self.flapping_since = None;
+        self.calm_since = None;
+        self.crossings.clear();
+    }
+
+    // 視窗內的跨越次數
+    pub fn rate(&self, settings: &Settings, now: Instant) -> usize {
+        self.crossings
+            .iter()
+            .filter(|at| now.duration_since(**at) <= settings.window)
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flapping_needs_a_calm_dwell_to_settle() {
+        let settings = Settings {
+            crossings: 4,
+            window: Duration::from_secs(60),
+            clear_at: 10,
+            dwell: Duration::from_secs(30),
+        };
+        let mut detector = Detector::default();
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+        // 第一次量測只是起點，不算跨越
+        assert_eq!(detector.record(&settings, true, 12, at(0)), None);
+        assert_eq!(detector.record(&settings, false, 9, at(5)), None);
+        assert_eq!(detector.record(&settings, true, 12, at(10)), None);
+        assert_eq!(detector.record(&settings, false, 9, at(15)), None);
+        assert_eq!(detector.rate(&settings, at(15)), 3);
+        // 超出視窗的跨越不計
+        assert_eq!(detector.rate(&settings, at(71)), 1);
+        assert_eq!(
+            detector.record(&settings, true, 12, at(20)),
+            Some(Change::Flapping(4))
+        );
+        assert_eq!(detector.flapping_since, Some(at(20)));
+
+        // 解除值以下的時間被一次超標打斷就重新計時
+        assert_eq!(detector.record(&settings, false, 9, at(25)), None);
+        assert_eq!(detector.record(&settings, true, 11, at(40)), None);
+        assert_eq!(detector.record(&settings, false, 10, at(45)), None);
+        assert_eq!(detector.record(&settings, false, 8, at(70)), None);
+        assert_eq!(
+            detector.record(&settings, false, 8, at(75)),
+            Some(Change::Settled(Duration::from_secs(55)))
+        );
+        assert_eq!(detector.rate(&settings, at(75)), 0);
+
+        detector.flapping_since = Some(Instant::now());
+        assert!(detector.clear().is_some());
+        assert_eq!(detector.clear(), None);
+    }
+}
@@ -0,0 +1,921 @@
+use std::fs;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use crate::{json_string, parse_duration_secs, state, wall_clock};
+
+// --history-file：定期取樣的連線數與每個事件（incident）從開始到結束的經過，追加寫入單一檔案，
+// 由 history 子命令查詢。檔案以 MAGIC 開頭，之後每筆記錄是 4 位元組長度、4 位元組 FNV-1a 校驗（小端序）
+// 與 key=value 的內容。寫到一半當機留下的殘缺記錄在讀取時略過、開啟時截去；事件記錄寫入後 fdatasync，
+// 取樣只寫入不同步。超過大小或保存期限時，把仍要保留的記錄寫進暫存檔再 rename，不會留下寫了一半的檔案
+const MAGIC: &[u8] = b"qq-x11-guard-rs history 1\n";
+const FRAME_HEADER: usize = 8;
+// 單筆記錄的上限；超過的長度欄位視為損壞
+const MAX_RECORD: usize = 64 * 1024;
+// 寫入失敗後隔這麼久才再試，期間的記錄直接略過
+pub const RETRY: Duration = Duration::from_secs(60);
+// 最舊的記錄超過保存期限這麼久才清理，免得之後每筆取樣都重寫整個檔案
+const AGE_SLACK: u64 = 3600;
+
+pub const OUTCOMES: [&str; 8] = [
+    "open",
+    "recovered",
+    "external-restart",
+    "restarted",
+    "reconnected",
+    "reloaded",
+    "dry-run",
+    "failed",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Record {
+    Sample(Sample),
+    Event(Event),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sample {
+    pub time: u64,
+    pub app: String,
+    pub display: String,
+    pub count: usize,
+    pub threshold: usize,
+    // 取樣當時每分鐘新開與關閉的連線數；量測間隔還不到一秒時沒有
+    pub opened_per_minute: Option<u64>,
+    pub closed_per_minute: Option<u64>,
+}
+
+// 事件的一個階段：opened（開始）、alert（進入警示等級）、action（動作與結果）、closed（結束）
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub time: u64,
+    pub app: String,
+    pub display: String,
+    pub incident: String,
+    pub stage: String,
+    // action 為 restarted、failed 等結果，alert 為等級名稱，closed 為 recovered 或 external-restart
+    pub outcome: String,
+    pub count: Option<usize>,
+    pub detail: String,
+    // action 的起因，與命令的 {reason} 相同：threshold、rule:<規則>、manual:<原因> 或 reload-escalated；
+    // 其他階段為空白
+    pub trigger: String,
+}
+
+impl Record {
+    pub fn time(&self) -> u64 {
+        match self {
+            Record::Sample(sample) => sample.time,
+            Record::Event(event) => event.time,
+        }
+    }
+
+    fn encode(&self) -> String {
+        let clean = |text: &str| text.replace(['\n', '\r'], " ");
+        match self {
+            Record::Sample(sample) => format!(
+                "kind=sample\ntime={}\napp={}\ndisplay={}\ncount={}\nthreshold={}\nopened_per_minute={}\nclosed_per_minute={}\n",
+                sample.time,
+                clean(&sample.app),
+                clean(&sample.display),
+                sample.count,
+                sample.threshold,
+                sample
+                    .opened_per_minute
+                    .map(|rate| rate.to_string())
+                    .unwrap_or_default(),
+                sample
+                    .closed_per_minute
+                    .map(|rate| rate.to_string())
+                    .unwrap_or_default()
+            ),
+            Record::Event(event) => format!(
+                "kind=event\ntime={}\napp={}\ndisplay={}\nincident={}\nstage={}\noutcome={}\ncount={}\ndetail={}\ntrigger={}\n",
+                event.time,
+                clean(&event.app),
+                clean(&event.display),
+                clean(&event.incident),
+                clean(&event.stage),
+                clean(&event.outcome),
+                event.count.map(|count| count.to_string()).unwrap_or_default(),
+                clean(&event.detail),
+                clean(&event.trigger)
+            ),
+        }
+    }
+
+    // 不認得的種類（較新的版本寫入）回傳 None，由呼叫端略過
+    fn decode(text: &str) -> Option<Record> {
+        let pairs = state::parse_pairs(text);
+        let field = |name: &str| {
+            pairs
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.clone())
+        };
+        let time = field("time")?.parse().ok()?;
+        let app = field("app")?;
+        let display = field("display")?;
+        match field("kind")?.as_str() {
+            "sample" => Some(Record::Sample(Sample {
+                time,
+                app,
+                display,
+                count: field("count")?.parse().ok()?,
+                threshold: field("threshold")?.parse().ok()?,
+                // 較早的版本沒有這兩個欄位
+                opened_per_minute: field("opened_per_minute").and_then(|rate| rate.parse().ok()),
+                closed_per_minute: field("closed_per_minute").and_then(|rate| rate.parse().ok()),
+            })),
+            "event" => Some(Record::Event(Event {
+                time,
+                app,
+                display,
+                incident: field("incident")?,
+                stage: field("stage")?,
+                outcome: field("outcome").unwrap_or_default(),
+                count: field("count").and_then(|count| count.parse().ok()),
+                detail: field("detail").unwrap_or_default(),
+                // 較早的版本沒有這個欄位
+                trigger: field("trigger").unwrap_or_default(),
+            })),
+            _ => None,
+        }
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash: u32, byte| {
+        (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+fn frame(record: &Record) -> Vec<u8> {
+    let payload = record.encode().into_bytes();
+    let mut bytes = Vec::with_capacity(FRAME_HEADER + payload.len());
+    bytes.extend((payload.len() as u32).to_le_bytes());
+    bytes.extend(checksum(&payload).to_le_bytes());
+    bytes.extend(payload);
+    bytes
+}
+
+// 記錄與它在檔案中的範圍（含長度與校驗）
+type Framed = (Record, Range<usize>);
+
+// 回傳各筆記錄與它在檔案中的範圍，以及最後一筆完整記錄的結尾；長度或校驗不符處即視為檔尾
+fn scan(bytes: &[u8]) -> Result<(Vec<Framed>, usize), String> {
+    if bytes.is_empty() {
+        return Ok((Vec::new(), 0));
+    }
+    if !bytes.starts_with(MAGIC) {
+        return Err("不是本程式的歷史記錄檔".to_string());
+    }
+    let mut records = Vec::new();
+    let mut offset = MAGIC.len();
+    while let Some(header) = bytes.get(offset..offset + FRAME_HEADER) {
+        let length = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let expected = u32::from_le_bytes(header[4..].try_into().unwrap());
+        let start = offset + FRAME_HEADER;
+        let Some(payload) = bytes
+            .get(start..start + length)
+            .filter(|_| length <= MAX_RECORD)
+        else {
+            break;
+        };
+        if checksum(payload) != expected {
+            break;
+        }
+        if let Some(record) = Record::decode(&String::from_utf8_lossy(payload)) {
+            records.push((record, offset..start + length));
+        }
+        offset = start + length;
+    }
+    Ok((records, offset))
+}
+
+pub fn read(path: &PathBuf) -> Result<Vec<Record>, String> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(format!("無法讀取 {}: {error}", path.display())),
+    };
+    let (records, _) = scan(&bytes).map_err(|error| format!("{}: {error}", path.display()))?;
+    Ok(records.into_iter().map(|(record, _)| record).collect())
+}
+
+pub struct Settings {
+    pub path: PathBuf,
+    pub resolution: Duration,
+    pub max_bytes: u64,
+    pub max_age: Duration,
+}
+
+pub struct Failure {
+    pub error: String,
+    pub since: Instant,
+    retry_at: Instant,
+    // 無法寫入期間略過的記錄數
+    pub dropped: u64,
+}
+
+pub enum Written {
+    Stored,
+    // 清理掉的舊記錄數
+    Pruned(usize),
+    Skipped,
+    Failed(String),
+    Recovered { dropped: u64, lasted: Duration },
+}
+
+pub struct Store {
+    pub settings: Settings,
+    file: Option<fs::File>,
+    pub size: u64,
+    oldest: Option<u64>,
+    last_sample: Option<Instant>,
+    pub failure: Option<Failure>,
+}
+
+impl Store {
+    pub fn new(settings: Settings) -> Self {
+        Self {
+            settings,
+            file: None,
+            size: 0,
+            oldest: None,
+            last_sample: None,
+            failure: None,
+        }
+    }
+
+    // 開啟並修復檔尾，需要時依期限與大小清理；回傳要記錄的說明。失敗時之後的寫入照常重試
+    pub fn open(&mut self, now: u64) -> Result<Vec<String>, String> {
+        let path = self.settings.path.clone();
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(error) => return Err(format!("無法讀取 {}: {error}", path.display())),
+        };
+        let (records, end) = scan(&bytes)?;
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|error| format!("無法開啟 {}: {error}", path.display()))?;
+        let mut notes = Vec::new();
+        let written = if bytes.is_empty() {
+            file.write_all(MAGIC)
+                .and_then(|()| file.sync_all())
+                .map_err(|error| format!("無法寫入 {}: {error}", path.display()))?;
+            MAGIC.len()
+        } else {
+            if end < bytes.len() {
+                file.set_len(end as u64)
+                    .map_err(|error| format!("無法截去 {} 的殘缺記錄: {error}", path.display()))?;
+                notes.push(format!(
+                    "歷史記錄 {} 尾端有 {} 位元組不完整的記錄（上次寫入途中中斷），已截去",
+                    path.display(),
+                    bytes.len() - end
+                ));
+            }
+            end
+        };
+        self.file = Some(file);
+        self.size = written as u64;
+        self.oldest = records.first().map(|(record, _)| record.time());
+        let pruned = self.prune_if_needed(now)?;
+        if pruned > 0 {
+            notes.push(format!(
+                "歷史記錄 {} 清理了 {pruned} 筆超過保存期限或大小上限的舊記錄",
+                path.display()
+            ));
+        }
+        Ok(notes)
+    }
+
+    // 取樣間隔由 --history-resolution 決定；到期時記下這次並回傳 true
+    pub fn sample_due(&mut self, now: Instant) -> bool {
+        if self
+            .last_sample
+            .is_some_and(|at| now.duration_since(at) < self.settings.resolution)
+        {
+            return false;
+        }
+        self.last_sample = Some(now);
+        true
+    }
+
+    pub fn append(&mut self, record: &Record, sync: bool, now: Instant) -> Written {
+        if let Some(failure) = &mut self.failure {
+            if now < failure.retry_at {
+                failure.dropped += 1;
+                return Written::Skipped;
+            }
+        }
+        match self.try_append(record, sync) {
+            Ok(pruned) => match self.failure.take() {
+                Some(failure) => Written::Recovered {
+                    dropped: failure.dropped,
+                    lasted: now.duration_since(failure.since),
+                },
+                None if pruned > 0 => Written::Pruned(pruned),
+                None => Written::Stored,
+            },
+            Err(error) => {
+                let first = self.fail(error.clone(), now);
+                if let Some(failure) = &mut self.failure {
+                    failure.dropped += 1;
+                }
+                if first {
+                    Written::Failed(error)
+                } else {
+                    Written::Skipped
+                }
+            }
+        }
+    }
+
+    // 記下寫入失敗，RETRY 之後才再試；回傳是否為這一段失敗的開始
+    pub fn fail(&mut self, error: String, now: Instant) -> bool {
+        self.file = None;
+        let first = self.failure.is_none();
+        let failure = self.failure.get_or_insert(Failure {
+            error: String::new(),
+            since: now,
+            retry_at: now,
+            dropped: 0,
+        });
+        failure.error = error;
+        failure.retry_at = now + RETRY;
+        first
+    }
+
+    fn try_append(&mut self, record: &Record, sync: bool) -> Result<usize, String> {
+        if self.file.is_none() {
+            self.open(record.time())?;
+        }
+        let path = &self.settings.path;
+        let bytes = frame(record);
+        let file = self.file.as_mut().expect("已開啟");
+        file.write_all(&bytes)
+            .and_then(|()| if sync { file.sync_data() } else { Ok(()) })
+            .map_err(|error| format!("無法寫入 {}: {error}", path.display()))?;
+        self.size += bytes.len() as u64;
+        self.oldest.get_or_insert(record.time());
+        self.prune_if_needed(record.time())
+    }
+
+    fn prune_if_needed(&mut self, now: u64) -> Result<usize, String> {
+        let expired = self
+            .oldest
+            .is_some_and(|oldest| oldest + self.settings.max_age.as_secs() + AGE_SLACK < now);
+        if !expired && self.size <= self.settings.max_bytes {
+            return Ok(0);
+        }
+        self.prune(now)
+    }
+
+    // 丟掉超過期限的記錄，再從最新的往回保留到上限的四分之三
+    fn prune(&mut self, now: u64) -> Result<usize, String> {
+        let path = self.settings.path.clone();
+        let bytes =
+            fs::read(&path).map_err(|error| format!("無法讀取 {}: {error}", path.display()))?;
+        let (records, _) = scan(&bytes)?;
+        let cutoff = now.saturating_sub(self.settings.max_age.as_secs());
+        let budget = (self.settings.max_bytes / 4 * 3).saturating_sub(MAGIC.len() as u64);
+        let mut used = 0;
+        let mut kept: Vec<&Framed> = records
+            .iter()
+            .rev()
+            .filter(|(record, _)| record.time() >= cutoff)
+            .take_while(|(_, range)| {
+                used += range.len() as u64;
+                used <= budget
+            })
+            .collect();
+        kept.reverse();
+        let mut text = MAGIC.to_vec();
+        for (_, range) in &kept {
+            text.extend(&bytes[range.clone()]);
+        }
+        let temporary = state::sidecar(&path, ".tmp");
+        (|| {
+            let mut file = fs::File::create(&temporary)?;
+            file.write_all(&text)?;
+            file.sync_all()?;
+            fs::rename(&temporary, &path)
+        })()
+        .map_err(|error| format!("無法清理 {}: {error}", path.display()))?;
+        self.file = Some(
+            fs::OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .map_err(|error| format!("無法開啟 {}: {error}", path.display()))?,
+        );
+        self.size = text.len() as u64;
+        self.oldest = kept.first().map(|(record, _)| record.time());
+        Ok(records.len() - kept.len())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Only {
+    Incidents,
+    Samples,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Query {
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub only: Option<Only>,
+    pub app: Option<String>,
+    pub outcome: Option<String>,
+    pub json: bool,
+}
+
+// history 子命令之後的篩選條件
+pub fn parse_query(args: &[String], now: u64) -> Result<Query, String> {
+    let mut query = Query::default();
+    let mut index = 0;
+    while index < args.len() {
+        let flag = args[index].as_str();
+        let mut value = || {
+            index += 1;
+            args.get(index)
+                .ok_or_else(|| format!("history {flag} 需要值"))
+        };
+        match flag {
+            "--since" => query.since = Some(parse_time(value()?, now)?),
+            "--until" => query.until = Some(parse_time(value()?, now)?),
+            "--only" => {
+                query.only = Some(match value()?.as_str() {
+                    "incidents" => Only::Incidents,
+                    "samples" => Only::Samples,
+                    other => {
+                        return Err(format!("history --only 只接受 incidents、samples: {other}"))
+                    }
+                })
+            }
+            "--app" => query.app = Some(value()?.clone()),
+            "--outcome" => {
+                let outcome = value()?;
+                if !OUTCOMES.contains(&outcome.as_str()) {
+                    return Err(format!(
+                        "history --outcome 只接受 {}: {outcome}",
+                        OUTCOMES.join("、")
+                    ));
+                }
+                query.outcome = Some(outcome.clone());
+            }
+            "--json" => query.json = true,
+            other => return Err(format!("history 不認得的參數: {other}")),
+        }
+        index += 1;
+    }
+    if query.outcome.is_some() && query.only == Some(Only::Samples) {
+        return Err("history --outcome 只用於事件，不能搭配 --only samples".to_string());
+    }
+    Ok(query)
+}
+
+// @<unix 秒數>、本地時間的 YYYY-MM-DD[ HH:MM[:SS]]（日期與時間之間也可用 T），或時間長度表示多久以前
+fn parse_time(text: &str, now: u64) -> Result<u64, String> {
+    if let Some(seconds) = text.strip_prefix('@') {
+        return seconds
+            .parse()
+            .map_err(|_| format!("無效的 unix 秒數: {text}"));
+    }
+    if let Ok(ago) = parse_duration_secs(text) {
+        return Ok(now.saturating_sub(ago));
+    }
+    let invalid =
+        || format!("無效的時間: {text}（可寫 2026-03-01、2026-03-01 08:30、@1772323200 或 7d）");
+    let (date, time) = text
+        .split_once(['T', ' '])
+        .map_or((text, None), |(date, time)| (date, Some(time)));
+    let numbers = |text: &str, separator: char| -> Option<Vec<i32>> {
+        text.split(separator)
+            .map(|part| part.parse().ok())
+            .collect()
+    };
+    let date = numbers(date, '-')
+        .filter(|parts| parts.len() == 3)
+        .ok_or_else(invalid)?;
+    let time = match time {
+        Some(time) => numbers(time, ':')
+            .filter(|parts| parts.len() == 2 || parts.len() == 3)
+            .ok_or_else(invalid)?,
+        None => vec![0, 0],
+    };
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    tm.tm_year = date[0] - 1900;
+    tm.tm_mon = date[1] - 1;
+    tm.tm_mday = date[2];
+    tm.tm_hour = time[0];
+    tm.tm_min = time[1];
+    tm.tm_sec = time.get(2).copied().unwrap_or(0);
+    tm.tm_isdst = -1;
+    let seconds = unsafe { libc::mktime(&mut tm) };
+    if seconds < 0 || !(1..=12).contains(&date[1]) || !(1..=31).contains(&date[2]) {
+        return Err(invalid());
+    }
+    Ok(seconds as u64)
+}
+
+pub struct Incident {
+    pub id: String,
+    pub app: String,
+    pub display: String,
+    pub events: Vec<Event>,
+}
+
+impl Incident {
+    fn opened(&self) -> u64 {
+        self.events.first().map_or(0, |event| event.time)
+    }
+
+    fn closed(&self) -> Option<u64> {
+        self.events
+            .iter()
+            .rev()
+            .find(|event| event.stage == "closed")
+            .map(|event| event.time)
+    }
+
+    fn peak(&self) -> Option<usize> {
+        self.events.iter().filter_map(|event| event.count).max()
+    }
+
+    // 最後一次動作的結果；沒有動作時為結束的方式，尚未結束為 open
+    fn outcome(&self) -> &str {
+        let last = |stage: &str| {
+            self.events
+                .iter()
+                .rev()
+                .find(|event| event.stage == stage)
+                .map(|event| event.outcome.as_str())
+        };
+        last("action").or_else(|| last("closed")).unwrap_or("open")
+    }
+
+    // --outcome：任一次動作的結果、結束的方式或 open 符合即可，例如先失敗、重試後成功的事件也算 failed
+    fn has_outcome(&self, outcome: &str) -> bool {
+        if outcome == "open" {
+            return self.closed().is_none();
+        }
+        self.events.iter().any(|event| {
+            (event.stage == "action" || event.stage == "closed") && event.outcome == outcome
+        })
+    }
+}
+
+// 依事件編號分組，順序為第一次出現的先後
+fn incidents(records: &[Record]) -> Vec<Incident> {
+    let mut incidents: Vec<Incident> = Vec::new();
+    for record in records {
+        let Record::Event(event) = record else {
+            continue;
+        };
+        match incidents
+            .iter_mut()
+            .find(|incident| incident.id == event.incident)
+        {
+            Some(incident) => incident.events.push(event.clone()),
+            None => incidents.push(Incident {
+                id: event.incident.clone(),
+                app: event.app.clone(),
+                display: event.display.clone(),
+                events: vec![event.clone()],
+            }),
+        }
+    }
+    incidents
+}
+
+fn clock(seconds: u64) -> String {
+    wall_clock(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+fn json_count<T: ToString>(count: Option<T>) -> String {
+    count.map_or("null".to_string(), |count| count.to_string())
+}
+
+pub fn render(records: &[Record], query: &Query) -> String {
+    let in_range = |start: u64, end: u64| {
+        query.since.is_none_or(|since| end >= since)
+            && query.until.is_none_or(|until| start <= until)
+    };
+    let app_matches = |app: &str| query.app.as_deref().is_none_or(|wanted| wanted == app);
+    let incidents: Vec<Incident> = incidents(records)
+        .into_iter()
+        .filter(|incident| {
+            let end = incident.events.last().map_or(0, |event| event.time);
+            in_range(incident.opened(), end)
+                && app_matches(&incident.app)
+                && query
+                    .outcome
+                    .as_deref()
+                    .is_none_or(|outcome| incident.has_outcome(outcome))
+        })
+        .collect();
+    let samples: Vec<&Sample> = records
+        .iter()
+        .filter_map(|record| match record {
+            Record::Sample(sample) => Some(sample),
+            Record::Event(_) => None,
+        })
+        .filter(|sample| in_range(sample.time, sample.time) && app_matches(&sample.app))
+        .collect();
+    // 指定 --outcome 時只看事件
+    let show_incidents = query.only != Some(Only::Samples);
+    let show_samples = query.only != Some(Only::Incidents) && query.outcome.is_none();
+
+    if query.json {
+        let mut sections = Vec::new();
+        if show_incidents {
+            let items: Vec<String> = incidents
+                .iter()
+                .map(|incident| {
+                    let events: Vec<String> = incident
+                        .events
+                        .iter()
+                        .map(|event| {
+                            format!(
+                                "{{\"time\":{},\"stage\":{},\"outcome\":{},\"count\":{},\"detail\":{},\"trigger\":{}}}",
+                                event.time,
+                                json_string(&event.stage),
+                                json_string(&event.outcome),
+                                json_count(event.count),
+                                json_string(&event.detail),
+                                match event.trigger.as_str() {
+                                    "" => "null".to_string(),
+                                    trigger => json_string(trigger),
+                                }
+                            )
+                        })
+                        .collect();
+                    format!(
+                        "{{\"id\":{},\"app\":{},\"display\":{},\"opened\":{},\"closed\":{},\"peak\":{},\"outcome\":{},\"events\":[{}]}}",
+                        json_string(&incident.id),
+                        json_string(&incident.app),
+                        json_string(&incident.display),
+                        incident.opened(),
+                        incident
+                            .closed()
+                            .map_or("null".to_string(), |time| time.to_string()),
+                        json_count(incident.peak()),
+                        json_string(incident.outcome()),
+                        events.join(",")
+                    )
+                })
+                .collect();
+            sections.push(format!("\"incidents\":[{}]", items.join(",")));
+        }
+        if show_samples {
+            let items: Vec<String> = samples
+                .iter()
+                .map(|sample| {
+                    format!(
+                        "{{\"time\":{},\"app\":{},\"display\":{},\"count\":{},\"threshold\":{},\"opened_per_minute\":{},\"closed_per_minute\":{}}}",
+                        sample.time,
+                        json_string(&sample.app),
+                        json_string(&sample.display),
+                        sample.count,
+                        sample.threshold,
+                        json_count(sample.opened_per_minute),
+                        json_count(sample.closed_per_minute)
+                    )
+                })
+                .collect();
+            sections.push(format!("\"samples\":[{}]", items.join(",")));
+        }
+        return format!("{{{}}}\n", sections.join(","));
+    }
+
+    let mut lines = Vec::new();
+    if show_incidents {
+        lines.push(format!("事件（{} 筆）", incidents.len()));
+        for incident in &incidents {
+            lines.push(format!(
+                "{}  {} {}  {} → {}  最高 {}  結果 {}",
+                incident.id,
+                incident.app,
+                incident.display,
+                clock(incident.opened()),
+                incident.closed().map_or("（尚未結束）".to_string(), clock),
+                incident
+                    .peak()
+                    .map_or("-".to_string(), |peak| format!("{peak} 條")),
+                incident.outcome()
+            ));
+            for event in &incident.events {
+                let mut stage = match event.outcome.as_str() {
+                    "" => event.stage.clone(),
+                    outcome => format!("{} {outcome}", event.stage),
+                };
+                if !event.trigger.is_empty() {
+                    stage.push_str(&format!("（{}）", event.trigger));
+                }
+                let line = format!("  {}  {stage}  {}", clock(event.time), event.detail);
+                lines.push(line.trim_end().to_string());
+            }
+        }
+    }
+    if show_samples {
+        lines.push(format!("取樣（{} 筆）", samples.len()));
+        for sample in &samples {
+            let mut line = format!(
+                "{}  {} {}  {} / {}",
+                clock(sample.time),
+                sample.app,
+                sample.display,
+                sample.count,
+                sample.threshold
+            );
+            if let (Some(opened), Some(closed)) =
+                (sample.opened_per_minute, sample.closed_per_minute)
+            {
+                line.push_str(&format!("  每分鐘開啟 {opened}、關閉 {closed}"));
+            }
+            lines.push(line);
+        }
+    }
+    lines.join("\n") + "\n"
+}
+
+// 給 status 的一行：檔案大小與寫入狀態
+pub fn status_text(store: &Store) -> String {
+    let path = store.settings.path.display();
+    match &store.failure {
+        Some(failure) => format!(
+            "{path}，無法寫入已 {} 秒（{}），略過 {} 筆",
+            failure.since.elapsed().as_secs(),
+            failure.error,
+            failure.dropped
+        ),
+        None => format!(
+            "{path}，{} 位元組（上限 {}），每 {} 秒取樣",
+            store.size,
+            store.settings.max_bytes,
+            store.settings.resolution.as_secs()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn event(time: u64, incident: &str, stage: &str, outcome: &str) -> Record {
+        Record::Event(Event {
+            time,
+            app: "qq".to_string(),
+            display: ":0".to_string(),
+            incident: incident.to_string(),
+            stage: stage.to_string(),
+            outcome: outcome.to_string(),
+            count: Some(12),
+            detail: "第一行\n第二行".to_string(),
+            trigger: match stage {
+                "action" => "rule:bloat".to_string(),
+                _ => String::new(),
+            },
+        })
+    }
+
+    fn sample(time: u64, count: usize) -> Record {
+        Record::Sample(Sample {
+            time,
+            app: "qq".to_string(),
+            display: ":0".to_string(),
+            count,
+            threshold: 10,
+            opened_per_minute: None,
+            closed_per_minute: None,
+        })
+    }
+
+    #[test]
+    fn torn_tails_are_cut_and_incidents_filter_by_outcome() {
+        let dir = env::temp_dir().join(format!("qq-x11-guard-history-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history");
+        let settings = || Settings {
+            path: path.clone(),
+            resolution: Duration::from_secs(60),
+            max_bytes: 1 << 20,
+            max_age: Duration::from_secs(90 * 86400),
+        };
+        let now = Instant::now();
+        let mut store = Store::new(settings());
+        assert!(store.open(1_000).unwrap().is_empty());
+        assert!(store.sample_due(now));
+        assert!(!store.sample_due(now + Duration::from_secs(30)));
+        for (record, sync) in [
+            (sample(1_000, 4), false),
+            (event(1_100, "inc-1100-1", "opened", ""), true),
+            (event(1_110, "inc-1100-1", "action", "failed"), true),
+            (event(1_200, "inc-1100-1", "action", "restarted"), true),
+            (event(1_300, "inc-1100-1", "closed", "recovered"), true),
+            (event(5_000, "inc-5000-2", "opened", ""), true),
+        ] {
+            assert!(matches!(store.append(&record, sync, now), Written::Stored));
+        }
+        drop(store);
+
+        // 寫到一半中斷：讀取略過殘缺的記錄，下次開啟時截去
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&frame(&sample(6_000, 9))[..10]).unwrap();
+        drop(file);
+        let records = read(&path).unwrap();
+        assert_eq!(records.len(), 6);
+        let mut store = Store::new(settings());
+        let notes = store.open(6_000).unwrap();
+        assert!(notes[0].contains("10 位元組不完整的記錄"), "{notes:?}");
+        let mut churning = sample(6_060, 5);
+        if let Record::Sample(sample) = &mut churning {
+            sample.opened_per_minute = Some(40);
+            sample.closed_per_minute = Some(0);
+        }
+        assert!(matches!(
+            store.append(&churning, false, now),
+            Written::Stored
+        ));
+        let records = read(&path).unwrap();
+        assert_eq!(records.len(), 7);
+        assert_eq!(records[6], churning);
+        // 較早版本寫入的取樣沒有連線變動率
+        let older =
+            Record::decode("kind=sample\ntime=1\napp=qq\ndisplay=:0\ncount=3\nthreshold=10\n");
+        assert_eq!(older, Some(sample(1, 3)));
+
+        // 先失敗、重試後成功的事件也算 failed；結果取最後一次動作
+        let query =
+            parse_query(&["--outcome".into(), "failed".into(), "--json".into()], 0).unwrap();
+        let json = render(&records, &query);
+        assert!(
+            json.starts_with("{\"incidents\":[{\"id\":\"inc-1100-1\""),
+            "{json}"
+        );
+        assert!(json.contains("\"closed\":1300,\"peak\":12,\"outcome\":\"restarted\""));
+        assert!(json.contains("\"detail\":\"第一行 第二行\",\"trigger\":null}"));
+        assert!(json.contains("\"outcome\":\"failed\",\"count\":12,\"detail\":\"第一行 第二行\",\"trigger\":\"rule:bloat\"}"));
+        let text = render(&records, &parse_query(&[], 0).unwrap());
+        assert!(
+            text.contains("  action restarted（rule:bloat）  第一行 第二行"),
+            "{text}"
+        );
+        assert!(!json.contains("inc-5000-2") && !json.contains("samples"));
+
+        let query = parse_query(&["--since".into(), "@4000".into()], 0).unwrap();
+        let text = render(&records, &query);
+        assert!(text.starts_with("事件（1 筆）\ninc-5000-2"), "{text}");
+        assert!(text.contains("（尚未結束）  最高 12 條  結果 open"));
+        assert!(text.contains("取樣（1 筆）"));
+        assert!(text.contains(" 5 / 10  每分鐘開啟 40、關閉 0\n"), "{text}");
+        let query = parse_query(&["--only".into(), "samples".into(), "--json".into()], 0).unwrap();
+        let json = render(&records, &query);
+        assert!(
+            json.contains("\"count\":4,\"threshold\":10,\"opened_per_minute\":null,\"closed_per_minute\":null}"),
+            "{json}"
+        );
+        assert!(json.contains(
+            "\"count\":5,\"threshold\":10,\"opened_per_minute\":40,\"closed_per_minute\":0}"
+        ));
+
+        assert!(parse_query(&["--outcome".into(), "exploded".into()], 0).is_err());
+        assert_eq!(
+            parse_query(&["--until".into(), "2h".into()], 10_000)
+                .unwrap()
+                .until,
+            Some(2_800)
+        );
+
+        // 超過大小上限時只留最新的記錄
+        let mut store = Store::new(Settings {
+            max_bytes: 4096,
+            ..settings()
+        });
+        store.open(6_100).unwrap();
+        let mut pruned = 0;
+        for time in 6_100..6_200 {
+            if let Written::Pruned(count) = store.append(&sample(time, 1), false, now) {
+                pruned += count;
+            }
+        }
+        let records = read(&path).unwrap();
+        assert!(pruned > 0 && store.size <= 4096);
+        assert_eq!(records.len() + pruned, 107);
+        assert_eq!(records.last(), Some(&sample(6_199, 1)));
+        assert!(!records
+            .iter()
+            .any(|record| matches!(record, Record::Event(_))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,480 @@
+use std::time::Instant;
+
+use crate::state;
+use crate::xres::{padded, u16_at, u32_at, Connection, TIMEOUT};
+use crate::X11Endpoint;
+
+// --restore-geometry：重啟前記下應用程式各頂層視窗的位置、大小、所在桌面與最大化／置頂於所有桌面的狀態，
+// 重啟驗證完成後等新程序的視窗出現，以 EWMH 的 client message 請視窗管理員放回原處。
+// 頂層視窗取自根視窗的 _NET_CLIENT_LIST，以 _NET_WM_PID 對應到程序；新舊視窗以 WM_CLASS 相同、
+// 標題最相近者配對。位置是視窗內容（不含邊框）在根視窗上的座標，送回時以 StaticGravity 表示同一個意思
+
+// 預先定義的 atom
+const ATOM_ATOM: u32 = 4;
+const ATOM_CARDINAL: u32 = 6;
+const ATOM_STRING: u32 = 31;
+const ATOM_WM_NAME: u32 = 39;
+const ATOM_WINDOW: u32 = 33;
+const ATOM_WM_CLASS: u32 = 67;
+
+const STATIC_GRAVITY: u32 = 10;
+// _NET_MOVERESIZE_WINDOW 的 x、y、寬、高都有指定；來源為 2（pager 等使用者代理的工具）
+const MOVERESIZE_FLAGS: u32 = STATIC_GRAVITY | 0xf00 | (2 << 12);
+const SOURCE_PAGER: u32 = 2;
+const STATE_REMOVE: u32 = 0;
+const STATE_ADD: u32 = 1;
+const SUBSTRUCTURE_NOTIFY: u32 = 1 << 19;
+const SUBSTRUCTURE_REDIRECT: u32 = 1 << 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Window {
+    // X 的視窗 id，只在同一次查詢內有意義，不寫入保存的配置
+    pub id: u32,
+    pub class: String,
+    pub title: String,
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    // None 表示視窗管理員沒有回報（或視窗不屬於任何桌面以外的情況）
+    pub desktop: Option<u32>,
+    pub maximized: bool,
+    pub sticky: bool,
+}
+
+impl Window {
+    pub fn describe(&self) -> String {
+        format!(
+            "{}「{}」{}x{}+{}+{}{}{}{}",
+            self.class,
+            self.title,
+            self.width,
+            self.height,
+            self.x,
+            self.y,
+            self.desktop
+                .map(|desktop| format!("，桌面 {desktop}"))
+                .unwrap_or_default(),
+            if self.maximized { "，最大化" } else { "" },
+            if self.sticky { "，所有桌面" } else { "" }
+        )
+    }
+
+    // 保存到檔案的一行：數值欄位以空白分隔，WM_CLASS 與標題以 tab 分隔放在最後
+    pub fn render(&self) -> String {
+        format!(
+            "{} {} {} {} {} {}{} {}\t{}",
+            self.x,
+            self.y,
+            self.width,
+            self.height,
+            self.desktop
+                .map_or("-".to_string(), |desktop| desktop.to_string()),
+            if self.maximized { "m" } else { "-" },
+            if self.sticky { "s" } else { "-" },
+            self.class.replace(['\t', '\n'], " "),
+            self.title.replace(['\t', '\n'], " ")
+        )
+    }
+
+    pub fn parse(line: &str) -> Option<Window> {
+        let (fields, title) = line.split_once('\t')?;
+        let mut fields = fields.splitn(7, ' ');
+        let mut next = || fields.next();
+        let x = next()?.parse().ok()?;
+        let y = next()?.parse().ok()?;
+        let width = next()?.parse().ok()?;
+        let height = next()?.parse().ok()?;
+        let desktop = match next()? {
+            "-" => None,
+            value => Some(value.parse().ok()?),
+        };
+        let flags = next()?;
+        let class = next()?.to_string();
+        Some(Window {
+            id: 0,
+            class,
+            title: title.to_string(),
+            x,
+            y,
+            width,
+            height,
+            desktop,
+            maximized: flags.contains('m'),
+            sticky: flags.contains('s'),
+        })
+    }
+}
+
+// 保存到 --state-file 旁的 .geometry：記下的時間與每個視窗一行
+pub fn render(saved: u64, windows: &[Window]) -> String {
+    let mut text = format!(
+        "# qq-x11-guard-rs --restore-geometry 記下的視窗配置，還原後即刪除\nsaved={saved}\n"
+    );
+    for window in windows {
+        text.push_str(&format!("window={}\n", window.render()));
+    }
+    text
+}
+
+pub fn parse(text: &str) -> Option<(u64, Vec<Window>)> {
+    let pairs = state::parse_pairs(text);
+    let saved = pairs
+        .iter()
+        .find(|(key, _)| key == "saved")?
+        .1
+        .parse()
+        .ok()?;
+    let windows = pairs
+        .iter()
+        .filter(|(key, _)| key == "window")
+        .map(|(_, value)| Window::parse(value))
+        .collect::<Option<_>>()?;
+    Some((saved, windows))
+}
+
+struct Atoms {
+    client_list: u32,
+    pid: u32,
+    desktop: u32,
+    state: u32,
+    maximized_vert: u32,
+    maximized_horz: u32,
+    sticky: u32,
+    moveresize: u32,
+    name: u32,
+    utf8: u32,
+}
+
+impl Atoms {
+    fn intern(connection: &mut Connection) -> Result<Self, String> {
+        let mut atom = |name: &str| -> Result<u32, String> {
+            let mut request = vec![16, 0, 0, 0];
+            request.extend((name.len() as u16).to_le_bytes());
+            request.extend([0, 0]);
+            request.extend(padded(name.as_bytes()));
+            Ok(u32_at(&connection.request(&request)?, 8))
+        };
+        Ok(Self {
+            client_list: atom("_NET_CLIENT_LIST")?,
+            pid: atom("_NET_WM_PID")?,
+            desktop: atom("_NET_WM_DESKTOP")?,
+            state: atom("_NET_WM_STATE")?,
+            maximized_vert: atom("_NET_WM_STATE_MAXIMIZED_VERT")?,
+            maximized_horz: atom("_NET_WM_STATE_MAXIMIZED_HORZ")?,
+            sticky: atom("_NET_WM_STATE_STICKY")?,
+            moveresize: atom("_NET_MOVERESIZE_WINDOW")?,
+            name: atom("_NET_WM_NAME")?,
+            utf8: atom("UTF8_STRING")?,
+        })
+    }
+}
+
+// GetProperty 的值；屬性不存在時為空
+fn property(
+    connection: &mut Connection,
+    window: u32,
+    property: u32,
+    kind: u32,
+) -> Result<Vec<u8>, String> {
+    let mut request = vec![20, 0, 0, 0];
+    for value in [window, property, kind, 0, 1024] {
+        request.extend(value.to_le_bytes());
+    }
+    let reply = connection.request(&request)?;
+    let unit = match reply[1] {
+        8 => 1,
+        16 => 2,
+        32 => 4,
+        _ => return Ok(Vec::new()),
+    };
+    let length = (u32_at(&reply, 16) as usize * unit).min(reply.len().saturating_sub(32));
+    Ok(reply[32..32 + length].to_vec())
+}
+
+fn words(bytes: &[u8]) -> Vec<u32> {
+    bytes
+        .chunks_exact(4)
+        .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+        .collect()
+}
+
+fn root(connection: &Connection) -> Result<u32, String> {
+    connection
+        .root
+        .ok_or("連線設定回覆中沒有 screen".to_string())
+}
+
+// 屬於 pids 的頂層視窗。個別視窗查詢失敗（例如查到一半被關閉）不中斷，原因放在第二個回傳值
+pub fn capture(
+    endpoint: &X11Endpoint,
+    display: &str,
+    pids: &[i32],
+) -> Result<(Vec<Window>, Vec<String>), String> {
+    let mut connection = Connection::open(endpoint, display, Instant::now() + TIMEOUT)?;
+    let atoms = Atoms::intern(&mut connection)?;
+    let root = root(&connection)?;
+    let clients = words(&property(
+        &mut connection,
+        root,
+        atoms.client_list,
+        ATOM_WINDOW,
+    )?);
+    if clients.is_empty() {
+        return Err("根視窗沒有 _NET_CLIENT_LIST（沒有執行支援 EWMH 的視窗管理員？）".to_string());
+    }
+    let mut windows = Vec::new();
+    let mut failures = Vec::new();
+    for id in clients {
+        match describe_window(&mut connection, &atoms, root, id, pids) {
+            Ok(Some(window)) => windows.push(window),
+            Ok(None) => {}
+            Err(error) => failures.push(format!("視窗 0x{id:x}：{error}")),
+        }
+    }
+    Ok((windows, failures))
+}
+
+fn describe_window(
+    connection: &mut Connection,
+    atoms: &Atoms,
+    root: u32,
+    id: u32,
+    pids: &[i32],
+) -> Result<Option<Window>, String> {
+    let pid = words(&property(connection, id, atoms.pid, ATOM_CARDINAL)?);
+    if !pid.first().is_some_and(|pid| pids.contains(&(*pid as i32))) {
+        return Ok(None);
+    }
+    // WM_CLASS 是以 NUL 分隔的 instance 與 class，以 class 配對
+    let class = property(connection, id, ATOM_WM_CLASS, ATOM_STRING)?;
+    let mut parts = class.split(|byte| *byte == 0);
+    let instance = parts.next().unwrap_or_default();
+    let class = parts
+        .next()
+        .filter(|class| !class.is_empty())
+        .unwrap_or(instance);
+    let mut title = property(connection, id, atoms.name, atoms.utf8)?;
+    if title.is_empty() {
+        title = property(connection, id, ATOM_WM_NAME, ATOM_STRING)?;
+    }
+
+    let mut request = vec![14, 0, 0, 0];
+    request.extend(id.to_le_bytes());
+    let geometry = connection.request(&request)?;
+    let mut request = vec![40, 0, 0, 0];
+    request.extend(id.to_le_bytes());
+    request.extend(root.to_le_bytes());
+    request.extend([0, 0, 0, 0]);
+    let position = connection.request(&request)?;
+
+    let desktop = words(&property(connection, id, atoms.desktop, ATOM_CARDINAL)?)
+        .first()
+        .copied();
+    let state = words(&property(connection, id, atoms.state, ATOM_ATOM)?);
+    Ok(Some(Window {
+        id,
+        class: String::from_utf8_lossy(class).into_owned(),
+        title: String::from_utf8_lossy(&title).into_owned(),
+        x: i32::from(u16_at(&position, 12) as i16),
+        y: i32::from(u16_at(&position, 14) as i16),
+        width: u32::from(u16_at(&geometry, 16)),
+        height: u32::from(u16_at(&geometry, 18)),
+        desktop,
+        maximized: state.contains(&atoms.maximized_vert) || state.contains(&atoms.maximized_horz),
+        sticky: state.contains(&atoms.sticky),
+    }))
+}
+
+// 標題的相似度：字元 bigram 的 Dice 係數，0 到 1
+fn similarity(left: &str, right: &str) -> f64 {
+    if left == right {
+        return 1.0;
+    }
+    let bigrams = |text: &str| -> Vec<(char, char)> {
+        let chars: Vec<char> = text.chars().collect();
+        chars.windows(2).map(|pair| (pair[0], pair[1])).collect()
+    };
+    let left = bigrams(left);
+    let mut right = bigrams(right);
+    let total = left.len() + right.len();
+    if total == 0 {
+        return 0.0;
+    }
+    let mut common = 0;
+    for gram in left {
+        if let Some(index) = right.iter().position(|other| *other == gram) {
+            right.swap_remove(index);
+            common += 1;
+        }
+    }
+    2.0 * common as f64 / total as f64
+}
+
+// 舊視窗與新視窗的配對（索引）：WM_CLASS 相同者之間，標題最相近的優先，每個視窗只配對一次
+pub fn pair(saved: &[Window], current: &[Window]) -> Vec<(usize, usize)> {
+    let mut candidates: Vec<(f64, usize, usize)> = saved
+        .iter()
+        .enumerate()
+        .flat_map(|(old, saved)| {
+            current
+                .iter()
+                .enumerate()
+                .filter(move |(_, window)| window.class == saved.class)
+                .map(move |(new, window)| (similarity(&saved.title, &window.title), old, new))
+        })
+        .collect();
+    candidates.sort_by(|left, right| {
+        right
+            .0
+            .total_cmp(&left.0)
+            .then(left.1.cmp(&right.1))
+            .then(left.2.cmp(&right.2))
+    });
+    let mut pairs: Vec<(usize, usize)> = Vec::new();
+    for (_, old, new) in candidates {
+        if pairs
+            .iter()
+            .all(|(used_old, used_new)| *used_old != old && *used_new != new)
+        {
+            pairs.push((old, new));
+        }
+    }
+    pairs.sort();
+    pairs
+}
+
+fn client_message(window: u32, kind: u32, data: [u32; 5]) -> Vec<u8> {
+    let mut event = vec![33, 32, 0, 0];
+    event.extend(window.to_le_bytes());
+    event.extend(kind.to_le_bytes());
+    for value in data {
+        event.extend(value.to_le_bytes());
+    }
+    event
+}
+
+fn send_to_root(connection: &mut Connection, root: u32, event: Vec<u8>) -> Result<(), String> {
+    let mut request = vec![25, 0, 0, 0];
+    request.extend(root.to_le_bytes());
+    request.extend((SUBSTRUCTURE_NOTIFY | SUBSTRUCTURE_REDIRECT).to_le_bytes());
+    request.extend(event);
+    connection.send(&request)
+}
+
+// 把 saved 的配置套用到目前的視窗 window：先換桌面、取消最大化，移動並調整大小後再恢復最大化與置頂
+pub fn apply(
+    endpoint: &X11Endpoint,
+    display: &str,
+    saved: &Window,
+    window: &Window,
+) -> Result<(), String> {
+    let mut connection = Connection::open(endpoint, display, Instant::now() + TIMEOUT)?;
+    let atoms = Atoms::intern(&mut connection)?;
+    let root = root(&connection)?;
+    let id = window.id;
+    if let Some(desktop) = saved
+        .desktop
+        .filter(|desktop| Some(*desktop) != window.desktop)
+    {
+        let message = client_message(id, atoms.desktop, [desktop, SOURCE_PAGER, 0, 0, 0]);
+        send_to_root(&mut connection, root, message)?;
+    }
+    let maximized = [atoms.maximized_vert, atoms.maximized_horz];
+    let state = |action: u32, [first, second]: [u32; 2]| {
+        client_message(id, atoms.state, [action, first, second, SOURCE_PAGER, 0])
+    };
+    if window.maximized {
+        send_to_root(&mut connection, root, state(STATE_REMOVE, maximized))?;
+    }
+    let geometry = client_message(
+        id,
+        atoms.moveresize,
+        [
+            MOVERESIZE_FLAGS,
+            saved.x as u32,
+            saved.y as u32,
+            saved.width,
+            saved.height,
+        ],
+    );
+    send_to_root(&mut connection, root, geometry)?;
+    if saved.maximized {
+        send_to_root(&mut connection, root, state(STATE_ADD, maximized))?;
+    }
+    if saved.sticky != window.sticky {
+        let action = if saved.sticky {
+            STATE_ADD
+        } else {
+            STATE_REMOVE
+        };
+        send_to_root(&mut connection, root, state(action, [atoms.sticky, 0]))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(class: &str, title: &str) -> Window {
+        Window {
+            id: 0,
+            class: class.to_string(),
+            title: title.to_string(),
+            x: -1200,
+            y: 40,
+            width: 800,
+            height: 600,
+            desktop: Some(2),
+            maximized: false,
+            sticky: true,
+        }
+    }
+
+    #[test]
+    fn saved_windows_round_trip_and_pair_by_class_then_title() {
+        let saved = window("QQ", "王小明\t的聊天");
+        let line = saved.render();
+        assert_eq!(line, "-1200 40 800 600 2 -s QQ\t王小明 的聊天");
+        let (time, windows) = parse(&render(1_792_000_000, std::slice::from_ref(&saved))).unwrap();
+        assert_eq!(time, 1_792_000_000);
+        let parsed = windows[0].clone();
+        assert_eq!(
+            (parsed.x, parsed.desktop, parsed.sticky, parsed.maximized),
+            (-1200, Some(2), true, false)
+        );
+        assert_eq!(parsed.title, "王小明 的聊天");
+        assert_eq!(Window::parse("1 2 3\tQQ"), None);
+
+        let saved = [
+            window("QQ", "QQ"),
+            window("QQ", "王小明 的聊天"),
+            window("QQ", "群組：讀書會"),
+        ];
+        // 新視窗的標題稍有不同（未讀數），順序也不同；其他程式的視窗不配對
+        let current = [
+            window("QQ", "群組：讀書會 (3)"),
+            window("Firefox", "QQ"),
+            window("QQ", "QQ"),
+            window("QQ", "王小明 的聊天 (1)"),
+        ];
+        assert_eq!(pair(&saved, &current), vec![(0, 2), (1, 3), (2, 0)]);
+        // 新視窗較少時，相似度最高的先配對，其餘的舊視窗沒有對象
+        assert_eq!(pair(&saved, &current[..1]), vec![(2, 0)]);
+    }
+
+    #[test]
+    fn moveresize_message_uses_static_gravity_and_pager_source() {
+        let message = client_message(
+            0x1e0_0003,
+            300,
+            [MOVERESIZE_FLAGS, -5i32 as u32, 10, 640, 480],
+        );
+        assert_eq!(message.len(), 32);
+        assert_eq!(&message[..2], &[33, 32]);
+        assert_eq!(u32_at(&message, 4), 0x1e0_0003);
+        assert_eq!(u32_at(&message, 12), 0x2f0a);
+        assert_eq!(u32_at(&message, 16) as i32, -5);
+    }
+}
@@ -0,0 +1,145 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+// --log-file：日誌改寫到檔案；指定 --log-max-bytes 時自行輪替，
+// 超過大小就把目前的檔案改名為 .1（舊的依序往後推，最多保留 keep 個）再開新檔。
+// 寫入與輪替都在同一把鎖內，不會有半行寫進改名後的檔案
+static SINK: OnceLock<Mutex<LogFile>> = OnceLock::new();
+
+pub struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: Option<u64>,
+    keep: usize,
+    // 上一次寫入或輪替失敗，避免每行都重複警告
+    failing: bool,
+}
+
+impl LogFile {
+    pub fn open(path: &Path, max_bytes: Option<u64>, keep: usize) -> io::Result<Self> {
+        let file = open_append(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_bytes,
+            keep,
+            failing: false,
+        })
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let length = line.len() as u64 + 1;
+        // 單行本身超過上限時照寫，只保證每個檔案至少有一行
+        if self
+            .max_bytes
+            .is_some_and(|limit| self.size > 0 && self.size + length > limit)
+        {
+            if let Err(error) = self.rotate() {
+                // 改名或開新檔失敗時繼續寫原本的檔案，下一行再試
+                self.report(&format!("日誌輪替失敗: {error}"));
+            }
+        }
+        self.file.write_all(format!("{line}\n").as_bytes())?;
+        self.size += length;
+        self.failing = false;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.keep).rev() {
+                match fs::rename(self.rotated(index), self.rotated(index + 1)) {
+                    Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{index}"));
+        PathBuf::from(name)
+    }
+
+    fn report(&mut self, message: &str) {
+        if !self.failing {
+            eprintln!("{}: {message}", self.path.display());
+        }
+        self.failing = true;
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+pub fn install(sink: LogFile) {
+    let _ = SINK.set(Mutex::new(sink));
+}
+
+// 未設定 --log-file 或寫入失敗時回傳 false，由呼叫端改印到 stdout
+pub fn write(line: &str) -> bool {
+    let Some(sink) = SINK.get() else {
+        return false;
+    };
+    let mut sink = sink.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    match sink.write_line(line) {
+        Ok(()) => true,
+        Err(error) => {
+            sink.report(&format!("無法寫入日誌檔: {error}，改輸出到標準輸出"));
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotation_keeps_at_most_n_old_files() {
+        let dir = std::env::temp_dir().join(format!("qq-x11-guard-log-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("guard.log");
+        fs::write(&path, "舊內容\n").unwrap();
+
+        let mut sink = LogFile::open(&path, Some(20), 2).unwrap();
+        for index in 0..8 {
+            sink.write_line(&format!("line {index} .....")).unwrap();
+        }
+        // 每行 13 bytes，上限 20：每寫一行就輪替一次
+        assert_eq!(fs::read_to_string(&path).unwrap(), "line 7 .....\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("guard.log.1")).unwrap(),
+            "line 6 .....\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("guard.log.2")).unwrap(),
+            "line 5 .....\n"
+        );
+        assert!(!dir.join("guard.log.3").exists());
+
+        // keep = 0 時直接清掉，不留舊檔
+        let mut sink = LogFile::open(&path, Some(20), 0).unwrap();
+        sink.write_line("line 8 .....").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "line 8 .....\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("guard.log.1")).unwrap(),
+            "line 6 .....\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,16 +1,53 @@
-use std::collections::{HashMap, HashSet};
+mod capture;
+mod configfile;
+mod control;
+mod coredump;
+mod counter;
+mod crashloop;
+#[cfg(feature = "backend-diag")]
+mod diag;
+mod explain;
+mod flap;
+mod history;
+mod layout;
+mod logfile;
+mod manpage;
+mod mux;
+mod pattern;
+mod priority;
+mod privileges;
+mod rule;
+mod sandbox;
+mod state;
+mod template;
+mod throttle;
+mod top;
+mod unit;
+mod xprobe;
+mod xres;
+
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::env;
 use std::ffi::CString;
+use std::fmt;
 use std::fs;
-use std::io;
+use std::io::{self, Write};
 use std::mem;
-use std::os::fd::RawFd;
-use std::path::Path;
-use std::process::{Command, Stdio};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
 use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
 use std::thread;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use counter::ConnectionCounter;
+use sandbox::CommandSpec;
+
 const WATCH_MASK: u32 = libc::IN_CREATE
     | libc::IN_DELETE
     | libc::IN_ATTRIB
@@ -19,48 +56,610 @@ const WATCH_MASK: u32 = libc::IN_CREATE
     | libc::IN_MOVE_SELF
     | libc::IN_DELETE_SELF;
 const EVENT_BUF_SIZE: usize = 8192;
+// 重啟時送出 SIGTERM 後等待結束的時間，逾時改送 SIGKILL 後再等的時間
+const TERM_GRACE: Duration = Duration::from_secs(8);
+const KILL_GRACE: Duration = Duration::from_secs(3);
+const SO_ACCEPTCON: u32 = 0x0001_0000;
+// /proc/net/tcp 的 st 欄位
+const TCP_ESTABLISHED: &str = "01";
+const TCP_LISTEN: &str = "0A";
+// X11 的 TCP port 為 6000 + display 編號
+const X11_TCP_BASE_PORT: u16 = 6000;
 
 // ===== 區塊 1：設定與參數 =====
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Subcommand {
+    Monitor,
+    RestartNow,
+    Top,
+    Ctl,
+    GenerateMan,
+    GenerateUnit,
+    Explain,
+    History,
+}
+
 #[derive(Debug, Clone)]
 struct Config {
+    subcommand: Subcommand,
+    // 日誌與通知中的名稱：app_names 以逗號串接，只有一個名稱時就是它本身
     app_name: String,
+    // --app-name 的所有名稱，符合任一個的程序都屬於同一組，連線合計、一起關閉
+    app_names: Vec<String>,
+    // --app-name 名稱:門檻 分別監控的程式；空白表示只有上面這一組
+    apps: Vec<AppSpec>,
+    // 名稱相符之外，命令列還須符合的條件（全部成立）；只套用在主程式。
+    // --match-cmdline 與 --app-name-regex 也放在這裡，取代 app_names 的完全相符
+    cmdline_match: Vec<CmdlineMatch>,
     threshold: usize,
+    // --threshold-scope：門檻對照所有程序合計的連線數，或是連線最多的單一程序
+    threshold_scope: ThresholdScope,
+    // --threshold-windowless：能向 X server 查到視窗時，改以無視窗的連線數對照此門檻
+    threshold_windowless: Option<usize>,
+    // 只有存在超過此秒數的連線計入門檻；None 表示全部計入
+    min_connection_age_seconds: Option<u64>,
     display: String,
+    // 允許動作的 DISPLAY；空白表示不限制
+    allowed_displays: Vec<String>,
+    // 進入監控前反覆執行到成功的就緒命令，最多等 timeout 秒；strict 時逾時即結束，否則照常開始
+    wait_for_cmd: Option<String>,
+    wait_for_cmd_timeout_seconds: u64,
+    wait_for_cmd_strict: bool,
     restart_cmd: String,
     cooldown_seconds: u64,
     fallback_poll_seconds: u64,
+    // 定期摘要的間隔；0 表示不輸出
+    summary_interval_seconds: u64,
     scan_interval_seconds: u64,
+    max_events_per_wakeup: usize,
+    // 守護自身 CPU 用量的上限（佔單一核心的百分比），每 window 秒結算，超過時放慢輪詢
+    max_cpu_percent: Option<u32>,
+    max_cpu_window_seconds: u64,
+    // 主迴圈等待事件的方式；None 為自動（epoll，無法使用時改用 poll）
+    event_loop: Option<mux::Kind>,
+    // 一小時內監控迴圈 panic 超過此次數就結束；0 表示第一次 panic 就結束
+    max_panics_per_hour: usize,
+    // 單次量測超過此毫秒數時警告；0 表示不警告
+    slow_measure_warn_ms: u64,
+    verify_timeout_seconds: u64,
     dry_run: bool,
+    exit_after_restart: bool,
+    escalation: Vec<EscalationLevel>,
+    // --rule 的組合規則；--threshold 不在其中，由 Guard 以 rule::Rule::threshold 加在最前面
+    rules: Vec<rule::Rule>,
+    churn_threshold: Option<u64>,
+    // 預估超過門檻的剩餘時間低於此秒數時發出警示
+    forecast_warn: Option<u64>,
+    notify_cmd: Option<String>,
+    // --notify-digest：非緊急通知合併成摘要的時間窗（秒），overrides 依通知方式覆寫，0 表示立即送出
+    notify_digest_seconds: Option<u64>,
+    notify_digest_overrides: Vec<(Notifier, u64)>,
+    breaker_max_restarts: usize,
+    breaker_window_seconds: u64,
+    // --flap-crossings：window 秒內跨越門檻達此次數時暫停自動動作，
+    // 直到連線數連續 dwell 秒不超過 flap_clear（預設為門檻）或以 ctl clear 解除
+    flap_crossings: Option<usize>,
+    flap_window_seconds: u64,
+    flap_clear: Option<usize>,
+    flap_dwell_seconds: u64,
+    // --crash-loop：本程式重啟後 uptime 秒內程式自行結束，window 秒內達此次數時不再自動重啟，
+    // 直到 ctl clear 或 backoff 秒後；沒有 backoff 時只能手動解除
+    crash_loop_exits: Option<usize>,
+    crash_loop_window_seconds: u64,
+    crash_loop_uptime_seconds: u64,
+    crash_loop_backoff_seconds: Option<u64>,
+    // 偵測到外部重啟時把剩餘冷卻縮短到此秒數；None 表示維持原冷卻
+    external_restart_cooldown: Option<u64>,
+    // 連續超標達此秒數時無視冷卻與熔斷，強制重啟一次
+    deadline_restart_seconds: Option<u64>,
+    seccomp: bool,
+    seccomp_log_only: bool,
+    failure_notifiers: Vec<Notifier>,
+    // 重啟失敗（程式沒有回來）時每個事件執行一次的命令與內建處理
+    on_failure_cmd: Option<String>,
+    on_failure_action: FailureAction,
+    capture_dir: Option<PathBuf>,
+    // 重啟前替主程序保存 core；單份大小上限、gdb 時限與保留份數未指定時用預設值
+    core_dump_dir: Option<PathBuf>,
+    core_dump_max_bytes: Option<u64>,
+    core_dump_timeout_seconds: Option<u64>,
+    core_dump_keep: Option<usize>,
+    // 日誌寫到檔案而非 stdout；指定大小上限時自行輪替，保留 log_keep 個舊檔
+    log_file: Option<PathBuf>,
+    log_max_bytes: Option<u64>,
+    log_keep: Option<usize>,
+    // 與主程式一起重啟的附屬程序；空白表示只重啟主程式
+    group_members: Vec<GroupMember>,
+    // 群組的停止與啟動順序（成員名稱，含 --app-name），parse_args 結束時補齊預設
+    group_stop_order: Vec<String>,
+    group_start_order: Vec<String>,
+    drop_privileges: Option<String>,
+    // 守護程序與量測工具的 CPU、I/O 排程優先權
+    nice: Option<i32>,
+    ionice: Option<priority::IoPriority>,
+    scan_threads: usize,
+    backend: BackendChoice,
+    connections_from: ConnectionSource,
+    reconnect_cmd: Option<String>,
+    reconnect_wait_seconds: u64,
+    action: ActionKind,
+    // 動作前先詢問的外部策略命令，以及逾時或失敗時採用的裁決
+    policy_cmd: Option<String>,
+    policy_timeout_seconds: u64,
+    policy_fallback: PolicyVerdict,
+    // 動作前等待操作者以控制 socket 核准，逾時視為拒絕；等待開始時以 confirm_notifiers 通知
+    confirm_restart: bool,
+    confirm_timeout_seconds: u64,
+    confirm_notifiers: Vec<Notifier>,
+    // 系統過載時延後自動動作：1 分鐘負載（可換算為每核心）或 CPU 壓力（PSI some avg10，百分比），
+    // 最多延後 max_load_wait 秒
+    max_load: Option<f64>,
+    max_load_per_cpu: bool,
+    max_cpu_pressure: Option<f64>,
+    max_load_wait_seconds: Option<u64>,
+    // --defer-while-active：使用者正在用程式時延後自動動作。程式所有程序合計的 CPU 使用率
+    // （100% 為一個核心）超過 active_cpu_percent，或指定 active_idle 時 X server 的輸入閒置時間
+    // 不到它，就視為使用中；最多延後 active_max_wait 秒
+    defer_while_active: bool,
+    active_cpu_percent: Option<f64>,
+    active_idle_seconds: Option<u64>,
+    active_max_wait_seconds: Option<u64>,
+    // --only-when-idle：使用者閒置（X server 的輸入閒置時間，查不到時用 logind 的 IdleHint）
+    // 達此秒數才動作，最多保留 idle_max_hold 秒
+    only_when_idle_seconds: Option<u64>,
+    idle_max_hold_seconds: Option<u64>,
+    // 超過門檻後等連線數持平 wait_for_plateau 秒才動作，最多等 plateau_max_wait 秒
+    wait_for_plateau_seconds: Option<u64>,
+    plateau_max_wait_seconds: Option<u64>,
+    // 每隔 x_probe 秒確認 X server 還會回應，連續失敗 x_probe_failures 次時暫緩自動動作
+    x_probe_seconds: Option<u64>,
+    x_probe_failures: Option<u32>,
+    reload_signal: i32,
+    // 關閉程序時依序送出的訊號與每一級的等待時間
+    stop_ladder: Vec<StopStep>,
+    // 重新載入成功後的冷卻；None 表示沿用 --cooldown
+    reload_cooldown_seconds: Option<u64>,
+    // 與其他守護程序共用的最近一次動作；state_sync 時每次動作前重新讀取，其他程序剛動作過就等它的冷卻
+    state_file: Option<PathBuf>,
+    state_sync: bool,
+    // 取樣與事件經過的歷史記錄檔，由 history 子命令查詢；超過大小或保存期限時清理最舊的記錄
+    history_file: Option<PathBuf>,
+    history_resolution_seconds: u64,
+    history_max_bytes: u64,
+    history_max_age_seconds: u64,
+    history_query: history::Query,
+    // self-upgrade exec 過來時由前一個執行檔加上，指向保存的執行期狀態
+    resume_state: Option<PathBuf>,
+    // 重啟前記下應用程式的視窗配置，重啟後放回原處
+    restore_geometry: bool,
+    learn_new_name: bool,
+    // None 表示依後端決定：diag 預設開啟，ss 預設關閉
+    strict_attribution: Option<bool>,
+    // 排除伺服器端也由監控中程序持有的連線（兩端都是應用程式）
+    exclude_intra_app: bool,
+    verbosity: u8,
+    proc_root: ProcRoot,
+    control_socket: Option<PathBuf>,
+    // ctl 子命令要送出的命令列
+    control_request: String,
+    // restart-now 的原因，寫入日誌、狀態與失敗通知
+    reason: Option<String>,
+    // generate-man 的輸出路徑；None 表示 stdout
+    man_output: Option<PathBuf>,
+    // generate-unit：寫進 ExecStart 的參數（子命令之前的全部參數），以及是否為 systemd --user 的服務
+    unit_args: Vec<String>,
+    unit_user: bool,
+    // explain 以 JSON 輸出
+    explain_json: bool,
+    self_test_restart: bool,
     log_prefix: String,
+    // 每行日誌在時間之後另加的遞增欄位
+    log_order: Option<LogOrder>,
+    // --print-config：印出合併後的設定後結束（由 parse_args 處理）
+    print_config: bool,
+    // 合併設定目錄時的警告，啟動監控時寫進日誌
+    config_warnings: Vec<String>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
+            subcommand: Subcommand::Monitor,
             app_name: "qq".to_string(),
+            app_names: vec!["qq".to_string()],
+            apps: Vec::new(),
+            cmdline_match: Vec::new(),
             threshold: 10,
+            threshold_scope: ThresholdScope::Total,
+            threshold_windowless: None,
             display: env::var("DISPLAY").unwrap_or_else(|_| ":0".to_string()),
+            allowed_displays: Vec::new(),
+            wait_for_cmd: None,
+            wait_for_cmd_timeout_seconds: 120,
+            wait_for_cmd_strict: false,
             restart_cmd: "qq".to_string(),
             cooldown_seconds: 120,
             fallback_poll_seconds: 15,
+            summary_interval_seconds: 3600,
             scan_interval_seconds: 2,
+            max_events_per_wakeup: 4096,
+            max_cpu_percent: None,
+            max_cpu_window_seconds: 60,
+            event_loop: None,
+            max_panics_per_hour: 5,
+            slow_measure_warn_ms: 500,
+            verify_timeout_seconds: 15,
             dry_run: false,
+            exit_after_restart: false,
+            escalation: Vec::new(),
+            rules: Vec::new(),
+            churn_threshold: None,
+            forecast_warn: None,
+            notify_cmd: None,
+            notify_digest_seconds: None,
+            notify_digest_overrides: Vec::new(),
+            breaker_max_restarts: 0,
+            breaker_window_seconds: 3600,
+            flap_crossings: None,
+            flap_window_seconds: 600,
+            flap_clear: None,
+            flap_dwell_seconds: 300,
+            crash_loop_exits: None,
+            crash_loop_window_seconds: 600,
+            crash_loop_uptime_seconds: 60,
+            crash_loop_backoff_seconds: None,
+            external_restart_cooldown: None,
+            deadline_restart_seconds: None,
+            min_connection_age_seconds: None,
+            seccomp: false,
+            seccomp_log_only: false,
+            failure_notifiers: Vec::new(),
+            on_failure_cmd: None,
+            on_failure_action: FailureAction::None,
+            capture_dir: None,
+            core_dump_dir: None,
+            core_dump_max_bytes: None,
+            core_dump_timeout_seconds: None,
+            core_dump_keep: None,
+            log_file: None,
+            log_max_bytes: None,
+            log_keep: None,
+            group_members: Vec::new(),
+            group_stop_order: Vec::new(),
+            group_start_order: Vec::new(),
+            drop_privileges: None,
+            nice: None,
+            ionice: None,
+            scan_threads: thread::available_parallelism()
+                .map(|value| value.get().min(4))
+                .unwrap_or(1),
+            backend: BackendChoice::Auto,
+            connections_from: ConnectionSource::Ss,
+            reconnect_cmd: None,
+            reconnect_wait_seconds: 10,
+            action: ActionKind::Restart,
+            policy_cmd: None,
+            policy_timeout_seconds: 5,
+            policy_fallback: PolicyVerdict::Deny,
+            confirm_restart: false,
+            confirm_timeout_seconds: 300,
+            confirm_notifiers: Vec::new(),
+            max_load: None,
+            max_load_per_cpu: false,
+            max_cpu_pressure: None,
+            max_load_wait_seconds: None,
+            defer_while_active: false,
+            active_cpu_percent: None,
+            active_idle_seconds: None,
+            active_max_wait_seconds: None,
+            only_when_idle_seconds: None,
+            idle_max_hold_seconds: None,
+            wait_for_plateau_seconds: None,
+            plateau_max_wait_seconds: None,
+            x_probe_seconds: None,
+            x_probe_failures: None,
+            reload_signal: libc::SIGUSR1,
+            stop_ladder: vec![
+                StopStep {
+                    signal: libc::SIGTERM,
+                    wait: TERM_GRACE,
+                },
+                StopStep {
+                    signal: libc::SIGKILL,
+                    wait: KILL_GRACE,
+                },
+            ],
+            reload_cooldown_seconds: None,
+            state_file: None,
+            state_sync: false,
+            history_file: None,
+            history_resolution_seconds: 60,
+            history_max_bytes: 16 * 1024 * 1024,
+            history_max_age_seconds: 90 * 86400,
+            history_query: history::Query::default(),
+            resume_state: None,
+            restore_geometry: false,
+            learn_new_name: false,
+            strict_attribution: None,
+            exclude_intra_app: false,
+            verbosity: 0,
+            proc_root: ProcRoot::default(),
+            control_socket: None,
+            control_request: String::new(),
+            reason: None,
+            man_output: None,
+            unit_args: Vec::new(),
+            unit_user: false,
+            explain_json: false,
+            self_test_restart: false,
             log_prefix: "[qq-x11-guard-rs]".to_string(),
+            log_order: None,
+            print_config: false,
+            config_warnings: Vec::new(),
         }
     }
 }
 
+// 設定的來源依序為預設值、--config 設定檔與設定目錄、QQ_GUARD_* 環境變數、命令列：前兩者轉成的參數依序插在命令列之前，
+// 較後的來源出現的參數取代較前來源中同一參數的值（可重複的參數也是整組取代；設定目錄之間的 --app-name 例外，見 configfile::merge）
 fn parse_args() -> Result<Config, String> {
+    let mut args: Vec<String> = env::args().collect();
+    let leading = leading_args(&args);
+    let flags: Vec<(&str, bool)> = OPTIONS
+        .iter()
+        .flat_map(|option| {
+            option
+                .flags
+                .iter()
+                .filter(|flag| flag.starts_with("--"))
+                .map(|flag| (*flag, option.value.is_some()))
+        })
+        .collect();
+    let mut entries = configfile::from_env(env::vars_os(), &flags)?;
+    let path = single_flag_value(leading, "--config")?.or_else(|| {
+        env::var(configfile::ENV_CONFIG)
+            .ok()
+            .filter(|path| !path.is_empty())
+    });
+    let mut files = Vec::new();
+    if let Some(path) = path {
+        // 設定檔旁的 <設定檔>.d/ 存在時自動讀取
+        let drop_ins = PathBuf::from(format!("{path}.d"));
+        files.push(PathBuf::from(path));
+        if drop_ins.is_dir() {
+            files.extend(configfile::drop_in_files(&drop_ins)?);
+        }
+    }
+    if let Some(dir) = single_flag_value(leading, "--config-dir")? {
+        files.extend(configfile::drop_in_files(Path::new(&dir))?);
+    }
+    let mut merged = Vec::new();
+    let mut warnings = Vec::new();
+    for file in &files {
+        let layer = configfile::load(file, &flags)?;
+        warnings.extend(configfile::merge(&mut merged, layer, |value| {
+            parse_app_spec(value)
+                .map(|app| app.names)
+                .unwrap_or_default()
+        }));
+    }
+    merged.retain(|entry| !entries.iter().any(|env| env.flag == entry.flag));
+    merged.append(&mut entries);
+    entries = merged;
+    entries.retain(|entry| !leading.contains(&entry.flag));
+    let effective = leading
+        .iter()
+        .any(|arg| arg == "--print-config")
+        .then(|| render_effective_config(&entries, leading, &warnings));
+    let layered: Vec<String> = entries
+        .iter()
+        .flat_map(|entry| entry.args.iter().cloned())
+        .collect();
+    let layered_len = layered.len();
+    args.splice(1..1, layered);
+    let mut config = parse_arg_list(&args, layered_len).map_err(|error| {
+        // 錯誤提到的參數不是來自命令列時，指出它的來源
+        match entries
+            .iter()
+            .find(|entry| mentions_flag(&error, &entry.flag))
+        {
+            Some(entry) => format!("{error}（{} 來自{}）", entry.flag, entry.origin),
+            None => error,
+        }
+    })?;
+    // 參數都通過檢查才印出
+    if let Some(text) = effective {
+        print!("{text}");
+        std::process::exit(0);
+    }
+    config.config_warnings = warnings;
+    Ok(config)
+}
+
+// --print-config：合併後生效的設定，寫成可以直接當設定檔讀回的格式，每個值以註解標明來源；
+// 未列出的參數為預設值。命令列上的參數也列出，來源為「命令列」
+fn render_effective_config(
+    entries: &[configfile::Entry],
+    command_line: &[String],
+    warnings: &[String],
+) -> String {
+    // 參數的每個值（不帶值的參數為 None）及來源
+    type Sourced = Vec<(Option<String>, String)>;
+    // 依第一次出現的順序
+    let mut keys: Vec<(&str, Sourced)> = Vec::new();
+    let mut add = |flag: &'static str, value: Option<String>, origin: &str| {
+        let item = (value, origin.to_string());
+        match keys.iter_mut().find(|(key, _)| *key == flag) {
+            Some((_, items)) => items.push(item),
+            None => keys.push((flag, vec![item])),
+        }
+    };
+    let sources = entries
+        .iter()
+        .map(|entry| (entry.args.as_slice(), entry.origin.as_str()))
+        .chain(std::iter::once((command_line, "命令列")));
+    for (args, origin) in sources {
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            // 子命令等不是參數的字略過
+            let Some(option) = OPTIONS
+                .iter()
+                .find(|option| option.flags.contains(&arg.as_str()))
+            else {
+                continue;
+            };
+            let value = option.value.and_then(|_| args.next().cloned());
+            let Some(flag) = option.flags.iter().find(|flag| flag.starts_with("--")) else {
+                continue;
+            };
+            if !matches!(*flag, "--config" | "--config-dir" | "--print-config") {
+                add(flag, value, origin);
+            }
+        }
+    }
+    let mut lines = vec![
+        "# qq-x11-guard-rs --print-config：合併後生效的設定，未列出的參數為預設值".to_string(),
+    ];
+    lines.extend(warnings.iter().map(|warning| format!("# 警告：{warning}")));
+    let literal = |value: &str| {
+        if !value.is_empty()
+            && value
+                .chars()
+                .all(|char| char.is_ascii_digit() || char == '.')
+            && value.parse::<f64>().is_ok()
+        {
+            value.to_string()
+        } else {
+            json_string(value)
+        }
+    };
+    for (flag, items) in keys {
+        let key = flag[2..].replace('-', "_");
+        let mut origins: Vec<&str> = Vec::new();
+        for (_, origin) in &items {
+            if !origins.contains(&origin.as_str()) {
+                origins.push(origin);
+            }
+        }
+        let values: Vec<&String> = items
+            .iter()
+            .filter_map(|(value, _)| value.as_ref())
+            .collect();
+        let line = match values.as_slice() {
+            // 不帶值的參數；-vv 這類重複的記為次數
+            [] if items.len() == 1 => format!("{key} = true"),
+            [] => format!("{key} = {}", items.len()),
+            [value] => format!("{key} = {}", literal(value)),
+            _ => {
+                lines.push(format!("{key} = ["));
+                for (value, origin) in &items {
+                    if let Some(value) = value {
+                        lines.push(format!("  {},  # {origin}", literal(value)));
+                    }
+                }
+                lines.push("]".to_string());
+                continue;
+            }
+        };
+        lines.push(format!("{line}  # {}", origins.join("、")));
+    }
+    lines.join("\n") + "\n"
+}
+
+// 吃掉其後所有參數的子命令之前的參數
+fn leading_args(args: &[String]) -> &[String] {
+    let rest = args.get(1..).unwrap_or_default();
+    let end = rest
+        .iter()
+        .position(|arg| {
+            matches!(
+                arg.as_str(),
+                "ctl" | "explain" | "history" | "generate-man" | "generate-unit"
+            )
+        })
+        .unwrap_or(rest.len());
+    &rest[..end]
+}
+
+// --config 與 --config-dir 在讀取設定檔之前就要知道，只能出現一次
+fn single_flag_value(args: &[String], flag: &str) -> Result<Option<String>, String> {
+    let mut path = None;
+    for (at, _) in args.iter().enumerate().filter(|(_, arg)| *arg == flag) {
+        let value = args.get(at + 1).ok_or(format!("{flag} 需要值"))?;
+        if path.replace(value.clone()).is_some() {
+            return Err(format!("{flag} 只能指定一次"));
+        }
+    }
+    Ok(path)
+}
+
+// --threshold 不算出現在「--threshold-scope」之中
+fn mentions_flag(text: &str, flag: &str) -> bool {
+    text.match_indices(flag).any(|(at, _)| {
+        !text[at + flag.len()..]
+            .starts_with(|char: char| char.is_ascii_alphanumeric() || char == '-')
+    })
+}
+
+// args[1..1 + layered_len] 為設定檔與環境變數轉成的參數
+fn parse_arg_list(args: &[String], layered_len: usize) -> Result<Config, String> {
     let mut config = Config::default();
-    let args: Vec<String> = env::args().collect();
+    let mut app_names = Vec::new();
+    let mut apps: Vec<AppSpec> = Vec::new();
+    let mut restart_cmd_given = false;
     let mut index = 1;
 
     while index < args.len() {
         let key = args[index].as_str();
         match key {
+            // 可重複，也可以逗號分隔；名稱本身的逗號寫成 \x2c。每次指定是一組，
+            // 有任一組帶門檻或命令時各組分別監控
             "--app-name" => {
                 index += 1;
-                config.app_name = args.get(index).ok_or("--app-name 需要值")?.clone();
+                let app = parse_app_spec(args.get(index).ok_or("--app-name 需要值")?)
+                    .map_err(|err| format!("--app-name {err}"))?;
+                for name in &app.names {
+                    if app_names.contains(name) {
+                        return Err(format!("--app-name 名稱重複: {name}"));
+                    }
+                    app_names.push(name.clone());
+                }
+                apps.push(app);
+            }
+            "--cmdline-arg" => {
+                index += 1;
+                let value = args.get(index).ok_or("--cmdline-arg 需要值")?;
+                config
+                    .cmdline_match
+                    .push(parse_cmdline_arg(value).map_err(|err| format!("--cmdline-arg {err}"))?);
+            }
+            "--cmdline-regex" => {
+                index += 1;
+                let value = args.get(index).ok_or("--cmdline-regex 需要值")?;
+                config.cmdline_match.push(CmdlineMatch::Joined(
+                    pattern::Pattern::parse(value)
+                        .map_err(|err| format!("--cmdline-regex {err}"))?,
+                ));
+            }
+            "--match-cmdline" => {
+                index += 1;
+                let value = args.get(index).ok_or("--match-cmdline 需要值")?;
+                if matches_by_cmdline_only(&config.cmdline_match) {
+                    return Err("--match-cmdline 只能指定一次".to_string());
+                }
+                config.cmdline_match.push(CmdlineMatch::Only(
+                    pattern::Pattern::parse(value)
+                        .map_err(|err| format!("--match-cmdline {err}"))?,
+                ));
+            }
+            "--app-name-regex" => {
+                index += 1;
+                let value = args.get(index).ok_or("--app-name-regex 需要值")?;
+                config
+                    .cmdline_match
+                    .push(CmdlineMatch::Name(pattern::Pattern::parse(value).map_err(
+                        |err| format!("--app-name-regex {value} 無效：{err}"),
+                    )?));
             }
             "--threshold" => {
                 index += 1;
@@ -72,549 +671,14992 @@ fn parse_args() -> Result<Config, String> {
                     return Err("--threshold 必須 >= 1".to_string());
                 }
             }
+            "--threshold-scope" => {
+                index += 1;
+                let value = args.get(index).ok_or("--threshold-scope 需要值")?;
+                config.threshold_scope = match value.as_str() {
+                    "total" => ThresholdScope::Total,
+                    "per-process" => ThresholdScope::PerProcess,
+                    _ => {
+                        return Err(format!(
+                            "--threshold-scope 只接受 total、per-process: {value}"
+                        ))
+                    }
+                };
+            }
+            "--threshold-windowless" => {
+                index += 1;
+                let value = args.get(index).ok_or("--threshold-windowless 需要值")?;
+                config.threshold_windowless = Some(
+                    value
+                        .parse::<usize>()
+                        .map_err(|_| "--threshold-windowless 必須是非負整數".to_string())?,
+                );
+            }
             "--display" => {
                 index += 1;
                 config.display = args.get(index).ok_or("--display 需要值")?.clone();
             }
+            "--allowed-displays" => {
+                index += 1;
+                let value = args.get(index).ok_or("--allowed-displays 需要值")?;
+                config.allowed_displays = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(|item| {
+                        parse_display(item)
+                            .map(|_| item.to_string())
+                            .map_err(|err| format!("--allowed-displays {err}"))
+                    })
+                    .collect::<Result<_, _>>()?;
+            }
+            "--wait-for-cmd" => {
+                index += 1;
+                config.wait_for_cmd = Some(args.get(index).ok_or("--wait-for-cmd 需要值")?.clone());
+            }
+            "--wait-for-cmd-timeout" => {
+                index += 1;
+                let value = args.get(index).ok_or("--wait-for-cmd-timeout 需要值")?;
+                config.wait_for_cmd_timeout_seconds = parse_duration_secs(value)
+                    .map_err(|err| format!("--wait-for-cmd-timeout {err}"))?;
+                if config.wait_for_cmd_timeout_seconds == 0 {
+                    return Err("--wait-for-cmd-timeout 必須 >= 1".to_string());
+                }
+            }
+            "--wait-for-cmd-strict" => {
+                config.wait_for_cmd_strict = true;
+            }
             "--restart-cmd" => {
                 index += 1;
                 config.restart_cmd = args.get(index).ok_or("--restart-cmd 需要值")?.clone();
+                restart_cmd_given = true;
             }
             "--cooldown" => {
                 index += 1;
                 let value = args.get(index).ok_or("--cooldown 需要值")?;
-                config.cooldown_seconds = value
-                    .parse::<u64>()
-                    .map_err(|_| "--cooldown 必須是整數".to_string())?;
+                config.cooldown_seconds =
+                    parse_duration_secs(value).map_err(|err| format!("--cooldown {err}"))?;
             }
             "--fallback-poll" => {
                 index += 1;
                 let value = args.get(index).ok_or("--fallback-poll 需要值")?;
-                config.fallback_poll_seconds = value
-                    .parse::<u64>()
-                    .map_err(|_| "--fallback-poll 必須是正整數".to_string())?;
+                config.fallback_poll_seconds =
+                    parse_duration_secs(value).map_err(|err| format!("--fallback-poll {err}"))?;
                 if config.fallback_poll_seconds == 0 {
                     return Err("--fallback-poll 必須 >= 1".to_string());
                 }
             }
+            "--summary-interval" => {
+                index += 1;
+                let value = args.get(index).ok_or("--summary-interval 需要值")?;
+                config.summary_interval_seconds = parse_duration_secs(value)
+                    .map_err(|err| format!("--summary-interval {err}"))?;
+            }
+            "--max-events-per-wakeup" => {
+                index += 1;
+                let value = args.get(index).ok_or("--max-events-per-wakeup 需要值")?;
+                config.max_events_per_wakeup = value
+                    .parse::<usize>()
+                    .map_err(|_| "--max-events-per-wakeup 必須是正整數".to_string())?;
+                if config.max_events_per_wakeup == 0 {
+                    return Err("--max-events-per-wakeup 必須 >= 1".to_string());
+                }
+            }
+            "--max-cpu" => {
+                index += 1;
+                let value = args.get(index).ok_or("--max-cpu 需要值")?;
+                let percent = value
+                    .trim_end_matches('%')
+                    .parse::<u32>()
+                    .map_err(|_| "--max-cpu 必須是 1-100 的整數".to_string())?;
+                if !(1..=100).contains(&percent) {
+                    return Err("--max-cpu 必須是 1-100 的整數".to_string());
+                }
+                config.max_cpu_percent = Some(percent);
+            }
+            "--max-cpu-window" => {
+                index += 1;
+                let value = args.get(index).ok_or("--max-cpu-window 需要值")?;
+                config.max_cpu_window_seconds =
+                    parse_duration_secs(value).map_err(|err| format!("--max-cpu-window {err}"))?;
+                if config.max_cpu_window_seconds == 0 {
+                    return Err("--max-cpu-window 必須 >= 1".to_string());
+                }
+            }
+            "--event-loop" => {
+                index += 1;
+                let value = args.get(index).ok_or("--event-loop 需要值")?;
+                config.event_loop = match value.as_str() {
+                    "auto" => None,
+                    "epoll" => Some(mux::Kind::Epoll),
+                    "poll" => Some(mux::Kind::Poll),
+                    _ => return Err(format!("--event-loop 只接受 auto、epoll、poll: {value}")),
+                };
+            }
+            "--max-panics" => {
+                index += 1;
+                let value = args.get(index).ok_or("--max-panics 需要值")?;
+                config.max_panics_per_hour = value
+                    .parse::<usize>()
+                    .map_err(|_| "--max-panics 必須是非負整數".to_string())?;
+            }
+            "--slow-measure-warn-ms" => {
+                index += 1;
+                let value = args.get(index).ok_or("--slow-measure-warn-ms 需要值")?;
+                config.slow_measure_warn_ms = value
+                    .parse::<u64>()
+                    .map_err(|_| "--slow-measure-warn-ms 必須是非負整數".to_string())?;
+            }
             "--scan-interval" => {
                 index += 1;
                 let value = args.get(index).ok_or("--scan-interval 需要值")?;
-                config.scan_interval_seconds = value
-                    .parse::<u64>()
-                    .map_err(|_| "--scan-interval 必須是正整數".to_string())?;
+                config.scan_interval_seconds =
+                    parse_duration_secs(value).map_err(|err| format!("--scan-interval {err}"))?;
                 if config.scan_interval_seconds == 0 {
                     return Err("--scan-interval 必須 >= 1".to_string());
                 }
             }
+            "--verify-timeout" => {
+                index += 1;
+                let value = args.get(index).ok_or("--verify-timeout 需要值")?;
+                config.verify_timeout_seconds =
+                    parse_duration_secs(value).map_err(|err| format!("--verify-timeout {err}"))?;
+                if config.verify_timeout_seconds == 0 {
+                    return Err("--verify-timeout 必須 >= 1".to_string());
+                }
+            }
+            "--reconnect-cmd" => {
+                index += 1;
+                config.reconnect_cmd =
+                    Some(args.get(index).ok_or("--reconnect-cmd 需要值")?.clone());
+            }
+            "--reconnect-wait" => {
+                index += 1;
+                let value = args.get(index).ok_or("--reconnect-wait 需要值")?;
+                config.reconnect_wait_seconds =
+                    parse_duration_secs(value).map_err(|err| format!("--reconnect-wait {err}"))?;
+            }
+            "--action" => {
+                index += 1;
+                let value = args.get(index).ok_or("--action 需要值")?;
+                config.action = match value.as_str() {
+                    "restart" => ActionKind::Restart,
+                    "reload" => ActionKind::Reload,
+                    _ => return Err(format!("--action 只接受 restart、reload: {value}")),
+                };
+            }
+            "--policy-cmd" => {
+                index += 1;
+                config.policy_cmd = Some(args.get(index).ok_or("--policy-cmd 需要值")?.clone());
+            }
+            "--policy-timeout" => {
+                index += 1;
+                let value = args.get(index).ok_or("--policy-timeout 需要值")?;
+                config.policy_timeout_seconds =
+                    parse_duration_secs(value).map_err(|err| format!("--policy-timeout {err}"))?;
+                if config.policy_timeout_seconds == 0 {
+                    return Err("--policy-timeout 必須 >= 1".to_string());
+                }
+            }
+            "--policy-fallback" => {
+                index += 1;
+                let value = args.get(index).ok_or("--policy-fallback 需要值")?;
+                config.policy_fallback = match parse_policy_verdict(value) {
+                    Ok(PolicyVerdict::Replace(_)) | Err(_) => {
+                        return Err(format!(
+                            "--policy-fallback 只接受 allow、deny、defer:<時間>: {value}"
+                        ))
+                    }
+                    Ok(verdict) => verdict,
+                };
+            }
+            "--confirm-restart" => {
+                config.confirm_restart = true;
+            }
+            "--confirm-timeout" => {
+                index += 1;
+                let value = args.get(index).ok_or("--confirm-timeout 需要值")?;
+                config.confirm_timeout_seconds =
+                    parse_duration_secs(value).map_err(|err| format!("--confirm-timeout {err}"))?;
+                if config.confirm_timeout_seconds == 0 {
+                    return Err("--confirm-timeout 必須 >= 1".to_string());
+                }
+            }
+            "--confirm-notify" => {
+                index += 1;
+                let value = args.get(index).ok_or("--confirm-notify 需要值")?;
+                config.confirm_notifiers =
+                    parse_notifiers(value).map_err(|err| format!("--confirm-notify {err}"))?;
+            }
+            "--max-load" => {
+                index += 1;
+                let value = args.get(index).ok_or("--max-load 需要值")?;
+                config.max_load = Some(
+                    value
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|load| load.is_finite() && *load > 0.0)
+                        .ok_or("--max-load 必須是大於 0 的數字")?,
+                );
+            }
+            "--max-load-per-cpu" => {
+                config.max_load_per_cpu = true;
+            }
+            "--max-cpu-pressure" => {
+                index += 1;
+                let value = args.get(index).ok_or("--max-cpu-pressure 需要值")?;
+                config.max_cpu_pressure = Some(
+                    value
+                        .trim_end_matches('%')
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|percent| *percent > 0.0 && *percent <= 100.0)
+                        .ok_or("--max-cpu-pressure 必須是 0 到 100 之間的百分比")?,
+                );
+            }
+            "--max-load-wait" => {
+                index += 1;
+                let value = args.get(index).ok_or("--max-load-wait 需要值")?;
+                config.max_load_wait_seconds = Some(
+                    parse_duration_secs(value).map_err(|err| format!("--max-load-wait {err}"))?,
+                );
+            }
+            "--defer-while-active" => {
+                config.defer_while_active = true;
+            }
+            "--active-cpu" => {
+                index += 1;
+                let value = args.get(index).ok_or("--active-cpu 需要值")?;
+                config.active_cpu_percent = Some(
+                    value
+                        .trim_end_matches('%')
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|percent| percent.is_finite() && *percent > 0.0)
+                        .ok_or("--active-cpu 必須是大於 0 的百分比")?,
+                );
+            }
+            "--active-idle" => {
+                index += 1;
+                let value = args.get(index).ok_or("--active-idle 需要值")?;
+                let seconds =
+                    parse_duration_secs(value).map_err(|err| format!("--active-idle {err}"))?;
+                if seconds == 0 {
+                    return Err("--active-idle 必須大於 0".to_string());
+                }
+                config.active_idle_seconds = Some(seconds);
+            }
+            "--active-max-wait" => {
+                index += 1;
+                let value = args.get(index).ok_or("--active-max-wait 需要值")?;
+                config.active_max_wait_seconds = Some(
+                    parse_duration_secs(value).map_err(|err| format!("--active-max-wait {err}"))?,
+                );
+            }
+            "--only-when-idle" => {
+                index += 1;
+                let value = args.get(index).ok_or("--only-when-idle 需要值")?;
+                let seconds =
+                    parse_duration_secs(value).map_err(|err| format!("--only-when-idle {err}"))?;
+                if seconds == 0 {
+                    return Err("--only-when-idle 必須大於 0".to_string());
+                }
+                config.only_when_idle_seconds = Some(seconds);
+            }
+            "--idle-max-hold" => {
+                index += 1;
+                let value = args.get(index).ok_or("--idle-max-hold 需要值")?;
+                config.idle_max_hold_seconds = Some(
+                    parse_duration_secs(value).map_err(|err| format!("--idle-max-hold {err}"))?,
+                );
+            }
+            "--wait-for-plateau" => {
+                index += 1;
+                let value = args.get(index).ok_or("--wait-for-plateau 需要值")?;
+                let seconds = parse_duration_secs(value)
+                    .map_err(|err| format!("--wait-for-plateau {err}"))?;
+                if seconds == 0 {
+                    return Err("--wait-for-plateau 必須大於 0".to_string());
+                }
+                config.wait_for_plateau_seconds = Some(seconds);
+            }
+            "--plateau-max-wait" => {
+                index += 1;
+                let value = args.get(index).ok_or("--plateau-max-wait 需要值")?;
+                config.plateau_max_wait_seconds = Some(
+                    parse_duration_secs(value)
+                        .map_err(|err| format!("--plateau-max-wait {err}"))?,
+                );
+            }
+            "--x-probe" => {
+                index += 1;
+                let value = args.get(index).ok_or("--x-probe 需要值")?;
+                let seconds =
+                    parse_duration_secs(value).map_err(|err| format!("--x-probe {err}"))?;
+                if seconds == 0 {
+                    return Err("--x-probe 必須 >= 1".to_string());
+                }
+                config.x_probe_seconds = Some(seconds);
+            }
+            "--x-probe-failures" => {
+                index += 1;
+                let value = args.get(index).ok_or("--x-probe-failures 需要值")?;
+                config.x_probe_failures = Some(
+                    value
+                        .parse::<u32>()
+                        .ok()
+                        .filter(|count| *count >= 1)
+                        .ok_or("--x-probe-failures 必須是 >= 1 的整數")?,
+                );
+            }
+            "--reload-signal" => {
+                index += 1;
+                let value = args.get(index).ok_or("--reload-signal 需要值")?;
+                config.reload_signal =
+                    parse_signal(value).map_err(|err| format!("--reload-signal {err}"))?;
+            }
+            "--escalate" => {
+                index += 1;
+                let value = args.get(index).ok_or("--escalate 需要值")?;
+                config.stop_ladder =
+                    parse_stop_ladder(value).map_err(|err| format!("--escalate {err}"))?;
+            }
+            "--reload-cooldown" => {
+                index += 1;
+                let value = args.get(index).ok_or("--reload-cooldown 需要值")?;
+                config.reload_cooldown_seconds = Some(
+                    parse_duration_secs(value).map_err(|err| format!("--reload-cooldown {err}"))?,
+                );
+            }
+            "--state-file" => {
+                index += 1;
+                config.state_file =
+                    Some(PathBuf::from(args.get(index).ok_or("--state-file 需要值")?));
+            }
+            "--state-sync" => {
+                config.state_sync = true;
+            }
+            "--history-file" => {
+                index += 1;
+                config.history_file = Some(PathBuf::from(
+                    args.get(index).ok_or("--history-file 需要值")?,
+                ));
+            }
+            "--history-resolution" => {
+                index += 1;
+                let value = args.get(index).ok_or("--history-resolution 需要值")?;
+                config.history_resolution_seconds = parse_duration_secs(value)
+                    .map_err(|err| format!("--history-resolution {err}"))?;
+                if config.history_resolution_seconds == 0 {
+                    return Err("--history-resolution 必須 >= 1".to_string());
+                }
+            }
+            "--history-max-bytes" => {
+                index += 1;
+                let value = args.get(index).ok_or("--history-max-bytes 需要值")?;
+                config.history_max_bytes = value
+                    .parse::<u64>()
+                    .map_err(|_| "--history-max-bytes 必須是正整數".to_string())?;
+                // 至少要放得下檔頭與幾筆記錄
+                if config.history_max_bytes < 4096 {
+                    return Err("--history-max-bytes 必須 >= 4096".to_string());
+                }
+            }
+            "--history-max-age" => {
+                index += 1;
+                let value = args.get(index).ok_or("--history-max-age 需要值")?;
+                config.history_max_age_seconds =
+                    parse_duration_secs(value).map_err(|err| format!("--history-max-age {err}"))?;
+                if config.history_max_age_seconds == 0 {
+                    return Err("--history-max-age 必須 >= 1".to_string());
+                }
+            }
+            "--resume-state" => {
+                index += 1;
+                config.resume_state = Some(PathBuf::from(
+                    args.get(index).ok_or("--resume-state 需要值")?,
+                ));
+            }
+            "--restore-geometry" => {
+                config.restore_geometry = true;
+            }
+            "--learn-new-name" => {
+                config.learn_new_name = true;
+            }
             "--dry-run" => {
                 config.dry_run = true;
             }
-            "--help" | "-h" => {
-                print_help(&args[0]);
-                std::process::exit(0);
+            "--self-test-restart" => {
+                config.self_test_restart = true;
             }
-            _ => {
-                return Err(format!("不支援的參數: {key}"));
+            "--reason" => {
+                index += 1;
+                let value = args.get(index).ok_or("--reason 需要值")?.trim();
+                // 原因會以一行控制命令轉送給守護程序
+                if value.is_empty() || value.contains(['\n', '\r']) {
+                    return Err("--reason 不可為空白或含換行".to_string());
+                }
+                if value.len() > 200 {
+                    return Err("--reason 最多 200 位元組".to_string());
+                }
+                config.reason = Some(value.to_string());
             }
-        }
-        index += 1;
-    }
-    Ok(config)
-}
-
-fn print_help(program: &str) {
-    println!(
-        "用法: {program} [選項]\n\
-         \n\
-         --app-name <name>        監控程序名，預設 qq\n\
-         --threshold <n>          X11 連線門檻，預設 10\n\
-         --display <display>      X11 DISPLAY，預設 $DISPLAY 或 :0\n\
-         --restart-cmd <cmd>      超標後重啟命令，預設 qq\n\
-         --cooldown <sec>         重啟冷卻秒數，預設 120\n\
-         --fallback-poll <sec>    備援輪詢秒數，預設 15\n\
-         --scan-interval <sec>    PID 同步秒數，預設 2\n\
-         --dry-run                只輸出行為，不真的重啟\n\
-         -h, --help               顯示說明"
-    );
-}
-
-fn timestamp() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs()
-}
-
-fn log(config: &Config, message: &str) {
-    println!("{} {} {}", timestamp(), config.log_prefix, message);
-}
-
-fn display_to_socket(display: &str) -> Result<String, String> {
-    if !display.starts_with(':') {
-        return Err(format!("無效 DISPLAY: {display}"));
-    }
-    let display_num = display[1..].split('.').next().unwrap_or("");
-    if display_num.is_empty() || !display_num.chars().all(|char| char.is_ascii_digit()) {
-        return Err(format!("無效 DISPLAY: {display}"));
-    }
-    Ok(format!("/tmp/.X11-unix/X{display_num}"))
-}
-
-// ===== 區塊 2：程序與 socket 狀態收集 =====
-fn find_pids_by_name(process_name: &str) -> Vec<i32> {
-    let mut pids = Vec::new();
-    let entries = match fs::read_dir("/proc") {
-        Ok(value) => value,
-        Err(_) => return pids,
-    };
-
-    for entry in entries.flatten() {
-        let file_name = entry.file_name();
-        let pid_text = file_name.to_string_lossy();
-        if !pid_text.chars().all(|char| char.is_ascii_digit()) {
-            continue;
-        }
-        let pid = match pid_text.parse::<i32>() {
-            Ok(value) => value,
-            Err(_) => continue,
-        };
-
-        let comm_path = format!("/proc/{pid}/comm");
-        let comm = match fs::read_to_string(&comm_path) {
-            Ok(value) => value,
-            Err(_) => continue,
-        };
-        if comm.trim() == process_name {
-            pids.push(pid);
-        }
-    }
-
-    pids.sort_unstable();
-    pids
-}
-
-fn socket_inodes_for_pid(pid: i32) -> HashSet<String> {
-    let mut result = HashSet::new();
-    let fd_path = format!("/proc/{pid}/fd");
-    let entries = match fs::read_dir(fd_path) {
-        Ok(value) => value,
-        Err(_) => return result,
-    };
-
-    for entry in entries.flatten() {
-        let link = match fs::read_link(entry.path()) {
-            Ok(value) => value,
-            Err(_) => continue,
-        };
-        let link_text = link.to_string_lossy();
-        if let Some(inode) = parse_socket_inode(&link_text) {
-            result.insert(inode.to_string());
-        }
-    }
-    result
-}
-
-fn parse_socket_inode(text: &str) -> Option<&str> {
-    if !text.starts_with("socket:[") || !text.ends_with(']') {
-        return None;
-    }
-    Some(&text[8..text.len() - 1])
-}
-
-fn peer_inodes_on_x11_socket(socket_path: &str) -> HashSet<String> {
-    let mut inodes = HashSet::new();
-    let sources = [format!("@{socket_path}"), socket_path.to_string()];
+            "--exit-after-restart" => {
+                config.exit_after_restart = true;
+            }
+            "--churn-threshold" => {
+                index += 1;
+                let value = args.get(index).ok_or("--churn-threshold 需要值")?;
+                let limit = value
+                    .parse::<u64>()
+                    .map_err(|_| "--churn-threshold 必須是正整數".to_string())?;
+                if limit == 0 {
+                    return Err("--churn-threshold 必須 >= 1".to_string());
+                }
+                config.churn_threshold = Some(limit);
+            }
+            "--forecast-warn" => {
+                index += 1;
+                let value = args.get(index).ok_or("--forecast-warn 需要值")?;
+                let seconds =
+                    parse_duration_secs(value).map_err(|err| format!("--forecast-warn {err}"))?;
+                if seconds == 0 {
+                    return Err("--forecast-warn 必須 >= 1 秒".to_string());
+                }
+                config.forecast_warn = Some(seconds);
+            }
+            "--escalation" => {
+                index += 1;
+                let value = args.get(index).ok_or("--escalation 需要值")?;
+                let level = parse_escalation_level(value)?;
+                if config.escalation.iter().any(|item| item.name == level.name) {
+                    return Err(format!("--escalation 等級名稱重複: {}", level.name));
+                }
+                config.escalation.push(level);
+            }
+            "--rule" => {
+                index += 1;
+                let value = args.get(index).ok_or("--rule 需要值")?;
+                let rule = rule::parse_rule(value)?;
+                if config.rules.iter().any(|item| item.name == rule.name) {
+                    return Err(format!("--rule 規則名稱重複: {}", rule.name));
+                }
+                config.rules.push(rule);
+            }
+            "--drop-privileges" => {
+                index += 1;
+                config.drop_privileges =
+                    Some(args.get(index).ok_or("--drop-privileges 需要值")?.clone());
+            }
+            "--nice" => {
+                index += 1;
+                let value = args.get(index).ok_or("--nice 需要值")?;
+                config.nice =
+                    Some(priority::parse_nice(value).map_err(|err| format!("--nice {err}"))?);
+            }
+            "--ionice" => {
+                index += 1;
+                let value = args.get(index).ok_or("--ionice 需要值")?;
+                config.ionice =
+                    Some(priority::parse_ionice(value).map_err(|err| format!("--ionice {err}"))?);
+            }
+            "--scan-threads" => {
+                index += 1;
+                let value = args.get(index).ok_or("--scan-threads 需要值")?;
+                config.scan_threads = value
+                    .parse::<usize>()
+                    .map_err(|_| "--scan-threads 必須是正整數".to_string())?;
+                if config.scan_threads == 0 || config.scan_threads > 64 {
+                    return Err("--scan-threads 必須介於 1 到 64".to_string());
+                }
+            }
+            "--proc-root" => {
+                index += 1;
+                let value = args.get(index).ok_or("--proc-root 需要值")?;
+                if !Path::new(value).is_dir() {
+                    return Err(format!("--proc-root 不是目錄: {value}"));
+                }
+                config.proc_root = ProcRoot::new(value);
+            }
+            "--strict-attribution" => {
+                config.strict_attribution = Some(true);
+            }
+            "--no-strict-attribution" => {
+                config.strict_attribution = Some(false);
+            }
+            "--exclude-intra-app" => {
+                if !cfg!(feature = "backend-ss") {
+                    return Err(
+                        "--exclude-intra-app 需要 ss 提供的對端程序資訊，此版本未編入 backend-ss"
+                            .to_string(),
+                    );
+                }
+                config.exclude_intra_app = true;
+            }
+            "--backend" => {
+                index += 1;
+                let value = args.get(index).ok_or("--backend 需要值")?;
+                config.backend = match value.as_str() {
+                    "auto" => BackendChoice::Auto,
+                    "diag" => BackendChoice::Diag,
+                    "ss" => BackendChoice::Ss,
+                    "lsof" => BackendChoice::Lsof,
+                    _ => return Err(format!("--backend 只接受 auto、diag、ss、lsof: {value}")),
+                };
+                if !config.backend.compiled() {
+                    return Err(format!(
+                        "--backend {value} 未編入此版本（編譯時需啟用 backend-{value} feature）"
+                    ));
+                }
+            }
+            "--connections-from" => {
+                index += 1;
+                let value = args.get(index).ok_or("--connections-from 需要值")?;
+                config.connections_from = match value.as_str() {
+                    "ss" => ConnectionSource::Ss,
+                    "net-unix" => ConnectionSource::NetUnix,
+                    "fd" => ConnectionSource::Fd,
+                    _ => {
+                        return Err(format!(
+                            "--connections-from 只接受 ss、net-unix、fd: {value}"
+                        ))
+                    }
+                };
+            }
+            "--seccomp" => {
+                config.seccomp = true;
+            }
+            "--seccomp-log" => {
+                config.seccomp = true;
+                config.seccomp_log_only = true;
+            }
+            "--capture-dir" => {
+                index += 1;
+                config.capture_dir = Some(PathBuf::from(
+                    args.get(index).ok_or("--capture-dir 需要值")?,
+                ));
+            }
+            "--core-dump-dir" => {
+                index += 1;
+                config.core_dump_dir = Some(PathBuf::from(
+                    args.get(index).ok_or("--core-dump-dir 需要值")?,
+                ));
+            }
+            "--core-dump-max-bytes" => {
+                index += 1;
+                let value = args.get(index).ok_or("--core-dump-max-bytes 需要值")?;
+                let limit = value
+                    .parse::<u64>()
+                    .map_err(|_| "--core-dump-max-bytes 必須是正整數".to_string())?;
+                if limit == 0 {
+                    return Err("--core-dump-max-bytes 必須 >= 1".to_string());
+                }
+                config.core_dump_max_bytes = Some(limit);
+            }
+            "--core-dump-timeout" => {
+                index += 1;
+                let value = args.get(index).ok_or("--core-dump-timeout 需要值")?;
+                let seconds = parse_duration_secs(value)
+                    .map_err(|err| format!("--core-dump-timeout {err}"))?;
+                if seconds == 0 {
+                    return Err("--core-dump-timeout 必須 >= 1".to_string());
+                }
+                config.core_dump_timeout_seconds = Some(seconds);
+            }
+            "--core-dump-keep" => {
+                index += 1;
+                let value = args.get(index).ok_or("--core-dump-keep 需要值")?;
+                let keep = value
+                    .parse::<usize>()
+                    .map_err(|_| "--core-dump-keep 必須是正整數".to_string())?;
+                if keep == 0 {
+                    return Err("--core-dump-keep 必須 >= 1".to_string());
+                }
+                config.core_dump_keep = Some(keep);
+            }
+            // 已由 parse_args 讀取
+            "--config" | "--config-dir" => {
+                index += 1;
+            }
+            "--print-config" => {
+                config.print_config = true;
+            }
+            "--log-prefix" => {
+                index += 1;
+                config.log_prefix = args.get(index).ok_or("--log-prefix 需要值")?.clone();
+            }
+            "--log-file" => {
+                index += 1;
+                config.log_file = Some(PathBuf::from(args.get(index).ok_or("--log-file 需要值")?));
+            }
+            "--log-max-bytes" => {
+                index += 1;
+                let value = args.get(index).ok_or("--log-max-bytes 需要值")?;
+                let limit = value
+                    .parse::<u64>()
+                    .map_err(|_| "--log-max-bytes 必須是正整數".to_string())?;
+                if limit == 0 {
+                    return Err("--log-max-bytes 必須 >= 1".to_string());
+                }
+                config.log_max_bytes = Some(limit);
+            }
+            "--log-keep" => {
+                index += 1;
+                let value = args.get(index).ok_or("--log-keep 需要值")?;
+                config.log_keep = Some(
+                    value
+                        .parse::<usize>()
+                        .map_err(|_| "--log-keep 必須是非負整數".to_string())?,
+                );
+            }
+            "--log-order" => {
+                index += 1;
+                let value = args.get(index).ok_or("--log-order 需要值")?;
+                config.log_order = Some(match value.as_str() {
+                    "seq" => LogOrder::Seq,
+                    "uptime" => LogOrder::Uptime,
+                    _ => return Err(format!("--log-order 只接受 seq、uptime: {value}")),
+                });
+            }
+            "--failure-notify" => {
+                index += 1;
+                let value = args.get(index).ok_or("--failure-notify 需要值")?;
+                config.failure_notifiers =
+                    parse_notifiers(value).map_err(|err| format!("--failure-notify {err}"))?;
+            }
+            "--on-failure-cmd" => {
+                index += 1;
+                config.on_failure_cmd =
+                    Some(args.get(index).ok_or("--on-failure-cmd 需要值")?.clone());
+            }
+            "--on-failure-action" => {
+                index += 1;
+                let value = args.get(index).ok_or("--on-failure-action 需要值")?;
+                config.on_failure_action = match value.as_str() {
+                    "none" => FailureAction::None,
+                    "exit" => FailureAction::Exit,
+                    "reboot-session" => FailureAction::RebootSession,
+                    _ => {
+                        return Err(format!(
+                            "--on-failure-action 只接受 none、exit、reboot-session: {value}"
+                        ))
+                    }
+                };
+            }
+            "--group-member" => {
+                index += 1;
+                let value = args.get(index).ok_or("--group-member 需要值")?;
+                config.group_members.push(
+                    parse_group_member(value).map_err(|err| format!("--group-member {err}"))?,
+                );
+            }
+            "--group-stop-order" => {
+                index += 1;
+                config.group_stop_order =
+                    parse_group_order(args.get(index).ok_or("--group-stop-order 需要值")?);
+            }
+            "--group-start-order" => {
+                index += 1;
+                config.group_start_order =
+                    parse_group_order(args.get(index).ok_or("--group-start-order 需要值")?);
+            }
+            "--notify-cmd" => {
+                index += 1;
+                config.notify_cmd = Some(args.get(index).ok_or("--notify-cmd 需要值")?.clone());
+            }
+            "--notify-digest" => {
+                index += 1;
+                let value = args.get(index).ok_or("--notify-digest 需要值")?;
+                let (notifiers, seconds) =
+                    parse_notify_digest(value).map_err(|err| format!("--notify-digest {err}"))?;
+                if notifiers.is_empty() {
+                    config.notify_digest_seconds = Some(seconds);
+                } else {
+                    config
+                        .notify_digest_overrides
+                        .extend(notifiers.into_iter().map(|notifier| (notifier, seconds)));
+                }
+            }
+            "--breaker-max-restarts" => {
+                index += 1;
+                let value = args.get(index).ok_or("--breaker-max-restarts 需要值")?;
+                config.breaker_max_restarts = value
+                    .parse::<usize>()
+                    .map_err(|_| "--breaker-max-restarts 必須是整數".to_string())?;
+            }
+            "--external-restart-cooldown" => {
+                index += 1;
+                let value = args
+                    .get(index)
+                    .ok_or("--external-restart-cooldown 需要值")?;
+                config.external_restart_cooldown = Some(
+                    parse_duration_secs(value)
+                        .map_err(|err| format!("--external-restart-cooldown {err}"))?,
+                );
+            }
+            "--deadline-restart" => {
+                index += 1;
+                let value = args.get(index).ok_or("--deadline-restart 需要值")?;
+                let seconds = parse_duration_secs(value)
+                    .map_err(|err| format!("--deadline-restart {err}"))?;
+                if seconds == 0 {
+                    return Err("--deadline-restart 必須 >= 1".to_string());
+                }
+                config.deadline_restart_seconds = Some(seconds);
+            }
+            "--min-connection-age" => {
+                index += 1;
+                let value = args.get(index).ok_or("--min-connection-age 需要值")?;
+                let seconds = parse_duration_secs(value)
+                    .map_err(|err| format!("--min-connection-age {err}"))?;
+                if seconds == 0 {
+                    return Err("--min-connection-age 必須 >= 1".to_string());
+                }
+                config.min_connection_age_seconds = Some(seconds);
+            }
+            "--breaker-window" => {
+                index += 1;
+                let value = args.get(index).ok_or("--breaker-window 需要值")?;
+                config.breaker_window_seconds =
+                    parse_duration_secs(value).map_err(|err| format!("--breaker-window {err}"))?;
+                if config.breaker_window_seconds == 0 {
+                    return Err("--breaker-window 必須 >= 1".to_string());
+                }
+            }
+            "--flap-crossings" => {
+                index += 1;
+                let value = args.get(index).ok_or("--flap-crossings 需要值")?;
+                let crossings = value
+                    .parse::<usize>()
+                    .map_err(|_| "--flap-crossings 必須是正整數".to_string())?;
+                if crossings < 2 {
+                    return Err("--flap-crossings 必須 >= 2".to_string());
+                }
+                config.flap_crossings = Some(crossings);
+            }
+            "--flap-window" => {
+                index += 1;
+                let value = args.get(index).ok_or("--flap-window 需要值")?;
+                config.flap_window_seconds =
+                    parse_duration_secs(value).map_err(|err| format!("--flap-window {err}"))?;
+                if config.flap_window_seconds == 0 {
+                    return Err("--flap-window 必須 >= 1".to_string());
+                }
+            }
+            "--flap-clear" => {
+                index += 1;
+                let value = args.get(index).ok_or("--flap-clear 需要值")?;
+                config.flap_clear = Some(
+                    value
+                        .parse::<usize>()
+                        .map_err(|_| "--flap-clear 必須是非負整數".to_string())?,
+                );
+            }
+            "--flap-dwell" => {
+                index += 1;
+                let value = args.get(index).ok_or("--flap-dwell 需要值")?;
+                config.flap_dwell_seconds =
+                    parse_duration_secs(value).map_err(|err| format!("--flap-dwell {err}"))?;
+            }
+            "--crash-loop" => {
+                index += 1;
+                let value = args.get(index).ok_or("--crash-loop 需要值")?;
+                let exits = value
+                    .parse::<usize>()
+                    .map_err(|_| "--crash-loop 必須是正整數".to_string())?;
+                if exits < 2 {
+                    return Err("--crash-loop 必須 >= 2".to_string());
+                }
+                config.crash_loop_exits = Some(exits);
+            }
+            "--crash-loop-window" => {
+                index += 1;
+                let value = args.get(index).ok_or("--crash-loop-window 需要值")?;
+                config.crash_loop_window_seconds = parse_duration_secs(value)
+                    .map_err(|err| format!("--crash-loop-window {err}"))?;
+                if config.crash_loop_window_seconds == 0 {
+                    return Err("--crash-loop-window 必須 >= 1".to_string());
+                }
+            }
+            "--crash-loop-uptime" => {
+                index += 1;
+                let value = args.get(index).ok_or("--crash-loop-uptime 需要值")?;
+                config.crash_loop_uptime_seconds = parse_duration_secs(value)
+                    .map_err(|err| format!("--crash-loop-uptime {err}"))?;
+                if config.crash_loop_uptime_seconds == 0 {
+                    return Err("--crash-loop-uptime 必須 >= 1".to_string());
+                }
+            }
+            "--crash-loop-backoff" => {
+                index += 1;
+                let value = args.get(index).ok_or("--crash-loop-backoff 需要值")?;
+                config.crash_loop_backoff_seconds = Some(
+                    parse_duration_secs(value)
+                        .map_err(|err| format!("--crash-loop-backoff {err}"))?,
+                );
+            }
+            // 本程式一律在前景執行，接受此參數只為相容其他服務檔的慣例
+            "--foreground" | "-f" => {}
+            "restart-now" => {
+                config.subcommand = Subcommand::RestartNow;
+            }
+            "top" => {
+                config.subcommand = Subcommand::Top;
+            }
+            // 其後的參數全部是查詢條件
+            "history" => {
+                config.subcommand = Subcommand::History;
+                config.history_query = history::parse_query(&args[index + 1..], timestamp())?;
+                break;
+            }
+            "explain" => {
+                config.subcommand = Subcommand::Explain;
+                match &args[index + 1..] {
+                    [] => {}
+                    [format] if format == "json" => config.explain_json = true,
+                    _ => return Err("explain 只接受 json".to_string()),
+                }
+                break;
+            }
+            "generate-man" => {
+                config.subcommand = Subcommand::GenerateMan;
+                match &args[index + 1..] {
+                    [] => {}
+                    [path] => config.man_output = Some(PathBuf::from(path)),
+                    _ => return Err("generate-man 最多接受一個輸出路徑".to_string()),
+                }
+                break;
+            }
+            // 之前的參數就是服務要用的參數，一樣經過下面的檢查
+            "generate-unit" => {
+                if config.subcommand != Subcommand::Monitor {
+                    return Err("generate-unit 不能與其他子命令併用".to_string());
+                }
+                config.subcommand = Subcommand::GenerateUnit;
+                // 設定檔與環境變數的內容不寫進參數：服務啟動時以 --config 重新讀取，環境變數另外寫成 Environment=
+                config.unit_args = args[1 + layered_len..index].to_vec();
+                match &args[index + 1..] {
+                    [] => {}
+                    [flag] if flag == "--user" => config.unit_user = true,
+                    _ => return Err("generate-unit 只接受 --user".to_string()),
+                }
+                break;
+            }
+            // 其後的參數全部視為要送出的控制命令
+            "ctl" => {
+                config.subcommand = Subcommand::Ctl;
+                config.control_request = args[index + 1..].join(" ");
+                if config.control_request.is_empty() {
+                    return Err("ctl 需要命令，例如 ctl status".to_string());
+                }
+                break;
+            }
+            "--control-socket" => {
+                index += 1;
+                let path = args.get(index).ok_or("--control-socket 需要值")?;
+                check_socket_path_length(path.as_bytes())
+                    .map_err(|error| format!("--control-socket {error}: {path}"))?;
+                config.control_socket = Some(PathBuf::from(path));
+            }
+            "--verbose" => {
+                config.verbosity = config.verbosity.saturating_add(1);
+            }
+            // -v 可重複或合併（-vv）
+            flag if flag.len() > 1
+                && flag.starts_with('-')
+                && flag[1..].chars().all(|char| char == 'v') =>
+            {
+                config.verbosity = config.verbosity.saturating_add((flag.len() - 1) as u8);
+            }
+            "--help" | "-h" => {
+                print_help(&args[0]);
+                std::process::exit(0);
+            }
+            "--version" | "-V" => {
+                println!("{}", version_text());
+                std::process::exit(0);
+            }
+            _ => {
+                return Err(format!("不支援的參數: {key}"));
+            }
+        }
+        index += 1;
+    }
+
+    if !app_names.is_empty() {
+        config.app_name = app_names.join(",");
+        config.app_names = app_names;
+    }
+    if apps
+        .iter()
+        .any(|app| app.threshold.is_some() || app.restart_cmd.is_some())
+    {
+        if restart_cmd_given {
+            return Err(
+                "--app-name 名稱:門檻 分別監控多個程式時，重啟命令寫成 --app-name 名稱:門檻=命令，不能用 --restart-cmd"
+                    .to_string(),
+            );
+        }
+        // 各程式的 Guard 在自己的執行緒監控，只有一份的資源與一次性的子命令無法分給多個程式
+        let conflicts = [
+            (!config.group_members.is_empty(), "--group-member"),
+            (
+                !config.cmdline_match.is_empty(),
+                "--cmdline-arg、--cmdline-regex、--match-cmdline 或 --app-name-regex",
+            ),
+            (config.control_socket.is_some(), "--control-socket"),
+            (config.state_file.is_some(), "--state-file"),
+            (config.history_file.is_some(), "--history-file"),
+            (config.seccomp, "--seccomp"),
+            (config.exit_after_restart, "--exit-after-restart"),
+            (config.subcommand == Subcommand::RestartNow, "restart-now"),
+            (config.subcommand == Subcommand::Explain, "explain"),
+        ];
+        if let Some((_, flag)) = conflicts.iter().find(|(conflict, _)| *conflict) {
+            return Err(format!(
+                "--app-name 名稱:門檻 分別監控多個程式時不能與 {flag} 併用"
+            ));
+        }
+        config.apps = apps;
+    }
+    if config.learn_new_name && matches_by_cmdline_only(&config.cmdline_match) {
+        return Err("--learn-new-name 不能與 --match-cmdline 併用（不比對程序名稱）".to_string());
+    }
+    if name_patterns(&config.cmdline_match).next().is_some() {
+        if matches_by_cmdline_only(&config.cmdline_match) {
+            return Err(
+                "--app-name-regex 不能與 --match-cmdline 併用（不比對程序名稱）".to_string(),
+            );
+        }
+        if config.learn_new_name {
+            return Err(
+                "--learn-new-name 不能與 --app-name-regex 併用（新名稱請寫進 regex）".to_string(),
+            );
+        }
+    }
+    let uses_cmd_notifier = config
+        .escalation
+        .iter()
+        .any(|level| level.notifiers.contains(&Notifier::Command))
+        || config
+            .rules
+            .iter()
+            .any(|rule| rule.notifiers.contains(&Notifier::Command))
+        || config.failure_notifiers.contains(&Notifier::Command)
+        || config.confirm_notifiers.contains(&Notifier::Command);
+    if uses_cmd_notifier && config.notify_cmd.is_none() {
+        return Err("使用 cmd 通知方式時必須同時指定 --notify-cmd".to_string());
+    }
+    // escalate 規則成立期間與警示等級列在一起，名稱不能相同
+    if let Some(rule) = config.rules.iter().find(|rule| {
+        config
+            .escalation
+            .iter()
+            .any(|level| level.name == rule.name)
+    }) {
+        return Err(format!(
+            "--rule 規則名稱與 --escalation 等級重複: {}",
+            rule.name
+        ));
+    }
+    let uses_churn = config
+        .escalation
+        .iter()
+        .any(|level| level.condition == EscalationCondition::Churn);
+    match (uses_churn, config.churn_threshold) {
+        (true, None) => {
+            return Err("使用 churn 條件時必須同時指定 --churn-threshold".to_string());
+        }
+        // 只給 --churn-threshold 時自動加上只記錄日誌的 churn 等級
+        (false, Some(_)) => config.escalation.push(EscalationLevel {
+            name: "churn".to_string(),
+            condition: EscalationCondition::Churn,
+            log_level: LogLevel::Warn,
+            notifiers: Vec::new(),
+            urgency: Urgency::Normal,
+        }),
+        _ => {}
+    }
+    let uses_forecast = config
+        .escalation
+        .iter()
+        .any(|level| level.condition == EscalationCondition::Forecast);
+    match (uses_forecast, config.forecast_warn) {
+        (true, None) => {
+            return Err("使用 forecast 條件時必須同時指定 --forecast-warn".to_string());
+        }
+        // 只給 --forecast-warn 時自動加上發桌面通知的 forecast 等級
+        (false, Some(_)) => config.escalation.push(EscalationLevel {
+            name: "forecast".to_string(),
+            condition: EscalationCondition::Forecast,
+            log_level: LogLevel::Warn,
+            notifiers: vec![Notifier::Desktop],
+            urgency: Urgency::Normal,
+        }),
+        _ => {}
+    }
+    let uses_flapping = config
+        .escalation
+        .iter()
+        .any(|level| level.condition == EscalationCondition::Flapping);
+    match (uses_flapping, config.flap_crossings) {
+        (true, None) => {
+            return Err("使用 flapping 條件時必須同時指定 --flap-crossings".to_string());
+        }
+        // 只給 --flap-crossings 時自動加上發桌面通知的 flapping 等級
+        (false, Some(_)) => config.escalation.push(EscalationLevel {
+            name: "flapping".to_string(),
+            condition: EscalationCondition::Flapping,
+            log_level: LogLevel::Warn,
+            notifiers: vec![Notifier::Desktop],
+            urgency: Urgency::Normal,
+        }),
+        _ => {}
+    }
+    if config.flap_crossings.is_none()
+        && (config.flap_window_seconds != 600
+            || config.flap_clear.is_some()
+            || config.flap_dwell_seconds != 300)
+    {
+        return Err(
+            "--flap-window、--flap-clear 與 --flap-dwell 需要同時指定 --flap-crossings".to_string(),
+        );
+    }
+    if config.history_file.is_none()
+        && (config.subcommand == Subcommand::History
+            || config.history_resolution_seconds != 60
+            || config.history_max_bytes != 16 * 1024 * 1024
+            || config.history_max_age_seconds != 90 * 86400)
+    {
+        return Err(
+            "history 子命令與 --history-resolution、--history-max-bytes、--history-max-age 需要同時指定 --history-file"
+                .to_string(),
+        );
+    }
+    if config.subcommand == Subcommand::GenerateUnit
+        && (config.self_test_restart || config.resume_state.is_some())
+    {
+        return Err(
+            "generate-unit 的參數不能包含 --self-test-restart 或 --resume-state".to_string(),
+        );
+    }
+    if config.max_cpu_percent.is_none() && config.max_cpu_window_seconds != 60 {
+        return Err("--max-cpu-window 需要同時指定 --max-cpu".to_string());
+    }
+    if config.wait_for_cmd.is_none()
+        && (config.wait_for_cmd_timeout_seconds != 120 || config.wait_for_cmd_strict)
+    {
+        return Err(
+            "--wait-for-cmd-timeout 與 --wait-for-cmd-strict 需要同時指定 --wait-for-cmd"
+                .to_string(),
+        );
+    }
+    if config.crash_loop_exits.is_none()
+        && (config.crash_loop_window_seconds != 600
+            || config.crash_loop_uptime_seconds != 60
+            || config.crash_loop_backoff_seconds.is_some())
+    {
+        return Err(
+            "--crash-loop-window、--crash-loop-uptime 與 --crash-loop-backoff 需要同時指定 --crash-loop"
+                .to_string(),
+        );
+    }
+    if config.max_load.is_none() && config.max_load_per_cpu {
+        return Err("--max-load-per-cpu 需要同時指定 --max-load".to_string());
+    }
+    if config.max_load.is_none()
+        && config.max_cpu_pressure.is_none()
+        && config.max_load_wait_seconds.is_some()
+    {
+        return Err("--max-load-wait 需要同時指定 --max-load 或 --max-cpu-pressure".to_string());
+    }
+    if !config.defer_while_active {
+        for (flag, given) in [
+            ("--active-cpu", config.active_cpu_percent.is_some()),
+            ("--active-idle", config.active_idle_seconds.is_some()),
+            (
+                "--active-max-wait",
+                config.active_max_wait_seconds.is_some(),
+            ),
+        ] {
+            if given {
+                return Err(format!("{flag} 需要同時指定 --defer-while-active"));
+            }
+        }
+    }
+    if config.state_sync && config.state_file.is_none() {
+        return Err("--state-sync 需要同時指定 --state-file".to_string());
+    }
+    if config.only_when_idle_seconds.is_none() && config.idle_max_hold_seconds.is_some() {
+        return Err("--idle-max-hold 需要同時指定 --only-when-idle".to_string());
+    }
+    match (
+        config.wait_for_plateau_seconds,
+        config.plateau_max_wait_seconds,
+    ) {
+        (None, Some(_)) => {
+            return Err("--plateau-max-wait 需要同時指定 --wait-for-plateau".to_string());
+        }
+        (Some(plateau), limit) if plateau >= limit.unwrap_or(DEFAULT_PLATEAU_MAX_WAIT) => {
+            return Err(format!(
+                "--wait-for-plateau {plateau} 秒不小於最長等待 {} 秒，請一併調整 --plateau-max-wait",
+                limit.unwrap_or(DEFAULT_PLATEAU_MAX_WAIT)
+            ));
+        }
+        _ => {}
+    }
+    // net-unix 記下的是伺服器端的 inode，與 ss 回報的應用程式端 inode 對不上
+    if config.exclude_intra_app && config.connections_from == ConnectionSource::NetUnix {
+        return Err("--exclude-intra-app 無法搭配 --connections-from net-unix".to_string());
+    }
+    // net-unix 算的是整個 DISPLAY 的連線，無法與應用程式的 X client 對應
+    if config.threshold_windowless.is_some() && config.connections_from == ConnectionSource::NetUnix
+    {
+        return Err("--threshold-windowless 無法搭配 --connections-from net-unix".to_string());
+    }
+    if config.threshold_scope == ThresholdScope::PerProcess {
+        // net-unix 的連線不屬於任何程序；無視窗的連線數只有合計
+        if config.connections_from == ConnectionSource::NetUnix {
+            return Err(
+                "--threshold-scope per-process 無法搭配 --connections-from net-unix".to_string(),
+            );
+        }
+        if config.threshold_windowless.is_some() {
+            return Err(
+                "--threshold-scope per-process 無法搭配 --threshold-windowless".to_string(),
+            );
+        }
+    }
+    if config.x_probe_seconds.is_none() && config.x_probe_failures.is_some() {
+        return Err("--x-probe-failures 需要同時指定 --x-probe".to_string());
+    }
+    if config.core_dump_dir.is_none()
+        && (config.core_dump_max_bytes.is_some()
+            || config.core_dump_timeout_seconds.is_some()
+            || config.core_dump_keep.is_some())
+    {
+        return Err(
+            "--core-dump-max-bytes、--core-dump-timeout 與 --core-dump-keep 需要同時指定 --core-dump-dir"
+                .to_string(),
+        );
+    }
+    if config.log_file.is_none() && config.log_max_bytes.is_some() {
+        return Err("--log-max-bytes 需要同時指定 --log-file".to_string());
+    }
+    if config.log_max_bytes.is_none() && config.log_keep.is_some() {
+        return Err("--log-keep 需要同時指定 --log-max-bytes".to_string());
+    }
+    resolve_group_order(&mut config)?;
+    // 佔位符與（不經過 shell 的版本）argv 切字都在啟動時確認，而不是到重啟時才失敗
+    let commands = [
+        ("--restart-cmd", Some(&config.restart_cmd)),
+        ("--reconnect-cmd", config.reconnect_cmd.as_ref()),
+        ("--notify-cmd", config.notify_cmd.as_ref()),
+        ("--policy-cmd", config.policy_cmd.as_ref()),
+        ("--on-failure-cmd", config.on_failure_cmd.as_ref()),
+        ("--wait-for-cmd", config.wait_for_cmd.as_ref()),
+    ]
+    .into_iter()
+    .filter_map(|(flag, command)| Some((flag, command?)))
+    .chain(
+        config
+            .group_members
+            .iter()
+            .map(|member| ("--group-member", &member.restart_cmd)),
+    )
+    .chain(
+        config
+            .apps
+            .iter()
+            .filter_map(|app| Some(("--app-name", app.restart_cmd.as_ref()?))),
+    );
+    for (flag, command) in commands {
+        template::validate(command, template::SHELL).map_err(|err| format!("{flag} {err}"))?;
+        if !cfg!(feature = "shell-restart") {
+            split_argv(command)
+                .map_err(|err| format!("{flag} {err}（此版本未編入 shell-restart）"))?;
+        }
+    }
+    if config.self_test_restart && config.subcommand != Subcommand::Monitor {
+        return Err("--self-test-restart 不能與子命令併用".to_string());
+    }
+    if config.reason.is_some() && config.subcommand != Subcommand::RestartNow {
+        return Err("--reason 只能與 restart-now 併用".to_string());
+    }
+    if config.confirm_restart && config.control_socket.is_none() {
+        return Err("--confirm-restart 需要 --control-socket 才能回覆 approve 或 deny".to_string());
+    }
+    if config.subcommand == Subcommand::Ctl && config.control_socket.is_none() {
+        return Err(
+            "ctl 需要以 --control-socket 指定守護程序的控制 socket（須寫在 ctl 之前）".to_string(),
+        );
+    }
+    Ok(config)
+}
+
+// 選項說明的單一來源：--help 與 generate-man 都由這張表產生，新增參數時與 parse_args 一起更新
+struct OptionDoc {
+    flags: &'static [&'static str],
+    value: Option<&'static str>,
+    default: Option<&'static str>,
+    description: &'static str,
+}
+
+const fn option(
+    flags: &'static [&'static str],
+    value: Option<&'static str>,
+    default: Option<&'static str>,
+    description: &'static str,
+) -> OptionDoc {
+    OptionDoc {
+        flags,
+        value,
+        default,
+        description,
+    }
+}
+
+const SUBCOMMANDS: &[(&str, &str)] = &[
+    (
+        "restart-now",
+        "立即執行關閉與重啟流程後結束，不等待超標；指定 --control-socket 且守護程序執行中時交由它執行",
+    ),
+    (
+        "top",
+        "全螢幕即時檢視各程序的 X11 連線、fd 與記憶體（q 離開、r 立即量測）",
+    ),
+    (
+        "ctl <命令>",
+        "透過 --control-socket 對執行中的守護程序下命令：status、metrics、measure [json]、pause [時間]、resume、clear、restart [原因]",
+    ),
+    (
+        "explain [json]",
+        "量測一次後列出計數的每個階段：比對到的程序與依據、各程序的 socket inode、後端回報的對端（附原始輸出）、交集、計數與各項門檻是否觸發",
+    ),
+    (
+        "history [條件]",
+        "查詢 --history-file 的事件與取樣：--since/--until <時間>、--only incidents|samples、--app <名稱>、--outcome <結果>、--json",
+    ),
+    (
+        "generate-man [路徑]",
+        "輸出 roff 格式的 man page 到 stdout 或指定路徑",
+    ),
+    (
+        "generate-unit [--user]",
+        "以之前的參數產生 systemd 服務檔到 stdout（--user 為 systemd --user 的服務），參數先經過與啟動時相同的檢查",
+    ),
+];
+
+const OPTIONS: &[OptionDoc] = &[
+    option(
+        &["--app-name"],
+        Some("name[:threshold][=cmd]"),
+        Some("qq"),
+        "監控程序名（逐位元組比對；非 UTF-8 的位元組寫成 \\xNN，反斜線寫成 \\\\）；多個名稱以逗號分隔或重複指定，視為同一組程式。任一次帶門檻或命令時，每次指定的名稱各自成為獨立監控的程式，有自己的門檻、冷卻與重啟命令（預設為第一個名稱）",
+    ),
+    option(
+        &["--app-name-regex"],
+        Some("regex"),
+        None,
+        "以 regex 比對程序名稱，取代 --app-name 的完全相符（例如帶版本號的執行檔名稱），可重複，符合任一個即可",
+    ),
+    option(
+        &["--cmdline-arg"],
+        Some("index=regex"),
+        None,
+        "命令列第 index 個參數（argv[0] 為 0）須符合 regex，可重複",
+    ),
+    option(
+        &["--match-cmdline"],
+        Some("regex"),
+        None,
+        "改以命令列比對程式：以空白串接的整個命令列符合 regex 的程序都算，不看 --app-name（名稱只用於日誌）",
+    ),
+    option(
+        &["--cmdline-regex"],
+        Some("regex"),
+        None,
+        "以空白串接的整個命令列須符合 regex",
+    ),
+    option(&["--threshold"], Some("n"), Some("10"), "X11 連線門檻"),
+    option(
+        &["--threshold-scope"],
+        Some("scope"),
+        Some("total"),
+        "門檻的對象：total（所有程序合計）、per-process（任一程序單獨超過即觸發）",
+    ),
+    option(
+        &["--threshold-windowless"],
+        Some("n"),
+        None,
+        "以 X-Resource 查出不擁有任何視窗的連線，超過 n 條才動作；查不到時退回總數與 --threshold",
+    ),
+    option(
+        &["--display"],
+        Some("display"),
+        Some("$DISPLAY 或 :0"),
+        "X11 DISPLAY；host:D（例如 ssh -X 的 localhost:10.0）計算連到本機 TCP port 6000+D 的連線",
+    ),
+    option(
+        &["--allowed-displays"],
+        Some("list"),
+        None,
+        "只允許在這些 DISPLAY 上執行，例如 :0,:1；其他 DISPLAY 拒絕啟動",
+    ),
+    option(
+        &["--wait-for-cmd"],
+        Some("cmd"),
+        None,
+        "開始監控前反覆執行此命令直到結束碼為 0，例如 xset -display {display} q",
+    ),
+    option(
+        &["--wait-for-cmd-timeout"],
+        Some("時間"),
+        Some("2m"),
+        "等待 --wait-for-cmd 成功的上限，逾時後照常開始監控",
+    ),
+    option(
+        &["--wait-for-cmd-strict"],
+        None,
+        None,
+        "--wait-for-cmd 逾時時不開始監控，以非 0 結束",
+    ),
+    option(
+        &["--restart-cmd"],
+        Some("cmd"),
+        Some("qq"),
+        "超標後重啟命令；可用 {display}、{pids}、{count}、{reason} 等佔位符",
+    ),
+    option(&["--cooldown"], Some("時間"), Some("120"), "重啟冷卻時間"),
+    option(
+        &["--fallback-poll"],
+        Some("時間"),
+        Some("15"),
+        "備援輪詢間隔",
+    ),
+    option(
+        &["--summary-interval"],
+        Some("時間"),
+        Some("3600"),
+        "定期輸出一行摘要（不論是否超標），0 表示不輸出",
+    ),
+    option(
+        &["--scan-interval"],
+        Some("時間"),
+        Some("2"),
+        "PID 同步間隔",
+    ),
+    option(
+        &["--slow-measure-warn-ms"],
+        Some("ms"),
+        Some("500"),
+        "單次量測（取得 peer 並讀取 fd）超過此毫秒數時警告，0 表示不警告",
+    ),
+    option(
+        &["--max-events-per-wakeup"],
+        Some("n"),
+        Some("4096"),
+        "每次喚醒最多處理的 inotify 事件數，其餘留待下一輪",
+    ),
+    option(
+        &["--max-cpu"],
+        Some("percent"),
+        None,
+        "守護自身的 CPU 用量（佔單一核心的百分比）超過此值時放慢輪詢並警告，恢復後逐步回到原本的速度",
+    ),
+    option(
+        &["--max-cpu-window"],
+        Some("時間"),
+        Some("1m"),
+        "結算 --max-cpu 用量的區間",
+    ),
+    option(
+        &["--event-loop"],
+        Some("auto|epoll|poll"),
+        Some("auto"),
+        "主迴圈等待 inotify、pidfd 與控制 socket 的方式；其中一種被擋下時改用另一種",
+    ),
+    option(
+        &["--max-panics"],
+        Some("n"),
+        Some("5"),
+        "監控迴圈 panic 後自動重新開始；一小時內超過 n 次就以非 0 結束（0 表示不重新開始）",
+    ),
+    option(
+        &["--verify-timeout"],
+        Some("時間"),
+        Some("15"),
+        "重啟後等待程序重新出現的時間",
+    ),
+    option(
+        &["--reconnect-cmd"],
+        Some("cmd"),
+        None,
+        "超標時先執行此命令讓程式自行清理連線，仍超標才完整重啟",
+    ),
+    option(
+        &["--reconnect-wait"],
+        Some("時間"),
+        Some("10"),
+        "執行 --reconnect-cmd 後等待多久再量測",
+    ),
+    option(
+        &["--learn-new-name"],
+        None,
+        None,
+        "重啟命令帶起的程序名稱與 --app-name 不同時，改為同時比對新名稱",
+    ),
+    option(
+        &["--action"],
+        Some("name"),
+        Some("restart"),
+        "超標時的動作：restart 或 reload（先送訊號，無效才重啟）",
+    ),
+    option(
+        &["--policy-cmd"],
+        Some("cmd"),
+        None,
+        "自動動作前執行此命令，stdin 為 JSON 決策內容，stdout 回覆 allow、deny、defer:<時間> 或 replace:<動作>",
+    ),
+    option(
+        &["--policy-timeout"],
+        Some("時間"),
+        Some("5"),
+        "策略命令的時限，逾時採用 --policy-fallback",
+    ),
+    option(
+        &["--policy-fallback"],
+        Some("verdict"),
+        Some("deny"),
+        "策略命令逾時時的裁決：allow、deny 或 defer:<時間>；輸出無法解析時不會採用 allow",
+    ),
+    option(
+        &["--confirm-restart"],
+        None,
+        None,
+        "自動動作前等待操作者以控制 socket 回覆 approve 或 deny，需要 --control-socket",
+    ),
+    option(
+        &["--confirm-timeout"],
+        Some("時間"),
+        Some("300"),
+        "等待確認的時限，逾時視為拒絕",
+    ),
+    option(
+        &["--confirm-notify"],
+        Some("list"),
+        None,
+        "開始等待確認時的通知方式，例如 desktop+cmd",
+    ),
+    option(
+        &["--max-load"],
+        Some("n"),
+        None,
+        "1 分鐘平均負載超過 n 時延後自動動作",
+    ),
+    option(
+        &["--max-load-per-cpu"],
+        None,
+        None,
+        "--max-load 以每個 CPU 核心的負載計算",
+    ),
+    option(
+        &["--max-cpu-pressure"],
+        Some("百分比"),
+        None,
+        "CPU 壓力（/proc/pressure/cpu 的 some avg10）超過此百分比時延後自動動作",
+    ),
+    option(
+        &["--max-load-wait"],
+        Some("時間"),
+        Some("10m"),
+        "因系統負載最多延後多久，之後照常動作",
+    ),
+    option(
+        &["--defer-while-active"],
+        None,
+        None,
+        "程式正在使用中（CPU 使用率高或最近有輸入）時延後自動動作",
+    ),
+    option(
+        &["--active-cpu"],
+        Some("百分比"),
+        Some("20"),
+        "程式所有程序合計的 CPU 使用率（100 為一個核心）超過此值時視為使用中",
+    ),
+    option(
+        &["--active-idle"],
+        Some("時間"),
+        None,
+        "X server 的輸入閒置時間（MIT-SCREEN-SAVER）不到此時間時視為使用中",
+    ),
+    option(
+        &["--active-max-wait"],
+        Some("時間"),
+        Some("10m"),
+        "因使用中最多延後多久，之後照常動作",
+    ),
+    option(
+        &["--only-when-idle"],
+        Some("時間"),
+        None,
+        "使用者閒置（X server 的輸入閒置時間，查不到時用 logind 的 IdleHint）達此時間才自動動作",
+    ),
+    option(
+        &["--idle-max-hold"],
+        Some("時間"),
+        Some("1h"),
+        "等待閒置最多保留多久，之後照常動作",
+    ),
+    option(
+        &["--wait-for-plateau"],
+        Some("時間"),
+        None,
+        "超過門檻後不立即動作，等連線數持平（不再上升）這麼久才動作；期間回到門檻內即取消",
+    ),
+    option(
+        &["--plateau-max-wait"],
+        Some("時間"),
+        Some("5m"),
+        "等待持平最多多久，之後即使仍在上升也照常動作",
+    ),
+    option(
+        &["--x-probe"],
+        Some("時間"),
+        None,
+        "每隔此時間對 X11 socket 做一次連線設定探測，X server 無回應時暫緩自動動作",
+    ),
+    option(
+        &["--x-probe-failures"],
+        Some("n"),
+        Some("3"),
+        "連續幾次探測失敗才判定 X server 無回應",
+    ),
+    option(
+        &["--reload-signal"],
+        Some("sig"),
+        Some("SIGUSR1"),
+        "reload 送出的訊號（可寫 USR1、SIGHUP 或數字）",
+    ),
+    option(
+        &["--escalate"],
+        Some("sig:秒,..."),
+        Some("TERM:8,KILL:3"),
+        "關閉程序時依序送出的訊號與每一級的等待時間，程序結束就停",
+    ),
+    option(
+        &["--reload-cooldown"],
+        Some("時間"),
+        Some("--cooldown 的值"),
+        "重新載入成功後的冷卻時間",
+    ),
+    option(
+        &["--state-file"],
+        Some("path"),
+        None,
+        "記錄最近一次動作，啟動時沿用其中仍在冷卻的動作；多個守護程序可共用同一個檔案",
+    ),
+    option(
+        &["--state-sync"],
+        None,
+        None,
+        "每次動作前重新讀取 --state-file，其他守護程序剛動作過就改為等待它的冷卻",
+    ),
+    option(
+        &["--history-file"],
+        Some("path"),
+        None,
+        "把定期取樣的連線數與每個事件的經過（開始、警示、動作結果、結束）追加寫入此檔案，供 history 子命令查詢",
+    ),
+    option(
+        &["--history-resolution"],
+        Some("時間"),
+        Some("1m"),
+        "歷史記錄的取樣間隔",
+    ),
+    option(
+        &["--history-max-bytes"],
+        Some("n"),
+        Some("16777216"),
+        "歷史記錄檔超過 n bytes 時清理最舊的記錄，保留約四分之三",
+    ),
+    option(
+        &["--history-max-age"],
+        Some("時間"),
+        Some("90d"),
+        "歷史記錄的保存期限，更舊的記錄會被清理",
+    ),
+    option(
+        &["--resume-state"],
+        Some("path"),
+        None,
+        "沿用 self-upgrade 保存的執行期狀態（由 self-upgrade 自動加上，讀取後刪除）",
+    ),
+    option(
+        &["--restore-geometry"],
+        None,
+        None,
+        "重啟前記下視窗的位置、大小與所在桌面，重啟後放回原處（需要支援 EWMH 的視窗管理員）",
+    ),
+    option(
+        &["--control-socket"],
+        Some("path"),
+        None,
+        "建立控制 socket，接受 status、pause、resume、restart、self-upgrade 等命令（一次性模式不建立）",
+    ),
+    option(&["--dry-run"], None, None, "只輸出行為，不真的重啟"),
+    option(
+        &["--self-test-restart"],
+        None,
+        None,
+        "對臨時的假程序跑一次關閉與重啟流程，逐步回報結果後結束，不會動到 --app-name 的程序",
+    ),
+    option(
+        &["--reason"],
+        Some("text"),
+        None,
+        "restart-now 的原因，寫入日誌、狀態與失敗通知",
+    ),
+    option(
+        &["--exit-after-restart"],
+        None,
+        None,
+        "完成一次重啟並驗證後結束（失敗則非零結束）",
+    ),
+    option(
+        &["--escalation"],
+        Some("spec"),
+        None,
+        "警示升級等級，可重複，格式見下",
+    ),
+    option(
+        &["--rule"],
+        Some("spec"),
+        None,
+        "組合多個指標的觸發規則，可重複，格式見下",
+    ),
+    option(
+        &["--churn-threshold"],
+        Some("n"),
+        None,
+        "連線變動持續一分鐘達每分鐘 n 條開啟或關閉時發出警示（不重啟）",
+    ),
+    option(
+        &["--forecast-warn"],
+        Some("時間"),
+        None,
+        "依連線數成長趨勢預估的超標時間首次進入此範圍時發出警示（不重啟）",
+    ),
+    option(
+        &["--notify-cmd"],
+        Some("cmd"),
+        None,
+        "notify=cmd 時執行的命令（以 GUARD_* 環境變數傳遞內容）",
+    ),
+    option(
+        &["--notify-digest"],
+        Some("[list=]時間"),
+        None,
+        "非緊急通知在時間窗內合併成一則摘要送出；list=時間 只設定該通知方式，0 為立即送出，可重複",
+    ),
+    option(
+        &["--failure-notify"],
+        Some("list"),
+        None,
+        "重啟失敗時的通知方式，例如 desktop+cmd",
+    ),
+    option(
+        &["--on-failure-cmd"],
+        Some("command"),
+        None,
+        "重啟失敗、程式沒有回來時執行的命令，每個失敗事件一次（以 GUARD_* 環境變數傳遞內容）",
+    ),
+    option(
+        &["--on-failure-action"],
+        Some("action"),
+        Some("none"),
+        "重啟失敗時的內建處理：none、exit（以結束碼 3 結束守護）、reboot-session（loginctl terminate-session）",
+    ),
+    option(
+        &["--capture-dir"],
+        Some("path"),
+        None,
+        "重啟前把舊程序的 fd 清單、status 與連線歸屬保存到此目錄下",
+    ),
+    option(
+        &["--core-dump-dir"],
+        Some("path"),
+        None,
+        "重啟前以 gcore / gdb 把主程序的 core 保存到此目錄",
+    ),
+    option(
+        &["--core-dump-max-bytes"],
+        Some("n"),
+        Some("2147483648（2 GiB）"),
+        "單份 core 的大小上限，超過就放棄這一份",
+    ),
+    option(
+        &["--core-dump-timeout"],
+        Some("時間"),
+        Some("60"),
+        "保存 core 的時限，逾時結束 gdb 並照常重啟",
+    ),
+    option(
+        &["--core-dump-keep"],
+        Some("n"),
+        Some("3"),
+        "最多保留的 core 份數，較舊的自動刪除",
+    ),
+    option(
+        &["--config"],
+        Some("path"),
+        None,
+        "從 TOML 設定檔（副檔名 .json 時為 JSON）讀取參數，見 README 的「設定檔」；命令列上的同一參數優先",
+    ),
+    option(
+        &["--config-dir"],
+        Some("dir"),
+        None,
+        "依檔名順序合併目錄中的 *.toml（--config 旁的 <設定檔>.d/ 自動讀取）；純量取代、--app-name 附加",
+    ),
+    option(
+        &["--print-config"],
+        None,
+        None,
+        "印出合併後生效的設定與每個值的來源後結束",
+    ),
+    option(
+        &["--log-prefix"],
+        Some("text"),
+        Some("[qq-x11-guard-rs]"),
+        "每行日誌的前綴",
+    ),
+    option(
+        &["--log-file"],
+        Some("path"),
+        None,
+        "日誌附加寫入此檔案，不再輸出到 stdout",
+    ),
+    option(
+        &["--log-max-bytes"],
+        Some("n"),
+        None,
+        "日誌檔超過 n bytes 時改名為 <path>.1 並開新檔",
+    ),
+    option(
+        &["--log-keep"],
+        Some("n"),
+        Some("5"),
+        "輪替時最多保留的舊日誌檔數，0 表示不保留",
+    ),
+    option(
+        &["--log-order"],
+        Some("kind"),
+        None,
+        "在每行的時間之後加上只會遞增的欄位：seq（序號 #n）或 uptime（啟動後秒數 +s.mmm）",
+    ),
+    option(
+        &["--group-member"],
+        Some("name=cmd"),
+        None,
+        "與主程式一起重啟的附屬程序（以程序名稱比對，cmd 為它的啟動命令），可重複",
+    ),
+    option(
+        &["--group-stop-order"],
+        Some("list"),
+        Some("附屬程序依序，最後主程式"),
+        "群組的停止順序，以逗號列出所有成員（含 --app-name）",
+    ),
+    option(
+        &["--group-start-order"],
+        Some("list"),
+        Some("附屬程序依序，最後主程式"),
+        "群組的啟動順序，以逗號列出所有成員（含 --app-name）",
+    ),
+    option(
+        &["--breaker-max-restarts"],
+        Some("n"),
+        Some("0（停用）"),
+        "熔斷器：視窗內重啟達 n 次即暫停自動重啟",
+    ),
+    option(
+        &["--breaker-window"],
+        Some("時間"),
+        Some("1h"),
+        "熔斷器統計視窗",
+    ),
+    option(
+        &["--flap-crossings"],
+        Some("n"),
+        None,
+        "連線數在 --flap-window 內跨越門檻（超標與回到門檻內各算一次）達 n 次時進入 flapping 狀態，暫停自動動作",
+    ),
+    option(
+        &["--flap-window"],
+        Some("時間"),
+        Some("10m"),
+        "計算跨越次數的視窗",
+    ),
+    option(
+        &["--flap-clear"],
+        Some("n"),
+        Some("門檻"),
+        "解除 flapping 時連線數不得超過的條數",
+    ),
+    option(
+        &["--flap-dwell"],
+        Some("時間"),
+        Some("5m"),
+        "連線數需連續這麼久不超過 --flap-clear 才解除 flapping（或以 ctl clear 解除）",
+    ),
+    option(
+        &["--crash-loop"],
+        Some("n"),
+        None,
+        "重啟後程式在 --crash-loop-uptime 內自行結束，--crash-loop-window 內達 n 次時判定為崩潰循環，不再自動重啟",
+    ),
+    option(
+        &["--crash-loop-window"],
+        Some("時間"),
+        Some("10m"),
+        "計算快速結束次數的視窗",
+    ),
+    option(
+        &["--crash-loop-uptime"],
+        Some("時間"),
+        Some("1m"),
+        "重啟後在這段時間內結束才算快速結束",
+    ),
+    option(
+        &["--crash-loop-backoff"],
+        Some("時間"),
+        None,
+        "崩潰循環經過這段時間後自動解除；未指定時只能以 ctl clear 解除",
+    ),
+    option(
+        &["--external-restart-cooldown"],
+        Some("時間"),
+        None,
+        "偵測到程式被外部重啟時，剩餘冷卻最多保留此時間（0 為清除）",
+    ),
+    option(
+        &["--deadline-restart"],
+        Some("時間"),
+        None,
+        "連續超標達此時間仍未動作時，無視冷卻與熔斷強制重啟一次",
+    ),
+    option(
+        &["--min-connection-age"],
+        Some("時間"),
+        None,
+        "只有持續存在超過此時間的連線計入門檻，短暫的連線只記錄不計入",
+    ),
+    option(
+        &["--scan-threads"],
+        Some("n"),
+        Some("min(CPU 數, 4)"),
+        "平行掃描 /proc/<pid>/fd 的執行緒數，1 為不平行",
+    ),
+    option(
+        &["--drop-privileges"],
+        Some("user"),
+        None,
+        "以 root 啟動時，初始化後切換為指定使用者，只保留必要能力",
+    ),
+    option(
+        &["--nice"],
+        Some("n"),
+        None,
+        "以 nice n（-20 到 19）執行守護程序與 ss、lsof；重啟與通知命令維持原本的優先權",
+    ),
+    option(
+        &["--ionice"],
+        Some("class[:level]"),
+        None,
+        "守護程序與 ss、lsof 的 I/O 優先權：idle、best-effort:<0-7> 或 realtime:<0-7>",
+    ),
+    option(
+        &["--proc-root"],
+        Some("path"),
+        Some("/proc"),
+        "proc 檔案系統的位置（容器中掛載主機 proc 或測試用）",
+    ),
+    option(
+        &["--backend"],
+        Some("name"),
+        Some("auto"),
+        "取得 X11 peer 的方式：auto、diag（netlink）、ss、lsof（限編入的後端，見 --version）",
+    ),
+    option(
+        &["--connections-from"],
+        Some("source"),
+        Some("ss"),
+        "計數方式：ss（peer 交集，peer 由 --backend 取得）、net-unix（整個 DISPLAY 的連線）、fd（getpeername）",
+    ),
+    option(
+        &["--strict-attribution"],
+        None,
+        None,
+        "計數後再次確認每條連線的 peer 與 fd 仍一致，剔除不符者（diag 後端預設開啟）",
+    ),
+    option(&["--no-strict-attribution"], None, None, "關閉上述檢查"),
+    option(
+        &["--exclude-intra-app"],
+        None,
+        None,
+        "以 ss 的程序資訊排除兩端都是監控中程序的連線，只計入連到 X server 的連線（需 backend-ss）",
+    ),
+    option(
+        &["--seccomp"],
+        None,
+        None,
+        "初始化後啟用 seccomp 白名單，違規即終止",
+    ),
+    option(
+        &["--seccomp-log"],
+        None,
+        None,
+        "同 --seccomp，但違規只記錄（SECCOMP_RET_LOG），供除錯",
+    ),
+    option(
+        &["-f", "--foreground"],
+        None,
+        None,
+        "相容用，無作用（本程式一律在前景執行）",
+    ),
+    option(
+        &["-v", "--verbose"],
+        None,
+        None,
+        "每次檢查都輸出連線數；-vv 再逐條列出連線的持有程序；-vvv 再記錄主迴圈每一輪的等待與各階段耗時",
+    ),
+    option(&["-h", "--help"], None, None, "顯示說明"),
+    option(
+        &["-V", "--version"],
+        None,
+        None,
+        "顯示版本與編入的 peer 後端、命令執行方式",
+    ),
+];
+
+const TIME_FORMAT_NOTE: &str = "<時間> 可為純秒數（90）或帶單位（90s、2m、1h30m、7d）";
+
+const ESCALATION_FORMAT: &[&str] = &[
+    "--escalation 格式：<名稱>:<條件>[:<動作>,<動作>...]",
+    "  條件：percent=<n>（達門檻 n%）、consecutive=<n>（連續 n 次超標）、breaker-open、",
+    "        churn（連線變動達 --churn-threshold）、forecast（預估在 --forecast-warn 內超標）、",
+    "        flapping（連線數反覆跨越門檻，見 --flap-crossings）",
+    "  動作：log=info|warn|error、notify=desktop+cmd、urgency=low|normal|critical",
+    "  例：--escalation early:percent=80:notify=desktop,urgency=low",
+];
+
+const RULE_FORMAT: &[&str] = &[
+    "--rule 格式：<名稱>:<運算式>:<動作>[,<選項>...]",
+    "  指標：x11（計入門檻的連線數）、fd（fd 總數）、rss（RSS 總和，可寫 K/M/G）、churn（每分鐘連線變動）",
+    "  運算式：<指標> >|>=|<|<=|==|!= <數值>，以 and/&&、or/|| 與括號組合，and 先於 or",
+    "  動作：alert（記錄並通知）、restart（執行 --action）、escalate（成立期間視同警示等級）",
+    "  選項：cooldown=<時間>（預設同 --cooldown）、log=info|warn|error、notify=desktop+cmd、urgency=low|normal|critical",
+    "  例：--rule bloat:'x11 > 10 and rss > 1.5G':restart",
+    "      --rule busy:'fd > 500 or churn > 50/min':alert,notify=desktop,cooldown=30m",
+];
+
+impl OptionDoc {
+    fn usage(&self) -> String {
+        let flags = self.flags.join(", ");
+        match self.value {
+            Some(value) => format!("{flags} <{value}>"),
+            None => flags,
+        }
+    }
+
+    fn text(&self) -> String {
+        match self.default {
+            Some(default) => format!("{}，預設 {default}", self.description),
+            None => self.description.to_string(),
+        }
+    }
+}
+
+// 全形字佔兩欄，說明欄才對得齊
+fn help_line(usage: &str, text: &str) -> String {
+    let width: usize = usage
+        .chars()
+        .map(|char| if char.is_ascii() { 1 } else { 2 })
+        .sum();
+    let padding = if width < 25 { 25 - width } else { 2 };
+    format!("{usage}{}{text}", " ".repeat(padding))
+}
+
+fn help_text(program: &str) -> String {
+    let mut lines = vec![format!("用法: {program} [子命令] [選項]"), String::new()];
+    lines.push("子命令：".to_string());
+    lines.extend(
+        SUBCOMMANDS
+            .iter()
+            .map(|(usage, text)| help_line(usage, text)),
+    );
+    lines.push(String::new());
+    lines.push("選項：".to_string());
+    lines.extend(
+        OPTIONS
+            .iter()
+            .map(|option| help_line(&option.usage(), &option.text())),
+    );
+    lines.push(String::new());
+    lines.push(TIME_FORMAT_NOTE.to_string());
+    lines.push(String::new());
+    lines.extend(ESCALATION_FORMAT.iter().map(|line| line.to_string()));
+    lines.push(String::new());
+    lines.extend(RULE_FORMAT.iter().map(|line| line.to_string()));
+    lines.join("\n")
+}
+
+fn print_help(program: &str) {
+    println!("{}", help_text(program));
+}
+
+// 編譯時啟用的 cargo feature；minimal 版本沒有 ss、lsof，命令也不經過 sh
+const COMPILED_FEATURES: &[(&str, bool)] = &[
+    ("backend-diag", cfg!(feature = "backend-diag")),
+    ("backend-ss", cfg!(feature = "backend-ss")),
+    ("backend-lsof", cfg!(feature = "backend-lsof")),
+    ("backend-procfs", cfg!(feature = "backend-procfs")),
+    ("shell-restart", cfg!(feature = "shell-restart")),
+];
+
+fn version_text() -> String {
+    let enabled: Vec<&str> = COMPILED_FEATURES
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+        .collect();
+    let backends: Vec<&str> = enabled
+        .iter()
+        .filter_map(|name| name.strip_prefix("backend-"))
+        .collect();
+    format!(
+        "qq-x11-guard-rs {}\n編入的 feature: {}\npeer 後端: {}\n命令執行: {}",
+        env!("CARGO_PKG_VERSION"),
+        enabled.join(", "),
+        backends.join(", "),
+        if cfg!(feature = "shell-restart") {
+            "sh -c / sh -lc"
+        } else {
+            "直接以 argv 執行（不經過 shell）"
+        }
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ActionKind {
+    Restart,
+    Reload,
+}
+
+impl ActionKind {
+    fn name(self) -> &'static str {
+        match self {
+            ActionKind::Restart => "restart",
+            ActionKind::Reload => "reload",
+        }
+    }
+}
+
+// --on-failure-action：重啟失敗時的內建處理
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FailureAction {
+    None,
+    // 以結束碼 3 結束守護，交給 systemd 的 Restart= 或 OnFailure= 處理
+    Exit,
+    // 以 loginctl 結束程式所在的登入 session，由 display manager 重新登入
+    RebootSession,
+}
+
+impl FailureAction {
+    fn name(self) -> &'static str {
+        match self {
+            FailureAction::None => "none",
+            FailureAction::Exit => "exit",
+            FailureAction::RebootSession => "reboot-session",
+        }
+    }
+}
+
+// --policy-cmd 的裁決：照做、不做、延後若干秒再問，或改用另一種動作
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PolicyVerdict {
+    Allow,
+    Deny,
+    Defer(u64),
+    Replace(ActionKind),
+}
+
+impl PolicyVerdict {
+    fn text(self) -> String {
+        match self {
+            PolicyVerdict::Allow => "allow".to_string(),
+            PolicyVerdict::Deny => "deny".to_string(),
+            PolicyVerdict::Defer(seconds) => format!("defer:{seconds}"),
+            PolicyVerdict::Replace(action) => format!("replace:{}", action.name()),
+        }
+    }
+}
+
+// 只接受單獨一行的裁決，其餘一律視為格式錯誤
+fn parse_policy_verdict(text: &str) -> Result<PolicyVerdict, String> {
+    let line = text.trim();
+    if line.contains('\n') {
+        return Err("輸出超過一行".to_string());
+    }
+    match line.split_once(':') {
+        None if line == "allow" => Ok(PolicyVerdict::Allow),
+        None if line == "deny" => Ok(PolicyVerdict::Deny),
+        Some(("defer", value)) => match parse_duration_secs(value)? {
+            0 => Err("defer 必須 >= 1 秒".to_string()),
+            seconds => Ok(PolicyVerdict::Defer(seconds)),
+        },
+        Some(("replace", "restart")) => Ok(PolicyVerdict::Replace(ActionKind::Restart)),
+        Some(("replace", "reload")) => Ok(PolicyVerdict::Replace(ActionKind::Reload)),
+        _ => Err(format!("無法辨識的裁決: {line}")),
+    }
+}
+
+fn json_object(fields: &[(&str, String)]) -> String {
+    let body: Vec<String> = fields
+        .iter()
+        .map(|(key, value)| format!("{}:{value}", json_string(key)))
+        .collect();
+    format!("{{{}}}", body.join(","))
+}
+
+fn json_string(text: &str) -> String {
+    let mut escaped = String::from("\"");
+    for char in text.chars() {
+        match char {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            char if (char as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", char as u32)),
+            char => escaped.push(char),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+const SIGNAL_NAMES: [(&str, i32); 11] = [
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("USR1", libc::SIGUSR1),
+    ("USR2", libc::SIGUSR2),
+    ("TERM", libc::SIGTERM),
+    ("CONT", libc::SIGCONT),
+    ("WINCH", libc::SIGWINCH),
+    ("PWR", libc::SIGPWR),
+    ("ALRM", libc::SIGALRM),
+    ("KILL", libc::SIGKILL),
+];
+
+fn parse_signal(text: &str) -> Result<i32, String> {
+    if let Ok(number) = text.parse::<i32>() {
+        if (1..=libc::SIGRTMAX()).contains(&number) && number != libc::SIGKILL {
+            return Ok(number);
+        }
+        return Err(format!("無效的訊號編號: {text}"));
+    }
+    let upper = text.to_ascii_uppercase();
+    let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+    SIGNAL_NAMES
+        .iter()
+        .find(|(candidate, _)| *candidate == name)
+        .map(|(_, number)| *number)
+        .filter(|number| *number != libc::SIGKILL)
+        .ok_or_else(|| format!("不支援的訊號: {text}"))
+}
+
+fn signal_name(signal: i32) -> String {
+    match SIGNAL_NAMES.iter().find(|(_, number)| *number == signal) {
+        Some((name, _)) => format!("SIG{name}"),
+        None => format!("訊號 {signal}"),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct StopStep {
+    signal: i32,
+    wait: Duration,
+}
+
+// --escalate TERM:8,INT:3,KILL:3：訊號名稱或編號，冒號後是送出後等待的時間。
+// 與 --reload-signal 不同，這裡允許 KILL
+fn parse_stop_ladder(value: &str) -> Result<Vec<StopStep>, String> {
+    let ladder = value
+        .split(',')
+        .map(|step| {
+            let (name, wait) = step
+                .split_once(':')
+                .ok_or_else(|| format!("每一級須寫成 <訊號>:<時間>: {step}"))?;
+            let upper = name.to_ascii_uppercase();
+            let signal = if upper.strip_prefix("SIG").unwrap_or(&upper) == "KILL" || name == "9" {
+                libc::SIGKILL
+            } else {
+                parse_signal(name)?
+            };
+            let seconds = parse_duration_secs(wait)?;
+            if seconds == 0 {
+                return Err(format!("等待時間必須 >= 1: {step}"));
+            }
+            Ok(StopStep {
+                signal,
+                wait: Duration::from_secs(seconds),
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    // SIGKILL 之後沒有程序能再收到訊號；同一訊號送兩次通常是打錯順序
+    for (index, step) in ladder.iter().enumerate() {
+        if ladder[..index]
+            .iter()
+            .any(|prev| prev.signal == step.signal)
+        {
+            return Err(format!("訊號重複: {}", signal_name(step.signal)));
+        }
+        if step.signal == libc::SIGKILL && index + 1 != ladder.len() {
+            return Err("SIGKILL 只能放在最後一級".to_string());
+        }
+    }
+    Ok(ladder)
+}
+
+fn parse_duration_secs(text: &str) -> Result<u64, String> {
+    if text.is_empty() {
+        return Err("需要時間值".to_string());
+    }
+    if text.chars().all(|char| char.is_ascii_digit()) {
+        return text
+            .parse::<u64>()
+            .map_err(|_| format!("時間數值過大: {text}"));
+    }
+
+    let units = [('d', 86400u64), ('h', 3600), ('m', 60), ('s', 1)];
+    let mut total = 0u64;
+    let mut next_unit = 0usize;
+    let mut digits = String::new();
+    for char in text.chars() {
+        if char.is_ascii_digit() {
+            digits.push(char);
+            continue;
+        }
+        let position = units[next_unit..]
+            .iter()
+            .position(|(unit, _)| *unit == char)
+            .ok_or_else(|| format!("無效時間格式: {text}"))?;
+        if digits.is_empty() {
+            return Err(format!("無效時間格式: {text}"));
+        }
+        let (_, scale) = units[next_unit + position];
+        let value = digits
+            .parse::<u64>()
+            .ok()
+            .and_then(|value| value.checked_mul(scale))
+            .and_then(|value| value.checked_add(total))
+            .ok_or_else(|| format!("時間數值過大: {text}"))?;
+        total = value;
+        next_unit += position + 1;
+        digits.clear();
+    }
+    if !digits.is_empty() {
+        return Err(format!("無效時間格式: {text}（缺少單位）"));
+    }
+    Ok(total)
+}
+
+const DEFAULT_LOG_KEEP: usize = 5;
+const DEFAULT_MAX_LOAD_WAIT: u64 = 600;
+const DEFAULT_ACTIVE_CPU_PERCENT: f64 = 20.0;
+const DEFAULT_ACTIVE_MAX_WAIT: u64 = 600;
+// --defer-while-active 取樣 CPU 時間的間隔；只在準備動作時取樣一次
+const ACTIVE_SAMPLE: Duration = Duration::from_millis(500);
+const DEFAULT_IDLE_MAX_HOLD: u64 = 3600;
+const LOGIND_TIMEOUT: Duration = Duration::from_secs(5);
+// 狀態檔的鎖最多等這麼久；另一個守護程序只在讀寫的瞬間持有
+const STATE_LOCK_TIMEOUT: Duration = Duration::from_secs(2);
+// --resume-state 只沿用剛寫入的狀態；更舊的多半是 exec 失敗或手動留下的，內容已不符合現況
+const RESUME_MAX_AGE: Duration = Duration::from_secs(60);
+// --restore-geometry 重啟後最多等新視窗出現這麼久；保存的配置超過 LAYOUT_MAX_AGE 就不再還原
+const LAYOUT_WAIT: Duration = Duration::from_secs(30);
+const LAYOUT_POLL: Duration = Duration::from_millis(500);
+const LAYOUT_MAX_AGE: Duration = Duration::from_secs(3600);
+// --wait-for-cmd 兩次嘗試的間隔與單次執行的時限（不超過剩餘的等待時間）
+const WAIT_FOR_CMD_INTERVAL: Duration = Duration::from_secs(2);
+const WAIT_FOR_CMD_ATTEMPT: Duration = Duration::from_secs(10);
+const DEFAULT_PLATEAU_MAX_WAIT: u64 = 300;
+const FAILURE_CMD_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_CORE_DUMP_MAX_BYTES: u64 = 2 << 30;
+const DEFAULT_CORE_DUMP_TIMEOUT: u64 = 60;
+const DEFAULT_CORE_DUMP_KEEP: usize = 3;
+const DEFAULT_X_PROBE_FAILURES: u32 = 3;
+
+fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn unix_seconds(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+// 單調時鐘的時間點與 unix 毫秒互換，都以當下的系統時鐘為準；self-upgrade 交接狀態用
+fn unix_ms(at: Instant) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let wall = match Instant::now().checked_duration_since(at) {
+        Some(ago) => now.saturating_sub(ago),
+        None => now + at.duration_since(Instant::now()),
+    };
+    wall.as_millis() as u64
+}
+
+fn instant_from_unix_ms(ms: u64) -> Option<Instant> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let at = Duration::from_millis(ms);
+    if at <= now {
+        Instant::now().checked_sub(now - at)
+    } else {
+        Instant::now().checked_add(at - now)
+    }
+}
+
+fn wall_clock_ago(at: SystemTime) -> String {
+    let ago = SystemTime::now()
+        .duration_since(at)
+        .unwrap_or_default()
+        .as_secs();
+    format!("{}（{ago} 秒前）", wall_clock(at))
+}
+
+// 給人看的輸出用本地時間；時區依 TZ 或 /etc/localtime，無法換算時退回 unix 秒數
+fn wall_clock(at: SystemTime) -> String {
+    let seconds = unix_seconds(at) as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&seconds, &mut tm) }.is_null() {
+        return format!("@{seconds}");
+    }
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LogLevel {
+    Info,
+    Warn,
+    Error,
+}
+
+// 累計輸出過的警告與錯誤行數，定期摘要以差值回報期間內的數量
+// --log-order：時間取自系統時鐘，NTP 調整時可能倒退；冷卻與各種間隔都以 Instant 計算不受影響，
+// 但日誌的先後只能靠另一個只會遞增的欄位分辨
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LogOrder {
+    Seq,
+    Uptime,
+}
+
+static LOG_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+// main 一開始就設定；測試等沒有設定的情況以第一行日誌為起點
+static LOG_EPOCH: OnceLock<Instant> = OnceLock::new();
+
+fn log_order_field(order: LogOrder) -> String {
+    match order {
+        LogOrder::Seq => format!("#{}", LOG_SEQUENCE.fetch_add(1, Ordering::Relaxed) + 1),
+        LogOrder::Uptime => format!(
+            "+{:.3}",
+            LOG_EPOCH.get_or_init(Instant::now).elapsed().as_secs_f64()
+        ),
+    }
+}
+
+static LOGGED_WARNINGS: AtomicU64 = AtomicU64::new(0);
+static LOGGED_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+fn log(config: &Config, message: &str) {
+    log_at(config, LogLevel::Info, message);
+}
+
+fn log_at(config: &Config, level: LogLevel, message: &str) {
+    let tag = match level {
+        LogLevel::Info => "",
+        LogLevel::Warn => {
+            LOGGED_WARNINGS.fetch_add(1, Ordering::Relaxed);
+            " [WARN]"
+        }
+        LogLevel::Error => {
+            LOGGED_ERRORS.fetch_add(1, Ordering::Relaxed);
+            " [ERROR]"
+        }
+    };
+    let order = config
+        .log_order
+        .map(|order| format!(" {}", log_order_field(order)))
+        .unwrap_or_default();
+    let line = format!(
+        "{}{order} {}{tag} {message}",
+        timestamp(),
+        config.log_prefix
+    );
+    if logfile::write(&line) {
+        return;
+    }
+    // explain 的結果佔用標準輸出（json 要能直接交給其他程式），日誌改寫到 stderr
+    if config.subcommand == Subcommand::Explain {
+        eprintln!("{line}");
+    } else {
+        println!("{line}");
+    }
+}
+
+// DISPLAY 對應的 X11 端點。:D 與 unix:D 是本機的 unix socket；host:D 是 TCP，
+// 例如 ssh -X 在遠端設定的 localhost:10.0：sshd 在 6000+10 = 6010 監聽，轉送回本機的 X server
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum X11Endpoint {
+    Unix(String),
+    Tcp(u16),
+}
+
+impl fmt::Display for X11Endpoint {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            X11Endpoint::Unix(path) => formatter.write_str(path),
+            X11Endpoint::Tcp(port) => write!(formatter, "TCP port {port}"),
+        }
+    }
+}
+
+impl X11Endpoint {
+    // 計數與排除 X server 時比對的 socket 名稱；TCP 端點沒有
+    fn socket_names(&self) -> Vec<String> {
+        match self {
+            X11Endpoint::Unix(path) => {
+                let runtime = env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from);
+                x11_socket_names(path, runtime.as_deref())
+            }
+            X11Endpoint::Tcp(_) => Vec::new(),
+        }
+    }
+}
+
+// 同一個 DISPLAY 的 X server 可能在多個名稱上監聽：/tmp/.X11-unix 下的 socket 檔、同名的抽象 socket，
+// 以及部分環境（例如以使用者身分執行的 Xwayland）放在 $XDG_RUNTIME_DIR/.X11-unix 下的 socket。
+// 客戶端連到哪一個都是同一個 DISPLAY，計數時全部比對，同一條連線只會有一個 inode，不會重複計算。
+// 名稱的寫法與 /proc/net/unix、ss 相同，抽象 socket 以 @ 開頭
+fn x11_socket_names(socket_path: &str, runtime_dir: Option<&Path>) -> Vec<String> {
+    let mut paths = vec![socket_path.to_string()];
+    if let (Some(runtime_dir), Some(name)) = (runtime_dir, Path::new(socket_path).file_name()) {
+        let path = runtime_dir
+            .join(".X11-unix")
+            .join(name)
+            .to_string_lossy()
+            .into_owned();
+        if path != socket_path {
+            paths.push(path);
+        }
+    }
+    paths
+        .into_iter()
+        .flat_map(|path| [format!("@{path}"), path])
+        .collect()
+}
+
+// sockaddr_un 的 sun_path 含結尾的 NUL（抽象 socket 則是開頭的 NUL），名稱最多 107 bytes；
+// 更長的路徑無法 bind 或 connect，也不會出現在 /proc/net/unix 中，比對它只會得到 0 條連線
+const SUN_PATH_MAX: usize = 107;
+
+fn check_socket_path_length(path: &[u8]) -> Result<(), String> {
+    if path.len() > SUN_PATH_MAX {
+        return Err(format!(
+            "socket 路徑長 {} bytes，超過 sockaddr_un 的上限 {SUN_PATH_MAX} bytes",
+            path.len()
+        ));
+    }
+    Ok(())
+}
+
+fn is_x11_socket_name(names: &[String], name: &str) -> bool {
+    names.iter().any(|candidate| candidate == name)
+}
+
+fn parse_display(display: &str) -> Result<X11Endpoint, String> {
+    let (host, rest) = display
+        .rsplit_once(':')
+        .ok_or_else(|| format!("無效 DISPLAY: {display}"))?;
+    // :D.S 的 screen 編號不影響 socket 路徑或 port（同一個 X server 的所有 screen 共用一個端點）
+    let (display_num, screen) = match rest.split_once('.') {
+        Some((number, screen)) => (number, Some(screen)),
+        None => (rest, None),
+    };
+    let is_number = |text: &str| !text.is_empty() && text.chars().all(|char| char.is_ascii_digit());
+    if !is_number(display_num) || screen.is_some_and(|value| !is_number(value)) {
+        return Err(format!("無效 DISPLAY: {display}"));
+    }
+    if host.is_empty() || host == "unix" {
+        let path = format!("/tmp/.X11-unix/X{display_num}");
+        check_socket_path_length(path.as_bytes())
+            .map_err(|error| format!("無效 DISPLAY: {display}（{error}）"))?;
+        return Ok(X11Endpoint::Unix(path));
+    }
+    if !cfg!(feature = "backend-procfs") {
+        return Err(format!(
+            "此版本未編入 backend-procfs，無法監控經 TCP 轉送的 DISPLAY: {display}"
+        ));
+    }
+    display_num
+        .parse::<u16>()
+        .ok()
+        .and_then(|number| X11_TCP_BASE_PORT.checked_add(number))
+        .map(X11Endpoint::Tcp)
+        .ok_or_else(|| format!("DISPLAY 編號過大，超出 TCP port 範圍: {display}"))
+}
+
+// ===== 區塊 2：程序與 socket 狀態收集 =====
+// 所有 /proc 存取都經過這裡，容器內可指向掛載進來的主機 proc，測試可指向假的目錄樹
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ProcRoot(PathBuf);
+
+impl Default for ProcRoot {
+    fn default() -> Self {
+        Self(PathBuf::from("/proc"))
+    }
+}
+
+impl ProcRoot {
+    fn new(path: &str) -> Self {
+        // 以 components 重組，去掉結尾與重複的斜線
+        Self(Path::new(path).components().collect())
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+
+    fn join(&self, relative: &str) -> PathBuf {
+        self.0.join(relative)
+    }
+
+    fn pid_entry(&self, pid: i32, entry: &str) -> PathBuf {
+        self.0.join(pid.to_string()).join(entry)
+    }
+}
+
+// 稽核用的 session 編號，systemd-logind 的 session 與它相同；未設定時為 u32::MAX
+fn read_session_id(proc_root: &ProcRoot, pid: i32) -> Option<u32> {
+    fs::read_to_string(proc_root.pid_entry(pid, "sessionid"))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .filter(|id| *id != u32::MAX)
+}
+
+fn list_pids(proc_root: &ProcRoot) -> Vec<i32> {
+    let entries = match fs::read_dir(proc_root.path()) {
+        Ok(value) => value,
+        Err(_) => return Vec::new(),
+    };
+    let mut pids: Vec<i32> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let file_name = entry.file_name();
+            let pid_text = file_name.to_string_lossy();
+            if !pid_text.chars().all(|char| char.is_ascii_digit()) {
+                return None;
+            }
+            pid_text.parse::<i32>().ok()
+        })
+        .collect();
+    pids.sort_unstable();
+    pids
+}
+
+// 回傳的一律是 proc_root 所屬 PID 命名空間的 PID：守護在主機上執行時，容器中的程序也以主機 PID 出現，
+// 與 kill、pidfd_open 使用的 PID 一致（不一致的情況由 check_pid_namespace 在啟動時擋下）。
+// 比對以程序為單位，只看程序的名稱（process_name），不看 /proc/<pid>/task/<tid>/comm：執行緒可以自行改名，
+// 而回傳的 PID 之後會被送出訊號，絕不能是執行緒 ID。
+// comm 相同的程式（java、python）再以 cmdline 區分；讀不到 cmdline（權限或核心執行緒）視為不符
+fn find_pids_by_name(
+    proc_root: &ProcRoot,
+    process_names: &[String],
+    cmdline: &[CmdlineMatch],
+) -> Vec<i32> {
+    match_pids_by_name(
+        proc_root,
+        &scan_process_names(proc_root),
+        process_names,
+        cmdline,
+    )
+}
+
+// 所有程序的名稱，依 PID 排序；守護保留每次掃描的結果，用來辨識 exec 前後的名稱
+fn scan_process_names(proc_root: &ProcRoot) -> Vec<(i32, String)> {
+    list_pids(proc_root)
+        .into_iter()
+        .filter_map(|pid| process_name(proc_root, pid).map(|name| (pid, name)))
+        .collect()
+}
+
+fn match_pids_by_name(
+    proc_root: &ProcRoot,
+    scan: &[(i32, String)],
+    process_names: &[String],
+    cmdline: &[CmdlineMatch],
+) -> Vec<i32> {
+    let by_cmdline = matches_by_cmdline_only(cmdline);
+    let by_pattern = name_patterns(cmdline).next().is_some();
+    let conditions: Vec<&CmdlineMatch> = cmdline
+        .iter()
+        .filter(|rule| !matches!(rule, CmdlineMatch::Name(_)))
+        .collect();
+    scan.iter()
+        .filter(|(_, comm)| by_cmdline || name_matches(process_names, cmdline, comm))
+        .map(|(pid, _)| *pid)
+        // 讀不到或空的 cmdline（核心執行緒、權限不足）不符合
+        .filter(|pid| {
+            conditions.is_empty()
+                || read_cmdline_args(proc_root, *pid)
+                    .is_some_and(|args| conditions.iter().all(|rule| rule.matches(&args)))
+        })
+        .filter(|pid| !(by_cmdline || by_pattern) || !is_guard_process(proc_root, *pid))
+        // 已結束但尚未被回收的殭屍仍有 comm，不算在執行：否則重啟驗證會把舊程序當成新的一代
+        .filter(|pid| {
+            read_proc_stat(proc_root, *pid).is_none_or(|stat| !matches!(stat.state, 'Z' | 'X'))
+        })
+        .filter(|pid| is_thread_group_leader(proc_root, *pid))
+        .collect()
+}
+
+// --cmdline-arg <index>=<regex> 比對單一參數，--cmdline-regex 比對以空白串接的整行；
+// --match-cmdline 同樣比對整行，但取代程序名稱的比對（程式經包裝腳本啟動、comm 被截斷或不固定時）。
+// --app-name-regex 不看命令列，以 regex 比對程序名稱、取代 --app-name 的完全相符（執行檔名稱帶版本號時），
+// 與其他條件放在一起，找程式、重啟前後與 top 等所有比對程序的地方都用同一組規則
+#[derive(Debug, Clone)]
+enum CmdlineMatch {
+    Arg(usize, pattern::Pattern),
+    Joined(pattern::Pattern),
+    Only(pattern::Pattern),
+    Name(pattern::Pattern),
+}
+
+impl CmdlineMatch {
+    // 超出參數個數的 index 不符合；Name 在比對名稱時處理，不是命令列的條件
+    fn matches(&self, args: &[String]) -> bool {
+        match self {
+            CmdlineMatch::Arg(index, pattern) => {
+                args.get(*index).is_some_and(|arg| pattern.is_match(arg))
+            }
+            CmdlineMatch::Joined(pattern) | CmdlineMatch::Only(pattern) => {
+                pattern.is_match(&joined_cmdline(args))
+            }
+            CmdlineMatch::Name(_) => true,
+        }
+    }
+}
+
+// 整行比對只看前 MAX_MATCHED_CMDLINE 位元組：命令列由其他使用者的程序決定，可以長達數 MB，
+// 每次 PID 同步都要對所有程序比對；程式的辨識資訊（執行檔路徑、主要參數）都在開頭
+const MAX_MATCHED_CMDLINE: usize = 16 * 1024;
+
+fn joined_cmdline(args: &[String]) -> String {
+    let mut joined = String::new();
+    for (index, arg) in args.iter().enumerate() {
+        if index > 0 {
+            joined.push(' ');
+        }
+        joined.push_str(arg);
+        if joined.len() >= MAX_MATCHED_CMDLINE {
+            break;
+        }
+    }
+    if joined.len() > MAX_MATCHED_CMDLINE {
+        let mut end = MAX_MATCHED_CMDLINE;
+        while !joined.is_char_boundary(end) {
+            end -= 1;
+        }
+        joined.truncate(end);
+    }
+    joined
+}
+
+fn name_patterns(cmdline: &[CmdlineMatch]) -> impl Iterator<Item = &pattern::Pattern> {
+    cmdline.iter().filter_map(|rule| match rule {
+        CmdlineMatch::Name(pattern) => Some(pattern),
+        _ => None,
+    })
+}
+
+// 程序名稱（comm_text 的形式）是否屬於程式：有 --app-name-regex 時符合任一個即可，否則須與某個名稱完全相同
+fn name_matches(process_names: &[String], cmdline: &[CmdlineMatch], comm: &str) -> bool {
+    let mut patterns = name_patterns(cmdline).peekable();
+    if patterns.peek().is_none() {
+        return process_names.iter().any(|name| name == comm);
+    }
+    patterns.any(|pattern| pattern.is_match(comm))
+}
+
+fn matches_by_cmdline_only(cmdline: &[CmdlineMatch]) -> bool {
+    cmdline
+        .iter()
+        .any(|rule| matches!(rule, CmdlineMatch::Only(_)))
+}
+
+// 只看命令列時，守護本身（以及同一執行檔的 ctl 等）與啟動它的程序（shell、timeout）的命令列常含程式名稱，一律不算；
+// --app-name-regex 也可能符合守護自己的名稱（qq-x11-guard-rs）或 fork 後尚未 exec 的子程序，同樣排除
+fn is_guard_process(proc_root: &ProcRoot, pid: i32) -> bool {
+    static OWN_EXE: OnceLock<Option<PathBuf>> = OnceLock::new();
+    let own = OWN_EXE.get_or_init(|| env::current_exe().ok());
+    if own.is_some() && fs::read_link(proc_root.pid_entry(pid, "exe")).ok().as_ref() == own.as_ref()
+    {
+        return true;
+    }
+    let mut ancestor = std::process::id() as i32;
+    while ancestor > 1 {
+        if ancestor == pid {
+            return true;
+        }
+        let Some(stat) = read_proc_stat(proc_root, ancestor) else {
+            break;
+        };
+        ancestor = stat.ppid;
+    }
+    false
+}
+
+fn parse_cmdline_arg(spec: &str) -> Result<CmdlineMatch, String> {
+    let (index, regex) = spec
+        .split_once('=')
+        .ok_or_else(|| format!("格式為 <index>=<regex>: {spec}"))?;
+    let index = index
+        .trim()
+        .parse::<usize>()
+        .map_err(|_| format!("index 必須是非負整數: {spec}"))?;
+    Ok(CmdlineMatch::Arg(index, pattern::Pattern::parse(regex)?))
+}
+
+// 程序名稱優先取 /proc/<pid>/comm；精簡或特殊的核心沒有 comm 時改用 stat 括號內的名稱（同樣是核心的 comm），
+// 兩者都讀不到才用 cmdline 的 argv[0] 檔名。三者都以 15 位元組為上限，argv[0] 也照樣截斷才比對得到。
+// 一律以位元組讀取、轉成 comm_text 的形式，不是 UTF-8 的名稱也能比對
+fn process_name(proc_root: &ProcRoot, pid: i32) -> Option<String> {
+    if let Ok(comm) = fs::read(proc_root.pid_entry(pid, "comm")) {
+        return Some(comm_text(comm.strip_suffix(b"\n").unwrap_or(&comm)));
+    }
+    if let Some(stat) = read_proc_stat(proc_root, pid) {
+        return Some(stat.comm);
+    }
+    let cmdline = fs::read(proc_root.pid_entry(pid, "cmdline")).ok()?;
+    let program = cmdline.split(|byte| *byte == 0).next()?;
+    let file_name = program.rsplit(|byte| *byte == b'/').next()?;
+    (!file_name.is_empty()).then(|| truncate_comm(file_name))
+}
+
+// 程序名稱的文字形式。核心的 comm 是任意位元組（不含 NUL），不一定是 UTF-8，
+// 超過 15 位元組的名稱也可能在多位元組字元中間被截斷：無效的位元組寫成 \xNN、反斜線寫成 \\，其餘照原樣。
+// 位元組與文字一對一對應，比對文字即是逐位元組比對
+fn comm_text(bytes: &[u8]) -> String {
+    let mut text = String::new();
+    for chunk in bytes.utf8_chunks() {
+        text.push_str(&chunk.valid().replace('\\', "\\\\"));
+        for byte in chunk.invalid() {
+            text.push_str(&format!("\\x{byte:02x}"));
+        }
+    }
+    text
+}
+
+// --app-name 與 --group-member 的名稱：\xNN 與 \\ 還原成位元組，再轉成 comm_text 的形式；
+// 其他的反斜線照原樣，既有寫法（例如 a\b）不受影響
+fn canonical_process_name(name: &str) -> String {
+    comm_text(&process_name_bytes(name))
+}
+
+fn process_name_bytes(name: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut rest = name.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        if byte == b'\\' {
+            if let Some(after) = after.strip_prefix(b"\\") {
+                bytes.push(b'\\');
+                rest = after;
+                continue;
+            }
+            let hex = after
+                .strip_prefix(b"x")
+                .and_then(|hex| hex.get(..2))
+                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if let Some(value) = hex {
+                bytes.push(value);
+                rest = &after[3..];
+                continue;
+            }
+        }
+        bytes.push(byte);
+        rest = after;
+    }
+    bytes
+}
+
+fn fd_count(proc_root: &ProcRoot, pid: i32) -> usize {
+    fs::read_dir(proc_root.pid_entry(pid, "fd"))
+        .map(|entries| entries.count())
+        .unwrap_or(0)
+}
+
+fn rss_kib(proc_root: &ProcRoot, pid: i32) -> u64 {
+    let statm = fs::read_to_string(proc_root.pid_entry(pid, "statm")).unwrap_or_default();
+    let pages: u64 = statm
+        .split_whitespace()
+        .nth(1)
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0);
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(0) as u64;
+    pages * page_size / 1024
+}
+
+// cmdline 以 NUL 分隔各參數、通常以 NUL 結尾；殭屍與核心執行緒的 cmdline 是空的
+fn read_cmdline_args(proc_root: &ProcRoot, pid: i32) -> Option<Vec<String>> {
+    let raw = fs::read(proc_root.pid_entry(pid, "cmdline")).ok()?;
+    if raw.is_empty() {
+        return None;
+    }
+    Some(split_cmdline(&raw))
+}
+
+fn split_cmdline(raw: &[u8]) -> Vec<String> {
+    let raw = raw.strip_suffix(&[0]).unwrap_or(raw);
+    raw.split(|byte| *byte == 0)
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect()
+}
+
+// /proc 的目錄列表只有程序，但 /proc/<tid> 仍可直接存取；以 status 的 Tgid 再確認一次不是執行緒。
+// 讀不到 status 時（程序剛結束）不在這裡排除，之後送訊號的 pidfd_open 會自行失敗
+fn is_thread_group_leader(proc_root: &ProcRoot, pid: i32) -> bool {
+    let Ok(status) = fs::read_to_string(proc_root.pid_entry(pid, "status")) else {
+        return true;
+    };
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Tgid:"))
+        .and_then(|value| value.trim().parse::<i32>().ok())
+        .is_none_or(|tgid| tgid == pid)
+}
+
+fn socket_inodes_for_pid(proc_root: &ProcRoot, pid: i32) -> HashSet<String> {
+    try_socket_inodes_for_pid(proc_root, pid).unwrap_or_default()
+}
+
+// 只有權限不足才回傳錯誤；程序已結束等其他錯誤視為沒有 socket。
+// 權限不足時不能當成「沒有連線」，否則守護會永遠回報正常
+fn try_socket_inodes_for_pid(proc_root: &ProcRoot, pid: i32) -> io::Result<HashSet<String>> {
+    let mut result = HashSet::new();
+    let entries = match fs::read_dir(proc_root.pid_entry(pid, "fd")) {
+        Ok(value) => value,
+        Err(err) if privileges::is_permission_error(&err) => return Err(err),
+        Err(_) => return Ok(result),
+    };
+
+    for entry in entries.flatten() {
+        let link = match fs::read_link(entry.path()) {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        let link_text = link.to_string_lossy();
+        if let Some(inode) = parse_socket_inode(&link_text) {
+            result.insert(inode.to_string());
+        }
+    }
+    Ok(result)
+}
+
+// /proc/<pid>/stat 第 22 欄（開機後的 clock tick），與 PID 一起辨識同一個程序，避免 PID 重用混淆
+struct ProcStat {
+    comm: String,
+    state: char,
+    ppid: i32,
+    start_time: u64,
+    // utime + stime（clock tick）
+    cpu_ticks: u64,
+}
+
+fn read_proc_stat(proc_root: &ProcRoot, pid: i32) -> Option<ProcStat> {
+    parse_proc_stat(&fs::read(proc_root.pid_entry(pid, "stat")).ok()?)
+}
+
+fn parse_proc_stat(stat: &[u8]) -> Option<ProcStat> {
+    // comm 可能含空白、括號或非 UTF-8 的位元組，從最後一個 ')' 之後開始數，第 3 欄起算
+    let close = stat.iter().rposition(|byte| *byte == b')')?;
+    let open = stat[..close].iter().position(|byte| *byte == b'(')?;
+    let rest = std::str::from_utf8(&stat[close + 1..]).ok()?;
+    let fields: Vec<&str> = rest.split_whitespace().collect();
+    Some(ProcStat {
+        comm: comm_text(&stat[open + 1..close]),
+        state: fields.first()?.chars().next()?,
+        ppid: fields.get(1)?.parse().ok()?,
+        start_time: fields.get(19)?.parse().ok()?,
+        cpu_ticks: fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?,
+    })
+}
+
+fn process_start_time(proc_root: &ProcRoot, pid: i32) -> Option<u64> {
+    read_proc_stat(proc_root, pid).map(|stat| stat.start_time)
+}
+
+// 符合的程序中父程序不在清單內的最小 PID，也就是程式的主程序；都讀不到 stat 時取最小的 PID
+fn main_pid(proc_root: &ProcRoot, pids: &[i32]) -> Option<i32> {
+    pids.iter()
+        .copied()
+        .filter(|pid| {
+            read_proc_stat(proc_root, *pid).is_some_and(|stat| !pids.contains(&stat.ppid))
+        })
+        .min()
+        .or_else(|| pids.iter().copied().min())
+}
+
+// /proc/loadavg 的第一欄是 1 分鐘平均負載
+fn parse_loadavg(body: &str) -> Option<f64> {
+    body.split_whitespace().next()?.parse().ok()
+}
+
+// /proc/pressure/cpu：some avg10=1.23 avg60=… avg300=… total=…，取最近 10 秒有工作在等 CPU 的時間比例
+fn parse_cpu_pressure(body: &str) -> Option<f64> {
+    body.lines()
+        .find_map(|line| line.strip_prefix("some "))?
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("avg10="))?
+        .parse()
+        .ok()
+}
+
+// /proc/stat 中 cpu0、cpu1… 的行數；讀不到時以本程序可用的核心數代替
+fn count_cpus(proc_root: &ProcRoot) -> usize {
+    let counted = fs::read_to_string(proc_root.join("stat"))
+        .map(|body| {
+            body.lines()
+                .filter(|line| {
+                    line.strip_prefix("cpu")
+                        .is_some_and(|rest| rest.starts_with(|char: char| char.is_ascii_digit()))
+                })
+                .count()
+        })
+        .unwrap_or(0);
+    if counted > 0 {
+        return counted;
+    }
+    thread::available_parallelism().map_or(1, usize::from)
+}
+
+// --max-load / --max-cpu-pressure 任一條件超過時回傳說明；讀不到的數值視為錯誤
+fn system_overload(config: &Config) -> Result<Option<String>, String> {
+    let mut reasons = Vec::new();
+    if let Some(limit) = config.max_load {
+        let load = fs::read_to_string(config.proc_root.join("loadavg"))
+            .ok()
+            .and_then(|body| parse_loadavg(&body))
+            .ok_or("無法讀取 loadavg")?;
+        let (value, detail) = if config.max_load_per_cpu {
+            let cpus = count_cpus(&config.proc_root);
+            (load / cpus as f64, format!("（{load:.2} / {cpus} 核心）"))
+        } else {
+            (load, String::new())
+        };
+        if value > limit {
+            reasons.push(format!(
+                "1 分鐘負載 {value:.2}{detail} 超過 --max-load {limit}"
+            ));
+        }
+    }
+    if let Some(limit) = config.max_cpu_pressure {
+        let pressure = fs::read_to_string(config.proc_root.join("pressure/cpu"))
+            .ok()
+            .and_then(|body| parse_cpu_pressure(&body))
+            .ok_or("無法讀取 pressure/cpu（核心需啟用 PSI）")?;
+        if pressure > limit {
+            reasons.push(format!(
+                "CPU 壓力 {pressure:.2}% 超過 --max-cpu-pressure {limit}%"
+            ));
+        }
+    }
+    Ok((!reasons.is_empty()).then(|| reasons.join("，")))
+}
+
+// --defer-while-active 的一次取樣
+struct Activity {
+    // 所有程序合計，100 為一個核心；都讀不到 stat 時為 None
+    cpu_percent: Option<f64>,
+    // 未指定 --active-idle 時不查詢
+    idle: Option<Result<Duration, String>>,
+}
+
+impl Activity {
+    fn text(&self) -> String {
+        let cpu = self.cpu_percent.map_or("CPU 未知".to_string(), |percent| {
+            format!("CPU {percent:.1}%")
+        });
+        match &self.idle {
+            Some(Ok(idle)) => format!("{cpu}，輸入閒置 {} 秒", idle.as_secs()),
+            Some(Err(_)) => format!("{cpu}，輸入閒置未知"),
+            None => cpu,
+        }
+    }
+}
+
+// 相隔 interval 讀兩次各程序的 CPU 時間；期間結束或換了一代（啟動時間不同）的程序不計
+fn sample_cpu_percent(proc_root: &ProcRoot, pids: &[i32], interval: Duration) -> Option<f64> {
+    let before: Vec<(i32, ProcStat)> = pids
+        .iter()
+        .filter_map(|pid| read_proc_stat(proc_root, *pid).map(|stat| (*pid, stat)))
+        .collect();
+    if before.is_empty() {
+        return None;
+    }
+    let started = Instant::now();
+    thread::sleep(interval);
+    let ticks: u64 = before
+        .iter()
+        .filter_map(|(pid, first)| {
+            let second = read_proc_stat(proc_root, *pid)?;
+            (second.start_time == first.start_time)
+                .then(|| second.cpu_ticks.saturating_sub(first.cpu_ticks))
+        })
+        .sum();
+    let per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    let elapsed = started.elapsed().as_secs_f64();
+    (per_second > 0 && elapsed > 0.0).then(|| ticks as f64 / per_second as f64 / elapsed * 100.0)
+}
+
+fn measure_activity(config: &Config, endpoint: &X11Endpoint, pids: &[i32]) -> Activity {
+    Activity {
+        cpu_percent: sample_cpu_percent(&config.proc_root, pids, ACTIVE_SAMPLE),
+        idle: config
+            .active_idle_seconds
+            .map(|_| xres::input_idle(endpoint, &config.display)),
+    }
+}
+
+// 視為使用中的原因；讀不到的數值不算使用中
+fn active_reason(config: &Config, activity: &Activity) -> Option<String> {
+    let mut reasons = Vec::new();
+    let cpu_limit = config
+        .active_cpu_percent
+        .unwrap_or(DEFAULT_ACTIVE_CPU_PERCENT);
+    if let Some(percent) = activity.cpu_percent.filter(|percent| *percent > cpu_limit) {
+        reasons.push(format!("CPU {percent:.1}% 超過 --active-cpu {cpu_limit}%"));
+    }
+    if let (Some(limit), Some(Ok(idle))) = (config.active_idle_seconds, &activity.idle) {
+        if idle.as_secs() < limit {
+            reasons.push(format!(
+                "輸入閒置 {} 秒，不到 --active-idle {limit} 秒",
+                idle.as_secs()
+            ));
+        }
+    }
+    (!reasons.is_empty()).then(|| reasons.join("，"))
+}
+
+// --only-when-idle 查詢到的閒置時間與來源
+type IdleReading = Result<(Duration, &'static str), String>;
+
+// 先問 X server（MIT-SCREEN-SAVER）；查不到時（例如 Wayland 下的 Xwayland 不回報輸入）改用 logind 的 IdleHint
+fn session_idle(config: &Config, endpoint: &X11Endpoint, session: Option<u32>) -> IdleReading {
+    let x_error = match xres::input_idle(endpoint, &config.display) {
+        Ok(idle) => return Ok((idle, "X server")),
+        Err(error) => error,
+    };
+    logind_idle(session)
+        .map(|idle| (idle, "logind"))
+        .map_err(|error| format!("X server：{x_error}；logind：{error}"))
+}
+
+// 程式所在的 session 優先，其次 XDG_SESSION_ID，都沒有時交給 loginctl 的 auto
+fn logind_idle(session: Option<u32>) -> Result<Duration, String> {
+    let session = session
+        .map(|id| id.to_string())
+        .or_else(|| env::var("XDG_SESSION_ID").ok().filter(|id| !id.is_empty()))
+        .unwrap_or_else(|| "auto".to_string());
+    let spec = CommandSpec::new("loginctl")
+        .arg("show-session")
+        .arg(&session)
+        .arg("--property=IdleHint")
+        .arg("--property=IdleSinceHint")
+        .timeout(LOGIND_TIMEOUT);
+    match sandbox::run_output(&spec) {
+        Ok((true, output)) => parse_idle_hint(&String::from_utf8_lossy(&output), SystemTime::now()),
+        Ok((false, _)) => Err(format!("loginctl show-session {session} 以非 0 結束碼結束")),
+        Err(error) => Err(format!("無法執行 loginctl（{error}）")),
+    }
+}
+
+// IdleHint=no 時閒置為 0；IdleSinceHint 是進入閒置的時間（unix 微秒）
+fn parse_idle_hint(output: &str, now: SystemTime) -> Result<Duration, String> {
+    let property = |name: &str| {
+        output.lines().find_map(|line| {
+            line.strip_prefix(name)
+                .and_then(|rest| rest.strip_prefix('='))
+                .map(str::trim)
+        })
+    };
+    match property("IdleHint") {
+        Some("no") => Ok(Duration::ZERO),
+        Some("yes") => {
+            let since = property("IdleSinceHint")
+                .and_then(|value| value.parse::<u64>().ok())
+                .filter(|micros| *micros > 0)
+                .ok_or("session 閒置中但沒有 IdleSinceHint")?;
+            Ok(now
+                .duration_since(UNIX_EPOCH + Duration::from_micros(since))
+                .unwrap_or_default())
+        }
+        _ => Err("loginctl 的輸出中沒有 IdleHint".to_string()),
+    }
+}
+
+fn idle_text(reading: &IdleReading) -> String {
+    match reading {
+        Ok((idle, source)) => format!("閒置 {} 秒（{source}）", idle.as_secs()),
+        Err(error) => format!("閒置時間未知（{error}）"),
+    }
+}
+
+// 以 root 為根的程序樹中仍存活程序的名稱（去重、排序），略過負責轉呼叫的 sh 本身
+fn process_tree_names(proc_root: &ProcRoot, root: i32) -> Vec<String> {
+    let stats: Vec<(i32, ProcStat)> = list_pids(proc_root)
+        .into_iter()
+        .filter_map(|pid| read_proc_stat(proc_root, pid).map(|stat| (pid, stat)))
+        .collect();
+    let mut tree = HashSet::from([root]);
+    loop {
+        let before = tree.len();
+        for (pid, stat) in &stats {
+            if tree.contains(&stat.ppid) {
+                tree.insert(*pid);
+            }
+        }
+        if tree.len() == before {
+            break;
+        }
+    }
+    let mut names: Vec<String> = stats
+        .into_iter()
+        .filter(|(pid, stat)| tree.contains(pid) && stat.state != 'Z' && stat.comm != "sh")
+        .map(|(_, stat)| stat.comm)
+        .collect();
+    names.sort_unstable();
+    names.dedup();
+    names
+}
+
+// 重啟命令第一個字的檔名，依核心 comm 的長度上限（15 bytes）截斷後即為預期的程序名稱
+fn restart_command_comm(command: &str) -> Option<String> {
+    let program = command.split_whitespace().next()?;
+    Some(truncate_comm(
+        Path::new(program).file_name()?.as_encoded_bytes(),
+    ))
+}
+
+// 與核心相同，直接在第 15 個位元組截斷，即使切在多位元組字元中間
+fn truncate_comm(name: &[u8]) -> String {
+    comm_text(&name[..name.len().min(15)])
+}
+
+// 常駐的掃描執行緒：在降權之後、seccomp 之前建立，之後不再需要 clone
+struct FdScanPool {
+    jobs: mpsc::Sender<(usize, i32)>,
+    results: mpsc::Receiver<(usize, io::Result<HashSet<String>>)>,
+}
+
+type ScanJobs = Arc<Mutex<mpsc::Receiver<(usize, i32)>>>;
+
+fn worker_loop(
+    proc_root: &ProcRoot,
+    jobs: &ScanJobs,
+    results: &mpsc::Sender<(usize, io::Result<HashSet<String>>)>,
+) {
+    loop {
+        let job = jobs.lock().unwrap_or_else(|err| err.into_inner()).recv();
+        let (slot, pid) = match job {
+            Ok(value) => value,
+            Err(_) => return,
+        };
+        if results
+            .send((slot, try_socket_inodes_for_pid(proc_root, pid)))
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+impl FdScanPool {
+    fn new(workers: usize, proc_root: &ProcRoot) -> io::Result<Self> {
+        let (job_tx, job_rx) = mpsc::channel::<(usize, i32)>();
+        let (result_tx, result_rx) = mpsc::channel();
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        for index in 0..workers {
+            let job_rx = Arc::clone(&job_rx);
+            let result_tx = result_tx.clone();
+            let ready_tx = ready_tx.clone();
+            let proc_root = proc_root.clone();
+            thread::Builder::new()
+                .name(format!("fd-scan-{index}"))
+                .spawn(move || {
+                    // 結束訊號只交給主執行緒，才能中斷它的 poll
+                    block_shutdown_signals();
+                    let _ = ready_tx.send(());
+                    drop(ready_tx);
+                    worker_loop(&proc_root, &job_rx, &result_tx)
+                })?;
+        }
+        // 等所有執行緒跑完啟動流程（rseq、sched_getaffinity 等）再回傳，之後才能安裝 seccomp
+        drop(ready_tx);
+        for _ in 0..workers {
+            if ready_rx.recv().is_err() {
+                return Err(io::Error::other("掃描執行緒啟動失敗"));
+            }
+        }
+        Ok(Self {
+            jobs: job_tx,
+            results: result_rx,
+        })
+    }
+
+    // 結果依輸入順序放回，與執行緒完成順序無關
+    fn scan(&self, pids: &[i32]) -> Vec<io::Result<HashSet<String>>> {
+        let mut results: Vec<io::Result<HashSet<String>>> =
+            pids.iter().map(|_| Ok(HashSet::new())).collect();
+        let mut pending = 0;
+        for (slot, pid) in pids.iter().enumerate() {
+            if self.jobs.send((slot, *pid)).is_ok() {
+                pending += 1;
+            }
+        }
+        for _ in 0..pending {
+            match self.results.recv() {
+                Ok((slot, inodes)) => results[slot] = inodes,
+                Err(_) => break,
+            }
+        }
+        results
+    }
+}
+
+// 每次檢查都逐一 readlink 所有程序的 fd：/proc/<pid>/fd 沒有隨 fd 開關而改變、又比 readlink 便宜的鍵
+// （mtime 不動，st_size 只是 fd 數量，關閉再開啟同一個號碼看不出來），用這些當鍵的快取會算出過時的連線。
+// 程序多時以 --scan-threads 的執行緒平行掃描
+struct FdScanner {
+    proc_root: ProcRoot,
+    pool: Option<FdScanPool>,
+    // 最近一次查詢中 fd 目錄因權限不足無法讀取的 PID，其結果不可信
+    unreadable: Vec<i32>,
+}
+
+impl FdScanner {
+    fn new(proc_root: ProcRoot) -> Self {
+        Self {
+            proc_root,
+            pool: None,
+            unreadable: Vec::new(),
+        }
+    }
+
+    fn start_pool(&mut self, workers: usize) -> io::Result<()> {
+        if workers > 1 {
+            self.pool = Some(FdScanPool::new(workers, &self.proc_root)?);
+        }
+        Ok(())
+    }
+
+    fn socket_inodes_by_pid(&mut self, pids: &[i32]) -> Vec<(i32, HashSet<String>)> {
+        let scanned = match &self.pool {
+            Some(pool) if pids.len() > 1 => pool.scan(pids),
+            _ => pids
+                .iter()
+                .map(|pid| try_socket_inodes_for_pid(&self.proc_root, *pid))
+                .collect(),
+        };
+        self.unreadable.clear();
+        let mut results: Vec<(i32, HashSet<String>)> = Vec::with_capacity(pids.len());
+        for (pid, scanned) in pids.iter().zip(scanned) {
+            match scanned {
+                Ok(inodes) => results.push((*pid, inodes)),
+                Err(_) => {
+                    self.unreadable.push(*pid);
+                    results.push((*pid, HashSet::new()));
+                }
+            }
+        }
+        results.sort_by_key(|(pid, _)| *pid);
+        self.unreadable.sort_unstable();
+        results
+    }
+}
+
+fn parse_socket_inode(text: &str) -> Option<&str> {
+    if !text.starts_with("socket:[") || !text.ends_with(']') {
+        return None;
+    }
+    Some(&text[8..text.len() - 1])
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BackendChoice {
+    Auto,
+    Diag,
+    Ss,
+    Lsof,
+}
+
+// --connections-from：ss 沿用 --backend 的 peer 交集，net-unix 與 fd 不經過 peer 後端，見 counter.rs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectionSource {
+    Ss,
+    NetUnix,
+    Fd,
+}
+
+impl ConnectionSource {
+    fn name(self) -> &'static str {
+        match self {
+            ConnectionSource::Ss => "ss",
+            ConnectionSource::NetUnix => "net-unix",
+            ConnectionSource::Fd => "fd",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ThresholdScope {
+    Total,
+    PerProcess,
+}
+
+impl ThresholdScope {
+    fn name(self) -> &'static str {
+        match self {
+            ThresholdScope::Total => "total",
+            ThresholdScope::PerProcess => "per-process",
+        }
+    }
+}
+
+impl BackendChoice {
+    fn compiled(self) -> bool {
+        match self {
+            BackendChoice::Auto => true,
+            BackendChoice::Diag => cfg!(feature = "backend-diag"),
+            BackendChoice::Ss => cfg!(feature = "backend-ss"),
+            BackendChoice::Lsof => cfg!(feature = "backend-lsof"),
+        }
+    }
+}
+
+#[cfg(not(any(
+    feature = "backend-diag",
+    feature = "backend-ss",
+    feature = "backend-lsof"
+)))]
+compile_error!("至少需要啟用 backend-diag、backend-ss、backend-lsof 其中之一");
+
+// 各後端依 cargo feature 編入；parse_args 已拒絕未編入的 --backend
+enum PeerBackend {
+    #[cfg(feature = "backend-diag")]
+    Diag(diag::DiagSocket),
+    #[cfg(feature = "backend-ss")]
+    Ss,
+    #[cfg(feature = "backend-lsof")]
+    Lsof,
+}
+
+impl PeerBackend {
+    // auto 時先實際查詢一次 sock_diag，部分強化過的核心會以 EPERM 拒絕，此時改用 ss
+    fn select(choice: BackendChoice) -> Result<(Self, Option<String>), String> {
+        match choice {
+            #[cfg(feature = "backend-ss")]
+            BackendChoice::Ss => Ok((PeerBackend::Ss, None)),
+            #[cfg(feature = "backend-lsof")]
+            BackendChoice::Lsof => match lsof_peer_inodes(&[]) {
+                Ok(_) => Ok((PeerBackend::Lsof, None)),
+                Err(error) => Self::fall_back(format!("lsof 無法使用（{error}）")),
+            },
+            #[cfg(feature = "backend-diag")]
+            BackendChoice::Auto | BackendChoice::Diag => {
+                let probe = diag::DiagSocket::open().and_then(|mut socket| {
+                    socket.peer_inodes(&[])?;
+                    Ok(socket)
+                });
+                match (probe, choice) {
+                    (Ok(socket), _) => Ok((PeerBackend::Diag(socket), None)),
+                    (Err(error), BackendChoice::Diag) => {
+                        Err(format!("無法使用 sock_diag: {error}"))
+                    }
+                    (Err(error), _) => Self::fall_back(format!("sock_diag 無法使用（{error}）")),
+                }
+            }
+            // 沒有編入 diag 時 auto 依序用 ss、lsof
+            #[cfg(not(feature = "backend-diag"))]
+            BackendChoice::Auto => Self::select(if cfg!(feature = "backend-ss") {
+                BackendChoice::Ss
+            } else {
+                BackendChoice::Lsof
+            }),
+            #[allow(unreachable_patterns)]
+            other => Err(format!("此版本未編入 {other:?} 後端")),
+        }
+    }
+
+    // diag 或 lsof 探測失敗時的退路
+    #[cfg(any(feature = "backend-diag", feature = "backend-lsof"))]
+    fn fall_back(problem: String) -> Result<(Self, Option<String>), String> {
+        #[cfg(feature = "backend-ss")]
+        return Ok((PeerBackend::Ss, Some(format!("{problem}，改用 ss"))));
+        #[cfg(not(feature = "backend-ss"))]
+        Err(format!("{problem}，此版本未編入 ss 可以退回"))
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "backend-diag")]
+            PeerBackend::Diag(_) => "diag",
+            #[cfg(feature = "backend-ss")]
+            PeerBackend::Ss => "ss",
+            #[cfg(feature = "backend-lsof")]
+            PeerBackend::Lsof => "lsof",
+            // 沒有編入任何後端時 PeerBackend 沒有變體，只留下上面的 compile_error!
+            #[cfg(not(any(
+                feature = "backend-diag",
+                feature = "backend-ss",
+                feature = "backend-lsof"
+            )))]
+            _ => unreachable!(),
+        }
+    }
+
+    // TCP 端點（ssh -X 轉送）一律讀 net/tcp：三種後端都只處理 unix socket
+    fn x11_peer_inodes(&mut self, proc_root: &ProcRoot, endpoint: &X11Endpoint) -> HashSet<String> {
+        match endpoint {
+            X11Endpoint::Unix(_) => self.peer_inodes(&endpoint.socket_names()),
+            X11Endpoint::Tcp(port) => tcp_x11_client_inodes(proc_root, *port),
+        }
+    }
+
+    // 單次查詢失敗時退回 ss，不讓一次 netlink 錯誤或 lsof 失敗造成漏算
+    fn peer_inodes(&mut self, names: &[String]) -> HashSet<String> {
+        match self {
+            #[cfg(feature = "backend-diag")]
+            PeerBackend::Diag(socket) => socket
+                .peer_inodes(names)
+                .unwrap_or_else(|_| peer_inodes_on_x11_socket(names)),
+            #[cfg(feature = "backend-ss")]
+            PeerBackend::Ss => peer_inodes_on_x11_socket(names),
+            #[cfg(feature = "backend-lsof")]
+            PeerBackend::Lsof => {
+                lsof_peer_inodes(names).unwrap_or_else(|_| peer_inodes_on_x11_socket(names))
+            }
+            // 沒有編入任何後端時 PeerBackend 沒有變體，只留下上面的 compile_error!
+            #[cfg(not(any(
+                feature = "backend-diag",
+                feature = "backend-ss",
+                feature = "backend-lsof"
+            )))]
+            _ => unreachable!("沒有後端可查詢 {names:?}"),
+        }
+    }
+}
+
+// lsof 的 +E 會在 NAME 附上對端資訊（->INO=<inode> <pid>,<comm>,<fd>），
+// 取 X server 端 socket（NAME 以 socket 路徑開頭）的對端 inode。
+// lsof 要掃過所有程序的 fd，是三種後端中最慢的；有錯誤（例如部分程序無權讀取）時結束碼非 0，
+// 因此只要有輸出就採用，完全沒有輸出才視為失敗
+#[cfg(feature = "backend-lsof")]
+fn lsof_peer_inodes(names: &[String]) -> io::Result<HashSet<String>> {
+    let spec = CommandSpec::new("lsof")
+        .arg("-nPw")
+        .arg("-U")
+        .arg("+E")
+        .arg("-F")
+        .arg("n")
+        .lowered();
+    let (_, stdout) = sandbox::run_output(&spec)?;
+    if stdout.is_empty() {
+        return Err(io::Error::other("lsof 沒有任何輸出"));
+    }
+    let stdout = String::from_utf8_lossy(&stdout);
+    // 輸出涵蓋所有 unix socket，explain 只留與 X socket 有關的行
+    if explain::capturing() {
+        let related: String = stdout
+            .lines()
+            .filter(|line| names.iter().any(|name| line.contains(name.as_str())))
+            .map(|line| format!("{line}\n"))
+            .collect();
+        explain::record_raw("lsof -nPw -U +E -F n", &related);
+    }
+    Ok(parse_lsof_peers(&stdout, names))
+}
+
+#[cfg(feature = "backend-lsof")]
+fn parse_lsof_peers(body: &str, names: &[String]) -> HashSet<String> {
+    body.lines()
+        .filter_map(|line| line.strip_prefix('n'))
+        .filter(|name| is_x11_socket_name(names, name.split_whitespace().next().unwrap_or("")))
+        .filter_map(|name| {
+            let peer = name.split_once("->INO=")?.1;
+            let end = peer
+                .find(|char: char| !char.is_ascii_digit())
+                .unwrap_or(peer.len());
+            (end > 0).then(|| peer[..end].to_string())
+        })
+        .collect()
+}
+
+// X socket 的每個名稱（路徑、abstract、$XDG_RUNTIME_DIR 下的路徑）以 or 併成一個篩選條件，
+// 每次查詢只執行一次 ss
+#[cfg(feature = "backend-ss")]
+fn ss_on_x11_socket(names: &[String]) -> CommandSpec {
+    let mut spec = CommandSpec::new("ss").arg("-xnpH");
+    for (index, source) in names.iter().enumerate() {
+        if index > 0 {
+            spec = spec.arg("or");
+        }
+        spec = spec.arg("src").arg(source);
+    }
+    spec.lowered()
+}
+
+#[cfg(feature = "backend-ss")]
+fn peer_inodes_on_x11_socket(names: &[String]) -> HashSet<String> {
+    // 沒有篩選條件時 ss 會列出所有 unix socket
+    if names.is_empty() {
+        return HashSet::new();
+    }
+    let spec = ss_on_x11_socket(names);
+    let stdout = match sandbox::run_output(&spec) {
+        Ok((true, value)) => value,
+        _ => return HashSet::new(),
+    };
+
+    let stdout = String::from_utf8_lossy(&stdout);
+    explain::record_raw(&spec.argv().join(" "), &stdout);
+    parse_ss_peers(&stdout, names)
+}
+
+#[cfg(feature = "backend-ss")]
+fn parse_ss_peers(body: &str, names: &[String]) -> HashSet<String> {
+    body.lines()
+        .filter_map(|line| {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            extract_peer_inode(&tokens, names).map(str::to_string)
+        })
+        .collect()
+}
+
+// 未編入 ss 時 diag、lsof 單次失敗沒有退路，這一輪視為沒有連線
+#[cfg(not(feature = "backend-ss"))]
+fn peer_inodes_on_x11_socket(_names: &[String]) -> HashSet<String> {
+    HashSet::new()
+}
+
+#[cfg(feature = "backend-ss")]
+fn extract_peer_inode<'a>(tokens: &'a [&'a str], names: &[String]) -> Option<&'a str> {
+    for (index, token) in tokens.iter().enumerate() {
+        if !is_x11_socket_name(names, token) {
+            continue;
+        }
+        if index + 3 >= tokens.len() {
+            return None;
+        }
+        if tokens[index + 2] != "*" {
+            return None;
+        }
+        // 對端已關閉、還沒被 X server 收掉的連線，對端 inode 是 0
+        let peer = tokens[index + 3];
+        if peer != "0" && peer.chars().all(|char| char.is_ascii_digit()) {
+            return Some(peer);
+        }
+    }
+    None
+}
+
+fn listening_inodes_on_x11_socket(proc_root: &ProcRoot, endpoint: &X11Endpoint) -> HashSet<String> {
+    read_listening_inodes(&proc_root.join("net"), endpoint).unwrap_or_default()
+}
+
+// net 為 <proc>/net 或 <proc>/<pid>/net；讀不到時回傳 None
+fn read_listening_inodes(net: &Path, endpoint: &X11Endpoint) -> Option<HashSet<String>> {
+    match endpoint {
+        X11Endpoint::Unix(_) => {
+            let body = fs::read_to_string(net.join("unix")).ok()?;
+            Some(parse_listening_inodes(&body, &endpoint.socket_names()))
+        }
+        X11Endpoint::Tcp(port) => {
+            let bodies = read_tcp_tables(net)?;
+            Some(
+                bodies
+                    .iter()
+                    .flat_map(|body| parse_tcp_listening_inodes(body, *port))
+                    .collect(),
+            )
+        }
+    }
+}
+
+// IPv4 與 IPv6 各一張表；tcp6 不存在（核心未啟用 IPv6）時只用 tcp
+fn read_tcp_tables(net: &Path) -> Option<Vec<String>> {
+    let mut bodies = vec![fs::read_to_string(net.join("tcp")).ok()?];
+    bodies.extend(fs::read_to_string(net.join("tcp6")).ok());
+    Some(bodies)
+}
+
+struct TcpEntry<'a> {
+    local: &'a str,
+    remote: &'a str,
+    state: &'a str,
+    inode: &'a str,
+}
+
+// /proc/net/tcp 每行：sl local_address rem_address st tx:rx tr:when retrnsmt uid timeout inode ...
+// 位址為十六進位的 <IP>:<port>，同一張表內可直接以字串比較
+fn parse_tcp_table(body: &str) -> Vec<TcpEntry<'_>> {
+    body.lines()
+        .skip(1)
+        .filter_map(|line| {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            (tokens.len() >= 10).then(|| TcpEntry {
+                local: tokens[1],
+                remote: tokens[2],
+                state: tokens[3],
+                inode: tokens[9],
+            })
+        })
+        .collect()
+}
+
+fn tcp_port(address: &str) -> Option<u16> {
+    u16::from_str_radix(address.rsplit_once(':')?.1, 16).ok()
+}
+
+// X11UseLocalhost yes（預設）時 sshd 綁在 127.0.0.1 與 ::1，no 時綁在所有介面（0.0.0.0 與 ::），只看 port
+fn parse_tcp_listening_inodes(body: &str, port: u16) -> HashSet<String> {
+    parse_tcp_table(body)
+        .into_iter()
+        .filter(|entry| entry.state == TCP_LISTEN && tcp_port(entry.local) == Some(port))
+        .map(|entry| entry.inode.to_string())
+        .collect()
+}
+
+// 應用程式端的連線：連到 port，而且對向那一端（sshd accept 的 socket，本機與對端位址對調）也在同一張表中，
+// 表示連線終止在這台機器上。X11UseLocalhost no 時程式可能經由主機名稱連到非 loopback 位址，一樣成立；
+// 連到其他機器 port 60xx 的連線沒有對向的那一端，不會被算進來
+fn parse_tcp_x11_clients(body: &str, port: u16) -> HashSet<String> {
+    let entries = parse_tcp_table(body);
+    let established: HashSet<(&str, &str)> = entries
+        .iter()
+        .filter(|entry| entry.state == TCP_ESTABLISHED)
+        .map(|entry| (entry.local, entry.remote))
+        .collect();
+    entries
+        .iter()
+        .filter(|entry| {
+            entry.state == TCP_ESTABLISHED
+                && tcp_port(entry.remote) == Some(port)
+                && established.contains(&(entry.remote, entry.local))
+        })
+        .map(|entry| entry.inode.to_string())
+        .collect()
+}
+
+fn tcp_x11_client_inodes(proc_root: &ProcRoot, port: u16) -> HashSet<String> {
+    let bodies = read_tcp_tables(&proc_root.join("net")).unwrap_or_default();
+    for (name, body) in ["net/tcp", "net/tcp6"].iter().zip(&bodies) {
+        explain::record_raw(name, body);
+    }
+    bodies
+        .iter()
+        .flat_map(|body| parse_tcp_x11_clients(body, port))
+        .collect()
+}
+
+// /proc/<pid>/ns/net 指向 net:[<inode>]，同一個網路命名空間的程序 inode 相同
+fn netns_of(proc_root: &ProcRoot, pid: i32) -> Option<u64> {
+    let link = fs::read_link(proc_root.pid_entry(pid, "ns/net")).ok()?;
+    link.to_str()?
+        .strip_prefix("net:[")?
+        .strip_suffix(']')?
+        .parse()
+        .ok()
+}
+
+// unix 與 tcp socket 表是各網路命名空間各自一份：/proc/net/unix 只有本程序所在的命名空間，
+// 容器中的程序要讀 /proc/<主機 PID>/net/unix 才看得到它自己命名空間裡的監聽 socket
+fn listening_inodes_in_netns_of(
+    proc_root: &ProcRoot,
+    pid: i32,
+    endpoint: &X11Endpoint,
+) -> HashSet<String> {
+    read_listening_inodes(&proc_root.pid_entry(pid, "net"), endpoint)
+        .unwrap_or_else(|| listening_inodes_on_x11_socket(proc_root, endpoint))
+}
+
+// proc_root/self 指向本程序在該 proc 所屬 PID 命名空間中的 PID；與 getpid() 不同表示
+// 在容器中以 --proc-root 讀主機的 proc，但沒有共用主機 PID 命名空間，找到的 PID 不能拿來送訊號
+fn check_pid_namespace(proc_root: &ProcRoot) -> Result<(), String> {
+    let Ok(link) = fs::read_link(proc_root.join("self")) else {
+        return Ok(());
+    };
+    let own = std::process::id();
+    match link.to_str().and_then(|text| text.parse::<u32>().ok()) {
+        Some(seen) if seen != own => Err(format!(
+            "{} 屬於另一個 PID 命名空間（其中本程序為 PID {seen}，本身為 PID {own}），找到的 PID 無法用來送訊號；容器中請共用主機 PID 命名空間（例如 --pid=host）",
+            proc_root.path().display()
+        )),
+        _ => Ok(()),
+    }
+}
+
+// /proc/net/unix 中所有監聽中的 socket：（inode, 路徑），抽象 socket 的路徑以 @ 開頭
+fn parse_listeners(body: &str) -> Vec<(&str, &str)> {
+    let mut listeners = Vec::new();
+    for line in body.lines().skip(1) {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.len() < 8 {
+            continue;
+        }
+        let flags = u32::from_str_radix(tokens[3], 16).unwrap_or(0);
+        if flags & SO_ACCEPTCON == 0 {
+            continue;
+        }
+        listeners.push((tokens[6], tokens[7]));
+    }
+    listeners
+}
+
+fn parse_listening_inodes(body: &str, names: &[String]) -> HashSet<String> {
+    parse_listeners(body)
+        .into_iter()
+        .filter(|(_, path)| is_x11_socket_name(names, path))
+        .map(|(inode, _)| inode.to_string())
+        .collect()
+}
+
+// 路徑上的 X11 socket：socket 檔本身（/tmp 為 tmpfs 被清空重建時 inode 會變）與在該路徑監聽的 socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct X11SocketIdentity {
+    dev: u64,
+    ino: u64,
+    listeners: Vec<String>,
+}
+
+fn x11_socket_identity(
+    proc_root: &ProcRoot,
+    endpoint: &X11Endpoint,
+) -> Result<X11SocketIdentity, String> {
+    let socket_path = match endpoint {
+        X11Endpoint::Unix(path) => path,
+        // TCP 沒有 socket 檔，只確認有程序在該 port 監聽（ssh 連線結束時 sshd 會關閉它）
+        X11Endpoint::Tcp(_) => {
+            let mut listeners: Vec<String> =
+                read_listening_inodes(&proc_root.join("net"), endpoint)
+                    .ok_or("無法讀取 net/tcp")?
+                    .into_iter()
+                    .collect();
+            if listeners.is_empty() {
+                return Err("沒有程序在此 port 監聽（ssh 的 X11 轉送是否已結束？）".to_string());
+            }
+            listeners.sort_unstable();
+            return Ok(X11SocketIdentity {
+                dev: 0,
+                ino: 0,
+                listeners,
+            });
+        }
+    };
+    let metadata =
+        fs::metadata(socket_path).map_err(|err| format!("無法存取 socket 檔（{err}）"))?;
+    if !metadata.file_type().is_socket() {
+        return Err("路徑存在但不是 socket".to_string());
+    }
+    let body = fs::read_to_string(proc_root.join("net/unix"))
+        .map_err(|err| format!("無法讀取 net/unix（{err}）"))?;
+    let mut listeners: Vec<String> = parse_listeners(&body)
+        .into_iter()
+        .filter(|(_, path)| path == socket_path)
+        .map(|(inode, _)| inode.to_string())
+        .collect();
+    if listeners.is_empty() {
+        return Err("沒有程序在此路徑監聽".to_string());
+    }
+    listeners.sort_unstable();
+    Ok(X11SocketIdentity {
+        dev: metadata.dev(),
+        ino: metadata.ino(),
+        listeners,
+    })
+}
+
+// X server 若剛好符合名稱條件，絕不能被監看或被殺：持有監聽 socket 的程序一律排除
+fn find_app_pids(
+    scanner: &mut FdScanner,
+    process_names: &[String],
+    cmdline: &[CmdlineMatch],
+    endpoint: &X11Endpoint,
+) -> (Vec<i32>, Vec<i32>) {
+    let pids = find_pids_by_name(&scanner.proc_root, process_names, cmdline);
+    exclude_x_servers(scanner, pids, endpoint)
+}
+
+fn exclude_x_servers(
+    scanner: &mut FdScanner,
+    pids: Vec<i32>,
+    endpoint: &X11Endpoint,
+) -> (Vec<i32>, Vec<i32>) {
+    if pids.is_empty() {
+        return (pids, Vec::new());
+    }
+    let server = xprobe::server_pid(endpoint);
+    let sockets: HashMap<i32, HashSet<String>> =
+        scanner.socket_inodes_by_pid(&pids).into_iter().collect();
+    let unreadable = scanner.unreadable.clone();
+    let proc_root = &scanner.proc_root;
+    // 每個主機 PID 對照它自己網路命名空間的監聽 socket；同一個命名空間只讀一次
+    let mut listening_by_netns: HashMap<Option<u64>, HashSet<String>> = HashMap::new();
+    pids.into_iter().partition(|pid| {
+        let listening = listening_by_netns
+            .entry(netns_of(proc_root, *pid))
+            .or_insert_with_key(|netns| match netns {
+                Some(_) => listening_inodes_in_netns_of(proc_root, *pid, endpoint),
+                None => listening_inodes_on_x11_socket(proc_root, endpoint),
+            });
+        let sockets = (!unreadable.contains(pid)).then(|| &sockets[pid]);
+        !possibly_x_server(*pid, server, sockets, listening)
+    })
+}
+
+// 寧可錯排也不能漏：確認不是 X server 才監看。SO_PEERCRED 找到的監聽者一定排除；
+// fd 目錄讀不到（root 的 Xorg 對一般使用者是 EACCES）或持有監聽 socket 的也排除；
+// 讀不到監聽表時只有 SO_PEERCRED 確認過 server 是誰，其他程序才算不是
+fn possibly_x_server(
+    pid: i32,
+    server: Option<i32>,
+    sockets: Option<&HashSet<String>>,
+    listening: &HashSet<String>,
+) -> bool {
+    if server == Some(pid) {
+        return true;
+    }
+    let Some(sockets) = sockets else {
+        return true;
+    };
+    if listening.is_empty() {
+        return server.is_none();
+    }
+    !sockets.is_disjoint(listening)
+}
+
+struct ConnectionCount {
+    // 計入的連線：（應用程式端 socket inode, 持有的 PID），依 inode 數值排序
+    connections: Vec<(String, i32)>,
+    // 嚴格歸屬檢查剔除的候選連線數
+    rejected: usize,
+    // fd 目錄無法讀取、連線未能計入的 PID
+    unreadable: Vec<i32>,
+}
+
+// explain 用：count_app_x11_connections 各階段的中間結果，inode 皆依數值排序。
+// 沒有程序或後端沒有回報任何對端時，計數在該階段就結束，之後的欄位維持空的
+#[derive(Default)]
+struct CountTrace {
+    peers: Vec<String>,
+    socket_inodes: Vec<(i32, Vec<String>)>,
+    candidates: Vec<(String, i32)>,
+    rejected: Vec<String>,
+    intra_app: Vec<String>,
+}
+
+fn sorted_inodes<'a>(inodes: impl IntoIterator<Item = &'a String>) -> Vec<String> {
+    let mut sorted: Vec<String> = inodes.into_iter().cloned().collect();
+    sorted.sort_by_key(|inode| inode.parse::<u64>().unwrap_or(u64::MAX));
+    sorted
+}
+
+fn count_app_x11_connections(
+    app_pids: &[i32],
+    backend: &mut PeerBackend,
+    endpoint: &X11Endpoint,
+    fd_scanner: &mut FdScanner,
+    strict: bool,
+    mut trace: Option<&mut CountTrace>,
+) -> ConnectionCount {
+    let mut result = ConnectionCount {
+        connections: Vec::new(),
+        rejected: 0,
+        unreadable: Vec::new(),
+    };
+    if app_pids.is_empty() {
+        return result;
+    }
+    let x11_peer_inodes = backend.x11_peer_inodes(&fd_scanner.proc_root, endpoint);
+    if let Some(trace) = trace.as_deref_mut() {
+        trace.peers = sorted_inodes(&x11_peer_inodes);
+    }
+    if x11_peer_inodes.is_empty() {
+        return result;
+    }
+    // 同一個 inode 出現在多個 PID（fork 後共用）時記在 PID 較小者
+    let mut candidates: HashMap<String, i32> = HashMap::new();
+    for (pid, inodes) in fd_scanner.socket_inodes_by_pid(app_pids) {
+        if let Some(trace) = trace.as_deref_mut() {
+            trace.socket_inodes.push((pid, sorted_inodes(&inodes)));
+        }
+        for inode in inodes {
+            if x11_peer_inodes.contains(&inode) {
+                candidates.entry(inode).or_insert(pid);
+            }
+        }
+    }
+    result.unreadable = fd_scanner.unreadable.clone();
+    if let Some(trace) = trace.as_deref_mut() {
+        trace.candidates = candidates.clone().into_iter().collect();
+        trace
+            .candidates
+            .sort_by_key(|(inode, _)| inode.parse::<u64>().unwrap_or(u64::MAX));
+    }
+    if strict && !candidates.is_empty() {
+        let verified = verify_attribution(
+            &fd_scanner.proc_root,
+            app_pids,
+            backend,
+            endpoint,
+            &candidates,
+        );
+        result.rejected = candidates.len() - verified.len();
+        if let Some(trace) = trace {
+            trace.rejected = sorted_inodes(
+                candidates
+                    .keys()
+                    .filter(|inode| !verified.contains_key(*inode)),
+            );
+        }
+        candidates = verified;
+    }
+    result.connections = candidates.into_iter().collect();
+    result
+        .connections
+        .sort_by_key(|(inode, _)| inode.parse::<u64>().unwrap_or(u64::MAX));
+    result
+}
+
+// 與上一次檢查相比新增與關閉的連線，皆依 inode 排序
+fn connection_delta(
+    previous: &HashMap<String, i32>,
+    current: &[(String, i32)],
+) -> (Vec<(String, i32)>, Vec<String>) {
+    let added = current
+        .iter()
+        .filter(|(inode, _)| !previous.contains_key(inode))
+        .cloned()
+        .collect();
+    let current_inodes: HashSet<&String> = current.iter().map(|(inode, _)| inode).collect();
+    let mut removed: Vec<String> = previous
+        .keys()
+        .filter(|inode| !current_inodes.contains(inode))
+        .cloned()
+        .collect();
+    removed.sort_by_key(|inode| inode.parse::<u64>().unwrap_or(u64::MAX));
+    (added, removed)
+}
+
+fn format_inode_list<'a>(items: impl Iterator<Item = String> + 'a, total: usize) -> String {
+    const LIMIT: usize = 20;
+    let mut text: Vec<String> = items.take(LIMIT).collect();
+    if total > LIMIT {
+        text.push(format!("…等 {total} 條"));
+    }
+    text.join(" ")
+}
+
+// 兩次觀察之間連線可能已關閉、inode 被無關的 socket 重用：
+// 再取一次 peer 集合並重新 readlink，兩邊都還在的候選才算數
+fn verify_attribution(
+    proc_root: &ProcRoot,
+    app_pids: &[i32],
+    backend: &mut PeerBackend,
+    endpoint: &X11Endpoint,
+    candidates: &HashMap<String, i32>,
+) -> HashMap<String, i32> {
+    let current_peers = backend.x11_peer_inodes(proc_root, endpoint);
+    let mut current_fds = HashSet::new();
+    for pid in app_pids {
+        current_fds.extend(socket_inodes_for_pid(proc_root, *pid));
+    }
+    candidates
+        .iter()
+        .filter(|(inode, _)| current_peers.contains(*inode) && current_fds.contains(*inode))
+        .map(|(inode, pid)| (inode.clone(), *pid))
+        .collect()
+}
+
+// --exclude-intra-app：ss 列出的是 X socket 上伺服器端的 socket，持有者通常是 X server；
+// 持有者是監控中的程序時（例如應用程式把 accept 到的 socket 傳給自己的其他程序），
+// 這條連線的兩端都是應用程式，回傳其應用程式端的 inode
+#[cfg(feature = "backend-ss")]
+fn intra_app_peer_inodes(names: &[String], app_pids: &[i32]) -> HashSet<String> {
+    if names.is_empty() {
+        return HashSet::new();
+    }
+    match sandbox::run_output(&ss_on_x11_socket(names)) {
+        Ok((true, stdout)) => {
+            parse_intra_app_peers(&String::from_utf8_lossy(&stdout), names, app_pids)
+        }
+        _ => HashSet::new(),
+    }
+}
+
+// parse_args 已拒絕未編入 ss 時的 --exclude-intra-app
+#[cfg(not(feature = "backend-ss"))]
+fn intra_app_peer_inodes(_names: &[String], _app_pids: &[i32]) -> HashSet<String> {
+    HashSet::new()
+}
+
+// 同一個 socket 可能有多個持有者，任一個是監控中的程序就算；沒有程序資訊的行不排除
+#[cfg(feature = "backend-ss")]
+fn parse_intra_app_peers(body: &str, names: &[String], app_pids: &[i32]) -> HashSet<String> {
+    let mut inodes = HashSet::new();
+    for line in body.lines() {
+        let Some((columns, users)) = split_ss_users(line) else {
+            continue;
+        };
+        let tokens: Vec<&str> = columns.split_whitespace().collect();
+        let Some(peer) = extract_peer_inode(&tokens, names) else {
+            continue;
+        };
+        let owned_by_app = users.split("pid=").skip(1).any(|rest| {
+            let end = rest
+                .find(|char: char| !char.is_ascii_digit())
+                .unwrap_or(rest.len());
+            rest[..end]
+                .parse::<i32>()
+                .is_ok_and(|pid| app_pids.contains(&pid))
+        });
+        if owned_by_app {
+            inodes.insert(peer.to_string());
+        }
+    }
+    inodes
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SocketOwner {
+    pid: i32,
+    comm: String,
+}
+
+// 以 ss -xnp 列出所有 unix socket 的持有程序；非 root 時 ss 看不到其他使用者的程序資訊，該 inode 就不會出現
+#[cfg(feature = "backend-ss")]
+fn unix_socket_owners() -> HashMap<String, SocketOwner> {
+    let spec = CommandSpec::new("ss").arg("-xnpH").lowered();
+    match sandbox::run_output(&spec) {
+        Ok((true, stdout)) => parse_socket_owners(&String::from_utf8_lossy(&stdout)),
+        _ => HashMap::new(),
+    }
+}
+
+#[cfg(not(feature = "backend-ss"))]
+fn unix_socket_owners() -> HashMap<String, SocketOwner> {
+    HashMap::new()
+}
+
+// 程序名稱與 socket 路徑都可能含空白，不能以固定欄位切割：先從 users:( 切出持有者，
+// 前面的欄位從行尾往回數（對端 inode、對端位址、本端 inode）
+#[cfg(feature = "backend-ss")]
+fn parse_socket_owners(body: &str) -> HashMap<String, SocketOwner> {
+    let mut owners = HashMap::new();
+    for line in body.lines() {
+        let Some((columns, users)) = split_ss_users(line) else {
+            continue;
+        };
+        let tokens: Vec<&str> = columns.split_whitespace().collect();
+        if tokens.len() < 8 {
+            continue;
+        }
+        let inode = tokens[tokens.len() - 3];
+        if !inode.chars().all(|char| char.is_ascii_digit()) {
+            continue;
+        }
+        if let Some(owner) = parse_ss_users(users) {
+            owners.insert(inode.to_string(), owner);
+        }
+    }
+    owners
+}
+
+// 把一行 ss 輸出分成前面的欄位與 users:(...) 的持有者資訊；沒有程序資訊的行回傳 None
+#[cfg(feature = "backend-ss")]
+fn split_ss_users(line: &str) -> Option<(&str, &str)> {
+    let start = line.find(" users:(")?;
+    Some((&line[..start], line[start + 1..].trim_end()))
+}
+
+// users:(("fakeqq",pid=4795,fd=5),...) 只取第一個持有者
+#[cfg(feature = "backend-ss")]
+fn parse_ss_users(text: &str) -> Option<SocketOwner> {
+    let body = text.strip_prefix("users:((\"")?;
+    let (comm, rest) = body.split_once('"')?;
+    let pid_text = rest.strip_prefix(",pid=")?;
+    let pid_end = pid_text
+        .find(|char: char| !char.is_ascii_digit())
+        .unwrap_or(pid_text.len());
+    let pid = pid_text[..pid_end].parse().ok()?;
+    Some(SocketOwner {
+        pid,
+        comm: comm.to_string(),
+    })
+}
+
+// ===== 區塊 3：事件來源（inotify） =====
+struct InotifyWatch {
+    fd: RawFd,
+    proc_root: ProcRoot,
+    wd_to_pid: HashMap<i32, i32>,
+    pid_to_wd: HashMap<i32, i32>,
+}
+
+impl InotifyWatch {
+    fn new(proc_root: ProcRoot) -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fd,
+            proc_root,
+            wd_to_pid: HashMap::new(),
+            pid_to_wd: HashMap::new(),
+        })
+    }
+
+    fn add_pid(&mut self, pid: i32) -> io::Result<()> {
+        if self.pid_to_wd.contains_key(&pid) {
+            return Ok(());
+        }
+        let fd_path = self.proc_root.pid_entry(pid, "fd");
+        if !fd_path.is_dir() {
+            return Ok(());
+        }
+        let c_path = match CString::new(fd_path.into_os_string().into_vec()) {
+            Ok(value) => value,
+            Err(_) => return Ok(()),
+        };
+        let wd = unsafe { libc::inotify_add_watch(self.fd, c_path.as_ptr(), WATCH_MASK) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.wd_to_pid.insert(wd, pid);
+        self.pid_to_wd.insert(pid, wd);
+        Ok(())
+    }
+
+    fn remove_pid(&mut self, pid: i32) {
+        let wd = match self.pid_to_wd.remove(&pid) {
+            Some(value) => value,
+            None => return,
+        };
+        self.wd_to_pid.remove(&wd);
+        unsafe {
+            libc::inotify_rm_watch(self.fd, wd);
+        }
+    }
+
+    fn sync_pids(&mut self, current_pids: &[i32]) -> Vec<(i32, io::Error)> {
+        let current: HashSet<i32> = current_pids.iter().copied().collect();
+        let existing: HashSet<i32> = self.pid_to_wd.keys().copied().collect();
+
+        for pid in existing.difference(&current) {
+            self.remove_pid(*pid);
+        }
+        let mut failures = Vec::new();
+        for pid in current.difference(&existing) {
+            if let Err(err) = self.add_pid(*pid) {
+                failures.push((*pid, err));
+            }
+        }
+        failures
+    }
+
+    // 讀取佇列中的事件，回傳有變動的 PID 與是否達到上限。
+    // 每次最多處理約 max_events 個事件（以整批 read 為單位檢查），
+    // 剩下的留在佇列裡，讓迴圈先回去處理計時任務
+    fn read_events(&mut self, max_events: usize) -> io::Result<(Vec<i32>, bool)> {
+        let mut events = Vec::new();
+        let mut capped = false;
+        let mut buffer = [0u8; EVENT_BUF_SIZE];
+        let mut processed = 0usize;
+
+        loop {
+            if processed >= max_events {
+                capped = true;
+                break;
+            }
+            let read_size =
+                unsafe { libc::read(self.fd, buffer.as_mut_ptr().cast(), buffer.len()) as isize };
+            if read_size < 0 {
+                let error = io::Error::last_os_error();
+                if matches!(error.raw_os_error(), Some(code) if code == libc::EAGAIN || code == libc::EINTR)
+                {
+                    break;
+                }
+                return Err(error);
+            }
+            if read_size == 0 {
+                break;
+            }
+
+            let mut offset = 0usize;
+            let total = read_size as usize;
+            while offset + mem::size_of::<libc::inotify_event>() <= total {
+                let event_ptr =
+                    unsafe { buffer.as_ptr().add(offset).cast::<libc::inotify_event>() };
+                let event = unsafe { ptr::read_unaligned(event_ptr) };
+                offset += mem::size_of::<libc::inotify_event>();
+
+                let name_len = event.len as usize;
+                if offset + name_len > total {
+                    break;
+                }
+                offset += name_len;
+                processed += 1;
+
+                let pid_opt = self.wd_to_pid.get(&event.wd).copied();
+                if event.mask & (libc::IN_DELETE_SELF | libc::IN_MOVE_SELF) != 0 {
+                    if let Some(pid) = pid_opt {
+                        self.remove_pid(pid);
+                        events.push(pid);
+                    }
+                    continue;
+                }
+                if let Some(pid) = pid_opt {
+                    events.push(pid);
+                }
+            }
+        }
+        Ok((events, capped))
+    }
+}
+
+// 主迴圈等待的 fd 來源
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Source {
+    Inotify,
+    Pidfd(i32),
+    Control,
+    Shutdown,
+}
+
+#[derive(Default)]
+struct Wakeup {
+    // fd 目錄有變動的 PID
+    events: Vec<i32>,
+    // pidfd 回報已結束的 PID
+    exited: Vec<i32>,
+    control: bool,
+    // 達到每次喚醒的事件上限，佇列中可能還有事件
+    capped: bool,
+}
+
+impl Drop for InotifyWatch {
+    fn drop(&mut self) {
+        if self.fd >= 0 {
+            unsafe {
+                libc::close(self.fd);
+            }
+        }
+    }
+}
+
+// pidfd（Linux 5.3+）：程序結束時 pidfd 變為可讀，送訊號也不會誤中被回收再利用的 PID
+fn pidfd_open(pid: i32) -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+fn pidfd_send_signal(fd: &OwnedFd, sig: i32) -> io::Result<()> {
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_pidfd_send_signal,
+            fd.as_raw_fd(),
+            sig,
+            ptr::null::<libc::siginfo_t>(),
+            0,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+struct PidfdTable {
+    supported: bool,
+    fds: HashMap<i32, OwnedFd>,
+    // 開過的 pidfd 數；主迴圈據此判斷要不要重新登記等待的 fd
+    opened: u64,
+    // 已回報結束但尚未被回收（殭屍）的 PID，仍會出現在 /proc，不再重開 pidfd
+    exited: HashSet<i32>,
+}
+
+impl PidfdTable {
+    fn detect() -> Self {
+        let supported = match pidfd_open(std::process::id() as i32) {
+            Ok(_) => true,
+            Err(error) => error.raw_os_error() != Some(libc::ENOSYS),
+        };
+        Self {
+            supported,
+            fds: HashMap::new(),
+            opened: 0,
+            exited: HashSet::new(),
+        }
+    }
+
+    fn mechanism(&self) -> &'static str {
+        if self.supported {
+            "pidfd"
+        } else {
+            "名稱掃描 + kill（核心不支援 pidfd）"
+        }
+    }
+
+    fn sync_pids(&mut self, pids: &[i32]) {
+        if !self.supported {
+            return;
+        }
+        self.fds.retain(|pid, _| pids.contains(pid));
+        self.exited.retain(|pid| pids.contains(pid));
+        for pid in pids {
+            if !self.fds.contains_key(pid) && !self.exited.contains(pid) {
+                if let Ok(fd) = pidfd_open(*pid) {
+                    self.fds.insert(*pid, fd);
+                    self.opened += 1;
+                }
+            }
+        }
+    }
+
+    fn poll_fds(&self) -> Vec<(i32, RawFd)> {
+        self.fds
+            .iter()
+            .map(|(pid, fd)| (*pid, fd.as_raw_fd()))
+            .collect()
+    }
+
+    fn mark_exited(&mut self, pid: i32) {
+        self.fds.remove(&pid);
+        self.exited.insert(pid);
+    }
+
+    // 尚未追蹤的 PID 先補開 pidfd；開啟時回報 ESRCH 代表程序已結束，不會退回 kill 以免誤中新程序
+    fn signal(&mut self, pid: i32, sig: i32) -> io::Result<()> {
+        if !self.supported {
+            if unsafe { libc::kill(pid, sig) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            return Ok(());
+        }
+        let fd = match self.fds.entry(pid) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                self.opened += 1;
+                entry.insert(pidfd_open(pid)?)
+            }
+        };
+        pidfd_send_signal(fd, sig)
+    }
+
+    // 有 pidfd 時看它是否已可讀；沒有時退回 kill(pid, 0)，EPERM 代表程序仍在只是無權限
+    // 沒有 pidfd 時只看該 PID 的 /proc/<pid>/stat，不做全表名稱掃描；殭屍程序視為已結束
+    fn is_alive(&self, proc_root: &ProcRoot, pid: i32) -> bool {
+        if let Some(fd) = self.fds.get(&pid) {
+            let mut poll_fd = libc::pollfd {
+                fd: fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            return unsafe { libc::poll(&mut poll_fd, 1, 0) } == 0;
+        }
+        if self.exited.contains(&pid) {
+            return false;
+        }
+        read_proc_stat(proc_root, pid).is_some_and(|stat| !matches!(stat.state, 'Z' | 'X'))
+    }
+
+    // 等待指定程序全部結束；回傳是否在時限內結束。未追蹤到 pidfd 的 PID 視為已結束
+    fn wait_exit(&self, pids: &[i32], timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut pending: Vec<RawFd> = pids
+            .iter()
+            .filter_map(|pid| self.fds.get(pid).map(|fd| fd.as_raw_fd()))
+            .collect();
+        while !pending.is_empty() {
+            let remain = deadline.saturating_duration_since(Instant::now());
+            let mut poll_fds: Vec<libc::pollfd> = pending
+                .iter()
+                .map(|fd| libc::pollfd {
+                    fd: *fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                })
+                .collect();
+            let ready = unsafe {
+                libc::poll(
+                    poll_fds.as_mut_ptr(),
+                    poll_fds.len() as libc::nfds_t,
+                    remain.as_millis().min(i32::MAX as u128) as i32,
+                )
+            };
+            if ready < 0 && io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                return false;
+            }
+            pending = poll_fds
+                .iter()
+                .filter(|poll_fd| poll_fd.revents == 0)
+                .map(|poll_fd| poll_fd.fd)
+                .collect();
+            if !pending.is_empty() && remain.is_zero() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// ===== 區塊 4：超標後的重啟動作 =====
+// 重啟群組的附屬成員：主程式超標時與它一起依序停止、啟動
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct GroupMember {
+    name: String,
+    restart_cmd: String,
+}
+
+fn parse_group_member(spec: &str) -> Result<GroupMember, String> {
+    let (name, command) = spec
+        .split_once('=')
+        .ok_or_else(|| format!("格式應為 <名稱>=<命令>: {spec}"))?;
+    let name = name.trim();
+    if name.is_empty() || command.trim().is_empty() {
+        return Err(format!("名稱與命令都不可為空: {spec}"));
+    }
+    // /proc/<pid>/comm 最多 15 位元組，更長的名稱永遠比對不到
+    if process_name_bytes(name).len() > 15 {
+        return Err(format!("程序名稱最多 15 位元組: {name}"));
+    }
+    Ok(GroupMember {
+        name: canonical_process_name(name),
+        restart_cmd: command.trim().to_string(),
+    })
+}
+
+// --app-name 名稱[,名稱...][:門檻][=命令]：命令從第一個 = 之後原樣保留，可以含有 : 與 ,
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct AppSpec {
+    names: Vec<String>,
+    // None 表示沿用 --threshold
+    threshold: Option<usize>,
+    // None 表示以第一個名稱作為重啟命令
+    restart_cmd: Option<String>,
+}
+
+fn parse_app_spec(spec: &str) -> Result<AppSpec, String> {
+    let (head, restart_cmd) = match spec.split_once('=') {
+        Some((_, command)) if command.trim().is_empty() => {
+            return Err(format!("= 之後的重啟命令不能是空的: {spec}"));
+        }
+        Some((head, command)) => (head, Some(command.trim().to_string())),
+        None => (spec, None),
+    };
+    let (names, threshold) = match head.rsplit_once(':') {
+        Some((names, threshold)) => {
+            let threshold = threshold
+                .trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|threshold| *threshold >= 1)
+                .ok_or_else(|| format!("門檻必須是 >= 1 的整數: {spec}"))?;
+            (names, Some(threshold))
+        }
+        None => (head, None),
+    };
+    let mut app = AppSpec {
+        names: Vec::new(),
+        threshold,
+        restart_cmd,
+    };
+    for name in names.split(',') {
+        let name = canonical_process_name(name.trim());
+        if name.is_empty() {
+            return Err("的名稱不能是空的".to_string());
+        }
+        if app.names.contains(&name) {
+            return Err(format!("名稱重複: {name}"));
+        }
+        app.names.push(name);
+    }
+    Ok(app)
+}
+
+fn parse_group_order(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(canonical_process_name)
+        .collect()
+}
+
+// 未指定順序時附屬成員依 --group-member 的順序排在主程式之前：先停附屬、再停主程式，啟動時同樣先附屬。
+// 主程式有多個名稱時，順序中寫其中任一個都代表整組主程式
+fn resolve_group_order(config: &mut Config) -> Result<(), String> {
+    if config.group_members.is_empty() {
+        if !config.group_stop_order.is_empty() || !config.group_start_order.is_empty() {
+            return Err(
+                "--group-stop-order 與 --group-start-order 需要搭配 --group-member".to_string(),
+            );
+        }
+        return Ok(());
+    }
+    let mut members: Vec<String> = Vec::new();
+    for member in &config.group_members {
+        if config.app_names.contains(&member.name) || members.contains(&member.name) {
+            return Err(format!("--group-member 名稱重複: {}", member.name));
+        }
+        members.push(member.name.clone());
+    }
+    members.push(config.app_name.clone());
+    let mut expected = members.clone();
+    expected.sort();
+    for (flag, order) in [
+        ("--group-stop-order", &mut config.group_stop_order),
+        ("--group-start-order", &mut config.group_start_order),
+    ] {
+        if order.is_empty() {
+            order.clone_from(&members);
+            continue;
+        }
+        for name in order.iter_mut() {
+            if config.app_names.contains(name) {
+                name.clone_from(&config.app_name);
+            }
+        }
+        let mut listed = order.clone();
+        listed.sort();
+        if listed != expected {
+            let primary = &config.app_names[0];
+            return Err(format!(
+                "{flag} 必須恰好列出每個成員一次：{}",
+                config
+                    .group_members
+                    .iter()
+                    .map(|member| &member.name)
+                    .chain([primary])
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn terminate_processes(pids: &[i32], sig: i32, pidfds: &mut PidfdTable) -> Vec<(i32, io::Error)> {
+    let mut failures = Vec::new();
+    for pid in pids {
+        if let Err(error) = pidfds.signal(*pid, sig) {
+            failures.push((*pid, error));
+        }
+    }
+    failures
+}
+
+fn permission_hint(config: &Config) -> &'static str {
+    if config.drop_privileges.is_some() {
+        "已以 --drop-privileges 降權，只保留 CAP_KILL、CAP_SYS_PTRACE、CAP_DAC_READ_SEARCH；\
+         若此操作確實需要 root，請改為不降權執行（代價是整個生命週期都持有 root）"
+    } else {
+        "權限不足，可改以目標程序的擁有者或 root 執行"
+    }
+}
+
+// 只追蹤送出訊號的那批 PID，不再以名稱比對：重啟期間自動啟動的新一代不會拖住等待。
+// 回傳逾時後仍存活的 PID
+fn wait_until_gone(
+    proc_root: &ProcRoot,
+    pids: &[i32],
+    pidfds: &PidfdTable,
+    timeout: Duration,
+) -> Vec<i32> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let alive: Vec<i32> = pids
+            .iter()
+            .copied()
+            .filter(|pid| pidfds.is_alive(proc_root, *pid))
+            .collect();
+        let remain = deadline.saturating_duration_since(Instant::now());
+        if alive.is_empty() || remain.is_zero() {
+            return alive;
+        }
+        // 有 pidfd 的程序結束時立即醒來；其餘的每 200ms 重新確認一次
+        let tracked: Vec<i32> = alive
+            .iter()
+            .copied()
+            .filter(|pid| pidfds.fds.contains_key(pid))
+            .collect();
+        let slice = if tracked.len() == alive.len() {
+            remain
+        } else {
+            remain.min(Duration::from_millis(200))
+        };
+        if tracked.is_empty() {
+            thread::sleep(slice);
+        } else {
+            pidfds.wait_exit(&tracked, slice);
+        }
+    }
+}
+
+fn start_process(command: &str, values: &[(&str, String)]) -> io::Result<u32> {
+    sandbox::spawn_detached(&template::fill(user_command(command, true), values))
+}
+
+// --restart-cmd 等使用者提供的命令。預設交給 sh（login 時用 -lc 讀取 profile）；
+// 未編入 shell-restart 時依引號切成 argv 直接執行，沒有變數展開、重導向與管線
+#[cfg(feature = "shell-restart")]
+fn user_command(command: &str, login: bool) -> CommandSpec {
+    CommandSpec::new("sh")
+        .arg(if login { "-lc" } else { "-c" })
+        .arg(command)
+}
+
+#[cfg(not(feature = "shell-restart"))]
+fn user_command(command: &str, _login: bool) -> CommandSpec {
+    // parse_args 已確認每個命令都能切開
+    let argv = split_argv(command).unwrap_or_default();
+    let program = argv.first().map_or("", String::as_str);
+    argv.iter()
+        .skip(1)
+        .fold(CommandSpec::new(program), |spec, arg| spec.arg(arg))
+}
+
+// 類似 sh 的切字：空白分隔，'…' 內照字面，"…" 內只有 \" \\ 是跳脫，引號外的 \ 跳脫下一個字元。
+// 不經過 shell 時遇到未加引號的 shell 語法直接拒絕，免得被當成一般參數傳給程式
+fn split_argv(command: &str) -> Result<Vec<String>, String> {
+    let mut argv = Vec::new();
+    let mut current: Option<String> = None;
+    let mut chars = command.chars();
+    while let Some(char) = chars.next() {
+        match char {
+            ' ' | '\t' | '\n' => {
+                argv.extend(current.take());
+            }
+            '\'' => {
+                let word = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next().ok_or("單引號未閉合")? {
+                        '\'' => break,
+                        char => word.push(char),
+                    }
+                }
+            }
+            '"' => {
+                let word = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next().ok_or("雙引號未閉合")? {
+                        '"' => break,
+                        '\\' => match chars.next().ok_or("雙引號未閉合")? {
+                            escaped @ ('"' | '\\') => word.push(escaped),
+                            other => {
+                                word.push('\\');
+                                word.push(other);
+                            }
+                        },
+                        char => word.push(char),
+                    }
+                }
+            }
+            '\\' => {
+                let escaped = chars.next().ok_or("結尾不能是單獨的 \\")?;
+                current.get_or_insert_with(String::new).push(escaped);
+            }
+            '|' | '&' | ';' | '<' | '>' | '$' | '`' | '(' | ')' | '*' | '?' | '~' => {
+                return Err(format!("不經過 shell，不能使用未加引號的 {char}"));
+            }
+            char => current.get_or_insert_with(String::new).push(char),
+        }
+    }
+    argv.extend(current);
+    if argv.is_empty() {
+        return Err("命令是空的".to_string());
+    }
+    Ok(argv)
+}
+
+fn restart_command_resolvable(command: &str) -> bool {
+    let program = match command.split_whitespace().next() {
+        Some(value) => value,
+        None => return false,
+    };
+    let is_executable = |path: &Path| {
+        fs::metadata(path)
+            .map(|metadata| metadata.is_file() && metadata.mode() & 0o111 != 0)
+            .unwrap_or(false)
+    };
+    if program.contains('/') {
+        return is_executable(Path::new(program));
+    }
+    env::var("PATH")
+        .unwrap_or_default()
+        .split(':')
+        .any(|dir| is_executable(&Path::new(dir).join(program)))
+}
+
+// --self-test-restart：對一個臨時的假程序跑一次和重啟相同的結束與啟動步驟，逐步回報，
+// 讓使用者在交給 qq 之前確認這個環境真的能結束並重新帶起程序
+fn self_test_restart(config: &Config) -> bool {
+    let own = std::process::id();
+    // /tmp 常以 noexec 掛載，優先用使用者的執行期目錄
+    let base = env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir);
+    let dir = base.join(format!("qq-x11-guard-selftest-{own}"));
+    // 程序名稱（comm）取自執行檔檔名，最多 15 位元組
+    let name = format!("qqgst-{own}");
+    let script = dir.join(&name);
+    let prepared = fs::create_dir_all(&dir).and_then(|()| {
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o755)
+            .open(&script)?
+            .write_all(b"#!/bin/sh\nwhile :; do sleep 1; done\n")
+    });
+    let passed = match prepared {
+        Ok(()) => run_self_test(config, &name, &script.to_string_lossy()),
+        Err(error) => {
+            log_at(
+                config,
+                LogLevel::Error,
+                &format!("自我測試：無法建立測試程序 {}: {error}", script.display()),
+            );
+            false
+        }
+    };
+    let _ = fs::remove_dir_all(&dir);
+    if passed {
+        log(config, "自我測試通過：這個環境可以結束並重新啟動程序");
+    } else {
+        log_at(
+            config,
+            LogLevel::Error,
+            &format!(
+                "自我測試失敗：請先排除上述問題，再交給守護處理 {}",
+                config.app_name
+            ),
+        );
+    }
+    passed
+}
+
+fn run_self_test(config: &Config, name: &str, command: &str) -> bool {
+    let endpoint =
+        parse_display(&config.display).unwrap_or_else(|_| X11Endpoint::Unix(String::new()));
+    let names = vec![name.to_string()];
+    let verify = Duration::from_secs(config.verify_timeout_seconds);
+    let mut pidfds = PidfdTable::detect();
+    let report = |step: &str, ok: bool, detail: String| {
+        let (level, verdict) = if ok {
+            (LogLevel::Info, "通過")
+        } else {
+            (LogLevel::Error, "失敗")
+        };
+        log_at(
+            config,
+            level,
+            &format!("自我測試 {step}：{verdict}{detail}"),
+        );
+        ok
+    };
+
+    let old = match start_process(command, &[]) {
+        Ok(_) => wait_until_started(
+            &config.proc_root,
+            &names,
+            &[],
+            &endpoint,
+            &HashSet::new(),
+            verify,
+        ),
+        Err(error) => return report("1/4 啟動測試程序", false, format!("（{error}）")),
+    };
+    if !report(
+        "1/4 啟動測試程序並以名稱找到",
+        !old.is_empty(),
+        if old.is_empty() {
+            format!(
+                "（{} 秒內找不到 {name}；確認 {command} 所在目錄未以 noexec 掛載）",
+                verify.as_secs()
+            )
+        } else {
+            format!("（{name}，PID {old:?}）")
+        },
+    ) {
+        return false;
+    }
+    let previous: HashSet<(i32, u64)> = old
+        .iter()
+        .filter_map(|pid| process_start_time(&config.proc_root, *pid).map(|start| (*pid, start)))
+        .collect();
+
+    let failures = terminate_processes(&old, libc::SIGTERM, &mut pidfds);
+    let signalled = report(
+        "2/4 送出 SIGTERM",
+        failures.is_empty(),
+        failures
+            .iter()
+            .map(|(pid, error)| format!("（PID {pid}: {error}；{}）", permission_hint(config)))
+            .collect(),
+    );
+
+    let started = Instant::now();
+    let mut remaining = wait_until_gone(&config.proc_root, &old, &pidfds, TERM_GRACE);
+    let detail = if remaining.is_empty() {
+        format!("（{} ms）", started.elapsed().as_millis())
+    } else {
+        terminate_processes(&remaining, libc::SIGKILL, &mut pidfds);
+        remaining = wait_until_gone(&config.proc_root, &remaining, &pidfds, KILL_GRACE);
+        format!(
+            "（{} 秒內未因 SIGTERM 結束，改送 SIGKILL 後仍存活: {remaining:?}）",
+            TERM_GRACE.as_secs()
+        )
+    };
+    // 測試程序是本程序的子程序，回收後才不會以殭屍留在 /proc 被誤認為新一代
+    sandbox::reap_children();
+    let gone = report("3/4 在時限內結束", remaining.is_empty(), detail);
+
+    let new = match start_process(command, &[]) {
+        Ok(_) => wait_until_started(&config.proc_root, &names, &[], &endpoint, &previous, verify),
+        Err(error) => return report("4/4 重新啟動並確認", false, format!("（{error}）")),
+    };
+    let relaunched = report(
+        "4/4 重新啟動並確認",
+        !new.is_empty(),
+        format!("（新 PID {new:?}）"),
+    );
+    terminate_processes(&new, libc::SIGKILL, &mut pidfds);
+    wait_until_gone(&config.proc_root, &new, &pidfds, KILL_GRACE);
+    sandbox::reap_children();
+    signalled && gone && relaunched
+}
+
+// previous 是重啟前的程序（PID, 啟動時間）：關閉後仍存活的舊程序不能讓驗證通過，
+// 只算新的 PID，或被重用、啟動時間不同的 PID
+fn wait_until_started(
+    proc_root: &ProcRoot,
+    process_names: &[String],
+    cmdline: &[CmdlineMatch],
+    endpoint: &X11Endpoint,
+    previous: &HashSet<(i32, u64)>,
+    timeout: Duration,
+) -> Vec<i32> {
+    let deadline = Instant::now() + timeout;
+    let mut scanner = FdScanner::new(proc_root.clone());
+    loop {
+        let (mut pids, _) = find_app_pids(&mut scanner, process_names, cmdline, endpoint);
+        pids.retain(|pid| match process_start_time(proc_root, *pid) {
+            Some(start) => !previous.contains(&(*pid, start)),
+            // 讀不到啟動時間（剛結束）時無法分辨，與舊程序同 PID 就不算
+            None => !previous.iter().any(|(old, _)| old == pid),
+        });
+        if !pids.is_empty() || Instant::now() >= deadline {
+            return pids;
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RestartOutcome {
+    DryRun,
+    // 柔性重連後已回到門檻內，未重啟
+    Reconnected {
+        before: usize,
+        after: usize,
+    },
+    // 送出重新載入訊號後已回到門檻內，未重啟
+    Reloaded {
+        before: usize,
+        after: usize,
+    },
+    Restarted {
+        old_pids: Vec<i32>,
+        new_pids: Vec<i32>,
+    },
+    Failed(String),
+}
+
+// ===== 區塊 5：警示升級與通知 =====
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EscalationCondition {
+    PercentOfThreshold(u32),
+    ConsecutiveBreaches(u32),
+    BreakerOpen,
+    // 連線變動率持續達 --churn-threshold
+    Churn,
+    // 依成長趨勢預估在 --forecast-warn 內超過門檻
+    Forecast,
+    // --flap-crossings 判定連線數反覆跨越門檻
+    Flapping,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Urgency {
+    Low,
+    Normal,
+    Critical,
+}
+
+impl Urgency {
+    fn as_str(self) -> &'static str {
+        match self {
+            Urgency::Low => "low",
+            Urgency::Normal => "normal",
+            Urgency::Critical => "critical",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Notifier {
+    Desktop,
+    Command,
+}
+
+impl Notifier {
+    fn name(self) -> &'static str {
+        match self {
+            Notifier::Desktop => "desktop",
+            Notifier::Command => "cmd",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct EscalationLevel {
+    name: String,
+    condition: EscalationCondition,
+    log_level: LogLevel,
+    notifiers: Vec<Notifier>,
+    urgency: Urgency,
+}
+
+fn parse_escalation_level(spec: &str) -> Result<EscalationLevel, String> {
+    let mut parts = spec.splitn(3, ':');
+    let name = parts.next().unwrap_or("").trim();
+    if name.is_empty() {
+        return Err(format!("--escalation 缺少等級名稱: {spec}"));
+    }
+    let condition_text = parts
+        .next()
+        .ok_or_else(|| format!("--escalation 缺少條件: {spec}"))?;
+    let condition = match condition_text.split_once('=') {
+        Some(("percent", value)) => {
+            let percent = value
+                .parse::<u32>()
+                .map_err(|_| format!("--escalation percent 必須是正整數: {spec}"))?;
+            if percent == 0 {
+                return Err(format!("--escalation percent 必須 >= 1: {spec}"));
+            }
+            EscalationCondition::PercentOfThreshold(percent)
+        }
+        Some(("consecutive", value)) => {
+            let count = value
+                .parse::<u32>()
+                .map_err(|_| format!("--escalation consecutive 必須是正整數: {spec}"))?;
+            if count == 0 {
+                return Err(format!("--escalation consecutive 必須 >= 1: {spec}"));
+            }
+            EscalationCondition::ConsecutiveBreaches(count)
+        }
+        None if condition_text == "breaker-open" => EscalationCondition::BreakerOpen,
+        None if condition_text == "churn" => EscalationCondition::Churn,
+        None if condition_text == "forecast" => EscalationCondition::Forecast,
+        None if condition_text == "flapping" => EscalationCondition::Flapping,
+        _ => {
+            return Err(format!(
+                "--escalation 不支援的條件 {condition_text}: {spec}"
+            ))
+        }
+    };
+
+    let mut level = EscalationLevel {
+        name: name.to_string(),
+        condition,
+        log_level: LogLevel::Warn,
+        notifiers: Vec::new(),
+        urgency: Urgency::Normal,
+    };
+    let actions = parts.next().unwrap_or("");
+    for action in actions.split(',').filter(|item| !item.is_empty()) {
+        match action.split_once('=') {
+            Some(("log", "info")) => level.log_level = LogLevel::Info,
+            Some(("log", "warn")) => level.log_level = LogLevel::Warn,
+            Some(("log", "error")) => level.log_level = LogLevel::Error,
+            Some(("urgency", "low")) => level.urgency = Urgency::Low,
+            Some(("urgency", "normal")) => level.urgency = Urgency::Normal,
+            Some(("urgency", "critical")) => level.urgency = Urgency::Critical,
+            Some(("notify", value)) => {
+                level.notifiers =
+                    parse_notifiers(value).map_err(|err| format!("--escalation {err}: {spec}"))?;
+            }
+            _ => return Err(format!("--escalation 不支援的動作 {action}: {spec}")),
+        }
+    }
+    Ok(level)
+}
+
+fn parse_notifiers(value: &str) -> Result<Vec<Notifier>, String> {
+    let mut notifiers = Vec::new();
+    for name in value.split('+') {
+        let notifier = match name {
+            "desktop" => Notifier::Desktop,
+            "cmd" => Notifier::Command,
+            _ => return Err(format!("不支援的通知方式 {name}")),
+        };
+        if !notifiers.contains(&notifier) {
+            notifiers.push(notifier);
+        }
+    }
+    Ok(notifiers)
+}
+
+// --notify-digest 的值：<時間> 設定所有通知方式，<list>=<時間> 只設定列出的方式
+fn parse_notify_digest(value: &str) -> Result<(Vec<Notifier>, u64), String> {
+    let (notifiers, duration) = match value.split_once('=') {
+        Some((list, duration)) => (parse_notifiers(list)?, duration),
+        None => (Vec::new(), value),
+    };
+    Ok((notifiers, parse_duration_secs(duration)?))
+}
+
+struct Notification<'a> {
+    level: &'a str,
+    incident: &'a str,
+    urgency: Urgency,
+    message: &'a str,
+    core_dump: Option<&'a Path>,
+}
+
+// notify=cmd 與 --on-failure-cmd 共用的環境變數
+fn guard_env(spec: CommandSpec, config: &Config, notification: &Notification) -> CommandSpec {
+    spec.env("GUARD_APP", &config.app_name)
+        .env("GUARD_LEVEL", notification.level)
+        .env("GUARD_INCIDENT", notification.incident)
+        .env("GUARD_URGENCY", notification.urgency.as_str())
+        .env("GUARD_MESSAGE", notification.message)
+        .env(
+            "GUARD_CORE_DUMP",
+            &notification
+                .core_dump
+                .map(|path| path.display().to_string())
+                .unwrap_or_default(),
+        )
+}
+
+// 命令佔位符（見 template.rs）的值；{count} 尚未量測時與 {pids} 沒有程序時為空字串
+fn placeholder_values(
+    config: &Config,
+    endpoint: &X11Endpoint,
+    count: Option<usize>,
+    pids: &[i32],
+    incident: &str,
+    reason: &str,
+) -> Vec<(&'static str, String)> {
+    let socket = match endpoint {
+        X11Endpoint::Unix(path) => path.clone(),
+        X11Endpoint::Tcp(port) => format!("tcp:{port}"),
+    };
+    vec![
+        ("display", config.display.clone()),
+        ("socket", socket),
+        (
+            "count",
+            count.map(|count| count.to_string()).unwrap_or_default(),
+        ),
+        ("threshold", config.threshold.to_string()),
+        (
+            "pids",
+            pids.iter()
+                .map(i32::to_string)
+                .collect::<Vec<_>>()
+                .join(" "),
+        ),
+        ("app", config.app_name.clone()),
+        ("incident", incident.to_string()),
+        ("reason", reason.to_string()),
+    ]
+}
+
+fn sorted_pids(generation: &HashSet<(i32, u64)>) -> Vec<i32> {
+    let mut pids: Vec<i32> = generation.iter().map(|(pid, _)| *pid).collect();
+    pids.sort_unstable();
+    pids
+}
+
+fn send_notification(
+    config: &Config,
+    notifier: Notifier,
+    notification: &Notification,
+    values: &[(&str, String)],
+) {
+    let spec = match notifier {
+        Notifier::Desktop => CommandSpec::new("notify-send")
+            .arg("-u")
+            .arg(notification.urgency.as_str())
+            .arg(&format!("{} X11 guard", config.app_name))
+            .arg(&match notification.core_dump {
+                Some(path) => format!("{}\ncore dump: {}", notification.message, path.display()),
+                None => notification.message.to_string(),
+            }),
+        Notifier::Command => {
+            let command = match &config.notify_cmd {
+                Some(value) => value,
+                None => return,
+            };
+            guard_env(
+                template::fill(user_command(command, false), values),
+                config,
+                notification,
+            )
+        }
+    };
+    if let Err(err) = sandbox::spawn_detached(&spec) {
+        log_at(
+            config,
+            LogLevel::Warn,
+            &format!("通知送出失敗（{notifier:?}）: {err}"),
+        );
+    }
+}
+
+// --notify-digest 一次最多列出的事件數；之後的通知只計入則數
+const DIGEST_MAX_INCIDENTS: usize = 50;
+
+struct DigestIncident {
+    incident: String,
+    // 等級（規則）名稱與次數，依首次出現的順序
+    levels: Vec<(String, u64)>,
+    last_message: String,
+}
+
+// 單一通知方式的摘要緩衝：第一則非緊急通知進來時開始計時，時間窗到期或結束監控時合併成一則送出
+struct NotifyDigest {
+    notifier: Notifier,
+    window: Duration,
+    since: Option<Instant>,
+    incidents: Vec<DigestIncident>,
+    pending: u64,
+    // 超過事件上限、沒有列出的則數
+    unlisted: u64,
+    urgency: Urgency,
+    // 時間窗開始時的動作統計，送出時以差值列出期間採取的動作；延後的次數依原因累計
+    outcomes: BTreeMap<&'static str, u64>,
+    deferred: BTreeMap<&'static str, u64>,
+}
+
+impl NotifyDigest {
+    fn new(notifier: Notifier, window: Duration) -> Self {
+        Self {
+            notifier,
+            window,
+            since: None,
+            incidents: Vec::new(),
+            pending: 0,
+            unlisted: 0,
+            urgency: Urgency::Low,
+            outcomes: BTreeMap::new(),
+            deferred: BTreeMap::new(),
+        }
+    }
+
+    fn due(&self) -> Option<Instant> {
+        self.since.map(|since| since + self.window)
+    }
+
+    fn push(&mut self, notification: &Notification, outcomes: &BTreeMap<&'static str, u64>) {
+        if self.since.is_none() {
+            self.since = Some(Instant::now());
+            self.outcomes = outcomes.clone();
+        }
+        self.pending += 1;
+        self.urgency = self.urgency.max(notification.urgency);
+        let index = match self
+            .incidents
+            .iter()
+            .position(|entry| entry.incident == notification.incident)
+        {
+            Some(index) => index,
+            None if self.incidents.len() < DIGEST_MAX_INCIDENTS => {
+                self.incidents.push(DigestIncident {
+                    incident: notification.incident.to_string(),
+                    levels: Vec::new(),
+                    last_message: String::new(),
+                });
+                self.incidents.len() - 1
+            }
+            None => {
+                self.unlisted += 1;
+                return;
+            }
+        };
+        let entry = &mut self.incidents[index];
+        match entry
+            .levels
+            .iter_mut()
+            .find(|(level, _)| level == notification.level)
+        {
+            Some((_, count)) => *count += 1,
+            None => entry.levels.push((notification.level.to_string(), 1)),
+        }
+        entry.last_message = notification.message.to_string();
+    }
+
+    // 只在時間窗開啟時累計，延後本身不會開始一份摘要
+    fn note_deferred(&mut self, reason: &'static str) {
+        if self.since.is_some() {
+            *self.deferred.entry(reason).or_default() += 1;
+        }
+    }
+
+    // 取出摘要內容（事件清單、訊息）並清空緩衝
+    fn take(
+        &mut self,
+        app_name: &str,
+        outcomes: &BTreeMap<&'static str, u64>,
+    ) -> Option<(String, Urgency, String)> {
+        let since = self.since.take()?;
+        let mut lines = vec![format!(
+            "{app_name} 通知摘要：{} 秒內 {} 則通知，{} 個事件",
+            since.elapsed().as_secs(),
+            self.pending,
+            self.incidents.len()
+        )];
+        for entry in &self.incidents {
+            let levels: Vec<String> = entry
+                .levels
+                .iter()
+                .map(|(level, count)| format!("{level} ×{count}"))
+                .collect();
+            lines.push(format!("{}：{}", levels.join("、"), entry.last_message));
+        }
+        if self.unlisted > 0 {
+            lines.push(format!(
+                "另有 {} 則超過 {DIGEST_MAX_INCIDENTS} 個事件的上限，未列出",
+                self.unlisted
+            ));
+        }
+        let actions: Vec<String> = outcomes
+            .iter()
+            .filter_map(|(label, count)| {
+                let before = self.outcomes.get(label).copied().unwrap_or(0);
+                (*count > before).then(|| format!("{label} {}", count - before))
+            })
+            .collect();
+        lines.push(format!(
+            "期間動作：{}",
+            if actions.is_empty() {
+                "無".to_string()
+            } else {
+                actions.join("、")
+            }
+        ));
+        if !self.deferred.is_empty() {
+            let deferred: Vec<String> = self
+                .deferred
+                .iter()
+                .map(|(reason, count)| format!("{reason} {count}"))
+                .collect();
+            lines.push(format!("期間延後：{}", deferred.join("、")));
+        }
+        let incidents: Vec<&str> = self
+            .incidents
+            .iter()
+            .map(|entry| entry.incident.as_str())
+            .collect();
+        let incidents = incidents.join(",");
+        self.incidents.clear();
+        self.deferred.clear();
+        self.pending = 0;
+        self.unlisted = 0;
+        let urgency = mem::replace(&mut self.urgency, Urgency::Low);
+        Some((incidents, urgency, lines.join("\n")))
+    }
+
+    fn status_text(&self) -> String {
+        let state = match self.due() {
+            Some(due) => format!(
+                "待送 {} 則（{} 個事件），約 {} 秒後送出",
+                self.pending,
+                self.incidents.len(),
+                due.saturating_duration_since(Instant::now()).as_secs()
+            ),
+            None => "無待送".to_string(),
+        };
+        format!(
+            "{} 每 {} 秒，{state}",
+            self.notifier.name(),
+            self.window.as_secs()
+        )
+    }
+}
+
+// 通知與重啟命令皆以非同步方式 spawn，於主迴圈回收子程序避免殭屍累積
+// SIGTERM/SIGINT 只記下訊號，由主迴圈在下一輪輸出摘要後正常結束；進行中的重啟是主迴圈同步執行的，
+// 會先跑完才看到訊號
+static SHUTDOWN_SIGNAL: AtomicI32 = AtomicI32::new(0);
+// SIGHUP 要求重新讀取設定，由主迴圈在下一輪處理
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+// 自我管線：讀取端加進主迴圈等待的 fd，收到訊號時寫入一個位元組。只靠 EINTR 時，訊號若落在
+// 檢查 SHUTDOWN_SIGNAL 之後、進入 poll 之前，要等到這次逾時才會結束。-1 表示尚未建立或建立失敗
+static SHUTDOWN_PIPE: [AtomicI32; 2] = [AtomicI32::new(-1), AtomicI32::new(-1)];
+
+// 只用 async-signal-safe 的操作
+fn wake_main_loop() {
+    let fd = SHUTDOWN_PIPE[1].load(Ordering::Relaxed);
+    if fd >= 0 {
+        unsafe {
+            libc::write(fd, [1u8].as_ptr().cast(), 1);
+        }
+    }
+}
+
+// 訊號處理程式與程式內部（分別監控多個程式時其中一個結束）共用。
+// 結束時管線不讀出：不會再回到等待，一直可讀正好讓每個執行緒的等待都立即返回
+fn request_shutdown(signal: libc::c_int) {
+    SHUTDOWN_SIGNAL.store(signal, Ordering::Relaxed);
+    wake_main_loop();
+}
+
+extern "C" fn on_shutdown_signal(signal: libc::c_int) {
+    request_shutdown(signal);
+}
+
+extern "C" fn on_reload_signal(_: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::Relaxed);
+    wake_main_loop();
+}
+
+// 只喚醒了重新載入時讀光管線，否則下一次等待會立刻返回
+fn drain_shutdown_pipe() {
+    let fd = SHUTDOWN_PIPE[0].load(Ordering::Relaxed);
+    if fd < 0 || SHUTDOWN_SIGNAL.load(Ordering::Relaxed) != 0 {
+        return;
+    }
+    let mut buffer = [0u8; 64];
+    while unsafe { libc::read(fd, buffer.as_mut_ptr().cast(), buffer.len()) } > 0 {}
+}
+
+// seccomp 沒有放行 pipe2，須在進入沙箱前建立；只建立一次，之後的呼叫直接返回。
+// 建立失敗時仍有 EINTR，只是少了上述的保證
+fn open_shutdown_pipe() {
+    static OPENED: std::sync::Once = std::sync::Once::new();
+    OPENED.call_once(|| {
+        let mut fds = [-1; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } == 0 {
+            SHUTDOWN_PIPE[0].store(fds[0], Ordering::Relaxed);
+            SHUTDOWN_PIPE[1].store(fds[1], Ordering::Relaxed);
+        }
+    });
+}
+
+// 不設 SA_RESTART，讓阻塞中的 poll 以 EINTR 返回
+fn install_shutdown_handler() {
+    open_shutdown_pipe();
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction =
+            on_shutdown_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        for signal in [libc::SIGTERM, libc::SIGINT] {
+            libc::sigaction(signal, &action, ptr::null_mut());
+        }
+        // 以 nohup 啟動（SIGHUP 已忽略）時維持忽略，關閉終端機不會觸發重新載入
+        let mut previous: libc::sigaction = mem::zeroed();
+        libc::sigaction(libc::SIGHUP, ptr::null(), &mut previous);
+        if previous.sa_sigaction != libc::SIG_IGN {
+            action.sa_sigaction =
+                on_reload_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            libc::sigaction(libc::SIGHUP, &action, ptr::null_mut());
+        }
+    }
+}
+
+fn block_shutdown_signals() {
+    unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGHUP);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+    }
+}
+
+// ===== 區塊 6：主事件迴圈 =====
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PeriodicTask {
+    SyncWatches,
+    FallbackPoll,
+    Summary,
+    XProbe,
+}
+
+impl PeriodicTask {
+    fn name(self) -> &'static str {
+        match self {
+            PeriodicTask::SyncWatches => "sync",
+            PeriodicTask::FallbackPoll => "fallback",
+            PeriodicTask::Summary => "summary",
+            PeriodicTask::XProbe => "x-probe",
+        }
+    }
+
+    // --max-cpu 放慢時拉長週期的任務；摘要與 X server 探測維持原本的間隔
+    fn polls(self) -> bool {
+        matches!(self, PeriodicTask::SyncWatches | PeriodicTask::FallbackPoll)
+    }
+}
+
+// 具名的週期任務：下一次觸發由上一次的預定時間推算，處理耗時不會累積成漂移；
+// 落後超過一個週期（例如重啟流程阻塞）時不補跑，直接從現在起算
+struct TimerWheel {
+    tasks: Vec<(PeriodicTask, Duration, Instant)>,
+    // --max-cpu 放慢的倍數，只套用在輪詢的任務上
+    factor: u32,
+}
+
+impl TimerWheel {
+    fn new() -> Self {
+        Self {
+            tasks: Vec::new(),
+            factor: 1,
+        }
+    }
+
+    fn scaled(factor: u32, task: PeriodicTask, period: Duration) -> Duration {
+        if task.polls() {
+            period * factor
+        } else {
+            period
+        }
+    }
+
+    // 倍數改變時輪詢的任務從現在起以新的週期重新排定
+    fn set_factor(&mut self, factor: u32, now: Instant) {
+        self.factor = factor;
+        for (task, period, next) in &mut self.tasks {
+            if task.polls() {
+                *next = now + *period * factor;
+            }
+        }
+    }
+
+    fn add(&mut self, task: PeriodicTask, period: Duration, now: Instant) {
+        self.tasks.push((task, period, now + period));
+    }
+
+    fn next_deadline(&self) -> Option<(PeriodicTask, Instant)> {
+        self.tasks
+            .iter()
+            .map(|(task, _, next)| (*task, *next))
+            .min_by_key(|(_, next)| *next)
+    }
+
+    // 回傳已到期的任務（依加入順序）並排好下一次
+    fn due(&mut self, now: Instant) -> Vec<PeriodicTask> {
+        let mut fired = Vec::new();
+        for (task, period, next) in &mut self.tasks {
+            if now < *next {
+                continue;
+            }
+            fired.push(*task);
+            let period = Self::scaled(self.factor, *task, *period);
+            *next += period;
+            if *next <= now {
+                *next = now + period;
+            }
+        }
+        fired
+    }
+}
+
+// -vvv：主迴圈每一輪怎麼花掉時間，診斷漏掉事件或 CPU 偏高時使用。
+// 未開啟時不建立，各階段也不取時間
+struct LoopTrace {
+    iteration: u64,
+    started: Instant,
+    timeout: Duration,
+    // 決定這次等待上限的期限：計時器任務名稱或 pause、confirm、plateau、digest
+    deadline: &'static str,
+    waited: Duration,
+    // 喚醒時的 inotify 事件（涉及的 PID 數）、結束的程序數與是否有控制命令
+    events: usize,
+    exited: usize,
+    control: bool,
+    phases: Vec<(&'static str, Duration)>,
+    fired: Vec<&'static str>,
+    // 這一輪開始時的量測次數；有量測時另列最後一次量測（計數本身）的耗時
+    measurements: u64,
+    measured: Option<Duration>,
+}
+
+impl LoopTrace {
+    fn phase<T>(trace: &mut Option<LoopTrace>, name: &'static str, run: impl FnOnce() -> T) -> T {
+        let Some(trace) = trace else {
+            return run();
+        };
+        let started = Instant::now();
+        let result = run();
+        trace.phases.push((name, started.elapsed()));
+        result
+    }
+
+    fn text(&self) -> String {
+        let ms = |duration: Duration| format!("{:.1} ms", duration.as_secs_f64() * 1000.0);
+        let phases: Vec<String> = self
+            .phases
+            .iter()
+            .map(|(name, elapsed)| format!("{name} {}", ms(*elapsed)))
+            .collect();
+        let list = |items: Vec<String>| {
+            if items.is_empty() {
+                "無".to_string()
+            } else {
+                items.join("、")
+            }
+        };
+        format!(
+            "迴圈 #{}：等待 {}（上限 {}，取自 {}），喚醒：inotify {} 個 PID、結束 {} 個、控制命令 {}；處理：{}{}；到期的計時器：{}；本輪共 {}",
+            self.iteration,
+            ms(self.waited),
+            ms(self.timeout),
+            self.deadline,
+            self.events,
+            self.exited,
+            if self.control { "有" } else { "無" },
+            list(phases),
+            self.measured
+                .map(|elapsed| format!("（其中量測 {}）", ms(elapsed)))
+                .unwrap_or_default(),
+            list(self.fired.iter().map(|name| name.to_string()).collect()),
+            ms(self.started.elapsed())
+        )
+    }
+}
+
+// --wait-for-plateau：超過門檻後每次量測更新的等待狀態。連線數比上一次量測多就算上升，
+// 持平（不再上升）的時間從最後一次上升起算
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PlateauWait {
+    since: Instant,
+    steady_since: Instant,
+    last_count: usize,
+    peak: usize,
+    climbing: bool,
+    // 已持平夠久、等待結束；之後若因其他原因暫緩，再次超標時不用重新等
+    settled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PlateauChange {
+    Climbing { from: usize, to: usize },
+    Steady { peak: usize },
+}
+
+impl PlateauWait {
+    // 剛超過門檻時視為仍在上升
+    fn new(count: usize, now: Instant) -> Self {
+        Self {
+            since: now,
+            steady_since: now,
+            last_count: count,
+            peak: count,
+            climbing: true,
+            settled: false,
+        }
+    }
+
+    fn record(&mut self, count: usize, now: Instant) -> Option<PlateauChange> {
+        let from = self.last_count;
+        self.last_count = count;
+        self.peak = self.peak.max(count);
+        if count > from {
+            self.steady_since = now;
+            if !self.climbing {
+                self.climbing = true;
+                return Some(PlateauChange::Climbing { from, to: count });
+            }
+        } else if self.climbing {
+            self.climbing = false;
+            return Some(PlateauChange::Steady { peak: self.peak });
+        }
+        None
+    }
+
+    // 持平時間與最長等待中先到的那個時間點，主迴圈在此時醒來重新量測
+    fn next_check(&self, plateau: Duration, limit: Duration) -> Instant {
+        (self.steady_since + plateau).min(self.since + limit)
+    }
+}
+
+// 重啟失敗後的狀態，保留到程式重新出現或控制 socket 的 clear；期間不再執行失敗處理
+struct FailureState {
+    incident: String,
+    since: Instant,
+    reason: String,
+    // --on-failure-cmd 與 --on-failure-action 的執行結果，顯示在 status
+    handled: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ActionGate {
+    Cooldown,
+    Breaker,
+    // --policy-cmd 回覆 deny 或 defer 後暫不再詢問
+    Policy,
+    // --confirm-restart 被拒絕或逾時後暫不再詢問
+    Confirmation,
+}
+
+impl ActionGate {
+    fn label(self) -> &'static str {
+        match self {
+            ActionGate::Cooldown => "冷卻",
+            ActionGate::Breaker => "熔斷",
+            ActionGate::Policy => "策略延後",
+            ActionGate::Confirmation => "未獲確認",
+        }
+    }
+
+    // 狀態摘要與 metrics 標籤用的英文名稱
+    fn name(self) -> &'static str {
+        match self {
+            ActionGate::Cooldown => "cooldown",
+            ActionGate::Breaker => "breaker",
+            ActionGate::Policy => "policy",
+            ActionGate::Confirmation => "denied",
+        }
+    }
+}
+
+struct Guard {
+    config: Config,
+    x11_endpoint: X11Endpoint,
+    inotify: InotifyWatch,
+    last_restart: Option<Instant>,
+    excluded_x_server: HashSet<i32>,
+    restart_times: VecDeque<Instant>,
+    consecutive_breaches: u32,
+    // 這一段連續超標從何時開始；回到門檻內或採取動作時清除
+    over_threshold_since: Option<Instant>,
+    // 最近一次量測超過門檻的時間，回到門檻內後仍保留，用來看出反覆發生的洩漏；
+    // 另依程序名稱各記一份（--learn-new-name 或 --cmdline-match 會比對到不只一種名稱）
+    last_over_threshold: Option<SystemTime>,
+    last_over_by_name: BTreeMap<String, SystemTime>,
+    // 最近一次與門檻比較的連線數，命令佔位符的 {count}
+    last_gauge: Option<usize>,
+    // --threshold-scope per-process：最近一次量測中連線最多的程序與它的連線數
+    offender: Option<(i32, usize)>,
+    // 因系統負載開始延後動作的時間；同上清除
+    load_deferred_since: Option<Instant>,
+    // --defer-while-active：因使用中開始延後的時間（同上清除）與最近一次取樣
+    active_deferred_since: Option<Instant>,
+    last_activity: Option<(Instant, Activity)>,
+    // --only-when-idle：開始保留動作的時間（同上清除）與最近一次查詢的閒置時間
+    idle_hold_since: Option<Instant>,
+    last_idle: Option<(Instant, IdleReading)>,
+    // --wait-for-plateau 的等待狀態；同上清除
+    plateau: Option<PlateauWait>,
+    // --x-probe 的探測結果；判定無回應期間暫緩自動動作
+    x_health: xprobe::Health,
+    active_levels: Vec<String>,
+    incident: Option<String>,
+    incident_seq: u64,
+    fd_scanner: FdScanner,
+    quarantine: Option<String>,
+    failure: Option<FailureState>,
+    // 程式最近一次所在的登入 session（/proc/<pid>/sessionid），reboot-session 用
+    app_session: Option<u32>,
+    // --on-failure-action exit：主迴圈在下一輪以重啟失敗結束
+    exit_on_failure: Option<String>,
+    watch_denied: HashSet<i32>,
+    attribution_rejected: u64,
+    // 上一次量測被 --exclude-intra-app 排除的連線數，數量改變時才寫入日誌
+    intra_app_excluded: usize,
+    // --threshold-windowless：最近一次成功的視窗分類，查詢失敗時為 None 並記下原因，原因改變時才寫日誌
+    windows: Option<xres::WindowSplit>,
+    windows_error: Option<String>,
+    // 所有程序的 fd 都讀不到時改以 X-Resource 回報的 client PID 歸屬連線：未改用時為 None，
+    // 查詢失敗時記下原因，狀態改變時才寫日誌
+    xres_fallback: Option<Result<(), String>>,
+    backend: Box<dyn ConnectionCounter>,
+    backend_note: Option<String>,
+    pidfds: PidfdTable,
+    last_connections: Option<HashMap<String, i32>>,
+    // --min-connection-age：各連線 inode 第一次量測到的時間
+    connection_first_seen: HashMap<String, Instant>,
+    incident_added: Vec<(String, i32)>,
+    incident_removed: Vec<String>,
+    // 目前這一代程序的（PID, 啟動時間），用來辨識外部重啟
+    generation: HashSet<(i32, u64)>,
+    control: Option<control::ControlServer>,
+    // 主迴圈以它同時等待 inotify、pidfd 與控制 socket
+    mux: mux::Multiplexer<Source>,
+    pause: Option<Pause>,
+    // last_restart 記錄的是哪一種動作，決定套用哪一個冷卻時間
+    last_action: ActionKind,
+    // 這次動作是重新載入未解決後升級的完整重啟；保留到下一次動作開始，動作的結果寫進歷史記錄時還要用
+    reload_escalated: bool,
+    // 策略命令 deny 或 defer 後，到此時間前不再詢問也不動作
+    policy_hold: Option<Instant>,
+    // --confirm-restart：等待中的確認，以及拒絕或逾時後到此時間前不再詢問
+    confirmation: Option<PendingConfirmation>,
+    confirm_hold: Option<Instant>,
+    // --notify-digest 有設定時間窗的通知方式各一份
+    digests: Vec<NotifyDigest>,
+    // --flap-crossings 的跨越記錄與 flapping 狀態
+    flap: flap::Detector,
+    // --crash-loop：本程式帶起的程序與快速結束的記錄
+    crash: crashloop::Detector,
+    // --history-file 的寫入端；只在監控時開啟
+    history: Option<history::Store>,
+    // --max-cpu：自身 CPU 用量與目前放慢的倍數
+    cpu: throttle::Governor,
+    // 目前或上一次動作是手動要求（restart-now / restart 控制命令）時的原因
+    manual_reason: Option<String>,
+    // --threshold 在最前面，其後是 --rule 的組合規則
+    rules: Vec<rule::Rule>,
+    // 目前或上一次自動動作是由哪一條 --rule 觸發；超過 --threshold 時為 None
+    action_rule: Option<String>,
+    // 各規則上一次觸發的時間，套用規則自己的冷卻
+    rule_fired: HashMap<String, Instant>,
+    // 上一次確認的 X11 socket 狀態；None 表示尚未確認
+    x11_socket: Option<Result<X11SocketIdentity, String>>,
+    // 比對用的程序名稱：--app-name 加上 --learn-new-name 學到的名稱
+    app_names: Vec<String>,
+    // 啟動時程式還沒執行，等它第一次出現時記錄一次
+    waiting_for_app: bool,
+    // --restore-geometry 記下、還沒還原的視窗配置
+    layout: Vec<layout::Window>,
+    // 這次重啟前保存的 core，寫進擷取摘要與失敗通知
+    core_dump: Option<PathBuf>,
+    // 最近一次量測時 fd 無法讀取的 PID，以及已警告過的（PID, 啟動時間）
+    unreadable: Vec<i32>,
+    unreadable_warned: HashSet<(i32, u64)>,
+    stats: RunStats,
+    interval: IntervalSummary,
+    churn: ChurnMeter,
+    trend: TrendMeter,
+    exec: ExecTracker,
+    measure: MeasureTiming,
+    // explain 執行期間記錄計數的各階段；平常為 None
+    count_trace: Option<CountTrace>,
+    // 控制 socket 的 self-upgrade：回覆送出後才 exec
+    upgrade_requested: bool,
+}
+
+// 連線變動率：以相鄰兩次量測之間的 inode 差異累計，除以實際經過的時間，
+// 漏掉的檢查只會拉長區間而不會高估。兩次量測之間開了又關的連線看不到
+const CHURN_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ChurnRate {
+    opened_per_minute: u64,
+    closed_per_minute: u64,
+    span: Duration,
+}
+
+impl ChurnRate {
+    // 資料涵蓋完整的統計視窗才算持續
+    fn sustained(&self) -> bool {
+        self.span >= CHURN_WINDOW
+    }
+
+    fn text(&self) -> String {
+        format!(
+            "每分鐘開啟 {}、關閉 {}（近 {} 秒）",
+            self.opened_per_minute,
+            self.closed_per_minute,
+            self.span.as_secs()
+        )
+    }
+}
+
+#[derive(Default)]
+struct ChurnMeter {
+    last: Option<Instant>,
+    // （區間開始, 區間結束, 開啟數, 關閉數）
+    samples: VecDeque<(Instant, Instant, usize, usize)>,
+}
+
+impl ChurnMeter {
+    // 連線記錄重設（換代、socket 變更等）後從這次量測重新起算
+    fn reset(&mut self, now: Instant) {
+        self.last = Some(now);
+        self.samples.clear();
+    }
+
+    fn record(&mut self, now: Instant, opened: usize, closed: usize) {
+        let Some(start) = self.last.replace(now) else {
+            return;
+        };
+        self.samples.push_back((start, now, opened, closed));
+        while let Some((_, end, _, _)) = self.samples.front() {
+            if now.duration_since(*end) < CHURN_WINDOW {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    fn rate(&self) -> Option<ChurnRate> {
+        let (start, _, _, _) = self.samples.front()?;
+        let (_, end, _, _) = self.samples.back()?;
+        let span = end.duration_since(*start);
+        // 間隔太短時換算成每分鐘沒有意義
+        if span < Duration::from_secs(1) {
+            return None;
+        }
+        let (opened, closed) = self
+            .samples
+            .iter()
+            .fold((0, 0), |(opened, closed), (_, _, add, remove)| {
+                (opened + add, closed + remove)
+            });
+        let per_minute = |count: usize| (count as u128 * 60_000 / span.as_millis()) as u64;
+        Some(ChurnRate {
+            opened_per_minute: per_minute(opened),
+            closed_per_minute: per_minute(closed),
+            span,
+        })
+    }
+}
+
+// 連線數成長趨勢：近半小時的量測每 10 秒留一筆，以 Theil–Sen（所有樣本對斜率的中位數）
+// 估計每小時的增減，單次尖峰、短暫持平或柔性處理造成的下降都不會拉動估計
+const TREND_WINDOW: Duration = Duration::from_secs(30 * 60);
+const TREND_MIN_SPAN: Duration = Duration::from_secs(5 * 60);
+const TREND_SAMPLE_GAP: Duration = Duration::from_secs(10);
+const TREND_MIN_SAMPLES: usize = 6;
+// 每小時增加不到 1 條視為持平，不做預估
+const TREND_FLAT_PER_HOUR: f64 = 1.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Trend {
+    per_hour: f64,
+    // 以目前連線數與斜率推算超過門檻的剩餘時間；持平或下降時為 None
+    eta: Option<Duration>,
+}
+
+impl Trend {
+    fn text(&self) -> String {
+        match self.eta {
+            Some(eta) => format!(
+                "每小時 {:+.1} 條，約 {} 分鐘後超過門檻",
+                self.per_hour,
+                eta.as_secs().div_ceil(60)
+            ),
+            None => format!("每小時 {:+.1} 條，持平或下降，不預估", self.per_hour),
+        }
+    }
+}
+
+#[derive(Default)]
+struct TrendMeter {
+    samples: VecDeque<(Instant, usize)>,
+}
+
+impl TrendMeter {
+    // 程式換代或連線記錄重設後，上一代的趨勢不再適用
+    fn reset(&mut self) {
+        self.samples.clear();
+    }
+
+    fn record(&mut self, now: Instant, count: usize) {
+        if let Some((last, _)) = self.samples.back() {
+            if now.duration_since(*last) < TREND_SAMPLE_GAP {
+                return;
+            }
+        }
+        self.samples.push_back((now, count));
+        while let Some((time, _)) = self.samples.front() {
+            if now.duration_since(*time) <= TREND_WINDOW {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    fn slope_per_hour(&self) -> Option<f64> {
+        let (first, _) = self.samples.front()?;
+        let (last, _) = self.samples.back()?;
+        if self.samples.len() < TREND_MIN_SAMPLES || last.duration_since(*first) < TREND_MIN_SPAN {
+            return None;
+        }
+        let mut slopes = Vec::new();
+        for (index, (start, from)) in self.samples.iter().enumerate() {
+            for (end, to) in self.samples.iter().skip(index + 1) {
+                let hours = end.duration_since(*start).as_secs_f64() / 3600.0;
+                slopes.push((*to as f64 - *from as f64) / hours);
+            }
+        }
+        slopes.sort_unstable_by(f64::total_cmp);
+        let middle = slopes.len() / 2;
+        Some(if slopes.len() % 2 == 0 {
+            (slopes[middle - 1] + slopes[middle]) / 2.0
+        } else {
+            slopes[middle]
+        })
+    }
+
+    // 超標是連線數 > 門檻，所以要到門檻 + 1 條；已超標時剩餘時間為 0
+    fn trend(&self, current: usize, threshold: usize) -> Option<Trend> {
+        let per_hour = self.slope_per_hour()?;
+        let eta = (per_hour >= TREND_FLAT_PER_HOUR).then(|| {
+            let remaining = (threshold + 1).saturating_sub(current) as f64;
+            Duration::from_secs_f64(remaining / per_hour * 3600.0)
+        });
+        Some(Trend { per_hour, eta })
+    }
+}
+
+// 同一個程序（PID 與啟動時間不變）exec 成另一個執行檔：名稱或 /proc/<pid>/exe 改變
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ExecTransition {
+    pid: i32,
+    start_time: u64,
+    from: String,
+    to: String,
+    // exec 前是否在監控中、exec 後是否符合比對條件
+    was_watched: bool,
+    matches: bool,
+}
+
+// exec 不換 PID 也不改啟動時間，只能比對前後兩次掃描：上一次掃描的所有程序名稱，
+// 以及當時監控中程序的（啟動時間, 名稱, 執行檔）。執行檔只讀監控中的程序，掃描本來就會讀每個程序的名稱
+#[derive(Default)]
+struct ExecTracker {
+    names: HashMap<i32, String>,
+    watched: HashMap<i32, (u64, String, Option<PathBuf>)>,
+    // 上一次掃描時開機後經過的 clock tick；比它晚啟動的是重用 PID 的新程序，不是 exec
+    scanned_at: Option<u64>,
+}
+
+impl ExecTracker {
+    fn observe(
+        &mut self,
+        proc_root: &ProcRoot,
+        scan: Vec<(i32, String)>,
+        matched: &[i32],
+    ) -> Vec<ExecTransition> {
+        let names: HashMap<i32, String> = scan.into_iter().collect();
+        let mut transitions = Vec::new();
+        let mut watched = HashMap::new();
+        for pid in matched {
+            let Some(start_time) = process_start_time(proc_root, *pid) else {
+                continue;
+            };
+            let name = names.get(pid).cloned().unwrap_or_default();
+            let exe = process_exe(proc_root, *pid);
+            let previous = match self.watched.get(pid) {
+                Some((start, old_name, old_exe)) if *start == start_time => {
+                    exec_change(old_name, old_exe.as_deref(), &name, exe.as_deref())
+                }
+                Some(_) => None,
+                None => self
+                    .names
+                    .get(pid)
+                    .filter(|old_name| **old_name != name)
+                    .filter(|_| self.scanned_at.is_none_or(|ticks| start_time <= ticks))
+                    .map(|old_name| (old_name.clone(), name.clone())),
+            };
+            if let Some((from, to)) = previous {
+                transitions.push(ExecTransition {
+                    pid: *pid,
+                    start_time,
+                    from,
+                    to,
+                    was_watched: self.watched.contains_key(pid),
+                    matches: true,
+                });
+            }
+            watched.insert(*pid, (start_time, name, exe));
+        }
+        for (pid, (start, old_name, old_exe)) in &self.watched {
+            // 已結束的程序不在掃描結果中
+            let Some(name) = names.get(pid).filter(|_| !watched.contains_key(pid)) else {
+                continue;
+            };
+            if process_start_time(proc_root, *pid) != Some(*start) {
+                continue;
+            }
+            let exe = process_exe(proc_root, *pid);
+            if let Some((from, to)) =
+                exec_change(old_name, old_exe.as_deref(), name, exe.as_deref())
+            {
+                transitions.push(ExecTransition {
+                    pid: *pid,
+                    start_time: *start,
+                    from,
+                    to,
+                    was_watched: true,
+                    matches: false,
+                });
+            }
+        }
+        self.names = names;
+        self.watched = watched;
+        self.scanned_at = boot_ticks(proc_root);
+        transitions.sort_by_key(|transition| transition.pid);
+        transitions
+    }
+}
+
+// 名稱不同時以名稱描述；名稱相同（例如同名的新版本）才比對執行檔路徑，兩邊都讀得到才算
+fn exec_change(
+    old_name: &str,
+    old_exe: Option<&Path>,
+    name: &str,
+    exe: Option<&Path>,
+) -> Option<(String, String)> {
+    if old_name != name {
+        return Some((old_name.to_string(), name.to_string()));
+    }
+    let (old_exe, exe) = (old_exe?, exe?);
+    (old_exe != exe).then(|| (old_exe.display().to_string(), exe.display().to_string()))
+}
+
+// 執行檔被更新或刪除後連結會加上「 (deleted)」，去掉後才不會誤判成 exec
+fn process_exe(proc_root: &ProcRoot, pid: i32) -> Option<PathBuf> {
+    let link = fs::read_link(proc_root.pid_entry(pid, "exe")).ok()?;
+    let text = link.to_string_lossy();
+    Some(match text.strip_suffix(" (deleted)") {
+        Some(path) => PathBuf::from(path),
+        None => link,
+    })
+}
+
+// 本程序累計的 CPU 時間（utime + stime）；不經 --proc-root，那裡可能是主機的 proc
+fn self_cpu_time() -> Option<Duration> {
+    let stat = parse_proc_stat(&fs::read("/proc/self/stat").ok()?)?;
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    (ticks > 0).then(|| Duration::from_secs_f64(stat.cpu_ticks as f64 / ticks as f64))
+}
+
+// /proc/uptime 的第一欄是開機後的秒數，換算成與 stat 啟動時間相同的 clock tick
+fn boot_ticks(proc_root: &ProcRoot) -> Option<u64> {
+    let body = fs::read_to_string(proc_root.join("uptime")).ok()?;
+    let seconds: f64 = body.split_whitespace().next()?.parse().ok()?;
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    (ticks > 0).then_some((seconds * ticks as f64) as u64)
+}
+
+// --summary-interval：不論是否超標都定期輸出一行 key=value 摘要，供以日誌判斷守護是否存活。
+// 只統計完成量測的檢查；讀不到 fd 的那幾次不計入
+// 每次量測（取得 peer 集合並讀取 fd）的耗時：ss 或 /proc 在負載下變慢時量測會落後，偵測跟著變鈍。
+// 直方圖的上界（秒），與 Prometheus 慣例相同為累計計數，最後另有 +Inf
+const MEASURE_BUCKETS: [f64; 11] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+// 持續緩慢時最多每分鐘警告一次，並附上期間內略過的次數
+const SLOW_MEASURE_WARN_GAP: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct MeasureTiming {
+    last: Option<Duration>,
+    // 落在各區間（非累計）的次數，最後一格為超過最大上界
+    buckets: [u64; MEASURE_BUCKETS.len() + 1],
+    sum: Duration,
+    slow_warned: Option<Instant>,
+    slow_suppressed: u64,
+}
+
+impl MeasureTiming {
+    fn record(&mut self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let index = MEASURE_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(MEASURE_BUCKETS.len());
+        self.buckets[index] += 1;
+        self.sum += elapsed;
+        self.last = Some(elapsed);
+    }
+
+    // 需要警告時回傳上次警告後略過的次數
+    fn note_slow(&mut self, now: Instant) -> Option<u64> {
+        if self
+            .slow_warned
+            .is_some_and(|last| now.saturating_duration_since(last) < SLOW_MEASURE_WARN_GAP)
+        {
+            self.slow_suppressed += 1;
+            return None;
+        }
+        self.slow_warned = Some(now);
+        Some(mem::take(&mut self.slow_suppressed))
+    }
+
+    fn histogram(&self, name: &str, help: &str, labels: &str) -> String {
+        let mut lines = vec![
+            format!("# HELP {name} {help}"),
+            format!("# TYPE {name} histogram"),
+        ];
+        let mut cumulative = 0;
+        for (bound, count) in MEASURE_BUCKETS.iter().zip(self.buckets) {
+            cumulative += count;
+            lines.push(format!(
+                "{name}_bucket{{{labels},le=\"{bound}\"}} {cumulative}"
+            ));
+        }
+        let total: u64 = self.buckets.iter().sum();
+        lines.push(format!("{name}_bucket{{{labels},le=\"+Inf\"}} {total}"));
+        lines.push(format!("{name}_sum{{{labels}}} {}", self.sum.as_secs_f64()));
+        lines.push(format!("{name}_count{{{labels}}} {total}"));
+        lines.join("\n")
+    }
+}
+
+const CHECK_TRIGGERS: [&str; 5] = ["startup", "event", "exit", "fallback", "plateau"];
+
+struct IntervalSummary {
+    started: Instant,
+    checks: [u64; CHECK_TRIGGERS.len()],
+    min: Option<usize>,
+    max: usize,
+    total: u64,
+    // 區間開始時的累計警告與錯誤數
+    warnings: u64,
+    errors: u64,
+}
+
+impl IntervalSummary {
+    fn new(now: Instant) -> Self {
+        Self {
+            started: now,
+            checks: [0; CHECK_TRIGGERS.len()],
+            min: None,
+            max: 0,
+            total: 0,
+            warnings: LOGGED_WARNINGS.load(Ordering::Relaxed),
+            errors: LOGGED_ERRORS.load(Ordering::Relaxed),
+        }
+    }
+
+    fn record(&mut self, trigger: &str, count: usize) {
+        if let Some(index) = CHECK_TRIGGERS.iter().position(|name| *name == trigger) {
+            self.checks[index] += 1;
+        }
+        self.min = Some(self.min.map_or(count, |min| min.min(count)));
+        self.max = self.max.max(count);
+        self.total += count as u64;
+    }
+
+    fn line(&self, now: Instant, state: &str, current: Option<usize>, levels: &[String]) -> String {
+        let measured: u64 = self.checks.iter().sum();
+        let mut fields = vec![
+            format!(
+                "period={}s",
+                now.saturating_duration_since(self.started).as_secs()
+            ),
+            format!("checks={measured}"),
+        ];
+        fields.extend(
+            CHECK_TRIGGERS
+                .iter()
+                .zip(self.checks)
+                .map(|(name, count)| format!("{name}={count}")),
+        );
+        match self.min {
+            Some(min) => fields.extend([
+                format!("min={min}"),
+                format!("max={}", self.max),
+                format!("mean={:.1}", self.total as f64 / measured as f64),
+            ]),
+            None => fields.extend(["min=-", "max=-", "mean=-"].map(str::to_string)),
+        }
+        fields.extend([
+            format!(
+                "warnings={}",
+                LOGGED_WARNINGS.load(Ordering::Relaxed) - self.warnings
+            ),
+            format!(
+                "errors={}",
+                LOGGED_ERRORS.load(Ordering::Relaxed) - self.errors
+            ),
+            format!("state={state}"),
+            format!(
+                "connections={}",
+                current.map_or("-".to_string(), |count| count.to_string())
+            ),
+            format!(
+                "levels={}",
+                if levels.is_empty() {
+                    "-".to_string()
+                } else {
+                    levels.join(",")
+                }
+            ),
+        ]);
+        format!("週期摘要 {}", fields.join(" "))
+    }
+}
+
+// 整個執行期間的統計，結束時輸出摘要
+struct RunStats {
+    started: Instant,
+    measurements: u64,
+    max_connections: usize,
+    outcomes: BTreeMap<&'static str, u64>,
+    // 已結束的冷卻時間總和，以及進行中的冷卻（開始, 預定結束）
+    cooldown: Duration,
+    cooldown_window: Option<(Instant, Instant)>,
+    paused: Duration,
+    // 群組重啟中停止或啟動失敗的次數，依成員名稱
+    member_failures: BTreeMap<String, u64>,
+    // --rule 各規則觸發的次數
+    rule_fired: BTreeMap<String, u64>,
+    // 進入 flapping 狀態的次數
+    flapping: u64,
+    // 判定為崩潰循環的次數
+    crash_loops: u64,
+    // --only-when-idle 保留期間就回到門檻內、不必動作的次數
+    self_recovered: u64,
+}
+
+impl RunStats {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            measurements: 0,
+            max_connections: 0,
+            outcomes: BTreeMap::new(),
+            cooldown: Duration::ZERO,
+            cooldown_window: None,
+            paused: Duration::ZERO,
+            member_failures: BTreeMap::new(),
+            rule_fired: BTreeMap::new(),
+            flapping: 0,
+            crash_loops: 0,
+            self_recovered: 0,
+        }
+    }
+
+    fn record_outcome(&mut self, outcome: &RestartOutcome, manual: bool, cooldown: Duration) {
+        let label = match (outcome, manual) {
+            (RestartOutcome::DryRun, _) => "dry-run",
+            (RestartOutcome::Reconnected { .. }, _) => "柔性重連",
+            (RestartOutcome::Reloaded { .. }, _) => "重新載入",
+            (RestartOutcome::Restarted { .. }, false) => "重啟",
+            (RestartOutcome::Restarted { .. }, true) => "手動重啟",
+            (RestartOutcome::Failed(_), false) => "失敗",
+            (RestartOutcome::Failed(_), true) => "手動重啟失敗",
+        };
+        *self.outcomes.entry(label).or_default() += 1;
+        let now = Instant::now();
+        self.cooldown = self.cooldown_until(now);
+        self.cooldown_window = Some((now, now + cooldown));
+    }
+
+    // 冷卻時間算到 now 為止；新的動作會截斷前一段冷卻
+    fn cooldown_until(&self, now: Instant) -> Duration {
+        match self.cooldown_window {
+            Some((start, end)) => self.cooldown + end.min(now).saturating_duration_since(start),
+            None => self.cooldown,
+        }
+    }
+
+    fn summary(&self, current_pause: Option<&Pause>) -> String {
+        let now = Instant::now();
+        let outcomes = if self.outcomes.is_empty() {
+            "無".to_string()
+        } else {
+            self.outcomes
+                .iter()
+                .map(|(label, count)| format!("{label} {count}"))
+                .collect::<Vec<_>>()
+                .join("、")
+        };
+        let paused =
+            self.paused + current_pause.map_or(Duration::ZERO, |pause| pause.since.elapsed());
+        let mut summary = format!(
+            "摘要：執行 {} 秒，量測 {} 次，最高 X11 連線 {} 條，動作：{outcomes}，冷卻 {} 秒，暫停 {} 秒",
+            now.duration_since(self.started).as_secs(),
+            self.measurements,
+            self.max_connections,
+            self.cooldown_until(now).as_secs(),
+            paused.as_secs()
+        );
+        if !self.member_failures.is_empty() {
+            let failures: Vec<String> = self
+                .member_failures
+                .iter()
+                .map(|(name, count)| format!("{name} {count}"))
+                .collect();
+            summary.push_str(&format!("，群組成員失敗：{}", failures.join("、")));
+        }
+        if !self.rule_fired.is_empty() {
+            let fired: Vec<String> = self
+                .rule_fired
+                .iter()
+                .map(|(name, count)| format!("{name} {count}"))
+                .collect();
+            summary.push_str(&format!("，規則觸發：{}", fired.join("、")));
+        }
+        if self.flapping > 0 {
+            summary.push_str(&format!("，flapping {} 次", self.flapping));
+        }
+        if self.crash_loops > 0 {
+            summary.push_str(&format!("，崩潰循環 {} 次", self.crash_loops));
+        }
+        if self.self_recovered > 0 {
+            summary.push_str(&format!(
+                "，等待閒置期間自行恢復 {} 次",
+                self.self_recovered
+            ));
+        }
+        summary
+    }
+}
+
+// --confirm-restart 等待中的動作；核准後記下由誰核准，下一次檢查據此動作
+struct PendingConfirmation {
+    incident: String,
+    action: ActionKind,
+    until: Instant,
+    approved_by: Option<String>,
+}
+
+// 經控制 socket 暫停時仍量測與記錄，只是不採取重啟動作
+struct Pause {
+    since: Instant,
+    // None 表示直到 resume
+    until: Option<Instant>,
+}
+
+impl Guard {
+    fn new(config: Config) -> Result<Self, String> {
+        let x11_endpoint = parse_display(&config.display)?;
+        // 以端點比較，:0 與 :0.0 視為同一個 display；:10 與 localhost:10 則不同
+        if !config.allowed_displays.is_empty()
+            && !config
+                .allowed_displays
+                .iter()
+                .any(|display| parse_display(display).as_ref() == Ok(&x11_endpoint))
+        {
+            return Err(format!(
+                "DISPLAY {} 不在 --allowed-displays 允許清單中（{}），拒絕啟動",
+                config.display,
+                config.allowed_displays.join(",")
+            ));
+        }
+        if let Err(error) = check_pid_namespace(&config.proc_root) {
+            if !config.dry_run {
+                return Err(error);
+            }
+            log_at(
+                &config,
+                LogLevel::Warn,
+                &format!("{error}（dry-run 模式，只量測不送訊號）"),
+            );
+        }
+        let inotify = InotifyWatch::new(config.proc_root.clone())
+            .map_err(|err| format!("inotify 初始化失敗: {err}"))?;
+        let (backend, backend_note) =
+            counter::select(config.connections_from, &config, &x11_endpoint)?;
+        let mux = mux::Multiplexer::new(config.event_loop);
+        let fd_scanner = FdScanner::new(config.proc_root.clone());
+        let app_names = config.app_names.clone();
+        let rules = std::iter::once(rule::Rule::threshold(config.threshold))
+            .chain(config.rules.iter().cloned())
+            .collect();
+        let digests = [Notifier::Desktop, Notifier::Command]
+            .into_iter()
+            .filter_map(|notifier| {
+                let seconds = config
+                    .notify_digest_overrides
+                    .iter()
+                    .rev()
+                    .find(|(name, _)| *name == notifier)
+                    .map(|(_, seconds)| *seconds)
+                    .or(config.notify_digest_seconds)?;
+                (seconds > 0).then(|| NotifyDigest::new(notifier, Duration::from_secs(seconds)))
+            })
+            .collect();
+        Ok(Self {
+            config,
+            x11_endpoint,
+            inotify,
+            last_restart: None,
+            excluded_x_server: HashSet::new(),
+            restart_times: VecDeque::new(),
+            consecutive_breaches: 0,
+            over_threshold_since: None,
+            last_over_threshold: None,
+            last_over_by_name: BTreeMap::new(),
+            last_gauge: None,
+            offender: None,
+            load_deferred_since: None,
+            active_deferred_since: None,
+            last_activity: None,
+            idle_hold_since: None,
+            last_idle: None,
+            plateau: None,
+            x_health: xprobe::Health::default(),
+            active_levels: Vec::new(),
+            incident: None,
+            incident_seq: 0,
+            fd_scanner,
+            quarantine: None,
+            failure: None,
+            app_session: None,
+            exit_on_failure: None,
+            watch_denied: HashSet::new(),
+            attribution_rejected: 0,
+            intra_app_excluded: 0,
+            windows: None,
+            windows_error: None,
+            xres_fallback: None,
+            backend,
+            backend_note,
+            pidfds: PidfdTable::detect(),
+            last_connections: None,
+            connection_first_seen: HashMap::new(),
+            incident_added: Vec::new(),
+            incident_removed: Vec::new(),
+            generation: HashSet::new(),
+            control: None,
+            mux,
+            pause: None,
+            last_action: ActionKind::Restart,
+            reload_escalated: false,
+            manual_reason: None,
+            rules,
+            action_rule: None,
+            rule_fired: HashMap::new(),
+            policy_hold: None,
+            confirmation: None,
+            confirm_hold: None,
+            digests,
+            flap: flap::Detector::default(),
+            crash: crashloop::Detector::default(),
+            history: None,
+            cpu: throttle::Governor::default(),
+            x11_socket: None,
+            app_names,
+            waiting_for_app: false,
+            layout: Vec::new(),
+            core_dump: None,
+            unreadable: Vec::new(),
+            unreadable_warned: HashSet::new(),
+            stats: RunStats::new(),
+            interval: IntervalSummary::new(Instant::now()),
+            churn: ChurnMeter::default(),
+            trend: TrendMeter::default(),
+            exec: ExecTracker::default(),
+            measure: MeasureTiming::default(),
+            count_trace: None,
+            upgrade_requested: false,
+        })
+    }
+
+    fn breaker_open(&mut self) -> bool {
+        if self.config.breaker_max_restarts == 0 {
+            return false;
+        }
+        let window = Duration::from_secs(self.config.breaker_window_seconds);
+        while let Some(first) = self.restart_times.front() {
+            if first.elapsed() < window {
+                break;
+            }
+            self.restart_times.pop_front();
+        }
+        self.restart_times.len() >= self.config.breaker_max_restarts
+    }
+
+    // 所有會延後動作的計時條件集中在此，回傳最晚解除的那一項
+    fn cooldown(&self) -> Duration {
+        self.cooldown_for(self.last_action)
+    }
+
+    fn cooldown_for(&self, kind: ActionKind) -> Duration {
+        let seconds = match kind {
+            ActionKind::Reload => self
+                .config
+                .reload_cooldown_seconds
+                .unwrap_or(self.config.cooldown_seconds),
+            ActionKind::Restart => self.config.cooldown_seconds,
+        };
+        Duration::from_secs(seconds)
+    }
+
+    fn time_until_eligible(&mut self) -> Option<(Duration, ActionGate)> {
+        let mut blocking: Option<(Duration, ActionGate)> = None;
+        let mut consider = |remain: Duration, reason: ActionGate| {
+            if remain.is_zero() {
+                return;
+            }
+            if blocking.is_none_or(|(current, _)| remain > current) {
+                blocking = Some((remain, reason));
+            }
+        };
+
+        if let Some(last) = self.last_restart {
+            consider(
+                self.cooldown().saturating_sub(last.elapsed()),
+                ActionGate::Cooldown,
+            );
+        }
+        if self.breaker_open() {
+            let window = Duration::from_secs(self.config.breaker_window_seconds);
+            let index = self.restart_times.len() - self.config.breaker_max_restarts;
+            let oldest = self.restart_times[index];
+            consider(window.saturating_sub(oldest.elapsed()), ActionGate::Breaker);
+        }
+        if let Some(until) = self.policy_hold {
+            consider(
+                until.saturating_duration_since(Instant::now()),
+                ActionGate::Policy,
+            );
+        }
+        if let Some(until) = self.confirm_hold {
+            consider(
+                until.saturating_duration_since(Instant::now()),
+                ActionGate::Confirmation,
+            );
+        }
+        blocking
+    }
+
+    // --deadline-restart：這一段連續超標已持續多久（僅在達到期限時回傳）
+    fn deadline_passed(&self) -> Option<Duration> {
+        let limit = Duration::from_secs(self.config.deadline_restart_seconds?);
+        let over = self.over_threshold_since?.elapsed();
+        (over >= limit).then_some(over)
+    }
+
+    fn eligibility_text(&mut self) -> String {
+        match self.time_until_eligible() {
+            None => "現在".to_string(),
+            Some((remain, reason)) => {
+                format!("約 {} 秒後（{}）", remain.as_secs() + 1, reason.label())
+            }
+        }
+    }
+
+    // {pids} 是最近一次量測的程序；重啟時即被結束的舊一代
+    fn placeholder_values(&self, incident: &str, reason: &str) -> Vec<(&'static str, String)> {
+        placeholder_values(
+            &self.config,
+            &self.x11_endpoint,
+            self.last_gauge,
+            &sorted_pids(&self.generation),
+            incident,
+            reason,
+        )
+    }
+
+    // 重啟、柔性重連與群組成員啟動命令的 {reason}：threshold、rule:<規則>、manual:<原因> 或 reload-escalated
+    fn action_placeholders(&mut self) -> Vec<(&'static str, String)> {
+        let incident = self.incident_id();
+        let reason = self.action_reason();
+        self.placeholder_values(&incident, &reason)
+    }
+
+    // 也寫進歷史記錄中動作的 trigger
+    fn action_reason(&self) -> String {
+        if self.reload_escalated {
+            "reload-escalated".to_string()
+        } else if let Some(manual) = &self.manual_reason {
+            format!("manual:{manual}")
+        } else if let Some(rule) = &self.action_rule {
+            format!("rule:{rule}")
+        } else {
+            "threshold".to_string()
+        }
+    }
+
+    fn incident_id(&mut self) -> String {
+        if let Some(id) = &self.incident {
+            return id.clone();
+        }
+        self.incident_seq += 1;
+        let id = format!("inc-{}-{}", timestamp(), self.incident_seq);
+        self.incident = Some(id.clone());
+        let count = self.last_gauge;
+        self.history_event(&id, "opened", "", count, String::new(), String::new());
+        id
+    }
+
+    // 事件結束：回到門檻內（recovered）或偵測到外部重啟（external-restart）
+    fn close_incident(&mut self, outcome: &str) {
+        if let Some(id) = self.incident.take() {
+            let count = self.last_gauge;
+            self.history_event(&id, "closed", outcome, count, String::new(), String::new());
+        }
+    }
+
+    fn record_outcome(&mut self, outcome: &RestartOutcome, manual: bool) {
+        let cooldown = self.cooldown();
+        self.stats.record_outcome(outcome, manual, cooldown);
+        if self.history.is_none() {
+            return;
+        }
+        let (name, detail) = match outcome {
+            RestartOutcome::DryRun => ("dry-run", String::new()),
+            RestartOutcome::Reconnected { before, after } => {
+                ("reconnected", format!("連線 {before} → {after} 條"))
+            }
+            RestartOutcome::Reloaded { before, after } => {
+                ("reloaded", format!("連線 {before} → {after} 條"))
+            }
+            RestartOutcome::Restarted { old_pids, new_pids } => {
+                ("restarted", format!("PID {old_pids:?} → {new_pids:?}"))
+            }
+            RestartOutcome::Failed(reason) => ("failed", reason.clone()),
+        };
+        let detail = match (manual, detail.is_empty()) {
+            (false, _) => detail,
+            (true, true) => "手動".to_string(),
+            (true, false) => format!("手動：{detail}"),
+        };
+        let incident = self.incident_id();
+        let count = self.last_gauge;
+        let trigger = self.action_reason();
+        self.history_event(&incident, "action", name, count, detail, trigger);
+    }
+
+    fn history_event(
+        &mut self,
+        incident: &str,
+        stage: &str,
+        outcome: &str,
+        count: Option<usize>,
+        detail: String,
+        trigger: String,
+    ) {
+        if self.history.is_none() {
+            return;
+        }
+        let record = history::Record::Event(history::Event {
+            time: timestamp(),
+            app: self.config.app_name.clone(),
+            display: self.config.display.clone(),
+            incident: incident.to_string(),
+            stage: stage.to_string(),
+            outcome: outcome.to_string(),
+            count,
+            detail,
+            trigger,
+        });
+        // 事件的經過要能在當機後查到，寫入後同步到磁碟
+        self.record_history(record, true);
+    }
+
+    // 寫不進去時守護照常執行：只在開始與恢復時各記一次，期間的記錄略過
+    fn record_history(&mut self, record: history::Record, sync: bool) {
+        let Some(store) = &mut self.history else {
+            return;
+        };
+        match store.append(&record, sync, Instant::now()) {
+            history::Written::Stored | history::Written::Skipped => {}
+            history::Written::Pruned(count) => {
+                if self.config.verbosity >= 1 {
+                    log(
+                        &self.config,
+                        &format!("歷史記錄清理了 {count} 筆超過保存期限或大小上限的舊記錄"),
+                    );
+                }
+            }
+            history::Written::Failed(error) => log_at(
+                &self.config,
+                LogLevel::Warn,
+                &format!(
+                    "歷史記錄無法寫入（{error}），守護照常執行，期間的記錄略過，每 {} 秒重試",
+                    history::RETRY.as_secs()
+                ),
+            ),
+            history::Written::Recovered { dropped, lasted } => log(
+                &self.config,
+                &format!(
+                    "歷史記錄恢復寫入（中斷 {} 秒，略過 {dropped} 筆）",
+                    lasted.as_secs()
+                ),
+            ),
+        }
+    }
+
+    // --max-cpu：window 結算後倍數有改變時回傳新的倍數
+    fn govern_cpu(&mut self) -> Option<u32> {
+        let percent = self.config.max_cpu_percent?;
+        let settings = throttle::Settings {
+            percent,
+            window: Duration::from_secs(self.config.max_cpu_window_seconds),
+        };
+        let since = self.cpu.slowed_since;
+        let change = self
+            .cpu
+            .observe(&settings, self_cpu_time()?, Instant::now())?;
+        match change {
+            throttle::Change::Slowed { usage, factor } => {
+                log_at(
+                    &self.config,
+                    LogLevel::Warn,
+                    &format!(
+                        "守護自身 CPU 用量 {usage:.1}% 超過 --max-cpu {percent}%（近 {} 秒），輪詢間隔放慢為 {factor} 倍，主迴圈每一輪至少間隔 {} 毫秒{}",
+                        settings.window.as_secs(),
+                        (throttle::ITERATION_FLOOR * factor).as_millis(),
+                        if factor == throttle::MAX_FACTOR {
+                            "（已達上限，請檢查設定）"
+                        } else {
+                            ""
+                        }
+                    ),
+                );
+                Some(factor)
+            }
+            throttle::Change::Eased { usage, factor } => {
+                let text = if factor == 1 {
+                    format!(
+                        "恢復原本的輪詢間隔（放慢了 {} 秒）",
+                        since.map_or(0, |since| since.elapsed().as_secs())
+                    )
+                } else {
+                    format!("輪詢間隔改為 {factor} 倍")
+                };
+                log(
+                    &self.config,
+                    &format!("守護自身 CPU 用量降到 {usage:.1}%，{text}"),
+                );
+                Some(factor)
+            }
+        }
+    }
+
+    fn open_history(&mut self) {
+        let Some(path) = self.config.history_file.clone() else {
+            return;
+        };
+        let mut store = history::Store::new(history::Settings {
+            path,
+            resolution: Duration::from_secs(self.config.history_resolution_seconds),
+            max_bytes: self.config.history_max_bytes,
+            max_age: Duration::from_secs(self.config.history_max_age_seconds),
+        });
+        match store.open(timestamp()) {
+            Ok(notes) => {
+                for note in notes {
+                    log(&self.config, &note);
+                }
+            }
+            // 開不了也照常監控，與寫入失敗相同，期間的記錄略過並定期重試
+            Err(error) => {
+                log_at(
+                    &self.config,
+                    LogLevel::Warn,
+                    &format!(
+                        "歷史記錄無法開啟（{error}），守護照常執行，期間的記錄略過，每 {} 秒重試",
+                        history::RETRY.as_secs()
+                    ),
+                );
+                store.fail(error, Instant::now());
+            }
+        }
+        self.history = Some(store);
+    }
+
+    // 只讀取規則用得到的指標：fd 與 rss 要逐一讀取每個程序的 /proc；churn 與 --churn-threshold 相同，未滿一分鐘視為未知
+    fn sample_metrics(&self, pids: &[i32], x11_count: usize) -> rule::Metrics {
+        let uses = |metric| self.rules.iter().any(|rule| rule.uses(metric));
+        let proc_root = &self.config.proc_root;
+        rule::Metrics {
+            x11: x11_count,
+            fd: uses(rule::Metric::Fd).then(|| {
+                pids.iter()
+                    .map(|pid| fd_count(proc_root, *pid) as u64)
+                    .sum()
+            }),
+            rss: uses(rule::Metric::Rss)
+                .then(|| pids.iter().map(|pid| rss_kib(proc_root, *pid) * 1024).sum()),
+            churn: self
+                .churn
+                .rate()
+                .filter(ChurnRate::sustained)
+                .map(|rate| rate.opened_per_minute.max(rate.closed_per_minute)),
+        }
+    }
+
+    // --rule 的組合規則（不含 --threshold）：alert 與 escalate 在此記錄並通知；
+    // 成立且已過自己冷卻時間的第一條 restart 規則回傳給 check_threshold，經 restart_app 的各項限制後執行
+    fn evaluate_rules(&mut self, metrics: &rule::Metrics) -> Option<String> {
+        let rules = self.rules[1..].to_vec();
+        let mut restart = None;
+        for rule in &rules {
+            let matched = rule.matches(metrics);
+            let cooldown = rule
+                .cooldown_seconds
+                .unwrap_or(self.config.cooldown_seconds);
+            let cooled = self
+                .rule_fired
+                .get(&rule.name)
+                .is_none_or(|last| last.elapsed() >= Duration::from_secs(cooldown));
+            match rule.action {
+                rule::RuleAction::Restart => {
+                    if matched && cooled && restart.is_none() {
+                        restart = Some(rule.name.clone());
+                    }
+                }
+                rule::RuleAction::Alert => {
+                    if matched && cooled {
+                        self.fire_rule(rule, metrics);
+                    }
+                }
+                rule::RuleAction::Escalate => {
+                    let active = self.active_levels.contains(&rule.name);
+                    if matched && !active {
+                        self.active_levels.push(rule.name.clone());
+                        if cooled {
+                            self.fire_rule(rule, metrics);
+                        } else {
+                            let incident = self.incident_id();
+                            log(
+                                &self.config,
+                                &format!(
+                                    "[{incident}] 規則 {} 再次成立（{}），冷卻中不重複通知",
+                                    rule.name,
+                                    rule.values_text(metrics)
+                                ),
+                            );
+                        }
+                    } else if !matched && active {
+                        self.active_levels.retain(|name| name != &rule.name);
+                        log(
+                            &self.config,
+                            &format!(
+                                "[{}] 規則 {} 解除",
+                                self.incident.as_deref().unwrap_or("-"),
+                                rule.name
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+        restart
+    }
+
+    // 緊急通知一律立即送出；其餘依 --notify-digest 進入該通知方式的摘要緩衝
+    fn notify(&mut self, notifiers: &[Notifier], notification: &Notification, immediate: bool) {
+        for notifier in notifiers {
+            match self
+                .digests
+                .iter_mut()
+                .find(|digest| digest.notifier == *notifier)
+            {
+                Some(digest) if !immediate && notification.urgency != Urgency::Critical => {
+                    digest.push(notification, &self.stats.outcomes)
+                }
+                _ => send_notification(
+                    &self.config,
+                    *notifier,
+                    notification,
+                    &self.placeholder_values(notification.incident, notification.level),
+                ),
+            }
+        }
+    }
+
+    // all 為 true 時（結束監控）不論時間窗是否到期都送出
+    fn flush_digests(&mut self, all: bool) {
+        let now = Instant::now();
+        for digest in &mut self.digests {
+            if !all && digest.due().is_none_or(|due| due > now) {
+                continue;
+            }
+            let Some((incidents, urgency, message)) =
+                digest.take(&self.config.app_name, &self.stats.outcomes)
+            else {
+                continue;
+            };
+            if self.config.verbosity >= 1 {
+                log(
+                    &self.config,
+                    &format!("送出通知摘要（{}）：{incidents}", digest.notifier.name()),
+                );
+            }
+            let notification = Notification {
+                level: "digest",
+                incident: &incidents,
+                urgency,
+                message: &message,
+                core_dump: None,
+            };
+            let values = placeholder_values(
+                &self.config,
+                &self.x11_endpoint,
+                self.last_gauge,
+                &sorted_pids(&self.generation),
+                &incidents,
+                "digest",
+            );
+            send_notification(&self.config, digest.notifier, &notification, &values);
+        }
+    }
+
+    fn fire_rule(&mut self, rule: &rule::Rule, metrics: &rule::Metrics) {
+        let incident = self.incident_id();
+        let message = format!(
+            "[{incident}] {} 規則 {} 成立：{}（{}）",
+            self.config.app_name,
+            rule.name,
+            rule.expression,
+            rule.values_text(metrics)
+        );
+        log_at(&self.config, rule.log_level, &message);
+        let notification = Notification {
+            level: &rule.name,
+            incident: &incident,
+            urgency: rule.urgency,
+            message: &message,
+            core_dump: None,
+        };
+        self.notify(&rule.notifiers, &notification, false);
+        self.rule_fired.insert(rule.name.clone(), Instant::now());
+        *self.stats.rule_fired.entry(rule.name.clone()).or_default() += 1;
+    }
+
+    fn evaluate_escalation(&mut self, x11_count: usize) {
+        let breaker_open = self.breaker_open();
+        let churn = self.churn.rate();
+        let churn_high = match (churn, self.config.churn_threshold) {
+            (Some(rate), Some(limit)) => {
+                rate.sustained() && rate.opened_per_minute.max(rate.closed_per_minute) >= limit
+            }
+            _ => false,
+        };
+        let threshold = self.active_threshold();
+        let trend = self.current_trend();
+        let forecast_near = match (trend.and_then(|value| value.eta), self.config.forecast_warn) {
+            (Some(eta), Some(horizon)) => eta <= Duration::from_secs(horizon),
+            _ => false,
+        };
+        let levels = self.config.escalation.clone();
+        for level in &levels {
+            let matched = match level.condition {
+                EscalationCondition::PercentOfThreshold(percent) => {
+                    x11_count * 100 >= threshold * percent as usize
+                }
+                EscalationCondition::ConsecutiveBreaches(count) => {
+                    self.consecutive_breaches >= count
+                }
+                EscalationCondition::BreakerOpen => breaker_open,
+                EscalationCondition::Churn => churn_high,
+                EscalationCondition::Forecast => forecast_near,
+                EscalationCondition::Flapping => self.flap.flapping_since.is_some(),
+            };
+            let active = self.active_levels.contains(&level.name);
+            if matched && !active {
+                let incident = self.incident_id();
+                self.active_levels.push(level.name.clone());
+                let message = match (level.condition, churn, trend) {
+                    (EscalationCondition::Forecast, _, Some(trend)) => format!(
+                        "[{incident}] 進入警示等級 {}：{} X11 連線 {} 條（門檻 {}），{}",
+                        level.name,
+                        self.config.app_name,
+                        x11_count,
+                        threshold,
+                        trend.text()
+                    ),
+                    (EscalationCondition::Flapping, _, _) => format!(
+                        "[{incident}] 進入警示等級 {}：{} 連線數反覆跨越門檻 {}（{}），暫停自動動作",
+                        level.name,
+                        self.config.app_name,
+                        threshold,
+                        self.flap_rate_text()
+                    ),
+                    (EscalationCondition::Churn, Some(rate), _) => format!(
+                        "[{incident}] 進入警示等級 {}：{} 連線變動{}，達門檻每分鐘 {} 條（目前 X11 連線 {} 條）",
+                        level.name,
+                        self.config.app_name,
+                        rate.text(),
+                        self.config.churn_threshold.unwrap_or_default(),
+                        x11_count
+                    ),
+                    _ => format!(
+                        "[{incident}] 進入警示等級 {}：{} X11 連線 {} 條（門檻 {}）",
+                        level.name, self.config.app_name, x11_count, threshold
+                    ),
+                };
+                log_at(&self.config, level.log_level, &message);
+                self.history_event(
+                    &incident,
+                    "alert",
+                    &level.name,
+                    Some(x11_count),
+                    message.clone(),
+                    String::new(),
+                );
+                let notification = Notification {
+                    level: &level.name,
+                    incident: &incident,
+                    urgency: level.urgency,
+                    message: &message,
+                    core_dump: None,
+                };
+                // 熔斷器開啟代表自動重啟已停止，不等摘要
+                let immediate = level.condition == EscalationCondition::BreakerOpen;
+                self.notify(&level.notifiers, &notification, immediate);
+            } else if !matched && active {
+                self.active_levels.retain(|name| name != &level.name);
+                let incident = self.incident.clone().unwrap_or_else(|| "-".to_string());
+                let message = format!("[{incident}] 警示等級 {} 解除", level.name);
+                log(&self.config, &message);
+                // 解除本來不通知；有摘要的通知方式一併列出，讓摘要看得出事件是否已平息
+                let notification = Notification {
+                    level: &format!("{}解除", level.name),
+                    incident: &incident,
+                    urgency: Urgency::Low,
+                    message: &message,
+                    core_dump: None,
+                };
+                for digest in &mut self.digests {
+                    if level.notifiers.contains(&digest.notifier) {
+                        digest.push(&notification, &self.stats.outcomes);
+                    }
+                }
+            }
+        }
+        if self.active_levels.is_empty() && x11_count <= threshold {
+            self.close_incident("recovered");
+        }
+    }
+
+    fn current_trend(&self) -> Option<Trend> {
+        let count = self.last_connections.as_ref()?.len();
+        self.trend.trend(count, self.config.threshold)
+    }
+
+    fn app_pids(&mut self) -> Vec<i32> {
+        let proc_root = &self.config.proc_root;
+        let scan = scan_process_names(proc_root);
+        let matched = match_pids_by_name(
+            proc_root,
+            &scan,
+            &self.app_names,
+            &self.config.cmdline_match,
+        );
+        let (pids, excluded) = exclude_x_servers(&mut self.fd_scanner, matched, &self.x11_endpoint);
+        for pid in &excluded {
+            if self.excluded_x_server.insert(*pid) {
+                log(
+                    &self.config,
+                    &format!(
+                        "PID {pid} 符合名稱但可能是 X server（持有 X11 監聽 socket，或無法確認），已排除"
+                    ),
+                );
+            }
+        }
+        self.excluded_x_server.retain(|pid| excluded.contains(pid));
+        for transition in self.exec.observe(&self.config.proc_root, scan, &pids) {
+            self.note_exec(&transition);
+        }
+        pids
+    }
+
+    // exec 後的程序視為新的身分：權限警告與 inotify 監看都作廢，
+    // 仍符合條件的由下一次 sync_inotify 重新加入監看；不再符合的也不屬於這一代
+    fn note_exec(&mut self, transition: &ExecTransition) {
+        let ExecTransition {
+            pid,
+            start_time,
+            from,
+            to,
+            was_watched,
+            matches,
+        } = transition;
+        let outcome = match (was_watched, matches) {
+            (true, false) => "不再符合比對條件，停止監控",
+            (false, _) => "符合比對條件，開始監控",
+            (true, true) => "仍符合比對條件，重建監看",
+        };
+        log(
+            &self.config,
+            &format!(
+                "{} PID {pid} 已 exec：{from} → {to}，{outcome}",
+                self.config.app_name
+            ),
+        );
+        let identity = (*pid, *start_time);
+        self.unreadable_warned.remove(&identity);
+        self.watch_denied.remove(pid);
+        self.inotify.remove_pid(*pid);
+        if !matches {
+            self.generation.remove(&identity);
+        }
+    }
+
+    fn sync_watches(&mut self) -> Vec<i32> {
+        let pids = self.app_pids();
+        self.sync_inotify(&pids);
+        pids
+    }
+
+    fn sync_inotify(&mut self, pids: &[i32]) {
+        if !pids.is_empty() && mem::take(&mut self.waiting_for_app) {
+            log(
+                &self.config,
+                &format!("{} 已啟動（PID {pids:?}），開始監控", self.config.app_name),
+            );
+            self.restore_layout();
+        }
+        self.pidfds.sync_pids(pids);
+        let failures = self.inotify.sync_pids(pids);
+        self.watch_denied.retain(|pid| pids.contains(pid));
+        for (pid, err) in failures {
+            if privileges::is_permission_error(&err) && self.watch_denied.insert(pid) {
+                log_at(
+                    &self.config,
+                    LogLevel::Warn,
+                    &format!(
+                        "無法監看 /proc/{pid}/fd（{err}），只能靠備援輪詢偵測：{}",
+                        permission_hint(&self.config)
+                    ),
+                );
+            }
+        }
+    }
+
+    fn signal_pids(&mut self, pids: &[i32], sig: i32) {
+        for (pid, err) in terminate_processes(pids, sig, &mut self.pidfds) {
+            if privileges::is_permission_error(&err) {
+                log_at(
+                    &self.config,
+                    LogLevel::Error,
+                    &format!(
+                        "無法對 PID {pid} 送出訊號 {sig}（{err}）：{}",
+                        permission_hint(&self.config)
+                    ),
+                );
+            }
+        }
+    }
+
+    // rule 為觸發動作的 --rule 名稱；超過 --threshold 時為 None
+    fn restart_app(&mut self, x11_count: usize, rule: Option<&str>) -> Option<RestartOutcome> {
+        // 反覆跨越時重啟只會讓情況更糟，--deadline-restart 也不例外
+        if let Some(since) = self.flap.flapping_since {
+            for digest in &mut self.digests {
+                digest.note_deferred("flapping");
+            }
+            let incident = self.incident_id();
+            log(
+                &self.config,
+                &format!(
+                    "[{incident}] 超標但處於 flapping 狀態（已 {} 秒），不採取動作",
+                    since.elapsed().as_secs()
+                ),
+            );
+            return None;
+        }
+        if let Some(since) = self.crash.looping_since {
+            for digest in &mut self.digests {
+                digest.note_deferred("崩潰循環");
+            }
+            let incident = self.incident_id();
+            log(
+                &self.config,
+                &format!(
+                    "[{incident}] 超標但 {} 處於崩潰循環（已 {} 秒），不自動重啟",
+                    self.config.app_name,
+                    since.elapsed().as_secs()
+                ),
+            );
+            return None;
+        }
+        let mut forced = false;
+        if let Some((remain, reason)) = self.time_until_eligible() {
+            let remain = remain.as_secs() + 1;
+            let now = Instant::now();
+            let held = self.policy_hold.is_some_and(|until| until > now)
+                || self.confirm_hold.is_some_and(|until| until > now);
+            if let Some(over) = self.deadline_passed().filter(|_| !held) {
+                // 冷卻或熔斷擋得住一次次的重啟，擋不住一直漏著的程式；策略命令的延後與操作者的拒絕仍然遵守
+                log_at(
+                    &self.config,
+                    LogLevel::Error,
+                    &format!(
+                        "{} 已連續超標 {} 秒，達到 --deadline-restart {} 秒：無視{}（約 {remain} 秒）強制重啟一次",
+                        self.config.app_name,
+                        over.as_secs(),
+                        self.config.deadline_restart_seconds.unwrap_or_default(),
+                        reason.label()
+                    ),
+                );
+                forced = true;
+            } else if reason == ActionGate::Breaker {
+                for digest in &mut self.digests {
+                    digest.note_deferred(reason.label());
+                }
+                log_at(
+                    &self.config,
+                    LogLevel::Warn,
+                    &format!(
+                        "熔斷器開啟：{} 秒內已重啟 {} 次，暫停自動重啟，約 {remain} 秒後可再動作",
+                        self.config.breaker_window_seconds,
+                        self.restart_times.len()
+                    ),
+                );
+            } else {
+                for digest in &mut self.digests {
+                    digest.note_deferred(reason.label());
+                }
+                log(
+                    &self.config,
+                    &format!("超標但在{}期中，約 {remain} 秒後可再動作", reason.label()),
+                );
+            }
+            if !forced {
+                return None;
+            }
+        }
+
+        let mut pids = self.app_pids();
+        if pids.is_empty() {
+            log(&self.config, "偵測超標時找不到目標程序，略過重啟");
+            return None;
+        }
+
+        // 規則與期限到期的強制重啟不等持平
+        if rule.is_none() && !forced && self.wait_for_plateau(x11_count) {
+            return None;
+        }
+
+        if let Some(since) = self.x_health.unhealthy_since {
+            let incident = self.incident_id();
+            log_at(
+                &self.config,
+                LogLevel::Warn,
+                &format!(
+                    "[{incident}] X server 已 {} 秒沒有回應，暫緩動作：連線數凍結多半是 X server 的問題，重啟 {} 無濟於事",
+                    since.elapsed().as_secs(),
+                    self.config.app_name
+                ),
+            );
+            return None;
+        }
+
+        if self.defer_for_load() || self.defer_while_active(&pids) || self.hold_until_idle() {
+            return None;
+        }
+
+        // 期限到了表示重新載入或柔性處理都沒能讓連線數回來，直接完整重啟
+        let mut action_kind = if forced {
+            ActionKind::Restart
+        } else {
+            self.config.action
+        };
+        if let Some(command) = self.config.policy_cmd.clone() {
+            match self.consult_policy(&command, action_kind, forced, x11_count, &pids) {
+                PolicyVerdict::Allow => {}
+                PolicyVerdict::Deny => {
+                    self.hold_for_policy(self.config.fallback_poll_seconds);
+                    return None;
+                }
+                PolicyVerdict::Defer(seconds) => {
+                    self.hold_for_policy(seconds);
+                    return None;
+                }
+                PolicyVerdict::Replace(kind) => action_kind = kind,
+            }
+        }
+        if self.config.confirm_restart && !self.await_confirmation(action_kind, x11_count) {
+            return None;
+        }
+        if !self.claim_action(action_kind, self.config.state_sync) {
+            return None;
+        }
+
+        self.over_threshold_since = None;
+        self.plateau = None;
+        self.manual_reason = None;
+        self.action_rule = rule.map(str::to_string);
+        self.reload_escalated = false;
+        let action = match action_kind {
+            ActionKind::Restart => "重啟".to_string(),
+            ActionKind::Reload => format!("重新載入（{}）", signal_name(self.config.reload_signal)),
+        };
+        match rule.and_then(|name| self.rules.iter().find(|item| item.name == name)) {
+            Some(rule) => {
+                let name = rule.name.clone();
+                log(
+                    &self.config,
+                    &format!(
+                        "{} 規則 {name} 成立（{}），準備{action}",
+                        self.config.app_name, rule.expression
+                    ),
+                );
+                self.rule_fired.insert(name.clone(), Instant::now());
+                *self.stats.rule_fired.entry(name).or_default() += 1;
+            }
+            None => log(
+                &self.config,
+                &format!(
+                    "{} {} {} 條{}，超過門檻 {}，準備{action}",
+                    self.config.app_name,
+                    self.gauge_name(),
+                    x11_count,
+                    self.offender
+                        .map(|(pid, _)| format!("（PID {pid}）"))
+                        .unwrap_or_default(),
+                    self.active_threshold()
+                ),
+            ),
+        }
+        if let Some(summary) = self.incident_delta_summary() {
+            log(&self.config, &summary);
+        }
+        if action_kind == ActionKind::Reload {
+            match self.try_reload(&pids) {
+                Some(after) if after <= self.active_threshold() => {
+                    log(
+                        &self.config,
+                        &format!(
+                            "{action}已解決：{} {x11_count} → {after} 條，不需重啟",
+                            self.gauge_name()
+                        ),
+                    );
+                    self.last_restart = Some(Instant::now());
+                    self.last_action = ActionKind::Reload;
+                    return Some(RestartOutcome::Reloaded {
+                        before: x11_count,
+                        after,
+                    });
+                }
+                Some(after) => {
+                    log_at(
+                        &self.config,
+                        LogLevel::Warn,
+                        &format!(
+                            "{action}未解決：{}仍有 {after} 條（門檻 {}），升級為完整重啟",
+                            self.gauge_name(),
+                            self.active_threshold()
+                        ),
+                    );
+                    self.reload_escalated = true;
+                    pids = self.app_pids();
+                }
+                None => {}
+            }
+        }
+        Some(self.restart_after_soft_tiers(x11_count, pids))
+    }
+
+    // --x-probe：連續失敗達門檻時判定 X server 無回應並通知，成功一次即恢復
+    fn probe_x_server(&mut self) {
+        let result = xprobe::probe(&self.x11_endpoint, xprobe::TIMEOUT);
+        let needed = self
+            .config
+            .x_probe_failures
+            .unwrap_or(DEFAULT_X_PROBE_FAILURES);
+        let change = self.x_health.record(result.clone(), needed, Instant::now());
+        match (change, result) {
+            (Some(xprobe::Change::Unresponsive), Err(error)) => {
+                let message = format!(
+                    "X server 無回應（{} 連續 {needed} 次探測失敗：{error}）——暫緩自動動作，探測恢復後繼續",
+                    self.x11_endpoint
+                );
+                log_at(&self.config, LogLevel::Error, &message);
+                self.notify_failure("x-server", &message);
+            }
+            (Some(xprobe::Change::Recovered(down)), _) => log(
+                &self.config,
+                &format!(
+                    "X server 已恢復回應（無回應約 {} 秒），恢復自動動作",
+                    down.as_secs()
+                ),
+            ),
+            (None, Err(error)) if self.x_health.unhealthy_since.is_none() => log_at(
+                &self.config,
+                LogLevel::Warn,
+                &format!(
+                    "X server 探測失敗（{}/{needed}）：{error}",
+                    self.x_health.consecutive_failures
+                ),
+            ),
+            (None, Ok(elapsed)) if self.config.verbosity >= 1 => log(
+                &self.config,
+                &format!(
+                    "X server 探測正常（{:.1} ms）",
+                    elapsed.as_secs_f64() * 1000.0
+                ),
+            ),
+            _ => {}
+        }
+    }
+
+    // --wait-for-plateau：連線數還在上升時動作，可能正好打斷一波會自行消退的尖峰，也錯過真正的峰值。
+    // 每次超標的量測更新狀態並記錄上升與持平的轉換；持平夠久或等待達上限時回傳 false 照常動作
+    fn wait_for_plateau(&mut self, count: usize) -> bool {
+        let Some(seconds) = self.config.wait_for_plateau_seconds else {
+            return false;
+        };
+        let limit = self
+            .config
+            .plateau_max_wait_seconds
+            .unwrap_or(DEFAULT_PLATEAU_MAX_WAIT);
+        let now = Instant::now();
+        let incident = self.incident_id();
+        let Some(wait) = self.plateau.as_mut() else {
+            self.plateau = Some(PlateauWait::new(count, now));
+            log(
+                &self.config,
+                &format!(
+                    "[{incident}] {} {} {count} 條超過門檻 {}，等待連線數持平 {seconds} 秒後再動作（最多等 {limit} 秒）",
+                    self.config.app_name,
+                    self.gauge_name(),
+                    self.active_threshold()
+                ),
+            );
+            return true;
+        };
+        if wait.settled {
+            return false;
+        }
+        let change = wait.record(count, now);
+        let wait = wait.clone();
+        let waited = now.duration_since(wait.since).as_secs();
+        let steady =
+            !wait.climbing && now.duration_since(wait.steady_since) >= Duration::from_secs(seconds);
+        if steady {
+            log(
+                &self.config,
+                &format!(
+                    "[{incident}] X11 連線已持平 {seconds} 秒（{count} 條，峰值 {} 條，共等待 {waited} 秒），動作",
+                    wait.peak
+                ),
+            );
+        } else if waited >= limit {
+            log_at(
+                &self.config,
+                LogLevel::Warn,
+                &format!(
+                    "[{incident}] 等待持平已 {waited} 秒，達到 --plateau-max-wait {limit} 秒，X11 連線 {count} 條（峰值 {} 條），照常動作",
+                    wait.peak
+                ),
+            );
+        } else {
+            let message = match change {
+                Some(PlateauChange::Climbing { from, to }) => {
+                    format!("[{incident}] X11 連線再度上升：{from} → {to} 條，重新計算持平時間")
+                }
+                Some(PlateauChange::Steady { peak }) => format!(
+                    "[{incident}] X11 連線停止上升（{count} 條，峰值 {peak} 條），持平 {seconds} 秒後動作"
+                ),
+                None => return true,
+            };
+            log(&self.config, &message);
+            return true;
+        }
+        if let Some(wait) = self.plateau.as_mut() {
+            wait.settled = true;
+        }
+        false
+    }
+
+    // 主迴圈在持平時間或最長等待到期時醒來重新量測；已結束等待或暫停中不需要
+    fn plateau_deadline(&self) -> Option<Instant> {
+        let wait = self.plateau.as_ref().filter(|wait| !wait.settled)?;
+        if self.pause.is_some() {
+            return None;
+        }
+        let seconds = self.config.wait_for_plateau_seconds?;
+        let limit = self
+            .config
+            .plateau_max_wait_seconds
+            .unwrap_or(DEFAULT_PLATEAU_MAX_WAIT);
+        Some(wait.next_check(Duration::from_secs(seconds), Duration::from_secs(limit)))
+    }
+
+    // --max-load / --max-cpu-pressure：系統已經過載時，關閉並重新啟動大型程式會讓機器卡住好一陣子，
+    // 先不動作，之後每次超標的檢查重新評估；延後累計達 --max-load-wait 時照常動作
+    fn defer_for_load(&mut self) -> bool {
+        if self.config.max_load.is_none() && self.config.max_cpu_pressure.is_none() {
+            return false;
+        }
+        let incident = self.incident_id();
+        let overload = match system_overload(&self.config) {
+            Ok(overload) => overload,
+            Err(error) => {
+                log_at(
+                    &self.config,
+                    LogLevel::Warn,
+                    &format!("[{incident}] {error}，不因系統負載延後"),
+                );
+                None
+            }
+        };
+        let Some(reason) = overload else {
+            if let Some(since) = self.load_deferred_since.take() {
+                log(
+                    &self.config,
+                    &format!(
+                        "[{incident}] 系統負載已回落，延後 {} 秒後動作",
+                        since.elapsed().as_secs()
+                    ),
+                );
+            }
+            return false;
+        };
+        let waited = self
+            .load_deferred_since
+            .get_or_insert_with(Instant::now)
+            .elapsed();
+        let limit = self
+            .config
+            .max_load_wait_seconds
+            .unwrap_or(DEFAULT_MAX_LOAD_WAIT);
+        if waited >= Duration::from_secs(limit) {
+            log_at(
+                &self.config,
+                LogLevel::Warn,
+                &format!(
+                    "[{incident}] {reason}，但已延後 {} 秒，達到 --max-load-wait {limit} 秒，照常動作",
+                    waited.as_secs()
+                ),
+            );
+            self.load_deferred_since = None;
+            return false;
+        }
+        log(
+            &self.config,
+            &format!(
+                "[{incident}] {reason}，延後動作（已延後 {} 秒，最多 {limit} 秒）",
+                waited.as_secs()
+            ),
+        );
+        true
+    }
+
+    // --defer-while-active：使用者正在聊天時重啟，比連線數再高一陣子更擾人。準備動作時取樣一次，
+    // 使用中就延後，之後每次超標的檢查重新評估；延後累計達 --active-max-wait 時照常動作
+    fn defer_while_active(&mut self, pids: &[i32]) -> bool {
+        if !self.config.defer_while_active {
+            return false;
+        }
+        let incident = self.incident_id();
+        let activity = measure_activity(&self.config, &self.x11_endpoint, pids);
+        if let Some(Err(error)) = &activity.idle {
+            log_at(
+                &self.config,
+                LogLevel::Warn,
+                &format!("[{incident}] 無法取得輸入閒置時間（{error}），只依 CPU 使用率判斷"),
+            );
+        }
+        let reason = active_reason(&self.config, &activity);
+        let sample = activity.text();
+        self.last_activity = Some((Instant::now(), activity));
+        let Some(reason) = reason else {
+            if let Some(since) = self.active_deferred_since.take() {
+                log(
+                    &self.config,
+                    &format!(
+                        "[{incident}] 程式已不在使用中（{sample}），延後 {} 秒後動作",
+                        since.elapsed().as_secs()
+                    ),
+                );
+            }
+            return false;
+        };
+        let waited = self
+            .active_deferred_since
+            .get_or_insert_with(Instant::now)
+            .elapsed();
+        let limit = self
+            .config
+            .active_max_wait_seconds
+            .unwrap_or(DEFAULT_ACTIVE_MAX_WAIT);
+        if waited >= Duration::from_secs(limit) {
+            log_at(
+                &self.config,
+                LogLevel::Warn,
+                &format!(
+                    "[{incident}] 程式使用中（{reason}），但已延後 {} 秒，達到 --active-max-wait {limit} 秒，照常動作",
+                    waited.as_secs()
+                ),
+            );
+            self.active_deferred_since = None;
+            return false;
+        }
+        for digest in &mut self.digests {
+            digest.note_deferred("使用中");
+        }
+        log(
+            &self.config,
+            &format!(
+                "[{incident}] 程式使用中（{reason}；{sample}），延後動作（已延後 {} 秒，最多 {limit} 秒）",
+                waited.as_secs()
+            ),
+        );
+        true
+    }
+
+    // --only-when-idle：只在使用者離開一段時間後動作。準備動作時查詢閒置時間，不夠久（或查不到）就保留這次動作，
+    // 之後每次超標的檢查重新查詢；保留累計達 --idle-max-hold 時照常動作
+    fn hold_until_idle(&mut self) -> bool {
+        let Some(needed) = self.config.only_when_idle_seconds else {
+            return false;
+        };
+        let reading = session_idle(&self.config, &self.x11_endpoint, self.app_session);
+        self.hold_for_idle(needed, reading)
+    }
+
+    fn hold_for_idle(&mut self, needed: u64, reading: IdleReading) -> bool {
+        let incident = self.incident_id();
+        let current = idle_text(&reading);
+        let idle_enough = reading
+            .as_ref()
+            .is_ok_and(|(idle, _)| *idle >= Duration::from_secs(needed));
+        self.last_idle = Some((Instant::now(), reading));
+        if idle_enough {
+            if let Some(since) = self.idle_hold_since.take() {
+                log(
+                    &self.config,
+                    &format!(
+                        "[{incident}] 使用者已{current}，保留 {} 秒後動作",
+                        since.elapsed().as_secs()
+                    ),
+                );
+            }
+            return false;
+        }
+        let limit = self
+            .config
+            .idle_max_hold_seconds
+            .unwrap_or(DEFAULT_IDLE_MAX_HOLD);
+        let Some(since) = self.idle_hold_since else {
+            self.idle_hold_since = Some(Instant::now());
+            for digest in &mut self.digests {
+                digest.note_deferred("等待閒置");
+            }
+            let level = if self
+                .last_idle
+                .as_ref()
+                .is_some_and(|(_, reading)| reading.is_err())
+            {
+                LogLevel::Warn
+            } else {
+                LogLevel::Info
+            };
+            log_at(
+                &self.config,
+                level,
+                &format!(
+                    "[{incident}] {current}，未達 --only-when-idle {needed} 秒，保留動作直到使用者閒置（最晚 {} 照常動作）",
+                    wall_clock(SystemTime::now() + Duration::from_secs(limit))
+                ),
+            );
+            return true;
+        };
+        let held = since.elapsed();
+        if held >= Duration::from_secs(limit) {
+            log_at(
+                &self.config,
+                LogLevel::Warn,
+                &format!(
+                    "[{incident}] {current}，但已保留 {} 秒，達到 --idle-max-hold {limit} 秒，照常動作",
+                    held.as_secs()
+                ),
+            );
+            self.idle_hold_since = None;
+            return false;
+        }
+        if self.config.verbosity >= 1 {
+            log(
+                &self.config,
+                &format!(
+                    "[{incident}] {current}，繼續保留動作（已保留 {} 秒，最多 {limit} 秒）",
+                    held.as_secs()
+                ),
+            );
+        }
+        true
+    }
+
+    // --policy-cmd：把這次決策的完整內容以 JSON 寫入 stdin，依回覆決定是否動作。
+    // 逾時採用 --policy-fallback；失敗或輸出無法解析時也用它，但絕不當成 allow
+    fn consult_policy(
+        &mut self,
+        command: &str,
+        proposed: ActionKind,
+        deadline: bool,
+        x11_count: usize,
+        pids: &[i32],
+    ) -> PolicyVerdict {
+        let incident = self.incident_id();
+        let context = self.policy_context(&incident, proposed, deadline, x11_count, pids);
+        log(
+            &self.config,
+            &format!(
+                "[{incident}] 詢問策略命令：提議 {}，{} {x11_count} 條（門檻 {}）",
+                proposed.name(),
+                self.gauge_name(),
+                self.active_threshold()
+            ),
+        );
+        let values = self.placeholder_values(&incident, proposed.name());
+        let spec = template::fill(user_command(command, false), &values)
+            .input(&context)
+            .timeout(Duration::from_secs(self.config.policy_timeout_seconds));
+        let fallback = self.config.policy_fallback;
+        let never_allow = match fallback {
+            PolicyVerdict::Allow => PolicyVerdict::Deny,
+            other => other,
+        };
+        let (verdict, problem) = match sandbox::run_output(&spec) {
+            Ok((true, stdout)) => match parse_policy_verdict(&String::from_utf8_lossy(&stdout)) {
+                Ok(verdict) => (verdict, None),
+                Err(error) => (never_allow, Some(format!("輸出格式錯誤（{error}）"))),
+            },
+            Ok((false, _)) => (never_allow, Some("以非 0 結束碼結束".to_string())),
+            Err(error) if error.kind() == io::ErrorKind::TimedOut => {
+                (fallback, Some(format!("逾時（{error}）")))
+            }
+            Err(error) => (never_allow, Some(format!("無法執行（{error}）"))),
+        };
+        match problem {
+            Some(problem) => log_at(
+                &self.config,
+                LogLevel::Warn,
+                &format!(
+                    "[{incident}] 策略命令{problem}，採用預設裁決 {}",
+                    verdict.text()
+                ),
+            ),
+            None => log(
+                &self.config,
+                &format!("[{incident}] 策略裁決：{}", verdict.text()),
+            ),
+        }
+        verdict
+    }
+
+    // --confirm-restart：第一次需要動作時開始等待並通知，核准前的檢查都不動作；
+    // 核准後的這一次檢查照常動作。拒絕與逾時分別由控制命令與 expire_confirmation 處理
+    fn await_confirmation(&mut self, action: ActionKind, x11_count: usize) -> bool {
+        if let Some(pending) = &self.confirmation {
+            if pending.approved_by.is_none() {
+                return false;
+            }
+            self.confirmation = None;
+            return true;
+        }
+        let incident = self.incident_id();
+        let timeout = self.config.confirm_timeout_seconds;
+        let message = format!(
+            "[{incident}] 提議 {}：{} {x11_count} 條（門檻 {}），等待確認；\
+             請在 {timeout} 秒內以 ctl approve 或 ctl deny 回覆，逾時視為拒絕",
+            action.name(),
+            self.gauge_name(),
+            self.active_threshold()
+        );
+        log_at(&self.config, LogLevel::Warn, &message);
+        let notification = Notification {
+            level: "confirm",
+            incident: &incident,
+            urgency: Urgency::Critical,
+            message: &message,
+            core_dump: None,
+        };
+        let values = self.placeholder_values(&incident, "confirm");
+        for notifier in &self.config.confirm_notifiers {
+            send_notification(&self.config, *notifier, &notification, &values);
+        }
+        self.confirmation = Some(PendingConfirmation {
+            incident,
+            action,
+            until: Instant::now() + Duration::from_secs(timeout),
+            approved_by: None,
+        });
+        false
+    }
+
+    fn confirmation_approved(&self) -> bool {
+        self.confirmation
+            .as_ref()
+            .is_some_and(|pending| pending.approved_by.is_some())
+    }
+
+    fn resolve_confirmation(&mut self, approve: bool, peer: Option<control::Peer>) -> String {
+        let by = match peer {
+            Some(peer) => format!("控制 socket（uid {}，PID {}）", peer.uid, peer.pid),
+            None => "控制 socket".to_string(),
+        };
+        let Some(pending) = &mut self.confirmation else {
+            return "目前沒有等待確認的動作".to_string();
+        };
+        if !approve {
+            let pending = self.confirmation.take().expect("上面已確認");
+            let seconds = self.hold_after_confirmation(&pending, &format!("由{by}拒絕"));
+            return format!("已拒絕 [{}]，{seconds} 秒內不再詢問", pending.incident);
+        }
+        if pending.approved_by.is_none() {
+            log(
+                &self.config,
+                &format!(
+                    "[{}] {} 由{by}核准",
+                    pending.incident,
+                    pending.action.name()
+                ),
+            );
+            pending.approved_by = Some(by);
+        }
+        format!(
+            "已核准 [{}]，立即執行 {}",
+            pending.incident,
+            pending.action.name()
+        )
+    }
+
+    // 拒絕或逾時都以提議動作的冷卻時間暫停詢問，之後仍超標才再次詢問
+    fn hold_after_confirmation(&mut self, pending: &PendingConfirmation, resolution: &str) -> u64 {
+        let seconds = match pending.action {
+            ActionKind::Reload => self
+                .config
+                .reload_cooldown_seconds
+                .unwrap_or(self.config.cooldown_seconds),
+            ActionKind::Restart => self.config.cooldown_seconds,
+        };
+        self.confirm_hold = Some(Instant::now() + Duration::from_secs(seconds));
+        log_at(
+            &self.config,
+            LogLevel::Warn,
+            &format!(
+                "[{}] {resolution}，不執行 {}，{seconds} 秒內不再詢問",
+                pending.incident,
+                pending.action.name()
+            ),
+        );
+        seconds
+    }
+
+    fn expire_confirmation(&mut self) {
+        let expired = self.confirmation.as_ref().is_some_and(|pending| {
+            pending.approved_by.is_none() && Instant::now() >= pending.until
+        });
+        if !expired {
+            return;
+        }
+        let pending = self.confirmation.take().expect("上面已確認");
+        self.hold_after_confirmation(
+            &pending,
+            &format!(
+                "{} 秒內沒有回覆，視為拒絕",
+                self.config.confirm_timeout_seconds
+            ),
+        );
+    }
+
+    fn hold_for_policy(&mut self, seconds: u64) {
+        self.policy_hold = Some(Instant::now() + Duration::from_secs(seconds));
+        log(
+            &self.config,
+            &format!(
+                "[{}] 依策略暫不動作，{seconds} 秒後再詢問",
+                self.incident.as_deref().unwrap_or("-")
+            ),
+        );
+    }
+
+    fn policy_context(
+        &mut self,
+        incident: &str,
+        proposed: ActionKind,
+        deadline: bool,
+        x11_count: usize,
+        pids: &[i32],
+    ) -> String {
+        let optional = |value: Option<String>| value.unwrap_or_else(|| "null".to_string());
+        let list = |items: Vec<String>| format!("[{}]", items.join(","));
+        let trend = self.current_trend();
+        let churn = self.churn.rate();
+        let breaker_open = self.breaker_open();
+        let cooldown = [
+            (
+                "last_action",
+                optional(
+                    self.last_restart
+                        .map(|_| json_string(self.last_action.name())),
+                ),
+            ),
+            (
+                "seconds_since_last_action",
+                optional(
+                    self.last_restart
+                        .map(|last| last.elapsed().as_secs().to_string()),
+                ),
+            ),
+            ("cooldown_seconds", self.cooldown().as_secs().to_string()),
+            (
+                "restarts_in_breaker_window",
+                self.restart_times.len().to_string(),
+            ),
+            (
+                "breaker_max_restarts",
+                self.config.breaker_max_restarts.to_string(),
+            ),
+            ("breaker_open", breaker_open.to_string()),
+        ];
+        let fields = [
+            ("incident", json_string(incident)),
+            ("app", json_string(&self.config.app_name)),
+            ("display", json_string(&self.config.display)),
+            ("proposed_action", json_string(proposed.name())),
+            ("connections", x11_count.to_string()),
+            ("threshold", self.active_threshold().to_string()),
+            (
+                "consecutive_breaches",
+                self.consecutive_breaches.to_string(),
+            ),
+            (
+                "seconds_over_threshold",
+                optional(
+                    self.over_threshold_since
+                        .map(|since| since.elapsed().as_secs().to_string()),
+                ),
+            ),
+            ("deadline_override", deadline.to_string()),
+            (
+                "pids",
+                list(pids.iter().map(|pid| pid.to_string()).collect()),
+            ),
+            (
+                "trend_per_hour",
+                optional(trend.map(|trend| format!("{:.2}", trend.per_hour))),
+            ),
+            (
+                "forecast_seconds",
+                optional(
+                    trend
+                        .and_then(|trend| trend.eta)
+                        .map(|eta| eta.as_secs().to_string()),
+                ),
+            ),
+            (
+                "churn_opened_per_minute",
+                optional(churn.map(|rate| rate.opened_per_minute.to_string())),
+            ),
+            (
+                "churn_closed_per_minute",
+                optional(churn.map(|rate| rate.closed_per_minute.to_string())),
+            ),
+            (
+                "active_levels",
+                list(
+                    self.active_levels
+                        .iter()
+                        .map(|name| json_string(name))
+                        .collect(),
+                ),
+            ),
+            ("cooldown", json_object(&cooldown)),
+            ("dry_run", self.config.dry_run.to_string()),
+        ];
+        json_object(&fields)
+    }
+
+    // 完整重啟前的柔性重連；兩者都不採用時直接重啟
+    fn restart_after_soft_tiers(&mut self, x11_count: usize, pids: Vec<i32>) -> RestartOutcome {
+        if let Some(command) = self.config.reconnect_cmd.clone() {
+            match self.try_reconnect(&command) {
+                Some(after) if after <= self.active_threshold() => {
+                    log(
+                        &self.config,
+                        &format!(
+                            "柔性重連已解決：{} {x11_count} → {after} 條，不需重啟",
+                            self.gauge_name()
+                        ),
+                    );
+                    self.last_restart = Some(Instant::now());
+                    self.last_action = ActionKind::Restart;
+                    return RestartOutcome::Reconnected {
+                        before: x11_count,
+                        after,
+                    };
+                }
+                Some(after) => log(
+                    &self.config,
+                    &format!(
+                        "柔性重連未解決：{}仍有 {after} 條（門檻 {}），改為完整重啟",
+                        self.gauge_name(),
+                        self.active_threshold()
+                    ),
+                ),
+                None => {}
+            }
+            let pids = self.app_pids();
+            return self.execute_restart(pids);
+        }
+        self.execute_restart(pids)
+    }
+
+    // --wait-for-cmd：開機順序中 X server 或其他前置服務可能還沒就緒，進入監控前反覆執行就緒命令。
+    // 每次嘗試在 -v 時記錄；逾時時 strict 回傳錯誤，否則記錄警告後照常開始
+    fn wait_for_command(&self, command: &str) -> Result<(), String> {
+        let timeout = Duration::from_secs(self.config.wait_for_cmd_timeout_seconds);
+        let started = Instant::now();
+        let spec = template::fill(
+            user_command(command, false),
+            &self.placeholder_values("", "wait-for-cmd"),
+        );
+        log(
+            &self.config,
+            &format!(
+                "開始監控前等待就緒命令成功（最多 {} 秒）：{command}",
+                timeout.as_secs()
+            ),
+        );
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let remaining = timeout.saturating_sub(started.elapsed());
+            let result =
+                sandbox::run_output(&spec.clone().timeout(remaining.min(WAIT_FOR_CMD_ATTEMPT)));
+            if let Ok((true, _)) = result {
+                log(
+                    &self.config,
+                    &format!(
+                        "就緒命令第 {attempt} 次嘗試成功（等待 {:.1} 秒），開始監控",
+                        started.elapsed().as_secs_f64()
+                    ),
+                );
+                return Ok(());
+            }
+            if self.config.verbosity >= 1 {
+                let outcome = match result {
+                    Ok(_) => "以非 0 結束碼結束".to_string(),
+                    Err(error) if error.kind() == io::ErrorKind::TimedOut => {
+                        format!("逾時（{error}）")
+                    }
+                    Err(error) => format!("無法執行（{error}）"),
+                };
+                log(
+                    &self.config,
+                    &format!("就緒命令第 {attempt} 次嘗試{outcome}"),
+                );
+            }
+            thread::sleep(
+                timeout
+                    .saturating_sub(started.elapsed())
+                    .min(WAIT_FOR_CMD_INTERVAL),
+            );
+            if started.elapsed() >= timeout {
+                let message = format!(
+                    "就緒命令 {} 秒內 {attempt} 次嘗試都未成功",
+                    timeout.as_secs()
+                );
+                if self.config.wait_for_cmd_strict {
+                    return Err(format!("{message}（--wait-for-cmd-strict）"));
+                }
+                log_at(
+                    &self.config,
+                    LogLevel::Warn,
+                    &format!("{message}，仍照常開始監控"),
+                );
+                return Ok(());
+            }
+        }
+    }
+
+    // 人為要求不受熔斷、暫停與確認限制，但冷卻中拒絕並回報剩餘時間：剛重啟過的程式不會因為重複的要求連續被關閉
+    fn restart_now(&mut self, reason: Option<String>) -> Result<RestartOutcome, String> {
+        let reason = reason.unwrap_or_else(|| "未註明原因".to_string());
+        if let Some(remain) = self
+            .last_restart
+            .map(|last| self.cooldown().saturating_sub(last.elapsed()))
+            .filter(|remain| !remain.is_zero())
+        {
+            let message = format!(
+                "冷卻中，{} 秒後才能再次重啟 {}",
+                remain.as_secs_f64().ceil() as u64,
+                self.config.app_name
+            );
+            log_at(
+                &self.config,
+                LogLevel::Warn,
+                &format!("拒絕手動重啟要求（{reason}）：{message}"),
+            );
+            return Err(message);
+        }
+        let pids = self.app_pids();
+        log(
+            &self.config,
+            &format!(
+                "手動要求立即重啟 {}（{reason}；目前 PID: {:?}）",
+                self.config.app_name, pids
+            ),
+        );
+        self.manual_reason = Some(reason);
+        self.action_rule = None;
+        self.reload_escalated = false;
+        self.claim_action(ActionKind::Restart, false);
+        Ok(self.execute_restart(pids))
+    }
+
+    // self-upgrade 要 exec 的執行檔與暫存狀態的路徑。套件更新通常以改名取代檔案，
+    // /proc/self/exe 此時指向已刪除的舊檔，去掉 " (deleted)" 就是同一路徑上的新版
+    fn upgrade_target(&self) -> Result<(PathBuf, PathBuf), String> {
+        if self.config.seccomp {
+            return Err(
+                "--seccomp 下無法 exec 新的執行檔（過濾器會沿用到新程序），請改為重新啟動服務"
+                    .to_string(),
+            );
+        }
+        if self.config.drop_privileges.is_some() {
+            return Err("降權後 exec 會失去保留的能力，請改為重新啟動服務".to_string());
+        }
+        let state_file = self
+            .config
+            .state_file
+            .as_ref()
+            .ok_or("self-upgrade 需要 --state-file（執行期狀態暫存在它旁邊的 .resume 檔）")?;
+        let link = fs::read_link("/proc/self/exe")
+            .map_err(|error| format!("無法讀取 /proc/self/exe: {error}"))?;
+        let text = link.as_os_str().as_encoded_bytes();
+        let binary = match text.strip_suffix(b" (deleted)") {
+            Some(path) => PathBuf::from(std::ffi::OsStr::from_bytes(path)),
+            None => link,
+        };
+        let metadata = fs::metadata(&binary)
+            .map_err(|error| format!("找不到執行檔 {}: {error}", binary.display()))?;
+        if !metadata.is_file() || metadata.mode() & 0o111 == 0 {
+            return Err(format!("{} 不是可執行檔", binary.display()));
+        }
+        Ok((binary, state::sidecar(state_file, ".resume")))
+    }
+
+    // SIGHUP：先以同樣的命令列與環境重新讀取設定檔與設定目錄並檢查，有誤時沿用目前的設定；
+    // 沒問題就走 self-upgrade 的流程 exec 自己，新程序以新的設定開始並沿用冷卻等執行期狀態
+    fn reload_config(&mut self) {
+        log(&self.config, "收到 SIGHUP，重新讀取設定");
+        let result = self
+            .upgrade_target()
+            .and_then(|_| parse_args().map_err(|error| format!("設定有誤：{error}")))
+            .and_then(|_| self.self_upgrade());
+        if let Err(error) = result {
+            log_at(
+                &self.config,
+                LogLevel::Error,
+                &format!("重新載入設定失敗：{error}，沿用目前的設定"),
+            );
+        }
+    }
+
+    // 保存執行期狀態後以原本的參數 exec 新的執行檔，成功時不會返回。控制 socket 的 fd 留給新程序接手，
+    // 用戶端不會遇到 socket 暫時不存在的空窗；exec 失敗時恢復原狀，目前的程序照常監控
+    fn self_upgrade(&mut self) -> Result<(), String> {
+        let (binary, resume) = self.upgrade_target()?;
+        let mut pairs = self.snapshot();
+        if let Some(server) = &self.control {
+            pairs.push(("control_fd", server.fd().to_string()));
+        }
+        state::write_text(&resume, &state::render_pairs(&pairs))?;
+        let mut args: Vec<std::ffi::OsString> = env::args_os().collect();
+        let arg0 = args.remove(0);
+        // 再次升級時換掉前一次加上的 --resume-state
+        if let Some(index) = args.iter().position(|arg| arg == "--resume-state") {
+            args.drain(index..(index + 2).min(args.len()));
+        }
+        args.push("--resume-state".into());
+        args.push(resume.clone().into());
+        let incident = self
+            .incident
+            .as_ref()
+            .map(|id| format!("，進行中的事件 {id}"))
+            .unwrap_or_default();
+        log(
+            &self.config,
+            &format!("self-upgrade：以 {} 重新執行{incident}", binary.display()),
+        );
+        let program = CString::new(binary.as_os_str().as_bytes())
+            .map_err(|_| format!("執行檔路徑含有 NUL: {}", binary.display()))?;
+        let argv: Vec<CString> = std::iter::once(arg0)
+            .chain(args)
+            .filter_map(|arg| CString::new(arg.into_vec()).ok())
+            .collect();
+        let mut pointers: Vec<*const libc::c_char> = argv.iter().map(|arg| arg.as_ptr()).collect();
+        pointers.push(ptr::null());
+        if let Some(server) = &self.control {
+            server
+                .set_inherited(true)
+                .map_err(|error| format!("無法交接控制 socket: {error}"))?;
+        }
+        // 直接 execv：execvp 遇到 ENOEXEC（例如更新到一半的檔案）會改交給 /bin/sh 執行，守護就此消失
+        unsafe { libc::execv(program.as_ptr(), pointers.as_ptr()) };
+        let error = io::Error::last_os_error();
+        if let Some(server) = &self.control {
+            let _ = server.set_inherited(false);
+        }
+        let _ = fs::remove_file(&resume);
+        Err(format!("無法執行 {}: {error}", binary.display()))
+    }
+
+    // exec 之後仍有意義的狀態。單調時鐘的時間點換成 unix 毫秒，新程序再依當下的系統時鐘換回
+    fn snapshot(&self) -> Vec<(&'static str, String)> {
+        let mut pairs = vec![
+            ("pid", std::process::id().to_string()),
+            ("written", unix_ms(Instant::now()).to_string()),
+            ("started", unix_ms(self.stats.started).to_string()),
+            ("measurements", self.stats.measurements.to_string()),
+            ("max_connections", self.stats.max_connections.to_string()),
+            ("last_action", self.last_action.name().to_string()),
+            (
+                "consecutive_breaches",
+                self.consecutive_breaches.to_string(),
+            ),
+            ("incident_seq", self.incident_seq.to_string()),
+        ];
+        let instants = [
+            ("last_restart", self.last_restart),
+            ("over_threshold_since", self.over_threshold_since),
+            ("policy_hold", self.policy_hold),
+            ("confirm_hold", self.confirm_hold),
+        ];
+        for (key, at) in instants {
+            pairs.extend(at.map(|at| (key, unix_ms(at).to_string())));
+        }
+        pairs.extend(self.incident.clone().map(|id| ("incident", id)));
+        pairs.extend(
+            self.quarantine
+                .as_ref()
+                .map(|reason| ("quarantine", reason.replace('\n', " "))),
+        );
+        if let Some(pause) = &self.pause {
+            pairs.push((
+                "pause",
+                format!(
+                    "{} {}",
+                    unix_ms(pause.since),
+                    pause
+                        .until
+                        .map_or("-".to_string(), |at| unix_ms(at).to_string())
+                ),
+            ));
+        }
+        for at in &self.restart_times {
+            pairs.push(("restart_time", unix_ms(*at).to_string()));
+        }
+        for name in self.app_names.iter().filter(|name| !name.contains('\n')) {
+            pairs.push(("app_name", name.clone()));
+        }
+        for (name, at) in &self.rule_fired {
+            pairs.push(("rule_fired", format!("{} {name}", unix_ms(*at))));
+        }
+        for (inode, pid) in self.last_connections.iter().flatten() {
+            pairs.push(("connection", format!("{inode} {pid}")));
+        }
+        for (inode, at) in &self.connection_first_seen {
+            pairs.push(("first_seen", format!("{inode} {}", unix_ms(*at))));
+        }
+        pairs
+    }
+
+    // 回傳交接的控制 socket fd。前一個程序的 PID 必須與自己相同（exec 不改變 PID），
+    // 且狀態是剛寫入的，否則整份不採用
+    fn restore(&mut self, pairs: &[(String, String)]) -> Result<Option<RawFd>, String> {
+        let value = |key: &str| {
+            pairs
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value.as_str())
+        };
+        let number = |key: &str| value(key).and_then(|value| value.parse::<u64>().ok());
+        let instant = |key: &str| number(key).and_then(instant_from_unix_ms);
+        if number("pid") != Some(u64::from(std::process::id())) {
+            return Err(format!(
+                "狀態由 PID {} 寫入，不是 exec 前的本程序",
+                value("pid").unwrap_or("?")
+            ));
+        }
+        let written = instant("written").ok_or("缺少寫入時間")?;
+        if written.elapsed() > RESUME_MAX_AGE {
+            return Err(format!(
+                "狀態已是 {} 秒前寫入的",
+                written.elapsed().as_secs()
+            ));
+        }
+        let entries = |key: &'static str| {
+            pairs
+                .iter()
+                .filter(move |(name, _)| name == key)
+                .filter_map(|(_, value)| value.split_once(' '))
+        };
+
+        self.stats.started = instant("started").unwrap_or(self.stats.started);
+        self.stats.measurements = number("measurements").unwrap_or_default();
+        self.stats.max_connections = number("max_connections").unwrap_or_default() as usize;
+        self.last_action = match value("last_action") {
+            Some("reload") => ActionKind::Reload,
+            _ => ActionKind::Restart,
+        };
+        self.last_restart = instant("last_restart");
+        self.over_threshold_since = instant("over_threshold_since");
+        self.policy_hold = instant("policy_hold");
+        self.confirm_hold = instant("confirm_hold");
+        self.consecutive_breaches = number("consecutive_breaches").unwrap_or_default() as u32;
+        self.incident_seq = number("incident_seq").unwrap_or_default();
+        self.incident = value("incident").map(str::to_string);
+        self.quarantine = value("quarantine").map(str::to_string);
+        self.pause = value("pause")
+            .and_then(|text| text.split_once(' '))
+            .and_then(|(since, until)| {
+                Some(Pause {
+                    since: instant_from_unix_ms(since.parse().ok()?)?,
+                    until: match until {
+                        "-" => None,
+                        at => Some(instant_from_unix_ms(at.parse().ok()?)?),
+                    },
+                })
+            });
+        self.restart_times = pairs
+            .iter()
+            .filter(|(name, _)| name == "restart_time")
+            .filter_map(|(_, at)| instant_from_unix_ms(at.parse().ok()?))
+            .collect();
+        for (_, name) in pairs.iter().filter(|(key, _)| key == "app_name") {
+            if !self.app_names.contains(name) {
+                self.app_names.push(name.clone());
+            }
+        }
+        self.rule_fired = entries("rule_fired")
+            .filter_map(|(at, name)| {
+                Some((name.to_string(), instant_from_unix_ms(at.parse().ok()?)?))
+            })
+            .collect();
+        let connections: HashMap<String, i32> = entries("connection")
+            .filter_map(|(inode, pid)| Some((inode.to_string(), pid.parse().ok()?)))
+            .collect();
+        self.last_connections = (!connections.is_empty()).then_some(connections);
+        self.connection_first_seen = entries("first_seen")
+            .filter_map(|(inode, at)| {
+                Some((inode.to_string(), instant_from_unix_ms(at.parse().ok()?)?))
+            })
+            .collect();
+
+        let cooldown = self
+            .last_restart
+            .map(|at| self.cooldown().saturating_sub(at.elapsed()).as_secs())
+            .unwrap_or_default();
+        log(
+            &self.config,
+            &format!(
+                "self-upgrade：沿用先前的狀態（冷卻還剩 {cooldown} 秒，熔斷視窗內 {} 次重啟，{} 條連線的首次出現時間{}）",
+                self.restart_times.len(),
+                self.connection_first_seen.len(),
+                self.incident
+                    .as_ref()
+                    .map(|id| format!("，進行中的事件 {id}"))
+                    .unwrap_or_default()
+            ),
+        );
+        Ok(number("control_fd").map(|fd| fd as RawFd))
+    }
+
+    // 狀態檔中同一個程式與 DISPLAY、仍在冷卻內的動作：冷卻改從該動作的時間起算，回傳距今多久。
+    // 比目前記得的動作舊的不採用
+    fn adopt_state(&mut self, record: &state::Record) -> Option<Duration> {
+        let kind = match record.action.as_str() {
+            "restart" => ActionKind::Restart,
+            "reload" => ActionKind::Reload,
+            _ => return None,
+        };
+        if record.app != self.config.app_name || record.display != self.config.display {
+            return None;
+        }
+        let age = Duration::from_secs(timestamp().checked_sub(record.time)?);
+        let at = Instant::now().checked_sub(age)?;
+        if age >= self.cooldown_for(kind) || self.last_restart.is_some_and(|last| last >= at) {
+            return None;
+        }
+        self.last_restart = Some(at);
+        self.last_action = kind;
+        Some(age)
+    }
+
+    // 啟動時沿用狀態檔中的動作，守護重新啟動或由另一個程序接手時不會忽略剛做過的重啟
+    fn load_state(&mut self) {
+        let Some(path) = self.config.state_file.clone() else {
+            return;
+        };
+        let result =
+            state::lock(&path, false, STATE_LOCK_TIMEOUT).and_then(|_lock| state::read(&path));
+        match result {
+            Ok(Some(record)) => {
+                if let Some(age) = self.adopt_state(&record) {
+                    log(
+                        &self.config,
+                        &format!(
+                            "狀態檔記錄 PID {} 在 {} 秒前{}（{}），冷卻還剩 {} 秒",
+                            record.instance,
+                            age.as_secs(),
+                            record.action_text(),
+                            record.incident,
+                            self.cooldown().saturating_sub(age).as_secs()
+                        ),
+                    );
+                }
+            }
+            Ok(None) => {}
+            Err(error) => log_at(
+                &self.config,
+                LogLevel::Warn,
+                &format!("狀態檔無法使用：{error}"),
+            ),
+        }
+    }
+
+    // 動作前持有排他鎖寫入這次的動作，兩個守護程序不會同時認定自己可以動作。
+    // honor 時先重新讀取：另一個守護程序剛動作過就改為等待它的冷卻。回傳 false 表示這一輪不動作
+    fn claim_action(&mut self, kind: ActionKind, honor: bool) -> bool {
+        let Some(path) = self.config.state_file.clone() else {
+            return true;
+        };
+        let incident = self.incident_id();
+        let _lock = match state::lock(&path, true, STATE_LOCK_TIMEOUT) {
+            Ok(lock) => lock,
+            Err(error) => {
+                log_at(
+                    &self.config,
+                    LogLevel::Warn,
+                    &format!(
+                        "[{incident}] {error}，{}",
+                        if honor {
+                            "無法確認其他守護程序是否剛動作過，這一輪不動作"
+                        } else {
+                            "這次動作不寫入狀態檔"
+                        }
+                    ),
+                );
+                return !honor;
+            }
+        };
+        if honor {
+            match state::read(&path) {
+                Ok(Some(record)) if record.instance != std::process::id() => {
+                    if let Some(age) = self.adopt_state(&record) {
+                        for digest in &mut self.digests {
+                            digest.note_deferred("其他守護程序");
+                        }
+                        log(
+                            &self.config,
+                            &format!(
+                                "[{incident}] 另一個守護程序（PID {}）在 {} 秒前已{}（{}），改為等待它的冷卻，還剩 {} 秒",
+                                record.instance,
+                                age.as_secs(),
+                                record.action_text(),
+                                record.incident,
+                                self.cooldown().saturating_sub(age).as_secs()
+                            ),
+                        );
+                        return false;
+                    }
+                }
+                Ok(_) => {}
+                Err(error) => log_at(
+                    &self.config,
+                    LogLevel::Warn,
+                    &format!("[{incident}] {error}，以這次動作覆寫"),
+                ),
+            }
+        }
+        if self.config.dry_run {
+            return true;
+        }
+        let record = state::Record {
+            time: timestamp(),
+            instance: std::process::id(),
+            action: kind.name().to_string(),
+            app: self.config.app_name.clone(),
+            display: self.config.display.clone(),
+            incident,
+        };
+        if let Err(error) = state::write(&path, &record) {
+            log_at(&self.config, LogLevel::Warn, &error);
+        }
+        true
+    }
+
+    // --restore-geometry 的配置在 --state-file 旁；沒有狀態檔時只保存在記憶體中
+    fn layout_path(&self) -> Option<PathBuf> {
+        self.config
+            .state_file
+            .as_deref()
+            .filter(|_| self.config.restore_geometry)
+            .map(|path| state::sidecar(path, ".geometry"))
+    }
+
+    // --restore-geometry：送出 SIGTERM 前記下舊程序的頂層視窗。寫到狀態檔旁，
+    // 守護在關閉程序與重新啟動之間重新啟動時仍可還原
+    fn save_layout(&mut self, pids: &[i32]) {
+        if !self.config.restore_geometry {
+            return;
+        }
+        let incident = self.incident_id();
+        let (windows, failures) =
+            match layout::capture(&self.x11_endpoint, &self.config.display, pids) {
+                Ok(found) => found,
+                Err(error) => {
+                    log_at(
+                        &self.config,
+                        LogLevel::Warn,
+                        &format!("[{incident}] 無法記下視窗配置：{error}"),
+                    );
+                    return;
+                }
+            };
+        for failure in failures {
+            log_at(
+                &self.config,
+                LogLevel::Warn,
+                &format!("[{incident}] 視窗配置略過{failure}"),
+            );
+        }
+        log(
+            &self.config,
+            &format!("[{incident}] 記下 {} 個視窗的配置", windows.len()),
+        );
+        for window in &windows {
+            log(
+                &self.config,
+                &format!("[{incident}]   {}", window.describe()),
+            );
+        }
+        if let Some(path) = self.layout_path() {
+            if let Err(error) = state::write_text(&path, &layout::render(timestamp(), &windows)) {
+                log_at(&self.config, LogLevel::Warn, &error);
+            }
+        }
+        self.layout = windows;
+    }
+
+    // 啟動時沿用上一個守護程序記下、還沒還原的配置；太舊的不再還原
+    fn load_layout(&mut self) {
+        let Some(path) = self.layout_path() else {
+            return;
+        };
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return,
+            Err(error) => {
+                log_at(
+                    &self.config,
+                    LogLevel::Warn,
+                    &format!("無法讀取 {}: {error}", path.display()),
+                );
+                return;
+            }
+        };
+        match layout::parse(&text) {
+            Some((saved, windows))
+                if timestamp().saturating_sub(saved) <= LAYOUT_MAX_AGE.as_secs() =>
+            {
+                log(
+                    &self.config,
+                    &format!(
+                        "沿用 {} 秒前記下的 {} 個視窗配置，{} 的視窗出現後還原",
+                        timestamp().saturating_sub(saved),
+                        windows.len(),
+                        self.config.app_name
+                    ),
+                );
+                self.layout = windows;
+            }
+            Some(_) => {
+                log(
+                    &self.config,
+                    &format!("{} 記下的視窗配置已過時，不再還原", path.display()),
+                );
+                let _ = fs::remove_file(&path);
+            }
+            None => {
+                log_at(
+                    &self.config,
+                    LogLevel::Warn,
+                    &format!("{} 的內容無法解析，不還原視窗配置", path.display()),
+                );
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
+
+    // 等新程序的視窗出現（數量達到記下的視窗數，或 LAYOUT_WAIT 為止）後逐一放回原處。
+    // 每次都重新找程序：重啟驗證只等到第一個程序出現，其餘的可能稍後才啟動。
+    // 個別視窗失敗只記錄，不影響重啟的結果
+    fn restore_layout(&mut self) {
+        if self.layout.is_empty() {
+            return;
+        }
+        let saved = mem::take(&mut self.layout);
+        let incident = self.incident_id();
+        let deadline = Instant::now() + LAYOUT_WAIT;
+        let found = loop {
+            let pids = self.app_pids();
+            let found = layout::capture(&self.x11_endpoint, &self.config.display, &pids);
+            if found
+                .as_ref()
+                .is_ok_and(|(windows, _)| windows.len() >= saved.len())
+                || Instant::now() >= deadline
+            {
+                break found;
+            }
+            thread::sleep(LAYOUT_POLL);
+        };
+        if let Some(path) = self.layout_path() {
+            let _ = fs::remove_file(path);
+        }
+        let (current, failures) = match found {
+            Ok(found) => found,
+            Err(error) => {
+                log_at(
+                    &self.config,
+                    LogLevel::Warn,
+                    &format!("[{incident}] 無法還原視窗配置：{error}"),
+                );
+                return;
+            }
+        };
+        for failure in failures {
+            log_at(
+                &self.config,
+                LogLevel::Warn,
+                &format!("[{incident}] 視窗配置略過{failure}"),
+            );
+        }
+        let pairs = layout::pair(&saved, &current);
+        let mut restored = 0;
+        for (index, window) in saved.iter().enumerate() {
+            let Some((_, new)) = pairs.iter().find(|(old, _)| *old == index) else {
+                log_at(
+                    &self.config,
+                    LogLevel::Warn,
+                    &format!(
+                        "[{incident}] 找不到對應的新視窗，未還原：{}",
+                        window.describe()
+                    ),
+                );
+                continue;
+            };
+            let target = &current[*new];
+            match layout::apply(&self.x11_endpoint, &self.config.display, window, target) {
+                Ok(()) => {
+                    restored += 1;
+                    log(
+                        &self.config,
+                        &format!(
+                            "[{incident}]   視窗 0x{:x} 還原為 {}",
+                            target.id,
+                            window.describe()
+                        ),
+                    );
+                }
+                Err(error) => log_at(
+                    &self.config,
+                    LogLevel::Warn,
+                    &format!(
+                        "[{incident}] 無法還原視窗 0x{:x}（{}）：{error}",
+                        target.id,
+                        window.describe()
+                    ),
+                ),
+            }
+        }
+        log(
+            &self.config,
+            &format!(
+                "[{incident}] 視窗配置：已還原 {restored}/{} 個視窗",
+                saved.len()
+            ),
+        );
+    }
+
+    // --capture-dir：送出 SIGTERM 前保存舊程序的狀態，程序一結束這些資訊就消失了
+    fn capture_before_kill(&mut self, pids: &[i32]) {
+        let Some(base) = self.config.capture_dir.clone() else {
+            return;
+        };
+        // 重新量測一次，restart-now 或柔性處理之後的連線記錄可能已過時
+        let connections = self.count_connections(pids).connections;
+        let mut summary = format!(
+            "程式: {}\nDISPLAY: {}\n門檻: {}\nX11 連線: {} 條\nPID: {pids:?}\n",
+            self.config.app_name,
+            self.config.display,
+            self.config.threshold,
+            connections.len()
+        );
+        if let Some(windows) = self.windows_status() {
+            summary.push_str(&format!("視窗分類: {windows}\n"));
+        }
+        if let Some(rule) = &self.action_rule {
+            summary.push_str(&format!("觸發規則: {rule}\n"));
+        }
+        if let Some(delta) = self.incident_delta_summary() {
+            summary.push_str(&delta);
+            summary.push('\n');
+        }
+        if let Some(path) = &self.core_dump {
+            summary.push_str(&format!("core dump: {}\n", path.display()));
+        }
+        let label = self.incident.as_deref().unwrap_or("restart");
+        match capture::capture_processes(
+            &base,
+            label,
+            &self.config.proc_root,
+            pids,
+            &connections,
+            &summary,
+        ) {
+            Ok(report) => {
+                log(
+                    &self.config,
+                    &format!("已保存重啟前的程序狀態: {}", report.dir.display()),
+                );
+                if !report.failures.is_empty() {
+                    log_at(
+                        &self.config,
+                        LogLevel::Warn,
+                        &format!("部分狀態未能保存: {}", report.failures.join("；")),
+                    );
+                }
+            }
+            Err(err) => log_at(
+                &self.config,
+                LogLevel::Warn,
+                &format!(
+                    "無法在 {} 建立擷取目錄（{err}），略過保存，照常重啟",
+                    base.display()
+                ),
+            ),
+        }
+    }
+
+    // --core-dump-dir：只保存主程序（父程序不在符合清單中的那一個）。
+    // 失敗、逾時或超過大小都只記警告，重啟照常進行
+    fn dump_core_before_kill(&mut self, pids: &[i32]) {
+        let Some(dir) = self.config.core_dump_dir.clone() else {
+            return;
+        };
+        let Some(pid) = main_pid(&self.config.proc_root, pids) else {
+            return;
+        };
+        let incident = self.incident_id();
+        let limits = coredump::CoreLimits {
+            max_bytes: self
+                .config
+                .core_dump_max_bytes
+                .unwrap_or(DEFAULT_CORE_DUMP_MAX_BYTES),
+            timeout: Duration::from_secs(
+                self.config
+                    .core_dump_timeout_seconds
+                    .unwrap_or(DEFAULT_CORE_DUMP_TIMEOUT),
+            ),
+        };
+        log(
+            &self.config,
+            &format!(
+                "[{incident}] 保存 PID {pid} 的 core（時限 {} 秒）",
+                limits.timeout.as_secs()
+            ),
+        );
+        match coredump::dump_core(&dir, &incident, &self.config.proc_root, pid, &limits) {
+            Ok(dump) => {
+                log(
+                    &self.config,
+                    &format!(
+                        "[{incident}] 已保存 core dump: {}（{} MiB，耗時 {:.1} 秒）",
+                        dump.path.display(),
+                        dump.bytes >> 20,
+                        dump.elapsed.as_secs_f64()
+                    ),
+                );
+                self.core_dump = Some(dump.path);
+            }
+            Err(reason) => log_at(
+                &self.config,
+                LogLevel::Warn,
+                &format!("[{incident}] 無法保存 core dump（{reason}），照常重啟"),
+            ),
+        }
+        let keep = self.config.core_dump_keep.unwrap_or(DEFAULT_CORE_DUMP_KEEP);
+        for removed in coredump::prune(&dir, keep) {
+            log(
+                &self.config,
+                &format!("刪除較舊的 core dump: {}", removed.display()),
+            );
+        }
+    }
+
+    fn execute_restart(&mut self, pids: Vec<i32>) -> RestartOutcome {
+        self.restart_times.push_back(Instant::now());
+        if self.config.dry_run {
+            let plan = if self.config.group_members.is_empty() {
+                String::new()
+            } else {
+                format!(
+                    "（群組：停止 {}，啟動 {}）",
+                    self.config.group_stop_order.join(" → "),
+                    self.config.group_start_order.join(" → ")
+                )
+            };
+            log(
+                &self.config,
+                &format!("dry-run 模式：不會實際重啟程序{plan}"),
+            );
+            self.last_restart = Some(Instant::now());
+            self.last_action = ActionKind::Restart;
+            return RestartOutcome::DryRun;
+        }
+
+        self.core_dump = None;
+        // 接下來由本程式關閉的程序不算快速結束
+        self.crash.forget();
+        if !pids.is_empty() {
+            self.dump_core_before_kill(&pids);
+            self.capture_before_kill(&pids);
+            self.save_layout(&pids);
+        }
+        if !self.config.group_members.is_empty() {
+            return self.restart_group(pids);
+        }
+        let previous = self.process_identities(&pids);
+        self.stop_pids(&pids);
+        self.last_restart = Some(Instant::now());
+        self.last_action = ActionKind::Restart;
+        match self.start_primary(&previous) {
+            Ok(new_pids) => RestartOutcome::Restarted {
+                old_pids: pids,
+                new_pids,
+            },
+            Err(reason) => self.restart_failed(reason),
+        }
+    }
+
+    // 依 --escalate 逐級送出訊號（預設 SIGTERM，逾時改送 SIGKILL），
+    // 每一級只送給仍存活的 PID，全部結束就停；回傳最後一級後仍存活的 PID
+    fn stop_pids(&mut self, pids: &[i32]) -> Vec<i32> {
+        let mut remaining = pids.to_vec();
+        let ladder = self.config.stop_ladder.clone();
+        let mut previous: Option<StopStep> = None;
+        for step in &ladder {
+            if remaining.is_empty() {
+                return remaining;
+            }
+            if let Some(previous) = previous {
+                log(
+                    &self.config,
+                    &format!(
+                        "PID {remaining:?} 在 {} 後 {} 秒內未結束，改送 {}",
+                        signal_name(previous.signal),
+                        previous.wait.as_secs(),
+                        signal_name(step.signal)
+                    ),
+                );
+            }
+            self.signal_pids(&remaining, step.signal);
+            remaining =
+                wait_until_gone(&self.config.proc_root, &remaining, &self.pidfds, step.wait);
+            previous = Some(*step);
+        }
+        if let (Some(last), false) = (previous, remaining.is_empty()) {
+            log_at(
+                &self.config,
+                LogLevel::Warn,
+                &format!(
+                    "{} 後仍未結束的 PID: {remaining:?}",
+                    signal_name(last.signal)
+                ),
+            );
+        }
+        remaining
+    }
+
+    fn start_primary(&mut self, previous: &HashSet<(i32, u64)>) -> Result<Vec<i32>, String> {
+        let values = self.action_placeholders();
+        let spawned = start_process(&self.config.restart_cmd, &values)
+            .map_err(|err| format!("無法執行重啟命令 {}: {err}", self.config.restart_cmd))?
+            as i32;
+        log(
+            &self.config,
+            &format!("已執行重啟命令: {}", self.config.restart_cmd),
+        );
+
+        let mut new_pids = wait_until_started(
+            &self.config.proc_root,
+            &self.app_names,
+            &self.config.cmdline_match,
+            &self.x11_endpoint,
+            previous,
+            Duration::from_secs(self.config.verify_timeout_seconds),
+        );
+        // 只看命令列或以 regex 比對名稱時 --app-name 不影響比對，沒有名稱可學
+        if new_pids.is_empty()
+            && !matches_by_cmdline_only(&self.config.cmdline_match)
+            && name_patterns(&self.config.cmdline_match).next().is_none()
+        {
+            new_pids = self.learn_launched_names(spawned);
+        }
+        if new_pids.is_empty() {
+            return Err(format!(
+                "重啟後 {} 秒內未見 {} 重新出現",
+                self.config.verify_timeout_seconds, self.config.app_name
+            ));
+        }
+        log(&self.config, &format!("重啟驗證完成，新 PID: {new_pids:?}"));
+        self.restore_layout();
+        // 由本程式帶起的新一代不算外部重啟
+        self.generation = self.process_identities(&new_pids);
+        self.crash.launched(self.generation.clone(), Instant::now());
+        self.trend.reset();
+        Ok(new_pids)
+    }
+
+    fn start_member(
+        &mut self,
+        member: &GroupMember,
+        previous: &HashSet<(i32, u64)>,
+    ) -> Result<Vec<i32>, String> {
+        let values = self.action_placeholders();
+        start_process(&member.restart_cmd, &values)
+            .map_err(|err| format!("無法執行 {}: {err}", member.restart_cmd))?;
+        let found = wait_until_started(
+            &self.config.proc_root,
+            std::slice::from_ref(&member.name),
+            &[],
+            &self.x11_endpoint,
+            previous,
+            Duration::from_secs(self.config.verify_timeout_seconds),
+        );
+        if found.is_empty() {
+            return Err(format!(
+                "{} 秒內未見 {} 出現",
+                self.config.verify_timeout_seconds, member.name
+            ));
+        }
+        Ok(found)
+    }
+
+    // 重啟群組：依停止順序逐一結束、依啟動順序逐一啟動並驗證。某個成員失敗不中斷其餘成員，
+    // 最後逐一列出各成員的結果；主程式沒有回來才算重啟失敗，只有附屬成員失敗時另外通知
+    fn restart_group(&mut self, pids: Vec<i32>) -> RestartOutcome {
+        let mut results = Vec::new();
+        let mut failed = BTreeSet::new();
+        let mut previous: HashMap<String, HashSet<(i32, u64)>> = HashMap::new();
+        for name in self.config.group_stop_order.clone() {
+            let member_pids = if name == self.config.app_name {
+                pids.clone()
+            } else {
+                find_pids_by_name(&self.config.proc_root, std::slice::from_ref(&name), &[])
+            };
+            previous.insert(name.clone(), self.process_identities(&member_pids));
+            let survivors = self.stop_pids(&member_pids);
+            let text = if survivors.is_empty() {
+                format!("{name} 停止：通過（PID {member_pids:?}）")
+            } else {
+                failed.insert(name.clone());
+                format!("{name} 停止：失敗（SIGKILL 後仍存活 {survivors:?}）")
+            };
+            log(&self.config, &format!("群組成員 {text}"));
+            results.push(text);
+        }
+        // 前一次群組重啟帶起的成員是本程序的子程序，先回收，殭屍才不會被當成新啟動的程序
+        sandbox::reap_children();
+        self.last_restart = Some(Instant::now());
+        self.last_action = ActionKind::Restart;
+
+        let mut new_pids = Vec::new();
+        for name in self.config.group_start_order.clone() {
+            let previous = previous.remove(&name).unwrap_or_default();
+            let started = if name == self.config.app_name {
+                self.start_primary(&previous)
+            } else {
+                let member = self
+                    .config
+                    .group_members
+                    .iter()
+                    .find(|member| member.name == name)
+                    .cloned()
+                    .expect("resolve_group_order 已確認成員存在");
+                self.start_member(&member, &previous)
+            };
+            let text = match started {
+                Ok(found) => {
+                    let text = format!("{name} 啟動：通過（新 PID {found:?}）");
+                    if name == self.config.app_name {
+                        new_pids = found;
+                    }
+                    text
+                }
+                Err(reason) => {
+                    failed.insert(name.clone());
+                    format!("{name} 啟動：失敗（{reason}）")
+                }
+            };
+            log(&self.config, &format!("群組成員 {text}"));
+            results.push(text);
+        }
+
+        for name in &failed {
+            *self.stats.member_failures.entry(name.clone()).or_default() += 1;
+        }
+        let summary = format!("群組重啟結果：{}", results.join("；"));
+        if new_pids.is_empty() {
+            return self.restart_failed(summary);
+        }
+        if failed.is_empty() {
+            log(&self.config, &summary);
+        } else {
+            let message = format!(
+                "群組重啟部分失敗（{}）— {} 已重啟，但群組不完整：{summary}",
+                failed.iter().cloned().collect::<Vec<_>>().join("、"),
+                self.config.app_name
+            );
+            log_at(&self.config, LogLevel::Error, &message);
+            self.notify_failure("group-member-failed", &message);
+        }
+        RestartOutcome::Restarted {
+            old_pids: pids,
+            new_pids,
+        }
+    }
+
+    // 重啟命令帶起的程序名稱與 --app-name 不同時，之後就再也找不到程式：大聲警告，
+    // 開啟 --learn-new-name 時把觀察到的名稱加入比對，回傳加入後找到的 PID
+    fn learn_launched_names(&mut self, spawned: i32) -> Vec<i32> {
+        let observed = process_tree_names(&self.config.proc_root, spawned);
+        if observed.is_empty() {
+            return Vec::new();
+        }
+        log_at(
+            &self.config,
+            LogLevel::Error,
+            &format!(
+                "重啟命令帶起的程序名稱為 {}，與 --app-name {} 不符，重啟後將無法再找到並保護它",
+                observed.join("、"),
+                self.config.app_name
+            ),
+        );
+        if !self.config.learn_new_name {
+            log_at(
+                &self.config,
+                LogLevel::Error,
+                "請修正 --app-name，或加上 --learn-new-name 讓守護自動加入觀察到的名稱",
+            );
+            return Vec::new();
+        }
+        for name in observed {
+            if !self.app_names.contains(&name) {
+                log(
+                    &self.config,
+                    &format!("--learn-new-name：本次執行期間改為同時比對 {name}"),
+                );
+                self.app_names.push(name);
+            }
+        }
+        find_app_pids(
+            &mut self.fd_scanner,
+            &self.app_names,
+            &self.config.cmdline_match,
+            &self.x11_endpoint,
+        )
+        .0
+    }
+
+    // 定期摘要用的狀態代號，依影響大小取一個
+    fn summary_state(&mut self) -> &'static str {
+        if self.crash.looping_since.is_some() {
+            return "crash-loop";
+        }
+        if self.quarantine.is_some() {
+            return "quarantined";
+        }
+        if self.pause.is_some() {
+            return "paused";
+        }
+        if self.flap.flapping_since.is_some() {
+            return "flapping";
+        }
+        if self.x_health.unhealthy_since.is_some() {
+            return "x-server";
+        }
+        if self.load_deferred_since.is_some() {
+            return "load";
+        }
+        if self.active_deferred_since.is_some() {
+            return "active";
+        }
+        if self.idle_hold_since.is_some() {
+            return "idle";
+        }
+        if self.plateau.as_ref().is_some_and(|wait| !wait.settled) {
+            return "plateau";
+        }
+        if self.confirmation.is_some() {
+            return "confirm";
+        }
+        match self.time_until_eligible() {
+            Some((_, gate)) => gate.name(),
+            None => "monitoring",
+        }
+    }
+
+    fn log_interval_summary(&mut self) {
+        let now = Instant::now();
+        let state = self.summary_state();
+        let current = self.last_connections.as_ref().map(HashMap::len);
+        let line = self.interval.line(now, state, current, &self.active_levels);
+        log(&self.config, &line);
+        self.interval = IntervalSummary::new(now);
+    }
+
+    fn pause_text(&self) -> String {
+        match &self.pause {
+            None => "監控中".to_string(),
+            Some(Pause { until: None, .. }) => "已暫停（直到 resume）".to_string(),
+            Some(Pause {
+                until: Some(until), ..
+            }) => format!(
+                "已暫停（剩餘 {} 秒）",
+                until.saturating_duration_since(Instant::now()).as_secs() + 1
+            ),
+        }
+    }
+
+    fn expire_pause(&mut self) {
+        if let Some(Pause {
+            since,
+            until: Some(until),
+        }) = &self.pause
+        {
+            if Instant::now() >= *until {
+                let paused = since.elapsed().as_secs();
+                self.end_pause();
+                log(
+                    &self.config,
+                    &format!("暫停期滿（共 {paused} 秒），恢復監控動作"),
+                );
+            }
+        }
+    }
+
+    fn end_pause(&mut self) -> Option<Pause> {
+        let pause = self.pause.take()?;
+        self.stats.paused += pause.since.elapsed();
+        Some(pause)
+    }
+
+    fn handle_control(&mut self) -> Option<RestartOutcome> {
+        let server = self.control.take()?;
+        let approved = self.confirmation_approved();
+        server.serve(|line, peer| self.control_command(line, peer));
+        self.control = Some(server);
+        if mem::take(&mut self.upgrade_requested) {
+            if let Err(error) = self.self_upgrade() {
+                log_at(
+                    &self.config,
+                    LogLevel::Error,
+                    &format!("self-upgrade 失敗：{error}，繼續以目前的執行檔監控"),
+                );
+            }
+        }
+        // 剛核准的動作立即重新檢查，不等下一次輪詢；仍超標才會真的動作
+        if approved || !self.confirmation_approved() {
+            return None;
+        }
+        self.check_threshold("approve", None)
+    }
+
+    fn control_command(&mut self, line: &str, peer: Option<control::Peer>) -> String {
+        let command = match control::parse_command(line) {
+            Ok(value) => value,
+            Err(error) => return format!("錯誤: {error}"),
+        };
+        match command {
+            control::ControlCommand::Status => self.status_text(),
+            control::ControlCommand::Metrics => self.metrics_text(),
+            control::ControlCommand::Pause(seconds) => {
+                self.end_pause();
+                let now = Instant::now();
+                self.pause = Some(Pause {
+                    since: now,
+                    until: seconds.map(|value| now + Duration::from_secs(value)),
+                });
+                let text = match seconds {
+                    Some(value) => format!("暫停監控動作 {value} 秒"),
+                    None => "暫停監控動作，直到 resume".to_string(),
+                };
+                log(&self.config, &format!("控制 socket：{text}"));
+                format!("已{text}")
+            }
+            control::ControlCommand::Resume => match self.end_pause() {
+                Some(pause) => {
+                    log(
+                        &self.config,
+                        &format!(
+                            "控制 socket：恢復監控動作（已暫停 {} 秒）",
+                            pause.since.elapsed().as_secs()
+                        ),
+                    );
+                    "已恢復監控動作".to_string()
+                }
+                None => "目前未暫停".to_string(),
+            },
+            // 手動要求不受熔斷與暫停限制，冷卻中則拒絕；執行後和自動重啟一樣會開始新的冷卻
+            control::ControlCommand::Restart(reason) => {
+                log(&self.config, "控制 socket：收到立即重啟要求");
+                let outcome = match self.restart_now(reason) {
+                    Ok(outcome) => outcome,
+                    Err(message) => return format!("未重啟: {message}"),
+                };
+                self.record_outcome(&outcome, true);
+                match outcome {
+                    RestartOutcome::Restarted { old_pids, new_pids } => format!(
+                        "已重啟 {}：舊 PID {old_pids:?} → 新 PID {new_pids:?}",
+                        self.config.app_name
+                    ),
+                    RestartOutcome::Failed(reason) => format!("重啟失敗: {reason}"),
+                    _ => "dry-run 模式：未實際重啟".to_string(),
+                }
+            }
+            control::ControlCommand::Measure(json) => self.measure_now(json),
+            control::ControlCommand::Clear => self.clear_failure(),
+            control::ControlCommand::Approve => self.resolve_confirmation(true, peer),
+            control::ControlCommand::Deny => self.resolve_confirmation(false, peer),
+            control::ControlCommand::SelfUpgrade => match self.upgrade_target() {
+                Ok((binary, resume)) => {
+                    log(&self.config, "控制 socket：收到 self-upgrade 要求");
+                    self.upgrade_requested = true;
+                    format!(
+                        "將以 {} 重新執行（PID 不變），執行期狀態暫存於 {}",
+                        binary.display(),
+                        resume.display()
+                    )
+                }
+                Err(error) => format!("錯誤: {error}"),
+            },
+        }
+    }
+
+    // 控制 socket 的 measure：不等下一次輪詢，立即重新量測並回覆。控制命令在主迴圈中依序處理，
+    // 不會和排程的量測同時進行；結果不寫入連線記錄與統計、不觸發動作，也不改變下一次檢查的時間
+    fn measure_now(&mut self, json: bool) -> String {
+        let pids = self.app_pids();
+        let counted = self.count_connections(&pids);
+        let counted_toward_threshold = self.threshold_count(&counted.connections);
+        let over = counted_toward_threshold > self.config.threshold;
+        let mut per_pid: BTreeMap<i32, usize> = pids.iter().map(|pid| (*pid, 0)).collect();
+        for (_, pid) in &counted.connections {
+            *per_pid.entry(*pid).or_default() += 1;
+        }
+        let elapsed_ms = self.measure.last.unwrap_or_default().as_secs_f64() * 1000.0;
+        if json {
+            let list = |items: Vec<String>| format!("[{}]", items.join(","));
+            return json_object(&[
+                ("app", json_string(&self.config.app_name)),
+                ("display", json_string(&self.config.display)),
+                ("connections", counted.connections.len().to_string()),
+                ("counted", counted_toward_threshold.to_string()),
+                ("threshold", self.config.threshold.to_string()),
+                ("over_threshold", over.to_string()),
+                (
+                    "last_over_threshold",
+                    self.last_over_threshold
+                        .map_or("null".to_string(), |at| unix_seconds(at).to_string()),
+                ),
+                (
+                    "pids",
+                    list(
+                        per_pid
+                            .iter()
+                            .map(|(pid, count)| {
+                                json_object(&[
+                                    ("pid", pid.to_string()),
+                                    ("connections", count.to_string()),
+                                ])
+                            })
+                            .collect(),
+                    ),
+                ),
+                (
+                    "unreadable",
+                    list(counted.unreadable.iter().map(i32::to_string).collect()),
+                ),
+                ("measure_ms", format!("{elapsed_ms:.1}")),
+            ]);
+        }
+        if pids.is_empty() {
+            return format!("找不到 {} 的程序", self.config.app_name);
+        }
+        let mut lines = vec![format!(
+            "X11 連線: {} 條{}（門檻 {}，{}）",
+            counted.connections.len(),
+            self.aged_text(),
+            self.config.threshold,
+            if over { "超標" } else { "未超標" }
+        )];
+        lines.extend(
+            per_pid
+                .iter()
+                .map(|(pid, count)| format!("PID {pid}: {count} 條")),
+        );
+        if !counted.unreadable.is_empty() {
+            lines.push(format!("無法讀取（未計入）: PID {:?}", counted.unreadable));
+        }
+        lines.push(format!("量測耗時: {elapsed_ms:.1} ms"));
+        lines.join("\n")
+    }
+
+    fn status_text(&mut self) -> String {
+        let count = match &self.last_connections {
+            Some(connections) => format!("{} 條{}", connections.len(), self.aged_text()),
+            None if !self.unreadable.is_empty() => "未知（所有程序的 fd 都無法讀取）".to_string(),
+            None => "尚未量測".to_string(),
+        };
+        let mut lines = vec![
+            format!("狀態: {}", self.pause_text()),
+            format!(
+                "DISPLAY={} 程式={} 門檻={}（{}）",
+                self.config.display,
+                self.config.app_name,
+                self.config.threshold,
+                self.config.threshold_scope.name()
+            ),
+            format!("X11 連線: {count}"),
+        ];
+        // 崩潰循環期間不會自動重啟，放在最前面
+        if let Some(text) = self.crash_loop_text() {
+            lines.insert(1, format!("崩潰循環: {text}"));
+        }
+        if let Some((pid, count)) = self.offender {
+            lines.push(format!("連線最多的程序: PID {pid}（{count} 條）"));
+        }
+        if let Some(windows) = self.windows_status() {
+            lines.push(format!("視窗分類: {windows}"));
+        }
+        lines.extend([
+            format!(
+                "連線變動: {}",
+                self.churn
+                    .rate()
+                    .map_or("資料不足".to_string(), |rate| rate.text())
+            ),
+            format!(
+                "成長趨勢: {}",
+                self.current_trend()
+                    .map_or("資料不足".to_string(), |trend| trend.text())
+            ),
+            format!("可再動作: {}", self.eligibility_text()),
+        ]);
+        if let Some(last) = self.measure.last {
+            lines.push(format!("量測耗時: {:.1} ms", last.as_secs_f64() * 1000.0));
+        }
+        if let Some(pending) = &self.confirmation {
+            let state = match &pending.approved_by {
+                Some(by) => format!("已由{by}核准，等待執行"),
+                None => format!(
+                    "剩 {} 秒",
+                    pending
+                        .until
+                        .saturating_duration_since(Instant::now())
+                        .as_secs()
+                ),
+            };
+            lines.push(format!(
+                "等待確認: [{}] 提議 {}，{state}",
+                pending.incident,
+                pending.action.name()
+            ));
+        }
+        if let Some(settings) = self.flap_settings() {
+            let state = match self.flap.flapping_since {
+                Some(since) => format!(
+                    "flapping 已 {} 秒，暫停自動動作；{}",
+                    since.elapsed().as_secs(),
+                    match self.flap.calm_since {
+                        Some(calm) => format!(
+                            "不超過 {} 條已 {} 秒，滿 {} 秒解除",
+                            settings.clear_at,
+                            calm.elapsed().as_secs(),
+                            settings.dwell.as_secs()
+                        ),
+                        None => format!(
+                            "需連續 {} 秒不超過 {} 條或 ctl clear 才解除",
+                            settings.dwell.as_secs(),
+                            settings.clear_at
+                        ),
+                    }
+                ),
+                None => "正常".to_string(),
+            };
+            lines.push(format!("反覆跨越: {state}（{}）", self.flap_rate_text()));
+        }
+        if !self.digests.is_empty() {
+            let digests: Vec<String> = self.digests.iter().map(NotifyDigest::status_text).collect();
+            lines.push(format!("通知摘要: {}", digests.join("；")));
+        }
+        if let Some(since) = self.load_deferred_since {
+            lines.push(format!(
+                "系統負載延後: 已 {} 秒（最多 {} 秒）",
+                since.elapsed().as_secs(),
+                self.config
+                    .max_load_wait_seconds
+                    .unwrap_or(DEFAULT_MAX_LOAD_WAIT)
+            ));
+        }
+        if self.config.defer_while_active {
+            let state = match self.active_deferred_since {
+                Some(since) => format!(
+                    "已延後 {} 秒（最多 {} 秒）",
+                    since.elapsed().as_secs(),
+                    self.config
+                        .active_max_wait_seconds
+                        .unwrap_or(DEFAULT_ACTIVE_MAX_WAIT)
+                ),
+                None => "未延後".to_string(),
+            };
+            let sample = match &self.last_activity {
+                Some((at, activity)) => {
+                    format!("{}，{} 秒前取樣", activity.text(), at.elapsed().as_secs())
+                }
+                None => "準備動作時才取樣".to_string(),
+            };
+            lines.push(format!("使用中延後: {state}；{sample}"));
+        }
+        if let Some(needed) = self.config.only_when_idle_seconds {
+            let limit = self
+                .config
+                .idle_max_hold_seconds
+                .unwrap_or(DEFAULT_IDLE_MAX_HOLD);
+            // 保留中時重新查詢，顯示的是目前的閒置時間
+            if self.idle_hold_since.is_some() {
+                let reading = session_idle(&self.config, &self.x11_endpoint, self.app_session);
+                self.last_idle = Some((Instant::now(), reading));
+            }
+            let state = match self.idle_hold_since {
+                Some(since) => {
+                    let remaining = Duration::from_secs(limit).saturating_sub(since.elapsed());
+                    format!(
+                        "保留中，已 {} 秒，最晚 {}（{} 秒後）照常動作",
+                        since.elapsed().as_secs(),
+                        wall_clock(SystemTime::now() + remaining),
+                        remaining.as_secs()
+                    )
+                }
+                None => "未保留".to_string(),
+            };
+            let idle = match &self.last_idle {
+                Some((at, reading)) => {
+                    format!(
+                        "{}，{} 秒前查詢",
+                        idle_text(reading),
+                        at.elapsed().as_secs()
+                    )
+                }
+                None => "準備動作時才查詢".to_string(),
+            };
+            lines.push(format!("等待閒置: {state}；{idle}，需閒置 {needed} 秒"));
+        }
+        if let Some(path) = &self.config.state_file {
+            let record =
+                state::lock(path, false, STATE_LOCK_TIMEOUT).and_then(|_lock| state::read(path));
+            let text = match record {
+                Ok(Some(record)) => format!(
+                    "最近一次為 PID {}{} {} 秒前{}（{}）",
+                    record.instance,
+                    if record.instance == std::process::id() {
+                        "（本程序）"
+                    } else {
+                        ""
+                    },
+                    timestamp().saturating_sub(record.time),
+                    record.action_text(),
+                    record.incident
+                ),
+                Ok(None) => "尚無紀錄".to_string(),
+                Err(error) => error,
+            };
+            lines.push(format!(
+                "狀態檔: {}{}；{text}",
+                path.display(),
+                if self.config.state_sync {
+                    "（動作前同步）"
+                } else {
+                    ""
+                }
+            ));
+        }
+        if let Some(store) = &self.history {
+            lines.push(format!("歷史記錄: {}", history::status_text(store)));
+        }
+        if !self.layout.is_empty() {
+            lines.push(format!(
+                "視窗配置: 記下 {} 個視窗，{} 的視窗出現後還原",
+                self.layout.len(),
+                self.config.app_name
+            ));
+        }
+        if let (Some(percent), Some(usage)) = (self.config.max_cpu_percent, self.cpu.last_usage) {
+            let state = match self.cpu.slowed_since {
+                Some(since) => format!(
+                    "，輪詢放慢為 {} 倍（{} 秒前開始）",
+                    self.cpu.factor,
+                    since.elapsed().as_secs()
+                ),
+                None => String::new(),
+            };
+            lines.push(format!(
+                "自身 CPU: 近 {} 秒 {usage:.1}%（上限 {percent}%）{state}",
+                self.config.max_cpu_window_seconds
+            ));
+        }
+        if let Some((at, result)) = &self.x_health.last {
+            let state = match (result, self.x_health.unhealthy_since) {
+                (Err(error), Some(since)) => format!(
+                    "無回應已 {} 秒，暫緩自動動作（{error}）",
+                    since.elapsed().as_secs()
+                ),
+                (Err(error), None) => {
+                    format!("失敗 {} 次（{error}）", self.x_health.consecutive_failures)
+                }
+                (Ok(elapsed), _) => format!("正常（{:.1} ms）", elapsed.as_secs_f64() * 1000.0),
+            };
+            lines.push(format!(
+                "X server 探測: {state}，{} 秒前",
+                at.elapsed().as_secs()
+            ));
+        }
+        if let Some(wait) = &self.plateau {
+            let state = match (wait.settled, wait.climbing) {
+                (true, _) => "已結束".to_string(),
+                (false, true) => "上升中".to_string(),
+                (false, false) => format!("已持平 {} 秒", wait.steady_since.elapsed().as_secs()),
+            };
+            lines.push(format!(
+                "等待持平: {state}（峰值 {} 條，已等待 {} 秒）",
+                wait.peak,
+                wait.since.elapsed().as_secs()
+            ));
+        }
+        if let Some(since) = self.over_threshold_since {
+            let deadline = self
+                .config
+                .deadline_restart_seconds
+                .map(|limit| format!("（--deadline-restart {limit} 秒）"))
+                .unwrap_or_default();
+            lines.push(format!(
+                "連續超標: {} 秒{deadline}",
+                since.elapsed().as_secs()
+            ));
+        }
+        if let Some(at) = self.last_over_threshold {
+            let mut line = format!("上次超標: {}", wall_clock_ago(at));
+            if self.last_over_by_name.len() > 1 {
+                let names: Vec<String> = self
+                    .last_over_by_name
+                    .iter()
+                    .map(|(name, at)| format!("{name} {}", wall_clock_ago(*at)))
+                    .collect();
+                line.push_str(&format!("；{}", names.join("、")));
+            }
+            lines.push(line);
+        }
+        if let Some(last) = self.last_restart {
+            let mut action = match (self.last_action, &self.manual_reason) {
+                (ActionKind::Restart, Some(reason)) => format!("手動重啟（{reason}）"),
+                (ActionKind::Restart, None) => "重啟".to_string(),
+                (ActionKind::Reload, _) => {
+                    format!("重新載入（{}）", signal_name(self.config.reload_signal))
+                }
+            };
+            if let Some(rule) = &self.action_rule {
+                action.push_str(&format!("，由規則 {rule} 觸發"));
+            }
+            lines.push(format!(
+                "上次動作: {action}，{} 秒前",
+                last.elapsed().as_secs()
+            ));
+        }
+        if self.last_connections.is_some() && !self.unreadable.is_empty() {
+            if self.xres_fallback == Some(Ok(())) {
+                lines.push(format!(
+                    "無法讀取: PID {:?}，改以 X-Resource 回報的 client 計數",
+                    self.unreadable
+                ));
+            } else {
+                lines.push(format!("無法讀取（未計入）: PID {:?}", self.unreadable));
+            }
+        }
+        if let Some(reason) = &self.quarantine {
+            lines.push(format!("隔離中: {reason}"));
+        }
+        if let Some(failure) = &self.failure {
+            let handled = if failure.handled.is_empty() {
+                String::new()
+            } else {
+                format!("；{}", failure.handled.join("、"))
+            };
+            lines.push(format!(
+                "重啟失敗: [{}] {} 秒前{handled}（程式重新出現或 ctl clear 時清除）",
+                failure.incident,
+                failure.since.elapsed().as_secs()
+            ));
+        }
+        lines.join("\n")
+    }
+
+    // Prometheus 文字格式，供 node_exporter textfile 或抓取腳本使用；尚未量測或資料不足的項目不輸出
+    fn metrics_text(&mut self) -> String {
+        let mut gauges = vec![(
+            "qq_x11_guard_threshold",
+            "超標門檻",
+            self.config.threshold as f64,
+        )];
+        if let Some(connections) = &self.last_connections {
+            gauges.push((
+                "qq_x11_guard_connections",
+                "最近一次量測的 X11 連線數",
+                connections.len() as f64,
+            ));
+        }
+        if let Some(split) = self.windows {
+            gauges.push((
+                "qq_x11_guard_windowed_connections",
+                "最近一次量測中擁有視窗的 X11 連線數",
+                split.windowed as f64,
+            ));
+            gauges.push((
+                "qq_x11_guard_windowless_connections",
+                "最近一次量測中不擁有任何視窗的 X11 連線數",
+                split.windowless as f64,
+            ));
+        }
+        if let Some(trend) = self.current_trend() {
+            gauges.push((
+                "qq_x11_guard_trend_per_hour",
+                "連線數成長趨勢（每小時條數）",
+                trend.per_hour,
+            ));
+            if let Some(eta) = trend.eta {
+                gauges.push((
+                    "qq_x11_guard_forecast_seconds",
+                    "預估超過門檻的剩餘秒數",
+                    eta.as_secs_f64().round(),
+                ));
+            }
+        }
+        if let Some((_, result)) = &self.x_health.last {
+            gauges.push((
+                "qq_x11_guard_x_server_up",
+                "X server 是否回應探測（判定無回應時為 0）",
+                if self.x_health.unhealthy_since.is_some() {
+                    0.0
+                } else {
+                    1.0
+                },
+            ));
+            gauges.push((
+                "qq_x11_guard_x_probe_failures",
+                "X server 探測連續失敗的次數",
+                self.x_health.consecutive_failures as f64,
+            ));
+            if let Ok(elapsed) = result {
+                gauges.push((
+                    "qq_x11_guard_x_probe_seconds",
+                    "最近一次成功探測的耗時（秒）",
+                    elapsed.as_secs_f64(),
+                ));
+            }
+        }
+        if let Some(settings) = self.flap_settings() {
+            gauges.push((
+                "qq_x11_guard_flapping",
+                "是否處於 flapping 狀態（暫停自動動作時為 1）",
+                if self.flap.flapping_since.is_some() {
+                    1.0
+                } else {
+                    0.0
+                },
+            ));
+            gauges.push((
+                "qq_x11_guard_flap_crossings",
+                "--flap-window 內跨越門檻的次數",
+                self.flap.rate(&settings, Instant::now()) as f64,
+            ));
+            if let Some(since) = self.flap.flapping_since {
+                gauges.push((
+                    "qq_x11_guard_flapping_seconds",
+                    "進入 flapping 狀態至今的秒數",
+                    since.elapsed().as_secs() as f64,
+                ));
+            }
+        }
+        if let Some(settings) = self.crash_settings() {
+            gauges.push((
+                "qq_x11_guard_crash_loop",
+                "是否處於崩潰循環（不再自動重啟時為 1）",
+                if self.crash.looping_since.is_some() {
+                    1.0
+                } else {
+                    0.0
+                },
+            ));
+            gauges.push((
+                "qq_x11_guard_rapid_exits",
+                "--crash-loop-window 內重啟後快速結束的次數",
+                self.crash.recent(&settings, Instant::now()).len() as f64,
+            ));
+            if let Some(since) = self.crash.looping_since {
+                gauges.push((
+                    "qq_x11_guard_crash_loop_seconds",
+                    "進入崩潰循環至今的秒數",
+                    since.elapsed().as_secs() as f64,
+                ));
+            }
+        }
+        if let Some(at) = self.last_over_threshold {
+            gauges.push((
+                "qq_x11_guard_last_over_threshold_timestamp_seconds",
+                "最近一次量測超過門檻的時間（unix 秒數）",
+                unix_seconds(at) as f64,
+            ));
+        }
+        if let Some(rate) = self.churn.rate() {
+            gauges.push((
+                "qq_x11_guard_connections_opened_per_minute",
+                "近期每分鐘新開的 X11 連線數",
+                rate.opened_per_minute as f64,
+            ));
+            gauges.push((
+                "qq_x11_guard_connections_closed_per_minute",
+                "近期每分鐘關閉的 X11 連線數",
+                rate.closed_per_minute as f64,
+            ));
+        }
+        if let Some(last) = self.measure.last {
+            gauges.push((
+                "qq_x11_guard_measure_seconds",
+                "最近一次量測的耗時（秒）",
+                last.as_secs_f64(),
+            ));
+        }
+        let app = self
+            .config
+            .app_name
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"");
+        let mut sections: Vec<String> = gauges
+            .iter()
+            .map(|(name, help, value)| {
+                format!(
+                    "# HELP {name} {help}\n# TYPE {name} gauge\n{name}{{app=\"{app}\"}} {value}"
+                )
+            })
+            .collect();
+        // 可再動作前的剩餘秒數，gate 標籤是最晚解除的條件；目前即可動作時為 0 且 gate="none"
+        let (remain, gate) = match self.time_until_eligible() {
+            Some((remain, gate)) => (remain.as_secs() + 1, gate.name()),
+            None => (0, "none"),
+        };
+        let name = "qq_x11_guard_next_action_eligible_seconds";
+        sections.push(format!(
+            "# HELP {name} 距離可再採取動作的秒數（gate 標籤為擋住動作的條件）\n# TYPE {name} gauge\n{name}{{app=\"{app}\",gate=\"{gate}\"}} {remain}"
+        ));
+        if !self.last_over_by_name.is_empty() {
+            let name = "qq_x11_guard_process_last_over_threshold_timestamp_seconds";
+            let mut section = format!(
+                "# HELP {name} 各程序名稱最近一次在超標量測中出現的時間（unix 秒數）\n# TYPE {name} gauge"
+            );
+            for (process, at) in &self.last_over_by_name {
+                let process = process.replace('\\', "\\\\").replace('"', "\\\"");
+                section.push_str(&format!(
+                    "\n{name}{{app=\"{app}\",process=\"{process}\"}} {}",
+                    unix_seconds(*at)
+                ));
+            }
+            sections.push(section);
+        }
+        sections.push(self.measure.histogram(
+            "qq_x11_guard_measure_duration_seconds",
+            "量測耗時的分布（秒）",
+            &format!("app=\"{app}\""),
+        ));
+        sections.join("\n")
+    }
+
+    // socket 檔被換掉（tmpfs 清空後由 X server 重建）或消失時，連線記錄都屬於舊 socket，一律重設
+    fn validate_x11_socket(&mut self) {
+        let current = x11_socket_identity(&self.config.proc_root, &self.x11_endpoint);
+        let message = match (&self.x11_socket, &current) {
+            (None, Err(error)) => Some(format!(
+                "X11 socket {} 無法確認：{error}",
+                self.x11_endpoint
+            )),
+            (Some(Ok(previous)), Ok(identity)) if previous != identity => Some(format!(
+                "X11 socket {} 已變更（inode {} → {}，監聽 {} → {}），重設連線狀態",
+                self.x11_endpoint,
+                previous.ino,
+                identity.ino,
+                previous.listeners.join(","),
+                identity.listeners.join(",")
+            )),
+            (Some(Ok(_)), Err(error)) => Some(format!(
+                "X11 socket {} 失效：{error}，重設連線狀態",
+                self.x11_endpoint
+            )),
+            (Some(Err(_)), Ok(identity)) => Some(format!(
+                "X11 socket {} 已恢復（inode {}），重設連線狀態",
+                self.x11_endpoint, identity.ino
+            )),
+            _ => None,
+        };
+        let first = self.x11_socket.is_none();
+        self.x11_socket = Some(current);
+        let Some(message) = message else {
+            return;
+        };
+        log_at(&self.config, LogLevel::Warn, &message);
+        if !first {
+            self.last_connections = None;
+            self.incident_added.clear();
+            self.incident_removed.clear();
+        }
+    }
+
+    fn process_identities(&self, pids: &[i32]) -> HashSet<(i32, u64)> {
+        pids.iter()
+            .filter_map(|pid| {
+                process_start_time(&self.config.proc_root, *pid).map(|start| (*pid, start))
+            })
+            .collect()
+    }
+
+    // 整組程序都換成新的（PID 不同或同 PID 但啟動時間不同）且不是本程式重啟的，
+    // 就是使用者或更新程式自行重啟：上一代的連線記錄、連續超標次數與警示狀態都不再適用
+    fn observe_generation(&mut self, pids: &[i32]) {
+        let current = self.process_identities(pids);
+        if current.is_empty() {
+            return;
+        }
+        let previous = mem::replace(&mut self.generation, current);
+        if previous != self.generation {
+            self.app_session = pids
+                .iter()
+                .find_map(|pid| read_session_id(&self.config.proc_root, *pid));
+        }
+        if previous.is_empty() || !previous.is_disjoint(&self.generation) {
+            return;
+        }
+        let mut old_pids: Vec<i32> = previous.iter().map(|(pid, _)| *pid).collect();
+        old_pids.sort_unstable();
+        log_at(
+            &self.config,
+            LogLevel::Warn,
+            &format!(
+                "偵測到外部重啟：{} PID {old_pids:?} → {pids:?}，清除上一代的狀態",
+                self.config.app_name
+            ),
+        );
+        self.consecutive_breaches = 0;
+        self.over_threshold_since = None;
+        self.load_deferred_since = None;
+        self.active_deferred_since = None;
+        self.idle_hold_since = None;
+        self.plateau = None;
+        self.active_levels.clear();
+        self.close_incident("external-restart");
+        self.last_connections = None;
+        self.incident_added.clear();
+        self.incident_removed.clear();
+
+        let (Some(cap), Some(last)) = (self.config.external_restart_cooldown, self.last_restart)
+        else {
+            return;
+        };
+        let cooldown = self.cooldown();
+        let cap = Duration::from_secs(cap);
+        if cooldown.saturating_sub(last.elapsed()) > cap {
+            // 把上次重啟時間往前推，使剩餘冷卻恰好等於上限
+            self.last_restart = Instant::now().checked_sub(cooldown - cap);
+            log(
+                &self.config,
+                &format!("外部重啟後剩餘冷卻縮短為 {} 秒", cap.as_secs()),
+            );
+        }
+    }
+
+    // 權限不足讀不到 fd 的程序每一代只警告一次；回傳是否所有程序都讀不到。
+    // peer 後端（sock_diag、ss）都只提供 X server 端的資訊，歸屬仍得讀各程序的 fd；
+    // 全部讀不到時由 xres_connections 改問 X server
+    fn note_unreadable(&mut self, pids: &[i32], unreadable: &[i32]) -> bool {
+        self.unreadable = unreadable.to_vec();
+        let generation = &self.generation;
+        self.unreadable_warned
+            .retain(|identity| generation.contains(identity));
+        let identities = self.process_identities(unreadable);
+        let fresh: Vec<i32> = identities
+            .iter()
+            .filter(|identity| !self.unreadable_warned.contains(*identity))
+            .map(|(pid, _)| *pid)
+            .collect();
+        if !fresh.is_empty() {
+            log_at(
+                &self.config,
+                LogLevel::Warn,
+                &format!(
+                    "無法讀取 {} PID {unreadable:?} 的 fd 目錄（權限不足），這些程序的 X11 連線無法計入：{}；\
+                     程序若切換過身分（setuid）或設為不可 dump，只有 root 能讀取；\
+                     /proc 以 hidepid 掛載時需加入 gid= 指定的群組；\
+                     所有程序都讀不到時改以 X server 的 X-Resource 擴充（QueryClientIds）回報的 client PID 計數",
+                    self.config.app_name,
+                    permission_hint(&self.config)
+                ),
+            );
+            self.unreadable_warned.extend(identities);
+        }
+        !unreadable.is_empty() && unreadable.len() == pids.len()
+    }
+
+    // 以 X server 回報的 client PID 歸屬連線，識別為 client 的 resource base；
+    // 查詢失敗（沒有 X-Resource 1.2、X server 在另一個 PID 命名空間等）時回傳 None
+    fn xres_connections(&mut self, pids: &[i32]) -> Option<Vec<(String, i32)>> {
+        let result = xres::app_clients(&self.x11_endpoint, &self.config.display, pids);
+        let state = result.as_ref().map(|_| ()).map_err(Clone::clone);
+        if self.xres_fallback.as_ref() != Some(&state) {
+            let message = match &state {
+                Ok(()) => format!(
+                    "{} 的 fd 目錄都無法讀取，改以 X-Resource 回報的 client PID 計算 X11 連線",
+                    self.config.app_name
+                ),
+                Err(error) => format!("無法改以 X-Resource 歸屬 X11 連線：{error}"),
+            };
+            log_at(&self.config, LogLevel::Warn, &message);
+            if state.is_ok() {
+                self.last_connections = None;
+            }
+            self.xres_fallback = Some(state);
+        }
+        let mut connections: Vec<(String, i32)> = result
+            .ok()?
+            .into_iter()
+            .map(|(client, pid)| (format!("xres:{client:#x}"), pid))
+            .collect();
+        connections.sort();
+        Some(connections)
+    }
+
+    fn restart_failed(&mut self, reason: String) -> RestartOutcome {
+        let escalated = if self.reload_escalated {
+            format!(
+                "（{} 重新載入未解決後升級）",
+                signal_name(self.config.reload_signal)
+            )
+        } else if let Some(manual) = &self.manual_reason {
+            format!("（手動要求：{manual}）")
+        } else if let Some(rule) = &self.action_rule {
+            format!("（由規則 {rule} 觸發）")
+        } else {
+            String::new()
+        };
+        let message = format!(
+            "重啟命令失敗{escalated} — {} 已停止運作：{reason}。進入隔離狀態，不再自動重啟，直到 {} 重新出現",
+            self.config.app_name, self.config.app_name
+        );
+        log_at(&self.config, LogLevel::Error, &message);
+        let level = if self.manual_reason.is_some() {
+            "manual-restart-failed"
+        } else {
+            "restart-failed"
+        };
+        self.notify_failure(level, &message);
+        self.quarantine = Some(reason.clone());
+        match &self.failure {
+            Some(failure) => log(
+                &self.config,
+                &format!("[{}] 失敗狀態尚未清除，不再執行失敗處理", failure.incident),
+            ),
+            None => self.handle_failure(level, &message, &reason),
+        }
+        RestartOutcome::Failed(reason)
+    }
+
+    // --on-failure-cmd / --on-failure-action：重啟流程救不回程式時的最後手段（重新登入、找人、換備用程式）。
+    // 每個失敗事件只執行一次；命令本身失敗只記錄，不重試
+    fn handle_failure(&mut self, level: &str, message: &str, reason: &str) {
+        let incident = self.incident_id();
+        let mut handled = Vec::new();
+        if let Some(command) = self.config.on_failure_cmd.clone() {
+            let notification = Notification {
+                level,
+                incident: &incident,
+                urgency: Urgency::Critical,
+                message,
+                core_dump: self.core_dump.as_deref(),
+            };
+            let values = self.placeholder_values(&incident, reason);
+            let spec = guard_env(
+                template::fill(user_command(&command, false), &values),
+                &self.config,
+                &notification,
+            )
+            .env("GUARD_FAILURE_ACTION", self.config.on_failure_action.name())
+            .timeout(FAILURE_CMD_TIMEOUT);
+            log(
+                &self.config,
+                &format!("[{incident}] 執行 --on-failure-cmd: {command}"),
+            );
+            let problem = match sandbox::run_output(&spec) {
+                Ok((true, _)) => None,
+                Ok((false, _)) => Some("以非 0 結束碼結束".to_string()),
+                Err(error) if error.kind() == io::ErrorKind::TimedOut => {
+                    Some(format!("逾時（{error}）"))
+                }
+                Err(error) => Some(format!("無法執行（{error}）")),
+            };
+            match &problem {
+                Some(problem) => log_at(
+                    &self.config,
+                    LogLevel::Warn,
+                    &format!("[{incident}] --on-failure-cmd {problem}，不重試"),
+                ),
+                None => log(
+                    &self.config,
+                    &format!("[{incident}] --on-failure-cmd 已完成"),
+                ),
+            }
+            handled.push(format!(
+                "on-failure-cmd {}",
+                problem.unwrap_or_else(|| "已完成".to_string())
+            ));
+        }
+        match self.config.on_failure_action {
+            FailureAction::None => {}
+            FailureAction::Exit => {
+                log_at(
+                    &self.config,
+                    LogLevel::Error,
+                    &format!("[{incident}] --on-failure-action exit：結束守護（結束碼 3）"),
+                );
+                self.exit_on_failure = Some(reason.to_string());
+                handled.push("exit".to_string());
+            }
+            FailureAction::RebootSession => {
+                let result = self.terminate_session();
+                match &result {
+                    Ok(session) => log_at(
+                        &self.config,
+                        LogLevel::Error,
+                        &format!("[{incident}] --on-failure-action reboot-session：已結束登入 session {session}"),
+                    ),
+                    Err(error) => log_at(
+                        &self.config,
+                        LogLevel::Error,
+                        &format!("[{incident}] --on-failure-action reboot-session 失敗：{error}，不重試"),
+                    ),
+                }
+                handled.push(match result {
+                    Ok(session) => format!("reboot-session 已結束 session {session}"),
+                    Err(error) => format!("reboot-session 失敗（{error}）"),
+                });
+            }
+        }
+        self.failure = Some(FailureState {
+            incident,
+            since: Instant::now(),
+            reason: reason.to_string(),
+            handled,
+        });
+    }
+
+    // 程式所在的 session 優先，守護本身在 session 中執行時退而用 XDG_SESSION_ID
+    fn terminate_session(&self) -> Result<String, String> {
+        let session = self
+            .app_session
+            .map(|id| id.to_string())
+            .or_else(|| env::var("XDG_SESSION_ID").ok().filter(|id| !id.is_empty()))
+            .ok_or(
+                "不知道程式所在的登入 session：/proc/<pid>/sessionid 未設定，也沒有 XDG_SESSION_ID",
+            )?;
+        let spec = CommandSpec::new("loginctl")
+            .arg("terminate-session")
+            .arg(&session)
+            .timeout(FAILURE_CMD_TIMEOUT);
+        match sandbox::run_output(&spec) {
+            Ok((true, _)) => Ok(session),
+            Ok((false, _)) => Err(format!(
+                "loginctl terminate-session {session} 以非 0 結束碼結束"
+            )),
+            Err(error) => Err(format!("無法執行 loginctl（{error}）")),
+        }
+    }
+
+    fn clear_failure(&mut self) -> String {
+        self.quarantine = None;
+        let flapping = self.flap.clear().map(|lasted| {
+            let incident = self.incident.clone().unwrap_or_else(|| "-".to_string());
+            log(
+                &self.config,
+                &format!(
+                    "[{incident}] 控制 socket：解除 flapping 狀態（持續 {} 秒），恢復自動動作",
+                    lasted.as_secs()
+                ),
+            );
+            format!("已解除 flapping 狀態（{incident}）")
+        });
+        let failure = match self.failure.take() {
+            Some(failure) => {
+                log(
+                    &self.config,
+                    &format!(
+                        "[{}] 控制 socket：清除失敗狀態（{} 秒前：{}）",
+                        failure.incident,
+                        failure.since.elapsed().as_secs(),
+                        failure.reason
+                    ),
+                );
+                format!("已清除失敗狀態（{}）", failure.incident)
+            }
+            None => "目前沒有失敗狀態".to_string(),
+        };
+        let crash_loop = self.crash.clear().map(|lasted| {
+            log(
+                &self.config,
+                &format!(
+                    "控制 socket：解除 {} 的崩潰循環（持續 {} 秒），恢復自動重啟",
+                    self.config.app_name,
+                    lasted.as_secs()
+                ),
+            );
+            "已解除崩潰循環".to_string()
+        });
+        [Some(failure), flapping, crash_loop]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join("；")
+    }
+
+    fn notify_failure(&mut self, level: &str, message: &str) {
+        let incident = self.incident_id();
+        let notification = Notification {
+            level,
+            incident: &incident,
+            urgency: Urgency::Critical,
+            message,
+            core_dump: self.core_dump.as_deref(),
+        };
+        let values = self.placeholder_values(&incident, level);
+        for notifier in &self.config.failure_notifiers {
+            send_notification(&self.config, *notifier, &notification, &values);
+        }
+    }
+
+    // 未指定 --strict-attribution 時，diag 後端與 TCP 端點預設開啟
+    fn strict_attribution(&self) -> bool {
+        self.config.strict_attribution.unwrap_or(
+            self.backend.name() == "diag" || matches!(self.x11_endpoint, X11Endpoint::Tcp(_)),
+        )
+    }
+
+    fn count_connections(&mut self, pids: &[i32]) -> ConnectionCount {
+        let strict = self.strict_attribution();
+        let started = Instant::now();
+        let mut counted = self.backend.count(
+            pids,
+            &self.x11_endpoint,
+            &mut self.fd_scanner,
+            strict,
+            self.count_trace.as_mut(),
+        );
+        if self.config.exclude_intra_app {
+            self.exclude_intra_app(pids, &mut counted);
+        }
+        self.note_measure_time(started.elapsed(), pids.len());
+        if counted.rejected > 0 {
+            self.attribution_rejected += counted.rejected as u64;
+            log_at(
+                &self.config,
+                LogLevel::Warn,
+                &format!(
+                    "嚴格歸屬檢查剔除 {} 條無法確認的連線（累計 {}）",
+                    counted.rejected, self.attribution_rejected
+                ),
+            );
+        }
+        counted
+    }
+
+    // TCP 端點的連線由 /proc/net/tcp 歸屬，沒有 ss 的程序資訊可用，不做排除
+    fn exclude_intra_app(&mut self, pids: &[i32], counted: &mut ConnectionCount) {
+        if !matches!(self.x11_endpoint, X11Endpoint::Unix(_)) || counted.connections.is_empty() {
+            return;
+        }
+        let intra = intra_app_peer_inodes(&self.x11_endpoint.socket_names(), pids);
+        let before = counted.connections.len();
+        counted
+            .connections
+            .retain(|(inode, _)| !intra.contains(inode));
+        let excluded = before - counted.connections.len();
+        if let Some(trace) = self.count_trace.as_mut() {
+            trace.intra_app = sorted_inodes(
+                intra
+                    .iter()
+                    .filter(|inode| trace.candidates.iter().any(|(item, _)| item == *inode)),
+            );
+        }
+        if excluded != self.intra_app_excluded {
+            self.intra_app_excluded = excluded;
+            log(
+                &self.config,
+                &format!(
+                    "--exclude-intra-app：{excluded} 條連線的兩端都是 {}，未計入",
+                    self.config.app_name
+                ),
+            );
+        }
+    }
+
+    fn note_measure_time(&mut self, elapsed: Duration, pid_count: usize) {
+        self.measure.record(elapsed);
+        let limit = self.config.slow_measure_warn_ms;
+        if limit == 0 || elapsed <= Duration::from_millis(limit) {
+            return;
+        }
+        let Some(suppressed) = self.measure.note_slow(Instant::now()) else {
+            return;
+        };
+        // 瓶頸通常在取得 peer 的方式：ss、lsof 每次都要產生子程序，diag 只有一次 netlink 查詢
+        let hint = match self.x11_endpoint {
+            X11Endpoint::Unix(_)
+                if matches!(self.backend.name(), "ss" | "lsof")
+                    && cfg!(feature = "backend-diag") =>
+            {
+                "，可考慮改用 --backend diag"
+            }
+            _ => "，可能是系統負載讓 /proc 讀取變慢",
+        };
+        let earlier = if suppressed > 0 {
+            format!("（上次警告後另有 {suppressed} 次）")
+        } else {
+            String::new()
+        };
+        log_at(
+            &self.config,
+            LogLevel::Warn,
+            &format!(
+                "量測耗時 {} ms，超過 --slow-measure-warn-ms {limit}{earlier}：peer 後端 {}，{pid_count} 個程序{hint}",
+                elapsed.as_millis(),
+                match self.x11_endpoint {
+                    X11Endpoint::Tcp(_) => "net/tcp",
+                    X11Endpoint::Unix(_) => self.backend.name(),
+                }
+            ),
+        );
+    }
+
+    // 完整重啟前的柔性處理：讓程式自行清理連線，等待後重新量測；回傳量測到的連線數
+    // 只送訊號給目前的程序，在驗證時限內每秒量測一次，回到門檻內即提早結束
+    fn try_reload(&mut self, pids: &[i32]) -> Option<usize> {
+        let signal = signal_name(self.config.reload_signal);
+        if self.config.dry_run {
+            log(
+                &self.config,
+                &format!("dry-run 模式：會先對 PID {pids:?} 送出 {signal} 重新載入"),
+            );
+            return None;
+        }
+        self.signal_pids(pids, self.config.reload_signal);
+        log(
+            &self.config,
+            &format!(
+                "重新載入：已對 PID {pids:?} 送出 {signal}，{} 秒內等待 X11 連線回到門檻內",
+                self.config.verify_timeout_seconds
+            ),
+        );
+        let deadline = Instant::now() + Duration::from_secs(self.config.verify_timeout_seconds);
+        loop {
+            let remain = deadline.saturating_duration_since(Instant::now());
+            thread::sleep(remain.min(Duration::from_secs(1)));
+            let pids = self.app_pids();
+            let connections = self.count_connections(&pids).connections;
+            let count = self.gauge(&pids, &connections);
+            if count <= self.active_threshold() || Instant::now() >= deadline {
+                return Some(count);
+            }
+        }
+    }
+
+    fn try_reconnect(&mut self, command: &str) -> Option<usize> {
+        if self.config.dry_run {
+            log(
+                &self.config,
+                &format!("dry-run 模式：會先執行柔性重連命令: {command}"),
+            );
+            return None;
+        }
+        let values = self.action_placeholders();
+        if let Err(err) = start_process(command, &values) {
+            log_at(
+                &self.config,
+                LogLevel::Warn,
+                &format!("無法執行柔性重連命令 {command}: {err}，改為完整重啟"),
+            );
+            return None;
+        }
+        log(
+            &self.config,
+            &format!(
+                "已執行柔性重連命令: {command}，{} 秒後重新量測",
+                self.config.reconnect_wait_seconds
+            ),
+        );
+        thread::sleep(Duration::from_secs(self.config.reconnect_wait_seconds));
+        let pids = self.app_pids();
+        let connections = self.count_connections(&pids).connections;
+        Some(self.gauge(&pids, &connections))
+    }
+
+    // --threshold-windowless：向 X server 查詢，把計入的連線分成有視窗與無視窗。查不到時不當作 0 條，
+    // 回傳 None 讓呼叫端以總數對照 --threshold；失敗的原因改變時才寫日誌
+    fn classify_windows(&mut self, pids: &[i32], total: usize) -> Option<xres::WindowSplit> {
+        let limit = self.config.threshold_windowless?;
+        match xres::classify(&self.x11_endpoint, &self.config.display, pids, total) {
+            Ok(split) => {
+                if self.windows_error.take().is_some() {
+                    log(
+                        &self.config,
+                        &format!(
+                            "已能查詢 X server 的視窗，恢復以無視窗的連線數對照 --threshold-windowless {limit}"
+                        ),
+                    );
+                }
+                self.windows = Some(split);
+            }
+            Err(error) => {
+                if self.windows_error.as_ref() != Some(&error) {
+                    log_at(
+                        &self.config,
+                        LogLevel::Warn,
+                        &format!(
+                            "無法區分有無視窗的連線（{error}），改以總數 {total} 條對照 --threshold {}",
+                            self.config.threshold
+                        ),
+                    );
+                    self.windows_error = Some(error);
+                }
+                self.windows = None;
+            }
+        }
+        self.windows
+    }
+
+    // 與 active_threshold 對照的連線數：視窗分類可用時為無視窗的連線數
+    fn gauge(&mut self, pids: &[i32], connections: &[(String, i32)]) -> usize {
+        let count = self.threshold_count(connections);
+        let windows = self.classify_windows(pids, connections.len());
+        self.scoped_gauge(pids, connections, windows, count)
+    }
+
+    // count 是 threshold_count 的結果；per-process 時改為連線最多的程序的連線數
+    fn scoped_gauge(
+        &mut self,
+        pids: &[i32],
+        connections: &[(String, i32)],
+        windows: Option<xres::WindowSplit>,
+        count: usize,
+    ) -> usize {
+        self.offender = self.per_process_offender(pids, connections);
+        match (windows, self.offender) {
+            (Some(split), _) => split.windowless,
+            (None, Some((_, busiest))) => busiest,
+            (None, None) => count,
+        }
+    }
+
+    fn active_threshold(&self) -> usize {
+        match (self.config.threshold_windowless, self.windows) {
+            (Some(limit), Some(_)) => limit,
+            _ => self.config.threshold,
+        }
+    }
+
+    // 日誌中與 active_threshold 一起出現的連線數名稱
+    fn flap_settings(&self) -> Option<flap::Settings> {
+        Some(flap::Settings {
+            crossings: self.config.flap_crossings?,
+            window: Duration::from_secs(self.config.flap_window_seconds),
+            clear_at: self.config.flap_clear.unwrap_or(self.active_threshold()),
+            dwell: Duration::from_secs(self.config.flap_dwell_seconds),
+        })
+    }
+
+    fn flap_rate_text(&self) -> String {
+        let Some(settings) = self.flap_settings() else {
+            return String::new();
+        };
+        format!(
+            "{} 秒內跨越 {} 次，上限 {}",
+            settings.window.as_secs(),
+            self.flap.rate(&settings, Instant::now()),
+            settings.crossings
+        )
+    }
+
+    fn note_over_threshold(&mut self, pids: &[i32]) {
+        let now = SystemTime::now();
+        self.last_over_threshold = Some(now);
+        for pid in pids {
+            if let Some(name) = process_name(&self.config.proc_root, *pid) {
+                self.last_over_by_name.insert(name, now);
+            }
+        }
+    }
+
+    fn crash_settings(&self) -> Option<crashloop::Settings> {
+        Some(crashloop::Settings {
+            exits: self.config.crash_loop_exits?,
+            window: Duration::from_secs(self.config.crash_loop_window_seconds),
+            uptime: Duration::from_secs(self.config.crash_loop_uptime_seconds),
+            backoff: self
+                .config
+                .crash_loop_backoff_seconds
+                .map(Duration::from_secs),
+        })
+    }
+
+    fn crash_loop_text(&self) -> Option<String> {
+        let settings = self.crash_settings()?;
+        let now = Instant::now();
+        let recent: Vec<String> = self
+            .crash
+            .recent(&settings, now)
+            .iter()
+            .map(|exit| {
+                format!(
+                    "{}，{} 秒前",
+                    exit.text(),
+                    now.duration_since(exit.at).as_secs()
+                )
+            })
+            .collect();
+        let rate = format!(
+            "{} 秒內快速結束 {}/{} 次",
+            settings.window.as_secs(),
+            recent.len(),
+            settings.exits
+        );
+        let Some(since) = self.crash.looping_since else {
+            return Some(format!("正常（{rate}）"));
+        };
+        let until = match settings.backoff {
+            Some(backoff) => format!(
+                "ctl clear 或 {} 秒後解除",
+                backoff.saturating_sub(since.elapsed()).as_secs()
+            ),
+            None => "需以 ctl clear 解除".to_string(),
+        };
+        Some(format!(
+            "已 {} 秒，不再自動重啟，{until}（{rate}：{}）",
+            since.elapsed().as_secs(),
+            recent.join("、")
+        ))
+    }
+
+    // 本程式帶起的程序在 --crash-loop-uptime 內消失時記一次快速結束，達上限就判定為崩潰循環
+    fn observe_crashes(&mut self, pids: &[i32]) {
+        let Some(settings) = self.crash_settings() else {
+            return;
+        };
+        let alive = self.process_identities(pids);
+        let Some(exits) = self.crash.observe(&settings, &alive, Instant::now()) else {
+            return;
+        };
+        self.stats.crash_loops += 1;
+        let evidence: Vec<String> = exits.iter().map(crashloop::Exit::text).collect();
+        let message = format!(
+            "{} 在重啟後 {} 秒內自行結束，{} 秒內已 {} 次（{}），判定為崩潰循環：不再自動重啟，{}",
+            self.config.app_name,
+            settings.uptime.as_secs(),
+            settings.window.as_secs(),
+            exits.len(),
+            evidence.join("、"),
+            match settings.backoff {
+                Some(backoff) => format!("直到 ctl clear 或 {} 秒後", backoff.as_secs()),
+                None => "直到 ctl clear".to_string(),
+            }
+        );
+        let incident = self.incident_id();
+        log_at(
+            &self.config,
+            LogLevel::Error,
+            &format!("[{incident}] {message}"),
+        );
+        self.notify_failure("crash-loop", &message);
+    }
+
+    fn expire_crash_loop(&mut self) {
+        let Some(settings) = self.crash_settings() else {
+            return;
+        };
+        if let Some(lasted) = self.crash.expire(&settings, Instant::now()) {
+            log(
+                &self.config,
+                &format!(
+                    "{} 的崩潰循環已持續 {} 秒，達 --crash-loop-backoff，恢復自動重啟",
+                    self.config.app_name,
+                    lasted.as_secs()
+                ),
+            );
+        }
+    }
+
+    // 每次量測記錄是否超標；進入與解除 flapping 時各記錄一次
+    fn observe_flapping(&mut self, over: bool, gauge: usize) {
+        let Some(settings) = self.flap_settings() else {
+            return;
+        };
+        match self.flap.record(&settings, over, gauge, Instant::now()) {
+            Some(flap::Change::Flapping(crossings)) => {
+                let incident = self.incident_id();
+                self.stats.flapping += 1;
+                log_at(
+                    &self.config,
+                    LogLevel::Warn,
+                    &format!(
+                        "[{incident}] {} {} {} 秒內跨越門檻 {crossings} 次（上限 {}），進入 flapping 狀態：\
+                         暫停自動動作，直到連續 {} 秒不超過 {} 條或以 ctl clear 解除",
+                        self.config.app_name,
+                        self.gauge_name(),
+                        settings.window.as_secs(),
+                        settings.crossings,
+                        settings.dwell.as_secs(),
+                        settings.clear_at
+                    ),
+                );
+            }
+            Some(flap::Change::Settled(lasted)) => log(
+                &self.config,
+                &format!(
+                    "[{}] {} 已連續 {} 秒不超過 {} 條，解除 flapping 狀態（持續 {} 秒），恢復自動動作",
+                    self.incident.as_deref().unwrap_or("-"),
+                    self.gauge_name(),
+                    settings.dwell.as_secs(),
+                    settings.clear_at,
+                    lasted.as_secs()
+                ),
+            ),
+            None => {}
+        }
+    }
+
+    fn gauge_name(&self) -> &'static str {
+        if self.windows.is_some() {
+            "無視窗的 X11 連線"
+        } else if self.offender.is_some() {
+            "單一程序最多的 X11 連線"
+        } else {
+            "X11 連線"
+        }
+    }
+
+    // --threshold-scope per-process：各程序各自的連線數（--min-connection-age 時只算夠久的），取最多的一個；
+    // 同數時取 PID 較小者。total 時為 None
+    fn per_process_offender(
+        &self,
+        pids: &[i32],
+        connections: &[(String, i32)],
+    ) -> Option<(i32, usize)> {
+        if self.config.threshold_scope != ThresholdScope::PerProcess {
+            return None;
+        }
+        let now = Instant::now();
+        let min_age = self
+            .config
+            .min_connection_age_seconds
+            .map(Duration::from_secs);
+        let mut per_pid: BTreeMap<i32, usize> = pids.iter().map(|pid| (*pid, 0)).collect();
+        for (inode, pid) in connections {
+            let aged = min_age.is_none_or(|min_age| {
+                self.connection_first_seen
+                    .get(inode)
+                    .is_some_and(|seen| now.duration_since(*seen) >= min_age)
+            });
+            if aged {
+                *per_pid.entry(*pid).or_default() += 1;
+            }
+        }
+        per_pid.into_iter().rev().max_by_key(|(_, count)| *count)
+    }
+
+    // status 與重啟前擷取的摘要：未指定 --threshold-windowless 時為 None
+    fn windows_status(&self) -> Option<String> {
+        let limit = self.config.threshold_windowless?;
+        Some(match (self.windows, &self.windows_error) {
+            (Some(split), _) => format!(
+                "有視窗 {}、無視窗 {}（--threshold-windowless {limit}）",
+                split.windowed, split.windowless
+            ),
+            (None, Some(error)) => format!(
+                "無法查詢（{error}），以總數對照 --threshold {}",
+                self.config.threshold
+            ),
+            (None, None) => "尚未量測".to_string(),
+        })
+    }
+
+    fn windows_text(&self) -> String {
+        self.windows
+            .map(|split| format!("（有視窗 {}、無視窗 {}）", split.windowed, split.windowless))
+            .unwrap_or_default()
+    }
+
+    // 與門檻比較的連線數。指定 --min-connection-age 時只算持續存在超過下限的連線：
+    // 以各 inode 第一次量測到的時間起算，守護程式啟動時已存在的連線從啟動時算起
+    fn threshold_count(&mut self, connections: &[(String, i32)]) -> usize {
+        if self.config.min_connection_age_seconds.is_none() {
+            return connections.len();
+        }
+        let now = Instant::now();
+        let current: HashSet<&str> = connections
+            .iter()
+            .map(|(inode, _)| inode.as_str())
+            .collect();
+        self.connection_first_seen
+            .retain(|inode, _| current.contains(inode.as_str()));
+        for (inode, _) in connections {
+            self.connection_first_seen
+                .entry(inode.clone())
+                .or_insert(now);
+        }
+        self.aged_connections(now).unwrap_or(connections.len())
+    }
+
+    fn aged_connections(&self, now: Instant) -> Option<usize> {
+        let min_age = Duration::from_secs(self.config.min_connection_age_seconds?);
+        Some(
+            self.connection_first_seen
+                .values()
+                .filter(|seen| now.duration_since(**seen) >= min_age)
+                .count(),
+        )
+    }
+
+    fn aged_text(&self) -> String {
+        match (
+            self.aged_connections(Instant::now()),
+            self.config.min_connection_age_seconds,
+        ) {
+            (Some(aged), Some(min_age)) => {
+                format!("（存在超過 {min_age} 秒、計入門檻的 {aged} 條）")
+            }
+            _ => String::new(),
+        }
+    }
+
+    // 記錄與上一次檢查的差異；事件進行中時累積，重啟前一併輸出供對照
+    fn track_connection_delta(&mut self, connections: &[(String, i32)]) {
+        let current: HashMap<String, i32> = connections.iter().cloned().collect();
+        let now = Instant::now();
+        let previous = match self.last_connections.replace(current) {
+            Some(value) => value,
+            None => {
+                self.churn.reset(now);
+                self.trend.reset();
+                self.trend.record(now, connections.len());
+                return;
+            }
+        };
+        self.trend.record(now, connections.len());
+        let (added, removed) = connection_delta(&previous, connections);
+        self.churn.record(now, added.len(), removed.len());
+        if self.config.verbosity >= 1 && (!added.is_empty() || !removed.is_empty()) {
+            log(
+                &self.config,
+                &format!(
+                    "連線變化：新增 {} 條 [{}]，關閉 {} 條 [{}]",
+                    added.len(),
+                    format_inode_list(
+                        added
+                            .iter()
+                            .map(|(inode, pid)| format!("{inode}(pid {pid})")),
+                        added.len()
+                    ),
+                    removed.len(),
+                    format_inode_list(removed.iter().cloned(), removed.len())
+                ),
+            );
+        }
+        if self.incident.is_some() {
+            self.incident_added.extend(added);
+            self.incident_removed.extend(removed);
+        } else {
+            self.incident_added.clear();
+            self.incident_removed.clear();
+        }
+    }
+
+    fn incident_delta_summary(&self) -> Option<String> {
+        let incident = self.incident.as_deref()?;
+        Some(format!(
+            "[{incident}] 事件開始以來新增 {} 條連線 [{}]，關閉 {} 條 [{}]",
+            self.incident_added.len(),
+            format_inode_list(
+                self.incident_added
+                    .iter()
+                    .map(|(inode, pid)| format!("{inode}(pid {pid})")),
+                self.incident_added.len()
+            ),
+            self.incident_removed.len(),
+            format_inode_list(
+                self.incident_removed.iter().cloned(),
+                self.incident_removed.len()
+            )
+        ))
+    }
+
+    fn log_connection_owners(&self, connections: &[(String, i32)]) {
+        if connections.is_empty() {
+            return;
+        }
+        // TCP 轉送的連線不在 ss -x 的輸出中，直接列出歸屬時記下的 PID
+        let owners = match self.x11_endpoint {
+            X11Endpoint::Unix(_) => unix_socket_owners(),
+            X11Endpoint::Tcp(_) => HashMap::new(),
+        };
+        for (inode, pid) in connections {
+            let message = match (owners.get(inode), &self.x11_endpoint) {
+                // --connections-from net-unix 記下的是 X server 端的 socket，不知道另一端是誰
+                (_, X11Endpoint::Unix(_)) if *pid == 0 => {
+                    format!("  連線 inode={inode}（X server 端，無法歸屬到程序）")
+                }
+                (Some(owner), _) => format!(
+                    "  連線 inode={inode} peer pid={} comm={}",
+                    owner.pid, owner.comm
+                ),
+                (None, X11Endpoint::Tcp(_)) => format!("  連線 inode={inode} pid={pid}（TCP）"),
+                (None, X11Endpoint::Unix(_)) if cfg!(feature = "backend-ss") => {
+                    format!("  連線 inode={inode}（ss 未提供程序資訊，可能需要 root）")
+                }
+                (None, X11Endpoint::Unix(_)) => format!("  連線 inode={inode} pid={pid}"),
+            };
+            log(&self.config, &message);
+        }
+    }
+
+    fn check_threshold(&mut self, trigger: &str, pids: Option<Vec<i32>>) -> Option<RestartOutcome> {
+        self.expire_pause();
+        self.expire_confirmation();
+        self.expire_crash_loop();
+        let pids = if let Some(value) = pids {
+            self.sync_inotify(&value);
+            value
+        } else {
+            self.sync_watches()
+        };
+        self.observe_crashes(&pids);
+        if pids.is_empty() {
+            if trigger == "fallback" {
+                if let Some(reason) = &self.quarantine {
+                    log_at(
+                        &self.config,
+                        LogLevel::Error,
+                        &format!(
+                            "仍處於隔離狀態，{} 未執行（{reason}）",
+                            self.config.app_name
+                        ),
+                    );
+                }
+            }
+            return None;
+        }
+        if self.quarantine.take().is_some() {
+            log(
+                &self.config,
+                &format!("{} 已重新出現，解除隔離狀態", self.config.app_name),
+            );
+            // 重啟命令失敗時記下的配置仍在，手動啟動後照樣還原
+            self.restore_layout();
+        }
+        if let Some(failure) = self.failure.take() {
+            log(
+                &self.config,
+                &format!(
+                    "[{}] {} 已重新出現，清除失敗狀態（失敗後 {} 秒）",
+                    failure.incident,
+                    self.config.app_name,
+                    failure.since.elapsed().as_secs()
+                ),
+            );
+        }
+
+        self.observe_generation(&pids);
+        let mut counted = self.count_connections(&pids);
+        if self.note_unreadable(&pids, &counted.unreadable) {
+            match self.xres_connections(&pids) {
+                Some(connections) => counted.connections = connections,
+                None => {
+                    // 讀不到任何程序的 fd 時 0 條並不代表正常，不更新連線記錄也不判斷門檻
+                    self.last_connections = None;
+                    if trigger == "fallback" || self.config.verbosity >= 1 {
+                        log_at(
+                            &self.config,
+                            LogLevel::Warn,
+                            &format!(
+                                "檢查（{trigger}）：{} X11 連線數未知，所有程序的 fd 都無法讀取",
+                                self.config.app_name
+                            ),
+                        );
+                    }
+                    return None;
+                }
+            }
+        } else if self.xres_fallback.take() == Some(Ok(())) {
+            // 兩種歸屬的連線識別不同，換回時重新開始比較
+            self.last_connections = None;
+            log(
+                &self.config,
+                &format!(
+                    "已能讀取 {} 的 fd 目錄，恢復以 socket 歸屬 X11 連線",
+                    self.config.app_name
+                ),
+            );
+        }
+        let total = counted.connections.len();
+        let x11_count = self.threshold_count(&counted.connections);
+        // --threshold-windowless 生效時以無視窗的連線數對照它的門檻，per-process 時以連線最多的程序對照；
+        // 兩者都只影響 --threshold，--rule 的 x11 仍是總數
+        let windows = self.classify_windows(&pids, total);
+        let gauge = self.scoped_gauge(&pids, &counted.connections, windows, x11_count);
+        self.last_gauge = Some(gauge);
+        self.stats.measurements += 1;
+        self.stats.max_connections = self.stats.max_connections.max(total);
+        self.interval.record(trigger, total);
+        self.track_connection_delta(&counted.connections);
+        // 取樣放在更新連線變動之後，變動率包含這一次量測
+        if self
+            .history
+            .as_mut()
+            .is_some_and(|store| store.sample_due(Instant::now()))
+        {
+            let churn = self.churn.rate();
+            let sample = history::Record::Sample(history::Sample {
+                time: timestamp(),
+                app: self.config.app_name.clone(),
+                display: self.config.display.clone(),
+                count: gauge,
+                threshold: self.active_threshold(),
+                opened_per_minute: churn.as_ref().map(|rate| rate.opened_per_minute),
+                closed_per_minute: churn.as_ref().map(|rate| rate.closed_per_minute),
+            });
+            self.record_history(sample, false);
+        }
+        // 啟動時的第一次量測一律記錄，與上面的「已在執行」成對
+        if self.config.verbosity >= 1 || trigger == "startup" {
+            let churn = self
+                .churn
+                .rate()
+                .map(|rate| format!("，連線變動{}", rate.text()))
+                .unwrap_or_default();
+            let trend = self
+                .current_trend()
+                .map(|trend| format!("，趨勢{}", trend.text()))
+                .unwrap_or_default();
+            log(
+                &self.config,
+                &format!(
+                    "檢查（{trigger}）：{} X11 連線 {total} 條{}{}{churn}{trend}",
+                    self.config.app_name,
+                    self.aged_text(),
+                    self.windows_text()
+                ),
+            );
+        }
+        if self.config.verbosity >= 2 {
+            self.log_connection_owners(&counted.connections);
+        }
+        let metrics = self.sample_metrics(&pids, x11_count);
+        // rules[0] 即 --threshold
+        let over = match windows {
+            Some(split) => split.windowless > self.active_threshold(),
+            None if self.offender.is_some() => gauge > self.active_threshold(),
+            None => self.rules[0].matches(&metrics),
+        };
+        if over {
+            self.consecutive_breaches += 1;
+            self.over_threshold_since.get_or_insert_with(Instant::now);
+            self.note_over_threshold(&pids);
+        } else {
+            self.consecutive_breaches = 0;
+            self.over_threshold_since = None;
+            self.load_deferred_since = None;
+            self.active_deferred_since = None;
+            if let Some(since) = self.idle_hold_since.take() {
+                let incident = self.incident_id();
+                self.stats.self_recovered += 1;
+                log(
+                    &self.config,
+                    &format!(
+                        "[{incident}] 等待閒置保留 {} 秒期間，{} {gauge} 條已回到門檻內，不必動作，記為自行恢復",
+                        since.elapsed().as_secs(),
+                        self.gauge_name()
+                    ),
+                );
+            }
+            if let Some(wait) = self.plateau.take() {
+                log(
+                    &self.config,
+                    &format!(
+                        "{} {} {gauge} 條已回到門檻內（等待持平期間峰值 {} 條），取消動作",
+                        self.config.app_name,
+                        self.gauge_name(),
+                        wait.peak
+                    ),
+                );
+            }
+        }
+        self.observe_flapping(over, gauge);
+        let restart_rule = self.evaluate_rules(&metrics);
+        if !over && restart_rule.is_none() {
+            if let Some(pending) = self.confirmation.take() {
+                log(
+                    &self.config,
+                    &format!(
+                        "[{}] {} {gauge} 條已回到門檻內，取消等待確認",
+                        pending.incident,
+                        self.gauge_name()
+                    ),
+                );
+            }
+        }
+        self.evaluate_escalation(gauge);
+        if over || restart_rule.is_some() {
+            if self.pause.is_some() {
+                let reason = match &restart_rule {
+                    Some(name) if !over => format!("規則 {name} 成立"),
+                    _ => format!(
+                        "{} {gauge} 條超過門檻 {}",
+                        self.gauge_name(),
+                        self.active_threshold()
+                    ),
+                };
+                log(
+                    &self.config,
+                    &format!(
+                        "{} {reason}，但{}，不採取動作",
+                        self.config.app_name,
+                        self.pause_text()
+                    ),
+                );
+                return None;
+            }
+            // 同時超過 --threshold 時以門檻為準
+            let rule = restart_rule.filter(|_| !over);
+            return self.restart_app(gauge, rule.as_deref());
+        }
+        if trigger == "fallback" {
+            let levels = if self.active_levels.is_empty() {
+                String::new()
+            } else {
+                format!("，警示等級: {}", self.active_levels.join(","))
+            };
+            let eligible = self.eligibility_text();
+            let unreadable = if self.unreadable.is_empty() {
+                String::new()
+            } else {
+                format!("（另有 PID {:?} 無法讀取，未計入）", self.unreadable)
+            };
+            log(
+                &self.config,
+                &format!(
+                    "目前 {} X11 連線 {} 條{}{}{unreadable}（{}門檻 {}）{levels}，可再動作: {eligible}",
+                    self.config.app_name,
+                    total,
+                    self.aged_text(),
+                    self.windows_text(),
+                    if self.windows.is_some() { "無視窗" } else { "" },
+                    self.active_threshold()
+                ),
+            );
+        }
+        None
+    }
+
+    // panic 後重新開始前換掉可能停在不一致狀態的 inotify、peer 後端與 pidfd；
+    // 冷卻、熔斷、暫停與統計留著，重新開始後照常生效。seccomp 啟用後無法再建立 inotify 與 netlink socket，只能沿用
+    fn rebuild_after_panic(&mut self) -> Result<(), String> {
+        if self.config.seccomp {
+            log_at(
+                &self.config,
+                LogLevel::Warn,
+                "seccomp 啟用中無法重建 inotify 與 peer 後端，沿用原本的實例",
+            );
+        } else {
+            self.inotify = InotifyWatch::new(self.config.proc_root.clone())
+                .map_err(|err| format!("inotify 初始化失敗: {err}"))?;
+            self.backend = counter::select(
+                self.config.connections_from,
+                &self.config,
+                &self.x11_endpoint,
+            )?
+            .0;
+        }
+        self.pidfds = PidfdTable::detect();
+        self.mux.forget();
+        self.last_connections = None;
+        self.x11_socket = None;
+        Ok(())
+    }
+
+    // 同時等待 inotify、各程序的 pidfd、控制 socket 與結束訊號的管線；新的 fd 來源加在這裡的集合即可
+    fn wait_for_wakeup(&mut self, timeout: Duration) -> io::Result<Wakeup> {
+        let mut fds = vec![(self.inotify.fd, Source::Inotify)];
+        fds.extend(
+            self.pidfds
+                .poll_fds()
+                .into_iter()
+                .map(|(pid, fd)| (fd, Source::Pidfd(pid))),
+        );
+        if let Some(server) = &self.control {
+            fds.push((server.fd(), Source::Control));
+        }
+        let shutdown = SHUTDOWN_PIPE[0].load(Ordering::Relaxed);
+        if shutdown >= 0 {
+            fds.push((shutdown, Source::Shutdown));
+        }
+        self.mux.sync(&fds, self.pidfds.opened)?;
+        let ready = self.mux.wait(timeout);
+        if let Some(note) = self.mux.take_note() {
+            log_at(&self.config, LogLevel::Warn, &note);
+        }
+        let mut wakeup = Wakeup::default();
+        for source in ready? {
+            match source {
+                Source::Inotify => {
+                    (wakeup.events, wakeup.capped) = self
+                        .inotify
+                        .read_events(self.config.max_events_per_wakeup)?;
+                }
+                Source::Pidfd(pid) => wakeup.exited.push(pid),
+                Source::Control => wakeup.control = true,
+                // 主迴圈下一輪開頭會看到 SHUTDOWN_SIGNAL 或 RELOAD_REQUESTED
+                Source::Shutdown => drain_shutdown_pipe(),
+            }
+        }
+        Ok(wakeup)
+    }
+
+    fn run(&mut self) -> io::Result<Option<RestartOutcome>> {
+        log(
+            &self.config,
+            &format!(
+                "啟動監控，DISPLAY={}，門檻={}（{}），掃描執行緒={}，peer 後端={}，程序追蹤={}，事件等待={}",
+                self.config.display,
+                self.config.threshold,
+                self.config.threshold_scope.name(),
+                self.config.scan_threads,
+                match self.x11_endpoint {
+                    X11Endpoint::Tcp(_) => "net/tcp",
+                    X11Endpoint::Unix(_) => self.backend.name(),
+                },
+                self.pidfds.mechanism(),
+                self.mux.kind().name()
+            ),
+        );
+        if let Some(note) = self.mux.take_note() {
+            log_at(&self.config, LogLevel::Warn, &note);
+        }
+        if let Some(note) = &self.backend_note {
+            log_at(&self.config, LogLevel::Warn, note);
+        }
+        for warning in &self.config.config_warnings {
+            log_at(&self.config, LogLevel::Warn, warning);
+        }
+        // $XDG_RUNTIME_DIR 很深時，其下的 X11 socket 名稱不可能存在，只剩 /tmp/.X11-unix 可比對
+        for name in self.x11_endpoint.socket_names() {
+            if name.starts_with('@') {
+                continue;
+            }
+            if let Err(error) = check_socket_path_length(name.as_bytes()) {
+                log_at(
+                    &self.config,
+                    LogLevel::Warn,
+                    &format!("X11 socket 名稱 {name} 無效：{error}，X server 無法在此監聽，只能經由其他名稱連線"),
+                );
+            }
+        }
+
+        if !restart_command_resolvable(&self.config.restart_cmd) {
+            log_at(
+                &self.config,
+                LogLevel::Warn,
+                &format!(
+                    "找不到重啟命令的執行檔: {}（超標時重啟可能失敗）",
+                    self.config.restart_cmd
+                ),
+            );
+        } else if let Some(comm) = restart_command_comm(&self.config.restart_cmd).filter(|comm| {
+            !name_matches(&self.config.app_names, &self.config.cmdline_match, comm)
+                && !matches_by_cmdline_only(&self.config.cmdline_match)
+        }) {
+            let (mismatch, hint) = if name_patterns(&self.config.cmdline_match).next().is_some() {
+                (
+                    "不符合 --app-name-regex".to_string(),
+                    "可調整 --app-name-regex",
+                )
+            } else {
+                (
+                    format!("與 --app-name {} 不同", self.config.app_name),
+                    if self.config.learn_new_name {
+                        "已開啟 --learn-new-name，重啟後會自動加入新名稱"
+                    } else {
+                        "可加上 --learn-new-name"
+                    },
+                )
+            };
+            log_at(
+                &self.config,
+                LogLevel::Warn,
+                &format!(
+                    "重啟命令的執行檔名稱 {comm} {mismatch}；若它不是包裝腳本，重啟後將找不到程式（{hint}）"
+                ),
+            );
+        }
+
+        self.validate_x11_socket();
+        self.load_state();
+        self.open_history();
+        self.load_layout();
+        let pids = self.sync_watches();
+        // 開機時守護可能比程式先啟動：明確記錄是已在執行還是等待中，連線數由下面的 startup 檢查記錄
+        if pids.is_empty() {
+            self.waiting_for_app = true;
+            log(
+                &self.config,
+                &format!(
+                    "{} 尚未執行，等待它啟動（每 {} 秒確認一次）",
+                    self.config.app_name, self.config.scan_interval_seconds
+                ),
+            );
+        } else {
+            log(
+                &self.config,
+                &format!("{} 已在執行（PID {pids:?}）", self.config.app_name),
+            );
+            self.restore_layout();
+        }
+        if let Some(outcome) = self.check_threshold("startup", Some(pids)) {
+            if self.config.exit_after_restart {
+                return Ok(Some(outcome));
+            }
+        }
+
+        let mut timers = TimerWheel::new();
+        let now = Instant::now();
+        timers.add(
+            PeriodicTask::SyncWatches,
+            Duration::from_secs(self.config.scan_interval_seconds),
+            now,
+        );
+        timers.add(
+            PeriodicTask::FallbackPoll,
+            Duration::from_secs(self.config.fallback_poll_seconds),
+            now,
+        );
+        if self.config.summary_interval_seconds > 0 {
+            timers.add(
+                PeriodicTask::Summary,
+                Duration::from_secs(self.config.summary_interval_seconds),
+                now,
+            );
+        }
+        if let Some(seconds) = self.config.x_probe_seconds {
+            timers.add(PeriodicTask::XProbe, Duration::from_secs(seconds), now);
+        }
+
+        install_shutdown_handler();
+        let mut iteration: u64 = 0;
+        let mut last_iteration: Option<Instant> = None;
+        loop {
+            iteration += 1;
+            let started = (self.config.verbosity >= 3).then(Instant::now);
+            sandbox::reap_children();
+            let signal = SHUTDOWN_SIGNAL.load(Ordering::Relaxed);
+            if signal != 0 {
+                log(
+                    &self.config,
+                    &format!("收到 {}，結束監控", signal_name(signal)),
+                );
+                self.flush_digests(true);
+                log(&self.config, &self.stats.summary(self.pause.as_ref()));
+                return Ok(None);
+            }
+            if RELOAD_REQUESTED.swap(false, Ordering::Relaxed) {
+                self.reload_config();
+            }
+            if let Some(reason) = self.exit_on_failure.take() {
+                self.flush_digests(true);
+                log(&self.config, &self.stats.summary(self.pause.as_ref()));
+                return Ok(Some(RestartOutcome::Failed(reason)));
+            }
+            self.expire_pause();
+            self.expire_confirmation();
+            if let Some(factor) = self.govern_cpu() {
+                timers.set_factor(factor, Instant::now());
+            }
+            if let Some(delay) =
+                last_iteration.and_then(|at| self.cpu.iteration_delay(at, Instant::now()))
+            {
+                thread::sleep(delay);
+            }
+            last_iteration = Some(Instant::now());
+            let now = Instant::now();
+            // 暫停或等待確認到期時也要醒來記錄
+            let pause_deadline = self.pause.as_ref().and_then(|pause| pause.until);
+            let confirm_deadline = self
+                .confirmation
+                .as_ref()
+                .filter(|pending| pending.approved_by.is_none())
+                .map(|pending| pending.until);
+            let plateau_deadline = self.plateau_deadline();
+            let digest_deadline = self.digests.iter().filter_map(NotifyDigest::due).min();
+            let next = [
+                timers
+                    .next_deadline()
+                    .map(|(task, deadline)| (task.name(), deadline)),
+                pause_deadline.map(|deadline| ("pause", deadline)),
+                confirm_deadline.map(|deadline| ("confirm", deadline)),
+                plateau_deadline.map(|deadline| ("plateau", deadline)),
+                digest_deadline.map(|deadline| ("digest", deadline)),
+            ]
+            .into_iter()
+            .flatten()
+            .min_by_key(|(_, deadline)| *deadline);
+            let timeout = next
+                .map_or(Duration::from_secs(1), |(_, deadline)| {
+                    deadline.saturating_duration_since(now)
+                })
+                .max(Duration::from_millis(100));
+
+            let waiting = started.map(|_| Instant::now());
+            let Wakeup {
+                events,
+                exited,
+                control,
+                capped,
+            } = self.wait_for_wakeup(timeout)?;
+            let mut trace = started.zip(waiting).map(|(started, waiting)| LoopTrace {
+                iteration,
+                started,
+                timeout,
+                deadline: next.map_or("無", |(name, _)| name),
+                waited: waiting.elapsed(),
+                events: events.len(),
+                exited: exited.len(),
+                control,
+                phases: Vec::new(),
+                fired: Vec::new(),
+                measurements: self.stats.measurements,
+                measured: None,
+            });
+            if capped && self.config.verbosity >= 1 {
+                log(
+                    &self.config,
+                    &format!(
+                        "本次喚醒已處理 {} 個以上的 inotify 事件，其餘留待下一輪",
+                        self.config.max_events_per_wakeup
+                    ),
+                );
+            }
+            let mut outcome = if control {
+                LoopTrace::phase(&mut trace, "control", || self.handle_control())
+            } else {
+                None
+            };
+            if outcome.is_none() && !exited.is_empty() {
+                for pid in &exited {
+                    self.pidfds.mark_exited(*pid);
+                }
+                if self.config.verbosity >= 1 {
+                    log(&self.config, &format!("PID {exited:?} 已結束"));
+                }
+                outcome =
+                    LoopTrace::phase(&mut trace, "exit", || self.check_threshold("exit", None));
+            }
+            if outcome.is_none() && !events.is_empty() {
+                outcome =
+                    LoopTrace::phase(&mut trace, "event", || self.check_threshold("event", None));
+            }
+
+            // 事件已觸發動作時，到期的任務留到下一輪
+            if outcome.is_none() {
+                for task in timers.due(Instant::now()) {
+                    if let Some(trace) = &mut trace {
+                        trace.fired.push(task.name());
+                    }
+                    LoopTrace::phase(&mut trace, task.name(), || match task {
+                        PeriodicTask::SyncWatches => {
+                            self.sync_watches();
+                        }
+                        PeriodicTask::FallbackPoll => {
+                            self.validate_x11_socket();
+                            outcome = self.check_threshold("fallback", None);
+                        }
+                        PeriodicTask::Summary => self.log_interval_summary(),
+                        PeriodicTask::XProbe => self.probe_x_server(),
+                    });
+                }
+            }
+            if outcome.is_none()
+                && self
+                    .plateau_deadline()
+                    .is_some_and(|deadline| deadline <= Instant::now())
+            {
+                outcome = LoopTrace::phase(&mut trace, "plateau", || {
+                    self.check_threshold("plateau", None)
+                });
+            }
+
+            if let Some(outcome) = outcome {
+                self.record_outcome(&outcome, false);
+                if self.config.exit_after_restart {
+                    self.flush_digests(true);
+                    return Ok(Some(outcome));
+                }
+            }
+            self.flush_digests(false);
+            if let Some(trace) = &mut trace {
+                if self.stats.measurements != trace.measurements {
+                    trace.measured = self.measure.last;
+                }
+                log(&self.config, &trace.text());
+            }
+        }
+    }
+}
+
+// 服務檔的 ExecStart 指向目前的執行檔；DISPLAY 取解析後的值，XAUTHORITY 有設定時一併帶入。
+// 系統服務沒有 --drop-privileges 時以目前的使用者執行（root 除外）
+fn generate_unit(config: &Config) -> Result<String, String> {
+    let exe = env::current_exe().map_err(|error| format!("無法取得執行檔路徑: {error}"))?;
+    // 服務的工作目錄不是目前目錄，相對的設定檔路徑改為絕對路徑
+    let directory = env::current_dir().map_err(|error| format!("無法取得目前目錄: {error}"))?;
+    let absolute = |path: &str| directory.join(path).to_string_lossy().into_owned();
+    let mut environment = vec![("DISPLAY".to_string(), config.display.clone())];
+    if let Ok(path) = env::var("XAUTHORITY") {
+        environment.push(("XAUTHORITY".to_string(), path));
+    }
+    // 來自環境變數的設定照樣交給服務
+    let mut overrides: Vec<(String, String)> = env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .filter(|(name, value)| name.starts_with(configfile::ENV_PREFIX) && !value.is_empty())
+        .map(|(name, value)| match name.as_str() {
+            configfile::ENV_CONFIG => (name, absolute(&value)),
+            _ => (name, value),
+        })
+        .collect();
+    overrides.sort();
+    environment.extend(overrides);
+    let run_as =
+        (!config.unit_user && config.drop_privileges.is_none() && unsafe { libc::getuid() } != 0)
+            .then(privileges::current_user_name)
+            .flatten();
+    let mut args = config.unit_args.clone();
+    for flag in ["--config", "--config-dir"] {
+        if let Some(at) = args.iter().position(|arg| arg == flag) {
+            if let Some(path) = args.get_mut(at + 1) {
+                *path = absolute(path);
+            }
+        }
+    }
+    Ok(unit::Unit {
+        exe: &exe,
+        args: &args,
+        user: config.unit_user,
+        environment,
+        run_as,
+        generated_at: wall_clock(SystemTime::now()),
+    }
+    .render())
+}
+
+fn main() {
+    LOG_EPOCH.get_or_init(Instant::now);
+    let config = match parse_args() {
+        Ok(value) => value,
+        Err(error) => {
+            eprintln!("參數錯誤: {error}");
+            eprintln!("使用 --help 查看用法");
+            std::process::exit(2);
+        }
+    };
+
+    if config.subcommand == Subcommand::Ctl {
+        let path = config.control_socket.as_deref().expect("parse_args 已檢查");
+        match control::request(path, &config.control_request, Duration::from_secs(5)) {
+            Ok(reply) => {
+                print!("{reply}");
+                std::process::exit(if reply.starts_with("錯誤") { 1 } else { 0 });
+            }
+            Err(error) => {
+                eprintln!("無法連線到控制 socket {}: {error}", path.display());
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if config.subcommand == Subcommand::RestartNow {
+        if let Some(path) = &config.control_socket {
+            forward_restart(&config, path);
+        }
+    }
+
+    if config.subcommand == Subcommand::GenerateMan {
+        let page = manpage::render();
+        match &config.man_output {
+            Some(path) => {
+                if let Err(error) = fs::write(path, page) {
+                    eprintln!("無法寫入 {}: {error}", path.display());
+                    std::process::exit(1);
+                }
+            }
+            None => print!("{page}"),
+        }
+        return;
+    }
+
+    if config.subcommand == Subcommand::GenerateUnit {
+        match generate_unit(&config) {
+            Ok(text) => print!("{text}"),
+            Err(error) => {
+                eprintln!("執行錯誤: {error}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if config.subcommand == Subcommand::Top {
+        if let Err(error) = top::run(&config) {
+            eprintln!("執行錯誤: {error}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // 只讀取，不修復殘缺的檔尾也不清理：守護程序可能正在寫入
+    if config.subcommand == Subcommand::History {
+        let path = config.history_file.as_ref().expect("已在參數檢查確認");
+        match history::read(path) {
+            Ok(records) => print!("{}", history::render(&records, &config.history_query)),
+            Err(error) => {
+                eprintln!("執行錯誤: {error}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    install_panic_hook(&config);
+
+    // 在降權前開啟，降權後仍可附加寫入；輪替時的改名與開新檔則以降權後的使用者進行
+    if let Some(path) = &config.log_file {
+        match logfile::LogFile::open(
+            path,
+            config.log_max_bytes,
+            config.log_keep.unwrap_or(DEFAULT_LOG_KEEP),
+        ) {
+            Ok(sink) => logfile::install(sink),
+            Err(error) => {
+                eprintln!("初始化失敗: 無法開啟日誌檔 {}: {error}", path.display());
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let mut guards = Vec::new();
+    for app_config in app_configs(&config) {
+        match Guard::new(app_config) {
+            Ok(value) => guards.push(value),
+            Err(error) => {
+                eprintln!("初始化失敗: {error}");
+                std::process::exit(1);
+            }
+        }
+    }
+    // 分別監控多個程式時，下面只對單一程式的步驟已在參數檢查排除，就緒命令只需等一次
+    let guard = &mut guards[0];
+
+    if let Some(user) = &config.drop_privileges {
+        if let Err(error) = drop_privileges(&config, user) {
+            eprintln!("初始化失敗: {error}");
+            std::process::exit(1);
+        }
+    }
+
+    // 以降權後的權限量測，看到的與守護程序執行時相同；不建立控制 socket、不進入 seccomp
+    if config.subcommand == Subcommand::Explain {
+        print!("{}", explain::run(guard, config.explain_json));
+        return;
+    }
+
+    // 在降權後測試，才能反映實際執行時的權限；seccomp 尚未啟用
+    if config.self_test_restart {
+        let passed = guards.iter().all(|guard| self_test_restart(&guard.config));
+        std::process::exit(if passed { 0 } else { 3 });
+    }
+
+    // self-upgrade exec 過來時前一個執行檔已經等過，不再等待
+    if let Some(command) = &config.wait_for_cmd {
+        if config.subcommand == Subcommand::Monitor && config.resume_state.is_none() {
+            if let Err(error) = guard.wait_for_command(command) {
+                log_at(&config, LogLevel::Error, &error);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // self-upgrade exec 過來：沿用前一個執行檔的狀態，控制 socket 直接接手它留下的 fd
+    let mut inherited_control = None;
+    if let Some(path) = &config.resume_state {
+        let restored = fs::read_to_string(path)
+            .map_err(|error| format!("無法讀取 {}: {error}", path.display()))
+            .and_then(|text| {
+                let _ = fs::remove_file(path);
+                guard.restore(&state::parse_pairs(&text))
+            });
+        match restored {
+            Ok(fd) => inherited_control = fd,
+            Err(error) => log_at(
+                &config,
+                LogLevel::Warn,
+                &format!("無法沿用 self-upgrade 前的狀態：{error}，以全新的狀態開始"),
+            ),
+        }
+    }
+
+    // 控制 socket 在降權後建立，檔案才會屬於降權後的使用者
+    if let Some(path) = &config.control_socket {
+        if config.subcommand == Subcommand::Monitor && !config.exit_after_restart {
+            let adopted = inherited_control.and_then(|fd| {
+                control::ControlServer::adopt(fd, path)
+                    .map_err(|error| {
+                        log_at(
+                            &config,
+                            LogLevel::Warn,
+                            &format!("無法接手先前的控制 socket（{error}），重新建立"),
+                        )
+                    })
+                    .ok()
+            });
+            match adopted.map_or_else(|| control::ControlServer::bind(path), Ok) {
+                Ok(server) => {
+                    log(
+                        &config,
+                        &format!("控制 socket: {}", server.path().display()),
+                    );
+                    guard.control = Some(server);
+                }
+                Err(error) => {
+                    eprintln!(
+                        "初始化失敗: 無法建立控制 socket {}: {error}",
+                        path.display()
+                    );
+                    std::process::exit(1);
+                }
+            }
+        } else {
+            log(&config, "一次性模式不建立控制 socket");
+        }
+    }
+
+    // 先 fork helper 再降低優先權：重啟帶起的程式與通知命令由 helper 執行，不會繼承調低的優先權
+    let priority = priority::Priority {
+        nice: config.nice,
+        io: config.ionice,
+    };
+    if config.seccomp || priority.is_set() {
+        priority::configure(priority);
+        if let Err(error) = sandbox::start_helper(helper_policy(&config)) {
+            eprintln!("初始化失敗: {error}");
+            std::process::exit(1);
+        }
+    }
+    // 在主執行緒設定，之後建立的掃描執行緒沿用
+    if priority.is_set() {
+        for warning in priority::apply(priority) {
+            log_at(&config, LogLevel::Warn, &warning);
+        }
+        log(
+            &config,
+            &format!(
+                "排程優先權：{}（ss、lsof 相同，重啟與通知命令維持原本的優先權）",
+                priority::current_text()
+            ),
+        );
+    }
+
+    // 掃描執行緒需在降權後建立（capset 只作用於呼叫的執行緒），並在 seccomp 前建立以免需要放行 clone
+    for guard in &mut guards {
+        if let Err(error) = guard.fd_scanner.start_pool(config.scan_threads) {
+            eprintln!("初始化失敗: 無法建立掃描執行緒: {error}");
+            std::process::exit(1);
+        }
+    }
+
+    open_shutdown_pipe();
+    if config.seccomp {
+        if let Err(error) = enter_sandbox(&config) {
+            eprintln!("初始化失敗: {error}");
+            std::process::exit(1);
+        }
+    }
+
+    let result = match config.subcommand {
+        Subcommand::Monitor if guards.len() > 1 => supervise_apps(&config, guards),
+        Subcommand::Monitor => supervise(&mut guards[0]),
+        // 沒有守護程序時自己執行，動作與原因同樣寫進歷史記錄
+        Subcommand::RestartNow => {
+            let guard = &mut guards[0];
+            guard.open_history();
+            match guard.restart_now(config.reason.clone()) {
+                Ok(outcome) => {
+                    guard.record_outcome(&outcome, true);
+                    Ok(Some(outcome))
+                }
+                Err(message) => {
+                    eprintln!("{} 未重啟: {message}", timestamp());
+                    std::process::exit(1);
+                }
+            }
+        }
+        Subcommand::Top
+        | Subcommand::Ctl
+        | Subcommand::GenerateMan
+        | Subcommand::GenerateUnit
+        | Subcommand::Explain
+        | Subcommand::History => {
+            unreachable!("top、ctl、generate-man、generate-unit、explain 與 history 在此之前已處理")
+        }
+    };
+    match result {
+        Ok(Some(outcome)) => exit_with_outcome(&config, outcome),
+        Ok(None) => {}
+        Err(error) => {
+            eprintln!("{} 執行錯誤: {}", timestamp(), error);
+            std::process::exit(1);
+        }
+    }
+}
+
+// panic 訊息與 backtrace 改走一般的日誌輸出（--log-file 或標準輸出），不直接寫到 stderr，
+// 在 journald 或檔案日誌中才找得到。掃描執行緒的 panic 也會經過這裡
+fn install_panic_hook(config: &Config) {
+    let config = config.clone();
+    panic::set_hook(Box::new(move |info| {
+        let payload = info.payload();
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|text| text.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "（非字串的 panic 內容）".to_string());
+        let location = info
+            .location()
+            .map(|location| format!("，{}:{}", location.file(), location.line()))
+            .unwrap_or_default();
+        log_at(
+            &config,
+            LogLevel::Error,
+            &format!(
+                "panic（執行緒 {}{location}）：{message}",
+                thread::current().name().unwrap_or("?")
+            ),
+        );
+        // 解析 backtrace 的符號需要白名單外的 syscall，seccomp 強制模式下擷取會讓整個程序被終止
+        if config.seccomp && !config.seccomp_log_only {
+            log(
+                &config,
+                "  （seccomp 啟用中不擷取 backtrace；需要時以 --seccomp-log 或不加 --seccomp 重現）",
+            );
+            return;
+        }
+        for line in std::backtrace::Backtrace::force_capture()
+            .to_string()
+            .lines()
+        {
+            log(&config, &format!("  {line}"));
+        }
+    }));
+}
+
+// 一小時內的 panic 次數；超過上限時回傳 None
+struct PanicBudget {
+    limit: usize,
+    times: VecDeque<Instant>,
+}
+
+impl PanicBudget {
+    const WINDOW: Duration = Duration::from_secs(3600);
+
+    fn record(&mut self, now: Instant) -> Option<usize> {
+        self.times.push_back(now);
+        while self
+            .times
+            .front()
+            .is_some_and(|time| now.duration_since(*time) >= Self::WINDOW)
+        {
+            self.times.pop_front();
+        }
+        (self.times.len() <= self.limit).then_some(self.times.len())
+    }
+}
+
+// 監控迴圈 panic 時不讓整個守護跟著結束：panic hook 已記錄訊息，這裡等一下（次數越多等越久）
+// 重建 inotify 與 peer 後端後重新開始。沒有 systemd 的環境沒人會把守護叫回來，
+// 但一直 panic 多半是無法自行恢復的問題，一小時內超過 --max-panics 次就以非 0 結束
+fn supervise(guard: &mut Guard) -> io::Result<Option<RestartOutcome>> {
+    let mut budget = PanicBudget {
+        limit: guard.config.max_panics_per_hour,
+        times: VecDeque::new(),
+    };
+    loop {
+        if let Ok(result) = panic::catch_unwind(AssertUnwindSafe(|| guard.run())) {
+            return result;
+        }
+        let Some(count) = budget.record(Instant::now()) else {
+            let message = format!(
+                "一小時內監控迴圈 panic 超過 --max-panics {} 次，結束",
+                budget.limit
+            );
+            log_at(&guard.config, LogLevel::Error, &message);
+            log(&guard.config, &guard.stats.summary(guard.pause.as_ref()));
+            return Err(io::Error::other(message));
+        };
+        let backoff = Duration::from_secs(5 * count as u64);
+        log_at(
+            &guard.config,
+            LogLevel::Warn,
+            &format!(
+                "監控迴圈 panic（一小時內第 {count} 次，上限 {}），{} 秒後重建 inotify 與 peer 後端並重新開始",
+                budget.limit,
+                backoff.as_secs()
+            ),
+        );
+        let resume_at = Instant::now() + backoff;
+        while Instant::now() < resume_at {
+            if SHUTDOWN_SIGNAL.load(Ordering::Relaxed) != 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        guard.rebuild_after_panic().map_err(io::Error::other)?;
+    }
+}
+
+// --app-name 名稱:門檻 時每個程式一份設定：名稱、門檻與重啟命令換成它自己的，日誌前綴加上名稱。
+// 沒有時只有原本的設定
+fn app_configs(config: &Config) -> Vec<Config> {
+    if config.apps.is_empty() {
+        return vec![config.clone()];
+    }
+    config
+        .apps
+        .iter()
+        .enumerate()
+        .map(|(at, app)| {
+            let mut app_config = config.clone();
+            app_config.app_name = app.names.join(",");
+            app_config.app_names = app.names.clone();
+            app_config.threshold = app.threshold.unwrap_or(config.threshold);
+            app_config.restart_cmd = app
+                .restart_cmd
+                .clone()
+                .unwrap_or_else(|| app.names[0].clone());
+            app_config.log_prefix = format!("{}[{}]", config.log_prefix, app_config.app_name);
+            app_config.apps.clear();
+            // 設定的警告只記一次
+            if at > 0 {
+                app_config.config_warnings.clear();
+            }
+            app_config
+        })
+        .collect()
+}
+
+// 分別監控多個程式：每個程式的 Guard 在自己的執行緒各自量測、冷卻與重啟。SIGTERM/SIGINT
+// 由哪個執行緒接到都一樣，結束訊號的管線會喚醒每個執行緒的等待；
+// 任一個以重啟結果或錯誤結束時同樣停下其他程式，整個守護以它的結果結束
+fn supervise_apps(config: &Config, guards: Vec<Guard>) -> io::Result<Option<RestartOutcome>> {
+    let mut running = Vec::new();
+    for mut guard in guards {
+        let label = guard.config.app_name.clone();
+        // 執行緒名稱會成為 fork 出的子程序 exec 前的 comm，不能與程式名稱相同，否則重啟驗證可能把它當成程式
+        let handle = thread::Builder::new()
+            .name(format!("guard:{label}"))
+            .spawn(move || supervise(&mut guard))?;
+        running.push((label, handle));
+    }
+    let mut result = Ok(None);
+    while !running.is_empty() {
+        let signal = SHUTDOWN_SIGNAL.load(Ordering::Relaxed);
+        let Some(at) = running.iter().position(|(_, handle)| handle.is_finished()) else {
+            thread::sleep(Duration::from_millis(100));
+            continue;
+        };
+        let (label, handle) = running.swap_remove(at);
+        let finished = handle
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other(format!("{label} 的監控執行緒 panic"))));
+        if matches!(finished, Ok(None)) || !matches!(result, Ok(None)) {
+            continue;
+        }
+        if !running.is_empty() && signal == 0 {
+            log(config, &format!("{label} 的監控已結束，停止其他程式的監控"));
+            request_shutdown(libc::SIGTERM);
+        }
+        result = finished;
+    }
+    result
+}
+
+// 守護程序執行中時由它重啟，冷卻與熔斷的狀態才會一致；沒有守護程序時返回，由呼叫端直接執行
+fn forward_restart(config: &Config, path: &Path) {
+    let command = match &config.reason {
+        Some(reason) => format!("restart {reason}"),
+        None => "restart".to_string(),
+    };
+    // 守護程序跑完整個流程才回覆：關閉最多約 11 秒，再加上驗證時間
+    let timeout = Duration::from_secs(config.verify_timeout_seconds + 60);
+    match control::request(path, &command, timeout) {
+        Ok(reply) => {
+            print!("{reply}");
+            let code = if reply.starts_with("重啟失敗") {
+                3
+            } else if reply.starts_with("錯誤") {
+                1
+            } else {
+                0
+            };
+            std::process::exit(code);
+        }
+        Err(error)
+            if matches!(
+                error.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+            ) =>
+        {
+            log(
+                config,
+                &format!("控制 socket {} 沒有守護程序，直接執行重啟", path.display()),
+            );
+        }
+        // 已送出要求卻沒拿到回覆時不能再自行重啟，否則可能重啟兩次
+        Err(error) => {
+            eprintln!("透過控制 socket {} 要求重啟失敗: {error}", path.display());
+            std::process::exit(1);
+        }
+    }
+}
+
+fn drop_privileges(config: &Config, user: &str) -> Result<(), String> {
+    let target = privileges::lookup_user(user)?;
+    privileges::drop_to(&target)?;
+    let caps: Vec<&str> = privileges::RETAINED_CAPS
+        .iter()
+        .map(|(_, name)| *name)
+        .collect();
+    log(
+        config,
+        &format!(
+            "已降權為 {}（uid={} gid={}），保留能力: {}",
+            target.name,
+            target.uid,
+            target.gid,
+            caps.join(",")
+        ),
+    );
+    Ok(())
+}
+
+// seccomp 時只允許白名單內的命令；只為了保留優先權而啟動時不限制
+fn helper_policy(config: &Config) -> sandbox::HelperPolicy {
+    let mut commands = vec![user_command(&config.restart_cmd, true)];
+    if let Some(command) = &config.reconnect_cmd {
+        commands.push(user_command(command, true));
+    }
+    if let Some(command) = &config.notify_cmd {
+        commands.push(user_command(command, false));
+    }
+    if let Some(command) = &config.policy_cmd {
+        commands.push(user_command(command, false));
+    }
+    if let Some(command) = &config.on_failure_cmd {
+        commands.push(user_command(command, false));
+    }
+    for member in &config.group_members {
+        commands.push(user_command(&member.restart_cmd, true));
+    }
+    sandbox::HelperPolicy {
+        commands: commands.iter().map(CommandSpec::argv).collect(),
+        programs: ["notify-send"]
+            .into_iter()
+            .chain(cfg!(feature = "backend-ss").then_some("ss"))
+            .chain((config.backend == BackendChoice::Lsof).then_some("lsof"))
+            .chain(
+                (config.on_failure_action == FailureAction::RebootSession
+                    || config.only_when_idle_seconds.is_some())
+                .then_some("loginctl"),
+            )
+            .chain(
+                config
+                    .core_dump_dir
+                    .is_some()
+                    .then_some(["gcore", "gdb"])
+                    .into_iter()
+                    .flatten(),
+            )
+            .map(str::to_string)
+            .collect(),
+        unrestricted: !config.seccomp,
+    }
+}
+
+// helper 已在 main 中啟動
+fn enter_sandbox(config: &Config) -> Result<(), String> {
+    let allowed = sandbox::install_filter(sandbox::FilterFeatures {
+        kill: !config.dry_run,
+        control: config.control_socket.is_some(),
+        capture: config.capture_dir.is_some(),
+        core_dump: config.core_dump_dir.is_some(),
+        log_rotate: config.log_max_bytes.is_some(),
+        state_file: config.state_file.is_some(),
+        history: config.history_file.is_some(),
+        peer_lookup: config.connections_from == ConnectionSource::Fd,
+        log_only: config.seccomp_log_only,
+    })?;
+    log(
+        config,
+        &format!(
+            "seccomp 已啟用（{} 個允許的 syscall，違規{}）",
+            allowed,
+            if config.seccomp_log_only {
+                "只記錄"
+            } else {
+                "即終止"
+            }
+        ),
+    );
+    Ok(())
+}
+
+fn exit_with_outcome(config: &Config, outcome: RestartOutcome) -> ! {
+    match outcome {
+        RestartOutcome::DryRun => {
+            log(config, "摘要：dry-run 模式，未實際重啟，結束");
+            std::process::exit(0);
+        }
+        RestartOutcome::Reconnected { before, after } => {
+            log(
+                config,
+                &format!(
+                    "摘要：柔性重連後 {} X11 連線 {before} → {after} 條，未重啟，結束",
+                    config.app_name
+                ),
+            );
+            std::process::exit(0);
+        }
+        RestartOutcome::Reloaded { before, after } => {
+            log(
+                config,
+                &format!(
+                    "摘要：送出 {} 重新載入後 {} X11 連線 {before} → {after} 條，未重啟，結束",
+                    signal_name(config.reload_signal),
+                    config.app_name
+                ),
+            );
+            std::process::exit(0);
+        }
+        RestartOutcome::Restarted { old_pids, new_pids } => {
+            log(
+                config,
+                &format!(
+                    "摘要：{} 已重啟，舊 PID {:?} → 新 PID {:?}，結束",
+                    config.app_name, old_pids, new_pids
+                ),
+            );
+            std::process::exit(0);
+        }
+        RestartOutcome::Failed(reason) => {
+            eprintln!("{} 重啟失敗: {}", timestamp(), reason);
+            std::process::exit(3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_duration_accepts_plain_seconds_and_units() {
+        assert_eq!(parse_duration_secs("90"), Ok(90));
+        assert_eq!(parse_duration_secs("90s"), Ok(90));
+        assert_eq!(parse_duration_secs("2m"), Ok(120));
+        assert_eq!(parse_duration_secs("1h"), Ok(3600));
+        assert_eq!(parse_duration_secs("1h30m"), Ok(5400));
+        assert_eq!(parse_duration_secs("1h0m5s"), Ok(3605));
+        assert_eq!(parse_duration_secs("1d12h"), Ok(129600));
+    }
+
+    #[test]
+    fn log_order_fields_only_increase() {
+        let sequence = |field: String| field[1..].parse::<u64>().unwrap();
+        let first = sequence(log_order_field(LogOrder::Seq));
+        let second = sequence(log_order_field(LogOrder::Seq));
+        assert!(second > first);
+        let uptime = |field: String| {
+            assert!(field.starts_with('+'), "{field}");
+            field[1..].parse::<f64>().unwrap()
+        };
+        let earlier = uptime(log_order_field(LogOrder::Uptime));
+        thread::sleep(Duration::from_millis(5));
+        assert!(uptime(log_order_field(LogOrder::Uptime)) > earlier);
+    }
+
+    #[test]
+    fn parse_listening_inodes_only_returns_x11_listeners() {
+        let body = "\
+Num       RefCount Protocol Flags    Type St Inode Path
+0000000000000000: 00000002 00000000 00010000 0001 01 20001 /tmp/.X11-unix/X0
+0000000000000000: 00000002 00000000 00010000 0001 01 20002 @/tmp/.X11-unix/X0
+0000000000000000: 00000003 00000000 00000000 0001 03 20003 /tmp/.X11-unix/X0
+0000000000000000: 00000002 00000000 00010000 0001 01 20004 /tmp/.X11-unix/X1
+0000000000000000: 00000003 00000000 00000000 0001 03 20005
+";
+        let inodes = parse_listening_inodes(body, &x11_socket_names("/tmp/.X11-unix/X0", None));
+        let expected: HashSet<String> = ["20001", "20002"].iter().map(|v| v.to_string()).collect();
+        assert_eq!(inodes, expected);
+    }
+
+    #[test]
+    fn x11_socket_revalidation_detects_replaced_socket() {
+        let dir = env::temp_dir().join(format!("qq-x11-guard-sock-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("X0");
+        let path_text = path.to_string_lossy().to_string();
+        let mut guard = Guard::new(Config {
+            display: ":4242".to_string(),
+            ..Config::default()
+        })
+        .expect("建立 Guard");
+        guard.x11_endpoint = X11Endpoint::Unix(path_text.clone());
+
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        guard.validate_x11_socket();
+        let first = guard.x11_socket.clone().unwrap().expect("socket 應有效");
+        guard.last_connections = Some(HashMap::new());
+        guard.validate_x11_socket();
+        assert!(guard.last_connections.is_some());
+
+        // 模擬 tmpfs 被清空後重建：路徑相同，但檔案與監聽 socket 都換了
+        drop(listener);
+        fs::remove_file(&path).unwrap();
+        let _listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        guard.validate_x11_socket();
+        let second = guard.x11_socket.clone().unwrap().expect("socket 應有效");
+        assert_ne!(first, second);
+        assert!(guard.last_connections.is_none());
+
+        fs::remove_file(&path).unwrap();
+        guard.last_connections = Some(HashMap::new());
+        guard.validate_x11_socket();
+        assert!(guard.x11_socket.clone().unwrap().is_err());
+        assert!(guard.last_connections.is_none());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn proc_root_joins_paths_regardless_of_trailing_slash() {
+        let expected = PathBuf::from("/host/proc/42/fd");
+        assert_eq!(ProcRoot::new("/host/proc").pid_entry(42, "fd"), expected);
+        assert_eq!(ProcRoot::new("/host/proc/").pid_entry(42, "fd"), expected);
+        assert_eq!(ProcRoot::new("/host//proc//").pid_entry(42, "fd"), expected);
+        assert_eq!(
+            ProcRoot::new("/host/proc/").join("net/unix"),
+            PathBuf::from("/host/proc/net/unix")
+        );
+    }
+
+    // 假的 proc 目錄樹：comm、stat（啟動時間取 PID 本身）與指向 socket:[N] 的 fd 連結
+    struct FakeProc {
+        root: PathBuf,
+    }
+
+    impl FakeProc {
+        fn new(tag: &str) -> Self {
+            let root = env::temp_dir().join(format!("qq-x11-guard-{tag}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(root.join("net")).unwrap();
+            // 測試用的 :0 與 :4242 有 X server 在監聽（不屬於任何假程序），否則監聽表是空的，程序會一律被當成可能的 X server
+            fs::write(
+                root.join("net/unix"),
+                "Num       RefCount Protocol Flags    Type St Inode Path\n\
+                 0000000000000000: 00000002 00000000 00010000 0001 01 4242000 /tmp/.X11-unix/X4242\n\
+                 0000000000000000: 00000002 00000000 00010000 0001 01 4242001 /tmp/.X11-unix/X0\n",
+            )
+            .unwrap();
+            Self { root }
+        }
+
+        fn proc_root(&self) -> ProcRoot {
+            ProcRoot::new(&format!("{}/", self.root.display()))
+        }
+
+        fn add(&self, pid: i32, comm: &str, sockets: &[&str]) {
+            self.add_child(pid, 1, comm, sockets);
+        }
+
+        fn add_child(&self, pid: i32, ppid: i32, comm: &str, sockets: &[&str]) {
+            let dir = self.root.join(pid.to_string());
+            let fd_dir = dir.join("fd");
+            fs::create_dir_all(&fd_dir).unwrap();
+            fs::write(dir.join("comm"), format!("{comm}\n")).unwrap();
+            fs::write(
+                dir.join("stat"),
+                format!(
+                    "{pid} ({comm}) S {ppid} {pid} {pid} 0 -1 0 0 0 0 0 0 0 0 0 20 0 1 0 {pid} 0 0\n"
+                ),
+            )
+            .unwrap();
+            for (index, inode) in sockets.iter().enumerate() {
+                let target = format!("socket:[{inode}]");
+                std::os::unix::fs::symlink(target, fd_dir.join((index + 3).to_string())).unwrap();
+            }
+            std::os::unix::fs::symlink("/dev/null", fd_dir.join("0")).unwrap();
+        }
+
+        fn remove(&self, pid: i32) {
+            fs::remove_dir_all(self.root.join(pid.to_string())).unwrap();
+        }
+    }
+
+    impl Drop for FakeProc {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    #[test]
+    fn name_falls_back_to_stat_and_cmdline_without_comm() {
+        let stat = parse_proc_stat(
+            b"4242 (qq (beta) x) S 1 4242 4242 0 -1 0 0 0 0 0 0 0 0 0 20 0 1 0 777 0 0\n",
+        )
+        .expect("解析 stat");
+        assert_eq!(stat.comm, "qq (beta) x");
+        assert_eq!((stat.state, stat.ppid, stat.start_time), ('S', 1, 777));
+        assert!(parse_proc_stat(b"4242 qq S 1").is_none());
+
+        let fake = FakeProc::new("nocomm");
+        fake.add(100, "qq", &[]);
+        fake.add(200, "qq", &[]);
+        fake.add(300, "other", &[]);
+        fs::remove_file(fake.root.join("200/comm")).unwrap();
+        // comm 與 stat 都沒有時取 argv[0] 的檔名，並和核心一樣截斷成 15 位元組
+        fs::remove_file(fake.root.join("300/comm")).unwrap();
+        fs::remove_file(fake.root.join("300/stat")).unwrap();
+        fs::write(
+            fake.root.join("300/cmdline"),
+            "/opt/QQ/qq-with-a-long-name\0--no-sandbox\0",
+        )
+        .unwrap();
+        let proc_root = fake.proc_root();
+        assert_eq!(
+            find_pids_by_name(&proc_root, &["qq".to_string()], &[]),
+            vec![100, 200]
+        );
+        assert_eq!(
+            process_name(&proc_root, 300).as_deref(),
+            Some("qq-with-a-long-")
+        );
+    }
+
+    #[test]
+    fn synthetic_proc_tree_drives_pid_and_socket_discovery() {
+        let fake = FakeProc::new("proc");
+        let root = &fake.root;
+        fake.add(100, "qq", &["5001", "5002"]);
+        fake.add(200, "qq", &["6001"]);
+        fake.add(300, "Xorg", &["7001"]);
+        // 名稱相同但持有 X server 監聽 socket 的程序必須被排除
+        fake.add(400, "qq", &["9000"]);
+        // 尚未被回收的殭屍不算
+        fake.add(500, "qq", &[]);
+        fs::write(
+            root.join("500/stat"),
+            "500 (qq) Z 1 500 500 0 -1 0 0 0 0 0 0 0 0 0 20 0 1 0 500 0 0\n",
+        )
+        .unwrap();
+        fs::create_dir_all(root.join("net")).unwrap();
+        fs::write(
+            root.join("net/unix"),
+            "Num       RefCount Protocol Flags    Type St Inode Path\n\
+             0000000000000000: 00000002 00000000 00010000 0001 01 9000 /tmp/.X11-unix/X0\n",
+        )
+        .unwrap();
+
+        let proc_root = fake.proc_root();
+        let (pids, excluded) = find_app_pids(
+            &mut FdScanner::new(proc_root.clone()),
+            &["qq".to_string()],
+            &[],
+            &X11Endpoint::Unix("/tmp/.X11-unix/X0".to_string()),
+        );
+        assert_eq!(pids, vec![100, 200]);
+        assert_eq!(excluded, vec![400]);
+
+        // 讀不到監聽表時無法從 fd 判斷：沒有 SO_PEERCRED 確認 server 是誰就全部排除
+        fs::write(
+            root.join("net/unix"),
+            "Num       RefCount Protocol Flags    Type St Inode Path\n",
+        )
+        .unwrap();
+        let missing = X11Endpoint::Unix(root.join("X0").to_string_lossy().into_owned());
+        let (pids, excluded) = find_app_pids(
+            &mut FdScanner::new(proc_root.clone()),
+            &["qq".to_string()],
+            &[],
+            &missing,
+        );
+        assert!(pids.is_empty());
+        assert_eq!(excluded, vec![100, 200, 400]);
+        // SO_PEERCRED 確認了監聽者（這裡是測試程序自己）時，只排除它
+        let own = std::process::id() as i32;
+        fake.add(own, "qq", &[]);
+        let listener = std::os::unix::net::UnixListener::bind(root.join("X0")).unwrap();
+        let (pids, excluded) = find_app_pids(
+            &mut FdScanner::new(proc_root.clone()),
+            &["qq".to_string()],
+            &[],
+            &missing,
+        );
+        drop(listener);
+        assert_eq!(excluded, vec![own]);
+        assert!(pids.contains(&100) && !pids.contains(&own));
+
+        let listening: HashSet<String> = ["9000".to_string()].into();
+        let sockets: HashSet<String> = ["5001".to_string()].into();
+        // fd 目錄讀不到（例如 root 的 Xorg）時可能就是 server
+        assert!(possibly_x_server(300, None, None, &listening));
+        assert!(possibly_x_server(300, Some(42), None, &listening));
+        assert!(!possibly_x_server(100, None, Some(&sockets), &listening));
+        assert!(possibly_x_server(42, Some(42), Some(&sockets), &listening));
+        assert!(possibly_x_server(
+            100,
+            None,
+            Some(&sockets),
+            &HashSet::new()
+        ));
+        assert!(!possibly_x_server(
+            100,
+            Some(42),
+            Some(&sockets),
+            &HashSet::new()
+        ));
+
+        let mut scanner = FdScanner::new(proc_root.clone());
+        let scanned = scanner.socket_inodes_by_pid(&pids);
+        let inodes: HashSet<String> = ["5001", "5002"].iter().map(|v| v.to_string()).collect();
+        assert_eq!(scanned[0], (100, inodes));
+        assert_eq!(scanned[1].1.len(), 1);
+        assert!(scanner.unreadable.is_empty());
+        assert_eq!(process_start_time(&proc_root, 200), Some(200));
+    }
+
+    #[test]
+    fn host_pids_are_paired_with_their_own_netns() {
+        let fake = FakeProc::new("netns");
+        let root = &fake.root;
+        let unix_table = |inode: &str| {
+            format!(
+                "Num       RefCount Protocol Flags    Type St Inode Path\n\
+                 0000000000000000: 00000002 00000000 00010000 0001 01 {inode} /tmp/.X11-unix/X0\n"
+            )
+        };
+        // 主機上的 qq 與 X server
+        fake.add(100, "qq", &["5001"]);
+        fake.add(300, "Xorg", &["7001"]);
+        // 容器中的 qq（容器內是 PID 1，主機 /proc 中是 4100）與容器內同名、自己開 X server 的程序
+        fake.add(4100, "qq", &["6001"]);
+        fake.add(4200, "qq", &["8001"]);
+        fs::create_dir_all(root.join("net")).unwrap();
+        fs::write(root.join("net/unix"), unix_table("7001")).unwrap();
+        for (pid, netns, listener) in [
+            (100, 4026531992u64, "7001"),
+            (300, 4026531992, "7001"),
+            (4100, 4026532500, "8001"),
+            (4200, 4026532500, "8001"),
+        ] {
+            let dir = root.join(pid.to_string());
+            fs::create_dir_all(dir.join("ns")).unwrap();
+            std::os::unix::fs::symlink(format!("net:[{netns}]"), dir.join("ns/net")).unwrap();
+            fs::create_dir_all(dir.join("net")).unwrap();
+            fs::write(dir.join("net/unix"), unix_table(listener)).unwrap();
+        }
+
+        let proc_root = fake.proc_root();
+        assert_eq!(netns_of(&proc_root, 4100), Some(4026532500));
+        assert_eq!(netns_of(&proc_root, 4242), None);
+        // 4200 的監聽 socket 只在容器的 unix socket 表裡，只查主機的表會把它當成一般的 qq
+        let (pids, excluded) = find_app_pids(
+            &mut FdScanner::new(proc_root.clone()),
+            &["qq".to_string()],
+            &[],
+            &X11Endpoint::Unix("/tmp/.X11-unix/X0".to_string()),
+        );
+        assert_eq!(pids, vec![100, 4100]);
+        assert_eq!(excluded, vec![4200]);
+
+        // 沒有 self 連結（或指向本程序）時不擋；指向其他 PID 表示不同的 PID 命名空間
+        assert!(check_pid_namespace(&proc_root).is_ok());
+        std::os::unix::fs::symlink("1", root.join("self")).unwrap();
+        assert!(check_pid_namespace(&proc_root).is_err());
+        assert!(check_pid_namespace(&ProcRoot::default()).is_ok());
+    }
+
+    #[test]
+    fn matching_is_process_level_and_never_returns_thread_ids() {
+        let fake = FakeProc::new("threads");
+        let root = &fake.root;
+        // 程序 100 名為 launcher，它的執行緒 101 改名為 qq：不能因為執行緒名稱而比對到
+        fake.add(100, "launcher", &[]);
+        fs::create_dir_all(root.join("100/task/101")).unwrap();
+        fs::write(root.join("100/task/101/comm"), "qq\n").unwrap();
+        // 程序 200 名為 qq，它的執行緒 201 也叫 qq；真正的 /proc 不會列出 201，
+        // 這裡模擬直接存取 /proc/201 的情況，Tgid 指回 200
+        fake.add(200, "qq", &[]);
+        fake.add(201, "qq", &[]);
+        fs::write(
+            root.join("200/status"),
+            "Name:\tqq\nTgid:\t200\nPid:\t200\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("201/status"),
+            "Name:\tqq\nTgid:\t200\nPid:\t201\n",
+        )
+        .unwrap();
+
+        let proc_root = fake.proc_root();
+        assert_eq!(
+            find_pids_by_name(&proc_root, &["qq".to_string()], &[]),
+            vec![200]
+        );
+        assert!(!is_thread_group_leader(&proc_root, 201));
+        assert_eq!(
+            find_pids_by_name(&proc_root, &["launcher".to_string()], &[]),
+            vec![100]
+        );
+    }
+
+    #[test]
+    fn cmdline_rules_split_on_nul_and_match_positionally() {
+        assert_eq!(
+            split_cmdline(b"java\0-jar\0/opt/qq.jar\0"),
+            vec!["java", "-jar", "/opt/qq.jar"]
+        );
+        // 空參數保留位置；沒有結尾 NUL（程序改寫過 argv）也照樣切開
+        assert_eq!(split_cmdline(b"java\0\0qq.jar"), vec!["java", "", "qq.jar"]);
+
+        let fake = FakeProc::new("cmdline");
+        let root = &fake.root;
+        fake.add(300, "java", &[]);
+        fake.add(301, "java", &[]);
+        fake.add(302, "java", &[]);
+        fs::write(root.join("300/cmdline"), b"java\0-jar\0/opt/qq/qq.jar\0").unwrap();
+        fs::write(root.join("301/cmdline"), b"java\0-jar\0/opt/ide/ide.jar\0").unwrap();
+        // 殭屍或核心執行緒：cmdline 是空的
+        fs::write(root.join("302/cmdline"), b"").unwrap();
+
+        let proc_root = fake.proc_root();
+        let java = ["java".to_string()];
+        let find = |rules: &[CmdlineMatch]| find_pids_by_name(&proc_root, &java, rules);
+        assert_eq!(find(&[]), vec![300, 301, 302]);
+        assert_eq!(
+            find(&[parse_cmdline_arg(r"2=qq\.jar$").unwrap()]),
+            vec![300]
+        );
+        assert_eq!(
+            find(&[parse_cmdline_arg("1=^-jar$").unwrap()]),
+            vec![300, 301]
+        );
+        // 超出參數個數的 index 即使 regex 可以匹配空字串也不符合
+        assert!(find(&[parse_cmdline_arg("9=.*").unwrap()]).is_empty());
+        let joined = CmdlineMatch::Joined(pattern::Pattern::parse(r"-jar /opt/ide/").unwrap());
+        assert_eq!(find(std::slice::from_ref(&joined)), vec![301]);
+        // 多條規則須全部成立
+        assert!(find(&[joined, parse_cmdline_arg(r"2=qq\.jar").unwrap()]).is_empty());
+
+        // --match-cmdline 不看名稱：經包裝腳本啟動、comm 不同的程序也算，空的 cmdline 仍不符合
+        fake.add(303, "qq-wrapper-lon", &[]);
+        fs::write(root.join("303/cmdline"), b"/bin/sh\0/opt/qq/run-qq.sh\0").unwrap();
+        let only = |regex: &str| CmdlineMatch::Only(pattern::Pattern::parse(regex).unwrap());
+        assert_eq!(find(&[only("/opt/qq/")]), vec![300, 303]);
+        assert_eq!(
+            find_pids_by_name(&proc_root, &["qq".to_string()], &[only(".*")]),
+            vec![300, 301, 303]
+        );
+        // 數百 KB 的命令列只比對開頭的 MAX_MATCHED_CMDLINE 位元組，截斷處不落在字元中間
+        fake.add(305, "node", &[]);
+        let filler = "參".repeat(100_000);
+        fs::write(
+            root.join("305/cmdline"),
+            format!("/opt/qq/qq\0--data={filler}\0--tail-marker\0"),
+        )
+        .unwrap();
+        assert_eq!(find(&[only("^/opt/qq/qq --data=")]), vec![305]);
+        assert_eq!(find(&[only("tail-marker")]), Vec::<i32>::new());
+        let args = [format!("/opt/qq/qq {filler}")];
+        let joined = joined_cmdline(&args);
+        assert!(joined.len() <= MAX_MATCHED_CMDLINE);
+        assert!(joined.len() > MAX_MATCHED_CMDLINE - "參".len());
+        assert_eq!(joined_cmdline(&["a".into(), "b".into()]), "a b");
+
+        // --app-name-regex 取代名稱的完全相符，命令列條件照常套用
+        fake.add(304, "qq-3.2.15", &[]);
+        fs::write(root.join("304/cmdline"), b"/opt/qq/qq-3.2.15\0").unwrap();
+        let name = |regex: &str| CmdlineMatch::Name(pattern::Pattern::parse(regex).unwrap());
+        assert_eq!(
+            find_pids_by_name(&proc_root, &["qq".to_string()], &[name(r"^qq-[0-9.]+$")]),
+            vec![304]
+        );
+        assert_eq!(
+            find(&[name("^java$"), parse_cmdline_arg(r"2=qq\.jar$").unwrap()]),
+            vec![300]
+        );
+        assert!(name_matches(
+            &[],
+            &[name("^qq"), name("wrapper")],
+            "qq-wrapper-lon"
+        ));
+        assert!(!name_matches(&java, &[name("^qq")], "java"));
+
+        let args = ["qq-x11-guard-rs", "--app-name-regex", "qq-(3"].map(String::from);
+        assert_eq!(
+            parse_arg_list(&args, 0).unwrap_err(),
+            "--app-name-regex qq-(3 無效：缺少 )（位置 3 的群組）"
+        );
+        let parse = |extra: &[&str]| {
+            let mut args = vec!["qq-x11-guard-rs".to_string()];
+            args.extend(extra.iter().map(|arg| arg.to_string()));
+            parse_arg_list(&args, 0)
+        };
+        let config = parse(&["--match-cmdline", "^/opt/QQ/qq( |$)"]).unwrap();
+        assert!(matches_by_cmdline_only(&config.cmdline_match));
+        assert!(config.cmdline_match[0].matches(&["/opt/QQ/qq".into(), "--no-sandbox".into()]));
+        assert!(!config.cmdline_match[0].matches(&["/opt/QQ/qq-helper".into()]));
+        assert_eq!(
+            parse(&["--match-cmdline", "a", "--match-cmdline", "b"]).unwrap_err(),
+            "--match-cmdline 只能指定一次"
+        );
+        assert_eq!(
+            parse(&["--match-cmdline"]).unwrap_err(),
+            "--match-cmdline 需要值"
+        );
+        assert!(parse(&["--match-cmdline", "(qq"])
+            .unwrap_err()
+            .starts_with("--match-cmdline "));
+        assert_eq!(
+            parse(&["--match-cmdline", "qq", "--learn-new-name"]).unwrap_err(),
+            "--learn-new-name 不能與 --match-cmdline 併用（不比對程序名稱）"
+        );
+        for invalid in ["x=qq", "-1=qq", "qq.jar", "2=(qq"] {
+            assert!(
+                parse_cmdline_arg(invalid).is_err(),
+                "{invalid:?} 應該被拒絕"
+            );
+        }
+    }
+
+    #[test]
+    fn group_order_defaults_to_companions_first_and_must_list_every_member() {
+        let mut config = Config {
+            app_name: "qq".to_string(),
+            group_members: vec![parse_group_member("imebridge = ime-bridge --daemon").unwrap()],
+            ..Config::default()
+        };
+        assert_eq!(config.group_members[0].restart_cmd, "ime-bridge --daemon");
+        resolve_group_order(&mut config).unwrap();
+        assert_eq!(config.group_stop_order, ["imebridge", "qq"]);
+        assert_eq!(config.group_start_order, ["imebridge", "qq"]);
+
+        config.group_start_order = parse_group_order("qq, imebridge");
+        assert!(resolve_group_order(&mut config).is_ok());
+        config.group_stop_order = parse_group_order("qq");
+        assert!(resolve_group_order(&mut config).is_err());
+
+        for spec in ["=cmd", "name=", "no-separator", "averyverylongname=cmd"] {
+            assert!(parse_group_member(spec).is_err(), "{spec} 應該被拒絕");
+        }
+        let mut orphan_order = Config {
+            group_stop_order: vec!["qq".to_string()],
+            ..Config::default()
+        };
+        assert!(resolve_group_order(&mut orphan_order).is_err());
+    }
+
+    #[test]
+    fn several_app_names_form_one_primary() {
+        let args: Vec<String> = [
+            "qq-x11-guard-rs",
+            "--app-name",
+            "qq, qq-helper",
+            "--app-name",
+            r"a\x2cb",
+            "--group-member",
+            "imebridge = ime-bridge",
+            "--group-stop-order",
+            "qq-helper,imebridge",
+        ]
+        .map(String::from)
+        .to_vec();
+        let config = parse_arg_list(&args, 0).unwrap();
+        assert_eq!(config.app_names, ["qq", "qq-helper", "a,b"]);
+        assert_eq!(config.app_name, "qq,qq-helper,a,b");
+        // 順序中寫任一個名稱都代表整組主程式
+        assert_eq!(config.group_stop_order, ["qq,qq-helper,a,b", "imebridge"]);
+
+        let error = |extra: &[&str]| {
+            let mut args = vec!["qq-x11-guard-rs".to_string()];
+            args.extend(extra.iter().map(|arg| arg.to_string()));
+            parse_arg_list(&args, 0).unwrap_err()
+        };
+        assert_eq!(error(&["--app-name", "qq,qq"]), "--app-name 名稱重複: qq");
+        assert_eq!(error(&["--app-name", "qq,"]), "--app-name 的名稱不能是空的");
+        assert_eq!(
+            error(&[
+                "--app-name",
+                "qq,helper",
+                "--group-member",
+                "helper = helper"
+            ]),
+            "--group-member 名稱重複: helper"
+        );
+        assert_eq!(
+            error(&[
+                "--app-name",
+                "qq,helper",
+                "--group-member",
+                "ime = ime",
+                "--group-start-order",
+                "qq,helper,ime"
+            ]),
+            "--group-start-order 必須恰好列出每個成員一次：ime,qq"
+        );
+    }
+
+    #[test]
+    fn app_names_with_thresholds_are_watched_separately() {
+        let args: Vec<String> = [
+            "qq-x11-guard-rs",
+            "--threshold",
+            "15",
+            "--app-name",
+            "qq:10",
+            "--app-name",
+            "wechat,wechat-helper:20=/opt/wechat/wechat --x11 DISPLAY=:0",
+            "--app-name",
+            r"odd\x3aname",
+            "--log-prefix",
+            "[g]",
+        ]
+        .map(String::from)
+        .to_vec();
+        let config = parse_arg_list(&args, 0).unwrap();
+        assert_eq!(
+            config.app_names,
+            ["qq", "wechat", "wechat-helper", "odd:name"]
+        );
+        let apps: Vec<_> = app_configs(&config)
+            .into_iter()
+            .map(|app| {
+                (
+                    app.app_names,
+                    app.threshold,
+                    app.restart_cmd,
+                    app.log_prefix,
+                )
+            })
+            .collect();
+        assert_eq!(
+            apps,
+            [
+                (
+                    vec!["qq".to_string()],
+                    10,
+                    "qq".to_string(),
+                    "[g][qq]".to_string()
+                ),
+                (
+                    vec!["wechat".to_string(), "wechat-helper".to_string()],
+                    20,
+                    "/opt/wechat/wechat --x11 DISPLAY=:0".to_string(),
+                    "[g][wechat,wechat-helper]".to_string()
+                ),
+                // 沒有門檻的沿用 --threshold
+                (
+                    vec!["odd:name".to_string()],
+                    15,
+                    "odd:name".to_string(),
+                    "[g][odd:name]".to_string()
+                ),
+            ]
+        );
+        // 都沒有門檻時維持同一組
+        let pooled = parse_arg_list(&args[..7], 0).unwrap();
+        assert_eq!(app_configs(&pooled).len(), 2);
+        let plain = ["qq-x11-guard-rs", "--app-name", "qq", "--app-name", "tim"]
+            .map(String::from)
+            .to_vec();
+        assert_eq!(app_configs(&parse_arg_list(&plain, 0).unwrap()).len(), 1);
 
-    for source in sources {
-        let output = Command::new("ss")
-            .args(["-xnpH", "src", source.as_str()])
-            .output();
-        let output = match output {
-            Ok(value) => value,
-            Err(_) => continue,
+        let error = |extra: &[&str]| {
+            let mut args = vec!["qq-x11-guard-rs".to_string()];
+            args.extend(extra.iter().map(|arg| arg.to_string()));
+            parse_arg_list(&args, 0).unwrap_err()
         };
-        if !output.status.success() {
-            continue;
+        assert_eq!(
+            error(&["--app-name", "qq:0"]),
+            "--app-name 門檻必須是 >= 1 的整數: qq:0"
+        );
+        assert_eq!(
+            error(&["--app-name", "qq:10="]),
+            "--app-name = 之後的重啟命令不能是空的: qq:10="
+        );
+        assert_eq!(
+            error(&["--app-name", "qq:10", "--app-name", "tim,qq:5"]),
+            "--app-name 名稱重複: qq"
+        );
+        assert_eq!(
+            error(&["--app-name", "qq:10", "--restart-cmd", "qq"]),
+            "--app-name 名稱:門檻 分別監控多個程式時，重啟命令寫成 --app-name 名稱:門檻=命令，不能用 --restart-cmd"
+        );
+        assert_eq!(
+            error(&["--control-socket", "/tmp/g.sock", "--app-name", "qq:10"]),
+            "--app-name 名稱:門檻 分別監控多個程式時不能與 --control-socket 併用"
+        );
+    }
+
+    #[test]
+    fn allowed_displays_gate_guard_startup() {
+        let config = |allowed: &[&str]| Config {
+            display: ":4242.0".to_string(),
+            allowed_displays: allowed.iter().map(|item| item.to_string()).collect(),
+            ..Config::default()
+        };
+        assert!(Guard::new(config(&[])).is_ok());
+        assert!(Guard::new(config(&[":0", ":4242"])).is_ok());
+        let error = Guard::new(config(&[":0", ":1"])).err().unwrap();
+        assert!(error.contains(":4242.0"), "{error}");
+    }
+
+    #[test]
+    fn parse_signal_accepts_names_and_numbers() {
+        assert_eq!(parse_signal("USR1"), Ok(libc::SIGUSR1));
+        assert_eq!(parse_signal("sighup"), Ok(libc::SIGHUP));
+        assert_eq!(parse_signal("12"), Ok(12));
+        for text in ["", "KILL", "9", "0", "SIGFOO", "999"] {
+            assert!(parse_signal(text).is_err(), "{text} 應該被拒絕");
         }
+        assert_eq!(signal_name(libc::SIGUSR2), "SIGUSR2");
 
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        for line in stdout.lines() {
-            let tokens: Vec<&str> = line.split_whitespace().collect();
-            if let Some(peer) = extract_peer_inode(&tokens, socket_path) {
-                inodes.insert(peer.to_string());
+        // --escalate 可以用 KILL
+        let ladder = parse_stop_ladder("TERM:8,sigint:3s,9:3").unwrap();
+        let steps: Vec<(i32, u64)> = ladder
+            .iter()
+            .map(|step| (step.signal, step.wait.as_secs()))
+            .collect();
+        assert_eq!(
+            steps,
+            [(libc::SIGTERM, 8), (libc::SIGINT, 3), (libc::SIGKILL, 3)]
+        );
+        for text in ["", "TERM", "TERM:0", "FOO:3", "TERM:8,", "TERM:x"] {
+            assert!(parse_stop_ladder(text).is_err(), "{text} 應該被拒絕");
+        }
+
+        let parse = |extra: &[&str]| {
+            let mut args = vec!["qq-x11-guard-rs".to_string()];
+            args.extend(extra.iter().map(|arg| arg.to_string()));
+            parse_arg_list(&args, 0)
+        };
+        let config = parse(&["--escalate", "TERM:8,INT:3,KILL:1m"]).unwrap();
+        let steps: Vec<(i32, u64)> = config
+            .stop_ladder
+            .iter()
+            .map(|step| (step.signal, step.wait.as_secs()))
+            .collect();
+        assert_eq!(
+            steps,
+            [(libc::SIGTERM, 8), (libc::SIGINT, 3), (libc::SIGKILL, 60)]
+        );
+        // 不以 KILL 結尾也可以
+        let config = parse(&["--escalate", "HUP:5"]).unwrap();
+        assert_eq!(config.stop_ladder.len(), 1);
+        assert_eq!(config.stop_ladder[0].signal, libc::SIGHUP);
+
+        assert_eq!(
+            parse(&["--escalate", "TERM:8,INT:3,TERM:3"]).unwrap_err(),
+            "--escalate 訊號重複: SIGTERM"
+        );
+        assert_eq!(
+            parse(&["--escalate", "KILL:3,TERM:8"]).unwrap_err(),
+            "--escalate SIGKILL 只能放在最後一級"
+        );
+        assert_eq!(
+            parse(&["--escalate", "TERM:8,9:3,KILL:3"]).unwrap_err(),
+            "--escalate SIGKILL 只能放在最後一級"
+        );
+        assert_eq!(
+            parse(&["--escalate", "TERM:0"]).unwrap_err(),
+            "--escalate 等待時間必須 >= 1: TERM:0"
+        );
+        for value in ["TERM:abc", "TERM:8s3", "TERM:", "TERM:-1"] {
+            let err = parse(&["--escalate", value]).unwrap_err();
+            assert!(err.starts_with("--escalate "), "{value}: {err}");
+        }
+        assert!(parse(&["--escalate"]).is_err());
+    }
+
+    #[test]
+    fn reload_uses_its_own_cooldown() {
+        let config = Config {
+            display: ":4242".to_string(),
+            cooldown_seconds: 120,
+            reload_cooldown_seconds: Some(10),
+            ..Config::default()
+        };
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        guard.last_restart = Some(Instant::now());
+        guard.last_action = ActionKind::Reload;
+        assert!(guard.time_until_eligible().unwrap().0 <= Duration::from_secs(10));
+        assert!(guard
+            .status_text()
+            .contains("上次動作: 重新載入（SIGUSR1）"));
+        guard.last_action = ActionKind::Restart;
+        assert!(guard.time_until_eligible().unwrap().0 > Duration::from_secs(100));
+    }
+
+    #[test]
+    fn only_connections_older_than_the_minimum_count() {
+        let config = Config {
+            display: ":4242".to_string(),
+            min_connection_age_seconds: Some(30),
+            ..Config::default()
+        };
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        let connections = |inodes: &[&str]| -> Vec<(String, i32)> {
+            inodes.iter().map(|inode| (inode.to_string(), 42)).collect()
+        };
+        assert_eq!(guard.threshold_count(&connections(&["1", "2", "3"])), 0);
+        let long_ago = Instant::now() - Duration::from_secs(60);
+        for inode in ["1", "2"] {
+            guard
+                .connection_first_seen
+                .insert(inode.to_string(), long_ago);
+        }
+        // 消失的連線不再計時，重新出現時從頭算起
+        assert_eq!(guard.threshold_count(&connections(&["1", "3", "4"])), 1);
+        assert!(!guard.connection_first_seen.contains_key("2"));
+        assert_eq!(guard.threshold_count(&connections(&["1", "2"])), 1);
+        guard.config.min_connection_age_seconds = None;
+        assert_eq!(guard.threshold_count(&connections(&["1", "2"])), 2);
+    }
+
+    #[test]
+    fn control_commands_pause_and_resume() {
+        let config = Config {
+            display: ":4242".to_string(),
+            ..Config::default()
+        };
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        assert!(guard
+            .control_command("status", None)
+            .starts_with("狀態: 監控中"));
+        assert!(guard.control_command("pause 10m", None).contains("600 秒"));
+        assert!(guard
+            .control_command("status", None)
+            .contains("剩餘 600 秒"));
+        assert_eq!(guard.control_command("resume", None), "已恢復監控動作");
+        assert_eq!(guard.control_command("resume", None), "目前未暫停");
+        assert!(guard.control_command("pause", None).contains("直到 resume"));
+        assert!(guard.control_command("bogus", None).starts_with("錯誤"));
+        // 沒有目標程序時 measure 照樣回覆，JSON 的連線數為 0
+        let measured = guard.control_command("measure json", None);
+        assert!(measured.starts_with("{\"app\":"), "{measured}");
+        assert!(measured.contains("\"connections\":0,"), "{measured}");
+        assert!(measured.contains("\"over_threshold\":false"), "{measured}");
+
+        guard.control_command("pause 1", None);
+        guard.pause.as_mut().unwrap().until = Some(Instant::now());
+        guard.expire_pause();
+        assert!(guard.pause.is_none());
+    }
+
+    #[test]
+    fn manual_restart_respects_cooldown_and_ignores_survivors() {
+        let fake = FakeProc::new("manual");
+        fake.add(100, "qq", &[]);
+        let config = Config {
+            display: ":4242".to_string(),
+            proc_root: fake.proc_root(),
+            dry_run: true,
+            cooldown_seconds: 120,
+            ..Config::default()
+        };
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        assert_eq!(guard.restart_now(None), Ok(RestartOutcome::DryRun));
+        // 冷卻中拒絕並回報剩餘時間，控制 socket 的 restart 也一樣
+        guard.last_restart = Some(Instant::now() - Duration::from_secs(20));
+        let error = guard.restart_now(Some("測試".to_string())).unwrap_err();
+        assert!(error.contains("100 秒後"), "{error}");
+        assert!(guard
+            .control_command("restart", None)
+            .starts_with("未重啟: 冷卻中"));
+        guard.last_restart = Some(Instant::now() - Duration::from_secs(121));
+        assert_eq!(guard.restart_now(None), Ok(RestartOutcome::DryRun));
+
+        // 關閉後仍存活的舊程序（PID 與啟動時間都相同）不算重新出現
+        let proc_root = fake.proc_root();
+        let endpoint = X11Endpoint::Unix("/tmp/.X11-unix/X4242".to_string());
+        let names = ["qq".to_string()];
+        let previous: HashSet<(i32, u64)> = [(100, 100)].into();
+        let wait = |previous: &HashSet<(i32, u64)>| {
+            wait_until_started(&proc_root, &names, &[], &endpoint, previous, Duration::ZERO)
+        };
+        assert_eq!(wait(&HashSet::new()), vec![100]);
+        assert!(wait(&previous).is_empty());
+        // 新的 PID，或同一個 PID 被重用（啟動時間不同）才算
+        fake.add(200, "qq", &[]);
+        assert_eq!(wait(&previous), vec![200]);
+        fs::write(
+            fake.root.join("100/stat"),
+            "100 (qq) S 1 100 100 0 -1 0 0 0 0 0 0 0 0 0 20 0 1 0 999 0 0\n",
+        )
+        .unwrap();
+        assert_eq!(wait(&previous), vec![100, 200]);
+    }
+
+    #[test]
+    fn restart_under_new_name_is_detected_and_learned() {
+        let fake = FakeProc::new("learn");
+        fake.add(400, "sh", &[]);
+        fake.add_child(401, 400, "qq-nt", &[]);
+        fake.add_child(402, 401, "qq-nt-helper", &[]);
+        fake.add(500, "qq-nt", &[]);
+        assert_eq!(
+            process_tree_names(&fake.proc_root(), 400),
+            vec!["qq-nt".to_string(), "qq-nt-helper".to_string()]
+        );
+
+        let config = |learn_new_name| Config {
+            display: ":4242".to_string(),
+            proc_root: fake.proc_root(),
+            learn_new_name,
+            ..Config::default()
+        };
+        let mut guard = Guard::new(config(false)).expect("建立 Guard");
+        assert!(guard.learn_launched_names(400).is_empty());
+        assert_eq!(guard.app_names, vec!["qq".to_string()]);
+
+        let mut guard = Guard::new(config(true)).expect("建立 Guard");
+        assert_eq!(guard.learn_launched_names(400), vec![401, 402, 500]);
+        assert_eq!(guard.app_names.len(), 3);
+        assert!(guard.learn_launched_names(999).is_empty());
+
+        assert_eq!(
+            restart_command_comm("/opt/QQ/qq --no-sandbox"),
+            Some("qq".to_string())
+        );
+        assert_eq!(
+            restart_command_comm("linuxqq-wrapper-launcher"),
+            Some("linuxqq-wrapper".to_string())
+        );
+    }
+
+    #[test]
+    fn non_utf8_comm_matches_byte_exactly() {
+        let fake = FakeProc::new("rawcomm");
+        fake.add(100, "qq", &[]);
+        fake.add(200, "qq", &[]);
+        fake.add(300, "qq", &[]);
+        // 「聊天」截在第 15 個位元組、多位元組字元的中間
+        let truncated = b"\xe8\x81\x8a\xe5\xa4\xa9qq-help\xe5\xa4";
+        fs::write(fake.root.join("100/comm"), [&truncated[..], b"\n"].concat()).unwrap();
+        fs::write(fake.root.join("200/comm"), b"q\\xffq\n").unwrap();
+        fs::remove_file(fake.root.join("300/comm")).unwrap();
+        fs::write(
+            fake.root.join("300/stat"),
+            b"300 (\xff) S 1 300 300 0 -1 0 0 0 0 0 0 0 0 0 20 0 1 0 300 0 0\n",
+        )
+        .unwrap();
+        let proc_root = fake.proc_root();
+        assert_eq!(
+            process_name(&proc_root, 100).as_deref(),
+            Some("聊天qq-help\\xe5\\xa4")
+        );
+        assert_eq!(process_name(&proc_root, 200).as_deref(), Some("q\\\\xffq"));
+        assert_eq!(process_name(&proc_root, 300).as_deref(), Some("\\xff"));
+
+        let find = |name: &str| find_pids_by_name(&proc_root, &[canonical_process_name(name)], &[]);
+        assert_eq!(find("\\xe8\\x81\\x8a天qq-help\\xE5\\xa4"), vec![100]);
+        assert!(find("聊天qq-help天").is_empty());
+        // 字面的反斜線與無效位元組不會混淆
+        assert_eq!(find("q\\\\xffq"), vec![200]);
+        assert!(find("q\\xffq").is_empty());
+        assert_eq!(find("\\xff"), vec![300]);
+        // 不成對的跳脫照原樣比對
+        assert_eq!(canonical_process_name("a\\b\\x4"), "a\\\\b\\\\x4");
+        assert_eq!(
+            restart_command_comm("/opt/聊天/聊天qq-help天launcher"),
+            Some("聊天qq-help\\xe5\\xa4".to_string())
+        );
+    }
+
+    #[test]
+    fn event_cap_leaves_remaining_events_queued() {
+        let fake = FakeProc::new("eventcap");
+        fake.add(300, "qq", &[]);
+        let config = Config {
+            display: ":4242".to_string(),
+            proc_root: fake.proc_root(),
+            max_events_per_wakeup: 1,
+            ..Config::default()
+        };
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        guard.inotify.add_pid(300).unwrap();
+        let fd_dir = fake.root.join("300/fd");
+        for index in 0..2000 {
+            fs::write(fd_dir.join(format!("storm-{index}")), "").unwrap();
+        }
+
+        let first = guard.wait_for_wakeup(Duration::from_secs(1)).unwrap();
+        assert!(first.capped);
+        assert!(!first.events.is_empty() && first.events.len() < 2000);
+
+        guard.config.max_events_per_wakeup = usize::MAX;
+        let mut total = first.events.len();
+        loop {
+            let wakeup = guard.wait_for_wakeup(Duration::from_millis(10)).unwrap();
+            assert!(!wakeup.capped);
+            if wakeup.events.is_empty() {
+                break;
             }
+            total += wakeup.events.len();
         }
+        // 每個檔案一個 CREATE 事件，超過上限的部分都留到後續喚醒，沒有遺失
+        assert_eq!(total, 2000);
     }
-    inodes
-}
 
-fn extract_peer_inode<'a>(tokens: &'a [&'a str], socket_path: &str) -> Option<&'a str> {
-    let with_at = format!("@{socket_path}");
-    for (index, token) in tokens.iter().enumerate() {
-        if *token != socket_path && *token != with_at {
-            continue;
+    #[test]
+    fn inotify_events_wake_the_loop_promptly_with_either_event_loop() {
+        for kind in [mux::Kind::Epoll, mux::Kind::Poll] {
+            let fake = FakeProc::new(&format!("wake-{}", kind.name()));
+            fake.add(310, "qq", &[]);
+            let config = Config {
+                display: ":4242".to_string(),
+                proc_root: fake.proc_root(),
+                event_loop: Some(kind),
+                ..Config::default()
+            };
+            let mut guard = Guard::new(config).expect("建立 Guard");
+            assert_eq!(guard.mux.kind(), kind);
+            guard.inotify.add_pid(310).unwrap();
+
+            // 沒有事件時等滿逾時才返回
+            let started = Instant::now();
+            let idle = guard.wait_for_wakeup(Duration::from_millis(100)).unwrap();
+            assert!(idle.events.is_empty() && idle.exited.is_empty() && !idle.control);
+            assert!(started.elapsed() >= Duration::from_millis(100));
+
+            let fd_dir = fake.root.join("310/fd");
+            let writer = thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                std::os::unix::fs::symlink("socket:[9]", fd_dir.join("9")).unwrap();
+            });
+            let started = Instant::now();
+            let wakeup = guard.wait_for_wakeup(Duration::from_secs(10)).unwrap();
+            writer.join().unwrap();
+            assert_eq!(wakeup.events, vec![310], "{}", kind.name());
+            assert!(
+                started.elapsed() < Duration::from_secs(2),
+                "{} 花了 {:?} 才醒來",
+                kind.name(),
+                started.elapsed()
+            );
         }
-        if index + 3 >= tokens.len() {
-            return None;
+    }
+
+    #[test]
+    fn unreadable_fd_dirs_make_count_unknown() {
+        let fake = FakeProc::new("unreadable");
+        fake.add(700, "qq", &[]);
+        fake.add(701, "qq", &[]);
+        let config = Config {
+            display: ":4242".to_string(),
+            proc_root: fake.proc_root(),
+            ..Config::default()
+        };
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        guard.observe_generation(&[700, 701]);
+
+        assert!(!guard.note_unreadable(&[700, 701], &[701]));
+        assert_eq!(guard.unreadable_warned.len(), 1);
+        guard.last_connections = Some(HashMap::new());
+        assert!(guard
+            .status_text()
+            .contains("無法讀取（未計入）: PID [701]"));
+
+        // 同一代不重複警告；全部讀不到時回報未知而非 0 條
+        assert!(guard.note_unreadable(&[700, 701], &[700, 701]));
+        assert_eq!(guard.unreadable_warned.len(), 2);
+        guard.last_connections = None;
+        assert!(guard.status_text().contains("X11 連線: 未知"));
+        // 連不上 X server 時也無法改以 X-Resource 歸屬，維持未知
+        assert_eq!(guard.xres_connections(&[700, 701]), None);
+        assert!(matches!(guard.xres_fallback, Some(Err(_))));
+
+        // 換代後舊的警告記錄清除
+        fake.remove(700);
+        fake.remove(701);
+        fake.add(702, "qq", &[]);
+        guard.observe_generation(&[702]);
+        assert!(!guard.note_unreadable(&[702], &[]));
+        assert!(guard.unreadable_warned.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "backend-lsof")]
+    fn parse_lsof_peers_reads_server_side_endpoints() {
+        let body = "p4790\n\
+                    f3\n\
+                    n/tmp/.X11-unix/X0 type=STREAM\n\
+                    f26\n\
+                    n/tmp/.X11-unix/X0 type=STREAM ->INO=58372 12530,qq,3u\n\
+                    f27\n\
+                    n@/tmp/.X11-unix/X0 type=STREAM ->INO=58374 12530,qq,4u\n\
+                    f28\n\
+                    n/tmp/.X11-unix/X01 type=STREAM ->INO=11 1,other,3u\n\
+                    p12530\n\
+                    f3\n\
+                    ntype=STREAM ->INO=58260 4790,Xorg,26u\n\
+                    f9\n\
+                    n/tmp/.X11-unix/X0 type=STREAM ->INO=\n";
+        let peers = parse_lsof_peers(body, &x11_socket_names("/tmp/.X11-unix/X0", None));
+        let expected: HashSet<String> = ["58372", "58374"].map(String::from).into();
+        assert_eq!(peers, expected);
+    }
+
+    #[test]
+    fn churn_rate_is_normalized_by_elapsed_time() {
+        let base = Instant::now();
+        let at = |secs: u64| base + Duration::from_secs(secs);
+        let mut meter = ChurnMeter::default();
+        meter.record(at(0), 50, 50);
+        assert_eq!(meter.rate(), None);
+
+        // 15 秒一次的檢查，中間漏掉一次也只是區間變長
+        meter.record(at(15), 30, 30);
+        meter.record(at(45), 60, 60);
+        let rate = meter.rate().unwrap();
+        assert_eq!(rate.opened_per_minute, 120);
+        assert!(!rate.sustained());
+
+        meter.record(at(60), 30, 0);
+        let rate = meter.rate().unwrap();
+        assert_eq!((rate.opened_per_minute, rate.closed_per_minute), (120, 90));
+        assert!(rate.sustained());
+
+        // 超出視窗的區間被移除
+        meter.record(at(120), 0, 0);
+        let rate = meter.rate().unwrap();
+        assert_eq!(rate.span, Duration::from_secs(60));
+        assert_eq!(rate.opened_per_minute, 0);
+
+        meter.reset(at(121));
+        assert_eq!(meter.rate(), None);
+        assert_eq!(
+            parse_escalation_level("busy:churn:notify=desktop")
+                .unwrap()
+                .condition,
+            EscalationCondition::Churn
+        );
+    }
+
+    #[test]
+    fn metrics_report_the_action_gate_and_churn() {
+        let config = Config {
+            display: ":4242".to_string(),
+            cooldown_seconds: 120,
+            ..Config::default()
+        };
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        let metrics = guard.metrics_text();
+        assert!(metrics
+            .contains("qq_x11_guard_next_action_eligible_seconds{app=\"qq\",gate=\"none\"} 0\n"));
+        assert!(!metrics.contains("per_minute"));
+
+        guard.last_restart = Some(Instant::now());
+        let base = Instant::now() - Duration::from_secs(30);
+        guard.churn.record(base, 0, 0);
+        guard.churn.record(base + Duration::from_secs(30), 20, 5);
+        let metrics = guard.metrics_text();
+        assert!(metrics.contains(
+            "qq_x11_guard_next_action_eligible_seconds{app=\"qq\",gate=\"cooldown\"} 120\n"
+        ));
+        assert!(metrics.contains("qq_x11_guard_connections_opened_per_minute{app=\"qq\"} 40\n"));
+        assert!(metrics.contains("qq_x11_guard_connections_closed_per_minute{app=\"qq\"} 10\n"));
+    }
+
+    #[test]
+    fn trend_forecasts_time_to_threshold_and_ignores_spikes() {
+        let base = Instant::now();
+        let at = |secs: u64| base + Duration::from_secs(secs);
+        let mut meter = TrendMeter::default();
+        // 每 30 秒一次，每分鐘多 1 條；其中一次尖峰不影響斜率
+        for step in 0..10u64 {
+            let count = 30 + step as usize / 2;
+            meter.record(at(step * 30), if step == 4 { 90 } else { count });
+            // 10 秒內的重複量測不另外記錄
+            meter.record(at(step * 30 + 5), 0);
         }
-        if tokens[index + 2] != "*" {
-            return None;
+        assert_eq!(meter.trend(34, 100), None, "不滿 5 分鐘不預估");
+        for step in 10..20u64 {
+            meter.record(at(step * 30), 30 + step as usize / 2);
         }
-        let peer = tokens[index + 3];
-        if peer.chars().all(|char| char.is_ascii_digit()) {
-            return Some(peer);
+        let trend = meter.trend(40, 100).unwrap();
+        assert!((trend.per_hour - 60.0).abs() < 1.0, "{trend:?}");
+        // 要到 101 條：還差 61 條，約 61 分鐘
+        let eta = trend.eta.unwrap().as_secs();
+        assert!((3600..3720).contains(&eta), "{eta}");
+        assert_eq!(meter.trend(120, 100).unwrap().eta, Some(Duration::ZERO));
+
+        // 持平或下降時不預估
+        let mut flat = TrendMeter::default();
+        for step in 0..20u64 {
+            flat.record(at(step * 30), 50 - step as usize % 2);
         }
+        assert_eq!(flat.trend(50, 100).unwrap().eta, None);
+        meter.reset();
+        assert_eq!(meter.trend(40, 100), None);
+        assert_eq!(
+            parse_escalation_level("soon:forecast").unwrap().condition,
+            EscalationCondition::Forecast
+        );
     }
-    None
-}
 
-fn count_app_x11_connections(app_pids: &[i32], socket_path: &str) -> usize {
-    if app_pids.is_empty() {
-        return 0;
+    #[test]
+    fn measure_timing_builds_a_cumulative_histogram_and_rate_limits_warnings() {
+        let mut timing = MeasureTiming::default();
+        for millis in [0, 3, 3, 40, 4000] {
+            timing.record(Duration::from_millis(millis));
+        }
+        let text = timing.histogram("m", "說明", "app=\"qq\"");
+        assert!(text.starts_with("# HELP m 說明\n# TYPE m histogram\n"));
+        for line in [
+            "m_bucket{app=\"qq\",le=\"0.001\"} 1",
+            "m_bucket{app=\"qq\",le=\"0.0025\"} 1",
+            "m_bucket{app=\"qq\",le=\"0.005\"} 3",
+            "m_bucket{app=\"qq\",le=\"0.05\"} 4",
+            "m_bucket{app=\"qq\",le=\"2.5\"} 4",
+            "m_bucket{app=\"qq\",le=\"+Inf\"} 5",
+            "m_sum{app=\"qq\"} 4.046",
+            "m_count{app=\"qq\"} 5",
+        ] {
+            assert!(
+                text.lines().any(|candidate| candidate == line),
+                "缺少 {line}:\n{text}"
+            );
+        }
+        assert_eq!(timing.last, Some(Duration::from_millis(4000)));
+
+        // 一分鐘內只警告一次，下次警告時帶出中間略過的次數
+        let start = Instant::now();
+        assert_eq!(timing.note_slow(start), Some(0));
+        assert_eq!(timing.note_slow(start + Duration::from_secs(10)), None);
+        assert_eq!(timing.note_slow(start + Duration::from_secs(59)), None);
+        assert_eq!(timing.note_slow(start + Duration::from_secs(60)), Some(2));
     }
-    let x11_peer_inodes = peer_inodes_on_x11_socket(socket_path);
-    if x11_peer_inodes.is_empty() {
-        return 0;
+
+    #[test]
+    fn interval_summary_reports_counts_by_trigger() {
+        let start = Instant::now();
+        let mut summary = IntervalSummary::new(start);
+        let empty = summary.line(start, "paused", None, &[]);
+        assert!(empty
+            .contains("checks=0 startup=0 event=0 exit=0 fallback=0 plateau=0 min=- max=- mean=-"));
+        assert!(empty.ends_with("state=paused connections=- levels=-"));
+
+        summary.record("startup", 4);
+        summary.record("event", 7);
+        summary.record("event", 2);
+        let line = summary.line(
+            start + Duration::from_secs(60),
+            "monitoring",
+            Some(2),
+            &["early".to_string()],
+        );
+        assert!(
+            line.starts_with("週期摘要 period=60s checks=3 startup=1 event=2 exit=0 fallback=0")
+        );
+        assert!(line.contains(" min=2 max=7 mean=4.3 "));
+        assert!(line.ends_with("state=monitoring connections=2 levels=early"));
     }
-    let mut app_socket_inodes = HashSet::new();
-    for pid in app_pids {
-        app_socket_inodes.extend(socket_inodes_for_pid(*pid));
+
+    #[test]
+    fn run_stats_summarize_outcomes_and_cooldown() {
+        let mut stats = RunStats::new();
+        assert!(stats.summary(None).contains("動作：無"));
+
+        let restarted = RestartOutcome::Restarted {
+            old_pids: vec![1],
+            new_pids: vec![2],
+        };
+        stats.record_outcome(&restarted, false, Duration::from_secs(3600));
+        // 前一段冷卻被新的動作截斷，不會重複計算
+        stats.cooldown_window = Some((
+            Instant::now() - Duration::from_secs(100),
+            Instant::now() + Duration::from_secs(3500),
+        ));
+        stats.record_outcome(
+            &RestartOutcome::Failed("x".to_string()),
+            false,
+            Duration::ZERO,
+        );
+        stats.record_outcome(&restarted, false, Duration::ZERO);
+        stats.record_outcome(&restarted, true, Duration::ZERO);
+        assert_eq!(stats.cooldown_until(Instant::now()).as_secs(), 100);
+
+        let pause = Pause {
+            since: Instant::now() - Duration::from_secs(30),
+            until: None,
+        };
+        stats.paused = Duration::from_secs(12);
+        let summary = stats.summary(Some(&pause));
+        assert!(
+            summary.contains("動作：失敗 1、手動重啟 1、重啟 2"),
+            "{summary}"
+        );
+        assert!(summary.contains("冷卻 100 秒"), "{summary}");
+        assert!(summary.contains("暫停 42 秒"), "{summary}");
     }
-    app_socket_inodes.intersection(&x11_peer_inodes).count()
-}
 
-// ===== 區塊 3：事件來源（inotify） =====
-struct InotifyWatch {
-    fd: RawFd,
-    wd_to_pid: HashMap<i32, i32>,
-    pid_to_wd: HashMap<i32, i32>,
-}
+    #[test]
+    fn policy_verdicts_are_parsed_strictly() {
+        assert_eq!(parse_policy_verdict("allow\n"), Ok(PolicyVerdict::Allow));
+        assert_eq!(parse_policy_verdict(" deny "), Ok(PolicyVerdict::Deny));
+        assert_eq!(
+            parse_policy_verdict("defer:2m"),
+            Ok(PolicyVerdict::Defer(120))
+        );
+        assert_eq!(
+            parse_policy_verdict("replace:reload"),
+            Ok(PolicyVerdict::Replace(ActionKind::Reload))
+        );
+        for text in [
+            "",
+            "ALLOW",
+            "allow\ndeny",
+            "defer:0",
+            "replace:reboot",
+            "ok",
+        ] {
+            assert!(parse_policy_verdict(text).is_err(), "{text:?} 應該被拒絕");
+        }
+        assert_eq!(json_string("a\"b\\c\n\t"), "\"a\\\"b\\\\c\\n\\u0009\"");
+    }
 
-impl InotifyWatch {
-    fn new() -> io::Result<Self> {
-        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
-        if fd < 0 {
-            return Err(io::Error::last_os_error());
+    #[test]
+    fn wait_for_command_retries_until_ready_and_strict_timeout_is_an_error() {
+        let fake = FakeProc::new("waitcmd");
+        let ready = fake.root.join("ready");
+        let config = Config {
+            display: ":4242".to_string(),
+            proc_root: fake.proc_root(),
+            wait_for_cmd_timeout_seconds: 5,
+            ..Config::default()
+        };
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        let marker = ready.clone();
+        let creator = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(500));
+            fs::write(marker, "").unwrap();
+        });
+        let started = Instant::now();
+        assert_eq!(
+            guard.wait_for_command(&format!("test -e {}", ready.display())),
+            Ok(())
+        );
+        creator.join().unwrap();
+        assert!(started.elapsed() >= WAIT_FOR_CMD_INTERVAL);
+
+        // 逾時：預設照常開始，strict 時回傳錯誤
+        guard.config.wait_for_cmd_timeout_seconds = 1;
+        assert_eq!(guard.wait_for_command("false"), Ok(()));
+        guard.config.wait_for_cmd_strict = true;
+        let error = guard.wait_for_command("false").unwrap_err();
+        assert!(
+            error.starts_with("就緒命令 1 秒內 1 次嘗試都未成功"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "shell-restart")]
+    fn policy_command_sees_context_and_malformed_output_is_never_allow() {
+        let fake = FakeProc::new("policy");
+        fake.add(100, "qq", &[]);
+        let context_path = fake.root.join("context.json");
+        let config = Config {
+            display: ":4242".to_string(),
+            proc_root: fake.proc_root(),
+            dry_run: true,
+            policy_cmd: Some(format!("cat > {}; echo defer:30", context_path.display())),
+            policy_fallback: PolicyVerdict::Allow,
+            ..Config::default()
+        };
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        assert_eq!(guard.restart_app(12, None), None);
+        let context = fs::read_to_string(&context_path).unwrap();
+        assert!(context.starts_with("{\"incident\":\"inc-"), "{context}");
+        assert!(
+            context.contains("\"proposed_action\":\"restart\",\"connections\":12,\"threshold\":10")
+        );
+        assert!(context.contains("\"pids\":[100]"));
+        let (remain, gate) = guard.time_until_eligible().unwrap();
+        assert_eq!(gate, ActionGate::Policy);
+        assert!(remain > Duration::from_secs(25));
+
+        // 預設裁決為 allow 時，輸出無法解析仍然不會動作
+        guard.policy_hold = None;
+        guard.config.policy_cmd = Some("echo sure".to_string());
+        assert_eq!(guard.restart_app(12, None), None);
+        assert_eq!(guard.time_until_eligible().unwrap().1, ActionGate::Policy);
+    }
+
+    #[test]
+    fn confirm_restart_waits_for_approval_and_holds_after_deny_or_timeout() {
+        let fake = FakeProc::new("confirm");
+        fake.add(100, "qq", &[]);
+        let config = Config {
+            display: ":4242".to_string(),
+            proc_root: fake.proc_root(),
+            dry_run: true,
+            confirm_restart: true,
+            cooldown_seconds: 120,
+            ..Config::default()
+        };
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        let peer = control::Peer {
+            pid: 4242,
+            uid: 1000,
+        };
+        assert_eq!(guard.restart_app(12, None), None);
+        assert!(guard.status_text().contains("等待確認: [inc-"));
+        // 等待期間的檢查不動作，也不重新詢問
+        assert_eq!(guard.restart_app(12, None), None);
+        assert!(guard
+            .control_command("approve", Some(peer))
+            .starts_with("已核准 [inc-"));
+        assert!(guard
+            .status_text()
+            .contains("已由控制 socket（uid 1000，PID 4242）核准"));
+        assert!(guard.restart_app(12, None).is_some());
+        assert!(guard.confirmation.is_none());
+        assert_eq!(
+            guard.control_command("deny", None),
+            "目前沒有等待確認的動作"
+        );
+
+        // 拒絕後以冷卻時間暫停詢問
+        guard.last_restart = None;
+        assert_eq!(guard.restart_app(12, None), None);
+        assert!(guard
+            .control_command("deny", Some(peer))
+            .ends_with("120 秒內不再詢問"));
+        assert_eq!(
+            guard.time_until_eligible().unwrap().1,
+            ActionGate::Confirmation
+        );
+
+        // 逾時視為拒絕
+        guard.confirm_hold = None;
+        assert_eq!(guard.restart_app(12, None), None);
+        guard.confirmation.as_mut().unwrap().until = Instant::now();
+        guard.expire_confirmation();
+        assert!(guard.confirmation.is_none());
+        assert_eq!(
+            guard.time_until_eligible().unwrap().1,
+            ActionGate::Confirmation
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "shell-restart")]
+    fn failure_hook_runs_once_per_incident_until_cleared() {
+        let fake = FakeProc::new("on-failure");
+        fake.add(100, "qq", &[]);
+        let hook_log = fake.root.join("hook.log");
+        let config = Config {
+            proc_root: fake.proc_root(),
+            on_failure_cmd: Some(format!(
+                "echo \"$GUARD_LEVEL $GUARD_INCIDENT $GUARD_FAILURE_ACTION\" >> {}; exit 1",
+                hook_log.display()
+            )),
+            on_failure_action: FailureAction::Exit,
+            ..Config::default()
+        };
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        guard.restart_failed("未見 qq 重新出現".to_string());
+        let lines = fs::read_to_string(&hook_log).unwrap();
+        assert!(lines.starts_with("restart-failed inc-"), "{lines}");
+        assert!(lines.trim_end().ends_with(" exit"), "{lines}");
+        assert_eq!(guard.exit_on_failure.as_deref(), Some("未見 qq 重新出現"));
+        let status = guard.status_text();
+        assert!(
+            status.contains("on-failure-cmd 以非 0 結束碼結束、exit"),
+            "{status}"
+        );
+
+        // 同一個失敗狀態中再次失敗不再執行；清除後才會再執行
+        guard.restart_failed("仍未出現".to_string());
+        assert_eq!(fs::read_to_string(&hook_log).unwrap().lines().count(), 1);
+        assert!(guard
+            .control_command("clear", None)
+            .starts_with("已清除失敗狀態"));
+        assert!(!guard.status_text().contains("重啟失敗"));
+        assert_eq!(guard.control_command("clear", None), "目前沒有失敗狀態");
+        guard.restart_failed("仍未出現".to_string());
+        assert_eq!(fs::read_to_string(&hook_log).unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    fn plateau_wait_acts_once_the_count_stops_climbing() {
+        let start = Instant::now();
+        let mut wait = PlateauWait::new(12, start);
+        assert_eq!(wait.record(14, start), None);
+        assert_eq!(
+            wait.record(14, start + Duration::from_secs(5)),
+            Some(PlateauChange::Steady { peak: 14 })
+        );
+        assert_eq!(wait.record(13, start + Duration::from_secs(10)), None);
+        assert_eq!(
+            wait.record(15, start + Duration::from_secs(20)),
+            Some(PlateauChange::Climbing { from: 13, to: 15 })
+        );
+        assert_eq!(wait.steady_since, start + Duration::from_secs(20));
+        assert_eq!(
+            wait.next_check(Duration::from_secs(30), Duration::from_secs(40)),
+            start + Duration::from_secs(40)
+        );
+
+        let fake = FakeProc::new("plateau");
+        fake.add(100, "qq", &[]);
+        let config = Config {
+            proc_root: fake.proc_root(),
+            dry_run: true,
+            wait_for_plateau_seconds: Some(30),
+            ..Config::default()
+        };
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        assert_eq!(guard.restart_app(12, None), None);
+        assert_eq!(guard.restart_app(12, None), None);
+        assert!(guard.plateau_deadline().is_some());
+        guard.plateau.as_mut().unwrap().steady_since = Instant::now() - Duration::from_secs(31);
+        assert_eq!(guard.restart_app(12, None), Some(RestartOutcome::DryRun));
+        assert_eq!(guard.plateau, None);
+
+        // 一直上升時，等待達上限照常動作；規則觸發的重啟不等待
+        guard.last_restart = None;
+        assert_eq!(guard.restart_app(12, None), None);
+        guard.plateau.as_mut().unwrap().since = Instant::now() - Duration::from_secs(301);
+        assert_eq!(guard.restart_app(20, None), Some(RestartOutcome::DryRun));
+        guard.last_restart = None;
+        assert_eq!(
+            guard.restart_app(20, Some("anything")),
+            Some(RestartOutcome::DryRun)
+        );
+    }
+
+    #[test]
+    fn high_load_defers_action_until_the_wait_limit() {
+        assert_eq!(parse_loadavg("3.52 2.10 1.00 4/812 12345\n"), Some(3.52));
+        assert_eq!(
+            parse_cpu_pressure(
+                "some avg10=41.50 avg60=12.00 avg300=3.00 total=123\nfull avg10=0.00 avg60=0.00 avg300=0.00 total=0\n"
+            ),
+            Some(41.5)
+        );
+        assert_eq!(parse_cpu_pressure("full avg10=1.00\n"), None);
+
+        let fake = FakeProc::new("load");
+        fake.add(100, "qq", &[]);
+        let root = &fake.root;
+        fs::write(root.join("loadavg"), "6.00 5.00 4.00 3/500 999\n").unwrap();
+        fs::write(
+            root.join("stat"),
+            "cpu  1 2 3\ncpu0 1 2 3\ncpu1 1 2 3\ncpu2 1 2 3\ncpu3 1 2 3\nintr 0\n",
+        )
+        .unwrap();
+        let config = Config {
+            proc_root: fake.proc_root(),
+            dry_run: true,
+            max_load: Some(2.0),
+            max_load_wait_seconds: Some(60),
+            ..Config::default()
+        };
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        assert_eq!(guard.restart_app(12, None), None);
+        assert!(guard.load_deferred_since.is_some());
+        assert!(guard.incident.is_some());
+
+        // 換算為每核心：6 / 4 = 1.5，未超過 2
+        guard.config.max_load_per_cpu = true;
+        assert_eq!(guard.restart_app(12, None), Some(RestartOutcome::DryRun));
+        assert_eq!(guard.load_deferred_since, None);
+
+        // 持續過載時，延後累計達上限就照常動作
+        guard.last_restart = None;
+        guard.config.max_load = Some(1.0);
+        guard.load_deferred_since = Some(Instant::now() - Duration::from_secs(61));
+        assert_eq!(guard.restart_app(12, None), Some(RestartOutcome::DryRun));
+        assert_eq!(guard.load_deferred_since, None);
+
+        // 讀不到 PSI 時不延後
+        guard.last_restart = None;
+        guard.config.max_load = None;
+        guard.config.max_cpu_pressure = Some(10.0);
+        assert_eq!(guard.restart_app(12, None), Some(RestartOutcome::DryRun));
+    }
+
+    #[test]
+    fn busy_app_defers_action_until_the_active_wait_limit() {
+        let fake = FakeProc::new("active");
+        fake.add(100, "qq", &[]);
+        let config = Config {
+            proc_root: fake.proc_root(),
+            dry_run: true,
+            defer_while_active: true,
+            active_max_wait_seconds: Some(60),
+            ..Config::default()
+        };
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        assert!(guard
+            .status_text()
+            .contains("使用中延後: 未延後；準備動作時才取樣"));
+
+        // 取樣期間 utime 增加 200 tick，遠超過預設的 20%
+        let stat = fake.root.join("100/stat");
+        let writer = {
+            let stat = stat.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                fs::write(
+                    stat,
+                    "100 (qq) S 1 100 100 0 -1 0 0 0 0 0 200 0 0 0 20 0 1 0 100 0 0\n",
+                )
+                .unwrap();
+            })
+        };
+        assert!(guard.defer_while_active(&[100]));
+        writer.join().unwrap();
+        assert!(guard.active_deferred_since.is_some());
+        assert_eq!(guard.summary_state(), "active");
+        let status = guard.status_text();
+        assert!(
+            status.contains("使用中延後: 已延後 0 秒（最多 60 秒）；CPU "),
+            "{status}"
+        );
+
+        // CPU 時間不再增加：不在使用中，照常動作
+        assert!(!guard.defer_while_active(&[100]));
+        assert_eq!(guard.active_deferred_since, None);
+        assert!(guard.status_text().contains("；CPU 0.0%，0 秒前取樣"));
+
+        // 持續使用中時，延後累計達上限就照常動作
+        guard.config.active_cpu_percent = Some(0.0);
+        guard.config.active_idle_seconds = Some(30);
+        guard.active_deferred_since = Some(Instant::now() - Duration::from_secs(61));
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            fs::write(
+                stat,
+                "100 (qq) S 1 100 100 0 -1 0 0 0 0 0 300 0 0 0 20 0 1 0 100 0 0\n",
+            )
+            .unwrap();
+        });
+        assert!(!guard.defer_while_active(&[100]));
+        writer.join().unwrap();
+        assert_eq!(guard.active_deferred_since, None);
+        // 沒有 X server 可查時輸入閒置為未知，只依 CPU 判斷
+        assert!(guard.status_text().contains("輸入閒置未知"));
+    }
+
+    #[test]
+    fn composite_rules_alert_escalate_and_restart_with_their_own_cooldown() {
+        let fake = FakeProc::new("rules");
+        fake.add(100, "qq", &["1", "2"]);
+        let config = Config {
+            proc_root: fake.proc_root(),
+            dry_run: true,
+            rules: vec![
+                rule::parse_rule("fds:fd > 2 and x11 < 5:restart").unwrap(),
+                rule::parse_rule("busy:fd > 1:alert,cooldown=1h").unwrap(),
+                rule::parse_rule("hot:fd >= 3:escalate").unwrap(),
+            ],
+            ..Config::default()
+        };
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        assert_eq!(guard.rules[0].name, rule::THRESHOLD_RULE);
+        // fd 目錄內有 0 與兩個 socket
+        let metrics = guard.sample_metrics(&[100], 0);
+        assert_eq!(metrics.fd, Some(3));
+        assert_eq!(metrics.rss, None);
+        assert_eq!(guard.evaluate_rules(&metrics).as_deref(), Some("fds"));
+        assert_eq!(guard.active_levels, vec!["hot".to_string()]);
+        assert_eq!(guard.stats.rule_fired.get("busy"), Some(&1));
+
+        // alert 在冷卻內不重複；restart 規則到實際動作後才開始冷卻
+        assert_eq!(guard.evaluate_rules(&metrics).as_deref(), Some("fds"));
+        assert_eq!(guard.stats.rule_fired.get("busy"), Some(&1));
+        assert_eq!(
+            guard.restart_app(0, Some("fds")),
+            Some(RestartOutcome::DryRun)
+        );
+        assert_eq!(guard.action_rule.as_deref(), Some("fds"));
+        assert_eq!(guard.stats.rule_fired.get("fds"), Some(&1));
+        assert!(guard.status_text().contains("由規則 fds 觸發"));
+        assert!(guard
+            .stats
+            .summary(None)
+            .contains("規則觸發：busy 1、fds 1"));
+        assert_eq!(guard.evaluate_rules(&metrics), None);
+
+        let calm = rule::Metrics {
+            fd: Some(1),
+            ..metrics
+        };
+        assert_eq!(guard.evaluate_rules(&calm), None);
+        assert!(guard.active_levels.is_empty());
+    }
+
+    #[test]
+    fn deadline_overrides_cooldown_once_and_then_resets() {
+        let fake = FakeProc::new("deadline");
+        fake.add(100, "qq", &[]);
+        let config = Config {
+            proc_root: fake.proc_root(),
+            dry_run: true,
+            action: ActionKind::Reload,
+            deadline_restart_seconds: Some(60),
+            ..Config::default()
+        };
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        guard.last_restart = Some(Instant::now());
+
+        // 超標未滿期限：照常被冷卻擋下
+        guard.over_threshold_since = Some(Instant::now() - Duration::from_secs(30));
+        assert_eq!(guard.restart_app(12, None), None);
+        assert!(guard.over_threshold_since.is_some());
+
+        // 策略命令要求延後時，期限也不覆蓋
+        guard.over_threshold_since = Some(Instant::now() - Duration::from_secs(90));
+        guard.policy_hold = Some(Instant::now() + Duration::from_secs(30));
+        assert_eq!(guard.restart_app(12, None), None);
+
+        // 達到期限：無視冷卻，以完整重啟（而非 --action reload）動作一次，並重新計時
+        guard.policy_hold = None;
+        assert_eq!(guard.restart_app(12, None), Some(RestartOutcome::DryRun));
+        assert_eq!(guard.last_action, ActionKind::Restart);
+        assert_eq!(guard.over_threshold_since, None);
+        assert_eq!(guard.restart_app(12, None), None);
+    }
+
+    #[test]
+    fn rapid_exits_after_restarts_stop_automatic_restarts_until_clear() {
+        let fake = FakeProc::new("crash");
+        let config = Config {
+            display: ":4242".to_string(),
+            proc_root: fake.proc_root(),
+            dry_run: true,
+            threshold: 10,
+            crash_loop_exits: Some(2),
+            ..Config::default()
+        };
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        for pid in [100, 200] {
+            fake.add(pid, "qq", &[]);
+            guard
+                .crash
+                .launched(guard.process_identities(&[pid]), Instant::now());
+            guard.observe_crashes(&[pid]);
+            fake.remove(pid);
+            guard.observe_crashes(&[]);
         }
-        Ok(Self {
-            fd,
-            wd_to_pid: HashMap::new(),
-            pid_to_wd: HashMap::new(),
-        })
+        assert!(guard.crash.looping_since.is_some());
+        assert_eq!(guard.stats.crash_loops, 1);
+        assert_eq!(guard.summary_state(), "crash-loop");
+        assert_eq!(guard.restart_app(12, None), None);
+        let status = guard.status_text();
+        assert!(
+            status.lines().nth(1).is_some_and(|line| line.starts_with(
+                "崩潰循環: 已 0 秒，不再自動重啟，需以 ctl clear 解除（600 秒內快速結束 2/2 次：PID 100（執行 0 秒），0 秒前、PID 200"
+            )),
+            "{status}"
+        );
+        assert!(guard
+            .metrics_text()
+            .contains("qq_x11_guard_crash_loop{app=\"qq\"} 1\n"));
+        assert!(guard
+            .control_command("clear", None)
+            .ends_with("；已解除崩潰循環"));
+        assert!(guard
+            .status_text()
+            .contains("崩潰循環: 正常（600 秒內快速結束 0/2 次）"));
+        fake.add(300, "qq", &[]);
+        assert_eq!(guard.restart_app(12, None), Some(RestartOutcome::DryRun));
+    }
+
+    #[test]
+    fn flapping_suppresses_actions_until_calm_or_clear() {
+        let fake = FakeProc::new("flap");
+        fake.add(100, "qq", &[]);
+        let config = Config {
+            display: ":4242".to_string(),
+            proc_root: fake.proc_root(),
+            dry_run: true,
+            threshold: 10,
+            flap_crossings: Some(3),
+            flap_dwell_seconds: 0,
+            ..Config::default()
+        };
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        for (over, count) in [(true, 12), (false, 9), (true, 12)] {
+            guard.observe_flapping(over, count);
+        }
+        assert!(guard.flap.flapping_since.is_none());
+        guard.observe_flapping(false, 9);
+        assert!(guard.flap.flapping_since.is_some());
+        assert_eq!(guard.summary_state(), "flapping");
+        assert_eq!(guard.restart_app(12, None), None);
+        let status = guard.status_text();
+        assert!(
+            status.contains("反覆跨越: flapping 已 0 秒，暫停自動動作；")
+                && status.contains("（600 秒內跨越 3 次，上限 3）"),
+            "{status}"
+        );
+        assert!(guard
+            .metrics_text()
+            .contains("qq_x11_guard_flapping{app=\"qq\"} 1\n"));
+        assert!(guard
+            .control_command("clear", None)
+            .starts_with("目前沒有失敗狀態；已解除 flapping 狀態（inc-"));
+        assert_eq!(guard.restart_app(12, None), Some(RestartOutcome::DryRun));
+
+        // 再次進入後，連線數一回到解除值（預設為門檻）以下且滿 dwell 就自動解除
+        for (over, count) in [(true, 12), (false, 9), (true, 12)] {
+            guard.observe_flapping(over, count);
+        }
+        assert!(guard.flap.flapping_since.is_some());
+        guard.observe_flapping(true, 11);
+        assert!(guard.flap.flapping_since.is_some());
+        guard.observe_flapping(false, 10);
+        assert!(guard.flap.flapping_since.is_none());
+        assert_eq!(guard.stats.flapping, 2);
+        assert!(guard.stats.summary(None).ends_with("，flapping 2 次"));
+    }
+
+    #[test]
+    fn last_over_threshold_is_kept_after_the_count_recovers() {
+        let fake = FakeProc::new("last-over");
+        fake.add(100, "qq", &[]);
+        fake.add(101, "qq-beta", &[]);
+        let config = Config {
+            display: ":4242".to_string(),
+            proc_root: fake.proc_root(),
+            threshold: 10,
+            ..Config::default()
+        };
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        assert!(!guard.status_text().contains("上次超標"));
+        assert!(guard
+            .control_command("measure json", None)
+            .contains("\"last_over_threshold\":null"));
+
+        guard.note_over_threshold(&[100]);
+        let first = guard.last_over_threshold.unwrap();
+        assert_eq!(wall_clock(first).len(), "2026-01-02 03:04:05".len());
+        let status = guard.status_text();
+        assert!(
+            status.contains(&format!("上次超標: {}（0 秒前）", wall_clock(first))),
+            "{status}"
+        );
+        assert!(!status.contains("qq-beta"), "{status}");
+
+        // 只有一部分名稱出現在較新的超標量測中時，各名稱保留自己的時間
+        let earlier = first - Duration::from_secs(3600);
+        guard.last_over_by_name.insert("qq".to_string(), earlier);
+        guard.note_over_threshold(&[101]);
+        let status = guard.status_text();
+        assert!(
+            status.contains(&format!(
+                "、qq-beta {}",
+                wall_clock_ago(guard.last_over_threshold.unwrap())
+            )) && status.contains(&format!("；qq {}（3600 秒前）", wall_clock(earlier))),
+            "{status}"
+        );
+        let metrics = guard.metrics_text();
+        assert!(metrics.contains(&format!(
+            "qq_x11_guard_process_last_over_threshold_timestamp_seconds{{app=\"qq\",process=\"qq\"}} {}\n",
+            unix_seconds(earlier)
+        )));
+        assert!(metrics.contains(&format!(
+            "qq_x11_guard_last_over_threshold_timestamp_seconds{{app=\"qq\"}} {}\n",
+            unix_seconds(guard.last_over_threshold.unwrap())
+        )));
+        assert!(guard
+            .control_command("measure json", None)
+            .contains(&format!(
+                "\"last_over_threshold\":{}",
+                unix_seconds(guard.last_over_threshold.unwrap())
+            )));
+    }
+
+    #[test]
+    fn idle_gate_holds_until_idle_and_counts_self_recovery() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        assert_eq!(
+            parse_idle_hint("IdleHint=yes\nIdleSinceHint=999880000000\n", now),
+            Ok(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_idle_hint("IdleHint=no\nIdleSinceHint=0\n", now),
+            Ok(Duration::ZERO)
+        );
+        assert!(parse_idle_hint("IdleHint=yes\nIdleSinceHint=0\n", now).is_err());
+        assert!(parse_idle_hint("", now).is_err());
+
+        let fake = FakeProc::new("idlehold");
+        fake.add(100, "qq", &[]);
+        let config = Config {
+            display: ":4242".to_string(),
+            proc_root: fake.proc_root(),
+            only_when_idle_seconds: Some(300),
+            idle_max_hold_seconds: Some(600),
+            ..Config::default()
+        };
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        let idle = |seconds| Ok((Duration::from_secs(seconds), "X server"));
+        assert!(guard.hold_for_idle(300, idle(20)));
+        assert_eq!(guard.summary_state(), "idle");
+        // 查不到閒置時間時也保留
+        assert!(guard.hold_for_idle(300, Err("沒有 MIT-SCREEN-SAVER".to_string())));
+        assert!(guard.hold_for_idle(300, idle(299)));
+        assert!(!guard.hold_for_idle(300, idle(300)));
+        assert_eq!(guard.idle_hold_since, None);
+
+        // 保留累計達上限時照常動作
+        assert!(guard.hold_for_idle(300, idle(5)));
+        guard.idle_hold_since = Some(Instant::now() - Duration::from_secs(601));
+        assert!(!guard.hold_for_idle(300, idle(5)));
+        assert_eq!(guard.idle_hold_since, None);
+
+        // 保留期間回到門檻內：不動作，記為自行恢復
+        assert!(guard.hold_for_idle(300, idle(5)));
+        guard.check_threshold("fallback", None);
+        assert_eq!(guard.idle_hold_since, None);
+        assert_eq!(guard.stats.self_recovered, 1);
+        assert!(guard
+            .stats
+            .summary(None)
+            .ends_with("，等待閒置期間自行恢復 1 次"));
+        assert!(guard
+            .status_text()
+            .contains("等待閒置: 未保留；閒置 5 秒（X server），0 秒前查詢，需閒置 300 秒"));
+    }
+
+    #[test]
+    fn state_sync_honours_another_instance_and_records_its_own_action() {
+        let dir = env::temp_dir().join(format!("qq-x11-guard-sync-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state");
+        let config = Config {
+            display: ":4242".to_string(),
+            cooldown_seconds: 120,
+            state_file: Some(path.clone()),
+            state_sync: true,
+            ..Config::default()
+        };
+        let app = config.app_name.clone();
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        let other = |age: u64, display: &str| state::Record {
+            time: timestamp() - age,
+            instance: std::process::id() + 1,
+            action: "restart".to_string(),
+            app: app.clone(),
+            display: display.to_string(),
+            incident: "inc-other".to_string(),
+        };
+
+        // 另一個守護程序 30 秒前重啟過：這一輪不動作，冷卻從它的時間起算
+        state::write(&path, &other(30, ":4242")).unwrap();
+        assert!(!guard.claim_action(ActionKind::Restart, true));
+        let remaining = guard.time_until_eligible().map(|(left, _)| left.as_secs());
+        assert!(matches!(remaining, Some(89 | 90)), "{remaining:?}");
+        assert!(guard.status_text().contains("inc-other"));
+
+        // 冷卻已過或是其他 DISPLAY 的紀錄不影響；動作前寫入自己的紀錄
+        for record in [other(300, ":4242"), other(5, ":0")] {
+            guard.last_restart = None;
+            state::write(&path, &record).unwrap();
+            assert!(guard.claim_action(ActionKind::Reload, true));
+        }
+        let mine = state::read(&path).unwrap().unwrap();
+        assert_eq!(
+            (mine.instance, mine.action.as_str()),
+            (std::process::id(), "reload")
+        );
+
+        // 另一個程序持有鎖：同步模式這一輪不動作，未同步的手動重啟照常
+        let held = state::lock(&path, true, Duration::ZERO).unwrap();
+        assert!(!guard.claim_action(ActionKind::Restart, true));
+        assert!(guard.claim_action(ActionKind::Restart, false));
+        drop(held);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn self_upgrade_snapshot_restores_timers_and_connections() {
+        let config = Config {
+            display: ":4242".to_string(),
+            cooldown_seconds: 120,
+            ..Config::default()
+        };
+        let mut old = Guard::new(config.clone()).expect("建立 Guard");
+        old.last_restart = Some(Instant::now() - Duration::from_secs(30));
+        old.last_action = ActionKind::Reload;
+        old.restart_times = VecDeque::from([Instant::now() - Duration::from_secs(30)]);
+        old.incident = Some("inc-1-7".to_string());
+        old.incident_seq = 7;
+        old.app_names.push("qq-new".to_string());
+        old.pause = Some(Pause {
+            since: Instant::now() - Duration::from_secs(5),
+            until: Some(Instant::now() + Duration::from_secs(55)),
+        });
+        old.last_connections = Some(HashMap::from([("4711".to_string(), 100)]));
+        old.connection_first_seen =
+            HashMap::from([("4711".to_string(), Instant::now() - Duration::from_secs(90))]);
+        let mut pairs = old.snapshot();
+        pairs.push(("control_fd", "9".to_string()));
+        let text = state::render_pairs(&pairs);
+
+        let mut new = Guard::new(config.clone()).expect("建立 Guard");
+        assert_eq!(new.restore(&state::parse_pairs(&text)), Ok(Some(9)));
+        assert_eq!(new.last_action, ActionKind::Reload);
+        let left = new
+            .cooldown()
+            .saturating_sub(new.last_restart.unwrap().elapsed());
+        assert!((89..=90).contains(&left.as_secs()), "{left:?}");
+        assert_eq!(new.restart_times.len(), 1);
+        assert_eq!(new.incident.as_deref(), Some("inc-1-7"));
+        assert_eq!(new.incident_id(), "inc-1-7");
+        assert!(new.app_names.contains(&"qq-new".to_string()));
+        let until = new.pause.as_ref().and_then(|pause| pause.until).unwrap();
+        assert!((54..=55).contains(&until.duration_since(Instant::now()).as_secs()));
+        assert_eq!(new.last_connections.as_ref().map(HashMap::len), Some(1));
+        let seen = new.connection_first_seen["4711"].elapsed().as_secs();
+        assert!((90..=91).contains(&seen), "{seen}");
+
+        // 別的程序寫入或太舊的狀態不採用
+        let stale = text.replace(&format!("pid={}", std::process::id()), "pid=1");
+        let mut fresh = Guard::new(config).expect("建立 Guard");
+        assert!(fresh.restore(&state::parse_pairs(&stale)).is_err());
+        assert_eq!(fresh.last_restart, None);
     }
 
-    fn add_pid(&mut self, pid: i32) {
-        if self.pid_to_wd.contains_key(&pid) {
-            return;
-        }
-        let fd_path = format!("/proc/{pid}/fd");
-        if !Path::new(&fd_path).is_dir() {
-            return;
-        }
-        let c_path = match CString::new(fd_path) {
-            Ok(value) => value,
-            Err(_) => return,
+    #[test]
+    fn per_process_scope_picks_the_busiest_process() {
+        let config = Config {
+            display: ":4242".to_string(),
+            threshold: 2,
+            ..Config::default()
         };
-        let wd = unsafe { libc::inotify_add_watch(self.fd, c_path.as_ptr(), WATCH_MASK) };
-        if wd < 0 {
-            return;
-        }
-        self.wd_to_pid.insert(wd, pid);
-        self.pid_to_wd.insert(pid, wd);
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        let connections: Vec<(String, i32)> = [("1", 200), ("2", 200), ("3", 100), ("4", 300)]
+            .map(|(inode, pid)| (inode.to_string(), pid))
+            .to_vec();
+        // 預設的 total 不看各程序
+        assert_eq!(
+            guard.per_process_offender(&[100, 200, 300], &connections),
+            None
+        );
+
+        guard.config.threshold_scope = ThresholdScope::PerProcess;
+        assert_eq!(
+            guard.per_process_offender(&[100, 200, 300], &connections),
+            Some((200, 2))
+        );
+        // 同數時取 PID 較小者；沒有連線的程序也算在內
+        assert_eq!(
+            guard.per_process_offender(&[100, 300], &connections[2..]),
+            Some((100, 1))
+        );
+        assert_eq!(guard.per_process_offender(&[400], &[]), Some((400, 0)));
+
+        // --min-connection-age：只算存在夠久的連線
+        guard.config.min_connection_age_seconds = Some(60);
+        guard.connection_first_seen = HashMap::from([
+            ("1".to_string(), Instant::now()),
+            ("2".to_string(), Instant::now()),
+            ("3".to_string(), Instant::now() - Duration::from_secs(61)),
+        ]);
+        guard.offender = guard.per_process_offender(&[100, 200, 300], &connections);
+        assert_eq!(guard.offender, Some((100, 1)));
+        assert_eq!(guard.gauge_name(), "單一程序最多的 X11 連線");
+        let status = guard.status_text();
+        assert!(status.contains("門檻=2（per-process）"), "{status}");
+        assert!(
+            status.contains("連線最多的程序: PID 100（1 條）"),
+            "{status}"
+        );
     }
 
-    fn remove_pid(&mut self, pid: i32) {
-        let wd = match self.pid_to_wd.remove(&pid) {
-            Some(value) => value,
-            None => return,
+    #[test]
+    fn command_placeholders_carry_values_with_spaces_and_quotes() {
+        let fake = FakeProc::new("placeholders");
+        let output = env::temp_dir().join(format!(
+            "qq-x11-guard-placeholders-{}.txt",
+            std::process::id()
+        ));
+        let config = Config {
+            display: ":4242".to_string(),
+            proc_root: fake.proc_root(),
+            threshold: 10,
+            on_failure_cmd: Some(format!(
+                "sh -c 'printf \"%s\\n\" \"$@\" > {}' sh {{reason}} {{pids}} {{count}} {{socket}} {{app}}",
+                output.display()
+            )),
+            ..Config::default()
         };
-        self.wd_to_pid.remove(&wd);
-        unsafe {
-            libc::inotify_rm_watch(self.fd, wd);
-        }
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        guard.generation = HashSet::from([(101, 101), (100, 100)]);
+        guard.last_gauge = Some(12);
+        guard.handle_failure("restart-failed", "重啟失敗", "it's a \"test\"; $(id)");
+        assert_eq!(
+            fs::read_to_string(&output).unwrap(),
+            "it's a \"test\"; $(id)\n100 101\n12\n/tmp/.X11-unix/X4242\nqq\n"
+        );
+        let _ = fs::remove_file(&output);
+
+        guard.manual_reason = Some("leak 'again'".to_string());
+        let values = guard.action_placeholders();
+        assert!(values.contains(&("reason", "manual:leak 'again'".to_string())));
+        assert!(values.contains(&("incident", guard.incident_id())));
+
+        // 歷史記錄中的動作同樣記下手動的原因與觸發的規則
+        let history_path = output.with_extension("history");
+        let _ = fs::remove_file(&history_path);
+        guard.config.history_file = Some(history_path.clone());
+        guard.open_history();
+        let restarted = RestartOutcome::Restarted {
+            old_pids: vec![100],
+            new_pids: vec![200],
+        };
+        guard.record_outcome(&restarted, true);
+        guard.manual_reason = None;
+        guard.action_rule = Some("bloat".to_string());
+        guard.record_outcome(&RestartOutcome::Failed("逾時".to_string()), false);
+        let actions: Vec<(String, String)> = history::read(&history_path)
+            .unwrap()
+            .into_iter()
+            .filter_map(|record| match record {
+                history::Record::Event(event) if event.stage == "action" => {
+                    Some((event.trigger, event.detail))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                (
+                    "manual:leak 'again'".to_string(),
+                    "手動：PID [100] → [200]".to_string()
+                ),
+                ("rule:bloat".to_string(), "逾時".to_string()),
+            ]
+        );
+        let _ = fs::remove_file(&history_path);
     }
 
-    fn sync_pids(&mut self, current_pids: &[i32]) {
-        let current: HashSet<i32> = current_pids.iter().copied().collect();
-        let existing: HashSet<i32> = self.pid_to_wd.keys().copied().collect();
+    #[test]
+    fn external_restart_resets_generation_state() {
+        let fake = FakeProc::new("generation");
+        fake.add(100, "qq", &[]);
+        fake.add(101, "qq", &[]);
+        let config = Config {
+            display: ":4242".to_string(),
+            proc_root: fake.proc_root(),
+            cooldown_seconds: 120,
+            external_restart_cooldown: Some(5),
+            ..Config::default()
+        };
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        guard.check_threshold("fallback", None);
+        assert_eq!(guard.generation.len(), 2);
 
-        for pid in existing.difference(&current) {
-            self.remove_pid(*pid);
-        }
-        for pid in current.difference(&existing) {
-            self.add_pid(*pid);
-        }
+        // 部分程序換新不算重啟，狀態保留
+        fake.add(102, "qq", &[]);
+        fake.remove(100);
+        guard.last_restart = Some(Instant::now());
+        guard.active_levels.push("early".to_string());
+        guard.check_threshold("fallback", None);
+        assert_eq!(guard.active_levels, vec!["early".to_string()]);
+        assert!(guard.time_until_eligible().unwrap().0 > Duration::from_secs(100));
+
+        // 整組換新：清除上一代狀態並縮短冷卻
+        fake.remove(101);
+        fake.remove(102);
+        fake.add(200, "qq", &[]);
+        guard.consecutive_breaches = 3;
+        guard.incident_removed.push("5001".to_string());
+        guard.check_threshold("fallback", None);
+        assert!(guard.active_levels.is_empty());
+        assert_eq!(guard.consecutive_breaches, 0);
+        assert!(guard.incident_removed.is_empty());
+        assert_eq!(guard.generation, HashSet::from([(200, 200)]));
+        assert!(guard.time_until_eligible().unwrap().0 <= Duration::from_secs(5));
     }
 
-    fn wait_for_events(&mut self, timeout: Duration) -> io::Result<Vec<i32>> {
-        let timeout_ms = timeout.as_millis().min(i32::MAX as u128) as i32;
-        let mut poll_fd = libc::pollfd {
-            fd: self.fd,
-            events: libc::POLLIN,
-            revents: 0,
+    #[test]
+    fn exec_transitions_rebuild_watches_in_both_directions() {
+        let fake = FakeProc::new("exec");
+        fake.add(100, "qq", &[]);
+        fake.add(200, "qq-launcher", &[]);
+        fake.add(300, "sh", &[]);
+        let names = vec!["qq".to_string()];
+        let config = Config {
+            display: ":4242".to_string(),
+            proc_root: fake.proc_root(),
+            app_name: "qq".to_string(),
+            ..Config::default()
         };
-        let poll_result = unsafe { libc::poll(&mut poll_fd as *mut libc::pollfd, 1, timeout_ms) };
-        if poll_result < 0 {
-            return Err(io::Error::last_os_error());
-        }
-        if poll_result == 0 {
-            return Ok(Vec::new());
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        guard.check_threshold("fallback", None);
+        assert_eq!(guard.generation, HashSet::from([(100, 100)]));
+        assert!(guard.inotify.pid_to_wd.contains_key(&100));
+
+        // 監控中的 100 exec 成別的程式、啟動器 200 exec 成 qq；同 PID 同啟動時間
+        fs::write(fake.root.join("100/comm"), "bash\n").unwrap();
+        fs::write(fake.root.join("200/comm"), "qq\n").unwrap();
+        guard.check_threshold("fallback", None);
+        assert_eq!(guard.generation, HashSet::from([(200, 200)]));
+        let watched: Vec<i32> = guard.inotify.pid_to_wd.keys().copied().collect();
+        assert_eq!(watched, vec![200]);
+        assert_eq!(
+            guard
+                .exec
+                .watched
+                .get(&200)
+                .map(|(start, name, _)| (*start, name.as_str())),
+            Some((200, "qq"))
+        );
+
+        // 直接比對 ExecTracker 的結果：兩個方向各一筆，名稱前後都記下
+        let proc_root = fake.proc_root();
+        let mut tracker = ExecTracker::default();
+        tracker.observe(&proc_root, scan_process_names(&proc_root), &[200]);
+        fs::write(fake.root.join("200/comm"), "qq-launcher\n").unwrap();
+        fs::write(fake.root.join("100/comm"), "qq\n").unwrap();
+        let matched = match_pids_by_name(&proc_root, &scan_process_names(&proc_root), &names, &[]);
+        assert_eq!(matched, vec![100]);
+        let transitions = tracker.observe(&proc_root, scan_process_names(&proc_root), &matched);
+        assert_eq!(
+            transitions
+                .iter()
+                .map(|t| (
+                    t.pid,
+                    t.from.as_str(),
+                    t.to.as_str(),
+                    t.was_watched,
+                    t.matches
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (100, "bash", "qq", false, true),
+                (200, "qq", "qq-launcher", true, false),
+            ]
+        );
+
+        // 上次掃描之後才啟動的是重用 PID 的新程序（啟動時間 300 晚於開機後 1.5 秒），不算 exec
+        fs::write(fake.root.join("uptime"), "1.50 0.00\n").unwrap();
+        tracker.observe(&proc_root, scan_process_names(&proc_root), &matched);
+        fs::write(fake.root.join("300/comm"), "qq\n").unwrap();
+        assert!(tracker
+            .observe(&proc_root, scan_process_names(&proc_root), &[100, 300])
+            .is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "backend-ss")]
+    fn parse_socket_owners_reads_process_info_when_present() {
+        let body = "\
+u_str ESTAB 0      0                       * 7931  * 7930  users:((\"fakeqq\",pid=4795,fd=5))
+u_str ESTAB 0      0      /tmp/.X11-unix/X99 7928 * 7929 users:((\"Xorg\",pid=4790,fd=10),(\"Xorg\",pid=4791,fd=3))
+u_str ESTAB 0      0                       * 8001  * 8002
+u_str ESTAB 0      0      /run/user/1000/my app/sock 8101 * 8100 users:((\"Web Content\",pid=5120,fd=31))
+";
+        let owners = parse_socket_owners(body);
+        assert_eq!(owners.len(), 3);
+        // 程序名稱與 socket 路徑含空白時欄位不會錯位
+        assert_eq!(
+            owners.get("8101"),
+            Some(&SocketOwner {
+                pid: 5120,
+                comm: "Web Content".to_string()
+            })
+        );
+        assert_eq!(
+            owners.get("7931"),
+            Some(&SocketOwner {
+                pid: 4795,
+                comm: "fakeqq".to_string()
+            })
+        );
+        assert_eq!(owners.get("7928").map(|owner| owner.pid), Some(4790));
+        assert!(!owners.contains_key("8001"));
+    }
+
+    #[test]
+    #[cfg(feature = "backend-ss")]
+    fn intra_app_peers_are_those_whose_server_side_is_held_by_the_app() {
+        let names = x11_socket_names("/tmp/.X11-unix/X99", None);
+        let body = "\
+u_str ESTAB 0 0 /tmp/.X11-unix/X99 7928 * 7929 users:((\"Xorg\",pid=4790,fd=10))
+u_str ESTAB 0 0 /tmp/.X11-unix/X99 7930 * 7931 users:((\"Xorg\",pid=4790,fd=11),(\"qq\",pid=4795,fd=40))
+u_str ESTAB 0 0 /tmp/.X11-unix/X99 7932 * 7933 users:((\"qq\",pid=4796,fd=41))
+u_str ESTAB 0 0 /tmp/.X11-unix/X99 7934 * 7935
+u_str ESTAB 0 0 /tmp/.X11-unix/X99 7936 * 7937 users:((\"Xorg\",pid=4790,fd=12),(\"qq helper\",pid=4796,fd=42))
+";
+        let mut inodes: Vec<String> = parse_intra_app_peers(body, &names, &[4795, 4796])
+            .into_iter()
+            .collect();
+        inodes.sort();
+        assert_eq!(
+            inodes,
+            vec!["7931".to_string(), "7933".to_string(), "7937".to_string()]
+        );
+        assert!(parse_intra_app_peers(body, &names, &[1]).is_empty());
+        // 所有名稱併成一次 ss 查詢；實際輸出中路徑與 abstract 的連線交錯出現
+        let combined = "\
+u_str ESTAB 0      0       /tmp/.X11-unix/X99 405795 * 405794 users:((\"qq\",pid=1102,fd=6)) 
+u_str ESTAB 0      0       /tmp/.X11-unix/X99 405799 * 405798 users:((\"Xorg\",pid=700,fd=10))
+u_str ESTAB 0      0       /tmp/.X11-unix/X99 250033 * 0      users:((\"Xorg\",pid=700,fd=8))
+u_str ESTAB 0      0      @/tmp/.X11-unix/X99 405797 * 405796 users:((\"Xorg\",pid=700,fd=8))
+";
+        let mut peers: Vec<String> = parse_ss_peers(combined, &names).into_iter().collect();
+        peers.sort();
+        assert_eq!(peers, ["405794", "405796", "405798"]);
+        assert!(parse_ss_peers("", &names).is_empty());
+        assert_eq!(
+            ss_on_x11_socket(&names).argv(),
+            [
+                "ss",
+                "-xnpH",
+                "src",
+                "@/tmp/.X11-unix/X99",
+                "or",
+                "src",
+                "/tmp/.X11-unix/X99"
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_escalation_level_reads_condition_and_actions() {
+        let level = parse_escalation_level("early:percent=80:notify=desktop+cmd,urgency=low")
+            .expect("有效設定");
+        assert_eq!(level.name, "early");
+        assert_eq!(level.condition, EscalationCondition::PercentOfThreshold(80));
+        assert_eq!(level.notifiers, vec![Notifier::Desktop, Notifier::Command]);
+        assert_eq!(level.urgency, Urgency::Low);
+        assert_eq!(level.log_level, LogLevel::Warn);
+
+        let level = parse_escalation_level("page:breaker-open:log=error").expect("有效設定");
+        assert_eq!(level.condition, EscalationCondition::BreakerOpen);
+        assert_eq!(level.log_level, LogLevel::Error);
+
+        for spec in [
+            "",
+            "x",
+            "x:percent=0",
+            "x:consecutive=a",
+            "x:load=3",
+            "x:percent=80:notify=mail",
+        ] {
+            assert!(parse_escalation_level(spec).is_err(), "{spec} 應該被拒絕");
         }
+    }
 
-        let mut events = Vec::new();
-        let mut buffer = [0u8; EVENT_BUF_SIZE];
+    #[test]
+    fn fd_scan_pool_keeps_input_order_and_isolates_missing_pids() {
+        let own = std::process::id() as i32;
+        let pids = [own, i32::MAX, own];
+        let proc_root = ProcRoot::default();
+        let pool = FdScanPool::new(3, &proc_root).expect("建立掃描執行緒");
+        let scanned: Vec<HashSet<String>> = pool
+            .scan(&pids)
+            .into_iter()
+            .map(|result| result.expect("自己的 fd 目錄應可讀取"))
+            .collect();
+        let serial: Vec<HashSet<String>> = pids
+            .iter()
+            .map(|pid| socket_inodes_for_pid(&proc_root, *pid))
+            .collect();
+        assert_eq!(scanned.len(), 3);
+        assert!(scanned[1].is_empty());
+        assert_eq!(scanned[0], scanned[2]);
+        assert_eq!(scanned[1], serial[1]);
+    }
 
-        loop {
-            let read_size =
-                unsafe { libc::read(self.fd, buffer.as_mut_ptr().cast(), buffer.len()) as isize };
-            if read_size < 0 {
-                let error = io::Error::last_os_error();
-                if matches!(error.raw_os_error(), Some(code) if code == libc::EAGAIN || code == libc::EINTR)
-                {
-                    break;
+    // 合成 100 個各持有 100 個 socketpair 的子程序，比較循序與執行緒池掃描
+    // 執行：cargo test --release -- --ignored --nocapture fd_scan_benchmark
+    #[test]
+    #[ignore]
+    fn fd_scan_benchmark_100_pids() {
+        let mut children = Vec::new();
+        for _ in 0..100 {
+            let pid = unsafe { libc::fork() };
+            assert!(pid >= 0, "fork 失敗");
+            if pid == 0 {
+                unsafe {
+                    for _ in 0..100 {
+                        let mut fds = [0; 2];
+                        libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr());
+                    }
+                    libc::pause();
+                    libc::_exit(0);
                 }
-                return Err(error);
-            }
-            if read_size == 0 {
-                break;
             }
+            children.push(pid);
+        }
+        thread::sleep(Duration::from_millis(500));
 
-            let mut offset = 0usize;
-            let total = read_size as usize;
-            while offset + mem::size_of::<libc::inotify_event>() <= total {
-                let event_ptr =
-                    unsafe { buffer.as_ptr().add(offset).cast::<libc::inotify_event>() };
-                let event = unsafe { ptr::read_unaligned(event_ptr) };
-                offset += mem::size_of::<libc::inotify_event>();
+        let proc_root = ProcRoot::default();
+        let rounds = 5;
+        let started = Instant::now();
+        let mut serial = Vec::new();
+        for _ in 0..rounds {
+            serial = children
+                .iter()
+                .map(|pid| socket_inodes_for_pid(&proc_root, *pid))
+                .collect();
+        }
+        let serial_elapsed = started.elapsed() / rounds;
 
-                let name_len = event.len as usize;
-                if offset + name_len > total {
-                    break;
-                }
-                offset += name_len;
+        let workers = thread::available_parallelism().map_or(4, |n| n.get().clamp(2, 8));
+        let pool = FdScanPool::new(workers, &proc_root).expect("建立掃描執行緒");
+        let started = Instant::now();
+        let mut pooled = Vec::new();
+        for _ in 0..rounds {
+            pooled = pool.scan(&children);
+        }
+        let pooled_elapsed = started.elapsed() / rounds;
+        let pooled: Vec<HashSet<String>> = pooled.into_iter().map(Result::unwrap).collect();
 
-                let pid_opt = self.wd_to_pid.get(&event.wd).copied();
-                if event.mask & (libc::IN_DELETE_SELF | libc::IN_MOVE_SELF) != 0 {
-                    if let Some(pid) = pid_opt {
-                        self.remove_pid(pid);
-                        events.push(pid);
-                    }
-                    continue;
-                }
-                if let Some(pid) = pid_opt {
-                    events.push(pid);
-                }
+        for pid in &children {
+            unsafe {
+                libc::kill(*pid, libc::SIGKILL);
+                libc::waitpid(*pid, ptr::null_mut(), 0);
             }
         }
-        Ok(events)
+        assert_eq!(serial, pooled);
+        assert!(serial.iter().all(|inodes| inodes.len() >= 200));
+        println!("循序: {serial_elapsed:?}，執行緒池（{workers} 條）: {pooled_elapsed:?}");
     }
-}
 
-impl Drop for InotifyWatch {
-    fn drop(&mut self) {
-        if self.fd >= 0 {
-            unsafe {
-                libc::close(self.fd);
+    // 舊一代收到訊號後結束、新一代同時啟動：等待只看舊一代，不受新一代影響
+    #[test]
+    fn wait_until_gone_ignores_overlapping_generation() {
+        let spawn = || {
+            std::process::Command::new("sleep")
+                .arg("30")
+                .spawn()
+                .expect("啟動 sleep")
+        };
+        let proc_root = ProcRoot::default();
+        for supported in [true, false] {
+            let mut table = PidfdTable::detect();
+            if supported && !table.supported {
+                continue;
+            }
+            table.supported = supported;
+            let mut old: Vec<_> = (0..2).map(|_| spawn()).collect();
+            let old_pids: Vec<i32> = old.iter().map(|child| child.id() as i32).collect();
+            table.sync_pids(&old_pids);
+            let mut new: Vec<_> = (0..2).map(|_| spawn()).collect();
+            let new_pids: Vec<i32> = new.iter().map(|child| child.id() as i32).collect();
+
+            let reaper = thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                for child in &mut old {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                }
+            });
+            assert!(
+                wait_until_gone(&proc_root, &old_pids, &table, Duration::from_secs(5)).is_empty()
+            );
+            reaper.join().unwrap();
+            assert_eq!(
+                wait_until_gone(&proc_root, &new_pids, &table, Duration::from_millis(50)),
+                new_pids
+            );
+            for child in &mut new {
+                let _ = child.kill();
+                let _ = child.wait();
             }
         }
     }
-}
 
-// ===== 區塊 4：超標後的重啟動作 =====
-fn terminate_processes(pids: &[i32], sig: i32) {
-    for pid in pids {
+    #[test]
+    fn wait_until_gone_treats_zombies_as_exited_without_pidfd() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .expect("啟動 sleep");
+        let pid = child.id() as i32;
+        let mut table = PidfdTable::detect();
+        table.supported = false;
+        let proc_root = ProcRoot::default();
+        assert_eq!(
+            wait_until_gone(&proc_root, &[pid], &table, Duration::from_millis(50)),
+            vec![pid]
+        );
+        // 尚未被回收的殭屍程序不應拖到逾時
         unsafe {
-            libc::kill(*pid, sig);
+            libc::kill(pid, libc::SIGKILL);
         }
+        let started = Instant::now();
+        assert!(wait_until_gone(&proc_root, &[pid], &table, Duration::from_secs(5)).is_empty());
+        assert!(started.elapsed() < Duration::from_secs(1));
+        let _ = child.wait();
     }
-}
 
-fn wait_until_gone(process_name: &str, timeout: Duration) -> bool {
-    let deadline = Instant::now() + timeout;
-    loop {
-        if find_pids_by_name(process_name).is_empty() {
-            return true;
-        }
-        if Instant::now() >= deadline {
-            return find_pids_by_name(process_name).is_empty();
+    #[test]
+    fn pidfd_table_signals_and_waits_for_exit() {
+        let mut table = PidfdTable::detect();
+        if !table.supported {
+            eprintln!("略過：核心不支援 pidfd");
+            return;
         }
-        thread::sleep(Duration::from_millis(200));
+        let mut child = std::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .expect("啟動 sleep");
+        let pid = child.id() as i32;
+        table.sync_pids(&[pid]);
+        assert!(!table.wait_exit(&[pid], Duration::from_millis(50)));
+        table.signal(pid, libc::SIGTERM).expect("送出訊號");
+        assert!(table.wait_exit(&[pid], Duration::from_secs(5)));
+        child.wait().expect("回收子程序");
+        // 已回收的 PID 無法再開 pidfd，不會退回 kill
+        table.sync_pids(&[]);
+        assert!(table.signal(pid, libc::SIGTERM).is_err());
     }
-}
-
-fn start_process(command: &str) {
-    let _ = Command::new("sh")
-        .args(["-lc", command])
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn();
-}
 
-// ===== 區塊 5：主事件迴圈 =====
-struct Guard {
-    config: Config,
-    socket_path: String,
-    inotify: InotifyWatch,
-    last_restart: Option<Instant>,
-}
+    #[test]
+    fn panic_budget_counts_only_the_last_hour() {
+        let start = Instant::now();
+        let mut budget = PanicBudget {
+            limit: 2,
+            times: VecDeque::new(),
+        };
+        assert_eq!(budget.record(start), Some(1));
+        assert_eq!(budget.record(start + Duration::from_secs(60)), Some(2));
+        // 一小時前的那次已經不算
+        assert_eq!(budget.record(start + Duration::from_secs(3650)), Some(2));
+        assert_eq!(budget.record(start + Duration::from_secs(3655)), None);
 
-impl Guard {
-    fn new(config: Config) -> Result<Self, String> {
-        let socket_path = display_to_socket(&config.display)?;
-        let inotify = InotifyWatch::new().map_err(|err| format!("inotify 初始化失敗: {err}"))?;
-        Ok(Self {
-            config,
-            socket_path,
-            inotify,
-            last_restart: None,
-        })
+        let mut strict = PanicBudget {
+            limit: 0,
+            times: VecDeque::new(),
+        };
+        assert_eq!(strict.record(start), None);
     }
 
-    fn sync_watches(&mut self) -> Vec<i32> {
-        let pids = find_pids_by_name(&self.config.app_name);
-        self.inotify.sync_pids(&pids);
-        pids
+    #[test]
+    fn loop_trace_times_phases_only_when_enabled() {
+        let mut disabled = None;
+        assert_eq!(LoopTrace::phase(&mut disabled, "event", || 7), 7);
+
+        let mut trace = Some(LoopTrace {
+            iteration: 3,
+            started: Instant::now(),
+            timeout: Duration::from_millis(1500),
+            deadline: "fallback",
+            waited: Duration::from_millis(1499),
+            events: 0,
+            exited: 1,
+            control: false,
+            phases: Vec::new(),
+            fired: vec!["fallback"],
+            measurements: 0,
+            measured: Some(Duration::from_micros(2500)),
+        });
+        LoopTrace::phase(&mut trace, "exit", || {
+            thread::sleep(Duration::from_millis(2))
+        });
+        let trace = trace.unwrap();
+        assert_eq!(trace.phases.len(), 1);
+        assert!(trace.phases[0].1 >= Duration::from_millis(2));
+        let text = trace.text();
+        assert!(
+            text.starts_with(
+                "迴圈 #3：等待 1499.0 ms（上限 1500.0 ms，取自 fallback），喚醒：inotify 0 個 PID、結束 1 個、控制命令 無；處理：exit "
+            ),
+            "{text}"
+        );
+        assert!(text.contains("（其中量測 2.5 ms）；到期的計時器：fallback；本輪共 "));
     }
 
-    fn restart_app(&mut self, x11_count: usize) {
-        if let Some(last) = self.last_restart {
-            let elapsed = last.elapsed().as_secs();
-            if elapsed < self.config.cooldown_seconds {
-                let remain = self.config.cooldown_seconds - elapsed;
-                log(
-                    &self.config,
-                    &format!("超標但在冷卻期中，剩餘約 {remain} 秒"),
-                );
-                return;
+    #[test]
+    fn timer_wheel_fires_each_task_at_its_cadence() {
+        let start = Instant::now();
+        let mut timers = TimerWheel::new();
+        timers.add(PeriodicTask::SyncWatches, Duration::from_secs(2), start);
+        timers.add(PeriodicTask::FallbackPoll, Duration::from_secs(15), start);
+        timers.add(PeriodicTask::Summary, Duration::from_secs(20), start);
+        assert_eq!(
+            timers.next_deadline(),
+            Some((PeriodicTask::SyncWatches, start + Duration::from_secs(2)))
+        );
+
+        let mut sync_at = Vec::new();
+        let mut fallback_at = Vec::new();
+        let mut summary_at = Vec::new();
+        // 每 250ms 檢查一次，並帶一點處理延遲，觸發時間仍應對齊週期
+        for tick in 1..=120u64 {
+            let now = start + Duration::from_millis(tick * 250 + 30);
+            for task in timers.due(now) {
+                let second = (now - start).as_secs();
+                match task {
+                    PeriodicTask::SyncWatches => sync_at.push(second),
+                    PeriodicTask::FallbackPoll => fallback_at.push(second),
+                    PeriodicTask::Summary => summary_at.push(second),
+                    PeriodicTask::XProbe => unreachable!("沒有加入探測任務"),
+                }
             }
         }
+        assert_eq!(sync_at, (1..=15).map(|n| n * 2).collect::<Vec<u64>>());
+        assert_eq!(fallback_at, vec![15, 30]);
+        assert_eq!(summary_at, vec![20]);
 
-        let pids = find_pids_by_name(&self.config.app_name);
-        if pids.is_empty() {
-            log(&self.config, "偵測超標時找不到目標程序，略過重啟");
-            return;
-        }
+        // 落後多個週期時只觸發一次，並從當下重新起算
+        let late = start + Duration::from_secs(100);
+        assert_eq!(
+            timers.due(late),
+            vec![
+                PeriodicTask::SyncWatches,
+                PeriodicTask::FallbackPoll,
+                PeriodicTask::Summary
+            ]
+        );
+        assert_eq!(
+            timers.next_deadline(),
+            Some((PeriodicTask::SyncWatches, late + Duration::from_secs(2)))
+        );
+    }
 
-        log(
-            &self.config,
-            &format!(
-                "{} X11 連線 {} 條，超過門檻 {}，準備重啟",
-                self.config.app_name, x11_count, self.config.threshold
-            ),
+    #[test]
+    fn overlong_socket_paths_fail_with_the_length() {
+        assert!(check_socket_path_length(&[b'a'; 107]).is_ok());
+        assert_eq!(
+            check_socket_path_length(&[b'a'; 108]).unwrap_err(),
+            "socket 路徑長 108 bytes，超過 sockaddr_un 的上限 107 bytes"
         );
 
-        if self.config.dry_run {
-            log(&self.config, "dry-run 模式：不會實際重啟程序");
-            self.last_restart = Some(Instant::now());
-            return;
-        }
+        let display = format!(":{}", "9".repeat(100));
+        assert_eq!(
+            parse_display(&display).unwrap_err(),
+            format!("無效 DISPLAY: {display}（socket 路徑長 116 bytes，超過 sockaddr_un 的上限 107 bytes）")
+        );
 
-        terminate_processes(&pids, libc::SIGTERM);
-        if !wait_until_gone(&self.config.app_name, Duration::from_secs(8)) {
-            let remaining = find_pids_by_name(&self.config.app_name);
-            if !remaining.is_empty() {
-                terminate_processes(&remaining, libc::SIGKILL);
-                let _ = wait_until_gone(&self.config.app_name, Duration::from_secs(3));
-            }
-        }
-        start_process(&self.config.restart_cmd);
-        self.last_restart = Some(Instant::now());
-        log(
-            &self.config,
-            &format!("已執行重啟命令: {}", self.config.restart_cmd),
+        let deep = format!("/run/user/1000/{}/guard.sock", "d".repeat(100));
+        let args = ["qq-x11-guard-rs", "--control-socket", &deep].map(String::from);
+        assert_eq!(
+            parse_arg_list(&args, 0).unwrap_err(),
+            format!("--control-socket socket 路徑長 126 bytes，超過 sockaddr_un 的上限 107 bytes: {deep}")
         );
-    }
 
-    fn check_threshold(&mut self, trigger: &str, pids: Option<Vec<i32>>) {
-        let pids = if let Some(value) = pids {
-            self.inotify.sync_pids(&value);
-            value
-        } else {
-            self.sync_watches()
-        };
-        if pids.is_empty() {
-            return;
-        }
+        // 直接連線的探測在送出 connect 之前就回報，不會變成含糊的連線失敗
+        let error = xprobe::connect(
+            &X11Endpoint::Unix(deep.clone()),
+            Instant::now() + Duration::from_secs(1),
+        )
+        .unwrap_err();
+        assert!(error.contains("超過 sockaddr_un 的上限"), "{error}");
+    }
 
-        let x11_count = count_app_x11_connections(&pids, &self.socket_path);
-        if x11_count > self.config.threshold {
-            self.restart_app(x11_count);
-        } else if trigger == "fallback" {
-            log(
-                &self.config,
-                &format!(
-                    "目前 {} X11 連線 {} 條（門檻 {}）",
-                    self.config.app_name, x11_count, self.config.threshold
-                ),
-            );
+    #[test]
+    #[cfg(feature = "backend-procfs")]
+    fn display_maps_to_unix_socket_or_forwarded_tcp_port() {
+        let unix = |path: &str| Ok(X11Endpoint::Unix(path.to_string()));
+        assert_eq!(parse_display(":0"), unix("/tmp/.X11-unix/X0"));
+        assert_eq!(parse_display(":1.1"), unix("/tmp/.X11-unix/X1"));
+        assert_eq!(parse_display(":10.0"), unix("/tmp/.X11-unix/X10"));
+        assert_eq!(parse_display("unix:10.0"), unix("/tmp/.X11-unix/X10"));
+        // ssh -X 的 localhost:10.0 → 6000 + 10；X11UseLocalhost no 時為主機名稱，port 相同
+        assert_eq!(parse_display("localhost:10.0"), Ok(X11Endpoint::Tcp(6010)));
+        assert_eq!(parse_display("remotebox:10"), Ok(X11Endpoint::Tcp(6010)));
+        assert_eq!(parse_display("127.0.0.1:0"), Ok(X11Endpoint::Tcp(6000)));
+        assert_eq!(
+            parse_display("localhost:59535"),
+            Ok(X11Endpoint::Tcp(65535))
+        );
+        for display in [
+            "",
+            "0",
+            ":",
+            ":.1",
+            ":0.",
+            ":0.a",
+            ":0.1.2",
+            "localhost:",
+            "localhost:59536",
+        ] {
+            assert!(parse_display(display).is_err(), "{display} 應該被拒絕");
         }
     }
 
-    fn run(&mut self) -> io::Result<()> {
-        log(
-            &self.config,
-            &format!(
-                "啟動監控，DISPLAY={}，門檻={}",
-                self.config.display, self.config.threshold
-            ),
+    #[test]
+    fn tcp_clients_count_only_connections_ending_at_a_local_forwarder() {
+        let header = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n";
+        let row = |local: &str, remote: &str, state: &str, inode: u32| {
+            format!("   0: {local} {remote} {state} 00000000:00000000 00:00000000 00000000  1000        0 {inode} 1 0000000000000000 20 4 30 10 -1\n")
+        };
+        // X11UseLocalhost yes：sshd 綁在 127.0.0.1:6010（0x177A），qq 從 127.0.0.1:40000（0x9C40）連入
+        let localhost = [
+            row("0100007F:177A", "00000000:0000", TCP_LISTEN, 10),
+            row("0100007F:9C40", "0100007F:177A", TCP_ESTABLISHED, 11),
+            row("0100007F:177A", "0100007F:9C40", TCP_ESTABLISHED, 12),
+            // 已關閉、等待回收的連線不算
+            row("0100007F:9C41", "0100007F:177A", "06", 0),
+            // 連到別台機器 6010 的連線沒有對向的一端
+            row("0100007F:9C42", "0A000005:177A", TCP_ESTABLISHED, 13),
+        ]
+        .concat();
+        let body = format!("{header}{localhost}");
+        assert_eq!(
+            parse_tcp_listening_inodes(&body, 6010),
+            HashSet::from(["10".to_string()])
+        );
+        assert_eq!(
+            parse_tcp_x11_clients(&body, 6010),
+            HashSet::from(["11".to_string()])
         );
+        assert!(parse_tcp_x11_clients(&body, 6011).is_empty());
 
-        let pids = self.sync_watches();
-        self.check_threshold("startup", Some(pids));
+        // X11UseLocalhost no：sshd 綁在 0.0.0.0:6010，qq 經由主機名稱連到 192.168.1.5
+        let all_interfaces = [
+            row("00000000:177A", "00000000:0000", TCP_LISTEN, 20),
+            row("0501A8C0:9C40", "0501A8C0:177A", TCP_ESTABLISHED, 21),
+            row("0501A8C0:177A", "0501A8C0:9C40", TCP_ESTABLISHED, 22),
+        ]
+        .concat();
+        let body = format!("{header}{all_interfaces}");
+        assert_eq!(
+            parse_tcp_listening_inodes(&body, 6010),
+            HashSet::from(["20".to_string()])
+        );
+        assert_eq!(
+            parse_tcp_x11_clients(&body, 6010),
+            HashSet::from(["21".to_string()])
+        );
 
-        let mut next_sync = Instant::now() + Duration::from_secs(self.config.scan_interval_seconds);
-        let mut next_fallback =
-            Instant::now() + Duration::from_secs(self.config.fallback_poll_seconds);
+        // 經由 proc_root 讀 tcp 與 tcp6，找到的 inode 就是 fd 指向的 socket:[N]
+        let fake = FakeProc::new("tcp");
+        fs::create_dir_all(fake.root.join("net")).unwrap();
+        fs::write(fake.root.join("net/tcp"), format!("{header}{localhost}")).unwrap();
+        fs::write(
+            fake.root.join("net/tcp6"),
+            format!(
+                "{header}{}{}",
+                row(
+                    "00000000000000000000000001000000:9C50",
+                    "00000000000000000000000001000000:177A",
+                    TCP_ESTABLISHED,
+                    31
+                ),
+                row(
+                    "00000000000000000000000001000000:177A",
+                    "00000000000000000000000001000000:9C50",
+                    TCP_ESTABLISHED,
+                    32
+                ),
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            tcp_x11_client_inodes(&fake.proc_root(), 6010),
+            HashSet::from(["11".to_string(), "31".to_string()])
+        );
+    }
 
-        loop {
-            let now = Instant::now();
-            if now >= next_sync {
-                self.sync_watches();
-                next_sync = now + Duration::from_secs(self.config.scan_interval_seconds);
-            }
+    #[test]
+    fn connection_delta_reports_added_with_owner_and_removed() {
+        let previous: HashMap<String, i32> = [("100", 7), ("101", 7), ("102", 8)]
+            .iter()
+            .map(|(inode, pid)| (inode.to_string(), *pid))
+            .collect();
+        let current = vec![
+            ("101".to_string(), 7),
+            ("103".to_string(), 8),
+            ("99".to_string(), 9),
+        ];
+        let (added, removed) = connection_delta(&previous, &current);
+        assert_eq!(added, vec![("103".to_string(), 8), ("99".to_string(), 9)]);
+        assert_eq!(removed, vec!["100".to_string(), "102".to_string()]);
+    }
 
-            let timeout_to_sync = next_sync.saturating_duration_since(now);
-            let timeout_to_fallback = next_fallback.saturating_duration_since(now);
-            let timeout = timeout_to_sync
-                .min(timeout_to_fallback)
-                .max(Duration::from_millis(100));
+    #[test]
+    fn notify_digest_batches_non_critical_notifications_per_notifier() {
+        assert_eq!(
+            parse_notify_digest("desktop+cmd=30m"),
+            Ok((vec![Notifier::Desktop, Notifier::Command], 1800))
+        );
+        assert_eq!(parse_notify_digest("90"), Ok((Vec::new(), 90)));
+        assert!(parse_notify_digest("sms=1m").is_err());
 
-            let events = self.inotify.wait_for_events(timeout)?;
-            if !events.is_empty() {
-                self.check_threshold("event", None);
+        let fake = FakeProc::new("digest");
+        let config = Config {
+            display: ":4242".to_string(),
+            proc_root: fake.proc_root(),
+            notify_digest_seconds: Some(600),
+            notify_digest_overrides: vec![(Notifier::Desktop, 0)],
+            ..Config::default()
+        };
+        let mut guard = Guard::new(config).expect("建立 Guard");
+        assert_eq!(guard.digests.len(), 1);
+        fn notification<'a>(
+            level: &'a str,
+            incident: &'a str,
+            urgency: Urgency,
+        ) -> Notification<'a> {
+            Notification {
+                level,
+                incident,
+                urgency,
+                message: "[inc-1] 進入警示等級 warn",
+                core_dump: None,
             }
+        }
+        guard.notify(
+            &[Notifier::Command],
+            &notification("warn", "inc-1", Urgency::Normal),
+            false,
+        );
+        guard.stats.outcomes.insert("重啟", 1);
+        for (level, incident) in [("warn", "inc-1"), ("rules", "inc-1"), ("warn", "inc-2")] {
+            guard.notify(
+                &[Notifier::Command],
+                &notification(level, incident, Urgency::Low),
+                false,
+            );
+        }
+        // 緊急通知與熔斷不進緩衝
+        guard.notify(
+            &[Notifier::Command],
+            &notification("page", "inc-2", Urgency::Critical),
+            false,
+        );
+        guard.notify(
+            &[Notifier::Command],
+            &notification("breaker", "inc-2", Urgency::Normal),
+            true,
+        );
+        assert!(guard
+            .status_text()
+            .contains("通知摘要: cmd 每 600 秒，待送 4 則（2 個事件）"));
+        guard.flush_digests(false);
+        assert_eq!(guard.digests[0].pending, 4);
 
-            let now = Instant::now();
-            if now >= next_fallback {
-                self.check_threshold("fallback", None);
-                next_fallback = now + Duration::from_secs(self.config.fallback_poll_seconds);
-            }
+        let digest = &mut guard.digests[0];
+        digest.note_deferred("冷卻");
+        let mut outcomes = guard.stats.outcomes.clone();
+        outcomes.insert("dry-run", 2);
+        let (incidents, urgency, message) = digest.take("qq", &outcomes).unwrap();
+        assert_eq!(incidents, "inc-1,inc-2");
+        assert_eq!(urgency, Urgency::Normal);
+        assert!(message.starts_with("qq 通知摘要：0 秒內 4 則通知，2 個事件\n"));
+        assert!(message.contains("\nwarn ×2、rules ×1：[inc-1]"));
+        assert!(message.contains("\n期間動作：dry-run 2、重啟 1\n期間延後：冷卻 1"));
+        assert!(digest.take("qq", &outcomes).is_none());
+
+        // 事件數有上限，超過的只計入則數
+        for index in 0..DIGEST_MAX_INCIDENTS + 3 {
+            let incident = format!("inc-{index}");
+            digest.push(&notification("warn", &incident, Urgency::Low), &outcomes);
         }
+        assert_eq!(digest.incidents.len(), DIGEST_MAX_INCIDENTS);
+        assert_eq!(digest.unlisted, 3);
+        guard.flush_digests(true);
+        assert!(guard.digests[0].due().is_none());
+        assert!(guard.status_text().contains("cmd 每 600 秒，無待送"));
     }
-}
 
-fn main() {
-    let config = match parse_args() {
-        Ok(value) => value,
-        Err(error) => {
-            eprintln!("參數錯誤: {error}");
-            eprintln!("使用 --help 查看用法");
-            std::process::exit(2);
+    #[test]
+    fn parse_duration_rejects_invalid_input() {
+        for text in [
+            "", "abc", "m", "1x", "1h30", "30m1h", "1m1m", "-5", "1.5h", " 5",
+        ] {
+            assert!(parse_duration_secs(text).is_err(), "{text} 應該被拒絕");
         }
-    };
+    }
 
-    let mut guard = match Guard::new(config.clone()) {
-        Ok(value) => value,
-        Err(error) => {
-            eprintln!("初始化失敗: {error}");
-            std::process::exit(1);
+    #[test]
+    fn commands_split_into_argv_without_a_shell() {
+        assert_eq!(
+            split_argv(r#"/opt/QQ/qq  --name 'a b' "c \"d\" \x" e\ f"#).unwrap(),
+            ["/opt/QQ/qq", "--name", "a b", "c \"d\" \\x", "e f"]
+        );
+        // 引號內的 shell 語法照字面傳給程式
+        assert_eq!(
+            split_argv("notify '$HOME > 2'").unwrap(),
+            ["notify", "$HOME > 2"]
+        );
+        for invalid in [
+            "", "   ", "qq 'x", "qq \"x", "qq | tee", "qq > log", "qq $HOME", "qq &", "qq \\",
+        ] {
+            assert!(split_argv(invalid).is_err(), "{invalid:?} 應該被拒絕");
         }
-    };
+        // --version 列出的 feature 與實際編入的一致
+        let version = version_text();
+        for (name, enabled) in COMPILED_FEATURES {
+            assert_eq!(version.contains(name), *enabled, "{name}");
+        }
+    }
+
+    #[test]
+    fn command_line_overrides_config_file_arguments() {
+        let args: Vec<String> = [
+            "qq-x11-guard-rs",
+            // 設定檔轉成的參數
+            "--threshold",
+            "20",
+            "--log-prefix",
+            "[guard]",
+            // 命令列
+            "--config",
+            "/etc/qq-guard.toml",
+            "--threshold",
+            "30",
+            "generate-unit",
+        ]
+        .map(String::from)
+        .to_vec();
+        let config = parse_arg_list(&args, 4).unwrap();
+        assert_eq!(config.threshold, 30);
+        assert_eq!(config.log_prefix, "[guard]");
+        assert_eq!(config.unit_args, args[5..9]);
+        assert_eq!(
+            single_flag_value(leading_args(&args), "--config")
+                .unwrap()
+                .as_deref(),
+            Some("/etc/qq-guard.toml")
+        );
+        assert_eq!(
+            single_flag_value(&args[5..7], "--config-dir").unwrap(),
+            None
+        );
+
+        let entry = |origin: &str, args: &[&str]| configfile::Entry {
+            origin: origin.to_string(),
+            flag: args[0].to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        };
+        let entries = [
+            entry("設定檔 a.toml 第 1 行的 app_name", &["--app-name", "qq"]),
+            entry(
+                "設定檔 b.toml 第 2 行的 app_name",
+                &["--app-name", "wechat:5"],
+            ),
+            entry("設定檔 b.toml 第 1 行的 display", &["--display", ":0"]),
+            entry("環境變數 QQ_GUARD_DRY_RUN", &["--dry-run"]),
+        ];
+        let command_line =
+            ["--config", "a.toml", "--threshold", "30", "-v", "-v", "top"].map(String::from);
+        assert_eq!(
+            render_effective_config(&entries, &command_line, &["衝突".to_string()]),
+            "# qq-x11-guard-rs --print-config：合併後生效的設定，未列出的參數為預設值
+# 警告：衝突
+app_name = [
+  \"qq\",  # 設定檔 a.toml 第 1 行的 app_name
+  \"wechat:5\",  # 設定檔 b.toml 第 2 行的 app_name
+]
+display = \":0\"  # 設定檔 b.toml 第 1 行的 display
+dry_run = true  # 環境變數 QQ_GUARD_DRY_RUN
+threshold = 30  # 命令列
+verbose = 2  # 命令列
+"
+        );
 
-    if let Err(error) = guard.run() {
-        eprintln!("{} 執行錯誤: {}", timestamp(), error);
-        std::process::exit(1);
+        assert!(mentions_flag("--threshold 必須 >= 1", "--threshold"));
+        assert!(!mentions_flag("--threshold-scope 不支援", "--threshold"));
     }
 }
@@ -0,0 +1,203 @@
+use crate::{OptionDoc, ESCALATION_FORMAT, OPTIONS, RULE_FORMAT, SUBCOMMANDS, TIME_FORMAT_NOTE};
+
+// generate-man：由 --help 用的同一張選項表產生 roff 格式的 qq-x11-guard(1)
+const PROGRAM: &str = "qq-x11-guard-rs";
+
+const EXIT_CODES: &[(&str, &str)] = &[
+    (
+        "0",
+        "正常結束：收到 SIGTERM/SIGINT、一次性模式重啟成功、dry-run 或柔性處理已解決",
+    ),
+    (
+        "1",
+        "執行或初始化錯誤（含一小時內 panic 超過 --max-panics 次）；ctl 與轉送的 restart-now 收到錯誤回覆或無法連線",
+    ),
+    ("2", "參數錯誤"),
+    (
+        "3",
+        "重啟失敗（--exit-after-restart、restart-now 與 --on-failure-action exit），或 --self-test-restart 有步驟失敗",
+    ),
+];
+
+const SIGNALS: &[(&str, &str)] = &[
+    (
+        "SIGTERM, SIGINT",
+        "在主迴圈的下一輪輸出結束摘要、移除控制 socket 後正常結束",
+    ),
+    (
+        "SIGTERM → SIGKILL",
+        "重啟時先對目標程序送出 SIGTERM，約 8 秒內未結束再送 SIGKILL；可用 --escalate 改變",
+    ),
+    (
+        "--reload-signal",
+        "--action reload 時送給目標程序的訊號，預設 SIGUSR1",
+    ),
+];
+
+const FILES: &[(&str, &str)] = &[
+    (
+        "/tmp/.X11-unix/X<n>",
+        "DISPLAY :<n> 的 X11 socket，連線數以此 socket 的 peer 計算",
+    ),
+    (
+        "/proc/<pid>/fd, /proc/<pid>/comm, /proc/<pid>/stat",
+        "尋找目標程序並歸屬連線；位置可由 --proc-root 改變",
+    ),
+    (
+        "/proc/net/unix, /proc/<pid>/net/unix",
+        "X server 的監聽 socket，用來排除同名的 X server",
+    ),
+    (
+        "--control-socket 指定的路徑",
+        "權限 0600 的控制 socket，結束時移除",
+    ),
+    (
+        "--capture-dir 指定的目錄",
+        "重啟前保存的 <時間戳>-<事件編號>/ 目錄，權限 0700",
+    ),
+    (
+        "--core-dump-dir 指定的目錄",
+        "重啟前保存的 core-<時間戳>-<事件編號>.<pid>，只保留最新的 --core-dump-keep 份",
+    ),
+    (
+        "--log-file 指定的路徑",
+        "日誌檔；指定 --log-max-bytes 時輪替為 <path>.1、<path>.2…",
+    ),
+    (
+        "--history-file 指定的路徑",
+        "取樣與事件的歷史記錄，由 history 子命令查詢；清理時經由 <path>.tmp 改名取代",
+    ),
+];
+
+pub fn render() -> String {
+    let mut page = vec![
+        format!(
+            ".TH QQ\\-X11\\-GUARD 1 \"\" \"{} {}\" \"User Commands\"",
+            escape(PROGRAM),
+            env!("CARGO_PKG_VERSION")
+        ),
+        ".SH NAME".to_string(),
+        format!(
+            "{} \\- 監控程式的 X11 連線數，超標時自動關閉並重啟",
+            escape(PROGRAM)
+        ),
+        ".SH SYNOPSIS".to_string(),
+        format!(".B {}", escape(PROGRAM)),
+        "[\\fI子命令\\fR] [\\fI選項\\fR]".to_string(),
+        ".SH DESCRIPTION".to_string(),
+        escape(
+            "以 inotify 監看目標程序的 fd 目錄，搭配備援輪詢，計算它連到 X11 socket 的連線數；\
+             超過門檻時（依設定先送出重新載入訊號或執行柔性重連）關閉並以 --restart-cmd 重啟，再確認程式重新出現。",
+        ),
+        ".SH SUBCOMMANDS".to_string(),
+    ];
+    for (usage, text) in SUBCOMMANDS {
+        page.push(".TP".to_string());
+        page.push(subcommand_heading(usage));
+        page.push(escape(text));
+    }
+    page.push(".SH OPTIONS".to_string());
+    for option in OPTIONS {
+        page.push(".TP".to_string());
+        page.push(option_heading(option));
+        page.push(escape(option.description));
+        if let Some(default) = option.default {
+            page.push(".br".to_string());
+            page.push(escape(&format!("預設：{default}")));
+        }
+    }
+    page.push(".SS 時間格式".to_string());
+    page.push(escape(TIME_FORMAT_NOTE));
+    page.push(".SS 警示升級格式".to_string());
+    page.push(".nf".to_string());
+    page.extend(ESCALATION_FORMAT.iter().map(|line| escape(line)));
+    page.push(".fi".to_string());
+    page.push(".SS 組合規則格式".to_string());
+    page.push(".nf".to_string());
+    page.extend(RULE_FORMAT.iter().map(|line| escape(line)));
+    page.push(".fi".to_string());
+    for (section, items) in [
+        ("EXIT STATUS", EXIT_CODES),
+        ("SIGNALS", SIGNALS),
+        ("FILES", FILES),
+    ] {
+        page.push(format!(".SH {section}"));
+        for (name, text) in items {
+            page.push(".TP".to_string());
+            page.push(format!(".B {}", escape(name)));
+            page.push(escape(text));
+        }
+    }
+    page.push(".SH SEE ALSO".to_string());
+    page.push(".BR ss (8),".to_string());
+    page.push(".BR lsof (8),".to_string());
+    page.push(".BR notify\\-send (1),".to_string());
+    page.push(".BR seccomp (2)".to_string());
+    page.join("\n") + "\n"
+}
+
+fn option_heading(option: &OptionDoc) -> String {
+    let flags: Vec<String> = option
+        .flags
+        .iter()
+        .map(|flag| format!("\\fB{}\\fR", escape(flag)))
+        .collect();
+    match option.value {
+        Some(value) => format!("{} \\fI{}\\fR", flags.join(", "), escape(value)),
+        None => flags.join(", "),
+    }
+}
+
+// 子命令名稱粗體，其後的參數斜體
+fn subcommand_heading(usage: &str) -> String {
+    match usage.split_once(' ') {
+        Some((name, arguments)) => {
+            format!("\\fB{}\\fR \\fI{}\\fR", escape(name), escape(arguments))
+        }
+        None => format!(".B {}", escape(usage)),
+    }
+}
+
+// roff 中 \ 與 - 需跳脫；以 . 或 ' 開頭的行會被當成巨集
+fn escape(text: &str) -> String {
+    let escaped = text.replace('\\', "\\e").replace('-', "\\-");
+    if escaped.starts_with('.') || escaped.starts_with('\'') {
+        format!("\\&{escaped}")
+    } else {
+        escaped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_option_and_subcommand_appears_in_the_page() {
+        let page = render();
+        assert!(page.starts_with(".TH QQ\\-X11\\-GUARD 1"));
+        for option in OPTIONS {
+            for flag in option.flags {
+                assert!(
+                    page.contains(&format!("\\fB{}\\fR", escape(flag))),
+                    "man page 缺少 {flag}"
+                );
+            }
+            if let Some(default) = option.default {
+                assert!(page.contains(&escape(&format!("預設：{default}"))));
+            }
+        }
+        for (usage, _) in SUBCOMMANDS {
+            let name = usage.split(' ').next().unwrap();
+            assert!(page.contains(&escape(name)), "man page 缺少子命令 {name}");
+        }
+        // 除了巨集行，沒有以 . 開頭、會被誤當巨集的內容
+        assert!(page
+            .lines()
+            .filter(|line| line.starts_with('.'))
+            .all(|line| {
+                let macro_name = line.split(' ').next().unwrap();
+                [".TH", ".SH", ".SS", ".TP", ".B", ".BR", ".br", ".nf", ".fi"].contains(&macro_name)
+            }));
+    }
+}
@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::time::Duration;
+
+// 主迴圈等待的 fd 集合：inotify、各程序的 pidfd、控制 socket，以及之後新增的 fd 來源。
+// 每輪以 sync 交出目前要等的 fd 與對應的來源，epoll 只更動有增減的登記，poll 則每輪重建 pollfd 陣列。
+// 其中一種被 syscall 過濾擋下（ENOSYS、EPERM）時改用另一種，原因留在 note 由呼叫端記錄
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Epoll,
+    Poll,
+}
+
+impl Kind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Epoll => "epoll",
+            Kind::Poll => "poll",
+        }
+    }
+
+    fn other(self) -> Kind {
+        match self {
+            Kind::Epoll => Kind::Poll,
+            Kind::Poll => Kind::Epoll,
+        }
+    }
+}
+
+pub struct Multiplexer<T> {
+    kind: Kind,
+    epoll: Option<OwnedFd>,
+    // 已交給 epoll 的 fd；poll 模式下即為目前的集合
+    registered: HashMap<RawFd, T>,
+    // 呼叫端的 fd 世代（例如 pidfd 重新開啟的次數）；改變時整組重新登記，
+    // 避免關閉後重開、剛好拿到同一個號碼的 fd 被當成已登記
+    generation: Option<u64>,
+    // 換過一次就不再換回，免得兩種都被擋時來回切換
+    switched: bool,
+    note: Option<String>,
+}
+
+fn epoll_create() -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn blocked(error: &io::Error) -> bool {
+    matches!(error.raw_os_error(), Some(libc::ENOSYS | libc::EPERM))
+}
+
+fn timeout_ms(timeout: Duration) -> libc::c_int {
+    timeout.as_millis().min(i32::MAX as u128) as libc::c_int
+}
+
+impl<T: Copy + PartialEq> Multiplexer<T> {
+    // None 為自動：優先 epoll，建立失敗時改用 poll
+    pub fn new(preferred: Option<Kind>) -> Self {
+        let mut mux = Self {
+            kind: preferred.unwrap_or(Kind::Epoll),
+            epoll: None,
+            registered: HashMap::new(),
+            generation: None,
+            switched: false,
+            note: None,
+        };
+        if mux.kind == Kind::Epoll {
+            match epoll_create() {
+                Ok(fd) => mux.epoll = Some(fd),
+                Err(error) => {
+                    mux.kind = Kind::Poll;
+                    mux.switched = true;
+                    mux.note = Some(format!("無法建立 epoll（{error}），改用 poll 等待事件"));
+                }
+            }
+        }
+        mux
+    }
+
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    pub fn take_note(&mut self) -> Option<String> {
+        self.note.take()
+    }
+
+    // 下一次 sync 時整組重新登記；fd 來源整個換掉（例如 panic 後重建 inotify）時呼叫
+    pub fn forget(&mut self) {
+        self.generation = None;
+    }
+
+    pub fn sync(&mut self, fds: &[(RawFd, T)], generation: u64) -> io::Result<()> {
+        let refresh = self.generation != Some(generation);
+        self.generation = Some(generation);
+        let Some(epoll) = &self.epoll else {
+            self.registered = fds.iter().copied().collect();
+            return Ok(());
+        };
+        let epoll = epoll.as_raw_fd();
+        // 已關閉的 fd 會被核心自動移出 epoll，DEL 失敗不必理會
+        self.registered.retain(|fd, _| {
+            let keep = fds.iter().any(|(wanted, _)| wanted == fd);
+            if !keep {
+                unsafe {
+                    libc::epoll_ctl(epoll, libc::EPOLL_CTL_DEL, *fd, std::ptr::null_mut());
+                }
+            }
+            keep
+        });
+        for (fd, token) in fds {
+            if !refresh && self.registered.get(fd) == Some(token) {
+                continue;
+            }
+            let mut event = libc::epoll_event {
+                events: libc::EPOLLIN as u32,
+                u64: *fd as u64,
+            };
+            if unsafe { libc::epoll_ctl(epoll, libc::EPOLL_CTL_ADD, *fd, &mut event) } != 0 {
+                let error = io::Error::last_os_error();
+                if error.raw_os_error() != Some(libc::EEXIST) {
+                    return Err(error);
+                }
+            }
+            self.registered.insert(*fd, *token);
+        }
+        Ok(())
+    }
+
+    // 回傳可讀（或已結束、出錯）的來源；被訊號中斷時回傳空集合，由呼叫端照常進入下一輪
+    pub fn wait(&mut self, timeout: Duration) -> io::Result<Vec<T>> {
+        let result = match self.kind {
+            Kind::Epoll => self.wait_epoll(timeout),
+            Kind::Poll => self.wait_poll(timeout),
+        };
+        match result {
+            Err(error) if blocked(&error) && !self.switched => {
+                self.fall_back(&error)?;
+                self.wait(timeout)
+            }
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => Ok(Vec::new()),
+            other => other,
+        }
+    }
+
+    fn fall_back(&mut self, error: &io::Error) -> io::Result<()> {
+        let from = self.kind;
+        self.switched = true;
+        self.kind = from.other();
+        self.epoll = match self.kind {
+            Kind::Epoll => Some(epoll_create()?),
+            Kind::Poll => None,
+        };
+        self.note = Some(format!(
+            "{} 無法使用（{error}），改用 {} 等待事件",
+            from.name(),
+            self.kind.name()
+        ));
+        // 重新登記目前的集合
+        let fds: Vec<(RawFd, T)> = self.registered.drain().collect();
+        let generation = self.generation.unwrap_or_default();
+        self.generation = None;
+        self.sync(&fds, generation)
+    }
+
+    fn wait_epoll(&mut self, timeout: Duration) -> io::Result<Vec<T>> {
+        let epoll = self.epoll.as_ref().map_or(-1, AsRawFd::as_raw_fd);
+        let mut events =
+            vec![libc::epoll_event { events: 0, u64: 0 }; self.registered.len().max(1)];
+        let ready = unsafe {
+            libc::epoll_wait(
+                epoll,
+                events.as_mut_ptr(),
+                events.len() as libc::c_int,
+                timeout_ms(timeout),
+            )
+        };
+        if ready < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(events[..ready as usize]
+            .iter()
+            .filter_map(|event| self.registered.get(&(event.u64 as RawFd)).copied())
+            .collect())
+    }
+
+    fn wait_poll(&mut self, timeout: Duration) -> io::Result<Vec<T>> {
+        let entries: Vec<(RawFd, T)> = self
+            .registered
+            .iter()
+            .map(|(fd, token)| (*fd, *token))
+            .collect();
+        let mut poll_fds: Vec<libc::pollfd> = entries
+            .iter()
+            .map(|(fd, _)| libc::pollfd {
+                fd: *fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let ready = unsafe {
+            libc::poll(
+                poll_fds.as_mut_ptr(),
+                poll_fds.len() as libc::nfds_t,
+                timeout_ms(timeout),
+            )
+        };
+        if ready < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(entries
+            .iter()
+            .zip(&poll_fds)
+            .filter(|(_, poll_fd)| poll_fd.revents != 0)
+            .map(|((_, token), _)| *token)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn pipe() -> (OwnedFd, OwnedFd) {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+        unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) }
+    }
+
+    fn write_byte(fd: &OwnedFd) {
+        assert_eq!(
+            unsafe { libc::write(fd.as_raw_fd(), b"x".as_ptr().cast(), 1) },
+            1
+        );
+    }
+
+    #[test]
+    fn both_kinds_report_ready_sources_and_honour_the_timeout() {
+        for kind in [Kind::Epoll, Kind::Poll] {
+            let mut mux = Multiplexer::new(Some(kind));
+            assert_eq!(mux.kind(), kind);
+            let (first_read, first_write) = pipe();
+            let (second_read, second_write) = pipe();
+            mux.sync(
+                &[(first_read.as_raw_fd(), 1), (second_read.as_raw_fd(), 2)],
+                0,
+            )
+            .unwrap();
+
+            let started = Instant::now();
+            assert!(mux.wait(Duration::from_millis(50)).unwrap().is_empty());
+            assert!(started.elapsed() >= Duration::from_millis(50));
+
+            write_byte(&second_write);
+            assert_eq!(mux.wait(Duration::from_secs(5)).unwrap(), vec![2]);
+
+            // 移出集合的 fd 不再回報
+            mux.sync(&[(first_read.as_raw_fd(), 1)], 0).unwrap();
+            assert!(mux.wait(Duration::ZERO).unwrap().is_empty());
+            write_byte(&first_write);
+            assert_eq!(mux.wait(Duration::ZERO).unwrap(), vec![1]);
+        }
+    }
+
+    #[test]
+    fn reopened_fd_with_the_same_number_is_registered_again() {
+        let mut mux = Multiplexer::new(Some(Kind::Epoll));
+        let (read, _write) = pipe();
+        mux.sync(&[(read.as_raw_fd(), 7)], 0).unwrap();
+        // 把另一個 pipe 換到同一個號碼上：原本的檔案關閉，核心已把它移出 epoll
+        let (other_read, other_write) = pipe();
+        assert!(
+            unsafe { libc::dup3(other_read.as_raw_fd(), read.as_raw_fd(), libc::O_CLOEXEC) } >= 0
+        );
+        drop(other_read);
+        write_byte(&other_write);
+        mux.sync(&[(read.as_raw_fd(), 7)], 0).unwrap();
+        assert!(mux.wait(Duration::ZERO).unwrap().is_empty());
+        // 呼叫端的世代改變時整組重新登記
+        mux.sync(&[(read.as_raw_fd(), 7)], 1).unwrap();
+        assert_eq!(mux.wait(Duration::from_secs(5)).unwrap(), vec![7]);
+    }
+
+    #[test]
+    fn falling_back_keeps_the_registered_sources() {
+        let mut mux = Multiplexer::new(Some(Kind::Epoll));
+        let (read, write) = pipe();
+        mux.sync(&[(read.as_raw_fd(), 3)], 0).unwrap();
+        mux.fall_back(&io::Error::from_raw_os_error(libc::EPERM))
+            .unwrap();
+        assert_eq!(mux.kind(), Kind::Poll);
+        assert!(mux.take_note().unwrap().starts_with("epoll 無法使用"));
+        write_byte(&write);
+        assert_eq!(mux.wait(Duration::from_secs(5)).unwrap(), vec![3]);
+    }
+}
@@ -0,0 +1,428 @@
+use std::mem;
+
+// --cmdline-arg / --cmdline-regex 用的精簡正規表示式。只依賴 libc 的原則下不引入 regex crate，
+// 支援比對命令列常用的語法：字面字元、.、\ 跳脫（\d \w \s 及其大寫、\n \t、符號）、
+// [...] 與 [^...] 字元類別（含範圍）、( ) 群組、|、* + ?、^ $。
+// 與 grep 相同是「搜尋」語意：沒有 ^ $ 時可以出現在任何位置。
+// 編譯成 NFA 後逐字元同時推進所有狀態（Thompson 的做法）：時間與輸入長度成正比、比對時不遞迴，
+// 其他使用者可以任意拉長的命令列也不會耗盡堆疊或讓比對退化成指數時間
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    program: Vec<Inst>,
+    // 原始寫法，供 explain 顯示
+    source: String,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Char(char),
+    Any,
+    Class {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    Start,
+    End,
+    Concat(Vec<Node>),
+    Alternation(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+    },
+}
+
+// NFA 的指令：Split 的兩個目標都要走，Char、Any、Class 消耗一個字元
+#[derive(Debug, Clone)]
+enum Inst {
+    Char(char),
+    Any,
+    Class {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    Start,
+    End,
+    Split(usize, usize),
+    Jump(usize),
+    Match,
+}
+
+const DIGIT: &[(char, char)] = &[('0', '9')];
+const WORD: &[(char, char)] = &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')];
+const SPACE: &[(char, char)] = &[('\t', '\r'), (' ', ' ')];
+
+impl Pattern {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            chars: source.chars().collect(),
+            pos: 0,
+        };
+        let node = parser.alternation()?;
+        if parser.pos < parser.chars.len() {
+            return Err(format!("多餘的 )（位置 {}）", parser.pos));
+        }
+        let mut program = Vec::new();
+        compile(&node, &mut program);
+        program.push(Inst::Match);
+        Ok(Self {
+            program,
+            source: source.to_string(),
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        let chars: Vec<char> = text.chars().collect();
+        let mut current = Threads::new(self.program.len());
+        let mut next = Threads::new(self.program.len());
+        for pos in 0..=chars.len() {
+            // 搜尋語意：每個位置都可以是比對的開頭
+            if current.add(&self.program, 0, pos, chars.len()) {
+                return true;
+            }
+            let Some(char) = chars.get(pos) else {
+                break;
+            };
+            next.clear();
+            for at in 0..current.list.len() {
+                let pc = current.list[at];
+                let consumed = match &self.program[pc] {
+                    Inst::Char(expected) => expected == char,
+                    Inst::Any => true,
+                    Inst::Class { ranges, negated } => {
+                        ranges
+                            .iter()
+                            .any(|(low, high)| (low..=high).contains(&char))
+                            != *negated
+                    }
+                    _ => false,
+                };
+                if consumed && next.add(&self.program, pc + 1, pos + 1, chars.len()) {
+                    return true;
+                }
+            }
+            mem::swap(&mut current, &mut next);
+        }
+        false
+    }
+}
+
+// 同一個位置上等著消耗字元的狀態；seen 避免同一狀態重複加入，也擋下 (a*)* 這類空迴圈
+struct Threads {
+    list: Vec<usize>,
+    seen: Vec<bool>,
+    stack: Vec<usize>,
+}
+
+impl Threads {
+    fn new(size: usize) -> Self {
+        Self {
+            list: Vec::new(),
+            seen: vec![false; size],
+            stack: Vec::new(),
+        }
+    }
+
+    fn clear(&mut self) {
+        self.seen.fill(false);
+        self.list.clear();
+    }
+
+    // 從 pc 沿著不消耗字元的指令展開（以明確的堆疊，不遞迴）；走到 Match 時回傳 true
+    fn add(&mut self, program: &[Inst], pc: usize, pos: usize, len: usize) -> bool {
+        self.stack.push(pc);
+        while let Some(pc) = self.stack.pop() {
+            if mem::replace(&mut self.seen[pc], true) {
+                continue;
+            }
+            match program[pc] {
+                Inst::Match => {
+                    self.stack.clear();
+                    return true;
+                }
+                Inst::Jump(target) => self.stack.push(target),
+                // 先處理第一個目標，維持貪婪的偏好（只回答是否符合，順序不影響結果）
+                Inst::Split(first, second) => {
+                    self.stack.push(second);
+                    self.stack.push(first);
+                }
+                Inst::Start if pos == 0 => self.stack.push(pc + 1),
+                Inst::End if pos == len => self.stack.push(pc + 1),
+                Inst::Start | Inst::End => {}
+                Inst::Char(_) | Inst::Any | Inst::Class { .. } => self.list.push(pc),
+            }
+        }
+        false
+    }
+}
+
+// Split 的目標在編譯完被跳過的部分後才知道，先放 0 再回填
+fn compile(node: &Node, program: &mut Vec<Inst>) {
+    match node {
+        Node::Char(char) => program.push(Inst::Char(*char)),
+        Node::Any => program.push(Inst::Any),
+        Node::Class { ranges, negated } => program.push(Inst::Class {
+            ranges: ranges.clone(),
+            negated: *negated,
+        }),
+        Node::Start => program.push(Inst::Start),
+        Node::End => program.push(Inst::End),
+        Node::Concat(nodes) => {
+            for node in nodes {
+                compile(node, program);
+            }
+        }
+        Node::Alternation(options) => {
+            let mut jumps = Vec::new();
+            for (index, option) in options.iter().enumerate() {
+                if index + 1 == options.len() {
+                    compile(option, program);
+                    break;
+                }
+                let split = program.len();
+                program.push(Inst::Split(split + 1, 0));
+                compile(option, program);
+                jumps.push(program.len());
+                program.push(Inst::Jump(0));
+                program[split] = Inst::Split(split + 1, program.len());
+            }
+            let end = program.len();
+            for jump in jumps {
+                program[jump] = Inst::Jump(end);
+            }
+        }
+        Node::Repeat { node, min, max } => {
+            for _ in 0..*min {
+                compile(node, program);
+            }
+            match max {
+                None => {
+                    let split = program.len();
+                    program.push(Inst::Split(split + 1, 0));
+                    compile(node, program);
+                    program.push(Inst::Jump(split));
+                    program[split] = Inst::Split(split + 1, program.len());
+                }
+                Some(max) => {
+                    for _ in *min..*max {
+                        let split = program.len();
+                        program.push(Inst::Split(split + 1, 0));
+                        compile(node, program);
+                        program[split] = Inst::Split(split + 1, program.len());
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let char = self.peek()?;
+        self.pos += 1;
+        Some(char)
+    }
+
+    fn alternation(&mut self) -> Result<Node, String> {
+        let mut options = vec![self.concat()?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            options.push(self.concat()?);
+        }
+        Ok(if options.len() == 1 {
+            options.pop().unwrap()
+        } else {
+            Node::Alternation(options)
+        })
+    }
+
+    fn concat(&mut self) -> Result<Node, String> {
+        let mut nodes = Vec::new();
+        while let Some(char) = self.peek() {
+            if char == '|' || char == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantified(atom)?);
+        }
+        Ok(Node::Concat(nodes))
+    }
+
+    fn quantified(&mut self, atom: Node) -> Result<Node, String> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            _ => return Ok(atom),
+        };
+        self.pos += 1;
+        if matches!(self.peek(), Some('*' | '+' | '?' | '{')) {
+            return Err(format!("不支援連續或非貪婪的量詞（位置 {}）", self.pos));
+        }
+        if matches!(atom, Node::Start | Node::End) {
+            return Err("^ 與 $ 不能重複".to_string());
+        }
+        Ok(Node::Repeat {
+            node: Box::new(atom),
+            min,
+            max,
+        })
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        let position = self.pos;
+        match self.bump().unwrap_or_default() {
+            '(' => {
+                let inner = self.alternation()?;
+                if self.bump() != Some(')') {
+                    return Err(format!("缺少 )（位置 {position} 的群組）"));
+                }
+                Ok(inner)
+            }
+            '[' => self.class(),
+            '.' => Ok(Node::Any),
+            '^' => Ok(Node::Start),
+            '$' => Ok(Node::End),
+            '\\' => self.escape(false),
+            '*' | '+' | '?' => Err(format!("量詞前沒有內容（位置 {position}）")),
+            '{' => Err("不支援 {n,m} 重複次數".to_string()),
+            char => Ok(Node::Char(char)),
+        }
+    }
+
+    // \ 之後的字元；在字元類別內只接受會展開成正向範圍的跳脫
+    fn escape(&mut self, in_class: bool) -> Result<Node, String> {
+        let char = self.bump().ok_or("結尾不能是單獨的 \\")?;
+        let class = |ranges: &[(char, char)], negated: bool| Node::Class {
+            ranges: ranges.to_vec(),
+            negated,
+        };
+        Ok(match char {
+            'd' => class(DIGIT, false),
+            'w' => class(WORD, false),
+            's' => class(SPACE, false),
+            'D' | 'W' | 'S' if in_class => {
+                return Err(format!("字元類別內不支援 \\{char}"));
+            }
+            'D' => class(DIGIT, true),
+            'W' => class(WORD, true),
+            'S' => class(SPACE, true),
+            'n' => Node::Char('\n'),
+            't' => Node::Char('\t'),
+            char if char.is_ascii_alphanumeric() => {
+                return Err(format!("不支援的跳脫 \\{char}"));
+            }
+            char => Node::Char(char),
+        })
+    }
+
+    fn class(&mut self) -> Result<Node, String> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let char = self.bump().ok_or("缺少 ]")?;
+            if char == ']' && !first {
+                break;
+            }
+            first = false;
+            let low = match char {
+                '\\' => match self.escape(true)? {
+                    Node::Char(char) => char,
+                    Node::Class { ranges: more, .. } => {
+                        ranges.extend(more);
+                        continue;
+                    }
+                    _ => unreachable!("escape 只回傳字元或字元類別"),
+                },
+                char => char,
+            };
+            // a-z；- 在開頭或結尾時是字面字元
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|c| *c != ']') {
+                self.pos += 1;
+                let high = match self.bump().ok_or("缺少 ]")? {
+                    '\\' => match self.escape(true)? {
+                        Node::Char(char) => char,
+                        _ => return Err("範圍的結尾必須是單一字元".to_string()),
+                    },
+                    char => char,
+                };
+                if high < low {
+                    return Err(format!("無效的範圍 {low}-{high}"));
+                }
+                ranges.push((low, high));
+            } else {
+                ranges.push((low, low));
+            }
+        }
+        Ok(Node::Class { ranges, negated })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn supported_syntax_matches_like_grep() {
+        let cases = [
+            (r"qq\.jar", "/opt/qq/qq.jar", true),
+            (r"qq\.jar", "/opt/qq/qqXjar", false),
+            (r"^-jar$", "-jar", true),
+            (r"^-jar$", "--jar", false),
+            ("qq(-beta)?\\.jar$", "qq-beta.jar", true),
+            ("qq(-beta)?\\.jar$", "qq-beta.jar.bak", false),
+            (r"(linuxqq|qq)\.(jar|bin)", "linuxqq.bin", true),
+            (r"[a-c]+\d*", "xxbca12", true),
+            (r"^[^/]+$", "qq", true),
+            (r"^[^/]+$", "/usr/bin/qq", false),
+            (r"\w+\s\S", "java -jar", true),
+            ("a.*b", "axxxb", true),
+            ("(a*)*b", "aaac", false),
+            ("", "任何內容", true),
+            ("設定檔=\\S+", "--設定檔=/tmp/x", true),
+        ];
+        for (pattern, text, expected) in cases {
+            let compiled = Pattern::parse(pattern).unwrap();
+            assert_eq!(compiled.is_match(text), expected, "{pattern} ~ {text}");
+        }
+        for invalid in [
+            "(qq", "qq)", "a**", "a+?", "*a", "a{2}", "[z-a]", "[abc", r"\b", "\\",
+        ] {
+            assert!(Pattern::parse(invalid).is_err(), "{invalid:?} 應該被拒絕");
+        }
+    }
+
+    #[test]
+    fn long_inputs_do_not_exhaust_the_stack() {
+        // 其他使用者的程序可以有上百 KB 的命令列；比對不隨輸入長度遞迴
+        let long = "a".repeat(150_000);
+        for (pattern, text, expected) in [
+            (".*qq", long.clone(), false),
+            (".*qq", format!("{long}qq"), true),
+            ("sleep.*x", format!("sleep {long}"), false),
+            ("sleep.*x", format!("sleep {long}x"), true),
+            ("^(a|b)+$", long.clone(), true),
+            ("(a*)*c", long.clone(), false),
+        ] {
+            let compiled = Pattern::parse(pattern).unwrap();
+            assert_eq!(compiled.is_match(&text), expected, "{pattern}");
+        }
+    }
+}
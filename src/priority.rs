@@ -0,0 +1,257 @@
+use std::io;
+use std::sync::OnceLock;
+
+// --nice / --ionice：守護程序自身的 CPU 與 I/O 排程優先權。
+// Linux 的 nice 與 I/O 優先權都是每個執行緒各自的屬性，新執行緒與子程序沿用建立者的設定，
+// 因此要在建立掃描執行緒之前、由主執行緒設定
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+// 核心對沒有指定 I/O 優先權的程序視為 best-effort，等級依 nice 換算，預設 4
+const DEFAULT_IO_LEVEL: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoClass {
+    Realtime,
+    BestEffort,
+    Idle,
+}
+
+impl IoClass {
+    fn number(self) -> u32 {
+        match self {
+            IoClass::Realtime => 1,
+            IoClass::BestEffort => 2,
+            IoClass::Idle => 3,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            IoClass::Realtime => "realtime",
+            IoClass::BestEffort => "best-effort",
+            IoClass::Idle => "idle",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoPriority {
+    pub class: IoClass,
+    // idle 沒有等級
+    pub level: Option<u8>,
+}
+
+impl IoPriority {
+    fn value(self) -> libc::c_int {
+        ((self.class.number() << IOPRIO_CLASS_SHIFT) | u32::from(self.level.unwrap_or(0))) as i32
+    }
+
+    pub fn text(self) -> String {
+        match self.level {
+            Some(level) => format!("{}:{level}", self.class.name()),
+            None => self.class.name().to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Priority {
+    pub nice: Option<i32>,
+    pub io: Option<IoPriority>,
+}
+
+impl Priority {
+    pub fn is_set(&self) -> bool {
+        self.nice.is_some() || self.io.is_some()
+    }
+}
+
+// 設定後 spawn 的量測工具（ss、lsof）在 exec 前套用同樣的優先權
+static CONFIGURED: OnceLock<Priority> = OnceLock::new();
+
+pub fn configure(priority: Priority) {
+    let _ = CONFIGURED.set(priority);
+}
+
+pub fn configured() -> Option<Priority> {
+    CONFIGURED.get().copied().filter(Priority::is_set)
+}
+
+pub fn parse_nice(value: &str) -> Result<i32, String> {
+    value
+        .trim()
+        .parse::<i32>()
+        .ok()
+        .filter(|nice| (-20..=19).contains(nice))
+        .ok_or_else(|| format!("必須是 -20 到 19 的整數: {value}"))
+}
+
+// 與 ionice 相同：類別可寫名稱或編號（1 realtime、2 best-effort、3 idle），
+// realtime 與 best-effort 的等級為 0（最高）到 7，省略時為 4；idle 不接受等級
+pub fn parse_ionice(value: &str) -> Result<IoPriority, String> {
+    let (class, level) = match value.trim().split_once(':') {
+        Some((class, level)) => (class, Some(level)),
+        None => (value.trim(), None),
+    };
+    let class = match class {
+        "realtime" | "rt" | "1" => IoClass::Realtime,
+        "best-effort" | "be" | "2" => IoClass::BestEffort,
+        "idle" | "3" => IoClass::Idle,
+        _ => {
+            return Err(format!(
+                "類別只接受 realtime、best-effort、idle（或 1、2、3）: {value}"
+            ))
+        }
+    };
+    let level = match (class, level) {
+        (IoClass::Idle, Some(_)) => return Err(format!("idle 沒有等級: {value}")),
+        (IoClass::Idle, None) => None,
+        (_, None) => Some(DEFAULT_IO_LEVEL),
+        (_, Some(level)) => Some(
+            level
+                .parse::<u8>()
+                .ok()
+                .filter(|level| *level <= 7)
+                .ok_or_else(|| format!("等級必須是 0 到 7: {value}"))?,
+        ),
+    };
+    Ok(IoPriority { class, level })
+}
+
+fn set_nice(nice: i32) -> io::Result<()> {
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn current_nice() -> i32 {
+    unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) }
+}
+
+fn set_io(io: IoPriority) -> io::Result<()> {
+    let result = unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, io.value()) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn current_io() -> Option<IoPriority> {
+    let value = unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, 0) };
+    if value < 0 {
+        return None;
+    }
+    let level = (value & ((1 << IOPRIO_CLASS_SHIFT) - 1)) as u8;
+    match value >> IOPRIO_CLASS_SHIFT {
+        1 => Some(IoPriority {
+            class: IoClass::Realtime,
+            level: Some(level),
+        }),
+        2 => Some(IoPriority {
+            class: IoClass::BestEffort,
+            level: Some(level),
+        }),
+        3 => Some(IoPriority {
+            class: IoClass::Idle,
+            level: None,
+        }),
+        // IOPRIO_CLASS_NONE：未設定，依 nice 換算成 best-effort
+        _ => None,
+    }
+}
+
+// 套用到呼叫的執行緒；權限不足時保留原值並回傳警告，不中止啟動。
+// 降低優先權（nice 調高、I/O idle）一律允許，提高則需要 CAP_SYS_NICE，realtime I/O 需要 CAP_SYS_ADMIN
+pub fn apply(priority: Priority) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if let Some(nice) = priority.nice {
+        if let Err(error) = set_nice(nice) {
+            warnings.push(format!(
+                "無法把 nice 設為 {nice}（{error}）：提高優先權（nice 小於目前的 {}）需要 root 或 CAP_SYS_NICE，維持原值",
+                current_nice()
+            ));
+        }
+    }
+    if let Some(io) = priority.io {
+        if let Err(error) = set_io(io) {
+            let needed = if io.class == IoClass::Realtime {
+                "realtime 類別需要 root 或 CAP_SYS_ADMIN"
+            } else {
+                "提高 I/O 優先權需要 root 或 CAP_SYS_NICE"
+            };
+            warnings.push(format!(
+                "無法把 I/O 優先權設為 {}（{error}）：{needed}，維持原值",
+                io.text()
+            ));
+        }
+    }
+    warnings
+}
+
+// 子程序在 exec 前呼叫：只用 syscall，不配置記憶體；失敗就沿用 helper 的優先權
+pub fn apply_in_child(priority: Priority) {
+    if let Some(nice) = priority.nice {
+        let _ = set_nice(nice);
+    }
+    if let Some(io) = priority.io {
+        let _ = set_io(io);
+    }
+}
+
+// 實際生效的值，寫入啟動日誌
+pub fn current_text() -> String {
+    let io = current_io().map_or("未設定（依 nice 換算）".to_string(), IoPriority::text);
+    format!("nice {}，I/O {io}", current_nice())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ionice_accepts_names_numbers_and_optional_level() {
+        let idle = IoPriority {
+            class: IoClass::Idle,
+            level: None,
+        };
+        assert_eq!(parse_ionice("idle"), Ok(idle));
+        assert_eq!(parse_ionice("3"), Ok(idle));
+        assert_eq!(
+            parse_ionice("best-effort:7").map(IoPriority::text),
+            Ok("best-effort:7".to_string())
+        );
+        assert_eq!(
+            parse_ionice("be").map(IoPriority::text),
+            Ok("best-effort:4".to_string())
+        );
+        assert_eq!(parse_ionice("1:0").map(IoPriority::value), Ok(1 << 13));
+        for value in ["idle:3", "be:8", "be:-1", "fast:1", ""] {
+            assert!(parse_ionice(value).is_err(), "{value:?} 應該被拒絕");
+        }
+        assert_eq!(parse_nice("19"), Ok(19));
+        assert_eq!(parse_nice("-20"), Ok(-20));
+        assert!(parse_nice("20").is_err());
+        assert!(parse_nice("ten").is_err());
+    }
+
+    #[test]
+    fn lowering_priority_needs_no_privilege() {
+        // 在另一個執行緒設定，不影響其他測試
+        let (nice, io) = std::thread::spawn(|| {
+            let warnings = apply(Priority {
+                nice: Some(19),
+                io: Some(IoPriority {
+                    class: IoClass::Idle,
+                    level: None,
+                }),
+            });
+            assert!(warnings.is_empty(), "{warnings:?}");
+            (current_nice(), current_io())
+        })
+        .join()
+        .unwrap();
+        assert_eq!(nice, 19);
+        assert_eq!(io.map(|io| io.class), Some(IoClass::Idle));
+    }
+}
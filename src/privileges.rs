@@ -0,0 +1,197 @@
+use std::ffi::{CStr, CString};
+use std::io;
+use std::ptr;
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+const CAP_DAC_READ_SEARCH: u32 = 2;
+const CAP_KILL: u32 = 5;
+const CAP_SYS_PTRACE: u32 = 19;
+
+// 降權後仍需要的能力：讀其他使用者的 /proc/<pid>/fd、對其程序送訊號
+pub const RETAINED_CAPS: [(u32, &str); 3] = [
+    (CAP_KILL, "CAP_KILL"),
+    (CAP_SYS_PTRACE, "CAP_SYS_PTRACE"),
+    (CAP_DAC_READ_SEARCH, "CAP_DAC_READ_SEARCH"),
+];
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetUser {
+    pub name: String,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+pub fn lookup_user(name: &str) -> Result<TargetUser, String> {
+    let c_name = CString::new(name).map_err(|_| format!("無效的使用者名稱: {name}"))?;
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 16384];
+    let mut result: *mut libc::passwd = ptr::null_mut();
+    let code = unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if code != 0 || result.is_null() {
+        return Err(format!("找不到使用者: {name}"));
+    }
+    let resolved = unsafe { CStr::from_ptr(passwd.pw_name) }
+        .to_string_lossy()
+        .into_owned();
+    Ok(TargetUser {
+        name: resolved,
+        uid: passwd.pw_uid,
+        gid: passwd.pw_gid,
+    })
+}
+
+// 目前 uid 的使用者名稱，generate-unit 產生系統服務時填入 User=
+pub fn current_user_name() -> Option<String> {
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 16384];
+    let mut result: *mut libc::passwd = ptr::null_mut();
+    let code = unsafe {
+        libc::getpwuid_r(
+            libc::getuid(),
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if code != 0 || result.is_null() {
+        return None;
+    }
+    Some(
+        unsafe { CStr::from_ptr(passwd.pw_name) }
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
+
+pub fn drop_to(user: &TargetUser) -> Result<(), String> {
+    if unsafe { libc::geteuid() } != 0 {
+        return Err("--drop-privileges 需要以 root 啟動".to_string());
+    }
+    let c_name = CString::new(user.name.as_str()).map_err(|_| "無效的使用者名稱".to_string())?;
+    unsafe {
+        if libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) != 0 {
+            return Err(last_error("PR_SET_KEEPCAPS"));
+        }
+        if libc::initgroups(c_name.as_ptr(), user.gid) != 0 {
+            return Err(last_error("initgroups"));
+        }
+        if libc::setresgid(user.gid, user.gid, user.gid) != 0 {
+            return Err(last_error("setresgid"));
+        }
+        if libc::setresuid(user.uid, user.uid, user.uid) != 0 {
+            return Err(last_error("setresuid"));
+        }
+        if libc::prctl(libc::PR_SET_KEEPCAPS, 0, 0, 0, 0) != 0 {
+            return Err(last_error("PR_SET_KEEPCAPS"));
+        }
+    }
+    set_capabilities(&RETAINED_CAPS.map(|(cap, _)| cap))?;
+    if unsafe { libc::setuid(0) } == 0 {
+        return Err("降權後仍可切回 root，已中止".to_string());
+    }
+    Ok(())
+}
+
+fn set_capabilities(caps: &[u32]) -> Result<(), String> {
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    for cap in caps {
+        let slot = &mut data[(*cap / 32) as usize];
+        slot.effective |= 1 << (cap % 32);
+        slot.permitted |= 1 << (cap % 32);
+    }
+    let result = unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_mut_ptr()) };
+    if result != 0 {
+        return Err(last_error("capset"));
+    }
+    Ok(())
+}
+
+fn last_error(call: &str) -> String {
+    format!("{call} 失敗: {}", io::Error::last_os_error())
+}
+
+pub fn is_permission_error(error: &io::Error) -> bool {
+    matches!(error.raw_os_error(), Some(code) if code == libc::EACCES || code == libc::EPERM)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process::Command;
+
+    const CHILD_ENV: &str = "QQ_GUARD_DROP_PRIVILEGES_CHILD";
+
+    fn status_field(status: &str, key: &str) -> String {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(key))
+            .unwrap_or("")
+            .trim()
+            .to_string()
+    }
+
+    // 降權會影響整個程序，因此在獨立的子程序中執行本測試本身
+    #[test]
+    fn drop_privileges_keeps_only_retained_caps() {
+        if env::var(CHILD_ENV).is_ok() {
+            let user = lookup_user("nobody").expect("需要 nobody 使用者");
+            drop_to(&user).expect("降權成功");
+            let status = fs::read_to_string("/proc/thread-self/status").expect("讀取 status");
+            let expected_uid = user.uid.to_string();
+            assert!(status_field(&status, "Uid:")
+                .split_whitespace()
+                .all(|uid| uid == expected_uid));
+            let expected_caps: u64 = RETAINED_CAPS.iter().map(|(cap, _)| 1u64 << cap).sum();
+            let cap_eff = u64::from_str_radix(&status_field(&status, "CapEff:"), 16).unwrap();
+            let cap_prm = u64::from_str_radix(&status_field(&status, "CapPrm:"), 16).unwrap();
+            assert_eq!(cap_eff, expected_caps);
+            assert_eq!(cap_prm, expected_caps);
+            return;
+        }
+        if unsafe { libc::geteuid() } != 0 || lookup_user("nobody").is_err() {
+            eprintln!("略過：需要 root 與 nobody 使用者");
+            return;
+        }
+        let output = Command::new(env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "privileges::tests::drop_privileges_keeps_only_retained_caps",
+                "--test-threads=1",
+            ])
+            .env(CHILD_ENV, "1")
+            .output()
+            .expect("啟動子程序");
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{stdout}");
+        assert!(stdout.contains("1 passed"), "{stdout}");
+    }
+}
@@ -0,0 +1,483 @@
+use crate::{parse_duration_secs, parse_notifiers, LogLevel, Notifier, Urgency};
+
+// --rule：以多個指標組合的觸發條件，例如「x11 > 10 and rss > 1.5G」「fd > 500 or churn > 50/min」。
+// 每次量測後由 check_threshold 評估；--threshold 本身也是一條只看 x11 的規則（名稱 threshold），
+// 與其他規則走同一條評估路徑，只是超標時的處理（連續超標、期限、警示等級）維持原本的流程
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    // 計入門檻的 X11 連線數（已套用 --min-connection-age）
+    X11,
+    // 所有程序的 fd 數總和
+    Fd,
+    // 所有程序的 RSS 總和（bytes）
+    Rss,
+    // 連線開啟或關閉較多的一方，每分鐘條數；資料未滿一分鐘時視為未知
+    Churn,
+}
+
+impl Metric {
+    fn parse(word: &str) -> Option<Self> {
+        match word.to_ascii_lowercase().as_str() {
+            "x11" => Some(Metric::X11),
+            "fd" => Some(Metric::Fd),
+            "rss" => Some(Metric::Rss),
+            "churn" => Some(Metric::Churn),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Metric::X11 => "x11",
+            Metric::Fd => "fd",
+            Metric::Rss => "rss",
+            Metric::Churn => "churn",
+        }
+    }
+}
+
+// 一次量測的指標值；None 表示這次沒有取得（沒有規則用到，或資料不足），比較一律不成立
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Metrics {
+    pub x11: usize,
+    pub fd: Option<u64>,
+    pub rss: Option<u64>,
+    pub churn: Option<u64>,
+}
+
+impl Metrics {
+    fn get(&self, metric: Metric) -> Option<u64> {
+        match metric {
+            Metric::X11 => Some(self.x11 as u64),
+            Metric::Fd => self.fd,
+            Metric::Rss => self.rss,
+            Metric::Churn => self.churn,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compare {
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Equal,
+    NotEqual,
+}
+
+impl Compare {
+    fn parse(token: &str) -> Option<Self> {
+        match token {
+            ">" => Some(Compare::Greater),
+            ">=" => Some(Compare::GreaterEqual),
+            "<" => Some(Compare::Less),
+            "<=" => Some(Compare::LessEqual),
+            "==" => Some(Compare::Equal),
+            "!=" => Some(Compare::NotEqual),
+            _ => None,
+        }
+    }
+
+    fn apply(self, left: f64, right: f64) -> bool {
+        match self {
+            Compare::Greater => left > right,
+            Compare::GreaterEqual => left >= right,
+            Compare::Less => left < right,
+            Compare::LessEqual => left <= right,
+            Compare::Equal => left == right,
+            Compare::NotEqual => left != right,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Compare(Metric, Compare, f64),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn eval(&self, metrics: &Metrics) -> bool {
+        match self {
+            Expr::Compare(metric, compare, value) => metrics
+                .get(*metric)
+                .is_some_and(|actual| compare.apply(actual as f64, *value)),
+            Expr::And(left, right) => left.eval(metrics) && right.eval(metrics),
+            Expr::Or(left, right) => left.eval(metrics) || right.eval(metrics),
+        }
+    }
+
+    fn collect_metrics(&self, metrics: &mut Vec<Metric>) {
+        match self {
+            Expr::Compare(metric, _, _) => {
+                if !metrics.contains(metric) {
+                    metrics.push(*metric);
+                }
+            }
+            Expr::And(left, right) | Expr::Or(left, right) => {
+                left.collect_metrics(metrics);
+                right.collect_metrics(metrics);
+            }
+        }
+    }
+}
+
+// 運算子與括號各自成一個 token，其餘連續的英數字（含 . 與 /，例如 1.5G、50/min）成一個字
+fn tokenize(text: &str) -> Result<Vec<String>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut index = 0;
+    while index < chars.len() {
+        let char = chars[index];
+        let pair: String = chars[index..chars.len().min(index + 2)].iter().collect();
+        if char.is_whitespace() {
+            index += 1;
+        } else if matches!(pair.as_str(), ">=" | "<=" | "==" | "!=" | "&&" | "||") {
+            tokens.push(pair);
+            index += 2;
+        } else if matches!(char, '>' | '<' | '(' | ')') {
+            tokens.push(char.to_string());
+            index += 1;
+        } else if char.is_ascii_alphanumeric() || matches!(char, '.' | '_' | '/') {
+            let start = index;
+            while index < chars.len()
+                && (chars[index].is_ascii_alphanumeric() || matches!(chars[index], '.' | '_' | '/'))
+            {
+                index += 1;
+            }
+            tokens.push(chars[start..index].iter().collect());
+        } else {
+            return Err(format!("無法辨識的字元 {char}"));
+        }
+    }
+    Ok(tokens)
+}
+
+// 數值可帶 K、M、G（1024 進位，用於 rss）與 /min（用於 churn，只是標示單位）
+fn parse_value(word: &str) -> Option<f64> {
+    let word = word.strip_suffix("/min").unwrap_or(word);
+    let (number, scale) = match word.char_indices().last()? {
+        (index, 'k' | 'K') => (&word[..index], 1024.0),
+        (index, 'm' | 'M') => (&word[..index], 1024.0 * 1024.0),
+        (index, 'g' | 'G') => (&word[..index], 1024.0 * 1024.0 * 1024.0),
+        _ => (word, 1.0),
+    };
+    let value = number.parse::<f64>().ok()?;
+    (value.is_finite() && value >= 0.0).then_some(value * scale)
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.position).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Result<String, String> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or("運算式不完整")?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn peek_keyword(&self, symbol: &str, word: &str) -> bool {
+        self.peek()
+            .is_some_and(|token| token == symbol || token.eq_ignore_ascii_case(word))
+    }
+
+    // or 的優先順序低於 and，與一般程式語言相同
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.peek_keyword("||", "or") {
+            self.position += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.primary()?;
+        while self.peek_keyword("&&", "and") {
+            self.position += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.primary()?));
+        }
+        Ok(expr)
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let token = self.next()?;
+        if token == "(" {
+            let expr = self.or()?;
+            return match self.next() {
+                Ok(close) if close == ")" => Ok(expr),
+                _ => Err("括號不成對".to_string()),
+            };
+        }
+        let metric = Metric::parse(&token)
+            .ok_or_else(|| format!("不支援的指標 {token}（可用 x11、fd、rss、churn）"))?;
+        let operator = self.next()?;
+        let compare = Compare::parse(&operator)
+            .ok_or_else(|| format!("{token} 之後需要比較運算子，而不是 {operator}"))?;
+        let value = self.next()?;
+        let number = parse_value(&value).ok_or_else(|| format!("數值格式錯誤 {value}"))?;
+        Ok(Expr::Compare(metric, compare, number))
+    }
+}
+
+fn parse_expression(text: &str) -> Result<Expr, String> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        position: 0,
+    };
+    let expr = parser.or()?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(token) => Err(format!("多餘的 {token}")),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleAction {
+    // 每次成立時記錄並通知（受冷卻時間限制）
+    Alert,
+    // 與超過 --threshold 相同，執行 --action 指定的動作，一樣受冷卻、熔斷與策略命令限制
+    Restart,
+    // 成立期間視同一個警示等級：進入與解除時各記錄一次，進入時通知
+    Escalate,
+}
+
+impl RuleAction {
+    pub fn name(self) -> &'static str {
+        match self {
+            RuleAction::Alert => "alert",
+            RuleAction::Restart => "restart",
+            RuleAction::Escalate => "escalate",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub name: String,
+    // 原始的運算式文字，寫進日誌與通知
+    pub expression: String,
+    condition: Expr,
+    pub action: RuleAction,
+    // 同一條規則兩次觸發的最短間隔；None 表示沿用 --cooldown
+    pub cooldown_seconds: Option<u64>,
+    pub log_level: LogLevel,
+    pub notifiers: Vec<Notifier>,
+    pub urgency: Urgency,
+}
+
+pub const THRESHOLD_RULE: &str = "threshold";
+
+impl Rule {
+    // --threshold 對應的規則
+    pub fn threshold(threshold: usize) -> Self {
+        let expression = format!("x11 > {threshold}");
+        Self {
+            name: THRESHOLD_RULE.to_string(),
+            condition: Expr::Compare(Metric::X11, Compare::Greater, threshold as f64),
+            expression,
+            action: RuleAction::Restart,
+            cooldown_seconds: None,
+            log_level: LogLevel::Warn,
+            notifiers: Vec::new(),
+            urgency: Urgency::Normal,
+        }
+    }
+
+    pub fn matches(&self, metrics: &Metrics) -> bool {
+        self.condition.eval(metrics)
+    }
+
+    pub fn metrics(&self) -> Vec<Metric> {
+        let mut metrics = Vec::new();
+        self.condition.collect_metrics(&mut metrics);
+        metrics
+    }
+
+    pub fn uses(&self, metric: Metric) -> bool {
+        self.metrics().contains(&metric)
+    }
+
+    // 規則用到的指標目前的值，例如「x11 12、rss 1.6G」
+    pub fn values_text(&self, metrics: &Metrics) -> String {
+        self.metrics()
+            .into_iter()
+            .map(|metric| {
+                let value = match (metric, metrics.get(metric)) {
+                    (_, None) => "未知".to_string(),
+                    (Metric::Rss, Some(bytes)) => format_bytes(bytes),
+                    (Metric::Churn, Some(rate)) => format!("{rate}/min"),
+                    (_, Some(value)) => value.to_string(),
+                };
+                format!("{} {value}", metric.name())
+            })
+            .collect::<Vec<_>>()
+            .join("、")
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [(&str, f64); 3] = [
+        ("G", 1024.0 * 1024.0 * 1024.0),
+        ("M", 1024.0 * 1024.0),
+        ("K", 1024.0),
+    ];
+    for (unit, size) in UNITS {
+        if bytes as f64 >= size {
+            return format!("{:.1}{unit}", bytes as f64 / size);
+        }
+    }
+    bytes.to_string()
+}
+
+// <名稱>:<運算式>:<動作>[,<選項>...]；運算式錯誤時附上整段運算式
+pub fn parse_rule(spec: &str) -> Result<Rule, String> {
+    let mut parts = spec.splitn(3, ':');
+    let name = parts.next().unwrap_or("").trim();
+    if name.is_empty() {
+        return Err(format!("--rule 缺少規則名稱: {spec}"));
+    }
+    if name == THRESHOLD_RULE {
+        return Err(format!(
+            "--rule 名稱 {THRESHOLD_RULE} 保留給 --threshold: {spec}"
+        ));
+    }
+    let expression = parts
+        .next()
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+        .ok_or_else(|| format!("--rule 缺少運算式: {spec}"))?;
+    let condition = parse_expression(expression)
+        .map_err(|err| format!("--rule {name} 的運算式「{expression}」有誤：{err}"))?;
+    let mut options = parts.next().unwrap_or("").split(',');
+    let action = match options.next().unwrap_or("").trim() {
+        "alert" => RuleAction::Alert,
+        "restart" => RuleAction::Restart,
+        "escalate" => RuleAction::Escalate,
+        "" => {
+            return Err(format!(
+                "--rule 缺少動作（alert、restart 或 escalate）: {spec}"
+            ))
+        }
+        other => return Err(format!("--rule 不支援的動作 {other}: {spec}")),
+    };
+    let mut rule = Rule {
+        name: name.to_string(),
+        expression: expression.to_string(),
+        condition,
+        action,
+        cooldown_seconds: None,
+        log_level: LogLevel::Warn,
+        notifiers: Vec::new(),
+        urgency: Urgency::Normal,
+    };
+    for option in options.filter(|item| !item.is_empty()) {
+        match option.split_once('=') {
+            Some(("cooldown", value)) => {
+                rule.cooldown_seconds = Some(
+                    parse_duration_secs(value)
+                        .map_err(|err| format!("--rule cooldown {err}: {spec}"))?,
+                );
+            }
+            Some(("log", "info")) => rule.log_level = LogLevel::Info,
+            Some(("log", "warn")) => rule.log_level = LogLevel::Warn,
+            Some(("log", "error")) => rule.log_level = LogLevel::Error,
+            Some(("urgency", "low")) => rule.urgency = Urgency::Low,
+            Some(("urgency", "normal")) => rule.urgency = Urgency::Normal,
+            Some(("urgency", "critical")) => rule.urgency = Urgency::Critical,
+            Some(("notify", value)) => {
+                rule.notifiers =
+                    parse_notifiers(value).map_err(|err| format!("--rule {err}: {spec}"))?;
+            }
+            _ => return Err(format!("--rule 不支援的選項 {option}: {spec}")),
+        }
+    }
+    Ok(rule)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expressions_combine_metrics_with_and_or() {
+        let rule = parse_rule("mem:x11 > 10 AND rss > 1.5G:restart").unwrap();
+        assert_eq!(rule.action, RuleAction::Restart);
+        assert_eq!(rule.metrics(), vec![Metric::X11, Metric::Rss]);
+        let mut metrics = Metrics {
+            x11: 11,
+            rss: Some(1024 * 1024 * 1024),
+            ..Metrics::default()
+        };
+        assert!(!rule.matches(&metrics));
+        metrics.rss = Some(2 * 1024 * 1024 * 1024);
+        assert!(rule.matches(&metrics));
+        assert_eq!(rule.values_text(&metrics), "x11 11、rss 2.0G");
+
+        // and 先於 or；未取得的指標比較一律不成立
+        let rule =
+            parse_rule("busy:fd>500 || churn > 50/min and x11 >= 1:alert,cooldown=5m").unwrap();
+        assert_eq!(rule.cooldown_seconds, Some(300));
+        let metrics = Metrics {
+            x11: 0,
+            fd: Some(501),
+            ..Metrics::default()
+        };
+        assert!(rule.matches(&metrics));
+        let metrics = Metrics {
+            x11: 3,
+            fd: Some(10),
+            churn: None,
+            ..Metrics::default()
+        };
+        assert!(!rule.matches(&metrics));
+        let rule = parse_rule("p:(fd > 1 or rss > 1) and x11 == 0:escalate").unwrap();
+        assert!(!rule.matches(&Metrics {
+            x11: 1,
+            fd: Some(2),
+            ..Metrics::default()
+        }));
+
+        assert!(Rule::threshold(10).matches(&Metrics {
+            x11: 11,
+            ..Metrics::default()
+        }));
+        assert!(!Rule::threshold(10).matches(&Metrics {
+            x11: 10,
+            ..Metrics::default()
+        }));
+    }
+
+    #[test]
+    fn parse_errors_quote_the_expression() {
+        let error = parse_rule("bad:x11 > 10 and vmem > 1G:restart").unwrap_err();
+        assert!(error.contains("「x11 > 10 and vmem > 1G」"), "{error}");
+        assert!(error.contains("vmem"), "{error}");
+        for spec in [
+            "bad:x11 > :alert",
+            "bad:x11 10:alert",
+            "bad:(x11 > 1:alert",
+            "bad:x11 > 1 rss:alert",
+            "bad:x11 > 1.5X:alert",
+        ] {
+            let error = parse_rule(spec).unwrap_err();
+            assert!(error.contains("運算式「"), "{spec}: {error}");
+        }
+        assert!(parse_rule("bad:x11 > 1").is_err());
+        assert!(parse_rule("bad:x11 > 1:reboot").is_err());
+        assert!(parse_rule("threshold:x11 > 1:alert").is_err());
+        assert!(parse_rule("ok:x11 > 1:alert,bogus=1").is_err());
+    }
+}
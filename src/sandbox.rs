@@ -0,0 +1,744 @@
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::sync::{Mutex, TryLockError};
+use std::time::{Duration, Instant};
+use std::{fs, ptr, thread};
+
+// seccomp filter 會隨 execve 繼承，若直接在受限程序內 spawn，重啟的 QQ 與 ss 也會被套上同一份 filter。
+// 因此在安裝 filter 前先 fork 一個不受限的 helper，只代為執行白名單內的命令。
+// --nice / --ionice 同樣會被繼承且無法由一般使用者調回，沒有 seccomp 時也先 fork helper 再降低優先權，不限制命令。
+static HELPER: Mutex<Option<UnixStream>> = Mutex::new(None);
+
+// 在本程序等待 run_output 的命令時持有：分別監控多個程式時，其他執行緒回收殭屍的 waitpid(-1)
+// 可能搶先回收這個子程序，讓等待失敗
+static WAITING: Mutex<()> = Mutex::new(());
+
+const REQUEST_SPAWN: u8 = 0;
+const REQUEST_OUTPUT: u8 = 1;
+
+// REQUEST_OUTPUT 回覆的第一個 byte
+const OUTPUT_FAILED: u8 = 0;
+const OUTPUT_SUCCEEDED: u8 = 1;
+const OUTPUT_TIMED_OUT: u8 = 2;
+// 帶時限的命令最多讀取的 stdout
+const TIMED_OUTPUT_LIMIT: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandSpec {
+    pub program: String,
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+    // 寫入 stdin 的內容與執行時限；只用於 run_output
+    pub input: Option<String>,
+    pub timeout: Option<Duration>,
+    // 量測工具：exec 前套用 --nice / --ionice；重啟與通知命令維持 helper 原本的優先權
+    pub lowered: bool,
+}
+
+impl CommandSpec {
+    pub fn new(program: &str) -> Self {
+        Self {
+            program: program.to_string(),
+            args: Vec::new(),
+            env: Vec::new(),
+            input: None,
+            timeout: None,
+            lowered: false,
+        }
+    }
+
+    pub fn arg(mut self, value: &str) -> Self {
+        self.args.push(value.to_string());
+        self
+    }
+
+    pub fn env(mut self, key: &str, value: &str) -> Self {
+        self.env.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn input(mut self, text: &str) -> Self {
+        self.input = Some(text.to_string());
+        self
+    }
+
+    pub fn timeout(mut self, limit: Duration) -> Self {
+        self.timeout = Some(limit);
+        self
+    }
+
+    #[cfg(any(feature = "backend-ss", feature = "backend-lsof"))]
+    pub fn lowered(mut self) -> Self {
+        self.lowered = true;
+        self
+    }
+
+    pub fn argv(&self) -> Vec<String> {
+        [self.program.clone()]
+            .into_iter()
+            .chain(self.args.iter().cloned())
+            .collect()
+    }
+
+    fn to_command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args);
+        for (key, value) in &self.env {
+            command.env(key, value);
+        }
+        command.stdin(Stdio::null()).stderr(Stdio::null());
+        if let Some(priority) = crate::priority::configured().filter(|_| self.lowered) {
+            unsafe {
+                command.pre_exec(move || {
+                    crate::priority::apply_in_child(priority);
+                    Ok(())
+                });
+            }
+        }
+        command
+    }
+}
+
+// 回傳新程序的 PID，供重啟後追查它帶起的程序樹
+pub fn spawn_detached(spec: &CommandSpec) -> io::Result<u32> {
+    if let Some(stream) = HELPER
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .as_mut()
+    {
+        let body = request(stream, REQUEST_SPAWN, spec)?;
+        let bytes: [u8; 4] = body
+            .get(..4)
+            .and_then(|value| value.try_into().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "helper 未回傳 PID"))?;
+        return Ok(u32::from_le_bytes(bytes));
+    }
+    spec.to_command()
+        .stdout(Stdio::null())
+        .spawn()
+        .map(|child| child.id())
+}
+
+// 指定 timeout 時逾時回傳 ErrorKind::TimedOut
+pub fn run_output(spec: &CommandSpec) -> io::Result<(bool, Vec<u8>)> {
+    if let Some(stream) = HELPER
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .as_mut()
+    {
+        let response = request(stream, REQUEST_OUTPUT, spec)?;
+        let (status, stdout) = response.split_first().unwrap_or((&OUTPUT_FAILED, &[]));
+        if *status == OUTPUT_TIMED_OUT {
+            return Err(timed_out(spec));
+        }
+        return Ok((*status == OUTPUT_SUCCEEDED, stdout.to_vec()));
+    }
+    let _waiting = WAITING.lock().unwrap_or_else(|err| err.into_inner());
+    if spec.input.is_some() || spec.timeout.is_some() {
+        return run_timed(spec);
+    }
+    let output = spec.to_command().output()?;
+    Ok((output.status.success(), output.stdout))
+}
+
+// 回收 spawn_detached 帶起、已結束的程序；有命令正在等待時留到下一次
+pub fn reap_children() {
+    let _waiting = match WAITING.try_lock() {
+        Ok(guard) => guard,
+        Err(TryLockError::Poisoned(err)) => err.into_inner(),
+        Err(TryLockError::WouldBlock) => return,
+    };
+    while unsafe { libc::waitpid(-1, ptr::null_mut(), libc::WNOHANG) } > 0 {}
+}
+
+// 帶 stdin 與時限的命令：在自己的程序群組中執行，結束或逾時後對整個群組送 SIGKILL，
+// 留在背景的子程序不會佔住 stdout 讓讀取卡住
+fn run_timed(spec: &CommandSpec) -> io::Result<(bool, Vec<u8>)> {
+    let mut child = spec
+        .to_command()
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .process_group(0)
+        .spawn()?;
+    let group = -(child.id() as i32);
+    let input = spec.input.clone().unwrap_or_default();
+    let stdin = child.stdin.take();
+    // 程式不讀 stdin 時寫入會卡住，交給執行緒，程序結束後會收到 EPIPE
+    let writer = thread::spawn(move || {
+        if let Some(mut stdin) = stdin {
+            let _ = stdin.write_all(input.as_bytes());
+        }
+    });
+    let stdout = child.stdout.take();
+    let reader = thread::spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(stdout) = stdout {
+            let _ = stdout.take(TIMED_OUTPUT_LIMIT).read_to_end(&mut buffer);
+        }
+        buffer
+    });
+    let deadline = spec.timeout.map(|limit| Instant::now() + limit);
+    let finished = loop {
+        if exited_unreaped(child.id()) {
+            break true;
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            break false;
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    // 領頭程序回收前群組編號不會被重用，這時送訊號不會誤傷其他程序
+    unsafe {
+        libc::kill(group, libc::SIGKILL);
+    }
+    let status = child.wait()?;
+    let _ = writer.join();
+    let stdout = reader.join().unwrap_or_default();
+    if !finished {
+        return Err(timed_out(spec));
+    }
+    Ok((status.success(), stdout))
+}
+
+// 以 WNOWAIT 查詢，結束的程序仍保留為殭屍
+fn exited_unreaped(pid: u32) -> bool {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let result = unsafe {
+        libc::waitid(
+            libc::P_PID,
+            pid,
+            &mut info,
+            libc::WEXITED | libc::WNOHANG | libc::WNOWAIT,
+        )
+    };
+    result != 0 || unsafe { info.si_pid() } != 0
+}
+
+fn timed_out(spec: &CommandSpec) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!(
+            "{} 秒內未結束",
+            spec.timeout.unwrap_or_default().as_secs_f64()
+        ),
+    )
+}
+
+// ===== helper 程序 =====
+#[derive(Debug, Clone, Default)]
+pub struct HelperPolicy {
+    // 使用者命令只允許由樣板（sh -lc <命令> 或切開後的命令）代入佔位符而來的 argv，見 template::matches；programs 不限參數
+    pub commands: Vec<Vec<String>>,
+    pub programs: Vec<String>,
+    // 只為了保留原本的優先權而啟動（沒有 seccomp）時不限制命令
+    pub unrestricted: bool,
+}
+
+impl HelperPolicy {
+    fn permits(&self, spec: &CommandSpec) -> bool {
+        if self.unrestricted {
+            return true;
+        }
+        let argv = spec.argv();
+        let listed = self
+            .commands
+            .iter()
+            .any(|template| crate::template::matches(template, &argv));
+        if spec.program == "sh" {
+            return listed;
+        }
+        self.programs.contains(&spec.program) || listed
+    }
+}
+
+pub fn start_helper(policy: HelperPolicy) -> Result<(), String> {
+    let (parent_end, child_end) =
+        UnixStream::pair().map_err(|err| format!("無法建立 helper 通道: {err}"))?;
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        return Err(format!("無法 fork helper: {}", io::Error::last_os_error()));
+    }
+    if pid == 0 {
+        drop(parent_end);
+        helper_main(child_end, policy);
+    }
+    drop(child_end);
+    *HELPER.lock().unwrap_or_else(|err| err.into_inner()) = Some(parent_end);
+    Ok(())
+}
+
+fn helper_main(mut stream: UnixStream, policy: HelperPolicy) -> ! {
+    unsafe {
+        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM);
+    }
+    close_inherited_fds(stream.as_raw_fd());
+
+    loop {
+        reap_helper_children();
+        let mut poll_fd = libc::pollfd {
+            fd: stream.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut poll_fd, 1, 1000) } <= 0 {
+            continue;
+        }
+        let frame = match read_frame(&mut stream) {
+            Ok(Some(value)) => value,
+            _ => std::process::exit(0),
+        };
+        let response = match decode_request(&frame) {
+            Some((kind, spec)) if policy.permits(&spec) => execute(kind, &spec),
+            Some((_, spec)) => Err(format!("helper 拒絕執行未授權命令: {}", spec.program)),
+            None => Err("helper 收到無效請求".to_string()),
+        };
+        let mut payload = Vec::new();
+        match response {
+            Ok(body) => {
+                payload.push(0);
+                payload.extend(body);
+            }
+            Err(message) => {
+                payload.push(1);
+                payload.extend(message.into_bytes());
+            }
+        }
+        if write_frame(&mut stream, &payload).is_err() {
+            std::process::exit(0);
+        }
+    }
+}
+
+fn execute(kind: u8, spec: &CommandSpec) -> Result<Vec<u8>, String> {
+    if kind == REQUEST_SPAWN {
+        return spec
+            .to_command()
+            .stdout(Stdio::null())
+            .spawn()
+            .map(|child| child.id().to_le_bytes().to_vec())
+            .map_err(|err| err.to_string());
+    }
+    let result = if spec.input.is_some() || spec.timeout.is_some() {
+        run_timed(spec)
+    } else {
+        spec.to_command()
+            .output()
+            .map(|output| (output.status.success(), output.stdout))
+    };
+    match result {
+        Ok((success, stdout)) => {
+            let status = if success {
+                OUTPUT_SUCCEEDED
+            } else {
+                OUTPUT_FAILED
+            };
+            Ok([status].into_iter().chain(stdout).collect())
+        }
+        Err(err) if err.kind() == io::ErrorKind::TimedOut => Ok(vec![OUTPUT_TIMED_OUT]),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn close_inherited_fds(keep: RawFd) {
+    let fds: Vec<RawFd> = match fs::read_dir("/proc/self/fd") {
+        Ok(entries) => entries
+            .flatten()
+            .filter_map(|entry| entry.file_name().to_string_lossy().parse().ok())
+            .collect(),
+        Err(_) => return,
+    };
+    for fd in fds {
+        if fd > 2 && fd != keep {
+            unsafe {
+                libc::close(fd);
+            }
+        }
+    }
+}
+
+fn reap_helper_children() {
+    while unsafe { libc::waitpid(-1, ptr::null_mut(), libc::WNOHANG) } > 0 {}
+}
+
+fn request(stream: &mut UnixStream, kind: u8, spec: &CommandSpec) -> io::Result<Vec<u8>> {
+    write_frame(stream, &encode_request(kind, spec))?;
+    let frame = read_frame(stream)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "helper 已結束"))?;
+    match frame.split_first() {
+        Some((0, body)) => Ok(body.to_vec()),
+        Some((_, message)) => Err(io::Error::other(
+            String::from_utf8_lossy(message).into_owned(),
+        )),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "helper 回應為空",
+        )),
+    }
+}
+
+fn encode_request(kind: u8, spec: &CommandSpec) -> Vec<u8> {
+    let mut buffer = vec![kind];
+    let mut push = |text: &str| {
+        buffer.extend((text.len() as u32).to_le_bytes());
+        buffer.extend(text.as_bytes());
+    };
+    push(&spec.program);
+    push(&spec.args.len().to_string());
+    for arg in &spec.args {
+        push(arg);
+    }
+    push(&spec.env.len().to_string());
+    for (key, value) in &spec.env {
+        push(key);
+        push(value);
+    }
+    // 沒有 stdin 內容或時限時為空字串
+    match &spec.input {
+        Some(input) => {
+            push("1");
+            push(input);
+        }
+        None => push("0"),
+    }
+    push(
+        &spec
+            .timeout
+            .map_or(String::new(), |limit| limit.as_millis().to_string()),
+    );
+    push(if spec.lowered { "1" } else { "0" });
+    buffer
+}
+
+fn decode_request(frame: &[u8]) -> Option<(u8, CommandSpec)> {
+    let (kind, mut rest) = frame.split_first()?;
+    let mut next = || -> Option<String> {
+        let len = u32::from_le_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+        let text = String::from_utf8(rest.get(4..4 + len)?.to_vec()).ok()?;
+        rest = &rest[4 + len..];
+        Some(text)
+    };
+    let mut spec = CommandSpec::new(&next()?);
+    let argc: usize = next()?.parse().ok()?;
+    for _ in 0..argc {
+        spec.args.push(next()?);
+    }
+    let envc: usize = next()?.parse().ok()?;
+    for _ in 0..envc {
+        let key = next()?;
+        spec.env.push((key, next()?));
+    }
+    if next()? == "1" {
+        spec.input = Some(next()?);
+    }
+    let timeout = next()?;
+    if !timeout.is_empty() {
+        spec.timeout = Some(Duration::from_millis(timeout.parse().ok()?));
+    }
+    spec.lowered = next()? == "1";
+    Some((*kind, spec))
+}
+
+fn write_frame(stream: &mut UnixStream, payload: &[u8]) -> io::Result<()> {
+    stream.write_all(&(payload.len() as u32).to_le_bytes())?;
+    stream.write_all(payload)
+}
+
+fn read_frame(stream: &mut UnixStream) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let mut payload = vec![0u8; u32::from_le_bytes(len) as usize];
+    stream.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+// ===== seccomp filter =====
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FilterFeatures {
+    pub kill: bool,
+    pub control: bool,
+    pub capture: bool,
+    pub core_dump: bool,
+    pub log_rotate: bool,
+    pub state_file: bool,
+    pub history: bool,
+    pub peer_lookup: bool,
+    pub log_only: bool,
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub fn allowed_syscalls(features: FilterFeatures) -> Vec<libc::c_long> {
+    let mut syscalls = vec![
+        // /proc 讀取、inotify 與 helper 通道
+        libc::SYS_read,
+        libc::SYS_readv,
+        libc::SYS_write,
+        libc::SYS_writev,
+        libc::SYS_openat,
+        libc::SYS_close,
+        libc::SYS_lseek,
+        libc::SYS_fstat,
+        libc::SYS_newfstatat,
+        libc::SYS_statx,
+        libc::SYS_readlinkat,
+        libc::SYS_getdents64,
+        libc::SYS_fcntl,
+        libc::SYS_inotify_add_watch,
+        libc::SYS_inotify_rm_watch,
+        libc::SYS_ppoll,
+        // --event-loop epoll：instance 在安裝 filter 前建立，之後只增減登記與等待
+        libc::SYS_epoll_ctl,
+        libc::SYS_epoll_pwait,
+        libc::SYS_recvfrom,
+        libc::SYS_sendto,
+        // 對 X server 另開的連線：PID 同步以 SO_PEERCRED 確認 X server，--x-probe、視窗分類、閒置時間等也用到
+        libc::SYS_socket,
+        libc::SYS_connect,
+        libc::SYS_getsockopt,
+        // 記憶體、時間與程序生命週期
+        libc::SYS_brk,
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_mprotect,
+        libc::SYS_madvise,
+        libc::SYS_futex,
+        libc::SYS_getrandom,
+        libc::SYS_clock_gettime,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_nanosleep,
+        libc::SYS_sched_yield,
+        libc::SYS_rt_sigaction,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_sigaltstack,
+        libc::SYS_restart_syscall,
+        libc::SYS_getpid,
+        libc::SYS_gettid,
+        libc::SYS_tgkill,
+        libc::SYS_wait4,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+        // 新發現的程序需要補開 pidfd
+        libc::SYS_pidfd_open,
+    ];
+    #[cfg(target_arch = "x86_64")]
+    syscalls.extend([libc::SYS_poll, libc::SYS_epoll_wait, libc::SYS_readlink]);
+    if features.kill {
+        syscalls.extend([libc::SYS_kill, libc::SYS_pidfd_send_signal]);
+    }
+    // 控制 socket：接受連線、設定讀寫逾時、取得用戶端身分，結束時移除 socket 檔
+    if features.control {
+        syscalls.extend([
+            libc::SYS_accept4,
+            libc::SYS_setsockopt,
+            libc::SYS_getsockopt,
+            libc::SYS_unlinkat,
+        ]);
+        #[cfg(target_arch = "x86_64")]
+        syscalls.push(libc::SYS_unlink);
+    }
+    // --capture-dir：重啟前建立擷取目錄
+    if features.capture {
+        syscalls.push(libc::SYS_mkdirat);
+        #[cfg(target_arch = "x86_64")]
+        syscalls.push(libc::SYS_mkdir);
+    }
+    // --core-dump-dir：建立目錄、刪除逾時或過大的 core 與較舊的份數
+    if features.core_dump {
+        syscalls.extend([libc::SYS_mkdirat, libc::SYS_unlinkat]);
+        #[cfg(target_arch = "x86_64")]
+        syscalls.extend([libc::SYS_mkdir, libc::SYS_unlink]);
+    }
+    // --log-max-bytes：輪替時改名舊日誌、keep 為 0 時刪除
+    if features.log_rotate {
+        syscalls.extend([libc::SYS_renameat, libc::SYS_renameat2, libc::SYS_unlinkat]);
+        #[cfg(target_arch = "x86_64")]
+        syscalls.extend([libc::SYS_rename, libc::SYS_unlink]);
+    }
+    // --state-file：鎖住旁邊的 .lock 檔，寫入暫存檔後改名；還原後刪除 --restore-geometry 的 .geometry
+    if features.state_file {
+        syscalls.extend([
+            libc::SYS_flock,
+            libc::SYS_fsync,
+            libc::SYS_renameat,
+            libc::SYS_renameat2,
+            libc::SYS_unlinkat,
+        ]);
+        #[cfg(target_arch = "x86_64")]
+        syscalls.extend([libc::SYS_rename, libc::SYS_unlink]);
+    }
+    // --history-file：事件寫入後同步、截去殘缺的檔尾，清理時寫入暫存檔後改名
+    if features.history {
+        syscalls.extend([
+            libc::SYS_fsync,
+            libc::SYS_fdatasync,
+            libc::SYS_ftruncate,
+            libc::SYS_renameat,
+            libc::SYS_renameat2,
+        ]);
+        #[cfg(target_arch = "x86_64")]
+        syscalls.push(libc::SYS_rename);
+    }
+    // --connections-from fd：複製應用程式的 socket fd 查詢對端位址
+    if features.peer_lookup {
+        syscalls.extend([libc::SYS_pidfd_getfd, libc::SYS_getpeername]);
+    }
+    syscalls
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn build_filter(syscalls: &[libc::c_long], default_action: u32) -> Vec<libc::sock_filter> {
+    let stmt = |code: u32, k: u32| libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let jump = |k: u32, jt: u8, jf: u8| libc::sock_filter {
+        code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+        jt,
+        jf,
+        k,
+    };
+    let load = libc::BPF_LD | libc::BPF_W | libc::BPF_ABS;
+    let ret = libc::BPF_RET | libc::BPF_K;
+
+    let mut program = vec![
+        stmt(load, 4),
+        jump(AUDIT_ARCH, 1, 0),
+        stmt(ret, libc::SECCOMP_RET_KILL_PROCESS),
+        stmt(load, 0),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    {
+        // 拒絕 x32 ABI（syscall 編號帶 0x40000000 位元），避免繞過白名單
+        program.push(libc::sock_filter {
+            code: (libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K) as u16,
+            jt: 0,
+            jf: 1,
+            k: 0x4000_0000,
+        });
+        program.push(stmt(ret, libc::SECCOMP_RET_KILL_PROCESS));
+    }
+    for syscall in syscalls {
+        program.push(jump(*syscall as u32, 0, 1));
+        program.push(stmt(ret, libc::SECCOMP_RET_ALLOW));
+    }
+    program.push(stmt(ret, default_action));
+    program
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub fn install_filter(features: FilterFeatures) -> Result<usize, String> {
+    let syscalls = allowed_syscalls(features);
+    let default_action = if features.log_only {
+        libc::SECCOMP_RET_LOG
+    } else {
+        libc::SECCOMP_RET_KILL_PROCESS
+    };
+    let program = build_filter(&syscalls, default_action);
+    let fprog = libc::sock_fprog {
+        len: program.len() as u16,
+        filter: program.as_ptr() as *mut libc::sock_filter,
+    };
+    unsafe {
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+            return Err(format!(
+                "PR_SET_NO_NEW_PRIVS 失敗: {}",
+                io::Error::last_os_error()
+            ));
+        }
+        // TSYNC：連同已存在的掃描執行緒一起套用
+        let result = libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &fprog as *const libc::sock_fprog,
+        );
+        if result != 0 {
+            return Err(format!(
+                "安裝 seccomp filter 失敗: {}",
+                io::Error::last_os_error()
+            ));
+        }
+    }
+    Ok(syscalls.len())
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn install_filter(_features: FilterFeatures) -> Result<usize, String> {
+    Err("此架構尚未支援 --seccomp".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_round_trips_and_policy_limits_commands() {
+        let spec = CommandSpec::new("sh")
+            .arg("-lc")
+            .arg("/opt/QQ/qq --no-sandbox")
+            .env("GUARD_MESSAGE", "連線 12 條");
+        let (kind, decoded) =
+            decode_request(&encode_request(REQUEST_SPAWN, &spec)).expect("可解碼");
+        assert_eq!(kind, REQUEST_SPAWN);
+        assert_eq!(decoded, spec);
+        let mut timed = CommandSpec::new("sh")
+            .arg("-c")
+            .arg("cat")
+            .input("{\"count\": 12}")
+            .timeout(Duration::from_millis(1500));
+        timed.lowered = true;
+        let (_, decoded) = decode_request(&encode_request(REQUEST_OUTPUT, &timed)).unwrap();
+        assert_eq!(decoded, timed);
+
+        let policy = HelperPolicy {
+            commands: vec![
+                spec.argv(),
+                vec!["/opt/QQ/qq".to_string(), "--no-sandbox".to_string()],
+            ],
+            programs: vec!["ss".to_string()],
+            unrestricted: false,
+        };
+        assert!(policy.permits(&spec));
+        assert!(policy.permits(&CommandSpec::new("ss").arg("-xnpH")));
+        assert!(!policy.permits(&CommandSpec::new("sh").arg("-lc").arg("rm -rf ~")));
+        assert!(policy.permits(&CommandSpec::new("/opt/QQ/qq").arg("--no-sandbox")));
+        assert!(!policy.permits(&CommandSpec::new("/opt/QQ/qq").arg("--evil")));
+        assert!(!policy.permits(&CommandSpec::new("curl")));
+        assert!(decode_request(&[REQUEST_OUTPUT, 9, 0]).is_none());
+    }
+
+    #[test]
+    fn timed_command_gets_stdin_and_is_killed_on_timeout() {
+        let echo = CommandSpec::new("sh")
+            .arg("-c")
+            .arg("read line; echo \"got $line\"")
+            .input("allow\n")
+            .timeout(Duration::from_secs(5));
+        assert_eq!(run_timed(&echo).unwrap(), (true, b"got allow\n".to_vec()));
+
+        // 背景子程序也在同一個群組，逾時後不會讓讀取卡住
+        let started = Instant::now();
+        let hang = CommandSpec::new("sh")
+            .arg("-c")
+            .arg("sleep 30 & sleep 30")
+            .timeout(Duration::from_millis(300));
+        let error = run_timed(&hang).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
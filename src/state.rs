@@ -0,0 +1,192 @@
+use std::fs;
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// --state-file：多個守護程序（例如一個只偵測、一個負責動作，或新舊兩個版本交接時）共用的最近一次動作。
+// 讀寫都以 flock 鎖住旁邊的 <path>.lock，不鎖狀態檔本身：寫入是先寫暫存檔再 rename，
+// 鎖在舊的 inode 上就鎖不到新的內容；rename 也讓沒拿到鎖的讀取不會看到寫一半的檔案
+const HEADER: &str = "# qq-x11-guard-rs 狀態檔：最近一次動作，由守護程序維護，請勿手動編輯";
+const POLL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    // unix 秒
+    pub time: u64,
+    // 寫入的守護程序 PID
+    pub instance: u32,
+    pub action: String,
+    pub app: String,
+    pub display: String,
+    pub incident: String,
+}
+
+impl Record {
+    pub fn render(&self) -> String {
+        format!(
+            "{HEADER}\ntime={}\ninstance={}\naction={}\napp={}\ndisplay={}\nincident={}\n",
+            self.time, self.instance, self.action, self.app, self.display, self.incident
+        )
+    }
+
+    pub fn action_text(&self) -> &str {
+        match self.action.as_str() {
+            "restart" => "重啟",
+            "reload" => "重新載入",
+            other => other,
+        }
+    }
+
+    // 缺少欄位或數字無法解析時整筆不採用；不認得的欄位略過，留給之後的版本
+    pub fn parse(text: &str) -> Option<Record> {
+        let field = |name: &str| {
+            text.lines().find_map(|line| {
+                line.strip_prefix(name)
+                    .and_then(|rest| rest.strip_prefix('='))
+                    .map(str::to_string)
+            })
+        };
+        Some(Record {
+            time: field("time")?.parse().ok()?,
+            instance: field("instance")?.parse().ok()?,
+            action: field("action")?,
+            app: field("app")?,
+            display: field("display")?,
+            incident: field("incident").unwrap_or_default(),
+        })
+    }
+}
+
+// 狀態檔旁的附屬檔：.lock、.tmp，以及 self-upgrade 的 .resume 與 --restore-geometry 的 .geometry
+pub fn sidecar(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+// 持有期間即持有鎖，drop 時關閉 fd 一併釋放
+pub struct Lock {
+    _fd: OwnedFd,
+}
+
+// 以 LOCK_NB 反覆嘗試到逾時：阻塞的 flock 無法設定逾時，持有鎖的程序卡住時守護也會跟著卡住
+pub fn lock(path: &Path, exclusive: bool, timeout: Duration) -> Result<Lock, String> {
+    let lock_path = sidecar(path, ".lock");
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .map_err(|error| format!("無法開啟 {}: {error}", lock_path.display()))?;
+    let fd = OwnedFd::from(file);
+    let operation = if exclusive {
+        libc::LOCK_EX
+    } else {
+        libc::LOCK_SH
+    };
+    let deadline = Instant::now() + timeout;
+    loop {
+        if unsafe { libc::flock(fd.as_raw_fd(), operation | libc::LOCK_NB) } == 0 {
+            return Ok(Lock { _fd: fd });
+        }
+        let error = io::Error::last_os_error();
+        if error.raw_os_error() != Some(libc::EWOULDBLOCK) {
+            return Err(format!("無法鎖定 {}: {error}", lock_path.display()));
+        }
+        if Instant::now() >= deadline {
+            return Err(format!(
+                "{} 秒內無法取得 {} 的鎖（另一個守護程序正持有）",
+                timeout.as_secs_f64(),
+                lock_path.display()
+            ));
+        }
+        thread::sleep(POLL);
+    }
+}
+
+// 檔案不存在時為 None；內容無法解析時回傳錯誤
+pub fn read(path: &Path) -> Result<Option<Record>, String> {
+    match fs::read_to_string(path) {
+        Ok(text) => Record::parse(&text)
+            .map(Some)
+            .ok_or_else(|| format!("{} 的內容無法解析", path.display())),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(format!("無法讀取 {}: {error}", path.display())),
+    }
+}
+
+// 呼叫端須持有排他鎖
+pub fn write(path: &Path, record: &Record) -> Result<(), String> {
+    write_text(path, &record.render())
+}
+
+pub fn write_text(path: &Path, text: &str) -> Result<(), String> {
+    let temporary = sidecar(path, ".tmp");
+    let result = (|| {
+        let mut file = fs::File::create(&temporary)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temporary, path)
+    })();
+    result.map_err(|error| format!("無法寫入 {}: {error}", path.display()))
+}
+
+// self-upgrade 交給新執行檔的執行期狀態：同樣是 key=value，同一個 key 可以出現多次（例如每個連線一行）。
+// 值不能含換行，呼叫端負責
+pub fn render_pairs(pairs: &[(&str, String)]) -> String {
+    let mut text =
+        String::from("# qq-x11-guard-rs self-upgrade 暫存的執行期狀態，新的執行檔讀取後即刪除\n");
+    for (key, value) in pairs {
+        text.push_str(&format!("{key}={value}\n"));
+    }
+    text
+}
+
+pub fn parse_pairs(text: &str) -> Vec<(String, String)> {
+    text.lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn records_round_trip_and_the_lock_times_out_under_contention() {
+        let dir = env::temp_dir().join(format!("qq-x11-guard-state-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state");
+        assert_eq!(read(&path), Ok(None));
+
+        let record = Record {
+            time: 1_792_000_000,
+            instance: 4242,
+            action: "restart".to_string(),
+            app: "qq".to_string(),
+            display: ":0".to_string(),
+            incident: "inc-1792000000-3".to_string(),
+        };
+        let held = lock(&path, true, Duration::ZERO).unwrap();
+        write(&path, &record).unwrap();
+        assert_eq!(read(&path), Ok(Some(record)));
+
+        // 另一個開啟的 fd 視同另一個程序：排他鎖持有期間共享鎖也要等
+        let error = lock(&path, false, Duration::from_millis(120))
+            .err()
+            .unwrap();
+        assert!(error.contains("另一個守護程序正持有"), "{error}");
+        drop(held);
+        assert!(lock(&path, false, Duration::ZERO).is_ok());
+
+        fs::write(&path, "time=soon\n").unwrap();
+        assert!(read(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,308 @@
+use crate::sandbox::CommandSpec;
+
+// --restart-cmd 等命令中的佔位符，在執行前換成當下的值。{{ 與 }} 是字面的大括號；
+// $ 後面的 {…} 是 shell 的變數展開，照原樣保留；大括號內不是英數字與底線的（例如 awk 的 {print $1}）也照原樣保留。
+// 經過 sh 時值一律包在單引號內，不經過 shell 時每個值只會留在原本的那個參數裡
+// （此時不要把佔位符寫進 sh -c 的命令字串，值會原樣成為 shell 語法）
+pub const NAMES: [&str; 8] = [
+    "display",
+    "socket",
+    "count",
+    "threshold",
+    "pids",
+    "app",
+    "incident",
+    "reason",
+];
+
+#[derive(Debug, PartialEq, Eq)]
+enum Segment {
+    Text(String),
+    Placeholder(&'static str),
+}
+
+fn parse(template: &str) -> Result<Vec<Segment>, String> {
+    let mut segments = Vec::new();
+    let mut text = String::new();
+    let mut rest = template;
+    while let Some(char) = rest.chars().next() {
+        if let Some(after) = rest.strip_prefix("{{").or_else(|| rest.strip_prefix("}}")) {
+            text.push(char);
+            rest = after;
+            continue;
+        }
+        let candidate = rest
+            .strip_prefix('{')
+            .filter(|_| !text.ends_with('$'))
+            .and_then(|after| after.split_once('}'))
+            .filter(|(name, _)| {
+                !name.is_empty()
+                    && name
+                        .chars()
+                        .all(|char| char.is_ascii_alphanumeric() || char == '_')
+            });
+        if let Some((name, after)) = candidate {
+            let known = NAMES.iter().find(|known| **known == name).ok_or_else(|| {
+                format!(
+                    "未知的佔位符 {{{name}}}（可用：{}；字面的大括號請寫成 {{{{ 與 }}}}）",
+                    NAMES.map(|name| format!("{{{name}}}")).join("、")
+                )
+            })?;
+            segments.extend((!text.is_empty()).then(|| Segment::Text(std::mem::take(&mut text))));
+            segments.push(Segment::Placeholder(known));
+            rest = after;
+            continue;
+        }
+        text.push(char);
+        rest = &rest[char.len_utf8()..];
+    }
+    segments.extend((!text.is_empty()).then_some(Segment::Text(text)));
+    Ok(segments)
+}
+
+// 啟動時檢查。經過 sh 時佔位符只能寫在引號外、且不能緊接在 \ 之後：
+// 值加上的單引號在引號內會變成關閉再開啟引號，值本身反而落在引號外，其中的空白、; 或 $(…) 會被 shell 解讀
+pub fn validate(template: &str, shell: bool) -> Result<(), String> {
+    let segments = parse(template)?;
+    if !shell {
+        return Ok(());
+    }
+    #[derive(PartialEq)]
+    enum Quote {
+        None,
+        Single,
+        Double,
+        Ansi,
+    }
+    let mut quote = Quote::None;
+    let mut escaped = false;
+    let mut previous = None;
+    for segment in &segments {
+        let text = match segment {
+            Segment::Text(text) => text,
+            Segment::Placeholder(name) => {
+                if escaped || quote != Quote::None {
+                    return Err(format!(
+                        "佔位符 {{{name}}} 不能放在引號內或 \\ 之後（值會自動加上單引號，直接寫在引號外即可）"
+                    ));
+                }
+                previous = None;
+                continue;
+            }
+        };
+        for char in text.chars() {
+            if escaped {
+                escaped = false;
+            } else {
+                match (&quote, char) {
+                    (Quote::Single, '\'') => quote = Quote::None,
+                    (Quote::Single, _) => {}
+                    (_, '\\') if quote != Quote::Single => escaped = true,
+                    (Quote::None, '\'') if previous == Some('$') => quote = Quote::Ansi,
+                    (Quote::None, '\'') => quote = Quote::Single,
+                    (Quote::None, '"') => quote = Quote::Double,
+                    (Quote::Double, '"') | (Quote::Ansi, '\'') => quote = Quote::None,
+                    _ => {}
+                }
+            }
+            previous = Some(char);
+        }
+    }
+    Ok(())
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+// 已通過 validate 的命令才會走到這裡；無法解析時照原樣回傳
+fn expand(template: &str, values: &[(&str, String)], shell: bool) -> String {
+    let Ok(segments) = parse(template) else {
+        return template.to_string();
+    };
+    segments
+        .iter()
+        .map(|segment| match segment {
+            Segment::Text(text) => text.clone(),
+            Segment::Placeholder(name) => {
+                let value = values
+                    .iter()
+                    .find(|(key, _)| key == name)
+                    .map_or("", |(_, value)| value.as_str());
+                if shell {
+                    shell_quote(value)
+                } else {
+                    value.to_string()
+                }
+            }
+        })
+        .collect()
+}
+
+// 使用者命令是否交給 sh，與 main.rs 的 user_command 一致
+pub const SHELL: bool = cfg!(feature = "shell-restart");
+
+// user_command 產生的命令（仍是樣板）代入值
+pub fn fill(spec: CommandSpec, values: &[(&str, String)]) -> CommandSpec {
+    fill_with(spec, values, SHELL)
+}
+
+fn fill_with(spec: CommandSpec, values: &[(&str, String)], shell: bool) -> CommandSpec {
+    CommandSpec {
+        program: expand(&spec.program, values, shell),
+        args: spec
+            .args
+            .iter()
+            .map(|arg| expand(arg, values, shell))
+            .collect(),
+        ..spec
+    }
+}
+
+// seccomp helper 檢查要執行的 argv 是否由白名單中的樣板代入而來：
+// 經過 sh 時每個佔位符只能對應一段完整的單引號字串（shell_quote 的輸出），否則可以是任意內容但不跨參數
+pub fn matches(template: &[String], argv: &[String]) -> bool {
+    matches_with(template, argv, SHELL)
+}
+
+fn matches_with(template: &[String], argv: &[String], shell: bool) -> bool {
+    template.len() == argv.len()
+        && template.iter().zip(argv).all(|(template, actual)| {
+            parse(template).is_ok_and(|segments| matches_segments(&segments, actual, shell))
+        })
+}
+
+fn matches_segments(segments: &[Segment], text: &str, shell: bool) -> bool {
+    let Some((first, rest)) = segments.split_first() else {
+        return text.is_empty();
+    };
+    match first {
+        Segment::Text(literal) => text
+            .strip_prefix(literal.as_str())
+            .is_some_and(|after| matches_segments(rest, after, shell)),
+        Segment::Placeholder(_) if shell => {
+            quoted_len(text).is_some_and(|len| matches_segments(rest, &text[len..], shell))
+        }
+        Segment::Placeholder(_) => (0..=text.len())
+            .filter(|index| text.is_char_boundary(*index))
+            .any(|index| matches_segments(rest, &text[index..], shell)),
+    }
+}
+
+// 開頭一段 shell_quote 輸出的長度：'…' 之後接 \'' 表示值裡的單引號，繼續同一個值
+fn quoted_len(text: &str) -> Option<usize> {
+    let mut position = text.strip_prefix('\'').map(|_| 1)?;
+    loop {
+        position += text[position..].find('\'')? + 1;
+        if !text[position..].starts_with("\\''") {
+            return Some(position);
+        }
+        position += 3;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> Vec<(&'static str, String)> {
+        vec![
+            ("display", ":0".to_string()),
+            ("pids", "100 101".to_string()),
+            ("reason", "it's \"odd\"; rm -rf ~ $(id)".to_string()),
+        ]
+    }
+
+    #[test]
+    fn placeholders_expand_with_escapes_and_unknown_names_are_rejected() {
+        assert_eq!(
+            expand(
+                "qq --display {display} {{literal}} ${HOME} awk '{print $1}'",
+                &values(),
+                false
+            ),
+            "qq --display :0 {literal} ${HOME} awk '{print $1}'"
+        );
+        assert_eq!(expand("x{count}y", &values(), false), "xy");
+        let error = validate("qq {dispaly}", true).unwrap_err();
+        assert!(
+            error.starts_with("未知的佔位符 {dispaly}（可用：{display}、"),
+            "{error}"
+        );
+        assert!(validate("qq {{dispaly}}", true).is_ok());
+
+        assert!(validate("relaunch {reason} --pids={pids}", true).is_ok());
+        for unsafe_template in [
+            "echo '{reason}'",
+            "echo \"{reason}\"",
+            "echo $'{reason}'",
+            "echo \\{reason}",
+        ] {
+            assert!(
+                validate(unsafe_template, true).is_err(),
+                "{unsafe_template}"
+            );
+            assert!(
+                validate(unsafe_template, false).is_ok(),
+                "{unsafe_template}"
+            );
+        }
+        // 引號已關閉後、\ 跳脫的引號之後都回到引號外
+        assert!(validate("echo \"a\\\"b\" {reason} 'c' {app}", true).is_ok());
+    }
+
+    #[test]
+    fn values_with_spaces_and_quotes_stay_one_word_in_both_modes() {
+        let template = "printf '%s|' {reason} {pids} x{display}";
+        let shell = fill_with(
+            CommandSpec::new("sh").arg("-c").arg(template),
+            &values(),
+            true,
+        );
+        let output = std::process::Command::new(&shell.program)
+            .args(&shell.args)
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "it's \"odd\"; rm -rf ~ $(id)|100 101|x:0|"
+        );
+
+        let exec = fill_with(
+            CommandSpec::new("relaunch")
+                .arg("--why={reason}")
+                .arg("{pids}"),
+            &values(),
+            false,
+        );
+        assert_eq!(
+            exec.args,
+            vec![
+                "--why=it's \"odd\"; rm -rf ~ $(id)".to_string(),
+                "100 101".to_string()
+            ]
+        );
+
+        // helper 的白名單：代入後的命令符合樣板，改寫過的不符合
+        let template_argv = vec!["sh".to_string(), "-c".to_string(), template.to_string()];
+        assert!(matches_with(&template_argv, &shell.argv(), true));
+        let mut forged = shell.argv();
+        forged[2] = "printf '%s|' 'a'; id '100 101' x':0'".to_string();
+        assert!(!matches_with(&template_argv, &forged, true));
+        let exec_template = vec![
+            "relaunch".to_string(),
+            "--why={reason}".to_string(),
+            "{pids}".to_string(),
+        ];
+        assert!(matches_with(&exec_template, &exec.argv(), false));
+        assert!(!matches_with(
+            &exec_template,
+            &[
+                "relaunch".to_string(),
+                "--evil".to_string(),
+                "1".to_string()
+            ],
+            false
+        ));
+    }
+}
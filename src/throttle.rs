@@ -0,0 +1,152 @@
+use std::time::{Duration, Instant};
+
+// --max-cpu：守護自身的 CPU 時間（/proc/self/stat 的 utime + stime）每個 window 結算一次，
+// 超過上限時把輪詢間隔與主迴圈每一輪的最短間隔加倍，最多 MAX_FACTOR 倍；
+// 之後某個 window 低於上限的一半就減半，逐步回到原本的速度
+pub const MAX_FACTOR: u32 = 16;
+// 放慢時主迴圈每一輪至少間隔 ITERATION_FLOOR × 倍數，事件再多也不會空轉
+pub const ITERATION_FLOOR: Duration = Duration::from_millis(100);
+
+pub struct Settings {
+    // 佔單一核心的百分比
+    pub percent: u32,
+    pub window: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change {
+    Slowed { usage: f64, factor: u32 },
+    Eased { usage: f64, factor: u32 },
+}
+
+pub struct Governor {
+    // 這個 window 開始的時間與當時的 CPU 時間
+    start: Option<(Instant, Duration)>,
+    pub factor: u32,
+    // 上一個 window 的用量（%）
+    pub last_usage: Option<f64>,
+    pub slowed_since: Option<Instant>,
+}
+
+impl Default for Governor {
+    fn default() -> Self {
+        Self {
+            start: None,
+            factor: 1,
+            last_usage: None,
+            slowed_since: None,
+        }
+    }
+}
+
+impl Governor {
+    // cpu 為目前累計的 CPU 時間；window 未滿時回傳 None
+    pub fn observe(&mut self, settings: &Settings, cpu: Duration, now: Instant) -> Option<Change> {
+        let Some((started, base)) = self.start else {
+            self.start = Some((now, cpu));
+            return None;
+        };
+        let elapsed = now.duration_since(started);
+        if elapsed < settings.window {
+            return None;
+        }
+        self.start = Some((now, cpu));
+        let usage = cpu.saturating_sub(base).as_secs_f64() / elapsed.as_secs_f64() * 100.0;
+        self.last_usage = Some(usage);
+        let limit = f64::from(settings.percent);
+        if usage > limit && self.factor < MAX_FACTOR {
+            self.factor *= 2;
+            self.slowed_since.get_or_insert(now);
+            Some(Change::Slowed {
+                usage,
+                factor: self.factor,
+            })
+        } else if usage < limit / 2.0 && self.factor > 1 {
+            self.factor /= 2;
+            if self.factor == 1 {
+                self.slowed_since = None;
+            }
+            Some(Change::Eased {
+                usage,
+                factor: self.factor,
+            })
+        } else {
+            None
+        }
+    }
+
+    // 放慢時主迴圈這一輪還要等多久
+    pub fn iteration_delay(&self, last_iteration: Instant, now: Instant) -> Option<Duration> {
+        if self.factor <= 1 {
+            return None;
+        }
+        let floor = ITERATION_FLOOR * self.factor;
+        floor
+            .checked_sub(now.duration_since(last_iteration))
+            .filter(|delay| !delay.is_zero())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sustained_usage_doubles_the_factor_and_quiet_windows_halve_it() {
+        let settings = Settings {
+            percent: 5,
+            window: Duration::from_secs(60),
+        };
+        let mut governor = Governor::default();
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+        let cpu = |millis| Duration::from_millis(millis);
+
+        assert_eq!(governor.observe(&settings, cpu(0), at(0)), None);
+        // window 未滿不結算
+        assert_eq!(governor.observe(&settings, cpu(30_000), at(30)), None);
+        // 60 秒用了 30 秒 CPU：50%
+        assert_eq!(
+            governor.observe(&settings, cpu(30_000), at(60)),
+            Some(Change::Slowed {
+                usage: 50.0,
+                factor: 2
+            })
+        );
+        assert_eq!(governor.slowed_since, Some(at(60)));
+        assert_eq!(
+            governor.iteration_delay(at(60), at(60) + cpu(50)),
+            Some(cpu(150))
+        );
+        assert_eq!(governor.iteration_delay(at(60), at(61)), None);
+
+        for (step, factor) in [(1, 4), (2, 8), (3, 16)] {
+            let change =
+                governor.observe(&settings, cpu(30_000 + step * 6_000), at(60 + step * 60));
+            assert_eq!(
+                change,
+                Some(Change::Slowed {
+                    usage: 10.0,
+                    factor: factor as u32
+                })
+            );
+        }
+        // 已到上限，不再加倍
+        assert_eq!(governor.observe(&settings, cpu(54_000), at(300)), None);
+        // 介於上限的一半與上限之間時維持
+        assert_eq!(governor.observe(&settings, cpu(56_400), at(360)), None);
+
+        let mut now = 360;
+        let mut used = 56_400;
+        for factor in [8, 4, 2, 1] {
+            now += 60;
+            used += 600;
+            assert_eq!(
+                governor.observe(&settings, cpu(used), at(now)),
+                Some(Change::Eased { usage: 1.0, factor })
+            );
+        }
+        assert_eq!(governor.slowed_since, None);
+        assert_eq!(governor.iteration_delay(at(now), at(now)), None);
+    }
+}
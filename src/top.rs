@@ -0,0 +1,529 @@
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, Write};
+use std::mem;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::{
+    control, fd_count, find_app_pids, parse_display, rss_kib, timestamp, Config, FdScanner,
+    PeerBackend, X11Endpoint,
+};
+
+const HISTORY_LEN: usize = 60;
+// 守護程序在重啟流程中不會處理控制命令，等不到回覆時這一輪改為自行量測
+const STATUS_TIMEOUT: Duration = Duration::from_secs(1);
+const MEASURE_TIMEOUT: Duration = Duration::from_secs(5);
+const SPARK_CHARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+static RESIZED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigwinch(_: libc::c_int) {
+    RESIZED.store(true, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ProcessRow {
+    pid: i32,
+    comm: String,
+    x11: usize,
+    fds: usize,
+    rss_kib: u64,
+    // fd 目錄權限不足，x11 與 fds 不可信
+    unreadable: bool,
+}
+
+struct Snapshot {
+    rows: Vec<ProcessRow>,
+    total: usize,
+}
+
+// 守護程序 status 回覆中 top 用得到的幾行
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DaemonStatus {
+    // 監控中、已暫停…；崩潰循環時附在後面
+    state: String,
+    threshold: Option<usize>,
+    // 尚未量測或 fd 都無法讀取時為 None
+    count: Option<usize>,
+    // 可再動作的時間，例如「約 42 秒後（冷卻）」
+    eligible: String,
+}
+
+fn parse_status(reply: &str) -> Option<DaemonStatus> {
+    if reply.starts_with("錯誤") {
+        return None;
+    }
+    let mut status = DaemonStatus {
+        state: String::new(),
+        threshold: None,
+        count: None,
+        eligible: String::new(),
+    };
+    let mut crash_loop = None;
+    for line in reply.lines() {
+        if let Some(state) = line.strip_prefix("狀態: ") {
+            status.state = state.to_string();
+        } else if let Some(text) = line.strip_prefix("崩潰循環: ") {
+            crash_loop = Some(text.to_string());
+        } else if let Some(count) = line.strip_prefix("X11 連線: ") {
+            status.count = leading_number(count);
+        } else if let Some(eligible) = line.strip_prefix("可再動作: ") {
+            status.eligible = eligible.to_string();
+        } else if let Some((_, threshold)) = line.split_once("門檻=") {
+            status.threshold = leading_number(threshold);
+        }
+    }
+    if let Some(text) = crash_loop {
+        status.state = format!("{}，崩潰循環（{text}）", status.state);
+    }
+    (!status.state.is_empty()).then_some(status)
+}
+
+fn leading_number<T: FromStr>(text: &str) -> Option<T> {
+    let end = text
+        .find(|char: char| !char.is_ascii_digit())
+        .unwrap_or(text.len());
+    text[..end].parse().ok()
+}
+
+// measure json 的回覆由守護程序產生，格式固定：只取第一個出現的 key 與不巢狀的陣列。
+// 最上層的 connections 在 pids 之前，不會取到各程序的值
+fn json_number<T: FromStr>(text: &str, key: &str) -> Option<T> {
+    let (_, rest) = text.split_once(&format!("\"{key}\":"))?;
+    leading_number(rest)
+}
+
+fn json_array<'a>(text: &'a str, key: &str) -> Option<&'a str> {
+    let (_, rest) = text.split_once(&format!("\"{key}\":["))?;
+    rest.split_once(']').map(|(items, _)| items)
+}
+
+// 各程序的連線數取自守護程序的 measure，名稱、fd 數與 RSS 在本機讀取
+fn daemon_snapshot(config: &Config, reply: &str) -> Option<Snapshot> {
+    let total = json_number(reply, "connections")?;
+    let unreadable: Vec<i32> = json_array(reply, "unreadable")?
+        .split(',')
+        .filter_map(|pid| pid.trim().parse().ok())
+        .collect();
+    let proc_root = &config.proc_root;
+    let rows = json_array(reply, "pids")?
+        .split('}')
+        .filter_map(|item| Some((json_number(item, "pid")?, json_number(item, "connections")?)))
+        .map(|(pid, x11)| ProcessRow {
+            pid,
+            comm: crate::process_name(proc_root, pid).unwrap_or_default(),
+            x11,
+            fds: fd_count(proc_root, pid),
+            rss_kib: rss_kib(proc_root, pid),
+            unreadable: unreadable.contains(&pid),
+        })
+        .collect();
+    Some(Snapshot { rows, total })
+}
+
+struct Sampler {
+    x11_endpoint: X11Endpoint,
+    backend: PeerBackend,
+    fd_scanner: FdScanner,
+}
+
+impl Sampler {
+    fn sample(&mut self, config: &Config) -> Snapshot {
+        let proc_root = &config.proc_root;
+        let (pids, _) = find_app_pids(
+            &mut self.fd_scanner,
+            &config.app_names,
+            &config.cmdline_match,
+            &self.x11_endpoint,
+        );
+        let peers = if pids.is_empty() {
+            HashSet::new()
+        } else {
+            self.backend.x11_peer_inodes(proc_root, &self.x11_endpoint)
+        };
+        let mut rows = Vec::new();
+        let mut total = 0;
+        for (pid, inodes) in self.fd_scanner.socket_inodes_by_pid(&pids) {
+            let unreadable = self.fd_scanner.unreadable.contains(&pid);
+            let x11 = inodes.intersection(&peers).count();
+            total += x11;
+            rows.push(ProcessRow {
+                pid,
+                comm: crate::process_name(proc_root, pid).unwrap_or_default(),
+                x11,
+                fds: fd_count(proc_root, pid),
+                rss_kib: rss_kib(proc_root, pid),
+                unreadable,
+            });
+        }
+        Snapshot { rows, total }
+    }
+}
+
+fn sparkline(values: &VecDeque<usize>, scale: usize) -> String {
+    let scale = scale.max(1);
+    values
+        .iter()
+        .map(|value| {
+            let index = (*value).min(scale) * (SPARK_CHARS.len() - 1) / scale;
+            SPARK_CHARS[index]
+        })
+        .collect()
+}
+
+// daemon 為 None 時是 top 自行量測的結果
+fn render(
+    config: &Config,
+    snapshot: &Snapshot,
+    daemon: Option<&DaemonStatus>,
+    history: &VecDeque<usize>,
+    width: usize,
+    height: usize,
+) -> Vec<String> {
+    let threshold = daemon
+        .and_then(|status| status.threshold)
+        .unwrap_or(config.threshold);
+    let total = daemon
+        .and_then(|status| status.count)
+        .unwrap_or(snapshot.total);
+    let state = if snapshot.rows.is_empty() {
+        "未執行"
+    } else if snapshot.rows.iter().all(|row| row.unreadable) {
+        "未知（fd 無法讀取）"
+    } else if total > threshold {
+        "超標"
+    } else {
+        "正常"
+    };
+    let daemon_text = match daemon {
+        Some(status) => format!("守護程序: {}  可再動作: {}", status.state, status.eligible),
+        None => "冷卻: 未連線到守護程序".to_string(),
+    };
+    let peak = history.iter().copied().max().unwrap_or(0);
+    let mut lines = vec![
+        format!(
+            "{} top  {}  DISPLAY={}  {}",
+            config.log_prefix,
+            timestamp(),
+            config.display,
+            config.app_name
+        ),
+        format!("X11 連線 {total} / 門檻 {threshold}  狀態: {state}  {daemon_text}"),
+        format!(
+            "近 {} 次: {} （最高 {peak}）",
+            history.len(),
+            sparkline(history, threshold.max(peak))
+        ),
+        String::new(),
+        format!(
+            "{:>8}  {:<16} {:>6} {:>6} {:>10}",
+            "PID", "COMM", "X11", "FD", "RSS(KiB)"
+        ),
+    ];
+    for row in &snapshot.rows {
+        if row.unreadable {
+            lines.push(format!(
+                "{:>8}  {:<16} {:>6} {:>6} {:>10}  無法讀取",
+                row.pid, row.comm, "?", "?", row.rss_kib
+            ));
+            continue;
+        }
+        lines.push(format!(
+            "{:>8}  {:<16} {:>6} {:>6} {:>10}",
+            row.pid, row.comm, row.x11, row.fds, row.rss_kib
+        ));
+    }
+    if snapshot.rows.is_empty() {
+        lines.push(format!("（找不到 {} 程序）", config.app_name));
+    }
+    if height > 0 {
+        lines.truncate(height.saturating_sub(1));
+        lines.push(match daemon {
+            Some(_) => "q 離開  r 要求守護程序立即重新量測".to_string(),
+            None => "q 離開  r 立即重新量測".to_string(),
+        });
+    }
+    lines
+        .into_iter()
+        .map(|line| line.chars().take(width.max(1)).collect())
+        .collect()
+}
+
+struct RawTerminal {
+    original: libc::termios,
+}
+
+impl RawTerminal {
+    fn enter() -> io::Result<Self> {
+        let mut original: libc::termios = unsafe { mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut raw = original;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+        raw.c_cc[libc::VMIN] = 0;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // 切換到替代畫面並隱藏游標
+        print!("\x1b[?1049h\x1b[?25l");
+        let _ = io::stdout().flush();
+        Ok(Self { original })
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+fn terminal_size() -> (usize, usize) {
+    let mut size: libc::winsize = unsafe { mem::zeroed() };
+    if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } != 0
+        || size.ws_col == 0
+    {
+        return (80, 24);
+    }
+    (size.ws_col as usize, size.ws_row as usize)
+}
+
+// 等待按鍵直到逾時；視窗大小改變會中斷 poll 並回傳 None 以便立即重繪
+fn read_key(timeout: Duration) -> Option<u8> {
+    let mut poll_fd = libc::pollfd {
+        fd: libc::STDIN_FILENO,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout_ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+    if unsafe { libc::poll(&mut poll_fd, 1, timeout_ms) } <= 0 {
+        return None;
+    }
+    let mut byte = 0u8;
+    let read = unsafe { libc::read(libc::STDIN_FILENO, (&mut byte as *mut u8).cast(), 1) };
+    (read == 1).then_some(byte)
+}
+
+// 指定 --control-socket 且守護程序有回應時，狀態、冷卻與連線數取自它的 status，各程序的連線數取自
+// 開始時與按下 r 時的 measure（守護程序重新量測），連線數變動時也重新取得；top 不自行找程序與量測。
+// 沒有守護程序或它沒有回應時，改由 top 自行量測
+struct Source<'a> {
+    config: &'a Config,
+    sampler: Option<Sampler>,
+    // 最近一次 measure 的結果與當時的連線數
+    measured: Option<(Snapshot, Option<usize>)>,
+}
+
+impl Source<'_> {
+    fn local(&mut self) -> Result<&mut Sampler, String> {
+        if self.sampler.is_none() {
+            let (backend, _) = PeerBackend::select(self.config.backend)?;
+            self.sampler = Some(Sampler {
+                x11_endpoint: parse_display(&self.config.display)?,
+                backend,
+                fd_scanner: FdScanner::new(self.config.proc_root.clone()),
+            });
+        }
+        Ok(self.sampler.as_mut().expect("剛建立"))
+    }
+
+    fn daemon(&mut self, path: &Path, remeasure: bool) -> Option<(Snapshot, DaemonStatus)> {
+        let status = parse_status(&control::request(path, "status", STATUS_TIMEOUT).ok()?)?;
+        let stale = self
+            .measured
+            .as_ref()
+            .is_none_or(|(_, count)| *count != status.count);
+        if remeasure || stale {
+            let reply = control::request(path, "measure json", MEASURE_TIMEOUT).ok()?;
+            self.measured = Some((daemon_snapshot(self.config, &reply)?, status.count));
+        }
+        let (snapshot, _) = self.measured.as_ref()?;
+        let snapshot = Snapshot {
+            rows: snapshot.rows.clone(),
+            total: snapshot.total,
+        };
+        Some((snapshot, status))
+    }
+
+    fn sample(&mut self, remeasure: bool) -> Result<(Snapshot, Option<DaemonStatus>), String> {
+        if let Some(path) = self.config.control_socket.clone() {
+            if let Some((snapshot, status)) = self.daemon(&path, remeasure) {
+                return Ok((snapshot, Some(status)));
+            }
+            self.measured = None;
+        }
+        let config = self.config;
+        let sampler = self.local()?;
+        Ok((sampler.sample(config), None))
+    }
+}
+
+pub fn run(config: &Config) -> Result<(), String> {
+    let mut source = Source {
+        config,
+        sampler: None,
+        measured: None,
+    };
+    let mut history = VecDeque::with_capacity(HISTORY_LEN);
+    let total = |snapshot: &Snapshot, daemon: &Option<DaemonStatus>| {
+        daemon
+            .as_ref()
+            .and_then(|status| status.count)
+            .unwrap_or(snapshot.total)
+    };
+
+    let interactive =
+        unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 && libc::isatty(libc::STDIN_FILENO) == 1 };
+    if !interactive {
+        let (snapshot, daemon) = source.sample(false)?;
+        history.push_back(total(&snapshot, &daemon));
+        for line in render(config, &snapshot, daemon.as_ref(), &history, usize::MAX, 0) {
+            println!("{line}");
+        }
+        return Ok(());
+    }
+
+    unsafe {
+        libc::signal(
+            libc::SIGWINCH,
+            on_sigwinch as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+    let (mut snapshot, mut daemon) = source.sample(false)?;
+    let _terminal = RawTerminal::enter().map_err(|err| format!("無法切換終端機模式: {err}"))?;
+    history.push_back(total(&snapshot, &daemon));
+    let mut next_sample = Instant::now() + Duration::from_secs(1);
+    loop {
+        let (width, height) = terminal_size();
+        let mut frame = String::from("\x1b[H\x1b[2J");
+        for line in render(config, &snapshot, daemon.as_ref(), &history, width, height) {
+            frame.push_str(&line);
+            // 只關閉 ICANON/ECHO/ISIG，輸出處理（OPOST）仍會把 \n 轉成 \r\n
+            frame.push('\n');
+        }
+        print!("{frame}");
+        let _ = io::stdout().flush();
+
+        let key = read_key(next_sample.saturating_duration_since(Instant::now()));
+        let force = match key {
+            Some(b'q') | Some(b'Q') | Some(0x03) => return Ok(()),
+            Some(b'r') | Some(b'R') => true,
+            _ => false,
+        };
+        if RESIZED.swap(false, Ordering::Relaxed) && !force {
+            continue;
+        }
+        if force || Instant::now() >= next_sample {
+            (snapshot, daemon) = source.sample(force)?;
+            if history.len() == HISTORY_LEN {
+                history.pop_front();
+            }
+            history.push_back(total(&snapshot, &daemon));
+            next_sample = Instant::now() + Duration::from_secs(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sparkline_scales_to_threshold_and_clamps() {
+        let values: VecDeque<usize> = [0, 5, 10, 20].into_iter().collect();
+        assert_eq!(sparkline(&values, 10), "▁▄██");
+        assert_eq!(sparkline(&VecDeque::new(), 10), "");
+        assert_eq!(sparkline(&[3].into_iter().collect(), 0), "█");
+    }
+
+    #[test]
+    fn render_marks_breach_and_fits_width() {
+        let config = Config {
+            threshold: 4,
+            ..Config::default()
+        };
+        let snapshot = Snapshot {
+            rows: vec![ProcessRow {
+                pid: 42,
+                comm: "qq".to_string(),
+                x11: 5,
+                fds: 120,
+                rss_kib: 2048,
+                unreadable: false,
+            }],
+            total: 5,
+        };
+        let history: VecDeque<usize> = [5].into_iter().collect();
+        let lines = render(&config, &snapshot, None, &history, 30, 24);
+        assert!(lines.iter().any(|line| line.contains("超標")));
+        assert!(lines.iter().all(|line| line.chars().count() <= 30));
+        assert!(lines.last().unwrap().starts_with('q'));
+
+        let mut snapshot = snapshot;
+        snapshot.rows[0].unreadable = true;
+        snapshot.total = 0;
+        let lines = render(&config, &snapshot, None, &history, 80, 24);
+        assert!(lines.iter().any(|line| line.contains("未知")));
+        assert!(!lines.iter().any(|line| line.contains("正常")));
+    }
+
+    #[test]
+    fn daemon_status_and_measure_replies_feed_the_view() {
+        let status = parse_status(
+            "狀態: 已暫停（剩餘 30 秒）\n崩潰循環: 10 分鐘內 3 次\nDISPLAY=:0 程式=qq 門檻=8（total）\nX11 連線: 9 條\n可再動作: 約 42 秒後（冷卻）\n",
+        )
+        .unwrap();
+        assert_eq!(
+            status,
+            DaemonStatus {
+                state: "已暫停（剩餘 30 秒），崩潰循環（10 分鐘內 3 次）".to_string(),
+                threshold: Some(8),
+                count: Some(9),
+                eligible: "約 42 秒後（冷卻）".to_string(),
+            }
+        );
+        assert_eq!(
+            parse_status("狀態: 監控中\nX11 連線: 尚未量測\n")
+                .unwrap()
+                .count,
+            None
+        );
+        assert_eq!(parse_status("錯誤: 不認得的命令"), None);
+
+        let snapshot = daemon_snapshot(
+            &Config::default(),
+            r#"{"app":"qq","display":":0","connections":9,"counted":9,"threshold":8,"over_threshold":true,"last_over_threshold":null,"pids":[{"pid":100,"connections":7},{"pid":101,"connections":2}],"unreadable":[101],"measure_ms":"1.5"}"#,
+        )
+        .unwrap();
+        assert_eq!(snapshot.total, 9);
+        assert_eq!(
+            snapshot
+                .rows
+                .iter()
+                .map(|row| (row.pid, row.x11, row.unreadable))
+                .collect::<Vec<_>>(),
+            [(100, 7, false), (101, 2, true)]
+        );
+
+        // 門檻與冷卻以守護程序為準
+        let config = Config {
+            threshold: 20,
+            ..Config::default()
+        };
+        let history: VecDeque<usize> = [9].into_iter().collect();
+        let lines = render(&config, &snapshot, Some(&status), &history, 200, 24);
+        assert!(
+            lines[1].starts_with("X11 連線 9 / 門檻 8  狀態: 超標  守護程序: 已暫停"),
+            "{lines:?}"
+        );
+        assert!(lines[1].ends_with("可再動作: 約 42 秒後（冷卻）"));
+        assert!(lines.last().unwrap().contains("要求守護程序"));
+    }
+}
@@ -0,0 +1,139 @@
+use std::path::Path;
+
+// generate-unit：以這次的命令列產生 systemd 服務檔，參數先經過與啟動時相同的檢查，
+// 不會產生一份啟動就失敗的設定。本程式沒有 sd_notify，一律為 Type=simple
+pub struct Unit<'a> {
+    pub exe: &'a Path,
+    pub args: &'a [String],
+    // systemd --user 的服務；否則為系統服務
+    pub user: bool,
+    // 傳給服務的 DISPLAY、XAUTHORITY 與 QQ_GUARD_* 設定
+    pub environment: Vec<(String, String)>,
+    // 系統服務以此使用者執行；None 表示 root（或由 --drop-privileges 自行降權）
+    pub run_as: Option<String>,
+    pub generated_at: String,
+}
+
+// ExecStart 的參數：不論是否加引號，systemd 都會展開 % 與 $，須寫成 %% 與 $$
+pub fn quote(arg: &str) -> String {
+    quoted(arg.replace('%', "%%").replace('$', "$$"))
+}
+
+// Environment= 只展開 %，$ 原樣保留
+fn quote_environment(assignment: &str) -> String {
+    quoted(assignment.replace('%', "%%"))
+}
+
+// 只含安全字元時原樣輸出，否則以雙引號包住並跳脫
+fn quoted(escaped: String) -> String {
+    let safe = |char: char| char.is_ascii_alphanumeric() || "_-.,:/=@+%$".contains(char);
+    if !escaped.is_empty() && escaped.chars().all(safe) {
+        return escaped;
+    }
+    // 單獨的分號是命令之間的分隔
+    if escaped == ";" {
+        return "\\;".to_string();
+    }
+    let mut quoted = String::from("\"");
+    for char in escaped.chars() {
+        match char {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            _ => quoted.push(char),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl Unit<'_> {
+    pub fn render(&self) -> String {
+        let mut exec = vec![quote(&self.exe.to_string_lossy())];
+        exec.extend(self.args.iter().map(|arg| quote(arg)));
+        let mut lines = vec![
+            format!(
+                "# 由 qq-x11-guard-rs generate-unit 於 {} 產生；參數取自當時的命令列，修改參數後重新產生",
+                self.generated_at
+            ),
+            format!(
+                "# 安裝：{}",
+                if self.user {
+                    "存成 ~/.config/systemd/user/qq-x11-guard-rs.service 後執行 systemctl --user daemon-reload && systemctl --user enable --now qq-x11-guard-rs"
+                } else {
+                    "存成 /etc/systemd/system/qq-x11-guard-rs.service 後執行 systemctl daemon-reload && systemctl enable --now qq-x11-guard-rs"
+                }
+            ),
+            "[Unit]".to_string(),
+            "Description=QQ X11 Connection Guard (Rust)".to_string(),
+        ];
+        if self.user {
+            lines.push("After=graphical-session.target".to_string());
+            lines.push("Wants=graphical-session.target".to_string());
+        } else {
+            lines.push("After=display-manager.service".to_string());
+        }
+        lines.push(String::new());
+        lines.push("[Service]".to_string());
+        lines.push("Type=simple".to_string());
+        if let Some(user) = &self.run_as {
+            lines.push(format!("User={user}"));
+        }
+        for (name, value) in &self.environment {
+            lines.push(format!(
+                "Environment={}",
+                quote_environment(&format!("{name}={value}"))
+            ));
+        }
+        lines.push(format!("ExecStart={}", exec.join(" ")));
+        // 參數錯誤（結束碼 2）重啟也不會好，不要反覆重啟
+        lines.push("Restart=on-failure".to_string());
+        lines.push("RestartSec=3".to_string());
+        lines.push("RestartPreventExitStatus=2".to_string());
+        lines.push(String::new());
+        lines.push("[Install]".to_string());
+        lines.push(format!(
+            "WantedBy={}",
+            if self.user {
+                "default.target"
+            } else {
+                "graphical.target"
+            }
+        ));
+        lines.join("\n") + "\n"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arguments_are_quoted_for_exec_start() {
+        assert_eq!(quote("--threshold"), "--threshold");
+        assert_eq!(quote("/opt/QQ/qq"), "/opt/QQ/qq");
+        assert_eq!(quote("qq --no-sandbox"), "\"qq --no-sandbox\"");
+        assert_eq!(quote("90%"), "90%%");
+        assert_eq!(quote("echo $HOME"), "\"echo $$HOME\"");
+        assert_eq!(quote("say \"hi\"\\"), "\"say \\\"hi\\\"\\\\\"");
+        assert_eq!(quote(""), "\"\"");
+        assert_eq!(quote(";"), "\\;");
+
+        let args = ["--restart-cmd".to_string(), "qq --no-sandbox".to_string()];
+        let unit = Unit {
+            exe: Path::new("/usr/local/bin/qq-x11-guard-rs"),
+            args: &args,
+            user: true,
+            environment: vec![("DISPLAY".to_string(), ":0".to_string())],
+            run_as: None,
+            generated_at: "2026-10-16 12:00:00".to_string(),
+        }
+        .render();
+        assert!(unit.contains(
+            "Environment=DISPLAY=:0\nExecStart=/usr/local/bin/qq-x11-guard-rs --restart-cmd \"qq --no-sandbox\"\nRestart=on-failure\n"
+        ));
+        assert!(unit.ends_with("[Install]\nWantedBy=default.target\n"));
+        assert!(!unit.contains("User="));
+    }
+}
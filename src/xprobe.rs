@@ -0,0 +1,321 @@
+use std::io;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::{Duration, Instant};
+
+use crate::X11Endpoint;
+
+// --x-probe：X server 卡住時所有 client 的連線數都凍結，新的連線在 backlog 排隊，
+// 這時重啟程式不會有任何幫助。定期開一條用完即丟的連線，送出不帶認證的連線設定請求，
+// 在時限內收到回覆的 8 bytes 標頭就算還活著——回覆拒絕（沒有授權）也表示 server 有在處理請求。
+// socket 全程非阻塞，成功、逾時或失敗都在函式結束時關閉，不會留下半開的連線
+pub const TIMEOUT: Duration = Duration::from_secs(2);
+
+// 位元組順序 'l'（little endian）、協定 11.0、認證名稱與資料長度皆為 0
+const SETUP_REQUEST: [u8; 12] = [b'l', 0, 11, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+pub fn probe(endpoint: &X11Endpoint, timeout: Duration) -> Result<Duration, String> {
+    let started = Instant::now();
+    let deadline = started + timeout;
+    let socket = connect(endpoint, deadline)?;
+    send(&socket, &SETUP_REQUEST, deadline, "送出連線設定")?;
+    let mut reply = [0u8; 8];
+    receive(&socket, &mut reply, deadline, "等待回覆")?;
+    // 0 Failed、1 Success、2 Authenticate
+    match reply[0] {
+        0..=2 => Ok(started.elapsed()),
+        status => Err(format!("無法辨識的回覆（狀態 {status}）")),
+    }
+}
+
+pub fn connect(endpoint: &X11Endpoint, deadline: Instant) -> Result<OwnedFd, String> {
+    let mut address: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let (family, length) = match endpoint {
+        X11Endpoint::Unix(path) => {
+            let unix = unsafe { &mut *(&mut address as *mut _ as *mut libc::sockaddr_un) };
+            crate::check_socket_path_length(path.as_bytes())
+                .map_err(|error| format!("{error}: {path}"))?;
+            unix.sun_family = libc::AF_UNIX as libc::sa_family_t;
+            for (slot, byte) in unix.sun_path.iter_mut().zip(path.bytes()) {
+                *slot = byte as libc::c_char;
+            }
+            (libc::AF_UNIX, mem::size_of::<libc::sockaddr_un>())
+        }
+        // TCP 的 X server 是本機的（連線數也是從本機的 /proc/net/tcp 計算）
+        X11Endpoint::Tcp(port) => {
+            let inet = unsafe { &mut *(&mut address as *mut _ as *mut libc::sockaddr_in) };
+            inet.sin_family = libc::AF_INET as libc::sa_family_t;
+            inet.sin_port = port.to_be();
+            inet.sin_addr.s_addr = u32::from(Ipv4Addr::LOCALHOST).to_be();
+            (libc::AF_INET, mem::size_of::<libc::sockaddr_in>())
+        }
+    };
+    let fd = unsafe {
+        libc::socket(
+            family,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(format!("無法建立 socket（{}）", io::Error::last_os_error()));
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    let result = unsafe {
+        libc::connect(
+            socket.as_raw_fd(),
+            &address as *const _ as *const libc::sockaddr,
+            length as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        return Ok(socket);
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        // unix socket 的 backlog 滿了：server 很久沒有 accept
+        Some(libc::EAGAIN) => Err("連線佇列已滿，X server 沒有接受新連線".to_string()),
+        Some(libc::EINPROGRESS) => {
+            wait(&socket, libc::POLLOUT, deadline, "建立連線")?;
+            let mut code: libc::c_int = 0;
+            let mut size = mem::size_of::<libc::c_int>() as libc::socklen_t;
+            unsafe {
+                libc::getsockopt(
+                    socket.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_ERROR,
+                    &mut code as *mut _ as *mut libc::c_void,
+                    &mut size,
+                );
+            }
+            if code != 0 {
+                return Err(format!(
+                    "無法連線（{}）",
+                    io::Error::from_raw_os_error(code)
+                ));
+            }
+            Ok(socket)
+        }
+        _ => Err(format!("無法連線（{err}）")),
+    }
+}
+
+// 監聽 X socket 的程序：unix socket 連線的 SO_PEERCRED 是對方呼叫 listen 時的身分，用來確認誰是 X server。
+// TCP 沒有這項資訊；連不上、或 server 在其他 PID 命名空間（核心回報 PID 0）時回傳 None
+pub fn server_pid(endpoint: &X11Endpoint) -> Option<i32> {
+    if !matches!(endpoint, X11Endpoint::Unix(_)) {
+        return None;
+    }
+    let socket = connect(endpoint, Instant::now() + Duration::from_millis(200)).ok()?;
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    (result == 0 && cred.pid > 0).then_some(cred.pid)
+}
+
+pub fn send(socket: &OwnedFd, bytes: &[u8], deadline: Instant, step: &str) -> Result<(), String> {
+    let mut sent = 0;
+    while sent < bytes.len() {
+        wait(socket, libc::POLLOUT, deadline, step)?;
+        let rest = &bytes[sent..];
+        let written = unsafe { libc::write(socket.as_raw_fd(), rest.as_ptr().cast(), rest.len()) };
+        if written < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                continue;
+            }
+            return Err(format!("{step}失敗（{err}）"));
+        }
+        sent += written as usize;
+    }
+    Ok(())
+}
+
+// 讀滿 buffer；對方先關閉連線時回傳錯誤
+pub fn receive(
+    socket: &OwnedFd,
+    buffer: &mut [u8],
+    deadline: Instant,
+    step: &str,
+) -> Result<(), String> {
+    let mut received = 0;
+    while received < buffer.len() {
+        wait(socket, libc::POLLIN, deadline, step)?;
+        let rest = &mut buffer[received..];
+        let count = unsafe { libc::read(socket.as_raw_fd(), rest.as_mut_ptr().cast(), rest.len()) };
+        if count < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                continue;
+            }
+            return Err(format!("{step}失敗（{err}）"));
+        }
+        if count == 0 {
+            return Err("X server 未回覆就關閉了連線".to_string());
+        }
+        received += count as usize;
+    }
+    Ok(())
+}
+
+// 等待可讀寫直到 deadline；被訊號打斷時以剩餘時間重試
+fn wait(
+    socket: &OwnedFd,
+    events: libc::c_short,
+    deadline: Instant,
+    step: &str,
+) -> Result<(), String> {
+    loop {
+        let remain = deadline.saturating_duration_since(Instant::now());
+        if remain.is_zero() {
+            return Err(format!("{step}逾時"));
+        }
+        let mut poll_fd = libc::pollfd {
+            fd: socket.as_raw_fd(),
+            events,
+            revents: 0,
+        };
+        let ready =
+            unsafe { libc::poll(&mut poll_fd, 1, remain.as_millis().max(1) as libc::c_int) };
+        match ready {
+            0 => return Err(format!("{step}逾時")),
+            count if count > 0 => return Ok(()),
+            _ => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(format!("{step}失敗（{err}）"));
+                }
+            }
+        }
+    }
+}
+
+// 探測結果的累計：連續失敗達門檻才判定無回應，成功一次就恢復
+#[derive(Default)]
+pub struct Health {
+    pub consecutive_failures: u32,
+    pub unhealthy_since: Option<Instant>,
+    pub last: Option<(Instant, Result<Duration, String>)>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Change {
+    Unresponsive,
+    Recovered(Duration),
+}
+
+impl Health {
+    pub fn record(
+        &mut self,
+        result: Result<Duration, String>,
+        failures_needed: u32,
+        now: Instant,
+    ) -> Option<Change> {
+        let change = match &result {
+            Ok(_) => {
+                self.consecutive_failures = 0;
+                self.unhealthy_since
+                    .take()
+                    .map(|since| Change::Recovered(now.duration_since(since)))
+            }
+            Err(_) => {
+                self.consecutive_failures += 1;
+                if self.consecutive_failures >= failures_needed && self.unhealthy_since.is_none() {
+                    self.unhealthy_since = Some(now);
+                    Some(Change::Unresponsive)
+                } else {
+                    None
+                }
+            }
+        };
+        self.last = Some((now, result));
+        change
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn probe_needs_a_reply_to_the_setup_request() {
+        let dir = std::env::temp_dir().join(format!("qq-x11-guard-probe-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("X0");
+        let listener = UnixListener::bind(&path).unwrap();
+        let endpoint = X11Endpoint::Unix(path.to_string_lossy().into_owned());
+
+        // 有 listen 但不 accept、不回覆：連線本身會成功，等回覆時逾時
+        let error = probe(&endpoint, Duration::from_millis(200)).unwrap_err();
+        assert!(error.contains("逾時"), "{error}");
+
+        let server = std::thread::spawn(move || {
+            // 先把上面那條逾時的連線收掉
+            let _ = listener.accept().unwrap();
+            let (mut client, _) = listener.accept().unwrap();
+            let mut request = [0u8; 12];
+            client.read_exact(&mut request).unwrap();
+            // 沒有授權的拒絕回覆也算活著
+            client.write_all(&[0, 10, 11, 0, 0, 0, 0, 0]).unwrap();
+            request
+        });
+        probe(&endpoint, Duration::from_secs(5)).unwrap();
+        assert_eq!(server.join().unwrap(), SETUP_REQUEST);
+
+        let missing = X11Endpoint::Unix(dir.join("X1").to_string_lossy().into_owned());
+        assert!(probe(&missing, Duration::from_millis(200)).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn health_turns_unresponsive_after_repeated_failures() {
+        let mut health = Health::default();
+        let start = Instant::now();
+        let failed = || Err("等待回覆逾時".to_string());
+        assert_eq!(health.record(failed(), 2, start), None);
+        assert_eq!(
+            health.record(failed(), 2, start),
+            Some(Change::Unresponsive)
+        );
+        assert_eq!(health.record(failed(), 2, start), None);
+        assert_eq!(
+            health.record(Ok(Duration::ZERO), 2, start + Duration::from_secs(30)),
+            Some(Change::Recovered(Duration::from_secs(30)))
+        );
+        assert_eq!(health.consecutive_failures, 0);
+        assert_eq!(health.record(failed(), 2, start), None);
+    }
+
+    #[test]
+    fn server_pid_is_the_listening_process() {
+        let dir =
+            std::env::temp_dir().join(format!("qq-x11-guard-peercred-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("X0");
+        let _listener = UnixListener::bind(&path).unwrap();
+        let endpoint = X11Endpoint::Unix(path.to_string_lossy().into_owned());
+        // 監聽的是測試程序自己；沒有人監聽的路徑與 TCP 端點都無法確認
+        assert_eq!(server_pid(&endpoint), Some(std::process::id() as i32));
+        let missing = X11Endpoint::Unix(dir.join("X1").to_string_lossy().into_owned());
+        assert_eq!(server_pid(&missing), None);
+        assert_eq!(server_pid(&X11Endpoint::Tcp(6000)), None);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
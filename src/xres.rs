@@ -0,0 +1,499 @@
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::os::fd::OwnedFd;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::{xprobe, X11Endpoint};
+
+// --threshold-windowless：洩漏的連線通常不擁有任何視窗。以 X-Resource 擴充（1.2 以上）的
+// QueryClientIds 取得每個 X client 的 PID，屬於應用程式的 client 再以 QueryClientResources
+// 查詢它擁有的 WINDOW 資源數，區分有視窗與無視窗的連線。
+// 每次查詢開一條自己的連線，帶上 Xauthority 中對應 DISPLAY 的 cookie，查完即關閉
+pub const TIMEOUT: Duration = Duration::from_secs(2);
+
+// 預先定義的 atom：WINDOW 為 33，X server 以它作為視窗資源類型的名稱
+const ATOM_WINDOW: u32 = 33;
+const XRES_CLIENT_ID_PID_MASK: u32 = 2;
+const FAMILY_LOCAL: u16 = 256;
+const FAMILY_WILD: u16 = 65535;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowSplit {
+    pub windowed: usize,
+    pub windowless: usize,
+}
+
+// 把計入的 total 條連線分成有視窗與無視窗：X server 確認擁有視窗的 client 數（最多 total）為有視窗，
+// 其餘都算無視窗。X server 回報的 client 中完全沒有應用程式的 PID 時（例如應用程式在另一個
+// PID 命名空間），分類不可信，回傳錯誤讓呼叫端退回總數
+pub fn classify(
+    endpoint: &X11Endpoint,
+    display: &str,
+    app_pids: &[i32],
+    total: usize,
+) -> Result<WindowSplit, String> {
+    let windows = app_client_windows(endpoint, display, app_pids, Instant::now() + TIMEOUT)?;
+    if windows.is_empty() && total > 0 {
+        return Err(format!(
+            "X server 回報的 client 中沒有 PID {app_pids:?}（X server 是否在另一個 PID 命名空間？）"
+        ));
+    }
+    let windowed = windows
+        .iter()
+        .filter(|count| **count > 0)
+        .count()
+        .min(total);
+    Ok(WindowSplit {
+        windowed,
+        windowless: total - windowed,
+    })
+}
+
+// 所有程序的 fd 目錄都讀不到時，改由 X server 回報哪些 client 屬於應用程式：
+// 回傳（client 的 resource base, PID），resource base 在連線存續期間不變，可當作連線的識別
+pub fn app_clients(
+    endpoint: &X11Endpoint,
+    display: &str,
+    app_pids: &[i32],
+) -> Result<Vec<(u32, i32)>, String> {
+    let mut connection = Connection::open(endpoint, display, Instant::now() + TIMEOUT)?;
+    let major = connection.extension_opcode("X-Resource")?;
+    let clients = query_app_clients(&mut connection, major, app_pids)?;
+    if clients.is_empty() {
+        return Err(format!(
+            "X server 回報的 client 中沒有 PID {app_pids:?}（X server 是否在另一個 PID 命名空間？）"
+        ));
+    }
+    Ok(clients)
+}
+
+// 應用程式每個 X client 擁有的視窗數
+fn app_client_windows(
+    endpoint: &X11Endpoint,
+    display: &str,
+    app_pids: &[i32],
+    deadline: Instant,
+) -> Result<Vec<u32>, String> {
+    let mut connection = Connection::open(endpoint, display, deadline)?;
+    let major = connection.extension_opcode("X-Resource")?;
+    let mut windows = Vec::new();
+    for (client, _) in query_app_clients(&mut connection, major, app_pids)? {
+        let mut query = vec![major, 2, 2, 0];
+        query.extend(client.to_le_bytes());
+        let reply = connection.request(&query)?;
+        windows.push(parse_window_count(&reply));
+    }
+    Ok(windows)
+}
+
+// QueryClientIds 需要 X-Resource 1.2 以上；只回傳 PID 屬於應用程式的 client
+fn query_app_clients(
+    connection: &mut Connection,
+    major: u8,
+    app_pids: &[i32],
+) -> Result<Vec<(u32, i32)>, String> {
+    let version = connection.request(&[major, 0, 2, 0, 1, 2, 0, 0])?;
+    let (server_major, server_minor) = (u16_at(&version, 8), u16_at(&version, 10));
+    if (server_major, server_minor) < (1, 2) {
+        return Err(format!(
+            "X-Resource 版本 {server_major}.{server_minor} 過舊，需要 1.2 以上"
+        ));
+    }
+    // 一個 client 為 0 的規格代表所有 client
+    let mut query = vec![major, 4, 4, 0];
+    query.extend(1u32.to_le_bytes());
+    query.extend(0u32.to_le_bytes());
+    query.extend(XRES_CLIENT_ID_PID_MASK.to_le_bytes());
+    let reply = connection.request(&query)?;
+    let wanted: HashSet<i32> = app_pids.iter().copied().collect();
+    Ok(parse_client_pids(&reply)
+        .into_iter()
+        .filter(|(_, pid)| wanted.contains(pid))
+        .collect())
+}
+
+// --active-idle：MIT-SCREEN-SAVER 的 QueryInfo 回報 X server 上次收到鍵盤或滑鼠輸入至今的時間，
+// 與螢幕保護程式是否啟用無關
+pub fn input_idle(endpoint: &X11Endpoint, display: &str) -> Result<Duration, String> {
+    let mut connection = Connection::open(endpoint, display, Instant::now() + TIMEOUT)?;
+    let major = connection.extension_opcode("MIT-SCREEN-SAVER")?;
+    let mut query = vec![major, 1, 2, 0];
+    let root = connection.root.ok_or("連線設定回覆中沒有 screen")?;
+    query.extend(root.to_le_bytes());
+    let reply = connection.request(&query)?;
+    Ok(Duration::from_millis(u64::from(u32_at(&reply, 16))))
+}
+
+// QueryClientIds 回覆：32 bytes 標頭（第 8 byte 起為筆數），之後每筆為
+// client、mask、值的長度（bytes），以及值本身；PID 的值是一個 32 位元整數
+fn parse_client_pids(reply: &[u8]) -> Vec<(u32, i32)> {
+    let count = u32_at(reply, 8) as usize;
+    let mut clients = Vec::new();
+    let mut offset = 32;
+    for _ in 0..count {
+        if offset + 12 > reply.len() {
+            break;
+        }
+        let client = u32_at(reply, offset);
+        let mask = u32_at(reply, offset + 4);
+        let length = u32_at(reply, offset + 8) as usize;
+        offset += 12;
+        if mask == XRES_CLIENT_ID_PID_MASK && length == 4 && offset + 4 <= reply.len() {
+            clients.push((client, u32_at(reply, offset) as i32));
+        }
+        offset += length.div_ceil(4) * 4;
+    }
+    clients
+}
+
+// QueryClientResources 回覆：第 8 byte 起為類型數，之後每筆為（資源類型 atom, 數量）
+fn parse_window_count(reply: &[u8]) -> u32 {
+    let count = u32_at(reply, 8) as usize;
+    (0..count)
+        .map(|index| 32 + index * 8)
+        .take_while(|offset| offset + 8 <= reply.len())
+        .find(|offset| u32_at(reply, *offset) == ATOM_WINDOW)
+        .map_or(0, |offset| u32_at(reply, offset + 4))
+}
+
+pub fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+pub fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+pub fn padded(bytes: &[u8]) -> Vec<u8> {
+    let mut result = bytes.to_vec();
+    result.resize(bytes.len().div_ceil(4) * 4, 0);
+    result
+}
+
+// 視窗配置（layout.rs）也經由這條連線查詢與送出請求
+pub struct Connection {
+    socket: OwnedFd,
+    deadline: Instant,
+    // 第一個 screen 的根視窗；回覆中沒有 screen 時為 None
+    pub root: Option<u32>,
+}
+
+// 連線設定回覆（去掉 8 bytes 標頭）：32 bytes 固定欄位（第 16 byte 起為廠商字串長度，
+// 第 21 byte 為像素格式數），之後是補齊到 4 的倍數的廠商字串、每筆 8 bytes 的像素格式，
+// 再來才是各 screen，screen 的第一個欄位是根視窗
+fn parse_root_window(body: &[u8]) -> Option<u32> {
+    if body.len() < 32 {
+        return None;
+    }
+    let offset = 32 + (u16_at(body, 16) as usize).div_ceil(4) * 4 + body[21] as usize * 8;
+    (offset + 4 <= body.len()).then(|| u32_at(body, offset))
+}
+
+impl Connection {
+    pub fn open(endpoint: &X11Endpoint, display: &str, deadline: Instant) -> Result<Self, String> {
+        let socket = xprobe::connect(endpoint, deadline)?;
+        let (name, data) = display_number(display)
+            .and_then(read_cookie)
+            .unwrap_or_default();
+        let mut setup = vec![b'l', 0, 11, 0, 0, 0];
+        setup.extend((name.len() as u16).to_le_bytes());
+        setup.extend((data.len() as u16).to_le_bytes());
+        setup.extend([0, 0]);
+        setup.extend(padded(&name));
+        setup.extend(padded(&data));
+        xprobe::send(&socket, &setup, deadline, "送出連線設定")?;
+        let mut header = [0u8; 8];
+        xprobe::receive(&socket, &mut header, deadline, "等待連線設定回覆")?;
+        let mut body = vec![0u8; u16_at(&header, 6) as usize * 4];
+        xprobe::receive(&socket, &mut body, deadline, "讀取連線設定回覆")?;
+        match header[0] {
+            1 => Ok(Self {
+                socket,
+                deadline,
+                root: parse_root_window(&body),
+            }),
+            0 => {
+                let reason = &body[..(header[1] as usize).min(body.len())];
+                Err(format!(
+                    "X server 拒絕連線：{}",
+                    String::from_utf8_lossy(reason).trim()
+                ))
+            }
+            _ => Err("X server 要求額外的認證".to_string()),
+        }
+    }
+
+    // 一次只送一個請求，讀到它的回覆為止；沒有選取任何事件，仍收到的事件直接略過
+    pub fn request(&mut self, request: &[u8]) -> Result<Vec<u8>, String> {
+        let mut request = request.to_vec();
+        let words = (request.len() / 4) as u16;
+        request[2..4].copy_from_slice(&words.to_le_bytes());
+        xprobe::send(&self.socket, &request, self.deadline, "送出請求")?;
+        loop {
+            let mut reply = vec![0u8; 32];
+            xprobe::receive(&self.socket, &mut reply, self.deadline, "等待回覆")?;
+            match reply[0] {
+                0 => {
+                    return Err(format!(
+                        "X server 回報錯誤 {}（請求 {}.{}）",
+                        reply[1], request[0], request[1]
+                    ))
+                }
+                1 => {
+                    let extra = u32_at(&reply, 4) as usize * 4;
+                    let mut rest = vec![0u8; extra];
+                    xprobe::receive(&self.socket, &mut rest, self.deadline, "讀取回覆")?;
+                    reply.extend(rest);
+                    return Ok(reply);
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    // 沒有回覆的請求（例如 SendEvent）：接著送一個 GetInputFocus 等它回覆，
+    // 前一個請求的錯誤會先於這個回覆送達，由 request 回報
+    pub fn send(&mut self, request: &[u8]) -> Result<(), String> {
+        let mut request = request.to_vec();
+        let words = (request.len() / 4) as u16;
+        request[2..4].copy_from_slice(&words.to_le_bytes());
+        xprobe::send(&self.socket, &request, self.deadline, "送出請求")?;
+        self.request(&[43, 0, 1, 0]).map(|_| ())
+    }
+
+    fn extension_opcode(&mut self, name: &str) -> Result<u8, String> {
+        let mut query = vec![98, 0, 0, 0];
+        query.extend((name.len() as u16).to_le_bytes());
+        query.extend([0, 0]);
+        query.extend(padded(name.as_bytes()));
+        let reply = self.request(&query)?;
+        if reply[8] == 0 {
+            return Err(format!("X server 沒有 {name} 擴充"));
+        }
+        Ok(reply[9])
+    }
+}
+
+// ":0"、":0.0"、"localhost:10.0" 的顯示編號
+fn display_number(display: &str) -> Option<&str> {
+    let number = display.rsplit_once(':')?.1;
+    Some(number.split('.').next().unwrap_or(number))
+}
+
+// $XAUTHORITY（未設定時為 ~/.Xauthority）中本機或萬用、編號相符的第一筆：（認證名稱, 資料）。
+// 找不到時不帶認證，X server 以 xhost 之類的方式放行本機使用者時一樣可以連線
+fn read_cookie(number: &str) -> Option<(Vec<u8>, Vec<u8>)> {
+    let path = env::var_os("XAUTHORITY")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".Xauthority")))?;
+    parse_xauthority(&fs::read(path).ok()?, &hostname(), number)
+}
+
+// 每筆：family（u16，big endian），之後 address、number、name、data 各為（u16 長度, 內容）
+fn parse_xauthority(body: &[u8], host: &str, number: &str) -> Option<(Vec<u8>, Vec<u8>)> {
+    let mut offset = 0;
+    let field = |offset: &mut usize| -> Option<Vec<u8>> {
+        let length = u16::from_be_bytes(body.get(*offset..*offset + 2)?.try_into().ok()?) as usize;
+        let value = body.get(*offset + 2..*offset + 2 + length)?.to_vec();
+        *offset += 2 + length;
+        Some(value)
+    };
+    while offset < body.len() {
+        let family = u16::from_be_bytes(body.get(offset..offset + 2)?.try_into().ok()?);
+        offset += 2;
+        let address = field(&mut offset)?;
+        let entry_number = field(&mut offset)?;
+        let name = field(&mut offset)?;
+        let data = field(&mut offset)?;
+        let host_matches =
+            family == FAMILY_WILD || (family == FAMILY_LOCAL && address == host.as_bytes());
+        if host_matches && (entry_number.is_empty() || entry_number == number.as_bytes()) {
+            return Some((name, data));
+        }
+    }
+    None
+}
+
+// 讀檔而不呼叫 gethostname：後者經由 uname，不在 seccomp 白名單內
+fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    fn reply(sequence: u16, body: &[u8]) -> Vec<u8> {
+        let mut reply = vec![1, 0];
+        reply.extend(sequence.to_le_bytes());
+        let mut rest = body.to_vec();
+        rest.resize(rest.len().max(24), 0);
+        reply.extend((((rest.len() - 24) / 4) as u32).to_le_bytes());
+        reply.extend(rest);
+        reply
+    }
+
+    fn read_request(client: &mut UnixStream) -> Vec<u8> {
+        let mut header = [0u8; 4];
+        client.read_exact(&mut header).unwrap();
+        let mut rest = vec![0u8; u16::from_le_bytes([header[2], header[3]]) as usize * 4 - 4];
+        client.read_exact(&mut rest).unwrap();
+        [header.to_vec(), rest].concat()
+    }
+
+    // 模擬的 X server：PID 100 有兩個 client，一個有視窗、一個沒有；PID 200 不屬於應用程式。
+    // windows 為 false 時只回答到 QueryClientIds
+    fn serve(mut client: UnixStream, windows: bool) {
+        let mut setup = [0u8; 12];
+        client.read_exact(&mut setup).unwrap();
+        client.write_all(&[1, 0, 11, 0, 0, 0, 0, 0]).unwrap();
+
+        let query = read_request(&mut client);
+        assert_eq!(query[0], 98);
+        assert_eq!(&query[8..18], b"X-Resource");
+        client.write_all(&reply(1, &[1, 140])).unwrap();
+
+        assert_eq!(read_request(&mut client)[..2], [140, 0]);
+        client.write_all(&reply(2, &[1, 0, 2, 0])).unwrap();
+
+        assert_eq!(read_request(&mut client)[..2], [140, 4]);
+        let mut ids = 3u32.to_le_bytes().to_vec();
+        ids.resize(24, 0);
+        for (client, pid) in [
+            (0x0040_0000u32, 100u32),
+            (0x0060_0000, 100),
+            (0x0080_0000, 200),
+        ] {
+            ids.extend(client.to_le_bytes());
+            ids.extend(XRES_CLIENT_ID_PID_MASK.to_le_bytes());
+            ids.extend(4u32.to_le_bytes());
+            ids.extend(pid.to_le_bytes());
+        }
+        client.write_all(&reply(3, &ids)).unwrap();
+        if !windows {
+            return;
+        }
+
+        for (sequence, windows) in [(4u16, 3u32), (5, 0)] {
+            let request = read_request(&mut client);
+            assert_eq!(request[..2], [140, 2]);
+            let mut types = 2u32.to_le_bytes().to_vec();
+            types.resize(24, 0);
+            // 一個 pixmap（atom 20）之外，有視窗的 client 另有 WINDOW
+            types.extend(20u32.to_le_bytes());
+            types.extend(1u32.to_le_bytes());
+            types.extend(ATOM_WINDOW.to_le_bytes());
+            types.extend(windows.to_le_bytes());
+            client.write_all(&reply(sequence, &types)).unwrap();
+        }
+    }
+
+    #[test]
+    fn windowless_connections_are_those_without_window_resources() {
+        let dir = env::temp_dir().join(format!("qq-x11-guard-xres-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("X0");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            serve(listener.accept().unwrap().0, true);
+            serve(listener.accept().unwrap().0, false);
+            serve(listener.accept().unwrap().0, false);
+        });
+        let endpoint = X11Endpoint::Unix(path.to_string_lossy().into_owned());
+
+        let split = classify(&endpoint, ":0", &[100], 2).unwrap();
+        assert_eq!(
+            split,
+            WindowSplit {
+                windowed: 1,
+                windowless: 1
+            }
+        );
+        // fd 目錄讀不到時改用的 client 歸屬；沒有應用程式的 client 時不當作 0 條
+        assert_eq!(
+            app_clients(&endpoint, ":0", &[100]).unwrap(),
+            [(0x0040_0000, 100), (0x0060_0000, 100)]
+        );
+        assert!(app_clients(&endpoint, ":0", &[300]).is_err());
+        server.join().unwrap();
+        // 連不上時回傳錯誤，不會當作 0 條
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(classify(&endpoint, ":0", &[100], 2).is_err());
+    }
+
+    #[test]
+    fn input_idle_queries_the_first_screen_root() {
+        let dir = env::temp_dir().join(format!("qq-x11-guard-idle-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("X0");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            let mut client = listener.accept().unwrap().0;
+            let mut setup = [0u8; 12];
+            client.read_exact(&mut setup).unwrap();
+            // 廠商字串 5 bytes（補齊為 8）、兩筆像素格式，之後是根視窗 0x1e5 的 screen
+            let mut body = vec![0u8; 32];
+            body[16] = 5;
+            body[21] = 2;
+            body.extend(padded(b"Xorg!"));
+            body.extend([0u8; 16]);
+            body.extend(0x1e5u32.to_le_bytes());
+            body.extend([0u8; 36]);
+            let mut header = vec![1, 0, 11, 0, 0, 0];
+            header.extend(((body.len() / 4) as u16).to_le_bytes());
+            client.write_all(&[header, body].concat()).unwrap();
+
+            let query = read_request(&mut client);
+            assert_eq!(&query[8..24], b"MIT-SCREEN-SAVER");
+            client.write_all(&reply(1, &[1, 150])).unwrap();
+            let request = read_request(&mut client);
+            assert_eq!(request[..2], [150, 1]);
+            assert_eq!(u32_at(&request, 4), 0x1e5);
+            let mut info = vec![0u8; 8];
+            info.extend(42_000u32.to_le_bytes());
+            client.write_all(&reply(2, &info)).unwrap();
+        });
+        let endpoint = X11Endpoint::Unix(path.to_string_lossy().into_owned());
+        assert_eq!(input_idle(&endpoint, ":0"), Ok(Duration::from_secs(42)));
+        server.join().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn xauthority_entries_match_by_host_and_display_number() {
+        let entry = |family: u16, address: &str, number: &str, data: &[u8]| {
+            let mut bytes = family.to_be_bytes().to_vec();
+            for field in [
+                address.as_bytes(),
+                number.as_bytes(),
+                b"MIT-MAGIC-COOKIE-1",
+                data,
+            ] {
+                bytes.extend((field.len() as u16).to_be_bytes());
+                bytes.extend(field);
+            }
+            bytes
+        };
+        let body = [
+            entry(FAMILY_LOCAL, "other", "0", b"a"),
+            entry(FAMILY_LOCAL, "box", "1", b"b"),
+            entry(FAMILY_LOCAL, "box", "0", b"c"),
+            entry(FAMILY_WILD, "", "", b"d"),
+        ]
+        .concat();
+        let found = |number| parse_xauthority(&body, "box", number).map(|(_, data)| data);
+        assert_eq!(found("0"), Some(b"c".to_vec()));
+        assert_eq!(found("1"), Some(b"b".to_vec()));
+        assert_eq!(found("7"), Some(b"d".to_vec()));
+        assert_eq!(display_number("localhost:10.0"), Some("10"));
+        assert_eq!(display_number(":0"), Some("0"));
+    }
+}